@@ -0,0 +1,58 @@
+//! Host shared by the examples.
+//!
+//! The examples are written for a bootloader-style environment: physical
+//! memory, including the controller's MMIO window, is identity mapped and
+//! the heap is physically contiguous. They build on any `std` target, but
+//! only run where that holds; under a hosted OS pass nothing and they exit
+//! after printing their usage.
+
+use std::alloc::{Layout, alloc, dealloc};
+
+use usb_oxide::Dma;
+
+/// `Dma` host for identity-mapped memory, allocating from the global heap
+pub struct IdentityHost;
+
+impl Dma for IdentityHost {
+    unsafe fn alloc(&self, size: usize, align: usize) -> Option<usize> {
+        let layout = Layout::from_size_align(size, align).ok()?;
+        let ptr = unsafe { alloc(layout) };
+        (!ptr.is_null()).then_some(ptr as usize)
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize, align: usize) {
+        let layout = Layout::from_size_align(size, align).unwrap();
+        unsafe { dealloc(addr as *mut u8, layout) }
+    }
+
+    unsafe fn map_mmio(&self, phys: usize, _size: usize) -> Option<usize> {
+        Some(phys)
+    }
+
+    unsafe fn unmap_mmio(&self, _virt: usize, _size: usize) {}
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        va
+    }
+
+    fn delay_us(&self, us: u32) {
+        std::thread::sleep(std::time::Duration::from_micros(us as u64));
+    }
+}
+
+/// Physical address of the controller's MMIO window from the first
+/// argument, in hex with or without `0x`
+pub fn mmio_arg(example: &str) -> Option<usize> {
+    let Some(arg) = std::env::args().nth(1) else {
+        eprintln!("usage: {example} <xhci-mmio-phys-hex>");
+        eprintln!("needs identity-mapped physical memory; see examples/common/mod.rs");
+        return None;
+    };
+    match usize::from_str_radix(arg.trim_start_matches("0x"), 16) {
+        Ok(addr) => Some(addr),
+        Err(_) => {
+            eprintln!("{example}: invalid MMIO address {arg}");
+            None
+        }
+    }
+}
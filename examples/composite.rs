@@ -0,0 +1,179 @@
+//! Drives both functions of the first composite keyboard with a card
+//! reader: prints the card capacity, then echoes key presses until Escape.
+#![cfg_attr(feature = "single-threaded", allow(clippy::arc_with_non_send_sync))]
+
+mod common;
+
+use std::sync::Arc;
+
+use usb_oxide::{
+    Dma, EndpointDesc, HidDevice, InterfaceDesc, KeyboardState, MscDevice, ReadyPolicy,
+    UnitReadiness, UsbDevice, XhciCtrl, find_hid_interfaces, find_msc_interfaces, hid_protocol,
+    scancode,
+};
+
+use common::IdentityHost;
+
+/// Keyboard and mass storage interfaces of one configuration
+struct Functions {
+    keyboard: (InterfaceDesc, EndpointDesc),
+    storage: (InterfaceDesc, EndpointDesc, EndpointDesc),
+}
+
+/// Finds a boot keyboard and a mass storage function in `config_data`
+fn composite_functions(config_data: &[u8]) -> Option<Functions> {
+    let keyboard = find_hid_interfaces(config_data)
+        .into_iter()
+        .find(|(iface, _)| iface.interface_protocol == hid_protocol::KEYBOARD)?;
+    let storage = find_msc_interfaces(config_data).into_iter().next()?;
+    Some(Functions { keyboard, storage })
+}
+
+/// Configures the device on `port` if it is a keyboard with storage
+///
+/// The keyboard comes back with its first report read queued.
+fn open_composite<H: Dma>(
+    ctrl: &Arc<XhciCtrl<H>>,
+    port: u8,
+) -> usb_oxide::Result<Option<(HidDevice<H>, MscDevice<H>)>> {
+    let mut device = UsbDevice::new(ctrl.clone(), port)?;
+    device.get_device_descriptor()?;
+
+    let tree = device.config_tree_at(0)?;
+    let Some(Functions { keyboard, storage }) = composite_functions(tree.raw()) else {
+        return Ok(None);
+    };
+    device.set_configuration(tree.config.config_value)?;
+
+    // Both drivers hold the same device; each owns only its own endpoints
+    let device = Arc::new(device);
+    let (iface, ep_in) = keyboard;
+    let hid = HidDevice::from_interface(device.clone(), &iface, &ep_in)?;
+    hid.queue_read()?;
+    let (iface, ep_in, ep_out) = storage;
+    let msc = MscDevice::from_interface(device, &iface, &ep_in, &ep_out)?;
+    Ok(Some((hid, msc)))
+}
+
+fn main() -> usb_oxide::Result<()> {
+    let Some(mmio_phys) = common::mmio_arg("composite") else {
+        return Ok(());
+    };
+
+    let ctrl = Arc::new(XhciCtrl::new(mmio_phys, IdentityHost)?);
+    let mut composite = None;
+    for port in ctrl.connected_ports() {
+        if let Some(drivers) = open_composite(&ctrl, port.port)? {
+            println!("port {}: {}", port.port, drivers.1.device().summary());
+            composite = Some(drivers);
+            break;
+        }
+    }
+    let Some((keyboard, mut msc)) = composite else {
+        println!("no keyboard with mass storage found");
+        return Ok(());
+    };
+
+    if msc.wait_ready(0, ReadyPolicy::default())? == UnitReadiness::Ready {
+        let cap = msc.read_capacity(0)?;
+        println!(
+            "card: {} blocks of {} bytes",
+            cap.last_lba() as u64 + 1,
+            cap.block_size()
+        );
+    } else {
+        println!("card: no medium");
+    }
+
+    println!("press keys; Escape quits");
+    let mut held = KeyboardState::default();
+    loop {
+        let Some(state) = keyboard.poll_keys() else {
+            continue;
+        };
+        for code in state.keys().filter(|&code| !held.is_pressed(code)) {
+            if code == scancode::ESCAPE {
+                return Ok(());
+            }
+            println!("key {code:#04x}");
+        }
+        held = state;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use usb_oxide::mock::{self, Reply};
+
+    /// A keyboard on interface 0 and a card reader on interface 1
+    const KEYBOARD_WITH_READER: [u8; 57] = [
+        // Configuration, 2 interfaces
+        9, 2, 57, 0, 2, 1, 0, 0x80, 50, //
+        // Interface 0: HID boot keyboard, 1 endpoint
+        9, 4, 0, 0, 1, 3, 1, 1, 0, //
+        // HID descriptor, 63-byte report descriptor
+        9, 0x21, 0x11, 1, 0, 1, 0x22, 63, 0, //
+        // Endpoint 0x81: interrupt IN, 8 bytes
+        7, 5, 0x81, 3, 8, 0, 4, //
+        // Interface 1: mass storage, SCSI, Bulk-Only, 2 endpoints
+        9, 4, 1, 0, 2, 8, 6, 0x50, 0, //
+        // Endpoint 0x82: bulk IN, 512 bytes
+        7, 5, 0x82, 2, 0, 2, 0, //
+        // Endpoint 0x02: bulk OUT, 512 bytes
+        7, 5, 0x02, 2, 0, 2, 0,
+    ];
+
+    #[test]
+    fn finds_both_functions() {
+        let Functions { keyboard, storage } = composite_functions(&KEYBOARD_WITH_READER).unwrap();
+        assert_eq!(keyboard.0.interface_number, 0);
+        assert_eq!(keyboard.1.endpoint_address, 0x81);
+        assert_eq!(storage.0.interface_number, 1);
+        assert_eq!(storage.1.endpoint_address, 0x82);
+        assert_eq!(storage.2.endpoint_address, 0x02);
+    }
+
+    #[test]
+    fn single_functions_are_skipped() {
+        // The keyboard alone, and the card reader alone
+        let mut keyboard = KEYBOARD_WITH_READER[..34].to_vec();
+        keyboard[2] = keyboard.len() as u8;
+        keyboard[4] = 1;
+        assert!(composite_functions(&keyboard).is_none());
+
+        let mut reader = KEYBOARD_WITH_READER[..9].to_vec();
+        reader.extend_from_slice(&KEYBOARD_WITH_READER[34..]);
+        reader[2] = reader.len() as u8;
+        reader[4] = 1;
+        assert!(composite_functions(&reader).is_none());
+    }
+
+    #[test]
+    fn drives_both_functions_on_the_mock() {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, mock::keyboard_with_card_reader());
+        mock.attach(1, mock::keyboard());
+
+        assert!(open_composite(&ctrl, 1).unwrap().is_none());
+        let (keyboard, mut msc) = open_composite(&ctrl, 0).unwrap().expect("composite");
+        assert_eq!(
+            msc.wait_ready(0, ReadyPolicy::default()).unwrap(),
+            UnitReadiness::Ready
+        );
+        let cap = msc.read_capacity(0).unwrap();
+        assert_eq!((cap.last_lba(), cap.block_size()), (63, 512));
+
+        // The queued read picks up the first key press
+        let report = vec![0, 0, scancode::ESCAPE, 0, 0, 0, 0, 0];
+        mock.with_device(0, 0, |d| d.push_input(0x81, Reply::Data(report)));
+        let state = (0..100)
+            .find_map(|_| {
+                mock.advance_us(125);
+                keyboard.poll_keys()
+            })
+            .expect("key press");
+        assert!(state.is_pressed(scancode::ESCAPE));
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+}
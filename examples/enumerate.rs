@@ -0,0 +1,37 @@
+//! Initializes the controller and lists every device on its root ports.
+#![cfg_attr(feature = "single-threaded", allow(clippy::arc_with_non_send_sync))]
+
+mod common;
+
+use std::sync::Arc;
+
+use usb_oxide::{UsbDevice, XhciCtrl};
+
+use common::IdentityHost;
+
+fn main() -> usb_oxide::Result<()> {
+    let Some(mmio_phys) = common::mmio_arg("enumerate") else {
+        return Ok(());
+    };
+
+    let ctrl = Arc::new(XhciCtrl::new(mmio_phys, IdentityHost)?);
+    let caps = ctrl.capabilities();
+    let (major, minor) = caps.hci_version;
+    println!(
+        "xHCI {major}.{minor:02x}: {} ports, {} slots",
+        caps.max_ports, caps.max_slots
+    );
+
+    for port in ctrl.connected_ports() {
+        let device = match UsbDevice::new(ctrl.clone(), port.port) {
+            Ok(device) => device,
+            Err(err) => {
+                println!("port {}: {err}", port.port);
+                continue;
+            }
+        };
+        println!("port {}: {}", port.port, device.summary());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+//! Echoes typed text from the first USB keyboard, line by line, until
+//! Escape is pressed.
+#![cfg_attr(feature = "single-threaded", allow(clippy::arc_with_non_send_sync))]
+
+mod common;
+
+use std::sync::Arc;
+
+use usb_oxide::{
+    HidDevice, KeyboardState, UsbDevice, XhciCtrl, find_hid_configuration, find_hid_interfaces,
+    hid_protocol, modifier, scancode, scancode_to_ascii,
+};
+
+use common::IdentityHost;
+
+/// Configures the device on `port` if it is a keyboard
+fn open_keyboard(
+    ctrl: &Arc<XhciCtrl<IdentityHost>>,
+    port: u8,
+) -> usb_oxide::Result<Option<HidDevice<IdentityHost>>> {
+    let mut device = UsbDevice::new(ctrl.clone(), port)?;
+    device.get_device_descriptor()?;
+
+    let Some((config_value, config_data)) = find_hid_configuration(&device)? else {
+        return Ok(None);
+    };
+    let Some((iface, ep_in)) = find_hid_interfaces(&config_data)
+        .into_iter()
+        .find(|(iface, _)| iface.interface_protocol == hid_protocol::KEYBOARD)
+    else {
+        return Ok(None);
+    };
+    device.set_configuration(config_value)?;
+
+    HidDevice::from_interface(Arc::new(device), &iface, &ep_in).map(Some)
+}
+
+fn main() -> usb_oxide::Result<()> {
+    let Some(mmio_phys) = common::mmio_arg("keyboard_echo") else {
+        return Ok(());
+    };
+
+    let ctrl = Arc::new(XhciCtrl::new(mmio_phys, IdentityHost)?);
+    let mut keyboard = None;
+    for port in ctrl.connected_ports() {
+        if let Some(device) = open_keyboard(&ctrl, port.port)? {
+            keyboard = Some(device);
+            break;
+        }
+    }
+    let Some(keyboard) = keyboard else {
+        println!("no keyboard found");
+        return Ok(());
+    };
+
+    // Each poll re-queues the read it completes; the first one is ours
+    keyboard.queue_read()?;
+    println!("type; Escape quits");
+    let mut held = KeyboardState::default();
+    let mut line = String::new();
+    loop {
+        let Some(state) = keyboard.poll_keys() else {
+            if let Some(err) = keyboard.last_error() {
+                eprintln!("read failed: {err}");
+            }
+            continue;
+        };
+
+        // Only keys that went down since the previous report produce text
+        let shift = state.modifiers & modifier::SHIFT != 0;
+        for code in state.keys().filter(|&code| !held.is_pressed(code)) {
+            match code {
+                scancode::ESCAPE => return Ok(()),
+                scancode::ENTER => println!("{}", std::mem::take(&mut line)),
+                scancode::BACKSPACE => {
+                    line.pop();
+                }
+                _ => line.extend(scancode_to_ascii(code, shift)),
+            }
+        }
+        held = state;
+    }
+}
@@ -0,0 +1,87 @@
+//! Finds the first mass storage device and prints its partition table.
+#![cfg_attr(feature = "single-threaded", allow(clippy::arc_with_non_send_sync))]
+
+mod common;
+
+use std::sync::Arc;
+
+use usb_oxide::{MscDevice, ReadyPolicy, UnitReadiness, UsbDevice, XhciCtrl, find_msc_interfaces};
+
+use common::IdentityHost;
+
+/// Offset of the partition table in the MBR
+const PARTITION_TABLE: usize = 446;
+
+/// Configures the device on `port` if it is mass storage
+fn open_msc(
+    ctrl: &Arc<XhciCtrl<IdentityHost>>,
+    port: u8,
+) -> usb_oxide::Result<Option<MscDevice<IdentityHost>>> {
+    let mut device = UsbDevice::new(ctrl.clone(), port)?;
+    device.get_device_descriptor()?;
+
+    let tree = device.config_tree_at(0)?;
+    let Some((iface, ep_in, ep_out)) = find_msc_interfaces(tree.raw()).into_iter().next() else {
+        return Ok(None);
+    };
+    device.set_configuration(tree.config.config_value)?;
+
+    // Class drivers share the device; the controller only keeps a weak reference
+    let device = Arc::new(device);
+    MscDevice::from_interface(device, &iface, &ep_in, &ep_out).map(Some)
+}
+
+fn main() -> usb_oxide::Result<()> {
+    let Some(mmio_phys) = common::mmio_arg("read_disk") else {
+        return Ok(());
+    };
+
+    let ctrl = Arc::new(XhciCtrl::new(mmio_phys, IdentityHost)?);
+    let mut msc = None;
+    for port in ctrl.connected_ports() {
+        if let Some(device) = open_msc(&ctrl, port.port)? {
+            println!("port {}: {}", port.port, device.device().summary());
+            msc = Some(device);
+            break;
+        }
+    }
+    let Some(mut msc) = msc else {
+        println!("no mass storage device found");
+        return Ok(());
+    };
+
+    // Flash drives may need a moment after configuration
+    if msc.wait_ready(0, ReadyPolicy::default())? == UnitReadiness::NoMedium {
+        println!("no medium");
+        return Ok(());
+    }
+
+    let cap = msc.read_capacity(0)?;
+    println!(
+        "{} blocks of {} bytes",
+        cap.last_lba() as u64 + 1,
+        cap.block_size()
+    );
+
+    let mut mbr = vec![0u8; cap.block_size() as usize];
+    msc.read_blocks(0, 0, 1, &mut mbr)?;
+    if mbr.len() < 512 || mbr[510..512] != [0x55, 0xaa] {
+        println!("LBA 0 holds no MBR");
+        return Ok(());
+    }
+
+    for (i, entry) in mbr[PARTITION_TABLE..510].chunks_exact(16).enumerate() {
+        let kind = entry[4];
+        if kind == 0 {
+            continue;
+        }
+        let start = u32::from_le_bytes(entry[8..12].try_into().unwrap());
+        let sectors = u32::from_le_bytes(entry[12..16].try_into().unwrap());
+        println!(
+            "partition {}: type {kind:#04x}, start {start}, {sectors} sectors",
+            i + 1
+        );
+    }
+
+    Ok(())
+}
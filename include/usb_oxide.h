@@ -0,0 +1,271 @@
+/* Generated by cbindgen from src/ffi.rs; do not edit. */
+
+#ifndef USB_OXIDE_H
+#define USB_OXIDE_H
+
+
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// Success
+#define USB_OXIDE_OK 0
+
+// `UsbError::Timeout`
+#define USB_OXIDE_ERR_TIMEOUT -1
+
+// `UsbError::OoRam`
+#define USB_OXIDE_ERR_OUT_OF_MEMORY -2
+
+// `UsbError::MapFail`
+#define USB_OXIDE_ERR_MAP_FAIL -3
+
+// `UsbError::InvSlot`
+#define USB_OXIDE_ERR_INVALID_SLOT -4
+
+// `UsbError::InvPort`
+#define USB_OXIDE_ERR_INVALID_PORT -5
+
+// `UsbError::InvEndpoint`
+#define USB_OXIDE_ERR_INVALID_ENDPOINT -6
+
+// `UsbError::CmdFail`, whatever the completion code
+#define USB_OXIDE_ERR_COMMAND_FAILED -7
+
+// `UsbError::XferFail`, whatever the completion code
+#define USB_OXIDE_ERR_TRANSFER_FAILED -8
+
+// `UsbError::DeviceNotFound`
+#define USB_OXIDE_ERR_DEVICE_NOT_FOUND -9
+
+// `UsbError::NotSupported`
+#define USB_OXIDE_ERR_NOT_SUPPORTED -10
+
+// `UsbError::InvalidDescriptor`
+#define USB_OXIDE_ERR_INVALID_DESCRIPTOR -11
+
+// `UsbError::Stall`
+#define USB_OXIDE_ERR_STALL -12
+
+// `UsbError::RingFull`
+#define USB_OXIDE_ERR_RING_FULL -13
+
+// `UsbError::MissedService`
+#define USB_OXIDE_ERR_MISSED_SERVICE -14
+
+// `UsbError::RingOverrun`
+#define USB_OXIDE_ERR_RING_OVERRUN -15
+
+// `UsbError::RingUnderrun`
+#define USB_OXIDE_ERR_RING_UNDERRUN -16
+
+// `UsbError::OverCurrent`
+#define USB_OXIDE_ERR_OVER_CURRENT -17
+
+// `UsbError::InsufficientPower`
+#define USB_OXIDE_ERR_INSUFFICIENT_POWER -18
+
+// `UsbError::ReadOnly`
+#define USB_OXIDE_ERR_READ_ONLY -19
+
+// `UsbError::InvalidArgument`, also returned for null pointers
+#define USB_OXIDE_ERR_INVALID_ARGUMENT -20
+
+// `UsbError::DeviceFailed`
+#define USB_OXIDE_ERR_DEVICE_FAILED -21
+
+// `UsbError::InvLun`
+#define USB_OXIDE_ERR_INVALID_LUN -22
+
+// `UsbError::Misaligned`
+#define USB_OXIDE_ERR_MISALIGNED -23
+
+// `UsbError::Disconnected`
+#define USB_OXIDE_ERR_DISCONNECTED -24
+
+// `UsbError::Inactive`
+#define USB_OXIDE_ERR_INACTIVE -25
+
+// `UsbError::ControllerGone`
+#define USB_OXIDE_ERR_CONTROLLER_GONE -26
+
+// `UsbError::EventLost`
+#define USB_OXIDE_ERR_EVENT_LOST -27
+
+// `UsbError::InsufficientBandwidth`
+#define USB_OXIDE_ERR_INSUFFICIENT_BANDWIDTH -28
+
+// `UsbError::Cancelled`
+#define USB_OXIDE_ERR_CANCELLED -29
+
+// The handle was closed, never opened, or is of another kind
+#define USB_OXIDE_ERR_INVALID_HANDLE -64
+
+// Opaque controller handle.
+typedef struct usb_oxide_ctrl usb_oxide_ctrl;
+
+// Opaque keyboard handle.
+typedef struct usb_oxide_kbd usb_oxide_kbd;
+
+// Opaque mass storage handle.
+typedef struct usb_oxide_msc usb_oxide_msc;
+
+// `Dma` operations implemented in C.
+//
+// Every callback is passed `ctx`. `alloc`, `free`, `map_mmio`,
+// `unmap_mmio`, `virt_to_phys` and `delay_us` are required; `alloc` and
+// `map_mmio` return 0 on failure. The callbacks may run from any context the
+// driver is called from.
+typedef struct {
+  // Passed to every callback
+  void *ctx;
+  // `Dma::alloc`
+  size_t (*alloc)(void *ctx, size_t size, size_t align);
+  // `Dma::free`
+  void (*free)(void *ctx, size_t addr, size_t size, size_t align);
+  // `Dma::map_mmio`
+  size_t (*map_mmio)(void *ctx, size_t phys, size_t size);
+  // `Dma::unmap_mmio`
+  void (*unmap_mmio)(void *ctx, size_t virt, size_t size);
+  // `Dma::virt_to_phys`
+  size_t (*virt_to_phys)(void *ctx, size_t va);
+  // `Dma::delay_us`, timed by a clock
+  void (*delay_us)(void *ctx, uint32_t us);
+  // `Dma::wmb`; null for the default release fence
+  void (*wmb)(void *ctx);
+  // `Dma::page_size`; 0 for 4096
+  size_t page_size;
+} usb_oxide_dma_ops;
+
+// Device found by `usb_oxide_enumerate`.
+typedef struct {
+  // Root hub port number
+  uint8_t port;
+  // Speed ID, as in PORTSC
+  uint8_t speed;
+  // bDeviceClass
+  uint8_t device_class;
+  // bDeviceSubClass
+  uint8_t device_subclass;
+  // bDeviceProtocol
+  uint8_t device_protocol;
+  // idVendor
+  uint16_t vendor_id;
+  // idProduct
+  uint16_t product_id;
+} usb_oxide_device_info;
+
+// Keys held on a keyboard, as returned by `usb_oxide_kbd_poll`.
+typedef struct {
+  // Modifier keys bitmap
+  uint8_t modifiers;
+  // One bit per scancode below the modifiers, LSB first
+  uint8_t keys[32];
+} usb_oxide_key_state;
+
+// Initialize the controller at `mmio_phys` and store its handle in `out`
+//
+// # Safety
+//
+// `ops` and `out` must be valid pointers, and the callbacks must
+// implement the `Dma` contract.
+int32_t usb_oxide_ctrl_init(size_t mmio_phys, const usb_oxide_dma_ops *ops, usb_oxide_ctrl **out);
+
+// Close a controller handle
+//
+// Mass storage and keyboard handles opened from it keep working until
+// they are closed too.
+int32_t usb_oxide_ctrl_close(usb_oxide_ctrl *ctrl);
+
+// Enumerate the devices on the root ports
+//
+// The first call enumerates and configures every connected device;
+// later calls report the same devices. Fills up to `capacity` entries
+// of `infos` and returns the number of devices, or an error code.
+//
+// # Safety
+//
+// `infos` must point to `capacity` writable entries, or be null if
+// `capacity` is 0.
+int32_t usb_oxide_enumerate(usb_oxide_ctrl *ctrl, usb_oxide_device_info *infos, size_t capacity);
+
+// Open the first Bulk-Only mass storage interface and store its handle in `out`
+//
+// Enumerates first if `usb_oxide_enumerate` was not called. Fails with
+// `USB_OXIDE_ERR_DEVICE_NOT_FOUND` if no device has one.
+//
+// # Safety
+//
+// `out` must be a valid pointer.
+int32_t usb_oxide_open_first_msc(usb_oxide_ctrl *ctrl, usb_oxide_msc **out);
+
+// Close a mass storage handle
+int32_t usb_oxide_msc_close(usb_oxide_msc *msc);
+
+// Read the size of a LUN: its number of blocks and block size in bytes
+//
+// # Safety
+//
+// `blocks` and `block_size` must be valid pointers.
+int32_t usb_oxide_msc_capacity(usb_oxide_msc *msc,
+                               uint8_t lun,
+                               uint64_t *blocks,
+                               uint32_t *block_size);
+
+// Read `count` blocks at `lba` into `buf`, which holds exactly `len` bytes
+//
+// The bytes transferred are stored in `transferred` unless it is null.
+//
+// # Safety
+//
+// `buf` must point to `len` writable bytes.
+int32_t usb_oxide_msc_read_blocks(usb_oxide_msc *msc,
+                                  uint8_t lun,
+                                  uint32_t lba,
+                                  uint16_t count,
+                                  uint8_t *buf,
+                                  size_t len,
+                                  size_t *transferred);
+
+// Write `count` blocks at `lba` from `buf`, which holds exactly `len` bytes
+//
+// The bytes transferred are stored in `transferred` unless it is null.
+//
+// # Safety
+//
+// `buf` must point to `len` bytes the driver may access mutably for
+// the duration of the call.
+int32_t usb_oxide_msc_write_blocks(usb_oxide_msc *msc,
+                                   uint8_t lun,
+                                   uint32_t lba,
+                                   uint16_t count,
+                                   uint8_t *buf,
+                                   size_t len,
+                                   size_t *transferred);
+
+// Open the first boot keyboard interface and store its handle in `out`
+//
+// Enumerates first if `usb_oxide_enumerate` was not called, and queues
+// the first report read. Fails with `USB_OXIDE_ERR_DEVICE_NOT_FOUND` if
+// no device has one.
+//
+// # Safety
+//
+// `out` must be a valid pointer.
+int32_t usb_oxide_open_first_keyboard(usb_oxide_ctrl *ctrl, usb_oxide_kbd **out);
+
+// Close a keyboard handle
+int32_t usb_oxide_kbd_close(usb_oxide_kbd *kbd);
+
+// Poll a keyboard for the keys held (non-blocking)
+//
+// Returns 1 and fills `state` if a report arrived, 0 if none did, or
+// the error code of a failed read.
+//
+// # Safety
+//
+// `state` must be a valid pointer.
+int32_t usb_oxide_kbd_poll(usb_oxide_kbd *kbd, usb_oxide_key_state *state);
+
+#endif  /* USB_OXIDE_H */
@@ -0,0 +1,293 @@
+//! Static DMA provisioning.
+//!
+//! `StaticDma` wraps a host and serves every DMA allocation from one
+//! region handed over up front, never calling the host's `alloc`. Size the
+//! region with `MappedXhci::memory_requirements`.
+
+use crate::{
+    err::{Result, UsbError},
+    ram::Dma,
+};
+use alloc::{vec, vec::Vec};
+
+/// Allocation granule of the arena, in bytes
+const GRANULE: usize = 64;
+
+/// DMA memory a controller needs for a given workload.
+///
+/// Returned by `MappedXhci::memory_requirements`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryPlan {
+    /// Size of the region, in bytes
+    pub bytes: usize,
+    /// Alignment of the region, in bytes
+    pub align: usize,
+    /// Number of allocations live at the peak
+    pub allocations: usize,
+}
+
+impl MemoryPlan {
+    /// Account for `count` allocations of `size` bytes aligned to `align`
+    pub(crate) fn add(&mut self, count: usize, size: usize, align: usize) {
+        // Worst-case padding to reach the alignment
+        let padding = align.max(GRANULE) - GRANULE;
+        self.bytes += count * (size.max(1).next_multiple_of(GRANULE) + padding);
+        self.allocations += count;
+    }
+}
+
+/// Bitmap allocator over the provisioned region
+struct Arena {
+    base: usize,
+    /// One bit per granule, set while allocated
+    bitmap: Vec<u64>,
+    granules: usize,
+    used: usize,
+    peak: usize,
+}
+
+impl Arena {
+    fn is_free(&self, granule: usize) -> bool {
+        self.bitmap[granule / 64] & (1 << (granule % 64)) == 0
+    }
+
+    fn mark(&mut self, start: usize, count: usize, allocated: bool) {
+        for granule in start..start + count {
+            let (word, bit) = (granule / 64, 1 << (granule % 64));
+            if allocated {
+                self.bitmap[word] |= bit;
+            } else {
+                self.bitmap[word] &= !bit;
+            }
+        }
+    }
+
+    /// First fit; deterministic for a given sequence of calls
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        let count = size.max(1).div_ceil(GRANULE);
+        let align = align.max(GRANULE);
+        let step = align / GRANULE;
+        let mut start = (self.base.next_multiple_of(align) - self.base) / GRANULE;
+        while start + count <= self.granules {
+            match (start..start + count).find(|&g| !self.is_free(g)) {
+                None => {
+                    self.mark(start, count, true);
+                    self.used += count * GRANULE;
+                    self.peak = self.peak.max(self.used);
+                    return Some(self.base + start * GRANULE);
+                }
+                // Skip past the allocated granule
+                Some(taken) => start += (taken - start) / step * step + step,
+            }
+        }
+        None
+    }
+
+    fn free(&mut self, addr: usize, size: usize) {
+        let count = size.max(1).div_ceil(GRANULE);
+        self.mark((addr - self.base) / GRANULE, count, false);
+        self.used -= count * GRANULE;
+    }
+}
+
+/// Host wrapper serving DMA allocations from one pre-allocated region.
+///
+/// For environments that forbid allocation after initialization. Every
+/// `alloc` and `free` goes to an arena over the region given to
+/// `provision`; the wrapped host's `alloc` and `free` are never called,
+/// and `low_memory` is not forwarded. An allocation that does not fit
+/// fails, so the operation needing it fails with `OoRam`. Everything
+/// else is forwarded to the wrapped host.
+///
+/// ```ignore
+/// let mapped = XhciBuilder::map(mmio_phys, StaticDma::new(host))?;
+/// let plan = mapped.memory_requirements(4, 4, 64 * 1024);
+/// let region = reserve_dma(plan.bytes, plan.align);
+/// unsafe { mapped.host().provision(region, plan.bytes)? };
+/// let ctrl = mapped.take_ownership()?.reset_and_start()?;
+/// ```
+///
+/// The arena hands out 64-byte granules first fit, so the same sequence
+/// of operations always succeeds or fails the same way. Allocations fail
+/// until a region is provisioned.
+pub struct StaticDma<H: Dma> {
+    host: H,
+    // Dma implementors must be Sync, even under `single-threaded`
+    arena: spin::Mutex<Option<Arena>>,
+}
+
+impl<H: Dma> StaticDma<H> {
+    /// Wrap `host`, with no region provisioned yet
+    pub fn new(host: H) -> Self {
+        Self {
+            host,
+            arena: spin::Mutex::new(None),
+        }
+    }
+
+    /// Hand over the region DMA memory is carved from
+    ///
+    /// Fails with `InvalidArgument` if a region was already provisioned,
+    /// or if `virt` is not 64-byte aligned.
+    ///
+    /// # Safety
+    ///
+    /// `virt` must be the virtual address of `size` bytes of physically
+    /// contiguous, DMA-coherent memory, as `Dma::alloc` would return,
+    /// owned by this wrapper for as long as it lives.
+    pub unsafe fn provision(&self, virt: usize, size: usize) -> Result<()> {
+        let mut arena = self.arena.lock();
+        if arena.is_some() || !virt.is_multiple_of(GRANULE) {
+            return Err(UsbError::InvalidArgument);
+        }
+        let granules = size / GRANULE;
+        *arena = Some(Arena {
+            base: virt,
+            bitmap: vec![0; granules.div_ceil(64)],
+            granules,
+            used: 0,
+            peak: 0,
+        });
+        Ok(())
+    }
+
+    /// Returns the wrapped host.
+    pub fn inner(&self) -> &H {
+        &self.host
+    }
+
+    /// Returns the bytes currently allocated from the region.
+    pub fn used(&self) -> usize {
+        self.arena.lock().as_ref().map_or(0, |arena| arena.used)
+    }
+
+    /// Returns the most bytes ever allocated at once from the region.
+    pub fn peak(&self) -> usize {
+        self.arena.lock().as_ref().map_or(0, |arena| arena.peak)
+    }
+
+    /// Returns the size of the provisioned region, 0 before `provision`.
+    pub fn capacity(&self) -> usize {
+        self.arena
+            .lock()
+            .as_ref()
+            .map_or(0, |arena| arena.granules * GRANULE)
+    }
+}
+
+impl<H: Dma> Dma for StaticDma<H> {
+    unsafe fn alloc(&self, size: usize, align: usize) -> Option<usize> {
+        self.arena.lock().as_mut()?.alloc(size, align)
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize, _align: usize) {
+        if let Some(arena) = self.arena.lock().as_mut() {
+            arena.free(addr, size);
+        }
+    }
+
+    unsafe fn map_mmio(&self, phys: usize, size: usize) -> Option<usize> {
+        unsafe { self.host.map_mmio(phys, size) }
+    }
+
+    unsafe fn unmap_mmio(&self, virt: usize, size: usize) {
+        unsafe { self.host.unmap_mmio(virt, size) }
+    }
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        self.host.virt_to_phys(va)
+    }
+
+    fn page_size(&self) -> usize {
+        self.host.page_size()
+    }
+
+    fn delay_us(&self, us: u32) {
+        self.host.delay_us(us)
+    }
+
+    fn now_us(&self) -> Option<u64> {
+        self.host.now_us()
+    }
+
+    fn pci_read_config(&self, offset: u16) -> Option<u32> {
+        self.host.pci_read_config(offset)
+    }
+
+    fn pci_write_config(&self, offset: u16, val: u32) {
+        self.host.pci_write_config(offset, val)
+    }
+
+    fn wmb(&self) {
+        self.host.wmb()
+    }
+
+    fn warn(&self, args: core::fmt::Arguments<'_>) {
+        self.host.warn(args)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        dev::default_config_policy,
+        hid::{HidDevice, find_hid_interfaces},
+        mock::{self, MOCK_PHYS, MockHost, Reply},
+        msc::{MscDevice, find_msc_interfaces},
+        xhci::XhciBuilder,
+    };
+    use alloc::sync::Arc;
+
+    #[test]
+    fn nothing_is_allocated_after_provisioning() {
+        let mock = mock::Mock::new();
+        let host = StaticDma::new(MockHost::new(mock.clone()));
+        let mapped = XhciBuilder::map(MOCK_PHYS, host).unwrap();
+        let plan = mapped.memory_requirements(2, 2, 4096);
+        unsafe {
+            let region = mapped.host().inner().alloc(plan.bytes, plan.align).unwrap();
+            mapped.host().provision(region, plan.bytes).unwrap();
+        }
+        let allocs = mock.allocations();
+
+        let ctrl = Arc::new(mapped.take_ownership().unwrap().reset_and_start().unwrap());
+        mock.attach(0, mock::keyboard());
+        let disk = mock::bulk_only_disk(0x81, 0x02, 64);
+        mock.attach(1, mock::mass_storage().with_handler(disk));
+        let mut devices = ctrl.enumerate_concurrent_with(1, default_config_policy);
+        devices.sort_by_key(|(port, _)| *port);
+        let [(0, Ok(keyboard)), (1, Ok(storage))] = <[_; 2]>::try_from(devices).ok().unwrap()
+        else {
+            panic!("enumeration failed");
+        };
+
+        let (iface, ep) = find_hid_interfaces(&keyboard.config)[0];
+        let hid = HidDevice::from_interface(Arc::new(keyboard.device), &iface, &ep).unwrap();
+        hid.queue_read().unwrap();
+        mock.with_device(0, 0, |d| {
+            d.push_input(0x81, Reply::Data(alloc::vec![0, 0, 0x04, 0, 0, 0, 0, 0]));
+        });
+        let state = (0..100)
+            .find_map(|_| {
+                mock.advance_us(125);
+                hid.poll_keys()
+            })
+            .expect("key report");
+        assert!(state.is_pressed(0x04));
+
+        let (iface, ep_in, ep_out) = find_msc_interfaces(&storage.config)[0];
+        let device = Arc::new(storage.device);
+        let mut msc = MscDevice::from_interface(device, &iface, &ep_in, &ep_out).unwrap();
+        let mut block = [0u8; 512];
+        assert_eq!(msc.read_blocks(0, 7, 1, &mut block).unwrap(), 512);
+        assert!(block.iter().all(|&b| b == 7));
+
+        // Everything came out of the region
+        assert_eq!(mock.allocations(), allocs);
+        let host = ctrl.host();
+        assert!(host.used() > 0);
+        assert!(host.peak() <= host.capacity());
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+}
@@ -0,0 +1,238 @@
+//! Periodic bandwidth estimates.
+//!
+//! The controller reserves bus time for interrupt and isochronous
+//! endpoints when they are configured, and fails Configure Endpoint with
+//! a Bandwidth Error once it runs out. The helpers here repeat that
+//! arithmetic from descriptors, so a combination of devices can be
+//! checked before it is configured: `estimate` sums the bytes each
+//! service interval moves, and `XhciCtrl::periodic_load` does the same
+//! for the endpoints configured on a controller.
+//!
+//! The budgets are approximations. Controllers schedule with their own
+//! overheads, so an estimate within budget may still be refused.
+
+use crate::{
+    desc::{EndpointDesc, SsEpCompDesc, ep_type},
+    xhci::Speed,
+};
+
+/// Number of interval tiers: 2^0 to 2^15 times 125 us
+pub const TIERS: usize = 16;
+
+/// A periodic endpoint, as input to `estimate`.
+#[derive(Clone, Copy, Debug)]
+pub struct PeriodicEp {
+    /// Speed of the device
+    pub speed: Speed,
+    /// Endpoint descriptor
+    pub desc: EndpointDesc,
+    /// SuperSpeed Endpoint Companion, for SuperSpeed devices
+    pub companion: Option<SsEpCompDesc>,
+    /// Interval in the xHCI encoding, overriding bInterval as
+    /// `UsbDevice::configure_endpoint_with_interval` does
+    pub interval: Option<u8>,
+}
+
+impl PeriodicEp {
+    /// Describe an endpoint of a device running at `speed`
+    pub fn new(speed: Speed, desc: EndpointDesc, companion: Option<SsEpCompDesc>) -> Self {
+        Self {
+            speed,
+            desc,
+            companion,
+            interval: None,
+        }
+    }
+
+    /// Returns true for interrupt and isochronous endpoints.
+    pub fn is_periodic(&self) -> bool {
+        matches!(
+            self.desc.transfer_type(),
+            ep_type::ISOCHRONOUS | ep_type::INTERRUPT
+        )
+    }
+
+    /// Returns the service interval in the xHCI encoding.
+    ///
+    /// The endpoint is serviced every 2^interval * 125 microseconds.
+    pub fn interval(&self) -> u8 {
+        match self.interval.filter(|_| self.is_periodic()) {
+            Some(interval) => interval,
+            None => xhci_interval(self.speed, &self.desc),
+        }
+    }
+
+    /// Returns the bytes moved per service interval (Max ESIT Payload).
+    ///
+    /// Taken from the companion's wBytesPerInterval for SuperSpeed
+    /// endpoints; 0 for control and bulk endpoints.
+    pub fn esit_payload(&self) -> u32 {
+        esit_payload(&self.desc, self.companion.as_ref())
+    }
+}
+
+/// Periodic bytes by service interval, as returned by `estimate`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthEstimate {
+    /// Bytes per service interval of the endpoints in each interval tier;
+    /// tier n is serviced every 2^n * 125 us
+    pub tiers: [u32; TIERS],
+    /// Number of periodic endpoints counted
+    pub endpoints: usize,
+}
+
+impl BandwidthEstimate {
+    /// Count an endpoint moving `payload` bytes every 2^`interval` * 125 us
+    pub(crate) fn add(&mut self, interval: u8, payload: u32) {
+        if payload == 0 {
+            return;
+        }
+        let tier = (interval as usize).min(TIERS - 1);
+        self.tiers[tier] = self.tiers[tier].saturating_add(payload);
+        self.endpoints += 1;
+    }
+
+    /// Returns the bytes of the busiest bus interval.
+    ///
+    /// Assumes every endpoint is serviced in the same interval, as can
+    /// happen once all their periods line up.
+    pub fn peak_bytes(&self) -> u32 {
+        self.tiers
+            .iter()
+            .fold(0, |sum, &bytes| sum.saturating_add(bytes))
+    }
+
+    /// Returns the average periodic bytes per second.
+    pub fn bytes_per_second(&self) -> u64 {
+        self.tiers
+            .iter()
+            .enumerate()
+            .map(|(tier, &bytes)| (bytes as u64 * 8000) >> tier)
+            .sum()
+    }
+
+    /// Returns true if the peak fits the periodic budget of `speed`.
+    pub fn fits(&self, speed: Speed) -> bool {
+        self.peak_bytes() <= periodic_budget(speed)
+    }
+}
+
+/// Periodic bytes of the endpoints configured on a controller.
+///
+/// Returned by `XhciCtrl::periodic_load`. USB 2 and SuperSpeed endpoints
+/// use different wires, so they are counted apart.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LoadSummary {
+    /// Endpoints of Low, Full and High Speed devices
+    pub usb2: BandwidthEstimate,
+    /// Endpoints of SuperSpeed and SuperSpeedPlus devices
+    pub superspeed: BandwidthEstimate,
+    /// Number of devices with at least one periodic endpoint
+    pub devices: usize,
+}
+
+/// Sum the per-interval bytes of `endpoints`
+///
+/// Control and bulk endpoints are skipped.
+pub fn estimate(endpoints: &[PeriodicEp]) -> BandwidthEstimate {
+    let mut estimate = BandwidthEstimate::default();
+    for ep in endpoints.iter().filter(|ep| ep.is_periodic()) {
+        estimate.add(ep.interval(), ep.esit_payload());
+    }
+    estimate
+}
+
+/// Returns the periodic bytes the bus carries in one interval at `speed`.
+///
+/// The interval is a 1 ms frame at Low and Full Speed and a 125 us bus
+/// interval otherwise. USB 2 reserves 90% of a frame and 80% of a
+/// microframe for periodic transfers, SuperSpeed 90% of a bus interval.
+pub fn periodic_budget(speed: Speed) -> u32 {
+    match speed {
+        Speed::Low => 168,
+        Speed::Full => 1350,
+        Speed::High => 6000,
+        Speed::Super => 56250,
+        Speed::SuperPlus => 136363,
+        Speed::Unknown(_) => 0,
+    }
+}
+
+/// Default service interval of an endpoint, in the xHCI encoding
+pub(crate) fn xhci_interval(speed: Speed, ep: &EndpointDesc) -> u8 {
+    if !matches!(speed, Speed::Low | Speed::Full) {
+        return ep.interval.saturating_sub(1);
+    }
+    // For FS/LS, convert ms to 125us frames
+    // Use integer log2: find highest set bit
+    let ms = ep.interval.max(1) as u32;
+    let log2_ceil = if ms.is_power_of_two() {
+        ms.trailing_zeros() as u8
+    } else {
+        (u32::BITS - ms.leading_zeros()) as u8
+    };
+    log2_ceil + 3
+}
+
+/// Max ESIT Payload, from the companion for SuperSpeed periodic endpoints
+pub(crate) fn esit_payload(ep: &EndpointDesc, companion: Option<&SsEpCompDesc>) -> u32 {
+    let periodic = matches!(
+        ep.transfer_type(),
+        ep_type::ISOCHRONOUS | ep_type::INTERRUPT
+    );
+    match companion {
+        Some(comp) if periodic => comp.bytes_per_interval as u32,
+        _ => ep.esit_payload(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
+
+    fn ep(speed: Speed, address: u8, attributes: u8, max_packet: u16, interval: u8) -> PeriodicEp {
+        let bytes = mock::endpoint(address, attributes, max_packet, interval);
+        PeriodicEp::new(speed, EndpointDesc::from_bytes(&bytes).unwrap(), None)
+    }
+
+    #[test]
+    fn estimate_webcam_audio_keyboard() {
+        let endpoints = [
+            // High Speed webcam: 3 x 1024 bytes every microframe
+            ep(Speed::High, 0x81, 0x05, 1024 | 2 << 11, 1),
+            // Full Speed audio: 192 bytes every 1 ms frame
+            ep(Speed::Full, 0x82, 0x0d, 192, 1),
+            // Low Speed keyboard: 8 bytes every 10 ms, rounded to 16 ms
+            ep(Speed::Low, 0x83, 0x03, 8, 10),
+            // Bulk endpoints are not counted
+            ep(Speed::High, 0x04, 0x02, 512, 0),
+        ];
+        let estimate = estimate(&endpoints);
+
+        let mut tiers = [0; TIERS];
+        (tiers[0], tiers[3], tiers[7]) = (3072, 192, 8);
+        assert_eq!(estimate.tiers, tiers);
+        assert_eq!(estimate.endpoints, 3);
+        assert_eq!(estimate.peak_bytes(), 3272);
+        // 3072 * 8000 + 192 * 1000 + 8 * 62.5
+        assert_eq!(estimate.bytes_per_second(), 24_768_500);
+        assert!(estimate.fits(Speed::High));
+        assert!(!estimate.fits(Speed::Full));
+    }
+
+    #[test]
+    fn estimate_uses_interval_override_and_companion() {
+        let mut keyboard = ep(Speed::Low, 0x83, 0x03, 8, 10);
+        keyboard.interval = Some(4);
+        let mut camera = ep(Speed::Super, 0x81, 0x05, 1024, 1);
+        camera.companion = Some(SsEpCompDesc {
+            bytes_per_interval: 6144,
+            ..SsEpCompDesc::default()
+        });
+
+        let estimate = estimate(&[keyboard, camera]);
+        assert_eq!((estimate.tiers[0], estimate.tiers[4]), (6144, 8));
+        assert_eq!(estimate.bytes_per_second(), 6144 * 8000 + 8 * 500);
+    }
+}
@@ -0,0 +1,218 @@
+//! Mass storage throughput benchmark.
+//!
+//! Times sequential and random block I/O against a mass storage device
+//! and reports throughput, IOPS and latency percentiles, so slow-bus
+//! reports come with reproducible numbers.
+
+use crate::{
+    Result, UsbError,
+    msc::MscDevice,
+    ram::Dma,
+    xhci::{Stopwatch, XhciCtrl},
+};
+
+use alloc::{vec, vec::Vec};
+
+/// Order in which a benchmark visits the blocks of its range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BenchPattern {
+    /// Consecutive commands, wrapping to the start of the range
+    Sequential,
+    /// Commands at pseudo-random offsets aligned to the command size
+    Random {
+        /// Seed of the offset generator; the same seed gives the same offsets
+        seed: u64,
+    },
+}
+
+/// What a benchmark does to the device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BenchOp {
+    /// READ (10) within the plan's range
+    Read,
+    /// WRITE (10) within a scratch range the caller gives up
+    ///
+    /// The plan's range is ignored; the contents of the scratch range
+    /// are destroyed.
+    Write {
+        /// First block that may be overwritten; 0 is refused
+        scratch_lba: u32,
+        /// Number of blocks that may be overwritten
+        scratch_blocks: u32,
+    },
+}
+
+/// Parameters of `MscDevice::benchmark`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BenchPlan {
+    /// Reads, or writes to an explicit scratch range
+    pub op: BenchOp,
+    /// Sequential or random offsets
+    pub pattern: BenchPattern,
+    /// First block of the range read
+    pub start_lba: u32,
+    /// Number of blocks in the range read
+    pub num_blocks: u32,
+    /// Blocks moved per command (1 measures command overhead)
+    pub blocks_per_io: u16,
+    /// Number of commands to time
+    pub ios: u32,
+}
+
+impl BenchPlan {
+    /// Sequential reads of `blocks_per_io` blocks over a range.
+    pub fn sequential_read(start_lba: u32, num_blocks: u32, blocks_per_io: u16, ios: u32) -> Self {
+        Self {
+            op: BenchOp::Read,
+            pattern: BenchPattern::Sequential,
+            start_lba,
+            num_blocks,
+            blocks_per_io,
+            ios,
+        }
+    }
+}
+
+/// Outcome of `MscDevice::benchmark`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BenchReport {
+    /// Commands completed
+    pub ios: u32,
+    /// Bytes moved
+    pub bytes: u64,
+    /// Total time, in microseconds
+    pub elapsed_us: u64,
+    /// Fastest command, in microseconds
+    pub latency_min_us: u32,
+    /// Median command latency, in microseconds
+    pub latency_p50_us: u32,
+    /// 90th percentile command latency, in microseconds
+    pub latency_p90_us: u32,
+    /// 99th percentile command latency, in microseconds
+    pub latency_p99_us: u32,
+    /// Slowest command, in microseconds
+    pub latency_max_us: u32,
+}
+
+impl BenchReport {
+    /// Returns the throughput in bytes per second.
+    pub fn bytes_per_sec(&self) -> u64 {
+        (self.bytes as u128 * 1_000_000 / self.elapsed_us.max(1) as u128) as u64
+    }
+
+    /// Returns the commands completed per second.
+    pub fn iops(&self) -> u64 {
+        self.ios as u64 * 1_000_000 / self.elapsed_us.max(1)
+    }
+}
+
+/// Microsecond clock: the host's if it has one, else the frame counter
+///
+/// The frame counter wraps every 2 seconds, so it must be read at least
+/// that often.
+struct Clock {
+    host_start: Option<u64>,
+    watch: Stopwatch,
+}
+
+impl Clock {
+    fn start<H: Dma>(ctrl: &XhciCtrl<H>) -> Self {
+        Self {
+            host_start: ctrl.host().now_us(),
+            watch: ctrl.stopwatch(),
+        }
+    }
+
+    fn elapsed_us<H: Dma>(&mut self, ctrl: &XhciCtrl<H>) -> u64 {
+        let frames = self.watch.elapsed_us(ctrl);
+        match (self.host_start, ctrl.host().now_us()) {
+            (Some(start), Some(now)) => now.saturating_sub(start),
+            _ => frames,
+        }
+    }
+}
+
+impl<H: Dma> MscDevice<H> {
+    /// Times a block I/O pattern on a LUN.
+    ///
+    /// Only reads unless `plan.op` is `BenchOp::Write`, which overwrites
+    /// nothing but its scratch range. Every command must fit the unit;
+    /// ranges past its end, empty plans and a command larger than the
+    /// range fail with `InvalidArgument`. Timed with `Dma::now_us` when
+    /// the host provides it, else with the controller's frame counter.
+    pub fn benchmark(&mut self, lun: u8, plan: BenchPlan) -> Result<BenchReport> {
+        let (start, blocks) = match plan.op {
+            BenchOp::Read => (plan.start_lba, plan.num_blocks),
+            BenchOp::Write { scratch_lba: 0, .. } => return Err(UsbError::InvalidArgument),
+            BenchOp::Write {
+                scratch_lba,
+                scratch_blocks,
+            } => (scratch_lba, scratch_blocks),
+        };
+        let per_io = plan.blocks_per_io as u32;
+        if plan.ios == 0 || per_io == 0 || blocks < per_io {
+            return Err(UsbError::InvalidArgument);
+        }
+        if start.checked_add(blocks).is_none() {
+            return Err(UsbError::InvalidArgument);
+        }
+
+        let block_size = self.load_geometry(lun)?.block_size as usize;
+        let mut buf = vec![0u8; per_io as usize * block_size];
+        if let BenchOp::Write { .. } = plan.op {
+            for (i, byte) in buf.iter_mut().enumerate() {
+                *byte = i as u8;
+            }
+        }
+
+        // Offsets are whole commands into the range
+        let slots = (blocks / per_io) as u64;
+        let mut rng = match plan.pattern {
+            BenchPattern::Random { seed } => seed | 1,
+            BenchPattern::Sequential => 0,
+        };
+
+        let ctrl = self.device().ctrl().clone();
+        let mut latencies: Vec<u32> = Vec::with_capacity(plan.ios as usize);
+        let mut total = Clock::start(&ctrl);
+        for i in 0..plan.ios {
+            let slot = match plan.pattern {
+                BenchPattern::Sequential => i as u64 % slots,
+                BenchPattern::Random { .. } => {
+                    // xorshift64
+                    rng ^= rng << 13;
+                    rng ^= rng >> 7;
+                    rng ^= rng << 17;
+                    rng % slots
+                }
+            };
+            let lba = start + slot as u32 * per_io;
+
+            let mut clock = Clock::start(&ctrl);
+            match plan.op {
+                BenchOp::Read => self.read_blocks(lun, lba, plan.blocks_per_io, &mut buf)?,
+                BenchOp::Write { .. } => {
+                    self.write_blocks(lun, lba, plan.blocks_per_io, &mut buf)?
+                }
+            };
+            let latency = clock.elapsed_us(&ctrl);
+            latencies.push(latency.min(u32::MAX as u64) as u32);
+            // Keep the frame counter fallback from wrapping
+            total.elapsed_us(&ctrl);
+        }
+        let elapsed_us = total.elapsed_us(&ctrl);
+
+        latencies.sort_unstable();
+        let percentile = |p: usize| latencies[(latencies.len() - 1) * p / 100];
+        Ok(BenchReport {
+            ios: plan.ios,
+            bytes: plan.ios as u64 * buf.len() as u64,
+            elapsed_us,
+            latency_min_us: percentile(0),
+            latency_p50_us: percentile(50),
+            latency_p90_us: percentile(90),
+            latency_p99_us: percentile(99),
+            latency_max_us: percentile(100),
+        })
+    }
+}
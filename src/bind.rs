@@ -0,0 +1,281 @@
+//! Class driver registry.
+//!
+//! Matches the interfaces of enumerated devices against class drivers
+//! and creates a driver for every interface one of them claims, so a
+//! kernel gets ready-to-use keyboards, mice and disks out of a single
+//! enumeration pass.
+//!
+//! # Features
+//!
+//! - Built-in drivers for HID and mass storage (Bulk-Only and CBI)
+//! - User drivers through the `ClassDriver` trait, probed before the
+//!   built-in ones
+//! - One entry per bound interface; composite devices share their
+//!   `UsbDevice`
+
+use crate::{
+    Dma, Result, UsbError,
+    cbi::CbiDevice,
+    desc::{ConfigTree, EndpointDesc, FoundInterface, InterfaceDesc, class, ep_type, msc_protocol},
+    dev::{DevicePath, UsbDevice},
+    hid::HidDevice,
+    msc::MscDevice,
+    xhci::XhciCtrl,
+};
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use core::any::Any;
+
+/// Interface claimed by a `ClassDriver`, passed back to its `bind`.
+#[derive(Clone, Debug)]
+pub struct Binding {
+    /// Alternate setting to drive
+    pub interface: InterfaceDesc,
+    /// Endpoints of that setting the driver uses, in driver-defined order
+    pub endpoints: Vec<EndpointDesc>,
+}
+
+/// Driver created by `ClassDriver::bind`.
+///
+/// Drivers are boxed, as their transfer state makes them large.
+pub enum Driver<H: Dma> {
+    /// HID keyboard, mouse or other HID interface
+    Hid(Box<HidDevice<H>>),
+    /// Bulk-Only mass storage
+    Msc(Box<MscDevice<H>>),
+    /// CBI mass storage
+    Cbi(Box<CbiDevice<H>>),
+    /// Driver of a user `ClassDriver`, to be downcast by its owner
+    Custom(Box<dyn Any>),
+}
+
+/// Class driver that can claim interfaces of an enumerated device.
+pub trait ClassDriver<H: Dma> {
+    /// Checks one interface of the active configuration
+    ///
+    /// Returns the setting and endpoints to drive if the interface is
+    /// handled by this driver. `config` gives access to the rest of the
+    /// configuration, e.g. for drivers spanning an Interface Association.
+    fn probe(&self, config: &ConfigTree, interface: &FoundInterface) -> Option<Binding>;
+
+    /// Creates the driver for an interface claimed by `probe`
+    fn bind(&self, device: Arc<UsbDevice<H>>, binding: Binding) -> Result<Driver<H>>;
+}
+
+/// Built-in driver for HID interfaces with an Interrupt IN endpoint.
+///
+/// A read is queued on the bound device, so it can be polled right away.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct HidClassDriver;
+
+impl<H: Dma> ClassDriver<H> for HidClassDriver {
+    fn probe(&self, _config: &ConfigTree, interface: &FoundInterface) -> Option<Binding> {
+        interface.settings.iter().find_map(|setting| {
+            if setting.iface.interface_class != class::HID {
+                return None;
+            }
+            let ep = setting
+                .endpoints
+                .iter()
+                .find(|ep| ep.is_in() && ep.transfer_type() == ep_type::INTERRUPT)?;
+            Some(Binding {
+                interface: setting.iface,
+                endpoints: alloc::vec![*ep],
+            })
+        })
+    }
+
+    fn bind(&self, device: Arc<UsbDevice<H>>, binding: Binding) -> Result<Driver<H>> {
+        let ep_in = binding.endpoints.first().ok_or(UsbError::InvEndpoint)?;
+        let hid = HidDevice::from_interface(device, &binding.interface, ep_in)?;
+        hid.queue_read()?;
+        Ok(Driver::Hid(Box::new(hid)))
+    }
+}
+
+/// Built-in driver for Bulk-Only and CBI mass storage interfaces.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MscClassDriver;
+
+impl<H: Dma> ClassDriver<H> for MscClassDriver {
+    fn probe(&self, _config: &ConfigTree, interface: &FoundInterface) -> Option<Binding> {
+        interface.settings.iter().find_map(|setting| {
+            let iface = &setting.iface;
+            let cbi = match iface.interface_protocol {
+                msc_protocol::BBB => false,
+                msc_protocol::CBI_INTERRUPT | msc_protocol::CBI_NO_INTERRUPT => true,
+                _ => return None,
+            };
+            if iface.interface_class != class::MASS_STORAGE {
+                return None;
+            }
+
+            let find = |kind: u8, is_in: bool| {
+                setting
+                    .endpoints
+                    .iter()
+                    .find(|ep| ep.transfer_type() == kind && ep.is_in() == is_in)
+                    .copied()
+            };
+            let mut endpoints =
+                alloc::vec![find(ep_type::BULK, true)?, find(ep_type::BULK, false)?];
+            if cbi {
+                endpoints.extend(find(ep_type::INTERRUPT, true));
+            }
+            Some(Binding {
+                interface: *iface,
+                endpoints,
+            })
+        })
+    }
+
+    fn bind(&self, device: Arc<UsbDevice<H>>, binding: Binding) -> Result<Driver<H>> {
+        let Binding {
+            interface,
+            endpoints,
+        } = binding;
+        let [ep_in, ep_out, ep_int @ ..] = endpoints.as_slice() else {
+            return Err(UsbError::InvEndpoint);
+        };
+
+        if interface.interface_protocol == msc_protocol::BBB {
+            MscDevice::from_interface(device, &interface, ep_in, ep_out)
+                .map(|msc| Driver::Msc(Box::new(msc)))
+        } else {
+            CbiDevice::from_interface(device, &interface, ep_in, ep_out, ep_int.first())
+                .map(|cbi| Driver::Cbi(Box::new(cbi)))
+        }
+    }
+}
+
+/// Ordered set of class drivers.
+pub struct DriverRegistry<H: Dma> {
+    /// Probed front to back
+    drivers: Vec<Box<dyn ClassDriver<H>>>,
+}
+
+impl<H: Dma> DriverRegistry<H> {
+    /// Creates a registry without any drivers
+    pub fn new() -> Self {
+        Self {
+            drivers: Vec::new(),
+        }
+    }
+
+    /// Creates a registry with the built-in HID and mass storage drivers
+    pub fn with_builtin() -> Self {
+        let mut registry = Self::new();
+        registry.drivers.push(Box::new(HidClassDriver));
+        registry.drivers.push(Box::new(MscClassDriver));
+        registry
+    }
+
+    /// Adds a driver, probed before every driver added earlier
+    ///
+    /// User drivers registered after `with_builtin` thus take precedence
+    /// over the built-in ones.
+    pub fn register(&mut self, driver: Box<dyn ClassDriver<H>>) {
+        self.drivers.insert(0, driver);
+    }
+
+    /// Binds drivers to the interfaces of a configured device
+    ///
+    /// Each interface of the active configuration goes to the first
+    /// driver whose `probe` claims it. Returns one entry per bound
+    /// interface, a `Failed` entry for every `bind` that failed, and a
+    /// single `Unbound` entry if no driver bound to any interface.
+    pub fn bind_device(&self, device: Arc<UsbDevice<H>>) -> Vec<BoundDevice<H>> {
+        let path = device.path();
+        let config = match device.config_tree() {
+            Ok(config) => config,
+            Err(error) => return alloc::vec![BoundDevice::Failed { path, error }],
+        };
+
+        let mut bound = Vec::new();
+        let mut any_bound = false;
+        for interface in &config.interfaces {
+            let claimed = self
+                .drivers
+                .iter()
+                .find_map(|driver| Some((driver, driver.probe(&config, interface)?)));
+            let Some((driver, binding)) = claimed else {
+                continue;
+            };
+            match driver.bind(device.clone(), binding) {
+                Ok(driver) => {
+                    any_bound = true;
+                    bound.push(BoundDevice::Bound(driver));
+                }
+                Err(error) => bound.push(BoundDevice::Failed { path, error }),
+            }
+        }
+
+        if !any_bound {
+            bound.push(BoundDevice::Unbound { device, config });
+        }
+        bound
+    }
+}
+
+impl<H: Dma> Default for DriverRegistry<H> {
+    fn default() -> Self {
+        Self::with_builtin()
+    }
+}
+
+/// Outcome of binding drivers, as returned by `XhciCtrl::enumerate_and_bind`.
+pub enum BoundDevice<H: Dma> {
+    /// Driver bound to one interface
+    Bound(Driver<H>),
+    /// Configured device none of whose interfaces was claimed
+    Unbound {
+        /// The device, ready for a driver outside the registry
+        device: Arc<UsbDevice<H>>,
+        /// Its active configuration
+        config: Arc<ConfigTree>,
+    },
+    /// Hub driven by `UsbMonitor`, whose downstream devices are reported
+    /// on their own
+    ///
+    /// `XhciCtrl::enumerate_and_bind` reports hubs as `Unbound`.
+    Hub {
+        /// The hub device
+        device: Arc<UsbDevice<H>>,
+    },
+    /// Enumeration of a port or the `bind` of a claimed interface failed
+    Failed {
+        /// Port the device is attached to
+        path: DevicePath,
+        /// Error of the failed step
+        error: UsbError,
+    },
+}
+
+impl<H: Dma> XhciCtrl<H> {
+    /// Enumerate every connected root port and bind drivers from `registry`
+    ///
+    /// Devices are brought up as by `enumerate_concurrent`, then passed
+    /// to `DriverRegistry::bind_device`. A device whose enumeration
+    /// fails yields a single `Failed` entry.
+    pub fn enumerate_and_bind(
+        self: &Arc<Self>,
+        max_in_flight: usize,
+        registry: &DriverRegistry<H>,
+    ) -> Vec<BoundDevice<H>> {
+        let mut bound = Vec::new();
+        for (port, result) in self.enumerate_concurrent(max_in_flight) {
+            match result {
+                Ok(enumerated) => bound.extend(registry.bind_device(Arc::new(enumerated.device))),
+                Err(error) => {
+                    let path = DevicePath {
+                        bus: self.bus_index(),
+                        root_port: port,
+                        route: 0,
+                    };
+                    bound.push(BoundDevice::Failed { path, error });
+                }
+            }
+        }
+        bound
+    }
+}
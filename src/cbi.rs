@@ -0,0 +1,588 @@
+//! Control/Bulk/Interrupt (CBI) transport for mass storage devices.
+//!
+//! Older USB floppy drives and some card readers speak CBI instead of
+//! Bulk-Only: commands go over the default control pipe as an Accept
+//! Device-Specific Command (ADSC) request, data over the bulk pipes and
+//! the status, where supported, over an interrupt IN pipe.
+//!
+//! # Features
+//!
+//! - The SCSI command surface of `MscDevice` over CBI
+//! - UFI and generic interrupt status decoding
+//! - Command Block Reset for aborting a command
+
+use crate::{
+    Dma, Result, UsbError,
+    desc::{EndpointDesc, InterfaceDesc, SetupPacket, class, feature, msc_protocol, msc_subclass},
+    dev::{DriverKind, EndpointHandle, UsbDevice},
+    msc::{
+        CommandClass, Csw, DataPhase, InquiryData, MscDevice, ReadCapacity10Data, RequestSenseData,
+        ScsiResult, scsi_op,
+    },
+    ring::PhysMem,
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+/// Length of a UFI command block; shorter CDBs are zero-padded
+const UFI_CDB_LEN: usize = 12;
+/// Highest LUN, carried in bits 7:5 of the second CDB byte
+const MAX_LUN: u8 = 7;
+/// Length of the sense data REQUEST SENSE asks for
+const SENSE_LEN: usize = 18;
+
+/// Interrupt data block of a CBI command completion.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CbiStatus {
+    /// UFI status: additional sense code and qualifier, 0/0 on success
+    Ufi {
+        /// Additional sense code
+        asc: u8,
+        /// Additional sense code qualifier
+        ascq: u8,
+    },
+    /// Command completion status of other command sets
+    Completion {
+        /// bType, 0 for a command completion
+        kind: u8,
+        /// bValue; bits 1:0 hold the status
+        value: u8,
+    },
+}
+
+impl CbiStatus {
+    /// Decodes the 2-byte interrupt data block.
+    pub fn from_bytes(data: [u8; 2], ufi: bool) -> Self {
+        if ufi {
+            Self::Ufi {
+                asc: data[0],
+                ascq: data[1],
+            }
+        } else {
+            Self::Completion {
+                kind: data[0],
+                value: data[1],
+            }
+        }
+    }
+
+    /// Returns the status as a CSW status (see `Csw::STATUS_*`).
+    ///
+    /// A persistent failure counts as a failed command; anything but a
+    /// command completion interrupt is a phase error.
+    pub fn csw_status(&self) -> u8 {
+        match *self {
+            Self::Ufi { asc: 0, ascq: 0 } => Csw::STATUS_PASSED,
+            Self::Ufi { .. } => Csw::STATUS_FAILED,
+            Self::Completion { kind: 0, value } => match value & 0x3 {
+                0 => Csw::STATUS_PASSED,
+                2 => Csw::STATUS_PHASE_ERROR,
+                _ => Csw::STATUS_FAILED,
+            },
+            Self::Completion { .. } => Csw::STATUS_PHASE_ERROR,
+        }
+    }
+}
+
+/// USB Mass Storage device using the CBI transport.
+pub struct CbiDevice<H: Dma> {
+    device: Arc<UsbDevice<H>>,
+    interface: u8,
+    /// bInterfaceSubClass, selecting the command set
+    subclass: u8,
+    ep_in: EndpointHandle<H>,
+    ep_out: EndpointHandle<H>,
+    /// Command completion interrupt pipe (protocol 0x00 only)
+    ep_int: Option<EndpointHandle<H>>,
+    ep_descs: Vec<EndpointDesc>,
+    read_only: bool,
+    max_transfer: usize,
+}
+
+impl<H: Dma> CbiDevice<H> {
+    /// Creates a CBI device from interface and endpoint descriptors.
+    ///
+    /// `ep_int` is the interrupt IN endpoint; it is required for the
+    /// CBI protocol with command completion interrupt and ignored
+    /// otherwise.
+    pub fn from_interface(
+        device: Arc<UsbDevice<H>>,
+        iface: &InterfaceDesc,
+        ep_in: &EndpointDesc,
+        ep_out: &EndpointDesc,
+        ep_int: Option<&EndpointDesc>,
+    ) -> Result<Self> {
+        if iface.interface_class != class::MASS_STORAGE {
+            return Err(UsbError::NotSupported);
+        }
+        let ep_int = match iface.interface_protocol {
+            msc_protocol::CBI_INTERRUPT => Some(*ep_int.ok_or(UsbError::InvEndpoint)?),
+            msc_protocol::CBI_NO_INTERRUPT => None,
+            _ => return Err(UsbError::NotSupported),
+        };
+
+        let mut ep_descs = alloc::vec![*ep_in, *ep_out];
+        ep_descs.extend(ep_int);
+        let mut handles = device.configure_endpoints(&ep_descs)?.into_iter();
+        let (Some(ep_in_handle), Some(ep_out_handle)) = (handles.next(), handles.next()) else {
+            return Err(UsbError::InvEndpoint);
+        };
+
+        device.bind_driver(DriverKind::MassStorage);
+        Ok(Self {
+            device,
+            interface: iface.interface_number,
+            subclass: iface.interface_subclass,
+            ep_in: ep_in_handle,
+            ep_out: ep_out_handle,
+            ep_int: handles.next(),
+            ep_descs,
+            read_only: false,
+            max_transfer: u32::MAX as usize,
+        })
+    }
+
+    /// Reconfigure the device after `UsbDevice::reset_and_restore`
+    ///
+    /// Re-creates the bulk and interrupt endpoints.
+    pub fn reinit(&mut self) -> Result<()> {
+        let mut handles = self.device.configure_endpoints(&self.ep_descs)?.into_iter();
+        let (Some(ep_in), Some(ep_out)) = (handles.next(), handles.next()) else {
+            return Err(UsbError::InvEndpoint);
+        };
+        self.ep_in = ep_in;
+        self.ep_out = ep_out;
+        self.ep_int = handles.next();
+        Ok(())
+    }
+
+    /// Returns the maximum LUN number.
+    ///
+    /// CBI has no GET_MAX_LUN; the LUN is carried in the CDB, which
+    /// leaves room for LUNs 0 to 7.
+    pub fn max_lun(&self) -> u8 {
+        MAX_LUN
+    }
+
+    /// Issues a Command Block Reset.
+    ///
+    /// Aborts the command in progress and returns the device to the
+    /// state in which it accepts a new command.
+    pub fn reset(&self) -> Result<()> {
+        let mut cdb = [0xFFu8; UFI_CDB_LEN];
+        cdb[0] = scsi_op::SEND_DIAGNOSTIC;
+        cdb[1] = 0x04; // SelfTest
+        self.send_command(&mut cdb)
+    }
+
+    /// Aborts the command in progress.
+    ///
+    /// Drops the transfers left on every pipe, issues a Command Block
+    /// Reset and clears the halt on both bulk endpoints.
+    pub fn reset_recovery(&self) -> Result<()> {
+        for ep in [Some(&self.ep_in), Some(&self.ep_out), self.ep_int.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            ep.restart()?;
+            // Discard the Stopped event of the aborted transfer
+            while ep.poll().is_some() {}
+        }
+
+        self.reset()?;
+        for desc in &self.ep_descs[..2] {
+            self.clear_halt(desc)?;
+        }
+        Ok(())
+    }
+
+    /// Opens the device read-only, or lifts the restriction.
+    ///
+    /// Works as `MscDevice::set_read_only`.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Returns true if the device was opened read-only.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Sets the largest data phase `pass_through` accepts, in bytes.
+    ///
+    /// Rounded down to a multiple of the bulk IN packet size.
+    pub fn set_max_transfer(&mut self, bytes: usize) {
+        let granularity = self.bulk_max_packet();
+        self.max_transfer = if bytes >= granularity {
+            bytes - bytes % granularity
+        } else {
+            bytes
+        };
+    }
+
+    /// Returns the largest data phase `pass_through` accepts, in bytes.
+    pub fn max_transfer(&self) -> usize {
+        self.max_transfer
+    }
+
+    /// Returns the max packet size of the bulk IN endpoint in bytes.
+    pub fn bulk_max_packet(&self) -> usize {
+        self.device.transfer_granularity(&self.ep_descs[0])
+    }
+
+    /// Executes a SCSI command with the default deadline.
+    ///
+    /// A status other than passed is returned as `XferFail`.
+    pub fn scsi_command(
+        &mut self,
+        lun: u8,
+        cdb: &[u8],
+        data: Option<&mut [u8]>,
+        direction_in: bool,
+    ) -> Result<usize> {
+        let timeout_us = MscDevice::<H>::default_timeout_us(cdb);
+        self.scsi_command_timeout(lun, cdb, data, direction_in, timeout_us)
+    }
+
+    /// Executes a SCSI command that must complete within `timeout_us`.
+    ///
+    /// See `pass_through` for the deadline; a status other than passed
+    /// is returned as `XferFail`.
+    pub fn scsi_command_timeout(
+        &mut self,
+        lun: u8,
+        cdb: &[u8],
+        data: Option<&mut [u8]>,
+        direction_in: bool,
+        timeout_us: u32,
+    ) -> Result<usize> {
+        let data = match data {
+            Some(d) if direction_in => DataPhase::In(d),
+            Some(d) => DataPhase::Out(d),
+            None => DataPhase::None,
+        };
+        let result = self.pass_through(lun, cdb, data, timeout_us)?;
+        if result.status != Csw::STATUS_PASSED {
+            return Err(UsbError::XferFail(result.status));
+        }
+        Ok(result.transferred)
+    }
+
+    /// Sends an arbitrary CDB and reports the outcome.
+    ///
+    /// `lun` must not exceed `max_lun` and is written to bits 7:5 of the
+    /// second CDB byte. The CDB must be 1 to 12 bytes and the data phase
+    /// no longer than `max_transfer`; UFI command blocks are padded to
+    /// 12 bytes. Commands that may write fail with `ReadOnly` on a
+    /// read-only device.
+    ///
+    /// The status comes from the interrupt pipe if the device has one;
+    /// otherwise a command passes unless the device stalls it. A failed
+    /// command is reported with the sense data fetched by REQUEST SENSE.
+    /// On a timeout or a phase error the command is aborted with
+    /// `reset_recovery`.
+    pub fn pass_through(
+        &mut self,
+        lun: u8,
+        cdb: &[u8],
+        data: DataPhase<'_>,
+        timeout_us: u32,
+    ) -> Result<ScsiResult> {
+        if lun > MAX_LUN {
+            return Err(UsbError::InvLun);
+        }
+        if !(1..=UFI_CDB_LEN).contains(&cdb.len()) || data.len() > self.max_transfer {
+            return Err(UsbError::InvalidArgument);
+        }
+        if self.read_only && CommandClass::of(cdb) == CommandClass::Write {
+            return Err(UsbError::ReadOnly);
+        }
+
+        let mut block = [0u8; UFI_CDB_LEN];
+        block[..cdb.len()].copy_from_slice(cdb);
+        if lun != 0 && cdb.len() > 1 {
+            block[1] = (block[1] & 0x1F) | (lun << 5);
+        }
+        let len = if self.is_ufi() {
+            UFI_CDB_LEN
+        } else {
+            cdb.len()
+        };
+
+        let (transferred, status) = match self.execute(&mut block[..len], data, timeout_us) {
+            Ok(done) => done,
+            Err(e) => {
+                if self.device.recover_from_errors()? {
+                    self.reinit()?;
+                }
+                return Err(e);
+            }
+        };
+        let raw_sense = match status {
+            Csw::STATUS_PASSED => None,
+            Csw::STATUS_FAILED if cdb[0] != scsi_op::REQUEST_SENSE => self.fetch_sense(lun).ok(),
+            Csw::STATUS_FAILED => None,
+            _ => {
+                self.reset_recovery()?;
+                None
+            }
+        };
+
+        Ok(ScsiResult {
+            transferred,
+            status,
+            sense: raw_sense.map(|raw| RequestSenseData::from_bytes(&raw)),
+            raw_sense: raw_sense.unwrap_or_default(),
+        })
+    }
+
+    /// Run a command; returns the bytes moved and the status
+    fn execute(
+        &mut self,
+        cdb: &mut [u8],
+        data: DataPhase<'_>,
+        timeout_us: u32,
+    ) -> Result<(usize, u8)> {
+        let ctrl = self.device.ctrl().clone();
+        let data_len = data.len();
+        let data_buf = if data_len > 0 {
+            Some(ctrl.alloc_mem(data_len, 64)?)
+        } else {
+            None
+        };
+        let status_len = self
+            .ep_descs
+            .get(2)
+            .map_or(2, |desc| self.device.transfer_granularity(desc));
+        let status_buf = ctrl.alloc_mem(status_len.max(2), 64)?;
+
+        let result = self.run_command(cdb, data_buf.as_ref(), &status_buf, data, timeout_us);
+
+        // Transfers still on the rings point into the buffers
+        let recovered = match result {
+            Err(UsbError::Timeout | UsbError::EventLost) => self.reset_recovery().is_ok(),
+            _ => true,
+        };
+        if recovered {
+            let host = ctrl.host();
+            status_buf.free(host);
+            if let Some(buf) = data_buf {
+                buf.free(host);
+            }
+        }
+
+        result
+    }
+
+    /// Run the command, data and status stages of a command
+    fn run_command(
+        &mut self,
+        cdb: &mut [u8],
+        data_buf: Option<&PhysMem<H>>,
+        status_buf: &PhysMem<H>,
+        data: DataPhase<'_>,
+        timeout_us: u32,
+    ) -> Result<(usize, u8)> {
+        let ctrl = self.device.ctrl().clone();
+        let mut watch = ctrl.stopwatch();
+
+        // A stalled ADSC means the device rejected the command; no
+        // status interrupt follows
+        match self.send_command(cdb) {
+            Ok(()) => {}
+            Err(UsbError::Stall) => return Ok((0, Csw::STATUS_FAILED)),
+            Err(e) => return Err(e),
+        }
+
+        let (transferred, stalled) = match (data_buf, data) {
+            (Some(buf), DataPhase::In(d)) => {
+                // IN: device to host
+                unsafe {
+                    core::ptr::write_bytes(buf.as_ptr::<u8>(), 0, d.len());
+                }
+                buf.poison(d.len());
+                self.ep_in.queue(buf, d.len())?;
+                let wait =
+                    MscDevice::wait_transfer(&ctrl, &self.ep_in, d.len(), &mut watch, timeout_us);
+                let (len, stalled) = self.data_outcome(wait, 0)?;
+                if !stalled {
+                    buf.check_in(len, d.len());
+                }
+                unsafe {
+                    core::ptr::copy_nonoverlapping(buf.as_ptr::<u8>(), d.as_mut_ptr(), len);
+                }
+                d[len..].fill(0);
+                (len, stalled)
+            }
+            (Some(buf), DataPhase::Out(d)) => {
+                // OUT: host to device
+                unsafe {
+                    core::ptr::copy_nonoverlapping(d.as_ptr(), buf.as_ptr(), d.len());
+                }
+                self.ep_out.queue(buf, d.len())?;
+                let wait =
+                    MscDevice::wait_transfer(&ctrl, &self.ep_out, d.len(), &mut watch, timeout_us);
+                let outcome = self.data_outcome(wait, 1)?;
+                buf.check_out(d);
+                outcome
+            }
+            _ => (0, false),
+        };
+
+        let status = self.read_status(status_buf, timeout_us)?;
+        // Without a status pipe, a stalled data stage is the only failure signal
+        let status = if stalled && status == Csw::STATUS_PASSED {
+            Csw::STATUS_FAILED
+        } else {
+            status
+        };
+        Ok((transferred, status))
+    }
+
+    /// Bytes moved by a data stage, and whether the device stalled it
+    ///
+    /// A stalled bulk endpoint is cleared so the next command can use it.
+    fn data_outcome(&self, wait: Result<usize>, desc: usize) -> Result<(usize, bool)> {
+        match wait {
+            Ok(len) => Ok((len, false)),
+            Err(UsbError::Stall) => {
+                self.clear_halt(&self.ep_descs[desc])?;
+                Ok((0, true))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Read the command completion interrupt; passed without a status pipe
+    fn read_status(&self, status_buf: &PhysMem<H>, timeout_us: u32) -> Result<u8> {
+        let Some(ep_int) = &self.ep_int else {
+            return Ok(Csw::STATUS_PASSED);
+        };
+
+        let len = status_buf.size();
+        status_buf.poison(len);
+        ep_int.queue(status_buf, len)?;
+        let evt = ep_int.wait(timeout_us)?;
+        status_buf.check_in(evt.transferred(len), len);
+        if evt.transferred(len) < 2 {
+            return Ok(Csw::STATUS_PHASE_ERROR);
+        }
+
+        let mut raw = [0u8; 2];
+        unsafe {
+            core::ptr::copy_nonoverlapping(status_buf.as_ptr::<u8>(), raw.as_mut_ptr(), 2);
+        }
+        Ok(CbiStatus::from_bytes(raw, self.is_ufi()).csw_status())
+    }
+
+    /// Send a command block with the ADSC request
+    fn send_command(&self, cdb: &mut [u8]) -> Result<()> {
+        let setup = SetupPacket::cbi_adsc(self.interface, cdb.len() as u16);
+        self.device.control_transfer(&setup, Some(cdb))?;
+        Ok(())
+    }
+
+    /// Reset a bulk endpoint and clear its halt on the device
+    fn clear_halt(&self, desc: &EndpointDesc) -> Result<()> {
+        let ep = if desc.is_in() {
+            &self.ep_in
+        } else {
+            &self.ep_out
+        };
+        ep.restart()?;
+        let setup =
+            SetupPacket::clear_endpoint_feature(feature::ENDPOINT_HALT, desc.endpoint_address);
+        self.device.control_transfer(&setup, None)?;
+        Ok(())
+    }
+
+    /// True if the device speaks the UFI command set
+    fn is_ufi(&self) -> bool {
+        self.subclass == msc_subclass::UFI
+    }
+
+    /// Sends TEST UNIT READY command.
+    pub fn test_unit_ready(&mut self, lun: u8) -> Result<bool> {
+        let cdb = [scsi_op::TEST_UNIT_READY, 0, 0, 0, 0, 0];
+        match self.scsi_command(lun, &cdb, None, false) {
+            Ok(_) => Ok(true),
+            Err(UsbError::XferFail(1)) => Ok(false), // Command failed
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends INQUIRY command.
+    pub fn inquiry(&mut self, lun: u8) -> Result<InquiryData> {
+        let cdb = [scsi_op::INQUIRY, 0, 0, 0, 36, 0];
+        let mut data = [0u8; 36];
+        self.scsi_command(lun, &cdb, Some(&mut data), true)?;
+        Ok(unsafe { *(data.as_ptr() as *const InquiryData) })
+    }
+
+    /// Sends READ CAPACITY (10) command.
+    pub fn read_capacity(&mut self, lun: u8) -> Result<ReadCapacity10Data> {
+        let cdb = [scsi_op::READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut data = [0u8; 8];
+        self.scsi_command(lun, &cdb, Some(&mut data), true)?;
+        Ok(unsafe { *(data.as_ptr() as *const ReadCapacity10Data) })
+    }
+
+    /// Sends REQUEST SENSE command.
+    pub fn request_sense(&mut self, lun: u8) -> Result<RequestSenseData> {
+        let raw = self.fetch_sense(lun)?;
+        Ok(RequestSenseData::from_bytes(&raw))
+    }
+
+    /// Send REQUEST SENSE and return the sense data as is
+    fn fetch_sense(&mut self, lun: u8) -> Result<[u8; 32]> {
+        let mut data = [0u8; 32];
+        let cdb = [scsi_op::REQUEST_SENSE, 0, 0, 0, SENSE_LEN as u8, 0];
+        self.scsi_command(lun, &cdb, Some(&mut data[..SENSE_LEN]), true)?;
+        Ok(data)
+    }
+
+    /// Reads blocks from the device (READ 10).
+    pub fn read_blocks(&mut self, lun: u8, lba: u32, count: u16, buf: &mut [u8]) -> Result<usize> {
+        let cdb = rw10_cdb(scsi_op::READ_10, lba, count);
+        self.scsi_command(lun, &cdb, Some(buf), true)
+    }
+
+    /// Writes blocks to the device (WRITE 10).
+    pub fn write_blocks(&mut self, lun: u8, lba: u32, count: u16, buf: &mut [u8]) -> Result<usize> {
+        let cdb = rw10_cdb(scsi_op::WRITE_10, lba, count);
+        self.scsi_command(lun, &cdb, Some(buf), false)
+    }
+
+    /// Returns a reference to the underlying USB device.
+    pub fn device(&self) -> &Arc<UsbDevice<H>> {
+        &self.device
+    }
+
+    /// Returns the interface number.
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+}
+
+impl<H: Dma> Drop for CbiDevice<H> {
+    /// Stops every endpoint, so transfers left behind by a command whose
+    /// recovery failed do not outlive the driver
+    fn drop(&mut self) {
+        self.device.unbind_driver(DriverKind::MassStorage);
+        for ep in [Some(&self.ep_in), Some(&self.ep_out), self.ep_int.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            let _ = ep.abort();
+        }
+    }
+}
+
+/// Build a READ (10) or WRITE (10) CDB
+fn rw10_cdb(opcode: u8, lba: u32, count: u16) -> [u8; 10] {
+    let mut cdb = [0u8; 10];
+    cdb[0] = opcode;
+    cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cdb[7..9].copy_from_slice(&count.to_be_bytes());
+    cdb
+}
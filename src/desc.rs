@@ -3,6 +3,20 @@
 //! This module provides all standard USB descriptor types, class codes,
 //! and related constants as defined in the USB 2.0 and USB 3.x specifications.
 
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Reads a little-endian u16 at `offset`.
+pub(crate) fn le16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+/// Reads a little-endian u32 at `offset`.
+pub(crate) fn le32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
 /// USB descriptor type constants.
 pub mod desc_type {
     /// Device descriptor (18 bytes)
@@ -96,6 +110,35 @@ pub mod class {
     pub const APPLICATION_SPECIFIC: u8 = 0xFE;
     /// Vendor specific class
     pub const VENDOR_SPECIFIC: u8 = 0xFF;
+
+    /// Returns a human-readable name for the class code.
+    pub const fn name(code: u8) -> &'static str {
+        match code {
+            INTERFACE_SPECIFIC => "Per-interface",
+            AUDIO => "Audio",
+            CDC => "Communications",
+            HID => "HID",
+            PHYSICAL => "Physical",
+            IMAGE => "Image",
+            PRINTER => "Printer",
+            MASS_STORAGE => "Mass Storage",
+            HUB => "Hub",
+            CDC_DATA => "CDC Data",
+            SMART_CARD => "Smart Card",
+            CONTENT_SECURITY => "Content Security",
+            VIDEO => "Video",
+            PERSONAL_HEALTHCARE => "Personal Healthcare",
+            AUDIO_VIDEO => "Audio/Video",
+            BILLBOARD => "Billboard",
+            TYPE_C_BRIDGE => "Type-C Bridge",
+            DIAGNOSTIC => "Diagnostic",
+            WIRELESS => "Wireless Controller",
+            MISC => "Miscellaneous",
+            APPLICATION_SPECIFIC => "Application Specific",
+            VENDOR_SPECIFIC => "Vendor Specific",
+            _ => "Unknown",
+        }
+    }
 }
 
 /// Mass Storage subclass codes.
@@ -120,6 +163,23 @@ pub mod msc_subclass {
     pub const IEEE1667: u8 = 0x08;
     /// Vendor specific
     pub const VENDOR_SPECIFIC: u8 = 0xFF;
+
+    /// Returns a human-readable name for the subclass code.
+    pub const fn name(code: u8) -> &'static str {
+        match code {
+            SCSI_NOT_REPORTED => "SCSI (not reported)",
+            RBC => "RBC",
+            MMC5 => "MMC-5",
+            QIC157 => "QIC-157",
+            UFI => "UFI",
+            SFF8070I => "SFF-8070i",
+            SCSI_TRANSPARENT => "SCSI",
+            LSD_FS => "LSD FS",
+            IEEE1667 => "IEEE 1667",
+            VENDOR_SPECIFIC => "Vendor Specific",
+            _ => "Unknown",
+        }
+    }
 }
 
 /// Mass Storage protocol codes.
@@ -134,6 +194,18 @@ pub mod msc_protocol {
     pub const UAS: u8 = 0x62;
     /// Vendor specific
     pub const VENDOR_SPECIFIC: u8 = 0xFF;
+
+    /// Returns a human-readable name for the protocol code.
+    pub const fn name(code: u8) -> &'static str {
+        match code {
+            CBI_INTERRUPT => "CBI with interrupt",
+            CBI_NO_INTERRUPT => "CBI",
+            BBB => "Bulk-Only",
+            UAS => "UAS",
+            VENDOR_SPECIFIC => "Vendor Specific",
+            _ => "Unknown",
+        }
+    }
 }
 
 /// HID subclass codes.
@@ -152,6 +224,16 @@ pub mod hid_protocol {
     pub const KEYBOARD: u8 = 1;
     /// Mouse
     pub const MOUSE: u8 = 2;
+
+    /// Returns a human-readable name for the protocol code.
+    pub const fn name(code: u8) -> &'static str {
+        match code {
+            NONE => "None",
+            KEYBOARD => "Keyboard",
+            MOUSE => "Mouse",
+            _ => "Unknown",
+        }
+    }
 }
 
 /// Hub subclass codes.
@@ -174,6 +256,17 @@ pub mod hub_protocol {
     pub const HI_SPEED_MULTI_TT: u8 = 2;
     /// SuperSpeed hub
     pub const SUPER_SPEED: u8 = 3;
+
+    /// Returns a human-readable name for the protocol code.
+    pub const fn name(code: u8) -> &'static str {
+        match code {
+            FULL_SPEED => "Full Speed",
+            HI_SPEED_SINGLE_TT => "Hi-Speed, single TT",
+            HI_SPEED_MULTI_TT => "Hi-Speed, multiple TTs",
+            SUPER_SPEED => "SuperSpeed",
+            _ => "Unknown",
+        }
+    }
 }
 
 /// CDC subclass codes.
@@ -204,6 +297,26 @@ pub mod cdc_subclass {
     pub const EEM: u8 = 0x0C;
     /// Network Control Model
     pub const NCM: u8 = 0x0D;
+
+    /// Returns a human-readable name for the subclass code.
+    pub const fn name(code: u8) -> &'static str {
+        match code {
+            DLCM => "Direct Line Control",
+            ACM => "ACM",
+            TCM => "Telephone Control",
+            MCCM => "Multi-Channel Control",
+            CAPI => "CAPI Control",
+            ENCM => "Ethernet (ECM)",
+            ANCM => "ATM Networking",
+            WHCM => "Wireless Handset Control",
+            DM => "Device Management",
+            MDLM => "Mobile Direct Line",
+            OBEX => "OBEX",
+            EEM => "Ethernet Emulation (EEM)",
+            NCM => "Network Control (NCM)",
+            _ => "Unknown",
+        }
+    }
 }
 
 /// Endpoint transfer type codes.
@@ -216,6 +329,17 @@ pub mod ep_type {
     pub const BULK: u8 = 2;
     /// Interrupt transfer
     pub const INTERRUPT: u8 = 3;
+
+    /// Returns a human-readable name for the transfer type.
+    pub const fn name(code: u8) -> &'static str {
+        match code {
+            CONTROL => "Control",
+            ISOCHRONOUS => "Isochronous",
+            BULK => "Bulk",
+            INTERRUPT => "Interrupt",
+            _ => "Unknown",
+        }
+    }
 }
 
 /// Endpoint synchronization types (for isochronous endpoints).
@@ -228,6 +352,17 @@ pub mod ep_sync {
     pub const ADAPTIVE: u8 = 2;
     /// Synchronous
     pub const SYNC: u8 = 3;
+
+    /// Returns a human-readable name for the synchronization type.
+    pub const fn name(code: u8) -> &'static str {
+        match code {
+            NONE => "no sync",
+            ASYNC => "async",
+            ADAPTIVE => "adaptive",
+            SYNC => "sync",
+            _ => "unknown",
+        }
+    }
 }
 
 /// Endpoint usage types (for isochronous endpoints).
@@ -238,6 +373,16 @@ pub mod ep_usage {
     pub const FEEDBACK: u8 = 1;
     /// Implicit feedback data endpoint
     pub const IMPLICIT_FEEDBACK: u8 = 2;
+
+    /// Returns a human-readable name for the usage type.
+    pub const fn name(code: u8) -> &'static str {
+        match code {
+            DATA => "data",
+            FEEDBACK => "feedback",
+            IMPLICIT_FEEDBACK => "implicit feedback",
+            _ => "unknown",
+        }
+    }
 }
 
 /// Standard USB request codes.
@@ -398,7 +543,66 @@ pub struct DeviceDesc {
     pub num_configurations: u8,
 }
 
+const _: () = {
+    assert!(size_of::<DeviceDesc>() == 18);
+    assert!(align_of::<DeviceDesc>() == 1);
+};
+
 impl DeviceDesc {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 18;
+
+    /// Decodes a descriptor from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            bcd_usb: le16(data, 2),
+            device_class: data[4],
+            device_subclass: data[5],
+            device_protocol: data[6],
+            max_packet_size0: data[7],
+            vendor_id: le16(data, 8),
+            product_id: le16(data, 10),
+            bcd_device: le16(data, 12),
+            manufacturer: data[14],
+            product: data[15],
+            serial_number: data[16],
+            num_configurations: data[17],
+        })
+    }
+
+    /// Returns true if bLength and bDescriptorType describe a device descriptor.
+    ///
+    /// Only the first 2 bytes are looked at, so the header of a partial
+    /// read can be checked too.
+    pub fn header_valid(data: &[u8]) -> bool {
+        data.len() >= 2 && data[0] as usize >= Self::SIZE && data[1] == desc_type::DEVICE
+    }
+
+    /// Encodes the descriptor as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0] = self.length;
+        b[1] = self.desc_type;
+        b[2..4].copy_from_slice(&{ self.bcd_usb }.to_le_bytes());
+        b[4] = self.device_class;
+        b[5] = self.device_subclass;
+        b[6] = self.device_protocol;
+        b[7] = self.max_packet_size0;
+        b[8..10].copy_from_slice(&{ self.vendor_id }.to_le_bytes());
+        b[10..12].copy_from_slice(&{ self.product_id }.to_le_bytes());
+        b[12..14].copy_from_slice(&{ self.bcd_device }.to_le_bytes());
+        b[14] = self.manufacturer;
+        b[15] = self.product;
+        b[16] = self.serial_number;
+        b[17] = self.num_configurations;
+        b
+    }
+
     /// Returns the USB version as a tuple (major, minor).
     pub fn usb_version(&self) -> (u8, u8) {
         ((self.bcd_usb >> 8) as u8, (self.bcd_usb & 0xFF) as u8)
@@ -410,6 +614,65 @@ impl DeviceDesc {
     }
 }
 
+/// Write a class/subclass/protocol triple as names, followed by the codes
+fn fmt_class(f: &mut fmt::Formatter<'_>, triple: (u8, u8, u8)) -> fmt::Result {
+    let (code, subclass, protocol) = triple;
+    f.write_str(class::name(code))?;
+    match code {
+        class::MASS_STORAGE => write!(
+            f,
+            ", {}, {}",
+            msc_subclass::name(subclass),
+            msc_protocol::name(protocol)
+        )?,
+        class::HID => {
+            if subclass == hid_subclass::BOOT {
+                f.write_str(", boot")?;
+            }
+            if protocol != hid_protocol::NONE {
+                write!(f, ", {}", hid_protocol::name(protocol))?;
+            }
+        }
+        class::HUB => write!(f, ", {}", hub_protocol::name(protocol))?,
+        class::CDC => write!(f, ", {}", cdc_subclass::name(subclass))?,
+        _ => {}
+    }
+    write!(f, " ({code:02x}/{subclass:02x}/{protocol:02x})")
+}
+
+/// Plural suffix for `n` items
+fn plural(n: u8) -> &'static str {
+    if n == 1 { "" } else { "s" }
+}
+
+/// Displayed as e.g. `USB 2.00 device 046d:c52b rev 12.03, HID (03/00/00),
+/// EP0 64 bytes, 1 configuration`.
+impl fmt::Display for DeviceDesc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (usb_major, usb_minor) = self.usb_version();
+        let (dev_major, dev_minor) = self.device_version();
+        write!(
+            f,
+            "USB {usb_major:x}.{usb_minor:02x} device {:04x}:{:04x} rev {dev_major:x}.{dev_minor:02x}, ",
+            { self.vendor_id },
+            { self.product_id },
+        )?;
+        let class = (
+            self.device_class,
+            self.device_subclass,
+            self.device_protocol,
+        );
+        fmt_class(f, class)?;
+        write!(
+            f,
+            ", EP0 {} bytes, {} configuration{}",
+            self.max_packet_size0,
+            self.num_configurations,
+            plural(self.num_configurations)
+        )
+    }
+}
+
 /// USB configuration descriptor (9 bytes).
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -432,7 +695,54 @@ pub struct ConfigDesc {
     pub max_power: u8,
 }
 
+const _: () = {
+    assert!(size_of::<ConfigDesc>() == 9);
+    assert!(align_of::<ConfigDesc>() == 1);
+};
+
 impl ConfigDesc {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 9;
+
+    /// Decodes a descriptor from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            total_length: le16(data, 2),
+            num_interfaces: data[4],
+            config_value: data[5],
+            configuration: data[6],
+            attributes: data[7],
+            max_power: data[8],
+        })
+    }
+
+    /// Returns true if bLength, bDescriptorType and wTotalLength are consistent.
+    pub fn header_valid(data: &[u8]) -> bool {
+        data.len() >= Self::SIZE
+            && data[0] as usize >= Self::SIZE
+            && data[1] == desc_type::CONFIGURATION
+            && le16(data, 2) as usize >= data[0] as usize
+    }
+
+    /// Encodes the descriptor as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0] = self.length;
+        b[1] = self.desc_type;
+        b[2..4].copy_from_slice(&{ self.total_length }.to_le_bytes());
+        b[4] = self.num_interfaces;
+        b[5] = self.config_value;
+        b[6] = self.configuration;
+        b[7] = self.attributes;
+        b[8] = self.max_power;
+        b
+    }
+
     /// Returns true if the device is self-powered in this configuration.
     pub fn self_powered(&self) -> bool {
         (self.attributes & 0x40) != 0
@@ -444,9 +754,49 @@ impl ConfigDesc {
     }
 
     /// Returns the maximum power in milliamps (USB 2.0 calculation).
+    #[deprecated(note = "Use max_power_ma_for, the unit depends on the USB version")]
     pub fn max_power_ma(&self) -> u16 {
         self.max_power as u16 * 2
     }
+
+    /// Returns the maximum power in milliamps for a device reporting `bcd_usb`.
+    ///
+    /// bMaxPower is in 8 mA units for USB 3.x devices, which only report
+    /// bcdUSB 3.x when operating at SuperSpeed, and 2 mA units otherwise.
+    pub fn max_power_ma_for(&self, bcd_usb: u16) -> u16 {
+        if bcd_usb >= 0x0300 {
+            self.max_power as u16 * 8
+        } else {
+            self.max_power as u16 * 2
+        }
+    }
+}
+
+/// Displayed as e.g. `Configuration 1: 1 interface, bus-powered,
+/// bMaxPower 50`.
+///
+/// bMaxPower is shown raw, as its unit depends on the USB version (see
+/// `max_power_ma_for`).
+impl fmt::Display for ConfigDesc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let power = if self.self_powered() {
+            "self-powered"
+        } else {
+            "bus-powered"
+        };
+        write!(
+            f,
+            "Configuration {}: {} interface{}, {}",
+            self.config_value,
+            self.num_interfaces,
+            plural(self.num_interfaces),
+            power
+        )?;
+        if self.remote_wakeup() {
+            f.write_str(", remote wakeup")?;
+        }
+        write!(f, ", bMaxPower {}", self.max_power)
+    }
 }
 
 /// USB interface descriptor (9 bytes).
@@ -473,6 +823,73 @@ pub struct InterfaceDesc {
     pub interface: u8,
 }
 
+const _: () = {
+    assert!(size_of::<InterfaceDesc>() == 9);
+    assert!(align_of::<InterfaceDesc>() == 1);
+};
+
+impl InterfaceDesc {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 9;
+
+    /// Decodes a descriptor from wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            interface_number: data[2],
+            alternate_setting: data[3],
+            num_endpoints: data[4],
+            interface_class: data[5],
+            interface_subclass: data[6],
+            interface_protocol: data[7],
+            interface: data[8],
+        })
+    }
+
+    /// Encodes the descriptor as wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        [
+            self.length,
+            self.desc_type,
+            self.interface_number,
+            self.alternate_setting,
+            self.num_endpoints,
+            self.interface_class,
+            self.interface_subclass,
+            self.interface_protocol,
+            self.interface,
+        ]
+    }
+}
+
+/// Displayed as e.g. `Interface 0 alt 0: Mass Storage, SCSI, Bulk-Only
+/// (08/06/50), 2 endpoints`.
+impl fmt::Display for InterfaceDesc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Interface {} alt {}: ",
+            self.interface_number, self.alternate_setting
+        )?;
+        let class = (
+            self.interface_class,
+            self.interface_subclass,
+            self.interface_protocol,
+        );
+        fmt_class(f, class)?;
+        write!(
+            f,
+            ", {} endpoint{}",
+            self.num_endpoints,
+            plural(self.num_endpoints)
+        )
+    }
+}
+
 /// USB endpoint descriptor (7 bytes).
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -491,7 +908,44 @@ pub struct EndpointDesc {
     pub interval: u8,
 }
 
+const _: () = {
+    assert!(size_of::<EndpointDesc>() == 7);
+    assert!(align_of::<EndpointDesc>() == 1);
+};
+
 impl EndpointDesc {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 7;
+
+    /// Decodes a descriptor from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            endpoint_address: data[2],
+            attributes: data[3],
+            max_packet_size: le16(data, 4),
+            interval: data[6],
+        })
+    }
+
+    /// Encodes the descriptor as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mps = { self.max_packet_size }.to_le_bytes();
+        [
+            self.length,
+            self.desc_type,
+            self.endpoint_address,
+            self.attributes,
+            mps[0],
+            mps[1],
+            self.interval,
+        ]
+    }
+
     /// Returns the endpoint number (0-15).
     pub fn number(&self) -> u8 {
         self.endpoint_address & 0x0F
@@ -531,6 +985,55 @@ impl EndpointDesc {
     pub fn additional_transactions(&self) -> u8 {
         ((self.max_packet_size >> 11) & 0x03) as u8
     }
+
+    /// Returns the bytes moved per service interval (Max ESIT Payload).
+    ///
+    /// 0 for control and bulk endpoints. SuperSpeed endpoints take the
+    /// value from their companion descriptor instead.
+    pub fn esit_payload(&self) -> u32 {
+        if !matches!(
+            self.transfer_type(),
+            ep_type::ISOCHRONOUS | ep_type::INTERRUPT
+        ) {
+            return 0;
+        }
+        self.packet_size() as u32 * (self.additional_transactions() as u32 + 1)
+    }
+}
+
+/// Displayed as e.g. `EP 0x81: Bulk IN, 512 bytes, interval 0`.
+///
+/// Isochronous endpoints add their synchronization and usage types, and
+/// high-bandwidth endpoints their transactions per microframe, as in
+/// `3x 1024 bytes`.
+impl fmt::Display for EndpointDesc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "EP {:#04x}: {} {}",
+            self.endpoint_address,
+            ep_type::name(self.transfer_type()),
+            if self.is_in() { "IN" } else { "OUT" }
+        )?;
+        if self.transfer_type() == ep_type::ISOCHRONOUS {
+            write!(
+                f,
+                " ({}, {})",
+                ep_sync::name(self.sync_type()),
+                ep_usage::name(self.usage_type())
+            )?;
+        }
+        f.write_str(", ")?;
+        if self.additional_transactions() > 0 {
+            write!(f, "{}x ", self.additional_transactions() + 1)?;
+        }
+        write!(
+            f,
+            "{} bytes, interval {}",
+            self.packet_size(),
+            self.interval
+        )
+    }
 }
 
 /// USB Device Qualifier descriptor (10 bytes).
@@ -559,6 +1062,11 @@ pub struct DeviceQualifierDesc {
     pub reserved: u8,
 }
 
+const _: () = {
+    assert!(size_of::<DeviceQualifierDesc>() == 10);
+    assert!(align_of::<DeviceQualifierDesc>() == 1);
+};
+
 /// Interface Association Descriptor (8 bytes).
 ///
 /// Groups multiple interfaces that belong to a single function.
@@ -583,6 +1091,433 @@ pub struct InterfaceAssocDesc {
     pub function: u8,
 }
 
+const _: () = {
+    assert!(size_of::<InterfaceAssocDesc>() == 8);
+    assert!(align_of::<InterfaceAssocDesc>() == 1);
+};
+
+impl InterfaceAssocDesc {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 8;
+
+    /// Decodes a descriptor from wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            first_interface: data[2],
+            interface_count: data[3],
+            function_class: data[4],
+            function_subclass: data[5],
+            function_protocol: data[6],
+            function: data[7],
+        })
+    }
+
+    /// Encodes the descriptor as wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        [
+            self.length,
+            self.desc_type,
+            self.first_interface,
+            self.interface_count,
+            self.function_class,
+            self.function_subclass,
+            self.function_protocol,
+            self.function,
+        ]
+    }
+}
+
+/// Iterator over the descriptors in a configuration or BOS blob.
+///
+/// Yields `(descriptor type, raw bytes)` and stops at the first truncated
+/// or zero-length descriptor. Needs no allocation.
+#[derive(Clone, Debug)]
+pub struct DescIter<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DescIter<'a> {
+    /// Creates an iterator over `data`.
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl<'a> Iterator for DescIter<'a> {
+    type Item = (u8, &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 2 {
+            return None;
+        }
+
+        let len = self.data[0] as usize;
+        if len < 2 || len > self.data.len() {
+            self.data = &[];
+            return None;
+        }
+
+        let (desc, rest) = self.data.split_at(len);
+        self.data = rest;
+        Some((desc[1], desc))
+    }
+}
+
+/// One alternate setting of an interface found by `find_interfaces`.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, Default)]
+pub struct InterfaceSetting {
+    /// Interface descriptor of this alternate setting
+    pub iface: InterfaceDesc,
+    /// Endpoint descriptors of this alternate setting
+    pub endpoints: Vec<EndpointDesc>,
+    /// Raw class-specific and companion descriptors that follow it
+    pub extra: Vec<u8>,
+    /// SuperSpeed Endpoint Companions by the address of their endpoint
+    pub companions: Vec<(u8, SsEpCompDesc)>,
+}
+
+#[cfg(feature = "alloc")]
+impl InterfaceSetting {
+    /// Returns the SuperSpeed Endpoint Companion of an endpoint, if any.
+    pub fn companion(&self, endpoint_address: u8) -> Option<SsEpCompDesc> {
+        self.companions
+            .iter()
+            .find(|(addr, _)| *addr == endpoint_address)
+            .map(|&(_, comp)| comp)
+    }
+}
+
+/// Interface found by `find_interfaces`, with all matching alternate settings.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, Default)]
+pub struct FoundInterface {
+    /// Alternate settings in descriptor order (never empty)
+    pub settings: Vec<InterfaceSetting>,
+    /// Interface Association the interface belongs to, if any
+    pub assoc: Option<InterfaceAssocDesc>,
+}
+
+#[cfg(feature = "alloc")]
+impl FoundInterface {
+    /// Returns the interface number.
+    pub fn number(&self) -> u8 {
+        self.settings[0].iface.interface_number
+    }
+
+    /// Returns the descriptor of the first matching alternate setting.
+    pub fn iface(&self) -> &InterfaceDesc {
+        &self.settings[0].iface
+    }
+
+    /// Returns the endpoints of the first matching alternate setting.
+    pub fn endpoints(&self) -> &[EndpointDesc] {
+        &self.settings[0].endpoints
+    }
+}
+
+/// One alternate setting of an interface, borrowed from configuration data.
+///
+/// Reported by `for_each_interface`; needs no allocation.
+#[derive(Clone, Copy, Debug)]
+pub struct InterfaceSettingRef<'a> {
+    /// Interface descriptor of this alternate setting
+    pub iface: InterfaceDesc,
+    /// Interface Association the interface belongs to, if any
+    pub assoc: Option<InterfaceAssocDesc>,
+    /// Raw descriptors following the interface descriptor, up to the next
+    /// interface or association
+    pub body: &'a [u8],
+}
+
+impl<'a> InterfaceSettingRef<'a> {
+    /// Returns the endpoint descriptors of this alternate setting.
+    pub fn endpoints(&self) -> impl Iterator<Item = EndpointDesc> + 'a {
+        DescIter::new(self.body)
+            .filter(|&(dtype, _)| dtype == desc_type::ENDPOINT)
+            .filter_map(|(_, data)| EndpointDesc::from_bytes(data))
+    }
+}
+
+/// Calls `f` for every alternate setting whose interface descriptor matches `pred`.
+///
+/// Settings are reported in descriptor order, each with the Interface
+/// Association covering it. The alloc-free counterpart of
+/// `find_interfaces`, which groups the settings by interface number.
+pub fn for_each_interface<'a>(
+    config_data: &'a [u8],
+    pred: impl Fn(&InterfaceDesc) -> bool,
+    mut f: impl FnMut(&InterfaceSettingRef<'a>),
+) {
+    let mut assoc: Option<InterfaceAssocDesc> = None;
+    // Matching setting whose body ends at the next boundary, and where it starts
+    let mut current: Option<(InterfaceDesc, Option<InterfaceAssocDesc>, usize)> = None;
+    let mut pos = 0;
+
+    for (dtype, data) in DescIter::new(config_data) {
+        let len = data.len();
+        let boundary = match dtype {
+            desc_type::INTERFACE_ASSOCIATION => len >= InterfaceAssocDesc::SIZE,
+            desc_type::INTERFACE => InterfaceDesc::from_bytes(data).is_some(),
+            _ => false,
+        };
+        if boundary && let Some((iface, assoc, start)) = current.take() {
+            let body = &config_data[start..pos];
+            f(&InterfaceSettingRef { iface, assoc, body });
+        }
+        pos += len;
+
+        match dtype {
+            desc_type::INTERFACE_ASSOCIATION if boundary => {
+                assoc = InterfaceAssocDesc::from_bytes(data);
+            }
+            desc_type::INTERFACE if let Some(iface) = InterfaceDesc::from_bytes(data) => {
+                let num = iface.interface_number;
+
+                // An association only covers its contiguous interface range
+                if let Some(a) = assoc
+                    && (num < a.first_interface || num - a.first_interface >= a.interface_count)
+                {
+                    assoc = None;
+                }
+
+                if pred(&iface) {
+                    current = Some((iface, assoc, pos));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    if let Some((iface, assoc, start)) = current {
+        let body = &config_data[start..pos];
+        f(&InterfaceSettingRef { iface, assoc, body });
+    }
+}
+
+/// Interface numbers seen so far, for picking one setting per interface
+/// without allocating
+#[derive(Default)]
+pub(crate) struct InterfaceSet([u64; 4]);
+
+impl InterfaceSet {
+    /// Add `number`; returns false if it was already present
+    pub(crate) fn insert(&mut self, number: u8) -> bool {
+        let (word, bit) = (number as usize / 64, 1u64 << (number % 64));
+        let new = self.0[word] & bit == 0;
+        self.0[word] |= bit;
+        new
+    }
+
+    /// Returns true if `number` was added.
+    pub(crate) fn contains(&self, number: u8) -> bool {
+        self.0[number as usize / 64] & (1u64 << (number % 64)) != 0
+    }
+}
+
+/// Finds the interfaces of a configuration whose descriptors match `pred`.
+///
+/// Alternate settings of the same interface number are grouped into one
+/// entry, and interfaces inside an Interface Association carry its
+/// descriptor so composite functions can be put back together. See
+/// `for_each_interface` for a variant without allocation.
+#[cfg(feature = "alloc")]
+pub fn find_interfaces(
+    config_data: &[u8],
+    pred: impl Fn(&InterfaceDesc) -> bool,
+) -> Vec<FoundInterface> {
+    let mut result: Vec<FoundInterface> = Vec::new();
+
+    for_each_interface(config_data, pred, |found| {
+        let num = found.iface.interface_number;
+        let idx = match result.iter().position(|f| f.number() == num) {
+            Some(idx) => idx,
+            None => {
+                result.push(FoundInterface {
+                    settings: Vec::new(),
+                    assoc: found.assoc,
+                });
+                result.len() - 1
+            }
+        };
+
+        let mut setting = InterfaceSetting {
+            iface: found.iface,
+            ..Default::default()
+        };
+        for (dtype, data) in DescIter::new(found.body) {
+            if dtype == desc_type::ENDPOINT
+                && let Some(ep) = EndpointDesc::from_bytes(data)
+            {
+                setting.endpoints.push(ep);
+                continue;
+            }
+            setting.extra.extend_from_slice(data);
+
+            // A companion directly follows its endpoint
+            if dtype == desc_type::SS_EP_COMPANION
+                && let Some(comp) = SsEpCompDesc::from_bytes(data)
+                && let Some(ep) = setting.endpoints.last()
+                && setting.companion(ep.endpoint_address).is_none()
+            {
+                setting.companions.push((ep.endpoint_address, comp));
+            }
+        }
+        result[idx].settings.push(setting);
+    });
+
+    result
+}
+
+/// Most interfaces accepted in one configuration
+#[cfg(feature = "alloc")]
+const MAX_INTERFACES: usize = 32;
+/// Most endpoints accepted in one alternate setting, besides EP0
+#[cfg(feature = "alloc")]
+const MAX_ENDPOINTS: usize = 30;
+
+/// Check that a configuration stays within `MAX_INTERFACES` interface
+/// numbers and `MAX_ENDPOINTS` endpoints per alternate setting
+#[cfg(feature = "alloc")]
+fn within_limits(config_data: &[u8]) -> bool {
+    let mut interfaces = [0u64; 4];
+    let mut endpoints = 0;
+    for (dtype, data) in DescIter::new(config_data) {
+        match dtype {
+            desc_type::INTERFACE if data.len() >= InterfaceDesc::SIZE => {
+                let num = data[2];
+                interfaces[num as usize / 64] |= 1 << (num % 64);
+                endpoints = 0;
+            }
+            desc_type::ENDPOINT => endpoints += 1,
+            _ => {}
+        }
+        let count: u32 = interfaces.iter().map(|bits| bits.count_ones()).sum();
+        if count as usize > MAX_INTERFACES || endpoints > MAX_ENDPOINTS {
+            return false;
+        }
+    }
+    true
+}
+
+/// Trim configuration descriptor data to the descriptors that arrived whole
+///
+/// `data` holds the bytes the device returned, which may fall short of
+/// wTotalLength or run past it. A descriptor cut off at the end is
+/// dropped, as is anything past wTotalLength from the first descriptor
+/// with a bLength below 2, and wTotalLength is rewritten to the
+/// remaining length. `None` if the header is inconsistent, a descriptor
+/// within wTotalLength has a bLength below 2 or the configuration has
+/// more interfaces or endpoints than plausible.
+#[cfg(feature = "alloc")]
+pub(crate) fn trim_config_data(mut data: Vec<u8>) -> Option<Vec<u8>> {
+    if !ConfigDesc::header_valid(&data) {
+        return None;
+    }
+
+    let claimed = le16(&data, 2) as usize;
+    let mut end = 0;
+    while end + 2 <= data.len() {
+        let len = data[end] as usize;
+        if len < 2 {
+            // Trailing bytes the header did not claim are not descriptors
+            if end >= claimed {
+                break;
+            }
+            return None;
+        }
+        if end + len > data.len() {
+            break;
+        }
+        end += len;
+    }
+    // The configuration descriptor itself must be complete
+    if end < ConfigDesc::SIZE {
+        return None;
+    }
+
+    data.truncate(end);
+    data[2..4].copy_from_slice(&(end as u16).to_le_bytes());
+    within_limits(&data).then_some(data)
+}
+
+/// Parsed configuration: the configuration descriptor and all interfaces.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug)]
+pub struct ConfigTree {
+    /// Configuration descriptor
+    pub config: ConfigDesc,
+    /// All interfaces, with alternate settings grouped
+    pub interfaces: Vec<FoundInterface>,
+    raw: Vec<u8>,
+}
+
+#[cfg(feature = "alloc")]
+impl ConfigTree {
+    /// Parses the full configuration descriptor data.
+    ///
+    /// Returns `None` for data that is not a configuration descriptor or
+    /// has more than 32 interfaces or 30 endpoints in an alternate setting.
+    pub fn parse(config_data: Vec<u8>) -> Option<Self> {
+        if config_data.len() < 9 || config_data[1] != desc_type::CONFIGURATION {
+            return None;
+        }
+        if !within_limits(&config_data) {
+            return None;
+        }
+
+        let config = ConfigDesc::from_bytes(&config_data)?;
+        let interfaces = find_interfaces(&config_data, |_| true);
+        Some(Self {
+            config,
+            interfaces,
+            raw: config_data,
+        })
+    }
+
+    /// Returns the raw configuration descriptor data.
+    pub fn raw(&self) -> &[u8] {
+        &self.raw
+    }
+
+    /// Finds an interface by number.
+    pub fn interface(&self, number: u8) -> Option<&FoundInterface> {
+        self.interfaces.iter().find(|f| f.number() == number)
+    }
+
+    /// Returns the SuperSpeed Endpoint Companion following an endpoint descriptor.
+    ///
+    /// Looks in every alternate setting holding a descriptor identical to `ep`.
+    pub fn companion(&self, ep: &EndpointDesc) -> Option<SsEpCompDesc> {
+        self.interfaces
+            .iter()
+            .flat_map(|f| &f.settings)
+            .filter(|s| s.endpoints.iter().any(|e| e.to_bytes() == ep.to_bytes()))
+            .find_map(|s| s.companion(ep.endpoint_address))
+    }
+}
+
+/// Displayed as the configuration followed by each alternate setting and
+/// its endpoints, one per line, indented by two spaces per level.
+#[cfg(feature = "alloc")]
+impl fmt::Display for ConfigTree {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.config)?;
+        for setting in self.interfaces.iter().flat_map(|i| &i.settings) {
+            write!(f, "\n  {}", setting.iface)?;
+            for ep in &setting.endpoints {
+                write!(f, "\n    {ep}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Binary Object Store (BOS) descriptor header (5 bytes).
 ///
 /// Container for device capability descriptors (USB 3.0+).
@@ -599,6 +1534,35 @@ pub struct BosDesc {
     pub num_device_caps: u8,
 }
 
+const _: () = {
+    assert!(size_of::<BosDesc>() == 5);
+    assert!(align_of::<BosDesc>() == 1);
+};
+
+impl BosDesc {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 5;
+
+    /// Decodes a descriptor from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            total_length: le16(data, 2),
+            num_device_caps: data[4],
+        })
+    }
+
+    /// Encodes the descriptor as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let total = { self.total_length }.to_le_bytes();
+        [self.length, self.desc_type, total[0], total[1], self.num_device_caps]
+    }
+}
+
 /// USB 2.0 Extension Capability descriptor (7 bytes).
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -613,11 +1577,59 @@ pub struct Usb20ExtCapDesc {
     pub bm_attributes: u32,
 }
 
+const _: () = {
+    assert!(size_of::<Usb20ExtCapDesc>() == 7);
+    assert!(align_of::<Usb20ExtCapDesc>() == 1);
+};
+
 impl Usb20ExtCapDesc {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 7;
+
+    /// Decodes a descriptor from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            dev_capability_type: data[2],
+            bm_attributes: le32(data, 3),
+        })
+    }
+
+    /// Encodes the descriptor as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0] = self.length;
+        b[1] = self.desc_type;
+        b[2] = self.dev_capability_type;
+        b[3..7].copy_from_slice(&{ self.bm_attributes }.to_le_bytes());
+        b
+    }
+
     /// Returns true if Link Power Management (LPM) is supported.
     pub fn lpm_supported(&self) -> bool {
         (self.bm_attributes & 0x02) != 0
     }
+
+    /// Returns true if BESL (rather than HIRD) LPM is supported.
+    pub fn besl_supported(&self) -> bool {
+        (self.bm_attributes & 0x04) != 0
+    }
+
+    /// Returns the recommended baseline BESL value, if valid.
+    pub fn baseline_besl(&self) -> Option<u8> {
+        let attrs = self.bm_attributes;
+        ((attrs & 0x08) != 0).then_some(((attrs >> 8) & 0x0F) as u8)
+    }
+
+    /// Returns the recommended deep BESL value, if valid.
+    pub fn deep_besl(&self) -> Option<u8> {
+        let attrs = self.bm_attributes;
+        ((attrs & 0x10) != 0).then_some(((attrs >> 12) & 0x0F) as u8)
+    }
 }
 
 /// SuperSpeed USB Device Capability descriptor (10 bytes).
@@ -634,19 +1646,71 @@ pub struct SsDevCapDesc {
     pub bm_attributes: u8,
     /// Bitmap of supported speeds
     pub speeds_supported: u16,
+    /// Lowest speed at which all functionality is available
+    pub functionality_support: u8,
     /// U1 device exit latency
     pub u1_dev_exit_lat: u8,
     /// U2 device exit latency
     pub u2_dev_exit_lat: u16,
 }
 
+const _: () = {
+    assert!(size_of::<SsDevCapDesc>() == 10);
+    assert!(align_of::<SsDevCapDesc>() == 1);
+};
+
 impl SsDevCapDesc {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 10;
+
+    /// Decodes a descriptor from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            dev_capability_type: data[2],
+            bm_attributes: data[3],
+            speeds_supported: le16(data, 4),
+            functionality_support: data[6],
+            u1_dev_exit_lat: data[7],
+            u2_dev_exit_lat: le16(data, 8),
+        })
+    }
+
+    /// Encodes the descriptor as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0] = self.length;
+        b[1] = self.desc_type;
+        b[2] = self.dev_capability_type;
+        b[3] = self.bm_attributes;
+        b[4..6].copy_from_slice(&{ self.speeds_supported }.to_le_bytes());
+        b[6] = self.functionality_support;
+        b[7] = self.u1_dev_exit_lat;
+        b[8..10].copy_from_slice(&{ self.u2_dev_exit_lat }.to_le_bytes());
+        b
+    }
+
     /// Returns true if Low-power operation is supported.
     pub fn ltm_capable(&self) -> bool {
         (self.bm_attributes & 0x02) != 0
     }
 }
 
+/// Finds a device capability descriptor of the given type in BOS data.
+///
+/// Returns the raw bytes of the first matching capability descriptor.
+pub fn find_capability(bos_data: &[u8], cap_type: u8) -> Option<&[u8]> {
+    DescIter::new(bos_data)
+        .find(|(dtype, data)| {
+            *dtype == desc_type::DEVICE_CAPABILITY && data.len() >= 3 && data[2] == cap_type
+        })
+        .map(|(_, data)| data)
+}
+
 /// SuperSpeed Endpoint Companion descriptor (6 bytes).
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -663,7 +1727,40 @@ pub struct SsEpCompDesc {
     pub bytes_per_interval: u16,
 }
 
+const _: () = {
+    assert!(size_of::<SsEpCompDesc>() == 6);
+    assert!(align_of::<SsEpCompDesc>() == 1);
+};
+
 impl SsEpCompDesc {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 6;
+
+    /// Decodes a descriptor from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            max_burst: data[2],
+            bm_attributes: data[3],
+            bytes_per_interval: le16(data, 4),
+        })
+    }
+
+    /// Encodes the descriptor as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0] = self.length;
+        b[1] = self.desc_type;
+        b[2] = self.max_burst;
+        b[3] = self.bm_attributes;
+        b[4..6].copy_from_slice(&{ self.bytes_per_interval }.to_le_bytes());
+        b
+    }
+
     /// Returns the maximum number of streams for bulk endpoints.
     pub fn max_streams(&self) -> u8 {
         self.bm_attributes & 0x1F
@@ -695,6 +1792,32 @@ pub struct HidDesc {
     pub report_desc_length: u16,
 }
 
+const _: () = {
+    assert!(size_of::<HidDesc>() == 9);
+    assert!(align_of::<HidDesc>() == 1);
+};
+
+impl HidDesc {
+    /// Encoded size in bytes, with one class descriptor.
+    pub const SIZE: usize = 9;
+
+    /// Decodes a descriptor from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            length: data[0],
+            desc_type: data[1],
+            bcd_hid: le16(data, 2),
+            country_code: data[4],
+            num_descriptors: data[5],
+            report_desc_type: data[6],
+            report_desc_length: le16(data, 7),
+        })
+    }
+}
+
 /// USB Hub descriptor (variable length, at least 7 bytes).
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -714,6 +1837,11 @@ pub struct HubDesc {
     // Variable length fields follow: DeviceRemovable, PortPwrCtrlMask
 }
 
+const _: () = {
+    assert!(size_of::<HubDesc>() == 7);
+    assert!(align_of::<HubDesc>() == 1);
+};
+
 impl HubDesc {
     /// Returns true if this is a compound device.
     pub fn is_compound(&self) -> bool {
@@ -760,6 +1888,180 @@ pub struct SsHubDesc {
     pub device_removable: u16,
 }
 
+const _: () = {
+    assert!(size_of::<SsHubDesc>() == 12);
+    assert!(align_of::<SsHubDesc>() == 1);
+};
+
+/// Hub descriptor together with its per-port bitmaps.
+///
+/// Decodes both the USB 2.0 layout (0x29), whose DeviceRemovable and
+/// PortPwrCtrlMask bitmaps grow with the port count, and the SuperSpeed
+/// layout (0x2A), whose DeviceRemovable is a fixed 16-bit field. Bit 0 of
+/// each bitmap is reserved; bit `n` describes port `n`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HubDescriptorFull {
+    /// Descriptor type (0x29 for hub, 0x2A for SS hub)
+    pub desc_type: u8,
+    /// Number of downstream ports
+    pub num_ports: u8,
+    /// Hub characteristics
+    pub hub_characteristics: u16,
+    /// Power on to power good time (2ms units)
+    pub pwr_on_2_pwr_good: u8,
+    /// Hub controller current (mA)
+    pub hub_contr_current: u8,
+    /// Hub header decode latency (SS hubs only, 0 otherwise)
+    pub hub_hdr_dec_lat: u8,
+    /// Hub delay in ns (SS hubs only, 0 otherwise)
+    pub hub_delay: u16,
+    removable: [u8; 32],
+    pwr_ctrl_mask: [u8; 32],
+}
+
+impl HubDescriptorFull {
+    /// Returns the length of the descriptor of a hub with `num_ports` ports.
+    pub fn wire_len(superspeed: bool, num_ports: u8) -> usize {
+        if superspeed {
+            size_of::<SsHubDesc>()
+        } else {
+            size_of::<HubDesc>() + 2 * (num_ports as usize / 8 + 1)
+        }
+    }
+
+    /// Decodes a hub descriptor of a hub with `num_ports` ports.
+    ///
+    /// `num_ports` is the count read from the fixed part of the descriptor
+    /// beforehand. Returns `None` if the descriptor disagrees with it or is
+    /// not a hub descriptor. Bitmap bytes the hub left out read as zero.
+    pub fn parse(data: &[u8], num_ports: u8) -> Option<Self> {
+        if data.len() < size_of::<HubDesc>() || data[2] != num_ports {
+            return None;
+        }
+        let mut desc = Self {
+            desc_type: data[1],
+            num_ports,
+            hub_characteristics: le16(data, 3),
+            pwr_on_2_pwr_good: data[5],
+            hub_contr_current: data[6],
+            hub_hdr_dec_lat: 0,
+            hub_delay: 0,
+            removable: [0; 32],
+            pwr_ctrl_mask: [0; 32],
+        };
+        match desc.desc_type {
+            desc_type::HUB => {
+                let n = num_ports as usize / 8 + 1;
+                for (i, &b) in data[7..].iter().take(2 * n).enumerate() {
+                    if i < n {
+                        desc.removable[i] = b;
+                    } else {
+                        desc.pwr_ctrl_mask[i - n] = b;
+                    }
+                }
+            }
+            desc_type::SS_HUB => {
+                if num_ports > 15 {
+                    return None;
+                }
+                if data.len() >= size_of::<SsHubDesc>() {
+                    desc.hub_hdr_dec_lat = data[7];
+                    desc.hub_delay = le16(data, 8);
+                    desc.removable[..2].copy_from_slice(&data[10..12]);
+                }
+            }
+            _ => return None,
+        }
+        Some(desc)
+    }
+
+    /// Returns true if the device on `port` (1-based) is removable.
+    ///
+    /// A non-removable port has a device built into the hub's product.
+    /// Ports out of range read as removable.
+    pub fn is_port_removable(&self, port: u8) -> bool {
+        if port == 0 || port > self.num_ports {
+            return true;
+        }
+        self.removable[port as usize / 8] & (1 << (port % 8)) == 0
+    }
+
+    /// Returns true if `port` (1-based) ignores gang-mode power requests.
+    ///
+    /// From the legacy PortPwrCtrlMask of USB 1.x hubs; always false for
+    /// SuperSpeed hubs, whose descriptor has no such field.
+    pub fn is_port_power_masked(&self, port: u8) -> bool {
+        if port == 0 || port > self.num_ports {
+            return false;
+        }
+        self.pwr_ctrl_mask[port as usize / 8] & (1 << (port % 8)) != 0
+    }
+
+    /// Returns true if this is a compound device.
+    pub fn is_compound(&self) -> bool {
+        (self.hub_characteristics & 0x04) != 0
+    }
+
+    /// Returns the power switching mode (0=ganged, 1=individual, 2-3=reserved).
+    pub fn power_switching_mode(&self) -> u8 {
+        (self.hub_characteristics & 0x03) as u8
+    }
+
+    /// Returns the TT think time (0=8, 1=16, 2=24, 3=32 FS bit times).
+    pub fn tt_think_time(&self) -> u8 {
+        ((self.hub_characteristics >> 5) & 0x03) as u8
+    }
+
+    /// Returns true if the hub has port indicators (wHubCharacteristics D7).
+    pub fn has_port_indicators(&self) -> bool {
+        (self.hub_characteristics & 0x80) != 0
+    }
+}
+
+/// Data stage of a SET_SEL request (6 bytes).
+///
+/// Exit latencies of the path from the host to a SuperSpeed device, as
+/// sent with `SetupPacket::set_sel`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SelData {
+    /// U1 System Exit Latency (microseconds)
+    pub u1_sel: u8,
+    /// U1 Device to Host Exit Latency (microseconds)
+    pub u1_pel: u8,
+    /// U2 System Exit Latency (microseconds)
+    pub u2_sel: u16,
+    /// U2 Device to Host Exit Latency (microseconds)
+    pub u2_pel: u16,
+}
+
+impl SelData {
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 6;
+
+    /// Encodes the latencies as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0] = self.u1_sel;
+        b[1] = self.u1_pel;
+        b[2..4].copy_from_slice(&self.u2_sel.to_le_bytes());
+        b[4..6].copy_from_slice(&self.u2_pel.to_le_bytes());
+        b
+    }
+
+    /// Decodes the latencies from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            u1_sel: data[0],
+            u1_pel: data[1],
+            u2_sel: le16(data, 2),
+            u2_pel: le16(data, 4),
+        })
+    }
+}
+
 /// USB setup packet for control transfers (8 bytes).
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -776,6 +2078,11 @@ pub struct SetupPacket {
     pub length: u16,
 }
 
+const _: () = {
+    assert!(size_of::<SetupPacket>() == 8);
+    assert!(align_of::<SetupPacket>() == 1);
+};
+
 impl SetupPacket {
     /// Creates a new setup packet.
     pub const fn new(request_type: u8, request: u8, value: u16, index: u16, length: u16) -> Self {
@@ -788,6 +2095,28 @@ impl SetupPacket {
         }
     }
 
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 8;
+
+    /// Decodes a setup packet from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self::new(data[0], data[1], le16(data, 2), le16(data, 4), le16(data, 6)))
+    }
+
+    /// Encodes the setup packet as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0] = self.request_type;
+        b[1] = self.request;
+        b[2..4].copy_from_slice(&{ self.value }.to_le_bytes());
+        b[4..6].copy_from_slice(&{ self.index }.to_le_bytes());
+        b[6..8].copy_from_slice(&{ self.length }.to_le_bytes());
+        b
+    }
+
     /// Creates a GET_STATUS request for device.
     pub fn get_device_status() -> Self {
         Self::new(0x80, request::GET_STATUS, 0, 0, 2)
@@ -828,6 +2157,41 @@ impl SetupPacket {
         Self::new(0x02, request::SET_FEATURE, feature, endpoint as u16, 0)
     }
 
+    /// Creates a SET_FEATURE(B_HNP_ENABLE) request (OTG).
+    ///
+    /// Lets an OTG B-device take the host role when the A-device
+    /// suspends the bus. Only legal on the port of an OTG-capable device
+    /// whose OTG descriptor advertises HNP.
+    pub fn set_b_hnp_enable() -> Self {
+        Self::set_device_feature(feature::B_HNP_ENABLE)
+    }
+
+    /// Creates a SET_FEATURE(A_HNP_SUPPORT) request (OTG).
+    ///
+    /// Tells an OTG B-device that the port it is attached to supports
+    /// HNP. Only legal on an OTG-capable A-device port.
+    pub fn set_a_hnp_support() -> Self {
+        Self::set_device_feature(feature::A_HNP_SUPPORT)
+    }
+
+    /// Creates a SET_FEATURE(A_ALT_HNP_SUPPORT) request (OTG).
+    ///
+    /// Tells an OTG B-device that HNP is only supported on another port
+    /// of the host.
+    pub fn set_a_alt_hnp_support() -> Self {
+        Self::set_device_feature(feature::A_ALT_HNP_SUPPORT)
+    }
+
+    /// Creates a SET_ADDRESS request.
+    ///
+    /// Not for normal use: under xHCI the controller sends SET_ADDRESS
+    /// itself as part of the Address Device command, and a second one
+    /// sent on EP0 leaves the slot context out of step with the device.
+    /// Only for device-side testing or non-xHCI hosts.
+    pub fn set_address(address: u8) -> Self {
+        Self::new(0x00, request::SET_ADDRESS, (address & 0x7f) as u16, 0, 0)
+    }
+
     /// Creates a GET_DESCRIPTOR request.
     pub fn get_descriptor(desc_type: u8, index: u8, length: u16) -> Self {
         Self::new(
@@ -839,6 +2203,40 @@ impl SetupPacket {
         )
     }
 
+    /// Creates a SET_DESCRIPTOR request with `length` bytes of descriptor data.
+    ///
+    /// `lang` is the language ID for string descriptors and 0 otherwise.
+    /// Optional; most devices stall it. Only legal in the Addressed and
+    /// Configured states.
+    pub fn set_descriptor(desc_type: u8, index: u8, lang: u16, length: u16) -> Self {
+        Self::new(
+            0x00,
+            request::SET_DESCRIPTOR,
+            ((desc_type as u16) << 8) | (index as u16),
+            lang,
+            length,
+        )
+    }
+
+    /// Creates a SET_SEL request (USB 3.0).
+    ///
+    /// The data stage is the 6 bytes of `SelData::to_bytes`. Only legal
+    /// for SuperSpeed devices in the Addressed or Configured state, and
+    /// required before enabling U1 or U2.
+    pub fn set_sel() -> Self {
+        Self::new(0x00, request::SET_SEL, 0, 0, SelData::SIZE as u16)
+    }
+
+    /// Creates a SET_ISOCH_DELAY request (USB 3.0).
+    ///
+    /// `delay_ns` is the time from the host sending a packet to the
+    /// device receiving it, in nanoseconds. Only legal for SuperSpeed
+    /// devices, in the Default, Addressed or Configured state; has no
+    /// data stage.
+    pub fn set_isoch_delay(delay_ns: u16) -> Self {
+        Self::new(0x00, request::SET_ISOCH_DELAY, delay_ns, 0, 0)
+    }
+
     /// Creates a GET_DESCRIPTOR request for a specific language (strings).
     pub fn get_string_descriptor(index: u8, lang_id: u16, length: u16) -> Self {
         Self::new(
@@ -894,6 +2292,17 @@ impl SetupPacket {
         )
     }
 
+    /// Creates a GET_DESCRIPTOR(Report) request for a HID interface.
+    pub fn hid_get_report_descriptor(interface: u8, length: u16) -> Self {
+        Self::new(
+            0x81,
+            request::GET_DESCRIPTOR,
+            (desc_type::HID_REPORT as u16) << 8,
+            interface as u16,
+            length,
+        )
+    }
+
     /// Creates a GET_IDLE request (HID class).
     pub fn hid_get_idle(interface: u8, report_id: u8) -> Self {
         Self::new(0xA1, 0x02, report_id as u16, interface as u16, 1)
@@ -943,6 +2352,11 @@ impl SetupPacket {
         Self::new(0xA3, request::GET_STATUS, 0, port as u16, 4)
     }
 
+    /// Creates a CLEAR_HUB_FEATURE request.
+    pub fn hub_clear_feature(feature: u16) -> Self {
+        Self::new(0x20, request::CLEAR_FEATURE, feature, 0, 0)
+    }
+
     /// Creates a SET_PORT_FEATURE request.
     pub fn hub_set_port_feature(feature: u16, port: u8) -> Self {
         Self::new(0x23, request::SET_FEATURE, feature, port as u16, 0)
@@ -953,6 +2367,26 @@ impl SetupPacket {
         Self::new(0x23, request::CLEAR_FEATURE, feature, port as u16, 0)
     }
 
+    /// Creates a SET_PORT_FEATURE(PORT_INDICATOR) request.
+    ///
+    /// The indicator selector goes in the high byte of wIndex.
+    pub fn hub_set_port_indicator(port: u8, selector: u8) -> Self {
+        Self::new(
+            0x23,
+            request::SET_FEATURE,
+            hub_feature::PORT_INDICATOR,
+            ((selector as u16) << 8) | port as u16,
+            0,
+        )
+    }
+
+    /// Creates a SET_HUB_DEPTH request (SuperSpeed hubs).
+    ///
+    /// `depth` is 0 for a hub on a root port.
+    pub fn hub_set_depth(depth: u8) -> Self {
+        Self::new(0x20, 12, depth as u16, 0, 0)
+    }
+
     /// Creates a GET_HUB_DESCRIPTOR request.
     pub fn hub_get_descriptor(length: u16) -> Self {
         Self::new(
@@ -976,6 +2410,13 @@ impl SetupPacket {
         Self::new(0x21, 0xFF, 0, interface as u16, 0)
     }
 
+    /// Creates an Accept Device-Specific Command request (CBI transport).
+    ///
+    /// The data stage carries the `length`-byte command block.
+    pub fn cbi_adsc(interface: u8, length: u16) -> Self {
+        Self::new(0x21, 0x00, 0, interface as u16, length)
+    }
+
     // Deprecated aliases for backward compatibility
 
     /// Creates a GET_REPORT request (HID class).
@@ -987,6 +2428,11 @@ impl SetupPacket {
 
 /// Hub port feature selectors.
 pub mod hub_feature {
+    /// C_HUB_LOCAL_POWER (hub feature, local power change)
+    pub const C_HUB_LOCAL_POWER: u16 = 0;
+    /// C_HUB_OVER_CURRENT (hub feature, over-current change)
+    pub const C_HUB_OVER_CURRENT: u16 = 1;
+
     /// Port connection
     pub const PORT_CONNECTION: u16 = 0;
     /// Port enable
@@ -1060,3 +2506,369 @@ pub mod lang_id {
     /// Chinese (Traditional)
     pub const ZH_TW: u16 = 0x0404;
 }
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::{
+        hid::{find_hid_interfaces, for_each_hid_interface},
+        hub::{find_hub_interfaces, for_each_hub_interface},
+        mock::{self, config, endpoint, interface},
+        msc::{find_msc_interfaces, for_each_msc_interface},
+    };
+    use alloc::vec;
+
+    /// CDC ACM function behind an IAD, a HID interface whose second
+    /// setting has the endpoint, a Bulk-Only disk and a hub
+    fn composite() -> Vec<u8> {
+        let iad = desc_type::INTERFACE_ASSOCIATION;
+        config(
+            1,
+            &[
+                vec![8, iad, 0, 2, 0x02, 0x02, 0x01, 0],
+                interface(0, 0, (0x02, 0x02, 0x01), 1),
+                vec![5, 0x24, 0x00, 0x10, 0x01],
+                endpoint(0x83, 0x03, 16, 8),
+                interface(1, 0, (0x0a, 0, 0), 2),
+                endpoint(0x81, 0x02, 512, 0),
+                endpoint(0x02, 0x02, 512, 0),
+                interface(2, 0, (0x03, 0, 0), 0),
+                interface(2, 1, (0x03, 0, 0), 1),
+                endpoint(0x84, 0x03, 8, 4),
+                interface(3, 0, (0x08, 0x06, 0x50), 2),
+                endpoint(0x85, 0x02, 512, 0),
+                endpoint(0x06, 0x02, 512, 0),
+                interface(4, 0, (0x09, 0, 0), 1),
+                endpoint(0x87, 0x03, 2, 12),
+            ],
+        )
+    }
+
+    #[test]
+    fn for_each_interface_reports_every_setting() {
+        let data = composite();
+        let mut seen = Vec::new();
+        let record = |s: &InterfaceSettingRef<'_>| {
+            let eps: Vec<u8> = s.endpoints().map(|ep| ep.endpoint_address).collect();
+            let (num, alt) = (s.iface.interface_number, s.iface.alternate_setting);
+            seen.push((num, alt, s.assoc.is_some(), eps, s.body.len()));
+        };
+        for_each_interface(&data, |_| true, record);
+        assert_eq!(
+            seen,
+            [
+                (0, 0, true, vec![0x83], 12),
+                (1, 0, true, vec![0x81, 0x02], 14),
+                (2, 0, false, vec![], 0),
+                (2, 1, false, vec![0x84], 7),
+                (3, 0, false, vec![0x85, 0x06], 14),
+                (4, 0, false, vec![0x87], 7),
+            ]
+        );
+
+        let cdc = find_interfaces(&data, |i| i.interface_class == 0x02);
+        assert_eq!(cdc[0].settings[0].extra, [5, 0x24, 0x00, 0x10, 0x01]);
+    }
+
+    #[test]
+    fn alloc_free_finders_match_find_functions() {
+        let data = composite();
+
+        let mut hid = Vec::new();
+        for_each_hid_interface(&data, |i, ep| {
+            hid.push((i.alternate_setting, ep.endpoint_address))
+        });
+        let found: Vec<_> = find_hid_interfaces(&data)
+            .iter()
+            .map(|(i, ep)| (i.alternate_setting, ep.endpoint_address))
+            .collect();
+        assert_eq!(hid, [(1, 0x84)]);
+        assert_eq!(hid, found);
+
+        let mut msc = Vec::new();
+        for_each_msc_interface(&data, |i, a, b| {
+            msc.push((i.interface_number, a.endpoint_address, b.endpoint_address));
+        });
+        let found: Vec<_> = find_msc_interfaces(&data)
+            .iter()
+            .map(|(i, a, b)| (i.interface_number, a.endpoint_address, b.endpoint_address))
+            .collect();
+        assert_eq!(msc, [(3, 0x85, 0x06)]);
+        assert_eq!(msc, found);
+
+        let mut hub = Vec::new();
+        for_each_hub_interface(&data, |i, ep| {
+            hub.push((i.interface_number, ep.endpoint_address))
+        });
+        let found: Vec<_> = find_hub_interfaces(&data)
+            .iter()
+            .map(|(i, ep)| (i.interface_number, ep.endpoint_address))
+            .collect();
+        assert_eq!(hub, [(4, 0x87)]);
+        assert_eq!(hub, found);
+    }
+
+    /// CDC ACM function: an IAD over a communications interface with
+    /// functional descriptors and a data interface with two settings,
+    /// followed by a vendor interface outside the association
+    fn cdc_acm() -> Vec<u8> {
+        let iad = desc_type::INTERFACE_ASSOCIATION;
+        config(
+            1,
+            &[
+                vec![8, iad, 0, 2, 0x02, 0x02, 0x01, 0],
+                interface(0, 0, (0x02, 0x02, 0x01), 1),
+                vec![5, 0x24, 0x00, 0x10, 0x01],
+                vec![5, 0x24, 0x01, 0x00, 0x01],
+                vec![4, 0x24, 0x02, 0x02],
+                vec![5, 0x24, 0x06, 0x00, 0x01],
+                endpoint(0x81, 0x03, 16, 8),
+                interface(1, 0, (0x0a, 0, 0), 0),
+                interface(1, 1, (0x0a, 0, 0), 2),
+                endpoint(0x82, 0x02, 512, 0),
+                endpoint(0x03, 0x02, 512, 0),
+                interface(2, 0, (0xff, 0, 0), 1),
+                endpoint(0x84, 0x02, 512, 0),
+            ],
+        )
+    }
+
+    #[test]
+    fn find_interfaces_groups_cdc_function() {
+        let data = cdc_acm();
+        let found = find_interfaces(&data, |_| true);
+        let numbers: Vec<u8> = found.iter().map(FoundInterface::number).collect();
+        assert_eq!(numbers, [0, 1, 2]);
+
+        // Both CDC interfaces carry the association
+        for f in &found[..2] {
+            let assoc = f.assoc.expect("inside the IAD");
+            assert_eq!((assoc.first_interface, assoc.interface_count), (0, 2));
+            assert_eq!(assoc.function_class, 0x02);
+        }
+        assert!(found[2].assoc.is_none());
+
+        // Functional descriptors stay with the communications interface
+        let comm = &found[0].settings[0];
+        assert_eq!(comm.extra.len(), 19);
+        assert_eq!(comm.extra[..3], [5, 0x24, 0x00]);
+        assert_eq!(comm.endpoints[0].endpoint_address, 0x81);
+
+        // The data interface's settings are grouped
+        let alts: Vec<(u8, usize)> = found[1]
+            .settings
+            .iter()
+            .map(|s| (s.iface.alternate_setting, s.endpoints.len()))
+            .collect();
+        assert_eq!(alts, [(0, 0), (1, 2)]);
+
+        // The predicate filters settings, not the association
+        let data_if = find_interfaces(&data, |i| i.interface_class == 0x0a);
+        assert_eq!(data_if.len(), 1);
+        assert_eq!(data_if[0].number(), 1);
+        assert_eq!(data_if[0].assoc.map(|a| a.first_interface), Some(0));
+    }
+
+    /// Decode distinct bytes, check the re-encoding and reject short input
+    macro_rules! round_trip {
+        ($($ty:ty),*) => {$({
+            let bytes: [u8; <$ty>::SIZE] = core::array::from_fn(|i| 0x10 + i as u8);
+            let decoded = <$ty>::from_bytes(&bytes).expect(stringify!($ty));
+            assert_eq!(decoded.to_bytes(), bytes, stringify!($ty));
+            assert!(<$ty>::from_bytes(&bytes[..<$ty>::SIZE - 1]).is_none());
+        })*};
+    }
+
+    #[test]
+    fn descriptors_round_trip() {
+        round_trip!(
+            DeviceDesc,
+            ConfigDesc,
+            InterfaceDesc,
+            EndpointDesc,
+            InterfaceAssocDesc,
+            BosDesc,
+            Usb20ExtCapDesc,
+            SsDevCapDesc,
+            SsEpCompDesc,
+            SelData,
+            SetupPacket
+        );
+
+        // Multi-byte fields are little-endian
+        let bytes = mock::device_desc(0, 0x1234, 0x5678, 1).to_bytes();
+        assert_eq!(bytes[8..12], [0x34, 0x12, 0x78, 0x56]);
+        let setup = SetupPacket::from_bytes(&[0x80, 6, 0x00, 0x01, 0x09, 0x04, 0x12, 0x00]);
+        let (value, index, length) = setup.map(|s| (s.value, s.index, s.length)).unwrap();
+        assert_eq!((value, index, length), (0x0100, 0x0409, 18));
+    }
+
+    #[test]
+    fn interface_and_endpoint_counts_are_bounded() {
+        let interfaces = |count: u8| {
+            let vendor = |i| interface(i, 0, (0xff, 0, 0), 0);
+            let body: Vec<Vec<u8>> = (0..count).map(vendor).collect();
+            config(1, &body)
+        };
+        assert!(ConfigTree::parse(interfaces(32)).is_some());
+        assert!(ConfigTree::parse(interfaces(33)).is_none());
+        assert!(trim_config_data(interfaces(33)).is_none());
+
+        // Alternate settings of one interface count once
+        let alt = |alt| interface(0, alt, (0xff, 0, 0), 0);
+        let alts: Vec<Vec<u8>> = (0..40).map(alt).collect();
+        assert!(ConfigTree::parse(config(1, &alts)).is_some());
+
+        let endpoints = |count: u8| {
+            let mut body = vec![interface(0, 0, (0xff, 0, 0), count)];
+            body.extend((1..=count).map(|i| endpoint(0x80 | i, 0x02, 512, 0)));
+            config(1, &body)
+        };
+        assert!(ConfigTree::parse(endpoints(30)).is_some());
+        assert!(ConfigTree::parse(endpoints(31)).is_none());
+        assert!(trim_config_data(endpoints(31)).is_none());
+    }
+
+    #[test]
+    fn hub_descriptor_bitmaps() {
+        use desc_type::HUB;
+        // Four ports: port 2 built in, every port in PortPwrCtrlMask
+        let four = [9, HUB, 4, 0x09, 0x00, 50, 100, 0b0000_0100, 0xff];
+        assert_eq!(HubDescriptorFull::wire_len(false, 4), four.len());
+        let hub = HubDescriptorFull::parse(&four, 4).unwrap();
+        let removable: Vec<bool> = (1..=4).map(|p| hub.is_port_removable(p)).collect();
+        assert_eq!(removable, [true, false, true, true]);
+        assert!((1..=4).all(|p| hub.is_port_power_masked(p)));
+        assert_eq!(hub.hub_characteristics, 0x0009);
+        // Out-of-range ports
+        assert!(hub.is_port_removable(0) && hub.is_port_removable(5));
+        assert!(!hub.is_port_power_masked(5));
+
+        // Twelve ports take two bytes per bitmap
+        let twelve = [11, HUB, 12, 0, 0, 50, 100, 0x00, 0x10, 0x02, 0x00];
+        assert_eq!(HubDescriptorFull::wire_len(false, 12), twelve.len());
+        let hub = HubDescriptorFull::parse(&twelve, 12).unwrap();
+        let built_in: Vec<u8> = (1..=12).filter(|&p| !hub.is_port_removable(p)).collect();
+        assert_eq!(built_in, [12]);
+        let masked: Vec<u8> = (1..=12).filter(|&p| hub.is_port_power_masked(p)).collect();
+        assert_eq!(masked, [1]);
+
+        // Bitmap bytes left out read as zero
+        let hub = HubDescriptorFull::parse(&twelve[..8], 12).unwrap();
+        assert!((1..=12).all(|p| hub.is_port_removable(p) && !hub.is_port_power_masked(p)));
+
+        assert!(HubDescriptorFull::parse(&four, 5).is_none());
+        let mut config = four;
+        config[1] = desc_type::CONFIGURATION;
+        assert!(HubDescriptorFull::parse(&config, 4).is_none());
+    }
+
+    #[test]
+    fn superspeed_hub_descriptor_bitmap() {
+        use desc_type::SS_HUB;
+        // Port 1 built in; DeviceRemovable is a fixed 16-bit field
+        let ss = [12, SS_HUB, 4, 0, 0, 50, 100, 3, 0x34, 0x12, 0b10, 0];
+        assert_eq!(HubDescriptorFull::wire_len(true, 4), ss.len());
+        let hub = HubDescriptorFull::parse(&ss, 4).unwrap();
+        let removable: Vec<bool> = (1..=4).map(|p| hub.is_port_removable(p)).collect();
+        assert_eq!(removable, [false, true, true, true]);
+        assert!(!(1..=4).any(|p| hub.is_port_power_masked(p)));
+        assert_eq!((hub.hub_hdr_dec_lat, hub.hub_delay), (3, 0x1234));
+
+        let mut sixteen = ss;
+        sixteen[2] = 16;
+        assert!(HubDescriptorFull::parse(&sixteen, 16).is_none());
+    }
+
+    #[test]
+    fn display_golden_output() {
+        let device = DeviceDesc {
+            bcd_usb: 0x0210,
+            bcd_device: 0x1203,
+            ..mock::device_desc(0, 0x046d, 0xc52b, 1)
+        };
+        assert_eq!(
+            alloc::format!("{device}"),
+            "USB 2.10 device 046d:c52b rev 12.03, Per-interface (00/00/00), EP0 64 bytes, 1 configuration"
+        );
+
+        let header = ConfigDesc {
+            num_interfaces: 1,
+            config_value: 2,
+            attributes: 0xe0,
+            max_power: 250,
+            ..ConfigDesc::default()
+        };
+        assert_eq!(
+            alloc::format!("{header}"),
+            "Configuration 2: 1 interface, self-powered, remote wakeup, bMaxPower 250"
+        );
+
+        let data = config(
+            1,
+            &[
+                interface(0, 0, (0x08, 0x06, 0x50), 2),
+                endpoint(0x81, 0x02, 512, 0),
+                endpoint(0x02, 0x02, 512, 0),
+                interface(1, 0, (0x0e, 0x02, 0x00), 0),
+                interface(1, 1, (0x0e, 0x02, 0x00), 1),
+                endpoint(0x83, 0x05, 1024 | 2 << 11, 1),
+                interface(2, 0, (0x03, 0x01, 0x01), 1),
+                endpoint(0x84, 0x03, 8, 10),
+            ],
+        );
+        let tree = ConfigTree::parse(data).unwrap();
+        let expected = [
+            "Configuration 1: 3 interfaces, bus-powered, bMaxPower 50",
+            "  Interface 0 alt 0: Mass Storage, SCSI, Bulk-Only (08/06/50), 2 endpoints",
+            "    EP 0x81: Bulk IN, 512 bytes, interval 0",
+            "    EP 0x02: Bulk OUT, 512 bytes, interval 0",
+            "  Interface 1 alt 0: Video (0e/02/00), 0 endpoints",
+            "  Interface 1 alt 1: Video (0e/02/00), 1 endpoint",
+            "    EP 0x83: Isochronous IN (async, data), 3x 1024 bytes, interval 1",
+            "  Interface 2 alt 0: HID, boot, Keyboard (03/01/01), 1 endpoint",
+            "    EP 0x84: Interrupt IN, 8 bytes, interval 10",
+        ];
+        assert_eq!(alloc::format!("{tree}"), expected.join("\n"));
+    }
+
+    #[test]
+    fn setup_packet_encodings() {
+        let cases = [
+            (
+                SetupPacket::set_address(0x85),
+                [0x00, 5, 0x05, 0, 0, 0, 0, 0],
+            ),
+            (
+                SetupPacket::set_descriptor(desc_type::STRING, 2, 0x0409, 64),
+                [0x00, 7, 2, 3, 0x09, 0x04, 64, 0],
+            ),
+            (SetupPacket::set_sel(), [0x00, 48, 0, 0, 0, 0, 6, 0]),
+            (
+                SetupPacket::set_isoch_delay(0x1234),
+                [0x00, 49, 0x34, 0x12, 0, 0, 0, 0],
+            ),
+            (SetupPacket::set_b_hnp_enable(), [0x00, 3, 3, 0, 0, 0, 0, 0]),
+            (
+                SetupPacket::set_a_hnp_support(),
+                [0x00, 3, 4, 0, 0, 0, 0, 0],
+            ),
+            (
+                SetupPacket::set_a_alt_hnp_support(),
+                [0x00, 3, 5, 0, 0, 0, 0, 0],
+            ),
+            (SetupPacket::hub_set_depth(2), [0x20, 12, 2, 0, 0, 0, 0, 0]),
+        ];
+        for (i, (setup, bytes)) in cases.iter().enumerate() {
+            assert_eq!(setup.to_bytes(), *bytes, "case {i}");
+        }
+
+        let sel = SelData {
+            u1_sel: 0x11,
+            u1_pel: 0x22,
+            u2_sel: 0x3344,
+            u2_pel: 0x5566,
+        };
+        assert_eq!(sel.to_bytes(), [0x11, 0x22, 0x44, 0x33, 0x66, 0x55]);
+    }
+}
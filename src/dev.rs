@@ -2,15 +2,33 @@
 
 use crate::{
     Dma, Result, UsbError,
-    desc::{ConfigDesc, DeviceDesc, EndpointDesc, SetupPacket, desc_type},
-    reg,
-    ring::{PhysMem, Ring, Trb, completion, trb_type},
-    xhci::XhciCtrl,
+    bandwidth::{self, LoadSummary},
+    desc::{
+        BosDesc, ConfigDesc, ConfigTree, DeviceDesc, EndpointDesc, FoundInterface, SelData,
+        SetupPacket, SsDevCapDesc, SsEpCompDesc, Usb20ExtCapDesc, capability, desc_type, feature,
+        find_capability, lang_id, le16, trim_config_data,
+    },
+    hid::find_hid_interfaces,
+    hub::find_hub_interfaces,
+    msc::find_msc_interfaces,
+    ring::{PhysMem, Reservation, Ring, Trb, completion, trb_flags, trb_type},
+    sync::Lock,
+    xhci::{PORT_RESET_TIMEOUT_US, Speed, Stopwatch, XhciCtrl},
 };
 
-use alloc::{sync::Arc, vec::Vec};
-use core::hint::spin_loop;
-use spin::Mutex;
+use alloc::{boxed::Box, string::String, sync::Arc, vec::Vec};
+use core::{
+    fmt,
+    hint::spin_loop,
+    mem::offset_of,
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU16, AtomicU32, AtomicU64, Ordering},
+};
+
+const CONTROL_TIMEOUT_US: u32 = 5_000_000;
+const MIN_EP0_RING_SIZE: usize = 4;
+/// Time a reactivated device gets to show up on its port
+const RECONNECT_TIMEOUT_US: u32 = 1_000_000;
+const RECONNECT_DEBOUNCE_US: u32 = 100_000;
 
 /// xHCI Slot Context (32 bytes).
 ///
@@ -30,6 +48,15 @@ pub struct SlotContext {
     _0: [u32; 4],
 }
 
+const _: () = {
+    assert!(size_of::<SlotContext>() == 32);
+    assert!(align_of::<SlotContext>() == 32);
+    assert!(offset_of!(SlotContext, dw0) == 0);
+    assert!(offset_of!(SlotContext, dw1) == 4);
+    assert!(offset_of!(SlotContext, dw2) == 8);
+    assert!(offset_of!(SlotContext, dw3) == 12);
+};
+
 impl SlotContext {
     /// Creates a new Slot Context.
     pub fn new(route: u32, speed: u8, context_entries: u8, root_port: u8) -> Self {
@@ -62,6 +89,16 @@ pub struct EndpointContext {
     _0: [u32; 3],
 }
 
+const _: () = {
+    assert!(size_of::<EndpointContext>() == 32);
+    assert!(align_of::<EndpointContext>() == 32);
+    assert!(offset_of!(EndpointContext, dw0) == 0);
+    assert!(offset_of!(EndpointContext, dw1) == 4);
+    assert!(offset_of!(EndpointContext, tr_dequeue_lo) == 8);
+    assert!(offset_of!(EndpointContext, tr_dequeue_hi) == 12);
+    assert!(offset_of!(EndpointContext, dw4) == 16);
+};
+
 impl EndpointContext {
     /// Creates a new Endpoint Context.
     pub fn new(
@@ -100,6 +137,15 @@ pub struct InputContext {
     pub endpoints: [EndpointContext; 31],
 }
 
+const _: () = {
+    // 33 contexts of 32 bytes, padded to the alignment
+    assert!(size_of::<InputContext>() == 1088);
+    assert!(align_of::<InputContext>() == 64);
+    assert!(offset_of!(InputContext, input_control) == 0);
+    assert!(offset_of!(InputContext, slot) == 32);
+    assert!(offset_of!(InputContext, endpoints) == 64);
+};
+
 /// xHCI Device Context.
 ///
 /// Output context maintained by the xHCI controller containing
@@ -113,392 +159,4831 @@ pub struct DeviceContext {
     pub endpoints: [EndpointContext; 31],
 }
 
-/// USB Device abstraction.
-///
-/// Represents an addressed USB device connected to an xHCI controller.
-/// Provides methods for control transfers, device enumeration, and
-/// endpoint configuration.
-pub struct UsbDevice<H: Dma> {
-    ctrl: Arc<XhciCtrl<H>>,
-    slot_id: u8,
-    port: u8,
-    speed: u8,
-    device_ctx: PhysMem<H>,
-    input_ctx: PhysMem<H>,
-    ep0_ring: Mutex<Ring<H>>,
-    ep_rings: Mutex<Vec<Option<Ring<H>>>>,
-    device_desc: Option<DeviceDesc>,
-}
-
-impl<H: Dma> UsbDevice<H> {
-    /// Create and address a new USB device
-    pub fn new(ctrl: Arc<XhciCtrl<H>>, port: u8) -> Result<Self> {
-        let host = ctrl.host();
-
-        // Enable slot
-        let slot_id = ctrl.enable_slot()?;
-
-        // Reset port and get speed
-        ctrl.reset_port(port)?;
-        let speed = ctrl.port_speed(port);
+const _: () = {
+    assert!(size_of::<DeviceContext>() == 1024);
+    assert!(align_of::<DeviceContext>() == 64);
+    assert!(offset_of!(DeviceContext, slot) == 0);
+    assert!(offset_of!(DeviceContext, endpoints) == 32);
+};
 
-        // Allocate contexts
-        let device_ctx = PhysMem::alloc(
-            host,
-            core::mem::size_of::<DeviceContext>(),
-            core::mem::align_of::<DeviceContext>(),
-        )?;
-        let input_ctx = PhysMem::alloc(
-            host,
-            core::mem::size_of::<InputContext>(),
-            core::mem::align_of::<InputContext>(),
-        )?;
+/// Device Context Index of an endpoint.
+///
+/// EP0 is DCI 1 in both directions; EP1 OUT is 2, EP1 IN is 3, and so on
+/// up to EP15 IN at 31.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dci(u8);
 
-        // Allocate EP0 transfer ring
-        let ep0_ring = Ring::new(host, 256)?;
+impl Dci {
+    /// Default control endpoint
+    pub const EP0: Self = Self(1);
 
-        // Setup Input Context
-        let input = input_ctx.as_ptr::<InputContext>();
-        unsafe {
-            // Add flags: Slot Context (bit 0) + EP0 Context (bit 1)
-            (*input).input_control[1] = 0b11;
-
-            // Slot Context
-            (*input).slot = SlotContext::new(0, speed, 1, port + 1);
-
-            // EP0 Context (Control endpoint)
-            let max_packet = match speed {
-                reg::SPEED_LOW => 8,
-                reg::SPEED_FULL => 8,
-                reg::SPEED_HIGH => 64,
-                reg::SPEED_SUPER => 512,
-                _ => 8,
-            };
-            (*input).endpoints[0] = EndpointContext::new(
-                4, // Control Bidirectional
-                max_packet,
-                0,
-                0,
-                ep0_ring.phys(host),
-            );
+    /// Returns the DCI of an endpoint number and direction.
+    ///
+    /// Only the low 4 bits of `ep_num` are used, as in an endpoint address.
+    pub fn from_ep(ep_num: u8, is_in: bool) -> Self {
+        match ep_num & 0x0F {
+            0 => Self::EP0,
+            n => Self(n * 2 + is_in as u8),
         }
+    }
 
-        // Set device context in DCBAA
-        ctrl.set_device_context(slot_id, device_ctx.phys(host));
-
-        // Address Device command
-        let trb = Trb {
-            param: input_ctx.phys(host),
-            status: 0,
-            control: (trb_type::ADDRESS_DEVICE << 10) | ((slot_id as u32) << 24),
-        };
-        ctrl.submit_command(trb)?;
+    /// Returns the DCI of an endpoint descriptor.
+    pub fn from_desc(ep: &EndpointDesc) -> Self {
+        Self::from_ep(ep.number(), ep.is_in())
+    }
 
-        // Allocate ep_rings on heap to reduce stack usage
-        let mut ep_rings = Vec::with_capacity(31);
-        ep_rings.resize_with(31, || None);
+    /// Returns the raw DCI (1-31), as used by doorbells and events.
+    pub fn raw(self) -> u8 {
+        self.0
+    }
 
-        Ok(Self {
-            ctrl,
-            slot_id,
-            port,
-            speed,
-            device_ctx,
-            input_ctx,
-            ep0_ring: Mutex::new(ep0_ring),
-            ep_rings: Mutex::new(ep_rings),
-            device_desc: None,
-        })
+    /// Returns the index into the endpoint context arrays (EP0 = 0).
+    pub fn index(self) -> usize {
+        self.0 as usize - 1
     }
+}
 
-    /// Perform a control transfer
-    pub fn control_transfer(
-        &self,
-        setup: &SetupPacket,
-        mut data: Option<&mut [u8]>,
-    ) -> Result<usize> {
-        let host = self.ctrl.host();
-        let mut ep0_ring = self.ep0_ring.lock();
+/// Endpoint state (Endpoint Context EP State).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EndpointState {
+    /// Not configured
+    Disabled,
+    /// Processing its transfer ring
+    Running,
+    /// Halted by a STALL or a babble; needs `EndpointHandle::reset`
+    Halted,
+    /// Stopped by a Stop Endpoint command
+    Stopped,
+    /// Transfer ring error
+    Error,
+    /// Reserved encoding
+    Reserved(u8),
+}
 
-        let data_dir = (setup.request_type & 0x80) != 0; // true = IN
-        let data_len = data.as_ref().map(|d| d.len()).unwrap_or(0);
+impl EndpointState {
+    /// Decodes the EP State field of an Endpoint Context.
+    pub fn from_raw(state: u8) -> Self {
+        match state & 0x7 {
+            0 => Self::Disabled,
+            1 => Self::Running,
+            2 => Self::Halted,
+            3 => Self::Stopped,
+            4 => Self::Error,
+            state => Self::Reserved(state),
+        }
+    }
+}
 
-        // Allocate data buffer if needed
-        // Use 64-byte alignment for DMA efficiency (cache line size)
-        let data_buf = if data_len > 0 {
-            let buf = PhysMem::alloc(host, data_len, 64)?;
-            if !data_dir {
-                // OUT: copy data to buffer
-                if let Some(ref d) = data {
-                    unsafe {
-                        core::ptr::copy_nonoverlapping(d.as_ptr(), buf.as_ptr(), d.len());
-                    }
-                }
-            }
-            Some(buf)
-        } else {
-            None
-        };
+/// Transfer error counts of an endpoint.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EndpointStats {
+    /// Missed Service Error completions
+    pub missed_service: u32,
+    /// Ring Overrun completions
+    pub ring_overrun: u32,
+    /// Ring Underrun completions
+    pub ring_underrun: u32,
+    /// Babble Detected completions
+    pub babble: u32,
+    /// USB Transaction Error completions
+    pub transaction_error: u32,
+    /// Endpoint recoveries after babble or transaction errors
+    pub recoveries: u32,
+}
 
-        // Setup Stage TRB
-        let setup_trb = Trb {
-            param: unsafe { *(setup as *const SetupPacket as *const u64) },
-            status: 8, // Transfer length = 8
-            control: (trb_type::SETUP << 10)
-                | (1 << 6) // IDT (Immediate Data)
-                | if data_len > 0 && setup.length > 0 {
-                    if data_dir { 3 << 16 } else { 2 << 16 } // TRT: IN or OUT
-                } else {
-                    0 // No data stage
-                },
-        };
-        ep0_ring.enqueue(host, setup_trb);
+/// Transfer ring and counters of one endpoint, shared by the device and
+/// its handles
+struct EpShared<H: Dma> {
+    ring: Lock<Option<Ring<H>>>,
+    missed_service: AtomicU32,
+    ring_overrun: AtomicU32,
+    ring_underrun: AtomicU32,
+    babble: AtomicU32,
+    transaction_error: AtomicU32,
+    recoveries: AtomicU32,
+    /// Microframe at which the current error window started
+    window_start: AtomicU32,
+    /// Errors counted in the current window
+    window_errors: AtomicU32,
+    /// Controller's `events_lost` when the last transfer was queued
+    queued_lost: AtomicU64,
+    /// Default of `queue` for terminating bulk OUT transfers with a ZLP
+    zlp: AtomicBool,
+    /// Number of `cancel_all` calls that dropped transfers
+    cancels: AtomicU32,
+    /// `cancels` when the last transfer was queued
+    queued_cancels: AtomicU32,
+}
 
-        // Data Stage TRB (if needed)
-        if let Some(ref buf) = data_buf {
-            let data_trb = Trb {
-                param: buf.phys(host),
-                status: setup.length as u32,
-                control: (trb_type::DATA << 10)
-                    | if data_dir { 1 << 16 } else { 0 } // DIR
-                    | (1 << 5), // IOC for debugging
-            };
-            ep0_ring.enqueue(host, data_trb);
+impl<H: Dma> Default for EpShared<H> {
+    fn default() -> Self {
+        Self {
+            ring: Lock::new(None),
+            missed_service: AtomicU32::new(0),
+            ring_overrun: AtomicU32::new(0),
+            ring_underrun: AtomicU32::new(0),
+            babble: AtomicU32::new(0),
+            transaction_error: AtomicU32::new(0),
+            recoveries: AtomicU32::new(0),
+            window_start: AtomicU32::new(0),
+            window_errors: AtomicU32::new(0),
+            queued_lost: AtomicU64::new(0),
+            zlp: AtomicBool::new(false),
+            cancels: AtomicU32::new(0),
+            queued_cancels: AtomicU32::new(0),
         }
+    }
+}
 
-        // Status Stage TRB
-        let status_trb = Trb {
-            param: 0,
-            status: 0,
-            control: (trb_type::STATUS << 10)
-                | if data_len > 0 && setup.length > 0 && data_dir { 0 } else { 1 << 16 } // DIR
-                | (1 << 5), // IOC
-        };
-        ep0_ring.enqueue(host, status_trb);
-
-        drop(ep0_ring);
+/// Escalation policy for babble and transaction errors.
+///
+/// Each error first recovers the endpoint. Once one endpoint reports
+/// `limit` errors within `window_us`, or its recovery fails, the device
+/// is due for a reset; see `UsbDevice::recover_from_errors`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorPolicy {
+    /// Errors within the window that escalate to a device reset (0 never
+    /// escalates)
+    pub limit: u32,
+    /// Length of the window, in microseconds
+    pub window_us: u32,
+}
 
-        // Ring doorbell for EP0 (target = 1)
-        self.ctrl.ring_doorbell(self.slot_id, 1);
+impl ErrorPolicy {
+    /// Recover endpoints only, never reset the device.
+    pub const NONE: Self = Self {
+        limit: 0,
+        window_us: 0,
+    };
+}
 
-        // Wait for completion
-        loop {
-            if let Some(evt) = self.ctrl.poll_event()
-                && evt.trb_type() == trb_type::TRANSFER_EVENT as u8
-                && evt.slot_id() == self.slot_id
-            {
-                let code = evt.completion_code();
-                match code {
-                    completion::SUCCESS | completion::SHORT_PACKET => {
-                        let transferred =
-                            (setup.length as usize).saturating_sub(evt.transfer_length() as usize);
-
-                        // Copy data back for IN transfers
-                        if data_dir && let (Some(buf), Some(d)) = (&data_buf, &mut data) {
-                            unsafe {
-                                core::ptr::copy_nonoverlapping(
-                                    buf.as_ptr::<u8>(),
-                                    d.as_mut_ptr(),
-                                    transferred.min(d.len()),
-                                );
-                            }
-                        }
-
-                        if let Some(buf) = data_buf {
-                            buf.free(host);
-                        }
-                        return Ok(transferred);
-                    }
-                    completion::STALL_ERROR => {
-                        if let Some(buf) = data_buf {
-                            buf.free(host);
-                        }
-                        return Err(UsbError::Stall);
-                    }
-                    _ => {
-                        if let Some(buf) = data_buf {
-                            buf.free(host);
-                        }
-                        return Err(UsbError::XferFail(code));
-                    }
-                }
-            }
-            spin_loop();
+impl Default for ErrorPolicy {
+    fn default() -> Self {
+        Self {
+            limit: 3,
+            window_us: 1_000_000,
         }
     }
+}
 
-    /// Get device descriptor
-    pub fn get_device_descriptor(&mut self) -> Result<DeviceDesc> {
-        let mut buf = [0u8; 18];
-        let setup = SetupPacket::get_descriptor(desc_type::DEVICE, 0, 18);
-        self.control_transfer(&setup, Some(&mut buf))?;
+/// Error escalation state of a device, shared with its endpoint handles
+///
+/// The controller only holds it weakly, for `XhciCtrl::release_all_devices`
+/// and `XhciCtrl::topology`.
+#[derive(Default)]
+pub(crate) struct DeviceHealth {
+    policy: Lock<ErrorPolicy>,
+    /// An endpoint asked for a device reset
+    reset_pending: AtomicBool,
+    /// The port was disabled; every transfer fails with `DeviceFailed`
+    failed: AtomicBool,
+    device_resets: AtomicU32,
+    port_disables: AtomicU32,
+    /// The device was detached or released; everything fails with `Disconnected`
+    disconnected: AtomicBool,
+    /// The device was deactivated; everything fails with `Inactive`
+    inactive: AtomicBool,
+    /// Slot still to be disabled, 0 once it was
+    slot_id: AtomicU8,
+    /// Disable Slot failed; the controller may still use the contexts
+    slot_stuck: AtomicBool,
+    /// Identification for `XhciCtrl::topology`; strings stay empty until
+    /// `UsbDevice::summary` is built
+    summary: Lock<DeviceSummary>,
+    /// Class drivers bound to the device, counted by `DriverKind`
+    drivers: [AtomicU8; 3],
+    /// Interval and Max ESIT Payload of the configured endpoints, by DCI
+    /// index; the payload is 0 for all but periodic endpoints
+    periodic: Lock<[(u8, u32); 31]>,
+    /// Transfers queued and not yet completed, by DCI index; the
+    /// controller counts completions as it dequeues their events
+    pending: [AtomicU32; 31],
+}
 
-        let desc = unsafe { *(buf.as_ptr() as *const DeviceDesc) };
-        self.device_desc = Some(desc);
-        Ok(desc)
+impl DeviceHealth {
+    /// Current slot ID, 0 while the device has no slot
+    pub(crate) fn slot_id(&self) -> u8 {
+        self.slot_id.load(Ordering::Acquire)
     }
 
-    /// Get configuration descriptor (full, with interfaces and endpoints)
-    pub fn get_config_descriptor(&self, index: u8) -> Result<Vec<u8>> {
-        // First, get just the config descriptor to find total length
-        let mut buf = [0u8; 9];
-        let setup = SetupPacket::get_descriptor(desc_type::CONFIGURATION, index, 9);
-        self.control_transfer(&setup, Some(&mut buf))?;
+    /// Transfers queued on an endpoint and not yet completed
+    fn pending(&self, dci: Dci) -> &AtomicU32 {
+        &self.pending[dci.index()]
+    }
 
-        let config = unsafe { *(buf.as_ptr() as *const ConfigDesc) };
-        let total_len = config.total_length as usize;
+    /// Count a dequeued Transfer Event for endpoint `ep_id` (a DCI)
+    pub(crate) fn transfer_completed(&self, ep_id: u8) {
+        let Some(pending) = self.pending.get((ep_id as usize).wrapping_sub(1)) else {
+            return;
+        };
+        let _ = pending.fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+    }
 
-        // Now get the full descriptor
-        let mut full_buf = alloc::vec![0u8; total_len];
-        let setup = SetupPacket::get_descriptor(desc_type::CONFIGURATION, index, total_len as u16);
-        self.control_transfer(&setup, Some(&mut full_buf))?;
+    /// The device was detached or released
+    pub(crate) fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::Acquire)
+    }
 
-        Ok(full_buf)
+    /// Copy of the identification and the bound drivers, as (slot ID, summary, drivers)
+    pub(crate) fn snapshot(&self) -> (u8, DeviceSummary, Vec<DriverKind>) {
+        let drivers = DriverKind::ALL
+            .into_iter()
+            .filter(|&kind| self.drivers[kind as usize].load(Ordering::Acquire) != 0)
+            .collect();
+        (self.slot_id(), self.summary.lock().clone(), drivers)
     }
 
-    /// Set configuration
-    pub fn set_configuration(&self, config: u8) -> Result<()> {
-        let setup = SetupPacket::set_configuration(config);
-        self.control_transfer(&setup, None)?;
+    /// Add the configured periodic endpoints to `load`
+    pub(crate) fn add_periodic_load(&self, load: &mut LoadSummary) {
+        let estimate = if self.summary.lock().speed.is_super_or_faster() {
+            &mut load.superspeed
+        } else {
+            &mut load.usb2
+        };
+        let before = estimate.endpoints;
+        for &(interval, payload) in self.periodic.lock().iter() {
+            estimate.add(interval, payload);
+        }
+        if estimate.endpoints != before {
+            load.devices += 1;
+        }
+    }
+
+    /// Take the IDs and class of the device descriptor into the summary
+    fn record_descriptor(&self, desc: &DeviceDesc) {
+        let mut summary = self.summary.lock();
+        summary.vendor_id = desc.vendor_id;
+        summary.product_id = desc.product_id;
+        summary.bcd_device = desc.bcd_device;
+        summary.class = (
+            desc.device_class,
+            desc.device_subclass,
+            desc.device_protocol,
+        );
+    }
+
+    fn check_failed(&self) -> Result<()> {
+        if self.disconnected.load(Ordering::Acquire) {
+            return Err(UsbError::Disconnected);
+        }
+        if self.inactive.load(Ordering::Acquire) {
+            return Err(UsbError::Inactive);
+        }
+        if self.failed.load(Ordering::Acquire) {
+            return Err(UsbError::DeviceFailed);
+        }
         Ok(())
     }
 
-    /// Configure an endpoint (after SET_CONFIGURATION)
-    pub fn configure_endpoint(&self, ep: &EndpointDesc) -> Result<()> {
-        let host = self.ctrl.host();
+    /// Invalidate the device and disable its slot
+    pub(crate) fn release<H: Dma>(&self, ctrl: &XhciCtrl<H>) {
+        self.disconnected.store(true, Ordering::Release);
+        self.disable_slot(ctrl);
+    }
 
-        let ep_num = ep.number();
-        let is_in = ep.is_in();
-        let ep_type = ep.transfer_type();
+    /// Disable the slot unless that was done already
+    fn disable_slot<H: Dma>(&self, ctrl: &XhciCtrl<H>) {
+        let slot_id = self.slot_id.swap(0, Ordering::AcqRel);
+        if slot_id != 0 && ctrl.disable_slot(slot_id).is_err() {
+            self.slot_stuck.store(true, Ordering::Release);
+        }
+    }
+}
 
-        // Endpoint Context Index: EP1 OUT = 2, EP1 IN = 3, EP2 OUT = 4, etc.
-        let dci = (ep_num as usize * 2) + if is_in { 1 } else { 0 };
-        let ring_idx = dci - 1; // rings array is 0-indexed for EP1+
+type EpRing<H> = Arc<EpShared<H>>;
 
-        // Allocate transfer ring for this endpoint
-        let ring = Ring::new(host, 256)?;
-        let ring_phys = ring.phys(host);
+/// Isoch TRB of a single-TRB TD of `len` bytes at `phys`
+///
+/// The Transfer Burst Count and Last Burst Packet Count are derived from
+/// the endpoint's max packet size and Max Burst. Without `start_frame`
+/// the TD starts as soon as possible (SIA).
+fn isoch_td(
+    phys: u64,
+    len: usize,
+    max_packet: usize,
+    max_burst: usize,
+    start_frame: Option<u16>,
+) -> Trb {
+    // Packets in the TD, at least one for a zero-length TD
+    let packets = len.div_ceil(max_packet.max(1)).max(1);
+    let burst = max_burst + 1;
+    let tbc = packets.div_ceil(burst) - 1;
+    let tlbpc = (packets - 1) % burst;
+    let start = match start_frame {
+        Some(frame) => ((frame as u32) & 0x7ff) << 20,
+        None => 1 << 31, // SIA
+    };
+    Trb {
+        param: phys,
+        status: len as u32,
+        control: (trb_type::ISOCH << 10)
+            | (1 << 5) // IOC
+            | ((tbc as u32 & 0x3) << 7)
+            | ((tlbpc as u32 & 0xf) << 16)
+            | start,
+    }
+}
 
-        // Update input context
-        let input = self.input_ctx.as_ptr::<InputContext>();
-        unsafe {
-            (*input).input_control[0] = 0; // Drop flags
-            (*input).input_control[1] = (1 << dci) | 1; // Add flags: this EP + Slot
-
-            // xHCI endpoint type encoding
-            let xhci_ep_type = match (ep_type, is_in) {
-                (0, _) => 4,     // Control (bidirectional)
-                (1, false) => 1, // Isoch OUT
-                (1, true) => 5,  // Isoch IN
-                (2, false) => 2, // Bulk OUT
-                (2, true) => 6,  // Bulk IN
-                (3, false) => 3, // Interrupt OUT
-                (3, true) => 7,  // Interrupt IN
-                _ => 4,
-            };
+/// Normal TRBs of a transfer of `len` bytes at `phys`, and how many are used
+///
+/// The data TD is followed by a zero-length TD if `zlp` is set and `len`
+/// is a non-zero multiple of `max_packet`; only the last TD interrupts on
+/// completion.
+fn normal_tds(phys: u64, len: usize, max_packet: u16, zlp: bool) -> ([Trb; 2], usize) {
+    const IOC: u32 = 1 << 5;
+    let data = Trb {
+        param: phys,
+        status: len as u32,
+        control: trb_type::NORMAL << 10,
+    };
+    if zlp && len != 0 && len.is_multiple_of(max_packet as usize) {
+        let zero = Trb {
+            param: 0,
+            status: 0,
+            control: (trb_type::NORMAL << 10) | IOC,
+        };
+        return ([data, zero], 2);
+    }
+    let data = Trb {
+        control: data.control | IOC,
+        ..data
+    };
+    ([data, Trb::default()], 1)
+}
 
-            // Calculate interval for xHCI (different from USB descriptor)
-            let interval = if self.speed >= reg::SPEED_HIGH {
-                ep.interval.saturating_sub(1)
-            } else {
-                // For FS/LS, convert ms to 125us frames
-                // Use integer log2: find highest set bit
-                let ms = ep.interval.max(1) as u32;
-                let log2_ceil = if ms.is_power_of_two() {
-                    ms.trailing_zeros() as u8
-                } else {
-                    (u32::BITS - ms.leading_zeros()) as u8
-                };
-                log2_ceil + 3
-            };
+/// Handle to a configured endpoint of a `UsbDevice`.
+///
+/// Obtained from `UsbDevice::configure_endpoint` or `UsbDevice::endpoint`.
+/// Each endpoint's ring has its own lock, so handles on different
+/// endpoints never block each other. A handle stays valid across
+/// reconfiguration of its endpoint; once the endpoint's ring is
+/// released, operations fail with `InvEndpoint`.
+pub struct EndpointHandle<H: Dma> {
+    ctrl: Arc<XhciCtrl<H>>,
+    dci: Dci,
+    shared: EpRing<H>,
+    device_ctx: Arc<PhysMem<H>>,
+    health: Arc<DeviceHealth>,
+}
 
-            (*input).endpoints[ring_idx] =
-                EndpointContext::new(xhci_ep_type, ep.max_packet_size, 0, interval, ring_phys);
+impl<H: Dma> Clone for EndpointHandle<H> {
+    fn clone(&self) -> Self {
+        Self {
+            ctrl: self.ctrl.clone(),
+            dci: self.dci,
+            shared: self.shared.clone(),
+            device_ctx: self.device_ctx.clone(),
+            health: self.health.clone(),
         }
+    }
+}
 
-        // Store ring
-        let mut ep_rings = self.ep_rings.lock();
-        ep_rings[ring_idx] = Some(ring);
-        drop(ep_rings);
+impl<H: Dma> EndpointHandle<H> {
+    /// Returns the endpoint's Device Context Index.
+    pub fn dci(&self) -> Dci {
+        self.dci
+    }
 
-        // Configure Endpoint command
-        let trb = Trb {
-            param: self.input_ctx.phys(host),
-            status: 0,
-            control: (trb_type::CONFIGURE_ENDPOINT << 10) | ((self.slot_id as u32) << 24),
-        };
-        self.ctrl.submit_command(trb)?;
+    /// Queue a transfer and ring the endpoint's doorbell
+    ///
+    /// Fails with `DeviceFailed` once the device's port was disabled, with
+    /// `Disconnected` once the device was detached or released, and with
+    /// `Inactive` while it is deactivated. Bulk OUT transfers are
+    /// terminated with a zero-length packet as set with `set_zlp`.
+    pub fn queue(&self, buf: &PhysMem<H>, len: usize) -> Result<()> {
+        self.queue_with_zlp(buf, len, self.zlp())
+    }
 
-        Ok(())
+    /// Like `queue`, choosing whether a zero-length packet ends the transfer
+    ///
+    /// With `zlp` set, a bulk OUT transfer whose length is a non-zero
+    /// multiple of the endpoint's max packet size is followed by a
+    /// zero-length TD. Only that TD interrupts on completion, so `wait`
+    /// still sees one Transfer Event. Other endpoints and lengths ignore
+    /// `zlp`.
+    pub fn queue_with_zlp(&self, buf: &PhysMem<H>, len: usize, zlp: bool) -> Result<()> {
+        self.health.check_failed()?;
+        let host = self.ctrl.host();
+
+        let zlp = zlp && self.is_bulk_out();
+        let (trbs, count) = normal_tds(buf.phys(host), len, self.max_packet_size(), zlp);
+        self.submit(&trbs[..count])
     }
 
-    /// Queue a transfer on an endpoint
-    pub fn queue_transfer(
+    /// Queue an isochronous transfer and ring the endpoint's doorbell
+    ///
+    /// The TD is transferred in frame `start_frame` (11 bits), e.g. as
+    /// returned by `XhciCtrl::next_start_frame`, or as soon as possible if
+    /// `None`. Fails with `InvEndpoint` unless the endpoint is isochronous,
+    /// and otherwise like `queue`.
+    pub fn queue_isoch(
         &self,
-        ep_num: u8,
-        is_in: bool,
         buf: &PhysMem<H>,
         len: usize,
+        start_frame: Option<u16>,
     ) -> Result<()> {
-        let dci = (ep_num as usize * 2) + if is_in { 1 } else { 0 };
-        let ring_idx = dci - 1;
+        let dw1 = self.context_dw1();
+        // EP Types 1 and 5 are Isoch OUT and IN
+        if (dw1 >> 3) & 0x3 != 1 {
+            return Err(UsbError::InvEndpoint);
+        }
+        self.health.check_failed()?;
 
-        let mut ep_rings = self.ep_rings.lock();
-        let ring = ep_rings[ring_idx].as_mut().ok_or(UsbError::InvEndpoint)?;
+        let max_packet = (dw1 >> 16) as usize;
+        let max_burst = ((dw1 >> 8) & 0xff) as usize;
+        let phys = buf.phys(self.ctrl.host());
+        self.submit(&[isoch_td(phys, len, max_packet, max_burst, start_frame)])
+    }
 
+    /// Put a TD on the ring and ring the doorbell
+    fn submit(&self, trbs: &[Trb]) -> Result<()> {
         let host = self.ctrl.host();
-        let trb = Trb {
-            param: buf.phys(host),
-            status: len as u32,
-            control: (trb_type::NORMAL << 10) | (1 << 5), // IOC
-        };
-        ring.enqueue(host, trb);
-        drop(ep_rings);
+        let mut guard = self.shared.ring.lock();
+        let ring = guard.as_mut().ok_or(UsbError::InvEndpoint)?;
+        for trb in trbs {
+            ring.enqueue(host, *trb);
+        }
+        self.health.pending(self.dci).fetch_add(1, Ordering::AcqRel);
+        let cancels = self.shared.cancels.load(Ordering::Acquire);
+        self.shared.queued_cancels.store(cancels, Ordering::Release);
+        drop(guard);
+        for trb in trbs {
+            self.ctrl
+                .trace_transfer(self.health.slot_id(), self.dci.raw(), trb);
+        }
 
-        // Ring doorbell
-        self.ctrl.ring_doorbell(self.slot_id, dci as u8);
+        let lost = self.ctrl.events_lost();
+        self.shared.queued_lost.store(lost, Ordering::Relaxed);
+        self.ctrl
+            .ring_doorbell(self.health.slot_id(), self.dci.raw())
+    }
 
-        Ok(())
+    /// Returns true if the event ring overflowed since the last `queue`.
+    ///
+    /// The completion of that transfer may have been dropped, so polling
+    /// for it can come up empty forever; `abort` the endpoint and queue
+    /// the transfer again.
+    pub fn events_lost_since_queue(&self) -> bool {
+        self.ctrl.events_lost() != self.shared.queued_lost.load(Ordering::Relaxed)
     }
 
-    /// Returns the xHCI slot ID assigned to this device.
-    pub fn slot_id(&self) -> u8 {
-        self.slot_id
+    /// Set whether `queue` ends bulk OUT transfers with a zero-length packet
+    ///
+    /// Applies to every handle on the endpoint and is kept across its
+    /// reconfiguration; off by default. Drivers for protocols such as
+    /// CDC-ACM set it once after claiming their interface.
+    pub fn set_zlp(&self, zlp: bool) {
+        self.shared.zlp.store(zlp, Ordering::Relaxed);
     }
 
-    /// Returns the root hub port number this device is connected to.
-    pub fn port(&self) -> u8 {
-        self.port
+    /// Returns true if `queue` ends bulk OUT transfers with a zero-length packet.
+    pub fn zlp(&self) -> bool {
+        self.shared.zlp.load(Ordering::Relaxed)
     }
 
-    /// Returns the device speed (see `reg::SPEED_*` constants).
-    pub fn speed(&self) -> u8 {
-        self.speed
+    /// Read the max packet size from the Device Context
+    pub fn max_packet_size(&self) -> u16 {
+        (self.context_dw1() >> 16) as u16
     }
 
-    /// Returns a reference to the xHCI controller.
-    pub fn ctrl(&self) -> &Arc<XhciCtrl<H>> {
-        &self.ctrl
+    fn is_bulk_out(&self) -> bool {
+        // EP Type 2 is Bulk OUT
+        (self.context_dw1() >> 3) & 0x7 == 2
     }
-}
 
-impl<H: Dma> Drop for UsbDevice<H> {
-    fn drop(&mut self) {
-        let _ = self.ctrl.disable_slot(self.slot_id);
+    fn context_dw1(&self) -> u32 {
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        unsafe { core::ptr::addr_of!((*ctx).endpoints[self.dci.index()].dw1).read_volatile() }
+    }
 
-        let host = self.ctrl.host();
+    /// Returns true if `evt` is a Transfer Event for this endpoint.
+    pub fn matches(&self, evt: &Trb) -> bool {
+        evt.trb_type() == trb_type::TRANSFER_EVENT as u8
+            && evt.slot_id() == self.health.slot_id()
+            && evt.endpoint_id() == self.dci.raw()
+    }
 
-        // Free endpoint rings
-        let mut ep_rings = self.ep_rings.lock();
-        for ring in ep_rings.iter_mut() {
-            if let Some(r) = ring.take() {
-                r.free(host);
+    /// Take the next Transfer Event of this endpoint, if one arrived
+    ///
+    /// The completion code is not mapped; see `check`.
+    pub fn poll(&self) -> Option<Trb> {
+        self.ctrl.poll_event_where(|e| self.matches(e))
+    }
+
+    /// Wait for the next Transfer Event of this endpoint
+    ///
+    /// Like `wait`, without mapping the completion code. Fails with
+    /// `Cancelled` once `cancel_all` drops the awaited transfer, and with
+    /// `EventLost` if the event ring overflows first.
+    pub fn wait_event(&self, timeout_us: u32) -> Result<Trb> {
+        let mut watch = self.ctrl.stopwatch();
+        let lost = self.ctrl.events_lost();
+        let cancels = self.shared.cancels.load(Ordering::Acquire);
+
+        loop {
+            if let Some(evt) = self.poll() {
+                return Ok(evt);
+            }
+            if self.cancelled_since(cancels) {
+                return Err(UsbError::Cancelled);
+            }
+            if self.ctrl.events_lost() != lost {
+                return Err(UsbError::EventLost);
+            }
+            self.ctrl.check_alive()?;
+            if timeout_us != 0 && watch.elapsed_us(&self.ctrl) >= timeout_us as u64 {
+                return Err(UsbError::Timeout);
+            }
+            spin_loop();
+        }
+    }
+
+    /// Wait for the completion of a queued transfer
+    ///
+    /// Returns the Transfer Event on success or short packet; stalls and
+    /// other completion codes are mapped to errors. A `timeout_us` of 0
+    /// waits forever.
+    pub fn wait(&self, timeout_us: u32) -> Result<Trb> {
+        let evt = self.wait_event(timeout_us)?;
+        self.check(&evt)?;
+        Ok(evt)
+    }
+
+    /// True if `cancel_all` ran since it counted `cancels`, or dropped the
+    /// last transfer queued
+    fn cancelled_since(&self, cancels: u32) -> bool {
+        let now = self.shared.cancels.load(Ordering::Acquire);
+        now != cancels
+            || (self.pending() == 0 && now != self.shared.queued_cancels.load(Ordering::Acquire))
+    }
+
+    /// Returns the number of transfers queued and not yet completed.
+    ///
+    /// A transfer counts as completed once the controller dequeues its
+    /// Transfer Event, whoever takes the event (`poll`, `wait` or
+    /// `XhciCtrl::poll_event_where`), or when the transfers left on the
+    /// ring are dropped.
+    pub fn pending(&self) -> usize {
+        self.health.pending(self.dci).load(Ordering::Acquire) as usize
+    }
+
+    /// Cancel every transfer queued on the endpoint
+    ///
+    /// Stops the endpoint and moves its dequeue pointer past the pending
+    /// TRBs, so the ring takes new transfers right away. Waits on the
+    /// dropped transfers fail with `Cancelled`. Completions that arrived
+    /// before the endpoint stopped are kept for `poll`. Returns the
+    /// number of transfers cancelled.
+    pub fn cancel_all(&self) -> Result<usize> {
+        let pending = self.pending();
+        if pending == 0 {
+            return Ok(0);
+        }
+        self.restart()?;
+        self.shared.cancels.fetch_add(1, Ordering::AcqRel);
+        // Discard the Stopped event of the transfer in progress
+        let stopped = |e: &Trb| self.matches(e) && completion::is_stopped(e.completion_code());
+        while self.ctrl.poll_event_where(stopped).is_some() {}
+        Ok(pending)
+    }
+
+    /// Map the completion code of a Transfer Event for this endpoint
+    ///
+    /// Success and short packets are `Ok`. Missed Service, Ring Overrun
+    /// and Ring Underrun completions are counted in `stats`.
+    ///
+    /// Other errors that halt the endpoint, such as Babble and USB
+    /// Transaction Errors, restart it here and count against the
+    /// device's `ErrorPolicy`.
+    pub fn check(&self, evt: &Trb) -> Result<()> {
+        match evt.completion_code() {
+            code if completion::is_success(code) => Ok(()),
+            completion::STALL_ERROR => Err(UsbError::Stall),
+            code if completion::is_stopped(code) => Err(UsbError::Cancelled),
+            code if completion::is_endpoint_halting(code) => {
+                match code {
+                    completion::BABBLE_DETECTED => &self.shared.babble,
+                    _ => &self.shared.transaction_error,
+                }
+                .fetch_add(1, Ordering::Relaxed);
+                self.recover_from_error();
+                Err(UsbError::XferFail(code))
             }
+            completion::MISSED_SERVICE => {
+                self.shared.missed_service.fetch_add(1, Ordering::Relaxed);
+                Err(UsbError::MissedService)
+            }
+            completion::RING_OVERRUN => {
+                self.shared.ring_overrun.fetch_add(1, Ordering::Relaxed);
+                Err(UsbError::RingOverrun)
+            }
+            completion::RING_UNDERRUN => {
+                self.shared.ring_underrun.fetch_add(1, Ordering::Relaxed);
+                Err(UsbError::RingUnderrun)
+            }
+            code => Err(UsbError::XferFail(code)),
         }
-        drop(ep_rings);
+    }
+
+    /// Returns the error counts of this endpoint.
+    ///
+    /// The counts persist across reconfiguration of the endpoint.
+    pub fn stats(&self) -> EndpointStats {
+        EndpointStats {
+            missed_service: self.shared.missed_service.load(Ordering::Relaxed),
+            ring_overrun: self.shared.ring_overrun.load(Ordering::Relaxed),
+            ring_underrun: self.shared.ring_underrun.load(Ordering::Relaxed),
+            babble: self.shared.babble.load(Ordering::Relaxed),
+            transaction_error: self.shared.transaction_error.load(Ordering::Relaxed),
+            recoveries: self.shared.recoveries.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Restart the endpoint after an error and apply the `ErrorPolicy`
+    fn recover_from_error(&self) {
+        let recovered = self.restart().is_ok();
+        if recovered {
+            self.shared.recoveries.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let policy = *self.health.policy.lock();
+        if policy.limit == 0 {
+            return;
+        }
+
+        // 125 us per microframe; a wrap of the counter starts a new window
+        let now = self.ctrl.microframe_counter();
+        let start = self.shared.window_start.load(Ordering::Relaxed);
+        let elapsed_us = now.wrapping_sub(start) as u64 * 125;
+        let errors = if elapsed_us > policy.window_us as u64 {
+            self.shared.window_start.store(now, Ordering::Relaxed);
+            self.shared.window_errors.store(1, Ordering::Relaxed);
+            1
+        } else {
+            self.shared.window_errors.fetch_add(1, Ordering::Relaxed) + 1
+        };
+
+        if !recovered || errors >= policy.limit {
+            self.shared.window_errors.store(0, Ordering::Relaxed);
+            self.health.reset_pending.store(true, Ordering::Release);
+        }
+    }
+
+    /// Stop the endpoint with a Stop Endpoint command
+    pub fn stop(&self) -> Result<()> {
+        self.endpoint_command(trb_type::STOP_ENDPOINT, 0)
+    }
+
+    /// Recover a halted endpoint
+    ///
+    /// Issues Reset Endpoint, then moves the dequeue pointer past any
+    /// TRBs left on the ring. The device side halt must still be cleared
+    /// with CLEAR_FEATURE(ENDPOINT_HALT).
+    pub fn reset(&self) -> Result<()> {
+        self.endpoint_command(trb_type::RESET_ENDPOINT, 0)?;
+        self.skip_pending()
+    }
+
+    /// Restart an endpoint after a Ring Overrun or Underrun
+    ///
+    /// A halted endpoint is reset; otherwise it is stopped. Either way
+    /// the dequeue pointer is moved past any TRBs left on the ring, so
+    /// the next queued transfer starts cleanly.
+    pub fn restart(&self) -> Result<()> {
+        match self.state() {
+            EndpointState::Halted => return self.reset(),
+            EndpointState::Stopped | EndpointState::Error => {}
+            _ => match self.stop() {
+                // The endpoint stopped on its own in the meantime
+                Ok(()) | Err(UsbError::CmdFail(completion::CONTEXT_STATE_ERROR)) => {}
+                Err(e) => return Err(e),
+            },
+        }
+        self.skip_pending()
+    }
+
+    /// Stop the endpoint and drop the transfers queued on it
+    ///
+    /// Once this returns `Ok`, the controller no longer accesses the
+    /// buffers of those transfers, so they may be freed; on error they
+    /// must be leaked. Their Stopped events are discarded.
+    pub fn abort(&self) -> Result<()> {
+        // A disabled slot or endpoint runs no transfers
+        if self.health.slot_id() == 0 || self.state() == EndpointState::Disabled {
+            return Ok(());
+        }
+        self.restart()?;
+        while self.poll().is_some() {}
+        Ok(())
+    }
+
+    /// Set TR Dequeue Pointer to the ring's enqueue position
+    fn skip_pending(&self) -> Result<()> {
+        let dequeue = {
+            let ring = self.shared.ring.lock();
+            ring.as_ref().ok_or(UsbError::InvEndpoint)?.enqueue_ptr(self.ctrl.host())
+        };
+        self.endpoint_command(trb_type::SET_TR_DEQUEUE, dequeue)?;
+        self.health.pending(self.dci).store(0, Ordering::Release);
+        Ok(())
+    }
+
+    /// Read the service interval from the Device Context
+    ///
+    /// In the xHCI encoding: the endpoint is serviced every
+    /// 2^interval * 125 microseconds.
+    pub fn interval(&self) -> u8 {
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        let dw0 = unsafe {
+            core::ptr::addr_of!((*ctx).endpoints[self.dci.index()].dw0).read_volatile()
+        };
+        (dw0 >> 16) as u8
+    }
+
+    /// Read the endpoint state from the Device Context
+    pub fn state(&self) -> EndpointState {
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        let dw0 = unsafe {
+            core::ptr::addr_of!((*ctx).endpoints[self.dci.index()].dw0).read_volatile()
+        };
+        EndpointState::from_raw(dw0 as u8)
+    }
+
+    fn endpoint_command(&self, trb_type: u32, param: u64) -> Result<()> {
+        let trb = Trb {
+            param,
+            status: 0,
+            control: (trb_type << 10)
+                | ((self.dci.raw() as u32) << 16)
+                | ((self.health.slot_id() as u32) << 24),
+        };
+        self.ctrl.submit_command(trb)?;
+        Ok(())
+    }
+}
+
+/// Position of a device attached to a downstream port of an external hub
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct HubAttach {
+    /// Route string of the device
+    pub route: u32,
+    /// Speed decoded from the hub's port status
+    pub speed: Speed,
+    /// Slot ID and port of the high-speed hub whose TT serves the device
+    pub tt: Option<(u8, u8)>,
+    /// That hub has one TT per port
+    pub multi_tt: bool,
+    /// Exit latencies of a SuperSpeed hub's link and path, if known
+    pub hub_link: Option<LinkPath>,
+}
+
+/// U1 and U2 exit latencies in nanoseconds
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ExitLatency {
+    pub u1: u32,
+    pub u2: u32,
+}
+
+impl ExitLatency {
+    /// Device exit latencies advertised in a SuperSpeed capability
+    pub fn from_capability(cap: &SsDevCapDesc) -> Self {
+        Self {
+            u1: cap.u1_dev_exit_lat as u32 * 1000,
+            u2: { cap.u2_dev_exit_lat } as u32 * 1000,
+        }
+    }
+}
+
+/// Exit latencies of the path from the host to a SuperSpeed device
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct LinkPath {
+    /// Exit latency of the device itself
+    pub exit: ExitLatency,
+    /// Device to Host Exit Latency (PEL) of the whole path
+    pub pel: ExitLatency,
+}
+
+impl LinkPath {
+    /// Path of a device whose upstream port has exit latency `parent_exit`
+    ///
+    /// `parent` is the path of the hub the device attaches to, or `None`
+    /// on a root port. A link exits as slowly as its slower end, and a
+    /// hub wakes its upstream link tHubPort2PortExitLat (1 us) after its
+    /// downstream port; for U2 by the hub's U2 and U1 exit latency
+    /// difference more (USB 3.2 C.1.5.2, 10.4.2.4).
+    pub fn new(exit: ExitLatency, parent_exit: ExitLatency, parent: Option<&LinkPath>) -> Self {
+        let first_link = ExitLatency {
+            u1: exit.u1.max(parent_exit.u1),
+            u2: exit.u2.max(parent_exit.u2),
+        };
+        let pel = match parent {
+            None => first_link,
+            Some(hub) => {
+                let u2_port_to_port = if parent_exit.u2 > parent_exit.u1 {
+                    1000 + parent_exit.u2 - parent_exit.u1
+                } else {
+                    1000 + parent_exit.u1
+                };
+                ExitLatency {
+                    u1: first_link.u1.max(1000 + hub.pel.u1),
+                    u2: first_link.u2.max(u2_port_to_port + hub.pel.u2),
+                }
+            }
+        };
+        Self { exit, pel }
+    }
+
+    /// SET_SEL latencies for a device `hubs` external hubs deep
+    ///
+    /// The System Exit Latency adds the time for the first hub to wake
+    /// the rest of the path (2.1 us, plus 250 ns per further hub) and
+    /// 250 ns per hub to forward the host's reply (USB 3.2 C.1.5.1).
+    /// Rounded up to microseconds and clamped to the field widths.
+    pub fn sel_data(&self, hubs: u8) -> SelData {
+        let hubs = hubs as u32;
+        let wake = if hubs > 0 { 2100 + 250 * (hubs - 1) } else { 0 };
+        let sel = |pel: u32| pel + wake + 250 * hubs;
+        let us = |ns: u32| ns.div_ceil(1000);
+        SelData {
+            u1_sel: us(sel(self.pel.u1)).min(0xff) as u8,
+            u1_pel: us(self.pel.u1).min(0xff) as u8,
+            u2_sel: us(sel(self.pel.u2)).min(0xffff) as u16,
+            u2_pel: us(self.pel.u2).min(0xffff) as u16,
+        }
+    }
+}
+
+/// `InsufficientBandwidth` naming the most demanding of the endpoints added
+///
+/// The controller does not tell which endpoint did not fit.
+fn bandwidth_error(eps: &[EndpointDesc]) -> UsbError {
+    let ep = eps.iter().rev().max_by_key(|ep| ep.esit_payload());
+    UsbError::InsufficientBandwidth {
+        endpoint: ep.map_or(0, |ep| ep.endpoint_address),
+        esit_payload: ep.map_or(0, |ep| ep.esit_payload()),
+    }
+}
+
+/// Fill the Input Context for an Address Device command (Slot + EP0)
+fn address_input<H: Dma>(
+    input_ctx: &PhysMem<H>,
+    speed: Speed,
+    port: u8,
+    below_hub: Option<&HubAttach>,
+    ep0_phys: u64,
+) {
+    let input = input_ctx.as_ptr::<InputContext>();
+    unsafe {
+        // Add flags: Slot Context (bit 0) + EP0 Context (bit 1)
+        (*input).input_control[0] = 0;
+        (*input).input_control[1] = 0b11;
+
+        // Slot Context
+        let route = below_hub.map_or(0, |attach| attach.route);
+        (*input).slot = SlotContext::new(route, speed.as_raw(), 1, port + 1);
+        if let Some(HubAttach {
+            tt: Some((hub_slot, hub_port)),
+            multi_tt,
+            ..
+        }) = below_hub
+        {
+            (*input).slot.dw0 |= (*multi_tt as u32) << 25;
+            (*input).slot.dw2 |= (*hub_slot as u32) | ((*hub_port as u32) << 8);
+        }
+
+        // EP0 Context (Control endpoint)
+        (*input).endpoints[0] = EndpointContext::new(
+            4, // Control Bidirectional
+            speed.default_ep0_max_packet(),
+            0,
+            0,
+            ep0_phys,
+        );
+    }
+}
+
+/// In-flight control transfer on EP0.
+pub(crate) struct ControlTransfer<H: Dma> {
+    data_in: bool,
+    data_len: usize,
+    data_buf: Option<PhysMem<H>>,
+    data_trb: Option<u64>,
+    status_trb: u64,
+    transferred: usize,
+    /// TRBs reserved on the EP0 ring
+    trbs: Reservation,
+}
+
+impl<H: Dma> ControlTransfer<H> {
+    /// Returns true if `evt` completes one of this transfer's stages
+    fn matches(&self, slot_id: u8, evt: &Trb) -> bool {
+        evt.trb_type() == trb_type::TRANSFER_EVENT as u8
+            && evt.slot_id() == slot_id
+            && (evt.param == self.status_trb || Some(evt.param) == self.data_trb)
+    }
+
+    /// Placeholder left behind once a transfer has completed
+    fn done() -> Self {
+        Self {
+            data_in: false,
+            data_len: 0,
+            data_buf: None,
+            data_trb: None,
+            status_trb: 0,
+            transferred: 0,
+            trbs: Reservation::default(),
+        }
+    }
+}
+
+/// Callback run by `UsbDevice::reset_and_restore` once the device is back.
+pub type RestoreHook<H> = Box<dyn FnMut(&UsbDevice<H>) -> Result<()> + Send>;
+
+/// Callback run by `UsbDevice::switch_configuration` with the new configuration.
+pub type ConfigHook<H> = Box<dyn FnMut(&UsbDevice<H>, &ConfigTree) -> Result<()> + Send>;
+
+/// Default configuration policy
+///
+/// Picks the first configuration with an interface one of the crate's
+/// class drivers binds to (HID, Bulk-Only mass storage or hub), else the
+/// first configuration.
+pub fn default_config_policy(_device: &DeviceDesc, configs: &[Arc<ConfigTree>]) -> usize {
+    configs
+        .iter()
+        .position(|tree| has_class_driver(tree))
+        .unwrap_or(0)
+}
+
+/// Check if a configuration has an interface one of the class drivers binds to
+fn has_class_driver(tree: &ConfigTree) -> bool {
+    class_drivers(tree).next().is_some()
+}
+
+/// Class drivers with an interface to bind to in a configuration
+fn class_drivers(tree: &ConfigTree) -> impl Iterator<Item = DriverKind> {
+    let raw = tree.raw();
+    let found = [
+        (DriverKind::Hid, !find_hid_interfaces(raw).is_empty()),
+        (DriverKind::MassStorage, !find_msc_interfaces(raw).is_empty()),
+        (DriverKind::Hub, !find_hub_interfaces(raw).is_empty()),
+    ];
+    found.into_iter().filter_map(|(kind, found)| found.then_some(kind))
+}
+
+/// Retry policy for control transfers failing with transient errors.
+///
+/// Only USB Transaction and Split Transaction errors are retried; STALL,
+/// Babble and every other completion fail immediately.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total attempts, including the first (0 and 1 disable retries)
+    pub attempts: u8,
+    /// Delay before the first retry, doubled for each further one
+    pub backoff_us: u32,
+}
+
+impl RetryPolicy {
+    /// No retries.
+    pub const NONE: Self = Self {
+        attempts: 1,
+        backoff_us: 0,
+    };
+
+    /// Returns true if a transfer failing with `err` may be retried.
+    pub fn retryable(err: &UsbError) -> bool {
+        matches!(err, UsbError::XferFail(code) if completion::is_retryable(*code))
+    }
+
+    /// Returns the delay before retry number `attempt` (1 for the first).
+    pub fn backoff_before(&self, attempt: u8) -> u32 {
+        let doublings = attempt.saturating_sub(1).min(16);
+        self.backoff_us.saturating_mul(1 << doublings)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff_us: 1000,
+        }
+    }
+}
+
+/// Workaround for a USB device that misreports its abilities.
+///
+/// Registered with `XhciCtrl::add_device_quirk` and applied as soon as
+/// the device descriptor of a matching device is read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeviceQuirk {
+    /// Vendor ID the quirk applies to
+    pub vendor_id: u16,
+    /// Product ID the quirk applies to
+    pub product_id: u16,
+    /// EP0 max packet size to use instead of bMaxPacketSize0
+    pub ep0_max_packet: Option<u16>,
+}
+
+impl DeviceQuirk {
+    /// Returns a quirk clamping the EP0 max packet size of a device.
+    pub fn clamp_ep0(vendor_id: u16, product_id: u16, max_packet: u16) -> Self {
+        Self {
+            vendor_id,
+            product_id,
+            ep0_max_packet: Some(max_packet),
+        }
+    }
+
+    /// Returns true if the quirk applies to the device described by `desc`.
+    pub fn matches(&self, desc: &DeviceDesc) -> bool {
+        desc.vendor_id == self.vendor_id && desc.product_id == self.product_id
+    }
+}
+
+/// Topological position of a device, stable across reconnects.
+///
+/// Displayed in dotted form as `bus-port.hub_port...` with 1-based port
+/// numbers, e.g. `0-3.2.1` for port 1 of a hub on port 2 of a hub on
+/// root port 3 of bus 0.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DevicePath {
+    /// Controller tag set with `XhciCtrl::set_bus_index`
+    pub bus: u8,
+    /// Root hub port (0-based)
+    pub root_port: u8,
+    /// Hub route string, 0 for a device on a root port
+    pub route: u32,
+}
+
+impl DevicePath {
+    /// Returns the hub port numbers below the root port, outermost first.
+    pub fn hub_ports(&self) -> impl Iterator<Item = u8> + use<> {
+        let route = self.route;
+        (0..5)
+            .map(move |tier| ((route >> (tier * 4)) & 0xf) as u8)
+            .take_while(|&port| port != 0)
+    }
+
+    /// Returns the number of hubs between the root port and the device.
+    pub fn depth(&self) -> u8 {
+        self.hub_ports().count() as u8
+    }
+
+    /// Returns the path of the device on `port` (1-based) of the hub at this path.
+    ///
+    /// `None` if `port` is out of 1..=15 or the hub is already at the
+    /// fifth tier, the deepest a route string can address.
+    pub fn child(&self, port: u8) -> Option<Self> {
+        let depth = self.depth();
+        if !(1..=15).contains(&port) || depth >= 5 {
+            return None;
+        }
+        Some(Self {
+            route: self.route | ((port as u32) << (depth * 4)),
+            ..*self
+        })
+    }
+
+    /// Returns the path of the hub the device is attached to, with the hub port (1-based).
+    ///
+    /// `None` for a device on a root port.
+    pub fn parent(&self) -> Option<(Self, u8)> {
+        let tier = self.depth().checked_sub(1)?;
+        let shift = tier as u32 * 4;
+        let parent = Self {
+            route: self.route & !(0xf << shift),
+            ..*self
+        };
+        Some((parent, ((self.route >> shift) & 0xf) as u8))
+    }
+
+    /// Returns true if this path is `other` or lies below it.
+    pub fn is_within(&self, other: &Self) -> bool {
+        let mask = (1u32 << (other.depth() as u32 * 4)) - 1;
+        self.bus == other.bus
+            && self.root_port == other.root_port
+            && self.depth() >= other.depth()
+            && self.route & mask == other.route
+    }
+}
+
+impl fmt::Display for DevicePath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.bus, self.root_port + 1)?;
+        for port in self.hub_ports() {
+            write!(f, ".{}", port)?;
+        }
+        Ok(())
+    }
+}
+
+/// Identification of a device, for logging.
+///
+/// Built by `UsbDevice::summary`. String fields are empty when the device
+/// has no such string or refused to return it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DeviceSummary {
+    /// Vendor ID
+    pub vendor_id: u16,
+    /// Product ID
+    pub product_id: u16,
+    /// Device release number (BCD)
+    pub bcd_device: u16,
+    /// Class, subclass and protocol; of the first interface if the device
+    /// descriptor defers to the interfaces
+    pub class: (u8, u8, u8),
+    /// Device speed
+    pub speed: Speed,
+    /// Root hub port (0-based)
+    pub root_port: u8,
+    /// Hub route string, 0 for a device on a root port
+    pub route: u32,
+    /// Manufacturer string
+    pub manufacturer: String,
+    /// Product string
+    pub product: String,
+    /// Serial number string
+    pub serial: String,
+}
+
+impl DeviceSummary {
+    /// Returns the name of the device speed.
+    pub fn speed_name(&self) -> &'static str {
+        self.speed.name()
+    }
+
+    /// Hub port numbers below the root port, outermost first
+    fn hub_ports(&self) -> impl Iterator<Item = u8> + use<> {
+        let path = DevicePath {
+            route: self.route,
+            ..Default::default()
+        };
+        path.hub_ports()
+    }
+}
+
+impl fmt::Display for DeviceSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (self.manufacturer.is_empty(), self.product.is_empty()) {
+            (false, false) => write!(f, "{} {}, ", self.manufacturer, self.product)?,
+            (false, true) => write!(f, "{}, ", self.manufacturer)?,
+            (true, false) => write!(f, "{}, ", self.product)?,
+            (true, true) => {}
+        }
+        write!(
+            f,
+            "VID {:04x} PID {:04x}, rev {:x}.{:02x}, class {:02x}/{:02x}/{:02x}, {}, port {}",
+            self.vendor_id,
+            self.product_id,
+            self.bcd_device >> 8,
+            self.bcd_device & 0xff,
+            self.class.0,
+            self.class.1,
+            self.class.2,
+            self.speed_name(),
+            self.root_port + 1,
+        )?;
+        for port in self.hub_ports() {
+            write!(f, ".{}", port)?;
+        }
+        if !self.serial.is_empty() {
+            write!(f, ", serial {}", self.serial)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "defmt")]
+impl defmt::Format for DeviceSummary {
+    fn format(&self, f: defmt::Formatter) {
+        defmt::write!(
+            f,
+            "{=str} {=str}, VID {=u16:04x} PID {=u16:04x}, rev {=u16:04x}, class {=u8:02x}/{=u8:02x}/{=u8:02x}, {=str}, port {=u8} route {=u32:05x}, serial {=str}",
+            self.manufacturer.as_str(),
+            self.product.as_str(),
+            self.vendor_id,
+            self.product_id,
+            self.bcd_device,
+            self.class.0,
+            self.class.1,
+            self.class.2,
+            self.speed_name(),
+            self.root_port + 1,
+            self.route,
+            self.serial.as_str(),
+        );
+    }
+}
+
+/// USB Device abstraction.
+///
+/// Represents an addressed USB device connected to an xHCI controller.
+/// Provides methods for control transfers, device enumeration, and
+/// endpoint configuration.
+///
+/// # Lock ordering
+///
+/// Each endpoint ring has its own lock, so transfers on different
+/// endpoints never contend. Locks are only taken in this order:
+///
+/// 1. the control transfer lock (`control_transfer`)
+/// 2. the Input Context lock (endpoint configuration, re-addressing)
+/// 3. a single endpoint ring lock, or the EP0 ring lock
+/// 4. the controller's command ring and event ring locks
+///
+/// No two ring locks are held at once, and no ring lock is held while
+/// waiting for a command or transfer event. The control transfer lock is
+/// held until the transfer and its retries are done.
+pub struct UsbDevice<H: Dma> {
+    ctrl: Arc<XhciCtrl<H>>,
+    port: u8,
+    speed: Speed,
+    device_ctx: Arc<PhysMem<H>>,
+    input_ctx: PhysMem<H>,
+    input_lock: Lock<()>,
+    /// Serializes `deactivate` and `reactivate`
+    activation: Lock<()>,
+    ep0_ring: Lock<Ring<H>>,
+    /// Serializes `control_transfer` calls, including their retries
+    control: Lock<()>,
+    ep0_trbs: usize,
+    /// Set for a device on a downstream port of an external hub
+    below_hub: Option<HubAttach>,
+    ep_rings: Vec<EpRing<H>>,
+    /// Bitmap of DCIs that have a transfer ring
+    ep_mask: AtomicU32,
+    device_desc: Option<DeviceDesc>,
+    config: AtomicU8,
+    configs: Lock<Vec<(u8, Arc<ConfigTree>)>>,
+    restore_hooks: Lock<Vec<RestoreHook<H>>>,
+    config_hooks: Lock<Vec<ConfigHook<H>>>,
+    retry_policy: Lock<RetryPolicy>,
+    control_retries: AtomicU32,
+    /// EP0 max packet size set with `clamp_ep0_max_packet`, 0 if none
+    ep0_clamp: AtomicU16,
+    health: Arc<DeviceHealth>,
+    summary: Lock<Option<DeviceSummary>>,
+    /// Language IDs of string descriptor 0, empty if the device has none
+    languages: Lock<Option<Arc<[u16]>>>,
+    /// Last negotiated string language, as (requested, chosen)
+    string_lang: Lock<Option<(u16, u16)>>,
+}
+
+impl<H: Dma> UsbDevice<H> {
+    /// Create and address a new USB device
+    ///
+    /// The EP0 ring gets the controller's `ep0_ring_size` TRBs.
+    pub fn new(ctrl: Arc<XhciCtrl<H>>, port: u8) -> Result<Self> {
+        let ep0_trbs = ctrl.ep0_ring_size();
+        Self::with_ep0_ring_size(ctrl, port, ep0_trbs)
+    }
+
+    /// Create and address a new USB device with an EP0 ring of `ep0_trbs`
+    ///
+    /// One TRB of the ring is taken by the Link TRB and a control
+    /// transfer needs up to 3, so at least 4 TRBs are used.
+    pub fn with_ep0_ring_size(ctrl: Arc<XhciCtrl<H>>, port: u8, ep0_trbs: usize) -> Result<Self> {
+        // Reset port
+        ctrl.reset_port(port)?;
+        Self::enable_and_address(ctrl, port, ep0_trbs)
+    }
+
+    /// Enable a slot and address the device on an already reset port
+    pub(crate) fn enable_and_address(
+        ctrl: Arc<XhciCtrl<H>>,
+        port: u8,
+        ep0_trbs: usize,
+    ) -> Result<Self> {
+        Self::address_with(ctrl, port, None, ep0_trbs, |_| {})
+    }
+
+    /// Enable a slot and address a device on an already reset hub port
+    ///
+    /// `root_port` is the root port the hub tree hangs off.
+    pub(crate) fn address_below_hub(
+        ctrl: Arc<XhciCtrl<H>>,
+        root_port: u8,
+        attach: HubAttach,
+    ) -> Result<Self> {
+        let ep0_trbs = ctrl.ep0_ring_size();
+        Self::address_with(ctrl, root_port, Some(attach), ep0_trbs, |_| {})
+    }
+
+    /// Like `enable_and_address`, calling `slot_enabled` once the slot is enabled
+    fn address_with(
+        ctrl: Arc<XhciCtrl<H>>,
+        port: u8,
+        below_hub: Option<HubAttach>,
+        ep0_trbs: usize,
+        slot_enabled: impl FnOnce(u8),
+    ) -> Result<Self> {
+        let ep0_trbs = ep0_trbs.max(MIN_EP0_RING_SIZE);
+        let host = ctrl.host();
+
+        // Get speed and enable slot
+        let speed = match below_hub {
+            Some(attach) => attach.speed,
+            None => ctrl.port_speed(port)?,
+        };
+        let slot_id = ctrl.enable_slot()?;
+        slot_enabled(slot_id);
+
+        // Allocate contexts and the EP0 transfer ring
+        let allocated = (
+            ctrl.alloc_mem(
+                core::mem::size_of::<DeviceContext>(),
+                core::mem::align_of::<DeviceContext>(),
+            ),
+            ctrl.alloc_mem(
+                core::mem::size_of::<InputContext>(),
+                core::mem::align_of::<InputContext>(),
+            ),
+            Ring::new(host, ep0_trbs, ctrl.dma32()),
+        );
+        let (device_ctx, input_ctx, ep0_ring) = match allocated {
+            (Ok(device_ctx), Ok(input_ctx), Ok(ep0_ring)) => (device_ctx, input_ctx, ep0_ring),
+            (device_ctx, input_ctx, ep0_ring) => {
+                // Not handed to the controller yet
+                if let Ok(mem) = device_ctx {
+                    mem.free(host);
+                }
+                if let Ok(mem) = input_ctx {
+                    mem.free(host);
+                }
+                if let Ok(ring) = ep0_ring {
+                    ring.free(host);
+                }
+                let _ = ctrl.disable_slot(slot_id);
+                return Err(UsbError::OoRam);
+            }
+        };
+
+        // Setup Input Context
+        address_input(
+            &input_ctx,
+            speed,
+            port,
+            below_hub.as_ref(),
+            ep0_ring.phys(host),
+        );
+
+        // Set device context in DCBAA, then Address Device command
+        let trb = Trb {
+            param: input_ctx.phys(host),
+            status: 0,
+            control: (trb_type::ADDRESS_DEVICE << 10) | ((slot_id as u32) << 24),
+        };
+        let addressed = ctrl
+            .set_device_context(slot_id, device_ctx.phys(host))
+            .and_then(|()| ctrl.submit_command(trb));
+        if let Err(e) = addressed {
+            // The controller may still use the memory while the slot is enabled
+            if ctrl.disable_slot(slot_id).is_ok() {
+                device_ctx.free(host);
+                input_ctx.free(host);
+                ep0_ring.free(host);
+            }
+            return Err(e);
+        }
+
+        // Ring slots by context index; EP0's ring is kept separately
+        let mut ep_rings = Vec::with_capacity(31);
+        ep_rings.resize_with(31, EpRing::default);
+
+        let health = Arc::new(DeviceHealth {
+            slot_id: AtomicU8::new(slot_id),
+            summary: Lock::new(DeviceSummary {
+                speed,
+                root_port: port,
+                route: below_hub.map_or(0, |attach| attach.route),
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        ctrl.register_device(&health);
+
+        Ok(Self {
+            ctrl,
+            port,
+            speed,
+            device_ctx: Arc::new(device_ctx),
+            input_ctx,
+            input_lock: Lock::new(()),
+            activation: Lock::new(()),
+            ep0_ring: Lock::new(ep0_ring),
+            control: Lock::new(()),
+            ep0_trbs,
+            below_hub,
+            ep_rings,
+            ep_mask: AtomicU32::new(0),
+            device_desc: None,
+            config: AtomicU8::new(0),
+            configs: Lock::new(Vec::new()),
+            restore_hooks: Lock::new(Vec::new()),
+            config_hooks: Lock::new(Vec::new()),
+            retry_policy: Lock::new(RetryPolicy::default()),
+            summary: Lock::new(None),
+            languages: Lock::new(None),
+            string_lang: Lock::new(None),
+            control_retries: AtomicU32::new(0),
+            ep0_clamp: AtomicU16::new(0),
+            health,
+        })
+    }
+
+    /// Perform a control transfer
+    ///
+    /// Transient transaction errors are retried according to the
+    /// device's `RetryPolicy`. Fails with `Disconnected` once the device
+    /// was detached or released, and with `DeviceFailed` once the port
+    /// was disabled.
+    ///
+    /// Concurrent calls on one device run one after another: a transfer
+    /// holds the device's control lock from its Setup stage until its
+    /// last retry, so recovering EP0 never discards another thread's
+    /// transfer. Must not be called from interrupt context, since it
+    /// waits for the transfer and would deadlock against an interrupted
+    /// transfer on the same device.
+    pub fn control_transfer(
+        &self,
+        setup: &SetupPacket,
+        mut data: Option<&mut [u8]>,
+    ) -> Result<usize> {
+        self.health.check_failed()?;
+        let _control = self.control.lock();
+        let policy = self.retry_policy();
+        let mut attempt = 1;
+        loop {
+            match self.control_transfer_once(setup, data.as_deref_mut()) {
+                Err(e) if RetryPolicy::retryable(&e) && attempt < policy.attempts => {
+                    self.prepare_retry(&policy, attempt)?;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Set the retry policy used for control transfers
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        *self.retry_policy.lock() = policy;
+    }
+
+    /// Returns the retry policy used for control transfers.
+    pub fn retry_policy(&self) -> RetryPolicy {
+        *self.retry_policy.lock()
+    }
+
+    /// Returns the number of control transfer retries so far.
+    pub fn control_retries(&self) -> u32 {
+        self.control_retries.load(Ordering::Relaxed)
+    }
+
+    /// Set the policy escalating babble and transaction errors
+    pub fn set_error_policy(&self, policy: ErrorPolicy) {
+        *self.health.policy.lock() = policy;
+    }
+
+    /// Returns the policy escalating babble and transaction errors.
+    pub fn error_policy(&self) -> ErrorPolicy {
+        *self.health.policy.lock()
+    }
+
+    /// Returns true if an endpoint asked for a device reset.
+    pub fn reset_pending(&self) -> bool {
+        self.health.reset_pending.load(Ordering::Acquire)
+    }
+
+    /// Returns true if the port was disabled after a failed reset.
+    pub fn is_failed(&self) -> bool {
+        self.health.failed.load(Ordering::Acquire)
+    }
+
+    /// Returns the number of device resets triggered by the `ErrorPolicy`.
+    pub fn error_resets(&self) -> u32 {
+        self.health.device_resets.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of times the port was disabled after a failed reset.
+    pub fn port_disables(&self) -> u32 {
+        self.health.port_disables.load(Ordering::Relaxed)
+    }
+
+    /// Mark the device as gone after it was unplugged
+    ///
+    /// Every later transfer fails with `Disconnected`, so drivers still
+    /// holding the device notice without waiting for timeouts.
+    pub(crate) fn mark_detached(&self) {
+        self.health.disconnected.store(true, Ordering::Release);
+    }
+
+    /// Returns the hub slot and port of the TT serving the device, and
+    /// whether that hub has one TT per port
+    pub(crate) fn transaction_translator(&self) -> Option<((u8, u8), bool)> {
+        let attach = self.below_hub?;
+        Some((attach.tt?, attach.multi_tt))
+    }
+
+    /// Describe the device as a hub in its slot context
+    ///
+    /// The fields are sent with the next Configure Endpoint command.
+    /// `think_time` is the TT think time field of the hub descriptor.
+    pub(crate) fn set_hub_fields(&self, num_ports: u8, multi_tt: bool, think_time: u8) {
+        let _input_lock = self.input_lock.lock();
+        let input = self.input_ctx.as_ptr::<InputContext>();
+        unsafe {
+            let slot = &mut (*input).slot;
+            slot.dw0 |= (1 << 26) | ((multi_tt as u32) << 25);
+            slot.dw1 = (slot.dw1 & 0x00ff_ffff) | ((num_ports as u32) << 24);
+            slot.dw2 = (slot.dw2 & !(0x3 << 16)) | (((think_time & 0x3) as u32) << 16);
+        }
+    }
+
+    /// Escalate the errors reported by the endpoints
+    ///
+    /// Does nothing unless an endpoint hit its `ErrorPolicy` limit.
+    /// Otherwise the device is reset with `reset_and_restore`, returning
+    /// `Ok(true)`; class drivers must then call their `reinit`. If the
+    /// reset fails, the port is disabled, the device is marked failed and
+    /// `DeviceFailed` is returned, as it is for every later transfer.
+    pub fn recover_from_errors(&self) -> Result<bool> {
+        self.health.check_failed()?;
+        if !self.health.reset_pending.swap(false, Ordering::AcqRel) {
+            return Ok(false);
+        }
+
+        self.health.device_resets.fetch_add(1, Ordering::Relaxed);
+        let result = self.reset_and_restore();
+        // Errors seen while restoring belong to the old endpoints
+        self.health.reset_pending.store(false, Ordering::Release);
+        if result.is_ok() {
+            return Ok(true);
+        }
+
+        if self.below_hub.is_none() {
+            let _ = self.ctrl.disable_port(self.port);
+        }
+        self.health.port_disables.fetch_add(1, Ordering::Relaxed);
+        self.health.failed.store(true, Ordering::Release);
+        Err(UsbError::DeviceFailed)
+    }
+
+    /// Recover EP0 and back off before retry number `attempt`
+    fn prepare_retry(&self, policy: &RetryPolicy, attempt: u8) -> Result<()> {
+        self.recover_for_retry()?;
+        let backoff = policy.backoff_before(attempt);
+        if backoff != 0 {
+            self.ctrl.host().delay_us(backoff);
+        }
+        Ok(())
+    }
+
+    /// Count a control retry and recover EP0 for it
+    fn recover_for_retry(&self) -> Result<()> {
+        self.control_retries.fetch_add(1, Ordering::Relaxed);
+        self.recover_ep0()
+    }
+
+    /// Discard every pending control transfer and restart EP0
+    ///
+    /// Stops EP0 (or resets it if halted) and moves its dequeue pointer to
+    /// the enqueue position, releasing the whole ring. Used after failed or
+    /// timed out control transfers; also recovers from `RingFull`.
+    /// Control transfers still in flight on other threads never complete.
+    pub fn recover_ep0(&self) -> Result<()> {
+        let command = |trb_type: u32, param: u64| {
+            self.ctrl.submit_command(Trb {
+                param,
+                status: 0,
+                control: (trb_type << 10)
+                    | ((Dci::EP0.raw() as u32) << 16)
+                    | ((self.slot_id() as u32) << 24),
+            })
+        };
+
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        let dw0 = unsafe { core::ptr::addr_of!((*ctx).endpoints[0].dw0).read_volatile() };
+        let trb_type = match EndpointState::from_raw(dw0 as u8) {
+            EndpointState::Halted => trb_type::RESET_ENDPOINT,
+            _ => trb_type::STOP_ENDPOINT,
+        };
+        match command(trb_type, 0) {
+            // Already stopped
+            Ok(_) | Err(UsbError::CmdFail(completion::CONTEXT_STATE_ERROR)) => {}
+            Err(e) => return Err(e),
+        }
+
+        let mut ep0_ring = self.ep0_ring.lock();
+        let dequeue = ep0_ring.enqueue_ptr(self.ctrl.host());
+        command(trb_type::SET_TR_DEQUEUE, dequeue)?;
+        ep0_ring.release_all();
+        Ok(())
+    }
+
+    /// Perform a single control transfer attempt
+    fn control_transfer_once(
+        &self,
+        setup: &SetupPacket,
+        mut data: Option<&mut [u8]>,
+    ) -> Result<usize> {
+        let mut xfer = self.start_control(setup, data.as_deref())?;
+
+        // Wait for completion
+        loop {
+            let evt = match self
+                .ctrl
+                .wait_event_where(|e| xfer.matches(self.slot_id(), e), CONTROL_TIMEOUT_US)
+            {
+                Ok(evt) => evt,
+                Err(e) => {
+                    let _ = self.abandon_control(xfer);
+                    return Err(e);
+                }
+            };
+
+            if let Some(result) = self.handle_control_event(&mut xfer, &evt, data.as_deref_mut()) {
+                return result;
+            }
+        }
+    }
+
+    /// Queue the stages of a control transfer and ring the EP0 doorbell
+    ///
+    /// For OUT transfers `data` is copied into the DMA buffer; for IN
+    /// transfers only its length is used.
+    pub(crate) fn start_control(
+        &self,
+        setup: &SetupPacket,
+        data: Option<&[u8]>,
+    ) -> Result<ControlTransfer<H>> {
+        let host = self.ctrl.host();
+
+        let data_dir = (setup.request_type & 0x80) != 0; // true = IN
+        let data_len = data
+            .map(|d| d.len())
+            .unwrap_or(0)
+            .min(setup.length as usize);
+
+        // Allocate data buffer if needed
+        // Use 64-byte alignment for DMA efficiency (cache line size)
+        let data_buf = if data_len > 0 {
+            let buf = self.ctrl.alloc_mem(data_len, 64)?;
+            if !data_dir {
+                // OUT: copy data to buffer
+                if let Some(d) = data {
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(d.as_ptr(), buf.as_ptr(), data_len);
+                    }
+                }
+            } else {
+                buf.poison(data_len);
+            }
+            Some(buf)
+        } else {
+            None
+        };
+
+        let mut ep0_ring = self.ep0_ring.lock();
+        let count = if data_buf.is_some() { 3 } else { 2 };
+        let trbs = match ep0_ring.reserve(count) {
+            Ok(trbs) => trbs,
+            Err(e) => {
+                drop(ep0_ring);
+                if let Some(buf) = data_buf {
+                    buf.free(host);
+                }
+                return Err(e);
+            }
+        };
+
+        // Setup Stage TRB
+        let setup_trb = Trb {
+            param: u64::from_le_bytes(setup.to_bytes()),
+            status: 8, // Transfer length = 8
+            control: (trb_type::SETUP << 10)
+                | (1 << 6) // IDT (Immediate Data)
+                | if data_len > 0 {
+                    if data_dir { 3 << 16 } else { 2 << 16 } // TRT: IN or OUT
+                } else {
+                    0 // No data stage
+                },
+        };
+        ep0_ring.enqueue(host, setup_trb);
+        self.ctrl
+            .trace_transfer(self.slot_id(), Dci::EP0.raw(), &setup_trb);
+
+        // Data Stage TRB (if needed)
+        let data_trb = data_buf.as_ref().map(|buf| {
+            let data_trb = Trb {
+                param: buf.phys(host),
+                status: data_len as u32,
+                control: (trb_type::DATA << 10)
+                    | if data_dir { 1 << 16 } else { 0 } // DIR
+                    | (1 << 5), // IOC to report the residual length
+            };
+            self.ctrl
+                .trace_transfer(self.slot_id(), Dci::EP0.raw(), &data_trb);
+            ep0_ring.enqueue(host, data_trb)
+        });
+
+        // Status Stage TRB
+        let status_trb = Trb {
+            param: 0,
+            status: 0,
+            control: (trb_type::STATUS << 10)
+                | if data_len > 0 && data_dir { 0 } else { 1 << 16 } // DIR
+                | (1 << 5), // IOC
+        };
+        self.ctrl
+            .trace_transfer(self.slot_id(), Dci::EP0.raw(), &status_trb);
+        let status_trb = ep0_ring.enqueue(host, status_trb);
+
+        drop(ep0_ring);
+
+        let xfer = ControlTransfer {
+            data_in: data_dir,
+            data_len,
+            data_buf,
+            data_trb,
+            status_trb,
+            transferred: 0,
+            trbs,
+        };
+
+        if let Err(e) = self.ctrl.ring_doorbell(self.slot_id(), Dci::EP0.raw()) {
+            self.finish_control(xfer, None);
+            return Err(e);
+        }
+
+        Ok(xfer)
+    }
+
+    /// Poll an in-flight control transfer (non-blocking)
+    ///
+    /// Returns `None` while the transfer is still running. On completion
+    /// IN data is copied into `data` and the transfer's buffers are freed.
+    pub(crate) fn poll_control(
+        &self,
+        xfer: &mut ControlTransfer<H>,
+        mut data: Option<&mut [u8]>,
+    ) -> Option<Result<usize>> {
+        loop {
+            let evt = self.ctrl.poll_event_where(|e| xfer.matches(self.slot_id(), e))?;
+            if let Some(result) = self.handle_control_event(xfer, &evt, data.as_deref_mut()) {
+                return Some(result);
+            }
+        }
+    }
+
+    /// Process a transfer event of an in-flight control transfer
+    fn handle_control_event(
+        &self,
+        xfer: &mut ControlTransfer<H>,
+        evt: &Trb,
+        data: Option<&mut [u8]>,
+    ) -> Option<Result<usize>> {
+        let code = evt.completion_code();
+        let result = match code {
+            completion::SUCCESS | completion::SHORT_PACKET => {
+                if Some(evt.param) == xfer.data_trb {
+                    // Data stage done, the status stage follows
+                    xfer.transferred = evt.transferred(xfer.data_len);
+                    xfer.data_trb = None;
+                    return None;
+                }
+                Ok(xfer.transferred)
+            }
+            completion::STALL_ERROR => Err(UsbError::Stall),
+            _ => Err(UsbError::XferFail(code)),
+        };
+
+        let xfer = core::mem::replace(xfer, ControlTransfer::done());
+        if completion::is_endpoint_halting(code) {
+            // The rest of the TD stays queued behind the halted EP0
+            let _ = self.abandon_control(xfer);
+            return Some(result);
+        }
+        let copy_back = if result.is_ok() { data } else { None };
+        self.finish_control(xfer, copy_back);
+        Some(result)
+    }
+
+    /// Take back the TRBs of a control transfer that will not complete
+    ///
+    /// EP0 is recovered first, so the controller is done with the TRBs
+    /// and the data buffer before the buffer is freed. If recovery fails
+    /// the buffer is leaked instead, as the controller may still use it.
+    pub(crate) fn abandon_control(&self, xfer: ControlTransfer<H>) -> Result<()> {
+        // Returning without finish_control leaks the buffer: PhysMem
+        // does not free itself
+        self.recover_ep0()?;
+        self.finish_control(xfer, None);
+        Ok(())
+    }
+
+    /// Copy back IN data and free the buffers of a finished control transfer
+    fn finish_control(&self, xfer: ControlTransfer<H>, data: Option<&mut [u8]>) {
+        let host = self.ctrl.host();
+        self.ep0_ring.lock().release(xfer.trbs);
+
+        if let Some(buf) = xfer.data_buf {
+            match data {
+                // Copy data back for IN transfers
+                Some(d) if xfer.data_in => {
+                    buf.check_in(xfer.transferred, xfer.data_len);
+                    unsafe {
+                        core::ptr::copy_nonoverlapping(
+                            buf.as_ptr::<u8>(),
+                            d.as_mut_ptr(),
+                            xfer.transferred.min(d.len()),
+                        );
+                    }
+                }
+                Some(d) => buf.check_out(&d[..xfer.data_len.min(d.len())]),
+                None => {}
+            }
+            buf.free(host);
+        }
+    }
+
+    /// Get device descriptor
+    ///
+    /// A short first read, as sent by devices whose EP0 packet size
+    /// differs from the guess made at addressing, corrects the EP0 max
+    /// packet size and is repeated once. An inconsistent header or a
+    /// second short read fails with `InvalidDescriptor`.
+    pub fn get_device_descriptor(&mut self) -> Result<DeviceDesc> {
+        let desc = self.read_device_descriptor()?;
+        self.device_desc = Some(desc);
+        self.health.record_descriptor(&desc);
+        self.apply_quirks(&desc)?;
+        Ok(desc)
+    }
+
+    /// Apply the controller's device quirks matching `desc`
+    fn apply_quirks(&self, desc: &DeviceDesc) -> Result<()> {
+        let Some(quirk) = self.ctrl.device_quirk(desc) else {
+            return Ok(());
+        };
+        if let Some(max_packet) = quirk.ep0_max_packet {
+            self.clamp_ep0_max_packet(max_packet)?;
+        }
+        Ok(())
+    }
+
+    /// Read the device descriptor without caching it
+    fn read_device_descriptor(&self) -> Result<DeviceDesc> {
+        let setup = SetupPacket::get_descriptor(desc_type::DEVICE, 0, DeviceDesc::SIZE as u16);
+        for _ in 0..2 {
+            let mut buf = [0u8; DeviceDesc::SIZE];
+            let len = self.control_transfer(&setup, Some(&mut buf))?;
+            if !DeviceDesc::header_valid(&buf[..len]) {
+                return Err(UsbError::InvalidDescriptor);
+            }
+            if len == DeviceDesc::SIZE {
+                return DeviceDesc::from_bytes(&buf).ok_or(UsbError::InvalidDescriptor);
+            }
+            if len >= 8 {
+                self.update_ep0_max_packet(buf[7])?;
+            }
+        }
+        Err(UsbError::InvalidDescriptor)
+    }
+
+    /// Correct the EP0 max packet size from bMaxPacketSize0
+    ///
+    /// Issues Evaluate Context if it differs from the size guessed from
+    /// the port speed. SuperSpeed devices always use 512.
+    fn update_ep0_max_packet(&self, max_packet_size0: u8) -> Result<()> {
+        if self.speed.is_super_or_faster() || !matches!(max_packet_size0, 8 | 16 | 32 | 64) {
+            return Ok(());
+        }
+        let clamp = self.ep0_clamp.load(Ordering::Acquire);
+        let max_packet = match clamp {
+            0 => max_packet_size0 as u16,
+            _ => clamp.min(max_packet_size0 as u16),
+        };
+        self.evaluate_ep0_max_packet(max_packet)
+    }
+
+    /// Clamp the EP0 max packet size below what the device claims
+    ///
+    /// For devices whose bMaxPacketSize0 is larger than the packets they
+    /// actually handle. Issues Evaluate Context; the controller splits
+    /// later control data stages into packets of `size` bytes. The clamp
+    /// survives resets and reactivation. Fails with `InvalidArgument`
+    /// unless `size` is 8, 16, 32 or 64, or 512 for SuperSpeed devices.
+    pub fn clamp_ep0_max_packet(&self, size: u16) -> Result<()> {
+        let valid = if self.speed.is_super_or_faster() {
+            size == 512
+        } else {
+            matches!(size, 8 | 16 | 32 | 64)
+        };
+        if !valid {
+            return Err(UsbError::InvalidArgument);
+        }
+        self.ep0_clamp.store(size, Ordering::Release);
+        if self.speed.is_super_or_faster() {
+            return Ok(());
+        }
+        self.evaluate_ep0_max_packet(size)
+    }
+
+    /// Set the EP0 max packet size with Evaluate Context, unless unchanged
+    fn evaluate_ep0_max_packet(&self, max_packet: u16) -> Result<()> {
+        let _input_lock = self.input_lock.lock();
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        let mut ep0 = unsafe { core::ptr::addr_of!((*ctx).endpoints[0]).read_volatile() };
+        if (ep0.dw1 >> 16) == max_packet as u32 {
+            return Ok(());
+        }
+        ep0.dw1 = (ep0.dw1 & 0xffff) | ((max_packet as u32) << 16);
+
+        let input = self.input_ctx.as_ptr::<InputContext>();
+        unsafe {
+            (*input).input_control[0] = 0;
+            (*input).input_control[1] = 1 << Dci::EP0.raw();
+            (*input).endpoints[0] = ep0;
+        }
+
+        let trb = Trb {
+            param: self.input_ctx.phys(self.ctrl.host()),
+            status: 0,
+            control: (trb_type::EVALUATE_CONTEXT << 10) | ((self.slot_id() as u32) << 24),
+        };
+        self.ctrl.submit_command(trb)?;
+        Ok(())
+    }
+
+    /// Get a string descriptor, decoded from UTF-16
+    ///
+    /// Unpaired surrogates are replaced with U+FFFD.
+    pub fn get_string(&self, index: u8, lang_id: u16) -> Result<String> {
+        let mut buf = [0u8; 255];
+        let setup = SetupPacket::get_string_descriptor(index, lang_id, buf.len() as u16);
+        let len = self.control_transfer(&setup, Some(&mut buf))?;
+        if len < 2 || buf[1] != desc_type::STRING {
+            return Err(UsbError::InvalidDescriptor);
+        }
+
+        let len = (buf[0] as usize).min(len) & !1;
+        let units = buf[2..len.max(2)]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]));
+        Ok(char::decode_utf16(units)
+            .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+            .collect())
+    }
+
+    /// Get the first language ID the device lists in string descriptor 0
+    ///
+    /// Fails with `InvalidDescriptor` if the device lists none.
+    pub fn get_language(&self) -> Result<u16> {
+        self.languages()?
+            .first()
+            .copied()
+            .ok_or(UsbError::InvalidDescriptor)
+    }
+
+    /// Language IDs listed in string descriptor 0, fetched once and cached
+    ///
+    /// Devices that stall the request or return a malformed descriptor
+    /// are taken to list no languages.
+    pub fn languages(&self) -> Result<Arc<[u16]>> {
+        if let Some(languages) = self.languages.lock().as_ref() {
+            return Ok(languages.clone());
+        }
+
+        let mut buf = [0u8; 255];
+        let setup = SetupPacket::get_string_descriptor(0, 0, buf.len() as u16);
+        let languages: Arc<[u16]> = match self.control_transfer(&setup, Some(&mut buf)) {
+            Ok(len) if len >= 2 && buf[1] == desc_type::STRING => {
+                let len = (buf[0] as usize).min(len) & !1;
+                buf[2..len.max(2)]
+                    .chunks_exact(2)
+                    .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                    .collect()
+            }
+            Ok(_) | Err(UsbError::Stall) => Arc::from([]),
+            Err(e) => return Err(e),
+        };
+        *self.languages.lock() = Some(languages.clone());
+        Ok(languages)
+    }
+
+    /// Pick the language to read strings in, preferring `preferred`
+    ///
+    /// Takes `preferred` if the device lists it, else the first language
+    /// listed, else language ID 0 for devices without a language list.
+    /// The choice is cached.
+    pub fn string_language(&self, preferred: u16) -> Result<u16> {
+        if let Some((requested, chosen)) = *self.string_lang.lock()
+            && requested == preferred
+        {
+            return Ok(chosen);
+        }
+
+        let languages = self.languages()?;
+        let chosen = if languages.contains(&preferred) {
+            preferred
+        } else {
+            languages.first().copied().unwrap_or(0)
+        };
+        *self.string_lang.lock() = Some((preferred, chosen));
+        Ok(chosen)
+    }
+
+    /// Read string descriptor `index` in the negotiated language
+    ///
+    /// See `string_language`. If the device stalls the request in a
+    /// listed language, it is retried with language ID 0, which becomes
+    /// the cached choice if the device answers it.
+    pub fn read_string(&self, index: u8, preferred: u16) -> Result<String> {
+        let lang = self.string_language(preferred)?;
+        match self.get_string(index, lang) {
+            Err(UsbError::Stall) if lang != 0 => {
+                let string = self.get_string(index, 0)?;
+                *self.string_lang.lock() = Some((preferred, 0));
+                Ok(string)
+            }
+            result => result,
+        }
+    }
+
+    /// Identification of the device for logging, built once and cached
+    ///
+    /// Missing strings, and string requests the device stalls, leave the
+    /// string fields empty. Nothing is cached if the device descriptor
+    /// cannot be read.
+    pub fn summary(&self) -> DeviceSummary {
+        if let Some(summary) = self.summary.lock().as_ref() {
+            return summary.clone();
+        }
+
+        let desc = match self.device_desc {
+            Some(desc) => Some(desc),
+            None => self.read_device_descriptor().ok(),
+        };
+        let mut summary = DeviceSummary {
+            speed: self.speed,
+            root_port: self.port,
+            route: self.path().route,
+            ..Default::default()
+        };
+        let Some(desc) = desc else {
+            return summary;
+        };
+
+        summary.vendor_id = desc.vendor_id;
+        summary.product_id = desc.product_id;
+        summary.bcd_device = desc.bcd_device;
+        summary.class = (desc.device_class, desc.device_subclass, desc.device_protocol);
+        if desc.device_class == 0 {
+            let first = self.config_tree().ok().and_then(|tree| {
+                let iface = *tree.interfaces.first()?.iface();
+                Some((
+                    iface.interface_class,
+                    iface.interface_subclass,
+                    iface.interface_protocol,
+                ))
+            });
+            if let Some(class) = first {
+                summary.class = class;
+            }
+        }
+
+        let indices = [desc.manufacturer, desc.product, desc.serial_number];
+        if indices.iter().any(|&i| i != 0) {
+            let string = |index: u8| match index {
+                0 => String::new(),
+                _ => self.read_string(index, lang_id::EN_US).unwrap_or_default(),
+            };
+            summary.manufacturer = string(desc.manufacturer);
+            summary.product = string(desc.product);
+            summary.serial = string(desc.serial_number);
+        }
+
+        *self.summary.lock() = Some(summary.clone());
+        *self.health.summary.lock() = summary.clone();
+        summary
+    }
+
+    /// Get configuration descriptor (full, with interfaces and endpoints)
+    ///
+    /// The descriptor is fetched from the device once and cached.
+    pub fn get_config_descriptor(&self, index: u8) -> Result<Vec<u8>> {
+        Ok(self.config_tree_at(index)?.raw().to_vec())
+    }
+
+    /// Parsed configuration descriptor at `index`, fetched once and cached
+    pub fn config_tree_at(&self, index: u8) -> Result<Arc<ConfigTree>> {
+        if let Some((_, tree)) = self.configs.lock().iter().find(|(i, _)| *i == index) {
+            return Ok(tree.clone());
+        }
+
+        let tree = ConfigTree::parse(self.fetch_config_descriptor(index)?)
+            .ok_or(UsbError::InvalidDescriptor)?;
+        Ok(self.cache_config(index, tree))
+    }
+
+    /// Parsed descriptor of the active configuration
+    ///
+    /// Before the device is configured this is the first configuration.
+    pub fn config_tree(&self) -> Result<Arc<ConfigTree>> {
+        let Some(active) = self.active_configuration() else {
+            return self.config_tree_at(0);
+        };
+
+        self.config_tree_for(active)
+    }
+
+    /// Parsed descriptor of the configuration with bConfigurationValue `value`
+    ///
+    /// Reads the device descriptor first if `get_device_descriptor` has
+    /// not been called, to learn how many configurations there are.
+    pub fn config_tree_for(&self, value: u8) -> Result<Arc<ConfigTree>> {
+        let num_configs = self.device_descriptor()?.num_configurations;
+        for index in 0..num_configs {
+            let tree = self.config_tree_at(index)?;
+            if tree.config.config_value == value {
+                return Ok(tree);
+            }
+        }
+        Err(UsbError::InvalidDescriptor)
+    }
+
+    /// Device descriptor from `get_device_descriptor`, or read afresh
+    fn device_descriptor(&self) -> Result<DeviceDesc> {
+        match self.device_desc {
+            Some(desc) => Ok(desc),
+            None => self.read_device_descriptor(),
+        }
+    }
+
+    /// Store a parsed configuration in the cache
+    fn cache_config(&self, index: u8, tree: ConfigTree) -> Arc<ConfigTree> {
+        let tree = Arc::new(tree);
+        let mut configs = self.configs.lock();
+        configs.retain(|(i, _)| *i != index);
+        configs.push((index, tree.clone()));
+        tree
+    }
+
+    /// Read a configuration descriptor from the device, bypassing the cache
+    ///
+    /// A payload shorter than wTotalLength is trimmed to the descriptors
+    /// that arrived whole. If the full descriptor's wTotalLength differs
+    /// from the header read first, it is fetched once more with the new
+    /// length. A wTotalLength above the controller's `max_descriptor_len`
+    /// fails with `InvalidDescriptor`, as do more interfaces or endpoints
+    /// than a configuration can hold.
+    fn fetch_config_descriptor(&self, index: u8) -> Result<Vec<u8>> {
+        // First, get just the config descriptor to find total length
+        let mut buf = [0u8; 9];
+        let setup = SetupPacket::get_descriptor(desc_type::CONFIGURATION, index, 9);
+        let len = self.control_transfer(&setup, Some(&mut buf))?;
+        if len < ConfigDesc::SIZE || !ConfigDesc::header_valid(&buf) {
+            return Err(UsbError::InvalidDescriptor);
+        }
+
+        let config = ConfigDesc::from_bytes(&buf).ok_or(UsbError::InvalidDescriptor)?;
+        let total_len = config.total_length as usize;
+        if total_len > self.ctrl.max_descriptor_len() {
+            return Err(UsbError::InvalidDescriptor);
+        }
+
+        // Now get the full descriptor
+        let mut requested = total_len;
+        let mut retried = false;
+        loop {
+            let mut full_buf = alloc::vec![0u8; requested];
+            let setup =
+                SetupPacket::get_descriptor(desc_type::CONFIGURATION, index, requested as u16);
+            let len = self.control_transfer(&setup, Some(&mut full_buf))?;
+            let answer = reconcile_config(
+                self.ctrl.host(),
+                index,
+                requested,
+                full_buf,
+                len,
+                retried,
+                self.ctrl.max_descriptor_len(),
+            );
+            match answer {
+                ConfigAnswer::Data(data) => {
+                    return trim_config_data(data).ok_or(UsbError::InvalidDescriptor);
+                }
+                ConfigAnswer::Refetch(len) => {
+                    requested = len as usize;
+                    retried = true;
+                }
+            }
+        }
+    }
+
+    /// Set configuration
+    ///
+    /// Clears the configuration descriptor cache; the next `config_tree`
+    /// reads the descriptors from the device again.
+    pub fn set_configuration(&self, config: u8) -> Result<()> {
+        let setup = SetupPacket::set_configuration(config);
+        self.control_transfer(&setup, None)?;
+        self.config.store(config, Ordering::Release);
+        self.configs.lock().clear();
+        Ok(())
+    }
+
+    /// Read the active configuration value from the device
+    ///
+    /// Also refreshes the value returned by `active_configuration`.
+    pub fn get_configuration(&self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        let setup = SetupPacket::get_configuration();
+        self.control_transfer(&setup, Some(&mut buf))?;
+        self.config.store(buf[0], Ordering::Release);
+        Ok(buf[0])
+    }
+
+    /// Returns the configuration selected by the last SET_CONFIGURATION.
+    pub fn active_configuration(&self) -> Option<u8> {
+        match self.config.load(Ordering::Acquire) {
+            0 => None,
+            config => Some(config),
+        }
+    }
+
+    /// Fetch every configuration and select the one `policy` picks
+    ///
+    /// `policy` gets the device descriptor and the parsed configurations
+    /// in descriptor index order, and returns an index into them; see
+    /// `default_config_policy`. Fails with `InvalidArgument` if the index
+    /// is out of range. Returns the selected configuration.
+    pub fn choose_configuration(
+        &self,
+        policy: impl Fn(&DeviceDesc, &[Arc<ConfigTree>]) -> usize,
+    ) -> Result<Arc<ConfigTree>> {
+        let desc = self.device_descriptor()?;
+        let configs = (0..desc.num_configurations.max(1))
+            .map(|index| self.config_tree_at(index))
+            .collect::<Result<Vec<_>>>()?;
+
+        let tree = configs
+            .get(policy(&desc, &configs))
+            .ok_or(UsbError::InvalidArgument)?
+            .clone();
+        self.set_configuration(tree.config.config_value)?;
+        Ok(tree)
+    }
+
+    /// Switch to the configuration with bConfigurationValue `value`
+    ///
+    /// Every endpoint but EP0 is dropped first, so handles held by class
+    /// drivers of the old configuration stop working. After
+    /// SET_CONFIGURATION the registered configuration hooks are run in
+    /// order to set up the drivers of the new one. Returns the new
+    /// configuration.
+    pub fn switch_configuration(&self, value: u8) -> Result<Arc<ConfigTree>> {
+        let tree = self.config_tree_for(value)?;
+
+        self.deconfigure_endpoints()?;
+        self.set_configuration(value)?;
+
+        let mut hooks = self.config_hooks.lock();
+        for hook in hooks.iter_mut() {
+            hook(self, &tree)?;
+        }
+        Ok(tree)
+    }
+
+    /// Register a callback to run at the end of `switch_configuration`
+    ///
+    /// Hooks must not register further hooks.
+    pub fn add_config_hook(&self, hook: ConfigHook<H>) {
+        self.config_hooks.lock().push(hook);
+    }
+
+    /// Drop every endpoint but EP0 with a deconfiguring Configure Endpoint
+    fn deconfigure_endpoints(&self) -> Result<()> {
+        let _input_lock = self.input_lock.lock();
+        let trb = Trb {
+            param: 0,
+            status: 0,
+            control: (trb_type::CONFIGURE_ENDPOINT << 10)
+                | trb_flags::DECONFIGURE
+                | ((self.slot_id() as u32) << 24),
+        };
+        self.ctrl.submit_command(trb)?;
+        self.free_ep_rings();
+        Ok(())
+    }
+
+    /// Select an alternate setting of an interface
+    ///
+    /// Transfers pending on the interface's endpoints are cancelled first.
+    pub fn set_interface(&self, interface: u8, alt_setting: u8) -> Result<()> {
+        if let Ok(tree) = self.config_tree() {
+            let found = tree.interfaces.iter().find(|i| i.number() == interface);
+            let eps = found
+                .into_iter()
+                .flat_map(|i| &i.settings)
+                .flat_map(|s| &s.endpoints);
+            for ep in eps.filter_map(|ep| self.endpoint(Dci::from_desc(ep)).ok()) {
+                ep.cancel_all()?;
+            }
+        }
+        let setup = SetupPacket::set_interface(interface, alt_setting);
+        self.control_transfer(&setup, None)?;
+        Ok(())
+    }
+
+    /// Read the synchronization frame of an isochronous endpoint
+    ///
+    /// Returns the frame number (11 bits) in which the endpoint's
+    /// repeating packet size pattern starts, for aligning the start frame
+    /// of isochronous transfers with `XhciCtrl::next_start_frame`. Returns
+    /// `None` if the endpoint stalls SYNCH_FRAME, as endpoints without
+    /// such a pattern do.
+    pub fn synch_frame(&self, ep_addr: u8) -> Result<Option<u16>> {
+        let mut buf = [0u8; 2];
+        let setup = SetupPacket::synch_frame(ep_addr);
+        match self.control_transfer(&setup, Some(&mut buf)) {
+            Ok(2) => Ok(Some(u16::from_le_bytes(buf) & 0x7ff)),
+            Ok(_) => Err(UsbError::InvalidDescriptor),
+            Err(UsbError::Stall) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reset the device and bring it back to its configured state
+    ///
+    /// Resets the port, returns the slot to the Default state with a Reset
+    /// Device command and re-addresses it under the same slot ID. Endpoint
+    /// rings other than EP0 are released; the last configuration selected
+    /// is restored and the registered restore hooks are run in order.
+    /// Class drivers on top must then call their `reinit`. Devices below
+    /// an external hub fail with `NotSupported`, as resetting the root
+    /// port would take down the whole hub tree.
+    pub fn reset_and_restore(&self) -> Result<()> {
+        if self.below_hub.is_some() {
+            return Err(UsbError::NotSupported);
+        }
+        let host = self.ctrl.host();
+
+        self.ctrl.reset_port(self.port)?;
+        match self.ctrl.reset_device(self.slot_id()) {
+            // Slot never left the Default state
+            Ok(()) | Err(UsbError::CmdFail(completion::CONTEXT_STATE_ERROR)) => {}
+            Err(e) => return Err(e),
+        }
+
+        // The Reset Device command disabled every endpoint but EP0
+        self.free_ep_rings();
+
+        // Re-address with a fresh EP0 ring
+        let input_lock = self.input_lock.lock();
+        let ep0_ring = Ring::new(host, self.ep0_trbs, self.ctrl.dma32())?;
+        address_input(
+            &self.input_ctx,
+            self.speed,
+            self.port,
+            self.below_hub.as_ref(),
+            ep0_ring.phys(host),
+        );
+        let old = core::mem::replace(&mut *self.ep0_ring.lock(), ep0_ring);
+        old.free(host);
+
+        let trb = Trb {
+            param: self.input_ctx.phys(host),
+            status: 0,
+            control: (trb_type::ADDRESS_DEVICE << 10) | ((self.slot_id() as u32) << 24),
+        };
+        self.ctrl.submit_command(trb)?;
+        drop(input_lock);
+
+        // Addressing went back to the packet size guessed from the speed
+        if let Some(desc) = self.device_desc {
+            self.update_ep0_max_packet(desc.max_packet_size0)?;
+        }
+
+        let config = self.config.load(Ordering::Acquire);
+        if config != 0 {
+            self.set_configuration(config)?;
+        }
+
+        let mut hooks = self.restore_hooks.lock();
+        for hook in hooks.iter_mut() {
+            hook(self)?;
+        }
+        Ok(())
+    }
+
+    /// Register a callback to run at the end of `reset_and_restore`
+    ///
+    /// Hooks also run at the end of `reactivate`. They must not register
+    /// further hooks.
+    pub fn add_restore_hook(&self, hook: RestoreHook<H>) {
+        self.restore_hooks.lock().push(hook);
+    }
+
+    /// Take the device out of service, keeping this object and its
+    /// cached descriptors
+    ///
+    /// Stops every endpoint, so a transfer in progress ends with a
+    /// Stopped completion, then disables the slot, which clears its DCBAA
+    /// entry, and releases the endpoint rings. A root port is powered off
+    /// if the controller has Port Power Control; below a hub, use
+    /// `HubDevice::set_port_power`. Until `reactivate`, transfers fail
+    /// with `Inactive` and `slot_id` is 0. Does nothing if the device is
+    /// already inactive; fails with `Disconnected` once it was released.
+    pub fn deactivate(&self) -> Result<()> {
+        let _activation = self.activation.lock();
+        if self.health.disconnected.load(Ordering::Acquire) {
+            return Err(UsbError::Disconnected);
+        }
+        if self.health.inactive.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        let slot_id = self.slot_id();
+        let mask = self.ep_mask.load(Ordering::Acquire) | (1 << Dci::EP0.raw());
+        for dci in (1..=31u32).filter(|dci| mask & (1 << dci) != 0) {
+            // Endpoints that are not running reject the command
+            let _ = self.ctrl.submit_command(Trb {
+                param: 0,
+                status: 0,
+                control: (trb_type::STOP_ENDPOINT << 10) | (dci << 16) | ((slot_id as u32) << 24),
+            });
+        }
+        self.health.disable_slot(&self.ctrl);
+        self.free_ep_rings();
+        self.ep0_ring.lock().release_all();
+
+        if self.below_hub.is_none() && self.ctrl.capabilities().ppc {
+            self.ctrl.set_port_power(self.port, false)?;
+        }
+        Ok(())
+    }
+
+    /// Bring a deactivated device back into service
+    ///
+    /// Powers the root port back on if needed, waits for the device to
+    /// connect, resets the port, enables a new slot and addresses the
+    /// device, then restores the last configuration selected and runs the
+    /// restore hooks, as `reset_and_restore` does. Class drivers on top
+    /// must then call their `reinit`. The slot ID usually changes.
+    ///
+    /// Does nothing if the device is active. On failure the device stays
+    /// inactive. Devices below an external hub fail with `NotSupported`.
+    pub fn reactivate(&self) -> Result<()> {
+        let _activation = self.activation.lock();
+        if self.health.disconnected.load(Ordering::Acquire) {
+            return Err(UsbError::Disconnected);
+        }
+        if !self.health.inactive.load(Ordering::Acquire) {
+            return Ok(());
+        }
+        if self.below_hub.is_some() {
+            return Err(UsbError::NotSupported);
+        }
+
+        if self.ctrl.capabilities().ppc {
+            self.ctrl.set_port_power(self.port, true)?;
+        }
+        self.ctrl
+            .wait_until(RECONNECT_TIMEOUT_US, || self.ctrl.port_connected(self.port))
+            .map_err(|_| UsbError::DeviceNotFound)?;
+        self.ctrl.host().delay_us(RECONNECT_DEBOUNCE_US);
+        self.ctrl.reset_port(self.port)?;
+
+        let slot_id = self.ctrl.enable_slot()?;
+        if let Err(e) = self.address_slot(slot_id) {
+            let _ = self.ctrl.disable_slot(slot_id);
+            return Err(e);
+        }
+        self.health.slot_id.store(slot_id, Ordering::Release);
+        self.health.inactive.store(false, Ordering::Release);
+
+        // Addressing went back to the packet size guessed from the speed
+        if let Some(desc) = self.device_desc {
+            self.update_ep0_max_packet(desc.max_packet_size0)?;
+        }
+
+        let config = self.config.load(Ordering::Acquire);
+        if config != 0 {
+            self.set_configuration(config)?;
+        }
+
+        let mut hooks = self.restore_hooks.lock();
+        for hook in hooks.iter_mut() {
+            hook(self)?;
+        }
+        Ok(())
+    }
+
+    /// Returns false while the device is deactivated.
+    pub fn is_active(&self) -> bool {
+        !self.health.inactive.load(Ordering::Acquire)
+    }
+
+    /// Install the device context in a newly enabled slot and address the
+    /// device there with a fresh EP0 ring
+    fn address_slot(&self, slot_id: u8) -> Result<()> {
+        let host = self.ctrl.host();
+        let _input_lock = self.input_lock.lock();
+
+        unsafe {
+            core::ptr::write_bytes(self.device_ctx.as_ptr::<u8>(), 0, self.device_ctx.size());
+        }
+        self.ctrl
+            .set_device_context(slot_id, self.device_ctx.phys(host))?;
+
+        let ep0_ring = Ring::new(host, self.ep0_trbs, self.ctrl.dma32())?;
+        address_input(
+            &self.input_ctx,
+            self.speed,
+            self.port,
+            None,
+            ep0_ring.phys(host),
+        );
+        let old = core::mem::replace(&mut *self.ep0_ring.lock(), ep0_ring);
+        old.free(host);
+
+        let trb = Trb {
+            param: self.input_ctx.phys(host),
+            status: 0,
+            control: (trb_type::ADDRESS_DEVICE << 10) | ((slot_id as u32) << 24),
+        };
+        self.ctrl.submit_command(trb)?;
+        Ok(())
+    }
+
+    /// Get BOS descriptor (full, with device capabilities)
+    pub fn get_bos_descriptor(&self) -> Result<Vec<u8>> {
+        // First, get just the BOS header to find total length
+        let mut buf = [0u8; 5];
+        let setup = SetupPacket::get_descriptor(desc_type::BOS, 0, 5);
+        self.control_transfer(&setup, Some(&mut buf))?;
+
+        let bos = BosDesc::from_bytes(&buf).ok_or(UsbError::InvalidDescriptor)?;
+        let total_len = bos.total_length as usize;
+        if total_len < 5 || total_len > self.ctrl.max_descriptor_len() {
+            return Err(UsbError::InvalidDescriptor);
+        }
+
+        // Now get the full descriptor
+        let mut full_buf = alloc::vec![0u8; total_len];
+        let setup = SetupPacket::get_descriptor(desc_type::BOS, 0, total_len as u16);
+        self.control_transfer(&setup, Some(&mut full_buf))?;
+
+        Ok(full_buf)
+    }
+
+    /// Enable SuperSpeed link power management (U1/U2).
+    ///
+    /// Issues SET_SEL with the exit latencies of the path to the device,
+    /// from the BOS SuperSpeed capabilities of the device and its hubs and
+    /// the controller's own, then SET_FEATURE(U1_ENABLE/U2_ENABLE). Fails
+    /// with `NotSupported` below a hub whose latencies are unknown.
+    /// The device must be in the configured state. A state the device
+    /// rejects with a STALL is left disabled rather than reported as an
+    /// error; the returned tuple tells which of U1 and U2 were enabled.
+    pub fn enable_link_pm(&self, u1: bool, u2: bool) -> Result<(bool, bool)> {
+        if !self.speed.is_super_or_faster() {
+            return Err(UsbError::NotSupported);
+        }
+
+        let bos = self.get_bos_descriptor()?;
+        let ss_cap = find_capability(&bos, capability::SUPERSPEED_USB)
+            .and_then(SsDevCapDesc::from_bytes)
+            .ok_or(UsbError::NotSupported)?;
+
+        let path = self
+            .link_path(ExitLatency::from_capability(&ss_cap))
+            .ok_or(UsbError::NotSupported)?;
+        let mut sel = path.sel_data(self.path().depth()).to_bytes();
+        self.control_transfer(&SetupPacket::set_sel(), Some(&mut sel))?;
+
+        let enable = |want: bool, feat: u16| -> Result<bool> {
+            if !want {
+                return Ok(false);
+            }
+            match self.control_transfer(&SetupPacket::set_device_feature(feat), None) {
+                Ok(_) => Ok(true),
+                Err(UsbError::Stall) => Ok(false),
+                Err(e) => Err(e),
+            }
+        };
+
+        let u1_on = enable(u1, feature::U1_ENABLE)?;
+        let u2_on = enable(u2, feature::U2_ENABLE)?;
+        Ok((u1_on, u2_on))
+    }
+
+    /// Exit latencies of the path to this device, given its own
+    ///
+    /// `None` below a hub whose exit latencies are unknown.
+    pub(crate) fn link_path(&self, exit: ExitLatency) -> Option<LinkPath> {
+        match &self.below_hub {
+            None => {
+                let caps = self.ctrl.capabilities();
+                let root = ExitLatency {
+                    u1: caps.u1_exit_latency as u32 * 1000,
+                    u2: caps.u2_exit_latency as u32 * 1000,
+                };
+                Some(LinkPath::new(exit, root, None))
+            }
+            Some(attach) => {
+                let hub = attach.hub_link?;
+                Some(LinkPath::new(exit, hub.exit, Some(&hub)))
+            }
+        }
+    }
+
+    /// Disable SuperSpeed link power management (U1/U2).
+    ///
+    /// Useful before high-throughput transfers, since U1/U2 exit latency
+    /// adds up on every idle gap. STALLs are ignored.
+    pub fn disable_link_pm(&self) -> Result<()> {
+        if !self.speed.is_super_or_faster() {
+            return Err(UsbError::NotSupported);
+        }
+
+        for feat in [feature::U1_ENABLE, feature::U2_ENABLE] {
+            match self.control_transfer(&SetupPacket::clear_device_feature(feat), None) {
+                Ok(_) | Err(UsbError::Stall) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Suspend the device by suspending its root port.
+    ///
+    /// Transfers pending on any endpoint are cancelled first, as they
+    /// would be lost when the link goes to U3. Devices behind a hub are
+    /// not supported.
+    pub fn suspend(&self) -> Result<()> {
+        if self.path().route != 0 {
+            return Err(UsbError::NotSupported);
+        }
+        for dci in 2..32 {
+            if let Ok(ep) = self.endpoint(Dci(dci)) {
+                ep.cancel_all()?;
+            }
+        }
+        self.ctrl.suspend_port(self.port)
+    }
+
+    /// Resume the device after `suspend` or a remote wakeup.
+    ///
+    /// Returns once the resume recovery time has passed and the device
+    /// accepts requests again. Does nothing if the port is not suspended.
+    pub fn resume(&self) -> Result<()> {
+        if self.path().route != 0 {
+            return Err(UsbError::NotSupported);
+        }
+        self.ctrl.resume_port(self.port)
+    }
+
+    /// Allow or forbid the device to signal remote wakeup.
+    ///
+    /// Only meaningful if the configuration descriptor advertises remote
+    /// wakeup. SuperSpeed devices arm wakeup per function instead and
+    /// are not supported.
+    pub fn set_remote_wakeup(&self, enable: bool) -> Result<()> {
+        if self.speed.is_super_or_faster() {
+            return Err(UsbError::NotSupported);
+        }
+
+        let setup = if enable {
+            SetupPacket::set_device_feature(feature::DEVICE_REMOTE_WAKEUP)
+        } else {
+            SetupPacket::clear_device_feature(feature::DEVICE_REMOTE_WAKEUP)
+        };
+        self.control_transfer(&setup, None)?;
+        Ok(())
+    }
+
+    /// Enable USB2 hardware LPM (L1) for this device.
+    ///
+    /// Consults the USB 2.0 Extension capability in the device's BOS and
+    /// only enables L1 if the device advertises LPM support. The BESL used
+    /// is the larger of `besl` and the device's baseline BESL. Devices
+    /// below an external hub fail with `NotSupported`; the controller
+    /// only manages L1 on its own root ports.
+    pub fn enable_usb2_lpm(&self, besl: u8) -> Result<()> {
+        if self.below_hub.is_some() || self.speed != Speed::High {
+            return Err(UsbError::NotSupported);
+        }
+
+        // Pre-2.01 devices have no BOS and stall the request
+        let bos = match self.get_bos_descriptor() {
+            Ok(bos) => bos,
+            Err(UsbError::Stall) => return Err(UsbError::NotSupported),
+            Err(e) => return Err(e),
+        };
+        let ext = find_capability(&bos, capability::USB_2_0_EXTENSION)
+            .and_then(Usb20ExtCapDesc::from_bytes)
+            .ok_or(UsbError::NotSupported)?;
+        if !ext.lpm_supported() {
+            return Err(UsbError::NotSupported);
+        }
+
+        let besl = besl.max(ext.baseline_besl().unwrap_or(0));
+        self.ctrl.enable_usb2_lpm(self.port, self.slot_id(), besl)
+    }
+
+    /// Disable USB2 hardware LPM (L1) for this device.
+    ///
+    /// Like `enable_usb2_lpm`, fails with `NotSupported` below a hub.
+    pub fn disable_usb2_lpm(&self) -> Result<()> {
+        if self.below_hub.is_some() {
+            return Err(UsbError::NotSupported);
+        }
+        self.ctrl.disable_usb2_lpm(self.port)
+    }
+
+    /// Configure an endpoint (after SET_CONFIGURATION)
+    pub fn configure_endpoint(&self, ep: &EndpointDesc) -> Result<EndpointHandle<H>> {
+        self.configure_endpoint_with_interval(ep, None)
+    }
+
+    /// Configure an endpoint with a service interval other than bInterval
+    ///
+    /// `interval` is in the xHCI encoding (see `set_endpoint_interval`)
+    /// and only applies to interrupt and isochronous endpoints; `None`
+    /// keeps the interval from the descriptor.
+    pub fn configure_endpoint_with_interval(
+        &self,
+        ep: &EndpointDesc,
+        interval: Option<u8>,
+    ) -> Result<EndpointHandle<H>> {
+        if let Some(interval) = interval {
+            self.check_interval(interval)?;
+        }
+        self.configure_endpoints_with(core::slice::from_ref(ep), interval)?;
+        self.endpoint(Dci::from_desc(ep))
+    }
+
+    /// Configure several endpoints with a single Configure Endpoint command
+    ///
+    /// The Input Context is locked for the whole command, so drivers
+    /// sharing a composite device may configure their endpoints
+    /// concurrently. Returns one handle per descriptor, in order.
+    pub fn configure_endpoints(&self, eps: &[EndpointDesc]) -> Result<Vec<EndpointHandle<H>>> {
+        self.configure_endpoints_with(eps, None)
+    }
+
+    /// Like `configure_endpoints`, overriding the interval of periodic endpoints
+    fn configure_endpoints_with(
+        &self,
+        eps: &[EndpointDesc],
+        interval: Option<u8>,
+    ) -> Result<Vec<EndpointHandle<H>>> {
+        self.reconfigure_endpoints(&[], eps, interval)?;
+        eps.iter().map(|ep| self.endpoint(Dci::from_desc(ep))).collect()
+    }
+
+    /// Drop and add endpoints with a single Configure Endpoint command
+    ///
+    /// Endpoints added while already configured are dropped and re-added.
+    /// The new rings only replace the old ones once the command succeeded,
+    /// so on failure the device keeps its previous endpoints. A Bandwidth
+    /// or Secondary Bandwidth Error fails with `InsufficientBandwidth`.
+    fn reconfigure_endpoints(
+        &self,
+        drop: &[Dci],
+        eps: &[EndpointDesc],
+        interval: Option<u8>,
+    ) -> Result<()> {
+        self.health.check_failed()?;
+        let host = self.ctrl.host();
+        let _input_lock = self.input_lock.lock();
+
+        let input = self.input_ctx.as_ptr::<InputContext>();
+        let mut drop_flags = 0;
+        for dci in drop {
+            if *dci == Dci::EP0 {
+                return Err(UsbError::InvEndpoint);
+            }
+            drop_flags |= 1 << dci.raw();
+        }
+
+        let mut add_flags = 1; // Slot
+        let mut rings = Vec::with_capacity(eps.len());
+        let free = |rings: Vec<(Dci, Ring<H>)>| rings.into_iter().for_each(|(_, r)| r.free(host));
+        for ep in eps {
+            let dci = Dci::from_desc(ep);
+            if dci == Dci::EP0 {
+                free(rings);
+                return Err(UsbError::InvEndpoint);
+            }
+
+            // Allocate transfer ring for this endpoint
+            let ring = match Ring::new(host, 256, self.ctrl.dma32()) {
+                Ok(ring) => ring,
+                Err(e) => {
+                    free(rings);
+                    return Err(e);
+                }
+            };
+            unsafe {
+                (*input).endpoints[dci.index()] =
+                    self.endpoint_context(ep, interval, ring.phys(host));
+            }
+            add_flags |= 1 << dci.raw();
+            rings.push((dci, ring));
+        }
+
+        let configured = self.ep_mask.load(Ordering::Acquire);
+        drop_flags |= add_flags & configured;
+
+        // Context Entries must cover the highest configured endpoint
+        let mask = (configured & !drop_flags) | add_flags | 1 << Dci::EP0.raw();
+        let entries = u32::BITS - 1 - mask.leading_zeros();
+
+        let slot_dw0 = unsafe { (*input).slot.dw0 };
+        unsafe {
+            (*input).input_control[0] = drop_flags;
+            (*input).input_control[1] = add_flags;
+            (*input).slot.dw0 = (slot_dw0 & !(0x1f << 27)) | (entries << 27);
+        }
+
+        // Configure Endpoint command
+        let trb = Trb {
+            param: self.input_ctx.phys(host),
+            status: 0,
+            control: (trb_type::CONFIGURE_ENDPOINT << 10) | ((self.slot_id() as u32) << 24),
+        };
+        if let Err(e) = self.ctrl.submit_command(trb) {
+            unsafe {
+                (*input).slot.dw0 = slot_dw0;
+            }
+            free(rings);
+            return Err(match e {
+                UsbError::CmdFail(
+                    completion::BANDWIDTH_ERROR | completion::SECONDARY_BANDWIDTH_ERROR,
+                ) => bandwidth_error(eps),
+                e => e,
+            });
+        }
+
+        for dci in drop {
+            let shared = &self.ep_rings[dci.index()];
+            if let Some(old) = shared.ring.lock().take() {
+                old.free(host);
+            }
+            self.health.pending(*dci).store(0, Ordering::Release);
+            self.health.periodic.lock()[dci.index()] = (0, 0);
+            self.ep_mask.fetch_and(!(1 << dci.raw()), Ordering::AcqRel);
+        }
+        for (dci, ring) in rings {
+            // Release the ring left from an earlier configuration
+            let shared = &self.ep_rings[dci.index()];
+            let old = shared.ring.lock().replace(ring);
+            if let Some(old) = old {
+                old.free(host);
+            }
+            self.health.pending(dci).store(0, Ordering::Release);
+            self.record_periodic(dci);
+            self.ep_mask.fetch_or(1 << dci.raw(), Ordering::AcqRel);
+        }
+        Ok(())
+    }
+
+    /// Select the first alternate setting of an interface that fits
+    ///
+    /// Tries the settings in `preference_order`, e.g. from most to least
+    /// bandwidth, configuring each one's endpoints in place of those of
+    /// the interface's other settings. A setting the controller has no
+    /// bandwidth for is skipped; the first that fits is selected with
+    /// SET_INTERFACE. Returns its number and endpoint handles, or the last
+    /// `InsufficientBandwidth` error if none fit.
+    ///
+    /// If SET_INTERFACE fails, the endpoints of the setting the device is
+    /// still in are configured again before the error is returned.
+    pub fn try_alt_settings(
+        &self,
+        interface: u8,
+        preference_order: &[u8],
+    ) -> Result<(u8, Vec<EndpointHandle<H>>)> {
+        let tree = self.config_tree()?;
+        let found = tree
+            .interfaces
+            .iter()
+            .find(|found| found.number() == interface)
+            .ok_or(UsbError::InvalidArgument)?;
+
+        // Endpoints of the interface configured for an earlier setting
+        let configured = self.ep_mask.load(Ordering::Acquire);
+        let owned: Vec<Dci> = found
+            .settings
+            .iter()
+            .flat_map(|setting| &setting.endpoints)
+            .map(Dci::from_desc)
+            .filter(|dci| configured & 1 << dci.raw() != 0)
+            .collect();
+
+        let mut result = Err(UsbError::InvalidArgument);
+        for &alt in preference_order {
+            let Some(setting) = found
+                .settings
+                .iter()
+                .find(|setting| setting.iface.alternate_setting == alt)
+            else {
+                continue;
+            };
+            match self.reconfigure_endpoints(&owned, &setting.endpoints, None) {
+                Ok(()) => {}
+                Err(e @ UsbError::InsufficientBandwidth { .. }) => {
+                    result = Err(e);
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+
+            if let Err(e) = self.set_interface(interface, alt) {
+                self.restore_alt_setting(found, &owned, &setting.endpoints);
+                return Err(e);
+            }
+            let handles = setting
+                .endpoints
+                .iter()
+                .map(|ep| self.endpoint(Dci::from_desc(ep)))
+                .collect::<Result<Vec<_>>>()?;
+            return Ok((alt, handles));
+        }
+        result
+    }
+
+    /// Put back the endpoints of the setting the device is still in
+    ///
+    /// `configured` are the interface's endpoints configured before
+    /// `added` replaced them. The previous setting is the first one with
+    /// exactly those endpoints; if none matches, only `added` is dropped.
+    fn restore_alt_setting(
+        &self,
+        found: &FoundInterface,
+        configured: &[Dci],
+        added: &[EndpointDesc],
+    ) {
+        let previous = found
+            .settings
+            .iter()
+            .find(|setting| {
+                let dcis = setting.endpoints.iter().map(Dci::from_desc);
+                dcis.clone().all(|dci| configured.contains(&dci))
+                    && configured.iter().all(|dci| dcis.clone().any(|d| d == *dci))
+            })
+            .map_or(&[][..], |setting| &setting.endpoints);
+        let added: Vec<Dci> = added.iter().map(Dci::from_desc).collect();
+        if let Err(e) = self.reconfigure_endpoints(&added, previous, None) {
+            self.ctrl.host().warn(format_args!(
+                "slot {}: restoring endpoints after SET_INTERFACE failed: {e:?}",
+                self.slot_id()
+            ));
+        }
+    }
+
+    /// Change the service interval of a configured periodic endpoint
+    ///
+    /// `interval` is in the xHCI encoding: the endpoint is serviced every
+    /// 2^interval * 125 microseconds, from 0 to 15, and at least 3 (1 ms)
+    /// on full- and low-speed devices. The endpoint is stopped and
+    /// re-added with a Configure Endpoint command on its existing ring;
+    /// transfers still pending are discarded.
+    pub fn set_endpoint_interval(&self, dci: Dci, interval: u8) -> Result<()> {
+        self.check_interval(interval)?;
+        let handle = self.endpoint(dci)?;
+        match handle.stop() {
+            // Already stopped, halted or in error
+            Ok(()) | Err(UsbError::CmdFail(completion::CONTEXT_STATE_ERROR)) => {}
+            Err(e) => return Err(e),
+        }
+
+        let host = self.ctrl.host();
+        let _input_lock = self.input_lock.lock();
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        let mut ep = unsafe { core::ptr::addr_of!((*ctx).endpoints[dci.index()]).read_volatile() };
+
+        // Interrupt and isochronous endpoint types (either direction)
+        if !matches!((ep.dw1 >> 3) & 0x3, 1 | 3) {
+            return Err(UsbError::InvEndpoint);
+        }
+
+        let dequeue = {
+            let mut ring = handle.shared.ring.lock();
+            let ring = ring.as_mut().ok_or(UsbError::InvEndpoint)?;
+            ring.release_all();
+            handle
+                .health
+                .pending(handle.dci)
+                .store(0, Ordering::Release);
+            ring.enqueue_ptr(host)
+        };
+        ep.dw0 = (ep.dw0 & !(0xff << 16 | 0x7)) | (interval as u32) << 16;
+        ep.tr_dequeue_lo = dequeue as u32;
+        ep.tr_dequeue_hi = (dequeue >> 32) as u32;
+
+        let input = self.input_ctx.as_ptr::<InputContext>();
+        unsafe {
+            (*input).input_control[0] = 1 << dci.raw();
+            (*input).input_control[1] = 1 | 1 << dci.raw();
+            (*input).endpoints[dci.index()] = ep;
+        }
+
+        let trb = Trb {
+            param: self.input_ctx.phys(host),
+            status: 0,
+            control: (trb_type::CONFIGURE_ENDPOINT << 10) | ((self.slot_id() as u32) << 24),
+        };
+        self.ctrl.submit_command(trb)?;
+        self.record_periodic(dci);
+        Ok(())
+    }
+
+    /// Note the interval and ESIT payload of a configured endpoint for
+    /// `XhciCtrl::periodic_load`
+    fn record_periodic(&self, dci: Dci) {
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        let ep = unsafe { core::ptr::addr_of!((*ctx).endpoints[dci.index()]).read_volatile() };
+        // Interrupt and isochronous endpoint types (either direction)
+        let payload = match (ep.dw1 >> 3) & 0x3 {
+            1 | 3 => (ep.dw0 >> 24) << 16 | ep.dw4 >> 16,
+            _ => 0,
+        };
+        self.health.periodic.lock()[dci.index()] = ((ep.dw0 >> 16) as u8, payload);
+    }
+
+    /// Fail with `InvalidArgument` unless `interval` suits the device speed
+    fn check_interval(&self, interval: u8) -> Result<()> {
+        let min = if matches!(self.speed, Speed::Low | Speed::Full) { 3 } else { 0 };
+        if !(min..=15).contains(&interval) {
+            return Err(UsbError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Returns the number of transfers pending on an endpoint.
+    ///
+    /// 0 for an endpoint that is not configured; see
+    /// `EndpointHandle::pending`.
+    pub fn pending_transfers(&self, ep_num: u8, is_in: bool) -> usize {
+        self.endpoint(Dci::from_ep(ep_num, is_in))
+            .map_or(0, |ep| ep.pending())
+    }
+
+    /// Cancel every transfer pending on an endpoint
+    ///
+    /// Returns the number of transfers cancelled; see
+    /// `EndpointHandle::cancel_all`. Fails with `InvEndpoint` if the
+    /// endpoint is not configured.
+    pub fn cancel_all(&self, ep_num: u8, is_in: bool) -> Result<usize> {
+        self.endpoint(Dci::from_ep(ep_num, is_in))?.cancel_all()
+    }
+
+    /// Handle to an already configured endpoint
+    pub fn endpoint(&self, dci: Dci) -> Result<EndpointHandle<H>> {
+        let ring = self
+            .ep_rings
+            .get(dci.index())
+            .filter(|r| dci != Dci::EP0 && r.ring.lock().is_some())
+            .ok_or(UsbError::InvEndpoint)?;
+
+        Ok(EndpointHandle {
+            ctrl: self.ctrl.clone(),
+            dci,
+            shared: ring.clone(),
+            device_ctx: self.device_ctx.clone(),
+            health: self.health.clone(),
+        })
+    }
+
+    /// Release the transfer rings of all endpoints but EP0
+    fn free_ep_rings(&self) {
+        let host = self.ctrl.host();
+        self.ep_mask.store(0, Ordering::Release);
+        for ring in &self.ep_rings {
+            let old = ring.ring.lock().take();
+            if let Some(r) = old {
+                r.free(host);
+            }
+        }
+        for pending in &self.health.pending {
+            pending.store(0, Ordering::Release);
+        }
+        *self.health.periodic.lock() = Default::default();
+    }
+
+    /// Build the Endpoint Context for an endpoint descriptor
+    ///
+    /// `interval` overrides the service interval of periodic endpoints.
+    fn endpoint_context(
+        &self,
+        ep: &EndpointDesc,
+        interval: Option<u8>,
+        ring_phys: u64,
+    ) -> EndpointContext {
+        // xHCI endpoint type encoding
+        let xhci_ep_type = match (ep.transfer_type(), ep.is_in()) {
+            (0, _) => 4,     // Control (bidirectional)
+            (1, false) => 1, // Isoch OUT
+            (1, true) => 5,  // Isoch IN
+            (2, false) => 2, // Bulk OUT
+            (2, true) => 6,  // Bulk IN
+            (3, false) => 3, // Interrupt OUT
+            (3, true) => 7,  // Interrupt IN
+            _ => 4,
+        };
+
+        let periodic = matches!(ep.transfer_type(), 1 | 3);
+
+        // Calculate interval for xHCI (different from USB descriptor)
+        let interval = match interval.filter(|_| periodic) {
+            Some(interval) => interval,
+            None => bandwidth::xhci_interval(self.speed, ep),
+        };
+
+        // High-speed periodic endpoints carry extra transactions per
+        // microframe in bits 12:11, which xHCI takes as Max Burst;
+        // SuperSpeed endpoints advertise their burst in the companion
+        let companion = self.ep_companion(ep);
+        let max_burst = match companion {
+            Some(comp) => comp.max_burst.min(15),
+            None if self.speed == Speed::High && periodic => ep.additional_transactions(),
+            None => 0,
+        };
+
+        let mut ctx = EndpointContext::new(
+            xhci_ep_type,
+            ep.packet_size(),
+            max_burst,
+            interval,
+            ring_phys,
+        );
+        if let Some(comp) = companion
+            && ep.transfer_type() == 1
+        {
+            ctx.dw0 |= (comp.mult() as u32) << 8;
+        }
+        // The controller reserves bandwidth for periodic endpoints by it
+        let esit = bandwidth::esit_payload(ep, companion.as_ref());
+        ctx.dw0 |= (esit >> 16) << 24;
+        ctx.dw4 |= (esit & 0xFFFF) << 16;
+        ctx
+    }
+
+    /// SuperSpeed Endpoint Companion of an endpoint
+    ///
+    /// Looks in the cached configurations, then in the active one, which
+    /// is fetched again after `set_configuration` cleared the cache.
+    fn ep_companion(&self, ep: &EndpointDesc) -> Option<SsEpCompDesc> {
+        if !self.speed.is_super_or_faster() {
+            return None;
+        }
+        let cached = self
+            .configs
+            .lock()
+            .iter()
+            .find_map(|(_, tree)| tree.companion(ep));
+        cached.or_else(|| self.config_tree().ok()?.companion(ep))
+    }
+
+    /// Dword 1 of a configured endpoint's output context
+    fn ep_context_dw1(&self, ep_num: u8, is_in: bool) -> Option<u32> {
+        if ep_num > 15 {
+            return None;
+        }
+        let dci = Dci::from_ep(ep_num, is_in);
+        let mask = self.ep_mask.load(Ordering::Acquire);
+        if dci != Dci::EP0 && mask & (1 << dci.raw()) == 0 {
+            return None;
+        }
+
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        Some(unsafe { core::ptr::addr_of!((*ctx).endpoints[dci.index()].dw1).read_volatile() })
+    }
+
+    /// Returns the max packet size the controller uses for an endpoint.
+    ///
+    /// Read back from the endpoint context, without the high-speed
+    /// additional transaction bits. `None` if the endpoint is not
+    /// configured.
+    pub fn ep_max_packet(&self, ep_num: u8, is_in: bool) -> Option<u16> {
+        self.ep_context_dw1(ep_num, is_in)
+            .map(|dw1| (dw1 >> 16) as u16)
+    }
+
+    /// Returns the Max Burst the controller uses for an endpoint.
+    ///
+    /// Packets per burst minus one: the companion's bMaxBurst on
+    /// SuperSpeed, the additional transactions of high-bandwidth
+    /// high-speed endpoints. `None` if the endpoint is not configured.
+    pub fn ep_max_burst(&self, ep_num: u8, is_in: bool) -> Option<u8> {
+        self.ep_context_dw1(ep_num, is_in)
+            .map(|dw1| (dw1 >> 8) as u8)
+    }
+
+    /// Returns the size transfers on an endpoint should be multiples of.
+    ///
+    /// A read of a multiple of this size only ends early when the device
+    /// sends less data, never through an unintended short packet. Taken
+    /// from the endpoint context once configured, else from the
+    /// descriptor; never 0.
+    pub fn transfer_granularity(&self, ep: &EndpointDesc) -> usize {
+        let num = ep.endpoint_address & 0x0f;
+        let max_packet = self.ep_max_packet(num, ep.is_in()).unwrap_or(ep.packet_size());
+        (max_packet as usize).max(1)
+    }
+
+    /// Returns the bytes one burst on an endpoint can carry.
+    ///
+    /// `transfer_granularity` times the packets per burst, so a periodic
+    /// read of this size takes everything the device sends in one
+    /// service interval.
+    pub fn burst_size(&self, ep: &EndpointDesc) -> usize {
+        let num = ep.endpoint_address & 0x0f;
+        let burst = self.ep_max_burst(num, ep.is_in()).unwrap_or(0);
+        self.transfer_granularity(ep) * (burst as usize + 1)
+    }
+
+    /// Returns the xHCI slot ID assigned to this device, 0 while it is
+    /// deactivated or released.
+    pub fn slot_id(&self) -> u8 {
+        self.health.slot_id()
+    }
+
+    /// Count a class driver bound to the device, for `XhciCtrl::topology`
+    pub(crate) fn bind_driver(&self, kind: DriverKind) {
+        self.health.drivers[kind as usize].fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Undo `bind_driver` when the driver is dropped
+    pub(crate) fn unbind_driver(&self, kind: DriverKind) {
+        self.health.drivers[kind as usize].fetch_sub(1, Ordering::AcqRel);
+    }
+
+    /// Returns the root hub port number this device is connected to.
+    pub fn port(&self) -> u8 {
+        self.port
+    }
+
+    /// Returns the topological path of the device.
+    ///
+    /// The hub port chain is taken from the route string of the slot
+    /// context, so it matches what the controller was given.
+    pub fn path(&self) -> DevicePath {
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        let dw0 = unsafe { core::ptr::addr_of!((*ctx).slot.dw0).read_volatile() };
+        DevicePath {
+            bus: self.ctrl.bus_index(),
+            root_port: self.port,
+            route: dw0 & 0xfffff,
+        }
+    }
+
+    /// Returns the USB address the controller assigned to the device.
+    ///
+    /// Read from the Device Address field of the slot context; 0 before
+    /// Address Device completes.
+    pub fn address(&self) -> u8 {
+        let ctx = self.device_ctx.as_ptr::<DeviceContext>();
+        let dw3 = unsafe { core::ptr::addr_of!((*ctx).slot.dw3).read_volatile() };
+        dw3 as u8
+    }
+
+    /// Returns the device speed.
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Returns the cached device descriptor, if it has been read.
+    pub fn device_desc(&self) -> Option<&DeviceDesc> {
+        self.device_desc.as_ref()
+    }
+
+    /// Returns a reference to the xHCI controller.
+    pub fn ctrl(&self) -> &Arc<XhciCtrl<H>> {
+        &self.ctrl
+    }
+}
+
+/// Device brought up by `XhciCtrl::enumerate_concurrent`.
+pub struct EnumeratedDevice<H: Dma> {
+    /// Addressed and configured device
+    pub device: UsbDevice<H>,
+    /// Full configuration descriptor of the active configuration
+    pub config: Vec<u8>,
+    /// Every configuration of the device, in descriptor index order
+    pub configs: Vec<Arc<ConfigTree>>,
+}
+
+/// Step of the enumeration sequence, as reported by `EnumEvent::Failed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnumStep {
+    /// Port reset
+    Reset,
+    /// Enable Slot command
+    EnableSlot,
+    /// Address Device command
+    Address,
+    /// GET_DESCRIPTOR for the device descriptor
+    DeviceDescriptor,
+    /// GET_DESCRIPTOR for a configuration descriptor
+    ConfigDescriptor,
+    /// SET_CONFIGURATION
+    SetConfiguration,
+}
+
+/// Class driver of this crate.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DriverKind {
+    /// `HidDevice`
+    Hid,
+    /// `MscDevice`
+    MassStorage,
+    /// `HubDevice`
+    Hub,
+}
+
+impl DriverKind {
+    const ALL: [Self; 3] = [Self::Hid, Self::MassStorage, Self::Hub];
+}
+
+/// Progress of the enumeration of one port.
+#[derive(Clone, Copy, Debug)]
+pub enum EnumEvent {
+    /// The port reset completed
+    PortReset {
+        /// Time from starting the reset to its completion, in microseconds
+        duration_us: u64,
+    },
+    /// A slot was enabled for the device
+    SlotEnabled {
+        /// Slot ID
+        slot_id: u8,
+    },
+    /// The Address Device command completed
+    Addressed,
+    /// The device descriptor was read
+    DeviceDescriptor(DeviceDesc),
+    /// A full configuration descriptor was read
+    ConfigDescriptor {
+        /// Descriptor index
+        index: u8,
+        /// Bytes read
+        len: usize,
+    },
+    /// SET_CONFIGURATION completed
+    Configured {
+        /// bConfigurationValue of the selected configuration
+        value: u8,
+    },
+    /// An interface of the selected configuration matches a class driver
+    ///
+    /// Reported once per driver kind; creating the driver is up to the
+    /// caller.
+    DriverBound(DriverKind),
+    /// Enumeration of the port failed; no further events follow
+    Failed(EnumStep, UsbError),
+}
+
+/// Observer of the progress of `XhciCtrl::enumerate_observed`.
+///
+/// Events are delivered synchronously from the polling loop, in order
+/// for each port; events of different ports interleave.
+pub trait EnumObserver {
+    /// False for observers that ignore every event, so events that are
+    /// costly to build are skipped.
+    const ENABLED: bool = true;
+
+    /// Called for every enumeration event of root port `port` (0-based).
+    fn event(&self, port: u8, ev: EnumEvent);
+}
+
+/// The no-op observer.
+impl EnumObserver for () {
+    const ENABLED: bool = false;
+
+    #[inline(always)]
+    fn event(&self, _port: u8, _ev: EnumEvent) {}
+}
+
+/// Enumeration stage of a single port.
+#[derive(Clone, Copy, PartialEq)]
+enum EnumStage {
+    Reset,
+    DeviceDesc,
+    /// Device descriptor requested again after a short first read
+    DeviceDescRetry,
+    ConfigHeader,
+    Config,
+    /// Configuration requested again after its wTotalLength changed
+    ConfigRetry,
+    SetConfig,
+}
+
+impl EnumStage {
+    /// Step reported if the stage fails
+    fn step(self) -> EnumStep {
+        match self {
+            Self::Reset => EnumStep::Reset,
+            Self::DeviceDesc | Self::DeviceDescRetry => EnumStep::DeviceDescriptor,
+            Self::ConfigHeader | Self::Config | Self::ConfigRetry => EnumStep::ConfigDescriptor,
+            Self::SetConfig => EnumStep::SetConfiguration,
+        }
+    }
+}
+
+/// Configuration descriptor answer, checked by `reconcile_config`
+enum ConfigAnswer {
+    /// The bytes that arrived
+    Data(Vec<u8>),
+    /// wTotalLength changed since the header was read; fetch again with it
+    Refetch(u16),
+}
+
+/// Reconcile the wTotalLength requested with the one the answer carries
+/// and the bytes that arrived
+///
+/// `data` is truncated to `transferred`. An answer whose header changed
+/// is fetched again once, unless `retried` or the new length is out of
+/// range. Disagreements are reported through `Dma::warn`.
+fn reconcile_config<H: Dma>(
+    host: &H,
+    index: u8,
+    requested: usize,
+    mut data: Vec<u8>,
+    transferred: usize,
+    retried: bool,
+    max_len: usize,
+) -> ConfigAnswer {
+    data.truncate(transferred);
+    if data.len() < 4 {
+        return ConfigAnswer::Data(data);
+    }
+
+    let claimed = le16(&data, 2) as usize;
+    if claimed != requested {
+        if !retried && (ConfigDesc::SIZE..=max_len).contains(&claimed) {
+            host.warn(format_args!(
+                "configuration {index}: wTotalLength changed from {requested} to {claimed}, fetching again"
+            ));
+            return ConfigAnswer::Refetch(claimed as u16);
+        }
+        host.warn(format_args!(
+            "configuration {index}: wTotalLength {claimed} disagrees with the {requested} bytes requested, {transferred} returned"
+        ));
+    } else if transferred < requested {
+        host.warn(format_args!(
+            "configuration {index}: {transferred} of {requested} bytes returned"
+        ));
+    }
+    ConfigAnswer::Data(data)
+}
+
+/// Per-port state of the concurrent enumeration state machine.
+struct EnumPort<H: Dma> {
+    port: u8,
+    stage: EnumStage,
+    /// Step reported if the port fails now
+    step: EnumStep,
+    reset_change: u32,
+    /// Started with the port reset, then with every control request
+    watch: Stopwatch,
+    device: Option<UsbDevice<H>>,
+    xfer: Option<ControlTransfer<H>>,
+    setup: SetupPacket,
+    attempt: u8,
+    /// Time on `watch` at which a backed-off retry is queued
+    resume_at: Option<u64>,
+    buf: Vec<u8>,
+    /// Configurations fetched so far, in descriptor index order
+    configs: Vec<Arc<ConfigTree>>,
+    /// Index of the selected configuration in `configs`
+    chosen: usize,
+}
+
+impl<H: Dma> EnumPort<H> {
+    fn start(ctrl: &XhciCtrl<H>, port: u8) -> Self {
+        // A connect change after the reset means the device went away
+        ctrl.clear_connect_change(port);
+        Self {
+            port,
+            stage: EnumStage::Reset,
+            step: EnumStep::Reset,
+            reset_change: ctrl.start_port_reset(port),
+            watch: ctrl.stopwatch(),
+            device: None,
+            xfer: None,
+            setup: SetupPacket::default(),
+            attempt: 1,
+            resume_at: None,
+            buf: Vec::new(),
+            configs: Vec::new(),
+            chosen: 0,
+        }
+    }
+
+    /// Queue the next control request of the bring-up sequence
+    fn request(&mut self, setup: SetupPacket, stage: EnumStage) -> Result<()> {
+        let device = self.device.as_ref().ok_or(UsbError::DeviceNotFound)?;
+        self.buf.clear();
+        self.buf.resize(setup.length as usize, 0);
+        self.xfer = Some(device.start_control(&setup, Some(&self.buf))?);
+        self.watch = device.ctrl.stopwatch();
+        self.setup = setup;
+        self.stage = stage;
+        self.step = stage.step();
+        self.attempt = 1;
+        Ok(())
+    }
+
+    /// Recover EP0 after a transient error and schedule the retry
+    ///
+    /// The request is queued again by `resume` once the backoff passed,
+    /// so other ports keep making progress meanwhile.
+    fn retry(&mut self) -> Result<()> {
+        let device = self.device.as_ref().ok_or(UsbError::DeviceNotFound)?;
+        device.recover_for_retry()?;
+        self.watch = device.ctrl.stopwatch();
+        self.resume_at = Some(device.retry_policy().backoff_before(self.attempt) as u64);
+        Ok(())
+    }
+
+    /// Queue the retry once its backoff passed; returns false until then
+    fn resume(&mut self, ctrl: &XhciCtrl<H>) -> Result<bool> {
+        let Some(resume_at) = self.resume_at else {
+            return Ok(true);
+        };
+        if self.watch.elapsed_us(ctrl) < resume_at {
+            return Ok(false);
+        }
+        let device = self.device.as_ref().ok_or(UsbError::DeviceNotFound)?;
+        self.resume_at = None;
+        self.buf.fill(0);
+        self.xfer = Some(device.start_control(&self.setup, Some(&self.buf))?);
+        self.watch = device.ctrl.stopwatch();
+        self.attempt += 1;
+        Ok(true)
+    }
+
+    /// Advance the state machine; returns the outcome once finished
+    fn step<O: EnumObserver>(
+        &mut self,
+        ctrl: &Arc<XhciCtrl<H>>,
+        policy: &dyn Fn(&DeviceDesc, &[Arc<ConfigTree>]) -> usize,
+        observer: &O,
+    ) -> Option<Result<EnumeratedDevice<H>>> {
+        match self.step_inner(ctrl, policy, observer) {
+            Ok(true) => {
+                let device = self.device.take()?;
+                let configs = core::mem::take(&mut self.configs);
+                let config = configs[self.chosen].raw().to_vec();
+                Some(Ok(EnumeratedDevice {
+                    device,
+                    config,
+                    configs,
+                }))
+            }
+            Ok(false) => None,
+            Err(e) => {
+                observer.event(self.port, EnumEvent::Failed(self.step, e));
+                Some(Err(e))
+            }
+        }
+    }
+
+    fn step_inner<O: EnumObserver>(
+        &mut self,
+        ctrl: &Arc<XhciCtrl<H>>,
+        policy: &dyn Fn(&DeviceDesc, &[Arc<ConfigTree>]) -> usize,
+        observer: &O,
+    ) -> Result<bool> {
+        if self.stage == EnumStage::Reset {
+            if !ctrl.port_reset_done(self.port, self.reset_change) {
+                if self.watch.elapsed_us(ctrl) >= PORT_RESET_TIMEOUT_US as u64 {
+                    return Err(UsbError::Timeout);
+                }
+                return Ok(false);
+            }
+
+            ctrl.clear_port_reset_change(self.port);
+            if ctrl.connect_changed(self.port) || !ctrl.port_connected(self.port) {
+                return Err(UsbError::DeviceNotFound);
+            }
+            let port = self.port;
+            let duration_us = self.watch.elapsed_us(ctrl);
+            observer.event(port, EnumEvent::PortReset { duration_us });
+
+            self.step = EnumStep::EnableSlot;
+            let mut step = self.step;
+            let ep0_trbs = ctrl.ep0_ring_size();
+            let device = UsbDevice::address_with(ctrl.clone(), port, None, ep0_trbs, |slot_id| {
+                observer.event(port, EnumEvent::SlotEnabled { slot_id });
+                step = EnumStep::Address;
+            });
+            self.step = step;
+            self.device = Some(device?);
+            observer.event(port, EnumEvent::Addressed);
+
+            self.request(
+                SetupPacket::get_descriptor(desc_type::DEVICE, 0, 18),
+                EnumStage::DeviceDesc,
+            )?;
+            return Ok(false);
+        }
+        if !self.resume(ctrl)? {
+            return Ok(false);
+        }
+
+        let (Some(device), Some(xfer)) = (self.device.as_mut(), self.xfer.as_mut()) else {
+            return Err(UsbError::DeviceNotFound);
+        };
+        let len = match device.poll_control(xfer, Some(&mut self.buf)) {
+            Some(Err(e))
+                if RetryPolicy::retryable(&e) && self.attempt < device.retry_policy().attempts =>
+            {
+                self.xfer = None;
+                self.retry()?;
+                return Ok(false);
+            }
+            Some(result) => result?,
+            None if self.watch.elapsed_us(ctrl) >= CONTROL_TIMEOUT_US as u64 => {
+                return Err(UsbError::Timeout);
+            }
+            None => return Ok(false),
+        };
+        self.xfer = None;
+
+        match self.stage {
+            EnumStage::DeviceDesc | EnumStage::DeviceDescRetry => {
+                if !DeviceDesc::header_valid(&self.buf[..len]) {
+                    return Err(UsbError::InvalidDescriptor);
+                }
+                if len < DeviceDesc::SIZE {
+                    if self.stage == EnumStage::DeviceDescRetry {
+                        return Err(UsbError::InvalidDescriptor);
+                    }
+                    if len >= 8 {
+                        device.update_ep0_max_packet(self.buf[7])?;
+                    }
+                    self.request(
+                        SetupPacket::get_descriptor(desc_type::DEVICE, 0, 18),
+                        EnumStage::DeviceDescRetry,
+                    )?;
+                    return Ok(false);
+                }
+                device.device_desc = DeviceDesc::from_bytes(&self.buf);
+                if let Some(desc) = device.device_desc {
+                    device.health.record_descriptor(&desc);
+                    device.apply_quirks(&desc)?;
+                    observer.event(self.port, EnumEvent::DeviceDescriptor(desc));
+                }
+                self.request(
+                    SetupPacket::get_descriptor(desc_type::CONFIGURATION, 0, 9),
+                    EnumStage::ConfigHeader,
+                )?;
+            }
+            EnumStage::ConfigHeader => {
+                if len < 9 || !ConfigDesc::header_valid(&self.buf) {
+                    return Err(UsbError::InvalidDescriptor);
+                }
+                let config =
+                    ConfigDesc::from_bytes(&self.buf).ok_or(UsbError::InvalidDescriptor)?;
+                let total_len = config.total_length;
+                if total_len as usize > ctrl.max_descriptor_len() {
+                    return Err(UsbError::InvalidDescriptor);
+                }
+                let index = self.configs.len() as u8;
+                self.request(
+                    SetupPacket::get_descriptor(desc_type::CONFIGURATION, index, total_len),
+                    EnumStage::Config,
+                )?;
+            }
+            EnumStage::Config | EnumStage::ConfigRetry => {
+                let index = self.configs.len() as u8;
+                let answer = reconcile_config(
+                    ctrl.host(),
+                    index,
+                    self.setup.length as usize,
+                    core::mem::take(&mut self.buf),
+                    len,
+                    self.stage == EnumStage::ConfigRetry,
+                    ctrl.max_descriptor_len(),
+                );
+                let data = match answer {
+                    ConfigAnswer::Data(data) => data,
+                    ConfigAnswer::Refetch(total_len) => {
+                        self.request(
+                            SetupPacket::get_descriptor(desc_type::CONFIGURATION, index, total_len),
+                            EnumStage::ConfigRetry,
+                        )?;
+                        return Ok(false);
+                    }
+                };
+                let config_data = trim_config_data(data).ok_or(UsbError::InvalidDescriptor)?;
+
+                // Drivers read the configurations from the cache
+                let tree = ConfigTree::parse(config_data).ok_or(UsbError::InvalidDescriptor)?;
+                self.configs.push(device.cache_config(index, tree));
+                observer.event(self.port, EnumEvent::ConfigDescriptor { index, len });
+
+                let desc = device.device_desc.unwrap_or_default();
+                if self.configs.len() < desc.num_configurations as usize {
+                    self.request(
+                        SetupPacket::get_descriptor(desc_type::CONFIGURATION, index + 1, 9),
+                        EnumStage::ConfigHeader,
+                    )?;
+                    return Ok(false);
+                }
+
+                self.chosen = policy(&desc, &self.configs);
+                let tree = self.configs.get(self.chosen).ok_or(UsbError::InvalidArgument)?;
+                self.request(
+                    SetupPacket::set_configuration(tree.config.config_value),
+                    EnumStage::SetConfig,
+                )?;
+            }
+            EnumStage::SetConfig => {
+                let tree = &self.configs[self.chosen];
+                let value = tree.config.config_value;
+                device.config.store(value, Ordering::Release);
+                observer.event(self.port, EnumEvent::Configured { value });
+                if O::ENABLED {
+                    for kind in class_drivers(tree) {
+                        observer.event(self.port, EnumEvent::DriverBound(kind));
+                    }
+                }
+                return Ok(true);
+            }
+            EnumStage::Reset => {}
+        }
+
+        Ok(false)
+    }
+}
+
+impl<H: Dma> Drop for EnumPort<H> {
+    fn drop(&mut self) {
+        // Release the buffers of an abandoned in-flight transfer
+        if let (Some(device), Some(xfer)) = (&self.device, self.xfer.take()) {
+            let _ = device.abandon_control(xfer);
+        }
+    }
+}
+
+impl<H: Dma> XhciCtrl<H> {
+    /// Enumerate and configure every connected root port.
+    ///
+    /// Up to `max_in_flight` devices are brought up at once: their
+    /// descriptor fetches and SET_CONFIGURATION requests are interleaved
+    /// from a single polling loop. Port reset and addressing stay
+    /// serialized so only one device is in the Default state at a time.
+    /// Each device is configured as picked by `default_config_policy`.
+    ///
+    /// Only ports passing `debounced_ports` are enumerated. A port whose
+    /// connection changes across its reset fails with `DeviceNotFound`.
+    pub fn enumerate_concurrent(
+        self: &Arc<Self>,
+        max_in_flight: usize,
+    ) -> Vec<(u8, Result<EnumeratedDevice<H>>)> {
+        self.enumerate_concurrent_with(max_in_flight, default_config_policy)
+    }
+
+    /// Enumerate every connected root port, choosing configurations with `policy`
+    ///
+    /// Like `enumerate_concurrent`, but every configuration descriptor is
+    /// fetched and `policy` picks the one to select; see
+    /// `UsbDevice::choose_configuration`.
+    pub fn enumerate_concurrent_with(
+        self: &Arc<Self>,
+        max_in_flight: usize,
+        policy: impl Fn(&DeviceDesc, &[Arc<ConfigTree>]) -> usize,
+    ) -> Vec<(u8, Result<EnumeratedDevice<H>>)> {
+        self.enumerate_observed(max_in_flight, policy, &())
+    }
+
+    /// Enumerate every connected root port, reporting progress to `observer`
+    ///
+    /// Like `enumerate_concurrent_with`; every step of every port is
+    /// reported as an `EnumEvent`, including how long the port reset
+    /// took, the descriptors read and the step that failed.
+    pub fn enumerate_observed<O: EnumObserver>(
+        self: &Arc<Self>,
+        max_in_flight: usize,
+        policy: impl Fn(&DeviceDesc, &[Arc<ConfigTree>]) -> usize,
+        observer: &O,
+    ) -> Vec<(u8, Result<EnumeratedDevice<H>>)> {
+        let max_in_flight = max_in_flight.max(1);
+        let mut ports = self.debounced_ports().into_iter();
+        let mut active: Vec<EnumPort<H>> = Vec::new();
+        let mut results = Vec::new();
+
+        loop {
+            let resetting = active.iter().any(|p| p.stage == EnumStage::Reset);
+            if !resetting
+                && active.len() < max_in_flight
+                && let Some(port) = ports.next()
+            {
+                active.push(EnumPort::start(self, port));
+            }
+
+            if active.is_empty() {
+                break;
+            }
+
+            let mut i = 0;
+            while i < active.len() {
+                if let Some(result) = active[i].step(self, &policy, observer) {
+                    let done = active.swap_remove(i);
+                    results.push((done.port, result));
+                } else {
+                    i += 1;
+                }
+            }
+
+            spin_loop();
+        }
+
+        results
+    }
+}
+
+impl<H: Dma> Drop for UsbDevice<H> {
+    fn drop(&mut self) {
+        // Already done if the controller released the device
+        self.health.disable_slot(&self.ctrl);
+
+        let host = self.ctrl.host();
+
+        // Free endpoint rings
+        self.free_ep_rings();
+
+        // Free EP0 ring in place; a placeholder ring could fail to allocate.
+        // Ring has no Drop, so the stale copy left behind is harmless.
+        unsafe { core::ptr::read(self.ep0_ring.get_mut()) }.free(host);
+
+        if self.health.slot_stuck.load(Ordering::Acquire) {
+            return;
+        }
+        unsafe {
+            host.free(
+                self.input_ctx.virt(),
+                self.input_ctx.size(),
+                self.input_ctx.align(),
+            );
+        }
+        // Endpoint handles may still read the device context
+        if Arc::get_mut(&mut self.device_ctx).is_some() {
+            unsafe {
+                host.free(
+                    self.device_ctx.virt(),
+                    self.device_ctx.size(),
+                    self.device_ctx.align(),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        desc::request,
+        mock::{self, MockHost, Reply, Request},
+        reg,
+    };
+
+    /// An addressed keyboard on root port 0
+    fn addressed(device: mock::MockDevice) -> (mock::Mock, UsbDevice<MockHost>) {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, device);
+        let dev = UsbDevice::new(ctrl, 0).expect("address device");
+        (mock, dev)
+    }
+
+    #[test]
+    fn controller_removed_mid_transfer() {
+        let (mock, dev) = addressed(mock::keyboard().with_latency(10_000));
+        let ctrl = dev.ctrl().clone();
+        let start = mock.now_us();
+        mock.remove_at(start + 2_000);
+
+        // The transfer in flight gives up without waiting for its timeout
+        let setup = SetupPacket::get_descriptor(desc_type::DEVICE, 0, 18);
+        let mut buf = [0u8; 18];
+        let result = dev.control_transfer(&setup, Some(&mut buf));
+        assert!(
+            matches!(result, Err(UsbError::ControllerGone)),
+            "{result:?}"
+        );
+        assert!(ctrl.is_dead());
+        assert!(mock.now_us() < start + 10_000);
+
+        // So does everything after it
+        let result = dev.control_transfer(&setup, Some(&mut buf));
+        assert!(
+            matches!(result, Err(UsbError::ControllerGone)),
+            "{result:?}"
+        );
+        assert!(matches!(
+            dev.set_configuration(1),
+            Err(UsbError::ControllerGone)
+        ));
+        assert!(matches!(ctrl.enable_slot(), Err(UsbError::ControllerGone)));
+        assert!(matches!(ctrl.reset_port(1), Err(UsbError::ControllerGone)));
+        assert!(!ctrl.port_connected(0));
+
+        // Tearing down leaves the registers alone
+        let writes = mock.register_writes().len();
+        drop(dev);
+        drop(ctrl);
+        assert_eq!(mock.register_writes().len(), writes);
+    }
+
+    #[test]
+    fn abandoned_enumeration_recovers_ep0_before_freeing() {
+        let (ctrl, mock) = mock::controller();
+        let nak_config = |r: &Request<'_>| match r {
+            Request::Control { setup, .. }
+                if setup.value >> 8 == desc_type::CONFIGURATION as u16 =>
+            {
+                Some(Reply::Nak)
+            }
+            _ => None,
+        };
+        mock.attach(0, mock::keyboard().with_handler(nak_config));
+
+        let results = ctrl.enumerate_concurrent(1);
+        assert!(matches!(results[..], [(0, Err(UsbError::Timeout))]));
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn control_transfers_survive_ep0_ring_wraps() {
+        let (mock, dev) = addressed(mock::keyboard());
+        let mut stalled = 0;
+        for i in 0..10_000u32 {
+            // Every tenth request stalls and recovers EP0 mid-ring
+            let (dtype, index) = if i % 10 == 9 {
+                (desc_type::STRING, 7)
+            } else {
+                (desc_type::DEVICE, 0)
+            };
+            let setup = SetupPacket::get_descriptor(dtype, index, 18);
+            let mut buf = [0u8; 18];
+            match dev.control_transfer(&setup, Some(&mut buf)) {
+                Ok(len) => assert_eq!((len, buf[1]), (18, desc_type::DEVICE), "transfer {i}"),
+                Err(UsbError::Stall) => stalled += 1,
+                Err(e) => panic!("transfer {i}: {e:?}"),
+            }
+        }
+        assert_eq!(stalled, 1000);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn stall_recovers_ep0() {
+        let (mock, mut dev) = addressed(mock::keyboard());
+        let setup = SetupPacket::get_descriptor(desc_type::STRING, 7, 4);
+        let mut buf = [0u8; 4];
+        let result = dev.control_transfer(&setup, Some(&mut buf));
+        assert!(matches!(result, Err(UsbError::Stall)), "{result:?}");
+
+        // A halted EP0 would time out here
+        let desc = dev.get_device_descriptor().unwrap();
+        assert_eq!({ desc.product_id }, 0xc31c);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn timed_out_transfer_leaks_buffer_if_ep0_cannot_recover() {
+        let nak_strings = |r: &Request<'_>| match r {
+            Request::Control { setup, .. } if setup.value >> 8 == desc_type::STRING as u16 => {
+                Some(Reply::Nak)
+            }
+            _ => None,
+        };
+        let (mock, dev) = addressed(mock::keyboard().with_handler(nak_strings));
+        dev.set_retry_policy(RetryPolicy::NONE);
+        mock.fail_command(trb_type::STOP_ENDPOINT, completion::TRB_ERROR);
+
+        let setup = SetupPacket::get_descriptor(desc_type::STRING, 1, 4);
+        let mut buf = [0u8; 4];
+        let result = dev.control_transfer(&setup, Some(&mut buf));
+        assert!(matches!(result, Err(UsbError::Timeout)), "{result:?}");
+        // Freeing the buffer would have been caught here
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn flapping_ports_are_not_enumerated() {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, mock::keyboard());
+        mock.attach(1, mock::keyboard());
+        let start = mock.now_us();
+        mock.flap_port(1, &[start + 50_000, start + 50_100]);
+
+        let results = ctrl.enumerate_concurrent(2);
+        assert!(matches!(results[..], [(0, Ok(_))]));
+        let pr_writes = mock.register_writes().into_iter().filter(|&(off, val)| {
+            off == mock::CAP_LENGTH + 0x400 + 0x10 && val as u32 & reg::PORTSC_PR != 0
+        });
+        assert_eq!(pr_writes.count(), 0, "port 1 was reset");
+        let slots = mock
+            .commands()
+            .into_iter()
+            .filter(|&c| c == trb_type::ENABLE_SLOT);
+        assert_eq!(slots.count(), 1);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    /// Virtual time to enumerate `count` devices of `latency_us` each
+    fn enumerate_time(count: u8, latency_us: u32, max_in_flight: usize) -> u64 {
+        let (ctrl, mock) = mock::controller();
+        for port in 0..count {
+            mock.attach(port, mock::keyboard().with_latency(latency_us));
+        }
+        let start = mock.now_us();
+        let results = ctrl.enumerate_concurrent(max_in_flight);
+        // The attach debounce is the same whatever the concurrency
+        let elapsed = mock.now_us() - start - crate::xhci::CONNECT_DEBOUNCE_US as u64;
+
+        assert_eq!(results.len(), count as usize);
+        for (port, result) in results {
+            let dev = result.unwrap_or_else(|e| panic!("port {port}: {e:?}"));
+            assert_eq!(dev.device.port(), port);
+            assert_eq!(dev.configs.len(), 1);
+        }
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+        elapsed
+    }
+
+    #[test]
+    fn concurrent_enumeration_overlaps_device_latency() {
+        let sequential = enumerate_time(4, 2000, 1);
+        let concurrent = enumerate_time(4, 2000, 4);
+        assert!(
+            concurrent * 2 < sequential,
+            "concurrent {concurrent} us, sequential {sequential} us"
+        );
+    }
+
+    /// Number of configuration descriptor reads the device saw
+    fn config_reads(mock: &mock::Mock) -> usize {
+        mock.with_device(0, 0, |d| {
+            let is_config = |s: &&SetupPacket| {
+                s.request == request::GET_DESCRIPTOR
+                    && s.value >> 8 == desc_type::CONFIGURATION as u16
+            };
+            d.setups.iter().filter(is_config).count()
+        })
+    }
+
+    #[test]
+    fn set_configuration_clears_config_cache() {
+        let (mock, dev) = addressed(mock::keyboard());
+        dev.config_tree_at(0).unwrap();
+        dev.config_tree_at(0).unwrap();
+        let reads = config_reads(&mock);
+
+        dev.set_configuration(1).unwrap();
+        let tree = dev.config_tree().unwrap();
+        assert_eq!(tree.config.config_value, 1);
+        assert_eq!(config_reads(&mock), reads * 2);
+    }
+
+    #[test]
+    fn config_tree_for_reads_num_configurations() {
+        let configs = [
+            mock::config(1, &[mock::interface(0, 0, (0xff, 0, 0), 0)]),
+            mock::config(2, &[mock::interface(0, 0, (0xff, 1, 0), 0)]),
+        ];
+        let desc = mock::device_desc(0, 0x1234, 0x5678, 2);
+        let (_mock, dev) = addressed(mock::MockDevice::new(reg::SPEED_HIGH, desc, &configs));
+
+        // get_device_descriptor was never called
+        let tree = dev.config_tree_for(2).unwrap();
+        assert_eq!(tree.interfaces[0].settings[0].iface.interface_subclass, 1);
+    }
+
+    /// The keyboard's configured interrupt IN endpoint
+    fn keyboard_endpoint() -> (mock::Mock, UsbDevice<MockHost>, EndpointHandle<MockHost>) {
+        let (mock, dev) = addressed(mock::keyboard());
+        let tree = dev.choose_configuration(default_config_policy).unwrap();
+        let ep = dev
+            .configure_endpoint(&tree.interfaces[0].endpoints()[0])
+            .unwrap();
+        (mock, dev, ep)
+    }
+
+    #[test]
+    fn pending_counts_events_taken_by_the_controller() {
+        let (mock, dev, ep) = keyboard_endpoint();
+        let buf = dev.ctrl().alloc_mem(8, 64).unwrap();
+        mock.with_device(0, 0, |d| d.push_input(0x81, Reply::Data(alloc::vec![0; 8])));
+        ep.queue(&buf, 8).unwrap();
+        assert_eq!(ep.pending(), 1);
+
+        let evt = dev.ctrl().wait_event_where(|e| ep.matches(e), 100_000);
+        assert!(evt.is_ok_and(|e| completion::is_success(e.completion_code())));
+        assert_eq!(ep.pending(), 0);
+        buf.free(dev.ctrl().host());
+    }
+
+    #[test]
+    fn service_and_ring_errors_are_typed_and_counted() {
+        let (mock, dev, ep) = keyboard_endpoint();
+        let buf = dev.ctrl().alloc_mem(8, 64).unwrap();
+        let faults = [
+            (completion::MISSED_SERVICE, "MissedService"),
+            (completion::RING_OVERRUN, "RingOverrun"),
+            (completion::RING_UNDERRUN, "RingUnderrun"),
+        ];
+        for (code, error) in faults {
+            mock.with_device(0, 0, |d| d.push_input(0x81, Reply::Fail(code)));
+            ep.queue(&buf, 8).unwrap();
+            let err = ep.wait(100_000).err();
+            assert_eq!(alloc::format!("{err:?}"), alloc::format!("Some({error})"));
+            // None of them halts the endpoint
+            assert_eq!(mock.endpoint_state(dev.slot_id(), 3), mock::EP_RUNNING);
+        }
+        let expected = EndpointStats {
+            missed_service: 1,
+            ring_overrun: 1,
+            ring_underrun: 1,
+            ..EndpointStats::default()
+        };
+        assert_eq!(ep.stats(), expected);
+
+        // A restart stops the endpoint and moves past what is left
+        ep.queue(&buf, 8).unwrap();
+        let commands = mock.commands().len();
+        ep.restart().unwrap();
+        assert_eq!(
+            mock.commands()[commands..],
+            [trb_type::STOP_ENDPOINT, trb_type::SET_TR_DEQUEUE]
+        );
+        assert_eq!(ep.pending(), 0);
+        // The dropped transfer ends with its Stopped event
+        assert!(matches!(ep.wait(100_000), Err(UsbError::Cancelled)));
+        mock.with_device(0, 0, |d| d.push_input(0x81, Reply::Data(alloc::vec![2; 8])));
+        ep.queue(&buf, 8).unwrap();
+        assert!(ep.wait(100_000).is_ok());
+        // Restarting is not an error of its own
+        assert_eq!(ep.stats(), expected);
+        buf.free(dev.ctrl().host());
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn cancel_all_keeps_completions() {
+        let (mock, dev, ep) = keyboard_endpoint();
+        let bufs = [(); 2].map(|_| dev.ctrl().alloc_mem(8, 64).unwrap());
+        mock.with_device(0, 0, |d| d.push_input(0x81, Reply::Data(alloc::vec![1; 8])));
+        // The first transfer completes, the second one NAKs until cancelled
+        ep.queue(&bufs[0], 8).unwrap();
+        ep.queue(&bufs[1], 8).unwrap();
+        mock.advance_us(1000);
+
+        assert_eq!(ep.cancel_all().unwrap(), 2);
+        assert_eq!(ep.pending(), 0);
+        let evt = ep.poll().expect("completion kept");
+        assert!(completion::is_success(evt.completion_code()));
+        assert!(ep.poll().is_none());
+        for buf in bufs {
+            buf.free(dev.ctrl().host());
+        }
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn failed_set_interface_restores_endpoints() {
+        let configs = [mock::config(
+            1,
+            &[
+                mock::interface(0, 0, (0xff, 0, 0), 0),
+                mock::interface(0, 1, (0xff, 0, 0), 1),
+                mock::endpoint(0x81, 0x03, 64, 4),
+                mock::interface(0, 2, (0xff, 0, 0), 2),
+                mock::endpoint(0x81, 0x03, 512, 4),
+                mock::endpoint(0x02, 0x03, 512, 4),
+            ],
+        )];
+        let stall_alt_2 = |r: &Request<'_>| match r {
+            Request::Control { setup, .. }
+                if setup.request == request::SET_INTERFACE && setup.value == 2 =>
+            {
+                Some(Reply::Stall)
+            }
+            _ => None,
+        };
+        let desc = mock::device_desc(0, 0x1234, 0x5678, 1);
+        let device = mock::MockDevice::new(reg::SPEED_HIGH, desc, &configs);
+        let (mock, dev) = addressed(device.with_handler(stall_alt_2));
+        dev.set_configuration(1).unwrap();
+        let result = dev.try_alt_settings(0, &[1]);
+        assert!(matches!(result, Ok((1, _))));
+
+        let result = dev.try_alt_settings(0, &[2]);
+        assert!(matches!(result, Err(UsbError::Stall)));
+        let ep = dev.endpoint(Dci::from_ep(1, true)).expect("alt 1 endpoint");
+        assert_eq!(ep.max_packet_size(), 64);
+        assert!(dev.endpoint(Dci::from_ep(2, false)).is_err());
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    /// Virtual times at which ports were addressed and configured
+    struct Timeline {
+        mock: mock::Mock,
+        events: std::sync::Mutex<Vec<(u8, &'static str, u64)>>,
+    }
+
+    impl Timeline {
+        fn at(&self, port: u8, name: &str) -> u64 {
+            let events = self.events.lock().unwrap();
+            let found = events.iter().find(|e| e.0 == port && e.1 == name);
+            found.expect("event reported").2
+        }
+    }
+
+    impl EnumObserver for Timeline {
+        fn event(&self, port: u8, ev: EnumEvent) {
+            let name = match ev {
+                EnumEvent::Addressed => "addressed",
+                EnumEvent::Configured { .. } => "configured",
+                _ => return,
+            };
+            let now = self.mock.now_us();
+            self.events.lock().unwrap().push((port, name, now));
+        }
+    }
+
+    /// Fails the first `count` device descriptor reads with `reply`
+    fn failing_device_desc(
+        mut count: u32,
+        reply: Reply,
+    ) -> impl FnMut(&Request<'_>) -> Option<Reply> + Send + 'static {
+        move |r| match r {
+            Request::Control { setup, .. }
+                if setup.value >> 8 == desc_type::DEVICE as u16 && count > 0 =>
+            {
+                count -= 1;
+                Some(reply.clone())
+            }
+            _ => None,
+        }
+    }
+
+    /// Every enumeration event, in the order reported
+    #[derive(Default)]
+    struct Recorder(std::sync::Mutex<Vec<(u8, EnumEvent)>>);
+
+    impl EnumObserver for Recorder {
+        fn event(&self, port: u8, ev: EnumEvent) {
+            self.0.lock().unwrap().push((port, ev));
+        }
+    }
+
+    #[test]
+    fn observer_sees_every_step_of_an_enumeration() {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, mock::keyboard());
+        let recorder = Recorder::default();
+
+        let results = ctrl.enumerate_observed(1, default_config_policy, &recorder);
+        assert!(matches!(results[..], [(0, Ok(_))]));
+        let events = recorder.0.into_inner().unwrap();
+        assert!(events.iter().all(|(port, _)| *port == 0));
+        let events: Vec<_> = events.into_iter().map(|(_, ev)| ev).collect();
+        assert!(
+            matches!(
+                events[..],
+                [
+                    EnumEvent::PortReset { .. },
+                    EnumEvent::SlotEnabled { slot_id: 1 },
+                    EnumEvent::Addressed,
+                    EnumEvent::DeviceDescriptor(desc),
+                    EnumEvent::ConfigDescriptor { index: 0, len },
+                    EnumEvent::Configured { value: 1 },
+                    EnumEvent::DriverBound(DriverKind::Hid),
+                ] if desc.vendor_id == 0x046d && len == results[0].1.as_ref().unwrap().config.len()
+            ),
+            "{events:?}"
+        );
+    }
+
+    #[test]
+    fn observer_sees_a_failed_address_device() {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, mock::keyboard());
+        mock.fail_command(trb_type::ADDRESS_DEVICE, completion::USB_TRANSACTION_ERROR);
+        let recorder = Recorder::default();
+
+        let results = ctrl.enumerate_observed(1, default_config_policy, &recorder);
+        const FAILED: u8 = completion::USB_TRANSACTION_ERROR;
+        assert!(matches!(results[..], [(0, Err(UsbError::CmdFail(FAILED)))]));
+        let events = recorder.0.into_inner().unwrap();
+        assert!(
+            matches!(
+                events[..],
+                [
+                    (0, EnumEvent::PortReset { .. }),
+                    (0, EnumEvent::SlotEnabled { slot_id: 1 }),
+                    (
+                        0,
+                        EnumEvent::Failed(EnumStep::Address, UsbError::CmdFail(FAILED))
+                    ),
+                ]
+            ),
+            "{events:?}"
+        );
+    }
+
+    #[test]
+    fn enumeration_backoff_does_not_block_other_ports() {
+        let (ctrl, mock) = mock::controller();
+        let flaky = failing_device_desc(2, Reply::Fail(completion::USB_TRANSACTION_ERROR));
+        mock.attach(0, mock::keyboard().with_handler(flaky));
+        mock.attach(1, mock::keyboard());
+        let timeline = Timeline {
+            mock: mock.clone(),
+            events: Default::default(),
+        };
+
+        let results = ctrl.enumerate_observed(2, default_config_policy, &timeline);
+        let retried = results.iter().find(|(port, _)| *port == 0).unwrap();
+        let retried = retried.1.as_ref().expect("port 0 enumerated");
+        assert_eq!(retried.device.control_retries(), 2);
+        assert!(results.iter().all(|(_, result)| result.is_ok()));
+
+        // Port 0 backs off for 1 + 2 ms; port 1 is not held up meanwhile
+        let backoffs = 3 * RetryPolicy::default().backoff_us as u64;
+        assert!(timeline.at(0, "configured") - timeline.at(0, "addressed") >= backoffs);
+        assert!(timeline.at(1, "configured") - timeline.at(1, "addressed") < backoffs);
+        assert!(timeline.at(1, "configured") < timeline.at(0, "configured"));
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn enumeration_retries_stop_at_stall() {
+        let (ctrl, mock) = mock::controller();
+        let stall = failing_device_desc(1, Reply::Stall);
+        mock.attach(0, mock::keyboard().with_handler(stall));
+
+        let results = ctrl.enumerate_concurrent(1);
+        assert!(matches!(results[..], [(0, Err(UsbError::Stall))]));
+        let reads = mock.with_device(0, 0, |d| {
+            let is_device = |s: &&SetupPacket| s.value >> 8 == desc_type::DEVICE as u16;
+            d.setups.iter().filter(is_device).count()
+        });
+        assert_eq!(reads, 1);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn control_transfer_retries_stop_at_stall() {
+        let stall = failing_device_desc(1, Reply::Stall);
+        let (_mock, dev) = addressed(mock::keyboard().with_handler(stall));
+        let setup = SetupPacket::get_descriptor(desc_type::DEVICE, 0, 18);
+        let mut buf = [0u8; 18];
+        let result = dev.control_transfer(&setup, Some(&mut buf));
+        assert!(matches!(result, Err(UsbError::Stall)));
+        assert_eq!(dev.control_retries(), 0);
+
+        let flaky = failing_device_desc(1, Reply::Fail(completion::USB_TRANSACTION_ERROR));
+        let (_mock, dev) = addressed(mock::keyboard().with_handler(flaky));
+        assert_eq!(dev.control_transfer(&setup, Some(&mut buf)).ok(), Some(18));
+        assert_eq!(dev.control_retries(), 1);
+    }
+
+    #[test]
+    fn normal_tds_add_zero_length_td_for_full_packets() {
+        const IOC: u32 = 1 << 5;
+        let is_normal = |trb: &Trb| trb.trb_type() == trb_type::NORMAL as u8;
+
+        // Zero-length transfer: one TD, no ZLP
+        let (trbs, count) = normal_tds(0x1000, 0, 512, true);
+        assert_eq!(count, 1);
+        assert!(is_normal(&trbs[0]));
+        assert_eq!((trbs[0].param, trbs[0].status), (0x1000, 0));
+        assert_ne!(trbs[0].control & IOC, 0);
+
+        // A multiple of the max packet size ends with a ZLP TD
+        let (trbs, count) = normal_tds(0x2000, 1024, 512, true);
+        assert_eq!(count, 2);
+        assert_eq!((trbs[0].param, trbs[0].status), (0x2000, 1024));
+        assert_eq!(trbs[0].control & IOC, 0);
+        assert!(is_normal(&trbs[1]));
+        assert_eq!((trbs[1].param, trbs[1].status), (0, 0));
+        assert_ne!(trbs[1].control & IOC, 0);
+
+        // A short last packet ends the transfer on its own
+        let (trbs, count) = normal_tds(0x3000, 1000, 512, true);
+        assert_eq!(count, 1);
+        assert_eq!(trbs[0].status, 1000);
+        assert_ne!(trbs[0].control & IOC, 0);
+
+        // Without the flag no ZLP is added
+        let (trbs, count) = normal_tds(0x4000, 1024, 512, false);
+        assert_eq!(count, 1);
+        assert_eq!(trbs[0].status, 1024);
+        assert_ne!(trbs[0].control & IOC, 0);
+    }
+
+    #[test]
+    fn isoch_td_burst_counts() {
+        // (TBC, TLBPC, frame, SIA)
+        let fields = |trb: Trb| {
+            let control = trb.control;
+            let tbc = control >> 7 & 0x3;
+            let tlbpc = control >> 16 & 0xf;
+            (tbc, tlbpc, control >> 20 & 0x7ff, control >> 31)
+        };
+        // Three high-bandwidth packets without bursts
+        assert_eq!(fields(isoch_td(0, 3072, 1024, 0, None)), (2, 0, 0, 1));
+        // Seven packets in bursts of four: a full burst, then three
+        assert_eq!(fields(isoch_td(0, 7168, 1024, 3, None)), (1, 2, 0, 1));
+        // Five packets: the last burst holds one
+        assert_eq!(fields(isoch_td(0, 5000, 1024, 3, None)), (1, 0, 0, 1));
+        // A zero-length TD still moves one packet
+        assert_eq!(fields(isoch_td(0, 0, 1024, 3, None)), (0, 0, 0, 1));
+        // A start frame replaces SIA and keeps 11 bits
+        assert_eq!(fields(isoch_td(0, 512, 1024, 0, Some(2049))), (0, 0, 1, 0));
+
+        let trb = isoch_td(0x5000, 512, 1024, 0, None);
+        assert_eq!((trb.param, trb.status), (0x5000, 512));
+        assert_eq!(trb.trb_type(), trb_type::ISOCH as u8);
+    }
+
+    /// Largest configuration descriptor read the device saw
+    fn largest_config_read(mock: &mock::Mock) -> u16 {
+        mock.with_device(0, 0, |d| {
+            let is_config = |s: &&SetupPacket| s.value >> 8 == desc_type::CONFIGURATION as u16;
+            let lengths = d.setups.iter().filter(is_config).map(|s| s.length);
+            lengths.max().unwrap_or(0)
+        })
+    }
+
+    #[test]
+    fn oversized_total_length_is_refused_before_reading() {
+        let iface = mock::interface(0, 0, (0xff, 0, 0), 0);
+        let mut config = mock::config(1, &[iface]);
+        config[2..4].copy_from_slice(&0xffffu16.to_le_bytes());
+        let desc = mock::device_desc(0, 0x1234, 0x5678, 1);
+        let device = mock::MockDevice::new(reg::SPEED_HIGH, desc, &[config]);
+        let (mock, dev) = addressed(device);
+
+        let result = dev.config_tree_at(0);
+        assert!(matches!(result, Err(UsbError::InvalidDescriptor)));
+        assert_eq!(largest_config_read(&mock), 9);
+    }
+
+    #[test]
+    fn max_descriptor_len_is_configurable() {
+        let (mock, dev) = addressed(mock::keyboard());
+        let total_len = dev.config_tree_at(0).unwrap().raw().len();
+        dev.configs.lock().clear();
+
+        dev.ctrl().set_max_descriptor_len(total_len - 1);
+        let result = dev.config_tree_at(0);
+        assert!(matches!(result, Err(UsbError::InvalidDescriptor)));
+
+        dev.ctrl().set_max_descriptor_len(total_len);
+        assert!(dev.config_tree_at(0).is_ok());
+        assert_eq!(largest_config_read(&mock) as usize, total_len);
+    }
+
+    #[test]
+    fn enumeration_refuses_oversized_total_length() {
+        let (ctrl, mock) = mock::controller();
+        let mut config = mock::config(1, &[mock::interface(0, 0, (0xff, 0, 0), 0)]);
+        config[2..4].copy_from_slice(&0x8000u16.to_le_bytes());
+        let desc = mock::device_desc(0, 0x1234, 0x5678, 1);
+        mock.attach(0, mock::MockDevice::new(reg::SPEED_HIGH, desc, &[config]));
+
+        let results = ctrl.enumerate_concurrent(1);
+        let refused = matches!(results[..], [(0, Err(UsbError::InvalidDescriptor))]);
+        assert!(refused);
+        assert_eq!(largest_config_read(&mock), 9);
+    }
+
+    /// A keyboard with a card reader, configured on root port 0
+    #[cfg(not(feature = "single-threaded"))]
+    fn composite() -> (mock::Mock, Arc<UsbDevice<MockHost>>) {
+        let (mock, dev) = addressed(mock::keyboard_with_card_reader());
+        dev.choose_configuration(default_config_policy).unwrap();
+        (mock, Arc::new(dev))
+    }
+
+    // Locks are not Sync with `single-threaded`
+    #[cfg(not(feature = "single-threaded"))]
+    #[test]
+    fn class_drivers_share_a_composite_device() {
+        use crate::{hid::HidDevice, msc::MscDevice};
+
+        let (mock, dev) = composite();
+        let tree = dev.config_tree_at(0).unwrap();
+        let (kbd_iface, kbd_ep) = find_hid_interfaces(tree.raw())[0];
+        let (msc_iface, msc_in, msc_out) = find_msc_interfaces(tree.raw())[0];
+
+        // Both drivers configure their endpoints at the same time
+        let (hid, msc) = std::thread::scope(|s| {
+            let hid = s.spawn(|| HidDevice::from_interface(dev.clone(), &kbd_iface, &kbd_ep));
+            let msc =
+                s.spawn(|| MscDevice::from_interface(dev.clone(), &msc_iface, &msc_in, &msc_out));
+            (hid.join().unwrap(), msc.join().unwrap())
+        });
+        let (hid, msc) = (hid.unwrap(), msc.unwrap());
+
+        let slot = dev.slot_id();
+        for ep in [kbd_ep, msc_in, msc_out] {
+            let dci = Dci::from_desc(&ep).0;
+            assert_eq!(
+                mock.endpoint_state(slot, dci),
+                mock::EP_RUNNING,
+                "endpoint {:#04x}",
+                ep.endpoint_address
+            );
+        }
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+
+        // Dropping one function leaves the other's endpoints alone
+        drop(hid);
+        let dci = Dci::from_desc(&msc_in).0;
+        assert_eq!(mock.endpoint_state(slot, dci), mock::EP_RUNNING);
+        drop(msc);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    // Locks are not Sync with `single-threaded`
+    #[cfg(not(feature = "single-threaded"))]
+    #[test]
+    fn composite_functions_transfer_at_the_same_time() {
+        use crate::{hid::HidDevice, msc::MscDevice};
+
+        const ROUNDS: u8 = 64;
+        let (mock, dev) = composite();
+        let tree = dev.config_tree_at(0).unwrap();
+        let (kbd_iface, kbd_ep) = find_hid_interfaces(tree.raw())[0];
+        let (msc_iface, msc_in, msc_out) = find_msc_interfaces(tree.raw())[0];
+        let hid = HidDevice::from_interface(dev.clone(), &kbd_iface, &kbd_ep).unwrap();
+        let mut msc = MscDevice::from_interface(dev, &msc_iface, &msc_in, &msc_out).unwrap();
+        hid.queue_read().unwrap();
+
+        std::thread::scope(|s| {
+            let keys = s.spawn(|| {
+                for round in 0..ROUNDS {
+                    let key = 0x04 + round % 26;
+                    let report = alloc::vec![0, 0, key, 0, 0, 0, 0, 0];
+                    mock.with_device(0, 0, |d| d.push_input(0x81, Reply::Data(report)));
+                    let state = (0..10_000)
+                        .find_map(|_| {
+                            mock.advance_us(125);
+                            hid.poll_keys()
+                        })
+                        .expect("report");
+                    assert!(state.keys().eq([key]), "round {round}");
+                }
+            });
+            let blocks = s.spawn(move || {
+                let mut block = [0; 512];
+                for lba in 0..ROUNDS as u32 {
+                    assert_eq!(msc.read_blocks(0, lba, 1, &mut block).unwrap(), 512);
+                    assert!(block.iter().all(|&b| b == lba as u8), "block {lba}");
+                }
+            });
+            keys.join().unwrap();
+            blocks.join().unwrap();
+        });
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn link_path_adds_hub_exit_latencies() {
+        let us = |u1: u32, u2: u32| ExitLatency {
+            u1: u1 * 1000,
+            u2: u2 * 1000,
+        };
+        // The slower end of the root port link decides its exit latency
+        let root = LinkPath::new(us(2, 60), us(1, 80), None);
+        assert_eq!(root.pel, us(2, 80));
+        let sel = root.sel_data(0);
+        assert_eq!(
+            (sel.u1_sel, sel.u1_pel, sel.u2_sel, sel.u2_pel),
+            (2, 2, 80, 80)
+        );
+
+        // A hub forwards the wake 1 us later, U2 by its U2/U1 difference more
+        let hub = LinkPath::new(us(1, 50), us(0, 0), None);
+        let below = LinkPath::new(us(2, 60), hub.exit, Some(&hub));
+        assert_eq!(below.pel, us(2, 100));
+        let sel = below.sel_data(1);
+        assert_eq!(
+            (sel.u1_sel, sel.u1_pel, sel.u2_sel, sel.u2_pel),
+            (5, 2, 103, 100)
+        );
+
+        // Every further hub tier adds to both
+        let deeper = LinkPath::new(us(2, 60), hub.exit, Some(&below));
+        assert_eq!(deeper.pel, us(3, 150));
+        let sel = deeper.sel_data(2);
+        assert_eq!(
+            (sel.u1_sel, sel.u1_pel, sel.u2_sel, sel.u2_pel),
+            (6, 3, 153, 150)
+        );
+
+        // Latencies beyond the SET_SEL fields are clamped
+        let slow = LinkPath::new(us(255, 65535), us(0, 0), Some(&below)).sel_data(5);
+        assert_eq!((slow.u1_sel, slow.u2_sel), (0xff, 0xffff));
+    }
+
+    // Locks are not Sync with `single-threaded`
+    #[cfg(not(feature = "single-threaded"))]
+    #[test]
+    fn concurrent_endpoint_use_does_not_deadlock() {
+        const ROUNDS: usize = 1000;
+        let (mock, dev) = composite();
+        let tree = dev.config_tree_at(0).unwrap();
+        let (_, kbd_ep) = find_hid_interfaces(tree.raw())[0];
+        let (_, msc_in, msc_out) = find_msc_interfaces(tree.raw())[0];
+        let [bulk_in, bulk_out] = dev
+            .configure_endpoints(&[msc_in, msc_out])
+            .unwrap()
+            .try_into()
+            .ok()
+            .unwrap();
+        for _ in 0..ROUNDS {
+            mock.with_device(0, 0, |d| {
+                d.push_input(0x82, Reply::Data(alloc::vec![0; 512]))
+            });
+        }
+
+        // Bulk transfers both ways, control transfers and reconfiguring
+        // another endpoint, all at once on one device
+        let transfer = |ep: &EndpointHandle<MockHost>| {
+            let buf = dev.ctrl().alloc_mem(512, 64).unwrap();
+            for _ in 0..ROUNDS {
+                ep.queue(&buf, 512).unwrap();
+                let evt = ep.wait(1_000_000).unwrap();
+                assert!(completion::is_success(evt.completion_code()));
+            }
+            buf.free(dev.ctrl().host());
+        };
+        std::thread::scope(|s| {
+            let workers = [
+                s.spawn(|| transfer(&bulk_in)),
+                s.spawn(|| transfer(&bulk_out)),
+                s.spawn(|| {
+                    for _ in 0..ROUNDS {
+                        let mut status = [0; 2];
+                        let setup = SetupPacket::get_device_status();
+                        dev.control_transfer(&setup, Some(&mut status)).unwrap();
+                    }
+                }),
+                s.spawn(|| {
+                    for _ in 0..ROUNDS / 10 {
+                        dev.configure_endpoint(&kbd_ep).unwrap();
+                    }
+                }),
+            ];
+            let deadline = std::time::Instant::now() + std::time::Duration::from_secs(30);
+            while workers.iter().any(|w| !w.is_finished()) {
+                assert!(std::time::Instant::now() < deadline, "deadlocked");
+                std::thread::yield_now();
+            }
+            for worker in workers {
+                worker.join().unwrap();
+            }
+        });
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    // Locks are not Sync with `single-threaded`
+    #[cfg(not(feature = "single-threaded"))]
+    #[test]
+    fn concurrent_control_transfers_keep_their_stages_together() {
+        use crate::ring::trb_type;
+
+        const ROUNDS: u16 = 1000;
+        // Each GET_STATUS answers with the next number, so a caller that
+        // got another transfer's completion would see a gap
+        let mut served = 0u16;
+        let numbered_status = move |r: &Request<'_>| match r {
+            Request::Control { setup, .. } if setup.request == request::GET_STATUS => {
+                served += 1;
+                Some(Reply::Data(served.to_le_bytes().to_vec()))
+            }
+            _ => None,
+        };
+        let (mock, dev) = addressed(
+            mock::keyboard()
+                .with_latency(250)
+                .with_handler(numbered_status),
+        );
+        let expected = mock.with_device(0, 0, |d| d.descriptors[0].1.clone());
+        let before = mock.tds(dev.slot_id(), 1).len();
+
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for round in 1..=ROUNDS {
+                    let mut status = [0; 2];
+                    let setup = SetupPacket::get_device_status();
+                    let len = dev.control_transfer(&setup, Some(&mut status)).unwrap();
+                    assert_eq!((len, u16::from_le_bytes(status)), (2, round));
+                }
+            });
+            s.spawn(|| {
+                for _ in 0..ROUNDS {
+                    let mut desc = [0; 18];
+                    let setup = SetupPacket::get_descriptor(desc_type::DEVICE, 0, 18);
+                    let len = dev.control_transfer(&setup, Some(&mut desc)).unwrap();
+                    assert_eq!((len, &desc[..]), (18, &expected[..]));
+                }
+            });
+        });
+
+        // Every TD on the EP0 ring is one whole transfer
+        let tds = mock.tds(dev.slot_id(), 1).split_off(before);
+        assert_eq!(tds.len(), 2 * ROUNDS as usize);
+        let whole = [trb_type::SETUP, trb_type::DATA, trb_type::STATUS];
+        for td in &tds {
+            assert_eq!(td[..], whole);
+        }
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn ep0_max_packet_fixup_evaluates_only_ep0() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        // A full-speed device with a 64-byte EP0, addressed with the 8
+        // bytes guessed from its speed: the first descriptor read ends
+        // after one packet
+        let first = AtomicBool::new(true);
+        let desc = mock::device_desc(0, 0x0403, 0x6001, 1).to_bytes();
+        let device = mock::MockDevice::new(
+            reg::SPEED_FULL,
+            mock::device_desc(0, 0x0403, 0x6001, 1),
+            &[mock::config(1, &[])],
+        )
+        .with_handler(move |r| match r {
+            Request::Control { setup, .. }
+                if setup.value == (desc_type::DEVICE as u16) << 8
+                    && first.swap(false, Ordering::Relaxed) =>
+            {
+                Some(Reply::Data(desc[..8].to_vec()))
+            }
+            _ => None,
+        });
+        let (mock, mut dev) = addressed(device);
+        assert!(mock.evaluated().is_empty());
+        let desc = dev.get_device_descriptor().unwrap();
+        assert_eq!(desc.max_packet_size0, 64);
+
+        let evaluated = mock.evaluated();
+        assert_eq!(evaluated.len(), 1);
+        let (slot_id, input) = evaluated[0];
+        assert_eq!(slot_id, dev.slot_id());
+        // Input Control Context: nothing dropped, only A1 (EP0) added
+        assert_eq!((input[0], input[1]), (0, 1 << 1));
+        // EP0 context dword 1: Max Packet Size in the upper half
+        assert_eq!(input[16 + 1] >> 16, 64);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn freed_ring_and_context_are_detected() {
+        let (mock, dev) = addressed(mock::keyboard());
+        let host = dev.ctrl().host();
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+
+        // Free the EP0 ring behind the driver's back: the next control
+        // transfer is read by the controller from freed memory
+        let ring = dev.ep0_ring.lock().phys(host) as usize;
+        let size = dev.ep0_trbs * core::mem::size_of::<Trb>();
+        unsafe { host.free(ring, size, core::mem::align_of::<Trb>()) };
+        let mut buf = [0; 18];
+        let setup = SetupPacket::get_descriptor(desc_type::DEVICE, 0, 18);
+        assert!(dev.control_transfer(&setup, Some(&mut buf)).is_err());
+        let violations = mock.violations();
+        let read = alloc::format!("transfer read of freed memory at {ring:#x}");
+        assert!(violations.contains(&read), "{violations:?}");
+
+        // Freeing the device context of an enabled slot is caught at once
+        let ctx = &dev.device_ctx;
+        unsafe { host.free(ctx.virt(), ctx.size(), ctx.align()) };
+        let used = alloc::format!(
+            "freed {:#x} still used by device context of slot {}",
+            ctx.virt(),
+            dev.slot_id()
+        );
+        assert!(mock.violations().contains(&used), "{:?}", mock.violations());
 
-        // Free EP0 ring
-        let ep0_ring = core::mem::replace(&mut *self.ep0_ring.lock(), Ring::new(host, 1).unwrap());
-        ep0_ring.free(host);
+        // Both regions were freed already
+        core::mem::forget(dev);
     }
 }
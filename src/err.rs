@@ -1,6 +1,7 @@
 //! USB error types.
 
-use core::result::Result as CoreResult;
+use crate::ring::completion;
+use core::{fmt, result::Result as CoreResult};
 
 /// USB driver error types.
 #[derive(Debug, Clone, Copy)]
@@ -29,7 +30,95 @@ pub enum UsbError {
     InvalidDescriptor,
     /// Endpoint stalled
     Stall,
+    /// Transfer ring has no room for another transfer
+    RingFull,
+    /// Isochronous or interrupt service interval missed
+    MissedService,
+    /// IN endpoint had no TRB to receive data into
+    RingOverrun,
+    /// OUT endpoint had no TRB to send data from
+    RingUnderrun,
+    /// Port over-current condition
+    OverCurrent,
+    /// Configuration draws more bus power than the port can supply
+    InsufficientPower,
+    /// Command would modify a device opened read-only
+    ReadOnly,
+    /// Argument out of the accepted range
+    InvalidArgument,
+    /// Device was disabled after repeated transfer errors
+    DeviceFailed,
+    /// Invalid logical unit number
+    InvLun,
+    /// Buffer length or offset is not a multiple of the logical block size
+    Misaligned,
+    /// Device was detached, or released by `XhciCtrl::release_all_devices`
+    Disconnected,
+    /// Device was put out of service by `UsbDevice::deactivate`
+    Inactive,
+    /// The controller was removed: its registers read back all ones
+    ControllerGone,
+    /// The event ring overflowed while waiting, so the awaited completion
+    /// may have been lost (see `XhciCtrl::events_lost`)
+    EventLost,
+    /// Transfer was cancelled by `UsbDevice::cancel_all`
+    Cancelled,
+    /// Controller lacks the bus bandwidth for a periodic endpoint
+    InsufficientBandwidth {
+        /// Endpoint address of the largest periodic endpoint requested
+        endpoint: u8,
+        /// Its Max ESIT Payload, in bytes per service interval
+        esit_payload: u32,
+    },
 }
 
+impl fmt::Display for UsbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::CmdFail(code) => {
+                write!(f, "command failed: {} ({code})", completion::name(code))
+            }
+            Self::XferFail(code) => {
+                write!(f, "transfer failed: {} ({code})", completion::name(code))
+            }
+            Self::Timeout => f.write_str("operation timed out"),
+            Self::OoRam => f.write_str("out of memory"),
+            Self::MapFail => f.write_str("failed to map MMIO region"),
+            Self::InvSlot => f.write_str("invalid slot ID"),
+            Self::InvPort => f.write_str("invalid port number"),
+            Self::InvEndpoint => f.write_str("invalid endpoint"),
+            Self::DeviceNotFound => f.write_str("device not found"),
+            Self::NotSupported => f.write_str("operation not supported"),
+            Self::InvalidDescriptor => f.write_str("invalid descriptor"),
+            Self::Stall => f.write_str("endpoint stalled"),
+            Self::RingFull => f.write_str("transfer ring full"),
+            Self::MissedService => f.write_str("service interval missed"),
+            Self::RingOverrun => f.write_str("ring overrun"),
+            Self::RingUnderrun => f.write_str("ring underrun"),
+            Self::OverCurrent => f.write_str("port over-current"),
+            Self::InsufficientPower => f.write_str("insufficient bus power"),
+            Self::ReadOnly => f.write_str("device is read-only"),
+            Self::InvalidArgument => f.write_str("invalid argument"),
+            Self::DeviceFailed => f.write_str("device failed"),
+            Self::InvLun => f.write_str("invalid logical unit number"),
+            Self::Misaligned => f.write_str("misaligned buffer length or offset"),
+            Self::Disconnected => f.write_str("device disconnected"),
+            Self::Inactive => f.write_str("device deactivated"),
+            Self::ControllerGone => f.write_str("controller removed"),
+            Self::EventLost => f.write_str("events lost to an event ring overflow"),
+            Self::Cancelled => f.write_str("transfer cancelled"),
+            Self::InsufficientBandwidth {
+                endpoint,
+                esit_payload,
+            } => write!(
+                f,
+                "insufficient bandwidth for endpoint {endpoint:#04x} ({esit_payload} bytes per interval)"
+            ),
+        }
+    }
+}
+
+impl core::error::Error for UsbError {}
+
 /// Result type for USB operations.
 pub type Result<T> = CoreResult<T, UsbError>;
@@ -0,0 +1,722 @@
+//! C ABI over the controller, mass storage read path and keyboards.
+//!
+//! Handles are opaque values looked up in a table, never dereferenced:
+//! closing one bumps its slot's generation, so a second close or any use
+//! after close fails with `USB_OXIDE_ERR_INVALID_HANDLE`. Each call clones
+//! the object out of the table, so closing a handle while another context
+//! uses it only drops the object once that call returns.
+//!
+//! With `single-threaded` the handle table still takes a spin lock, but
+//! the objects behind it do not: the host must make every call from one
+//! context, never from an interrupt handler while another call runs.
+//!
+//! `include/usb_oxide.h` is generated from this file and checked in;
+//! regenerate it after changing the ABI with
+//! `cbindgen --config cbindgen.toml --output include/usb_oxide.h src/ffi.rs`.
+#![allow(non_camel_case_types)]
+
+use crate::{
+    Dma, HidDevice, MscDevice, UsbDevice, UsbError, XhciCtrl, find_hid_interfaces,
+    find_msc_interfaces, hid_protocol,
+};
+use alloc::{sync::Arc, vec::Vec};
+use core::ffi::c_void;
+
+/// Success
+pub const USB_OXIDE_OK: i32 = 0;
+/// `UsbError::Timeout`
+pub const USB_OXIDE_ERR_TIMEOUT: i32 = -1;
+/// `UsbError::OoRam`
+pub const USB_OXIDE_ERR_OUT_OF_MEMORY: i32 = -2;
+/// `UsbError::MapFail`
+pub const USB_OXIDE_ERR_MAP_FAIL: i32 = -3;
+/// `UsbError::InvSlot`
+pub const USB_OXIDE_ERR_INVALID_SLOT: i32 = -4;
+/// `UsbError::InvPort`
+pub const USB_OXIDE_ERR_INVALID_PORT: i32 = -5;
+/// `UsbError::InvEndpoint`
+pub const USB_OXIDE_ERR_INVALID_ENDPOINT: i32 = -6;
+/// `UsbError::CmdFail`, whatever the completion code
+pub const USB_OXIDE_ERR_COMMAND_FAILED: i32 = -7;
+/// `UsbError::XferFail`, whatever the completion code
+pub const USB_OXIDE_ERR_TRANSFER_FAILED: i32 = -8;
+/// `UsbError::DeviceNotFound`
+pub const USB_OXIDE_ERR_DEVICE_NOT_FOUND: i32 = -9;
+/// `UsbError::NotSupported`
+pub const USB_OXIDE_ERR_NOT_SUPPORTED: i32 = -10;
+/// `UsbError::InvalidDescriptor`
+pub const USB_OXIDE_ERR_INVALID_DESCRIPTOR: i32 = -11;
+/// `UsbError::Stall`
+pub const USB_OXIDE_ERR_STALL: i32 = -12;
+/// `UsbError::RingFull`
+pub const USB_OXIDE_ERR_RING_FULL: i32 = -13;
+/// `UsbError::MissedService`
+pub const USB_OXIDE_ERR_MISSED_SERVICE: i32 = -14;
+/// `UsbError::RingOverrun`
+pub const USB_OXIDE_ERR_RING_OVERRUN: i32 = -15;
+/// `UsbError::RingUnderrun`
+pub const USB_OXIDE_ERR_RING_UNDERRUN: i32 = -16;
+/// `UsbError::OverCurrent`
+pub const USB_OXIDE_ERR_OVER_CURRENT: i32 = -17;
+/// `UsbError::InsufficientPower`
+pub const USB_OXIDE_ERR_INSUFFICIENT_POWER: i32 = -18;
+/// `UsbError::ReadOnly`
+pub const USB_OXIDE_ERR_READ_ONLY: i32 = -19;
+/// `UsbError::InvalidArgument`, also returned for null pointers
+pub const USB_OXIDE_ERR_INVALID_ARGUMENT: i32 = -20;
+/// `UsbError::DeviceFailed`
+pub const USB_OXIDE_ERR_DEVICE_FAILED: i32 = -21;
+/// `UsbError::InvLun`
+pub const USB_OXIDE_ERR_INVALID_LUN: i32 = -22;
+/// `UsbError::Misaligned`
+pub const USB_OXIDE_ERR_MISALIGNED: i32 = -23;
+/// `UsbError::Disconnected`
+pub const USB_OXIDE_ERR_DISCONNECTED: i32 = -24;
+/// `UsbError::Inactive`
+pub const USB_OXIDE_ERR_INACTIVE: i32 = -25;
+/// `UsbError::ControllerGone`
+pub const USB_OXIDE_ERR_CONTROLLER_GONE: i32 = -26;
+/// `UsbError::EventLost`
+pub const USB_OXIDE_ERR_EVENT_LOST: i32 = -27;
+/// `UsbError::InsufficientBandwidth`
+pub const USB_OXIDE_ERR_INSUFFICIENT_BANDWIDTH: i32 = -28;
+/// `UsbError::Cancelled`
+pub const USB_OXIDE_ERR_CANCELLED: i32 = -29;
+/// The handle was closed, never opened, or is of another kind
+pub const USB_OXIDE_ERR_INVALID_HANDLE: i32 = -64;
+
+/// Returns the C error code of an error.
+pub const fn error_code(err: UsbError) -> i32 {
+    match err {
+        UsbError::Timeout => USB_OXIDE_ERR_TIMEOUT,
+        UsbError::OoRam => USB_OXIDE_ERR_OUT_OF_MEMORY,
+        UsbError::MapFail => USB_OXIDE_ERR_MAP_FAIL,
+        UsbError::InvSlot => USB_OXIDE_ERR_INVALID_SLOT,
+        UsbError::InvPort => USB_OXIDE_ERR_INVALID_PORT,
+        UsbError::InvEndpoint => USB_OXIDE_ERR_INVALID_ENDPOINT,
+        UsbError::CmdFail(_) => USB_OXIDE_ERR_COMMAND_FAILED,
+        UsbError::XferFail(_) => USB_OXIDE_ERR_TRANSFER_FAILED,
+        UsbError::DeviceNotFound => USB_OXIDE_ERR_DEVICE_NOT_FOUND,
+        UsbError::NotSupported => USB_OXIDE_ERR_NOT_SUPPORTED,
+        UsbError::InvalidDescriptor => USB_OXIDE_ERR_INVALID_DESCRIPTOR,
+        UsbError::Stall => USB_OXIDE_ERR_STALL,
+        UsbError::RingFull => USB_OXIDE_ERR_RING_FULL,
+        UsbError::MissedService => USB_OXIDE_ERR_MISSED_SERVICE,
+        UsbError::RingOverrun => USB_OXIDE_ERR_RING_OVERRUN,
+        UsbError::RingUnderrun => USB_OXIDE_ERR_RING_UNDERRUN,
+        UsbError::OverCurrent => USB_OXIDE_ERR_OVER_CURRENT,
+        UsbError::InsufficientPower => USB_OXIDE_ERR_INSUFFICIENT_POWER,
+        UsbError::ReadOnly => USB_OXIDE_ERR_READ_ONLY,
+        UsbError::InvalidArgument => USB_OXIDE_ERR_INVALID_ARGUMENT,
+        UsbError::DeviceFailed => USB_OXIDE_ERR_DEVICE_FAILED,
+        UsbError::InvLun => USB_OXIDE_ERR_INVALID_LUN,
+        UsbError::Misaligned => USB_OXIDE_ERR_MISALIGNED,
+        UsbError::Disconnected => USB_OXIDE_ERR_DISCONNECTED,
+        UsbError::Inactive => USB_OXIDE_ERR_INACTIVE,
+        UsbError::ControllerGone => USB_OXIDE_ERR_CONTROLLER_GONE,
+        UsbError::EventLost => USB_OXIDE_ERR_EVENT_LOST,
+        UsbError::InsufficientBandwidth { .. } => USB_OXIDE_ERR_INSUFFICIENT_BANDWIDTH,
+        UsbError::Cancelled => USB_OXIDE_ERR_CANCELLED,
+    }
+}
+
+/// Opaque controller handle.
+pub struct usb_oxide_ctrl {
+    _private: [u8; 0],
+}
+
+/// Opaque mass storage handle.
+pub struct usb_oxide_msc {
+    _private: [u8; 0],
+}
+
+/// Opaque keyboard handle.
+pub struct usb_oxide_kbd {
+    _private: [u8; 0],
+}
+
+/// `Dma` operations implemented in C.
+///
+/// Every callback is passed `ctx`. `alloc`, `free`, `map_mmio`,
+/// `unmap_mmio`, `virt_to_phys` and `delay_us` are required; `alloc` and
+/// `map_mmio` return 0 on failure. The callbacks may run from any context the
+/// driver is called from.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct usb_oxide_dma_ops {
+    /// Passed to every callback
+    pub ctx: *mut c_void,
+    /// `Dma::alloc`
+    pub alloc: Option<unsafe extern "C" fn(ctx: *mut c_void, size: usize, align: usize) -> usize>,
+    /// `Dma::free`
+    pub free:
+        Option<unsafe extern "C" fn(ctx: *mut c_void, addr: usize, size: usize, align: usize)>,
+    /// `Dma::map_mmio`
+    pub map_mmio: Option<unsafe extern "C" fn(ctx: *mut c_void, phys: usize, size: usize) -> usize>,
+    /// `Dma::unmap_mmio`
+    pub unmap_mmio: Option<unsafe extern "C" fn(ctx: *mut c_void, virt: usize, size: usize)>,
+    /// `Dma::virt_to_phys`
+    pub virt_to_phys: Option<unsafe extern "C" fn(ctx: *mut c_void, va: usize) -> usize>,
+    /// `Dma::delay_us`, timed by a clock
+    pub delay_us: Option<unsafe extern "C" fn(ctx: *mut c_void, us: u32)>,
+    /// `Dma::wmb`; null for the default release fence
+    pub wmb: Option<unsafe extern "C" fn(ctx: *mut c_void)>,
+    /// `Dma::page_size`; 0 for 4096
+    pub page_size: usize,
+}
+
+/// Device found by `usb_oxide_enumerate`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct usb_oxide_device_info {
+    /// Root hub port number
+    pub port: u8,
+    /// Speed ID, as in PORTSC
+    pub speed: u8,
+    /// bDeviceClass
+    pub device_class: u8,
+    /// bDeviceSubClass
+    pub device_subclass: u8,
+    /// bDeviceProtocol
+    pub device_protocol: u8,
+    /// idVendor
+    pub vendor_id: u16,
+    /// idProduct
+    pub product_id: u16,
+}
+
+/// Keys held on a keyboard, as returned by `usb_oxide_kbd_poll`.
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+pub struct usb_oxide_key_state {
+    /// Modifier keys bitmap
+    pub modifiers: u8,
+    /// One bit per scancode below the modifiers, LSB first
+    pub keys: [u8; 32],
+}
+
+/// `Dma` over the C callbacks
+#[derive(Clone, Copy)]
+struct FfiHost {
+    ops: usb_oxide_dma_ops,
+}
+
+// The C side promises the callbacks may be called from any context
+unsafe impl Send for FfiHost {}
+unsafe impl Sync for FfiHost {}
+
+impl FfiHost {
+    /// Take the callbacks, failing if a required one is null
+    fn new(ops: usb_oxide_dma_ops) -> Option<Self> {
+        ops.alloc?;
+        ops.free?;
+        ops.map_mmio?;
+        ops.unmap_mmio?;
+        ops.virt_to_phys?;
+        ops.delay_us?;
+        Some(Self { ops })
+    }
+}
+
+impl Dma for FfiHost {
+    unsafe fn alloc(&self, size: usize, align: usize) -> Option<usize> {
+        let addr = unsafe { (self.ops.alloc?)(self.ops.ctx, size, align) };
+        (addr != 0).then_some(addr)
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize, align: usize) {
+        if let Some(free) = self.ops.free {
+            unsafe { free(self.ops.ctx, addr, size, align) }
+        }
+    }
+
+    unsafe fn map_mmio(&self, phys: usize, size: usize) -> Option<usize> {
+        let virt = unsafe { (self.ops.map_mmio?)(self.ops.ctx, phys, size) };
+        (virt != 0).then_some(virt)
+    }
+
+    unsafe fn unmap_mmio(&self, virt: usize, size: usize) {
+        if let Some(unmap) = self.ops.unmap_mmio {
+            unsafe { unmap(self.ops.ctx, virt, size) }
+        }
+    }
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        match self.ops.virt_to_phys {
+            Some(virt_to_phys) => unsafe { virt_to_phys(self.ops.ctx, va) },
+            None => va,
+        }
+    }
+
+    fn page_size(&self) -> usize {
+        match self.ops.page_size {
+            0 => 4096,
+            size => size,
+        }
+    }
+
+    fn delay_us(&self, us: u32) {
+        if let Some(delay) = self.ops.delay_us {
+            unsafe { delay(self.ops.ctx, us) }
+        }
+    }
+
+    fn wmb(&self) {
+        match self.ops.wmb {
+            Some(wmb) => unsafe { wmb(self.ops.ctx) },
+            None => core::sync::atomic::fence(core::sync::atomic::Ordering::Release),
+        }
+    }
+}
+
+/// Controller with the devices of its first enumeration
+struct Controller {
+    ctrl: Arc<XhciCtrl<FfiHost>>,
+    devices: spin::Mutex<Option<Vec<Arc<UsbDevice<FfiHost>>>>>,
+}
+
+impl Controller {
+    /// Devices of the first enumeration, enumerating now if there was none
+    fn devices(&self) -> Vec<Arc<UsbDevice<FfiHost>>> {
+        self.devices
+            .lock()
+            .get_or_insert_with(|| {
+                self.ctrl
+                    .enumerate_concurrent(1)
+                    .into_iter()
+                    .filter_map(|(_, result)| result.ok())
+                    .map(|enumerated| Arc::new(enumerated.device))
+                    .collect()
+            })
+            .clone()
+    }
+}
+
+/// Object behind a handle
+#[derive(Clone)]
+enum Object {
+    Ctrl(Arc<Controller>),
+    Msc(Arc<spin::Mutex<MscDevice<FfiHost>>>),
+    Keyboard(Arc<HidDevice<FfiHost>>),
+}
+
+// Under `single-threaded` the host only calls the driver from one context
+#[cfg(feature = "single-threaded")]
+unsafe impl Send for Object {}
+
+/// Handle table entry; `generation` changes whenever the slot is freed
+struct Slot {
+    generation: usize,
+    object: Option<Object>,
+}
+
+/// Bits of a handle holding the slot index plus one
+const INDEX_BITS: u32 = 16;
+
+static HANDLES: spin::Mutex<Vec<Slot>> = spin::Mutex::new(Vec::new());
+
+/// Store an object and return its handle; 0 if the table is full
+fn insert(object: Object) -> usize {
+    let mut handles = HANDLES.lock();
+    let index = match handles.iter().position(|slot| slot.object.is_none()) {
+        Some(index) => index,
+        None if handles.len() < (1 << INDEX_BITS) - 1 => {
+            handles.push(Slot {
+                generation: 0,
+                object: None,
+            });
+            handles.len() - 1
+        }
+        None => return 0,
+    };
+    let slot = &mut handles[index];
+    slot.object = Some(object);
+    slot.generation << INDEX_BITS | (index + 1)
+}
+
+/// Slot of a live handle
+fn lookup(handles: &mut [Slot], handle: usize) -> Option<&mut Slot> {
+    let index = (handle & ((1 << INDEX_BITS) - 1)).checked_sub(1)?;
+    let slot = handles.get_mut(index)?;
+    (slot.object.is_some() && slot.generation << INDEX_BITS == handle & !((1 << INDEX_BITS) - 1))
+        .then_some(slot)
+}
+
+/// Clone the object behind a handle
+fn get(handle: usize) -> Option<Object> {
+    lookup(&mut HANDLES.lock(), handle)?.object.clone()
+}
+
+/// Map a result to `USB_OXIDE_OK` or its error code
+fn status(result: crate::Result<()>) -> i32 {
+    match result {
+        Ok(()) => USB_OXIDE_OK,
+        Err(e) => error_code(e),
+    }
+}
+
+fn controller(ctrl: *mut usb_oxide_ctrl) -> Result<Arc<Controller>, i32> {
+    match get(ctrl as usize) {
+        Some(Object::Ctrl(ctrl)) => Ok(ctrl),
+        _ => Err(USB_OXIDE_ERR_INVALID_HANDLE),
+    }
+}
+
+fn msc_device(msc: *mut usb_oxide_msc) -> Result<Arc<spin::Mutex<MscDevice<FfiHost>>>, i32> {
+    match get(msc as usize) {
+        Some(Object::Msc(msc)) => Ok(msc),
+        _ => Err(USB_OXIDE_ERR_INVALID_HANDLE),
+    }
+}
+
+fn keyboard(kbd: *mut usb_oxide_kbd) -> Result<Arc<HidDevice<FfiHost>>, i32> {
+    match get(kbd as usize) {
+        Some(Object::Keyboard(kbd)) => Ok(kbd),
+        _ => Err(USB_OXIDE_ERR_INVALID_HANDLE),
+    }
+}
+
+/// Close a handle of the kind `is_kind` accepts
+fn close(handle: usize, is_kind: impl Fn(&Object) -> bool) -> i32 {
+    let object = {
+        let mut handles = HANDLES.lock();
+        match lookup(&mut handles, handle) {
+            Some(slot) if slot.object.as_ref().is_some_and(is_kind) => {
+                // Generations wrap within the bits left above the index
+                slot.generation = (slot.generation + 1) & (usize::MAX >> INDEX_BITS);
+                slot.object.take()
+            }
+            _ => return USB_OXIDE_ERR_INVALID_HANDLE,
+        }
+    };
+    // Dropped outside the table lock; tearing down a device takes commands
+    drop(object);
+    USB_OXIDE_OK
+}
+
+/// Initialize the controller at `mmio_phys` and store its handle in `out`
+///
+/// # Safety
+///
+/// `ops` and `out` must be valid pointers, and the callbacks must
+/// implement the `Dma` contract.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usb_oxide_ctrl_init(
+    mmio_phys: usize,
+    ops: *const usb_oxide_dma_ops,
+    out: *mut *mut usb_oxide_ctrl,
+) -> i32 {
+    if ops.is_null() || out.is_null() {
+        return USB_OXIDE_ERR_INVALID_ARGUMENT;
+    }
+    let Some(host) = FfiHost::new(unsafe { *ops }) else {
+        return USB_OXIDE_ERR_INVALID_ARGUMENT;
+    };
+    let ctrl = match XhciCtrl::new(mmio_phys, host) {
+        Ok(ctrl) => ctrl,
+        Err(e) => return error_code(e),
+    };
+    let handle = insert(Object::Ctrl(Arc::new(Controller {
+        ctrl: Arc::new(ctrl),
+        devices: spin::Mutex::new(None),
+    })));
+    if handle == 0 {
+        return USB_OXIDE_ERR_OUT_OF_MEMORY;
+    }
+    unsafe { out.write(handle as *mut usb_oxide_ctrl) };
+    USB_OXIDE_OK
+}
+
+/// Close a controller handle
+///
+/// Mass storage and keyboard handles opened from it keep working until
+/// they are closed too.
+#[unsafe(no_mangle)]
+pub extern "C" fn usb_oxide_ctrl_close(ctrl: *mut usb_oxide_ctrl) -> i32 {
+    close(ctrl as usize, |object| matches!(object, Object::Ctrl(_)))
+}
+
+/// Enumerate the devices on the root ports
+///
+/// The first call enumerates and configures every connected device;
+/// later calls report the same devices. Fills up to `capacity` entries
+/// of `infos` and returns the number of devices, or an error code.
+///
+/// # Safety
+///
+/// `infos` must point to `capacity` writable entries, or be null if
+/// `capacity` is 0.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usb_oxide_enumerate(
+    ctrl: *mut usb_oxide_ctrl,
+    infos: *mut usb_oxide_device_info,
+    capacity: usize,
+) -> i32 {
+    let ctrl = match controller(ctrl) {
+        Ok(ctrl) => ctrl,
+        Err(code) => return code,
+    };
+    if infos.is_null() && capacity != 0 {
+        return USB_OXIDE_ERR_INVALID_ARGUMENT;
+    }
+
+    let devices = ctrl.devices();
+    for (i, device) in devices.iter().take(capacity).enumerate() {
+        let desc = device.device_desc().copied().unwrap_or_default();
+        let info = usb_oxide_device_info {
+            port: device.port(),
+            speed: device.speed().as_raw(),
+            device_class: desc.device_class,
+            device_subclass: desc.device_subclass,
+            device_protocol: desc.device_protocol,
+            vendor_id: desc.vendor_id,
+            product_id: desc.product_id,
+        };
+        unsafe { infos.add(i).write(info) };
+    }
+    devices.len().min(i32::MAX as usize) as i32
+}
+
+/// Open the first Bulk-Only mass storage interface and store its handle in `out`
+///
+/// Enumerates first if `usb_oxide_enumerate` was not called. Fails with
+/// `USB_OXIDE_ERR_DEVICE_NOT_FOUND` if no device has one.
+///
+/// # Safety
+///
+/// `out` must be a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usb_oxide_open_first_msc(
+    ctrl: *mut usb_oxide_ctrl,
+    out: *mut *mut usb_oxide_msc,
+) -> i32 {
+    let ctrl = match controller(ctrl) {
+        Ok(ctrl) => ctrl,
+        Err(code) => return code,
+    };
+    if out.is_null() {
+        return USB_OXIDE_ERR_INVALID_ARGUMENT;
+    }
+
+    for device in ctrl.devices() {
+        let Ok(tree) = device.config_tree() else {
+            continue;
+        };
+        for (iface, ep_in, ep_out) in find_msc_interfaces(tree.raw()) {
+            let Ok(msc) = MscDevice::from_interface(device.clone(), &iface, &ep_in, &ep_out) else {
+                continue;
+            };
+            let handle = insert(Object::Msc(Arc::new(spin::Mutex::new(msc))));
+            if handle == 0 {
+                return USB_OXIDE_ERR_OUT_OF_MEMORY;
+            }
+            unsafe { out.write(handle as *mut usb_oxide_msc) };
+            return USB_OXIDE_OK;
+        }
+    }
+    USB_OXIDE_ERR_DEVICE_NOT_FOUND
+}
+
+/// Close a mass storage handle
+#[unsafe(no_mangle)]
+pub extern "C" fn usb_oxide_msc_close(msc: *mut usb_oxide_msc) -> i32 {
+    close(msc as usize, |object| matches!(object, Object::Msc(_)))
+}
+
+/// Read the size of a LUN: its number of blocks and block size in bytes
+///
+/// # Safety
+///
+/// `blocks` and `block_size` must be valid pointers.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usb_oxide_msc_capacity(
+    msc: *mut usb_oxide_msc,
+    lun: u8,
+    blocks: *mut u64,
+    block_size: *mut u32,
+) -> i32 {
+    let msc = match msc_device(msc) {
+        Ok(msc) => msc,
+        Err(code) => return code,
+    };
+    if blocks.is_null() || block_size.is_null() {
+        return USB_OXIDE_ERR_INVALID_ARGUMENT;
+    }
+
+    let mut msc = msc.lock();
+    let geometry = match msc.read_capacity(lun) {
+        // Too large for READ CAPACITY (10)
+        Ok(cap) if cap.last_lba() == u32::MAX => msc.read_capacity16(lun).map(|_| ()),
+        result => result.map(|_| ()),
+    }
+    .map(|()| msc.geometry(lun));
+    match geometry {
+        Ok(Some(geometry)) => {
+            unsafe {
+                blocks.write(geometry.num_blocks);
+                block_size.write(geometry.block_size);
+            }
+            USB_OXIDE_OK
+        }
+        Ok(None) => USB_OXIDE_ERR_INVALID_LUN,
+        Err(e) => error_code(e),
+    }
+}
+
+/// Read `count` blocks at `lba` into `buf`, which holds exactly `len` bytes
+///
+/// The bytes transferred are stored in `transferred` unless it is null.
+///
+/// # Safety
+///
+/// `buf` must point to `len` writable bytes.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usb_oxide_msc_read_blocks(
+    msc: *mut usb_oxide_msc,
+    lun: u8,
+    lba: u32,
+    count: u16,
+    buf: *mut u8,
+    len: usize,
+    transferred: *mut usize,
+) -> i32 {
+    let msc = match msc_device(msc) {
+        Ok(msc) => msc,
+        Err(code) => return code,
+    };
+    if buf.is_null() {
+        return USB_OXIDE_ERR_INVALID_ARGUMENT;
+    }
+
+    let buf = unsafe { core::slice::from_raw_parts_mut(buf, len) };
+    let result = msc.lock().read_blocks(lun, lba, count, buf);
+    unsafe { store_transferred(result, transferred) }
+}
+
+/// Write `count` blocks at `lba` from `buf`, which holds exactly `len` bytes
+///
+/// The bytes transferred are stored in `transferred` unless it is null.
+///
+/// # Safety
+///
+/// `buf` must point to `len` bytes the driver may access mutably for
+/// the duration of the call.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usb_oxide_msc_write_blocks(
+    msc: *mut usb_oxide_msc,
+    lun: u8,
+    lba: u32,
+    count: u16,
+    buf: *mut u8,
+    len: usize,
+    transferred: *mut usize,
+) -> i32 {
+    let msc = match msc_device(msc) {
+        Ok(msc) => msc,
+        Err(code) => return code,
+    };
+    if buf.is_null() {
+        return USB_OXIDE_ERR_INVALID_ARGUMENT;
+    }
+
+    let buf = unsafe { core::slice::from_raw_parts_mut(buf, len) };
+    let result = msc.lock().write_blocks(lun, lba, count, buf);
+    unsafe { store_transferred(result, transferred) }
+}
+
+/// Store the length of a block transfer in `out` unless it is null
+unsafe fn store_transferred(result: crate::Result<usize>, out: *mut usize) -> i32 {
+    status(result.map(|len| {
+        if !out.is_null() {
+            unsafe { out.write(len) };
+        }
+    }))
+}
+
+/// Open the first boot keyboard interface and store its handle in `out`
+///
+/// Enumerates first if `usb_oxide_enumerate` was not called, and queues
+/// the first report read. Fails with `USB_OXIDE_ERR_DEVICE_NOT_FOUND` if
+/// no device has one.
+///
+/// # Safety
+///
+/// `out` must be a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usb_oxide_open_first_keyboard(
+    ctrl: *mut usb_oxide_ctrl,
+    out: *mut *mut usb_oxide_kbd,
+) -> i32 {
+    let ctrl = match controller(ctrl) {
+        Ok(ctrl) => ctrl,
+        Err(code) => return code,
+    };
+    if out.is_null() {
+        return USB_OXIDE_ERR_INVALID_ARGUMENT;
+    }
+
+    for device in ctrl.devices() {
+        let Ok(tree) = device.config_tree() else {
+            continue;
+        };
+        let keyboards = find_hid_interfaces(tree.raw())
+            .into_iter()
+            .filter(|(iface, _)| iface.interface_protocol == hid_protocol::KEYBOARD);
+        for (iface, ep_in) in keyboards {
+            let Ok(kbd) = HidDevice::from_interface(device.clone(), &iface, &ep_in) else {
+                continue;
+            };
+            if let Err(e) = kbd.queue_read() {
+                return error_code(e);
+            }
+            let handle = insert(Object::Keyboard(Arc::new(kbd)));
+            if handle == 0 {
+                return USB_OXIDE_ERR_OUT_OF_MEMORY;
+            }
+            unsafe { out.write(handle as *mut usb_oxide_kbd) };
+            return USB_OXIDE_OK;
+        }
+    }
+    USB_OXIDE_ERR_DEVICE_NOT_FOUND
+}
+
+/// Close a keyboard handle
+#[unsafe(no_mangle)]
+pub extern "C" fn usb_oxide_kbd_close(kbd: *mut usb_oxide_kbd) -> i32 {
+    close(kbd as usize, |object| matches!(object, Object::Keyboard(_)))
+}
+
+/// Poll a keyboard for the keys held (non-blocking)
+///
+/// Returns 1 and fills `state` if a report arrived, 0 if none did, or
+/// the error code of a failed read.
+///
+/// # Safety
+///
+/// `state` must be a valid pointer.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn usb_oxide_kbd_poll(
+    kbd: *mut usb_oxide_kbd,
+    state: *mut usb_oxide_key_state,
+) -> i32 {
+    let kbd = match keyboard(kbd) {
+        Ok(kbd) => kbd,
+        Err(code) => return code,
+    };
+    if state.is_null() {
+        return USB_OXIDE_ERR_INVALID_ARGUMENT;
+    }
+
+    let Some(keys) = kbd.poll_keys() else {
+        return kbd.last_error().map_or(0, error_code);
+    };
+    let mut out = usb_oxide_key_state {
+        modifiers: keys.modifiers,
+        ..Default::default()
+    };
+    for code in keys.keys() {
+        out.keys[code as usize / 8] |= 1 << (code % 8);
+    }
+    unsafe { state.write(out) };
+    1
+}
@@ -10,18 +10,27 @@
 //! - Modifier key detection
 //! - LED control for keyboards
 
+use crate::desc::{EndpointDesc, InterfaceDesc, InterfaceSet, class, ep_type, for_each_interface};
+
+#[cfg(feature = "alloc")]
 use crate::{
     Dma, Result, UsbError,
     desc::{
-        EndpointDesc, InterfaceDesc, SetupPacket, class, desc_type, ep_type, hid_protocol,
-        hid_subclass,
+        ConfigDesc, DescIter, HidDesc, SetupPacket, desc_type, feature, hid_protocol, hid_subclass,
     },
-    dev::UsbDevice,
-    ring::PhysMem,
+    dev::{DriverKind, EndpointHandle, UsbDevice},
+    report::{ReportDescriptor, ReportField},
+    ring::{PhysMem, Trb},
+    sync::Lock,
 };
 
-use alloc::sync::Arc;
-use core::hint::spin_loop;
+#[cfg(feature = "alloc")]
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+#[cfg(feature = "alloc")]
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+#[cfg(all(feature = "alloc", feature = "input-traits"))]
+use crate::input::{KeyEvent, KeyInput, KeyTracker, PointerEvent, PointerInput};
 
 /// HID Usage Page codes.
 pub mod usage_page {
@@ -416,6 +425,44 @@ pub mod scancode {
     /// F24
     pub const F24: u8 = 0x73;
 
+    // International and language keys (0x87 - 0x98)
+    /// International1: Ro (`\` `_` on JIS keyboards)
+    pub const INTL_RO: u8 = 0x87;
+    /// International2: Katakana/Hiragana
+    pub const KATAKANA_HIRAGANA: u8 = 0x88;
+    /// International3: Yen (`¥` `|` on JIS keyboards)
+    pub const INTL_YEN: u8 = 0x89;
+    /// International4: Henkan (convert)
+    pub const HENKAN: u8 = 0x8A;
+    /// International5: Muhenkan (no convert)
+    pub const MUHENKAN: u8 = 0x8B;
+    /// International6: Keypad , on PC-98 keyboards
+    pub const KP_JP_COMMA: u8 = 0x8C;
+    /// International7
+    pub const INTL_7: u8 = 0x8D;
+    /// International8
+    pub const INTL_8: u8 = 0x8E;
+    /// International9
+    pub const INTL_9: u8 = 0x8F;
+    /// LANG1: Hangul/English toggle, Kana on Mac JIS keyboards
+    pub const LANG1: u8 = 0x90;
+    /// LANG2: Hanja, Eisu on Mac JIS keyboards
+    pub const LANG2: u8 = 0x91;
+    /// LANG3: Katakana
+    pub const LANG3: u8 = 0x92;
+    /// LANG4: Hiragana
+    pub const LANG4: u8 = 0x93;
+    /// LANG5: Zenkaku/Hankaku
+    pub const LANG5: u8 = 0x94;
+    /// LANG6
+    pub const LANG6: u8 = 0x95;
+    /// LANG7
+    pub const LANG7: u8 = 0x96;
+    /// LANG8
+    pub const LANG8: u8 = 0x97;
+    /// LANG9
+    pub const LANG9: u8 = 0x98;
+
     // Modifier keys (these don't appear in the keys array, only in modifiers byte)
     /// Left Control
     pub const LEFT_CTRL: u8 = 0xE0;
@@ -459,7 +506,32 @@ pub struct KeyboardReport {
     pub keys: [u8; 6],
 }
 
+const _: () = {
+    assert!(size_of::<KeyboardReport>() == 8);
+    assert!(align_of::<KeyboardReport>() == 1);
+};
+
 impl KeyboardReport {
+    /// Parses a report from the bytes actually received.
+    ///
+    /// Missing trailing bytes read as zero; fewer than 3 bytes is malformed.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 3 {
+            return None;
+        }
+        let mut raw = [0u8; 8];
+        let len = data.len().min(raw.len());
+        raw[..len].copy_from_slice(&data[..len]);
+
+        let mut keys = [0u8; 6];
+        keys.copy_from_slice(&raw[2..]);
+        Some(Self {
+            modifiers: raw[0],
+            reserved: raw[1],
+            keys,
+        })
+    }
+
     /// Returns true if either Ctrl key is pressed.
     pub fn ctrl(&self) -> bool {
         (self.modifiers & 0x11) != 0
@@ -479,6 +551,190 @@ impl KeyboardReport {
     pub fn gui(&self) -> bool {
         (self.modifiers & 0x88) != 0
     }
+
+    /// Returns the keys held, or `None` for a rollover report.
+    pub fn state(&self) -> Option<KeyboardState> {
+        let keys = self.keys;
+        if keys.contains(&scancode::ERR_ROLLOVER) {
+            return None;
+        }
+
+        let mut state = KeyboardState {
+            modifiers: self.modifiers,
+            ..Default::default()
+        };
+        for key in keys {
+            state.press(key);
+        }
+        Some(state)
+    }
+}
+
+/// Keys held on a keyboard, however the report carried them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyboardState {
+    /// Modifier keys bitmap (see `modifier`)
+    pub modifiers: u8,
+    /// One bit per scancode below the modifiers
+    keys: [u64; 4],
+}
+
+impl KeyboardState {
+    /// Returns true if the key with `scancode` is held.
+    pub fn is_pressed(&self, scancode: u8) -> bool {
+        if scancode >= scancode::LEFT_CTRL {
+            return scancode - scancode::LEFT_CTRL < 8
+                && self.modifiers & (1 << (scancode - scancode::LEFT_CTRL)) != 0;
+        }
+        self.keys[scancode as usize / 64] & (1 << (scancode % 64)) != 0
+    }
+
+    /// Returns the scancodes of the held keys other than modifiers, lowest first.
+    pub fn keys(&self) -> impl Iterator<Item = u8> + use<> {
+        let state = *self;
+        (0..scancode::LEFT_CTRL).filter(move |&code| state.is_pressed(code))
+    }
+
+    /// Mark a key as held; error and reserved scancodes are ignored
+    pub(crate) fn press(&mut self, scancode: u8) {
+        match scancode {
+            scancode::NONE..=scancode::ERR_UNDEFINED => {}
+            scancode::LEFT_CTRL..=scancode::RIGHT_GUI => {
+                self.modifiers |= 1 << (scancode - scancode::LEFT_CTRL);
+            }
+            code if code < scancode::LEFT_CTRL => self.keys[code as usize / 64] |= 1 << (code % 64),
+            _ => {}
+        }
+    }
+
+    /// Mark a key as released
+    #[cfg(all(feature = "alloc", feature = "input-traits"))]
+    pub(crate) fn release(&mut self, scancode: u8) {
+        match scancode {
+            scancode::LEFT_CTRL..=scancode::RIGHT_GUI => {
+                self.modifiers &= !(1 << (scancode - scancode::LEFT_CTRL));
+            }
+            code if code < scancode::LEFT_CTRL => {
+                self.keys[code as usize / 64] &= !(1 << (code % 64));
+            }
+            _ => {}
+        }
+    }
+
+    /// Lowest non-modifier key held here but not in `other`
+    #[cfg(all(feature = "alloc", feature = "input-traits"))]
+    pub(crate) fn first_key_not_in(&self, other: &Self) -> Option<u8> {
+        self.keys
+            .iter()
+            .zip(other.keys)
+            .enumerate()
+            .find_map(|(i, (&mine, theirs))| {
+                let diff = mine & !theirs;
+                (diff != 0).then(|| (i * 64) as u8 + diff.trailing_zeros() as u8)
+            })
+    }
+}
+
+/// Keyboard input report laid out by the report descriptor.
+///
+/// Decodes keyboards that do not send boot reports in report protocol:
+/// NKRO keyboards reporting a bitmap with one bit per key, key arrays in
+/// another layout, and hybrids sending a boot-compatible report followed
+/// by a bitmap.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug)]
+pub struct NkroKeyboard {
+    report_id: Option<u8>,
+    fields: Vec<ReportField>,
+    report_len: usize,
+}
+
+#[cfg(feature = "alloc")]
+impl NkroKeyboard {
+    /// Finds the keyboard input report of a report descriptor.
+    ///
+    /// Takes the first report with data fields on the Keyboard usage
+    /// page; `None` if there is none.
+    pub fn from_descriptor(desc: &ReportDescriptor) -> Option<Self> {
+        let is_key_field = |f: &&ReportField| f.usage_page() == Some(usage_page::KEYBOARD);
+        let report_id = desc.inputs.iter().find(is_key_field)?.report_id;
+        let fields = desc
+            .inputs
+            .iter()
+            .filter(|f| f.report_id == report_id)
+            .filter(is_key_field)
+            .cloned()
+            .collect();
+
+        let report_ids = desc.uses_report_ids();
+        Some(Self {
+            report_id: report_ids.then_some(report_id),
+            fields,
+            report_len: desc.input_len(report_id) + report_ids as usize,
+        })
+    }
+
+    /// Returns the report ID of keyboard reports, if the device uses report IDs.
+    pub fn report_id(&self) -> Option<u8> {
+        self.report_id
+    }
+
+    /// Returns the length of a keyboard report in bytes, with its report ID.
+    pub fn report_len(&self) -> usize {
+        self.report_len
+    }
+
+    /// Returns true if keys are reported as a bitmap, one bit per key.
+    pub fn has_bitmap(&self) -> bool {
+        self.fields
+            .iter()
+            .any(|f| f.is_variable() && f.bit_size == 1 && f.count > 8)
+    }
+
+    /// Decodes a keyboard report into the keys held.
+    ///
+    /// `None` for a report with another report ID, one too short for the
+    /// keyboard fields, or one signalling rollover without a bitmap to
+    /// fall back on. Hybrid reports combine the array and the bitmap.
+    pub fn decode(&self, report: &[u8]) -> Option<KeyboardState> {
+        let data = match self.report_id {
+            Some(id) => match report.split_first()? {
+                (&first, rest) if first == id => rest,
+                _ => return None,
+            },
+            None => report,
+        };
+
+        let bitmap = self.has_bitmap();
+        let mut state = KeyboardState::default();
+        for field in &self.fields {
+            for i in 0..field.count {
+                let value = field.value(data, i)?;
+                let usage = if field.is_variable() {
+                    if value == 0 {
+                        continue;
+                    }
+                    field.usage(i)
+                } else {
+                    // Array elements hold an index into the usages
+                    value
+                        .checked_sub(field.logical_min)
+                        .and_then(|index| u32::try_from(index).ok())
+                        .and_then(|index| field.usage(index))
+                };
+
+                let Some(usage) = usage else { continue };
+                if usage >> 16 != usage_page::KEYBOARD as u32 || usage & 0xFFFF > 0xFF {
+                    continue;
+                }
+                match usage as u8 {
+                    scancode::ERR_ROLLOVER if !bitmap => return None,
+                    code => state.press(code),
+                }
+            }
+        }
+        Some(state)
+    }
 }
 
 /// HID Boot Protocol Mouse Report (3 bytes).
@@ -495,7 +751,26 @@ pub struct MouseReport {
     pub y: i8,
 }
 
+const _: () = {
+    assert!(size_of::<MouseReport>() == 3);
+    assert!(align_of::<MouseReport>() == 1);
+};
+
 impl MouseReport {
+    /// Parses a report from the bytes actually received.
+    ///
+    /// Fewer than 3 bytes is malformed; extra bytes are ignored.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < 3 {
+            return None;
+        }
+        Some(Self {
+            buttons: data[0],
+            x: data[1] as i8,
+            y: data[2] as i8,
+        })
+    }
+
     /// Returns true if the left button is pressed.
     pub fn left(&self) -> bool {
         (self.buttons & 0x01) != 0
@@ -512,36 +787,172 @@ impl MouseReport {
     }
 }
 
+/// Boot mouse report with the bytes most mice send beyond the first three.
+///
+/// Nearly every boot mouse adds a wheel byte, and some a horizontal pan
+/// byte after it. Only bytes within the received length are used.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MouseReportEx {
+    /// Boot Protocol part of the report
+    pub report: MouseReport,
+    /// Vertical wheel movement (byte 3), positive away from the user
+    pub wheel: Option<i8>,
+    /// Horizontal pan (AC Pan, byte 4), positive to the right
+    pub pan: Option<i8>,
+}
+
+impl MouseReportEx {
+    /// Parses a report from the bytes actually received.
+    ///
+    /// Fewer than 3 bytes is malformed; bytes past the pan byte are ignored.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        Some(Self {
+            report: MouseReport::parse(data)?,
+            wheel: data.get(3).map(|&b| b as i8),
+            pan: data.get(4).map(|&b| b as i8),
+        })
+    }
+
+    /// Returns the buttons beyond left, right and middle (bits 3 to 7 of
+    /// the button byte), shifted down to bit 0.
+    pub fn extra_buttons(&self) -> u8 {
+        self.report.buttons >> 3
+    }
+}
+
 /// HID device type classification.
+///
+/// Taken from the boot subclass and protocol, or else from the top-level
+/// Application collection of the report descriptor.
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum HidType {
-    /// Boot Protocol Keyboard
+    /// Keyboard or keypad
     Keyboard,
-    /// Boot Protocol Mouse
+    /// Mouse or other pointer
     Mouse,
-    /// Other HID device (not Boot Protocol compatible)
+    /// Gamepad or joystick
+    Gamepad,
+    /// Digitizer, e.g. a pen tablet or touch screen
+    Digitizer,
+    /// Consumer control, e.g. media keys
+    Consumer,
+    /// Other HID device
     Other,
 }
 
+impl HidType {
+    /// Classify a top-level Application collection by its extended usage
+    /// (page << 16 | ID)
+    pub fn from_application(usage: u32) -> Self {
+        let page = (usage >> 16) as u16;
+        let id = usage as u16;
+        match page {
+            usage_page::GENERIC_DESKTOP if id <= 0xFF => match id as u8 {
+                usage_desktop::KEYBOARD | usage_desktop::KEYPAD => Self::Keyboard,
+                usage_desktop::MOUSE | usage_desktop::POINTER => Self::Mouse,
+                usage_desktop::GAMEPAD | usage_desktop::JOYSTICK => Self::Gamepad,
+                _ => Self::Other,
+            },
+            usage_page::CONSUMER => Self::Consumer,
+            usage_page::DIGITIZER => Self::Digitizer,
+            _ => Self::Other,
+        }
+    }
+
+    /// Classify a device by all its Application collections
+    ///
+    /// Composite devices take the first of keyboard, mouse, gamepad,
+    /// digitizer and consumer control they have, the order the variants
+    /// are declared in, so a keyboard with media keys is still a keyboard.
+    pub fn from_applications(usages: &[u32]) -> Self {
+        usages
+            .iter()
+            .map(|&usage| Self::from_application(usage))
+            .min_by_key(|&hid_type| hid_type as u8)
+            .unwrap_or(Self::Other)
+    }
+}
+
+/// HID protocol a device reports in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HidProtocol {
+    /// Fixed Boot Protocol reports
+    Boot,
+    /// Reports laid out by the report descriptor
+    Report,
+}
+
+/// Most keyboard reports kept from the wake-up window after `HidDevice::resume`
+#[cfg(feature = "alloc")]
+const WAKE_REPORTS: usize = 8;
+
+/// How long `HidDevice::resume` waits for each report after the wake-up
+#[cfg(feature = "alloc")]
+const WAKE_REPORT_TIMEOUT_US: u32 = 20_000;
+
 /// HID Device wrapper.
 ///
 /// Provides high-level interface for reading input from HID keyboards
-/// and mice using the Boot Protocol.
+/// and mice using the Boot Protocol, and from keyboards in report
+/// protocol through their report descriptor.
+#[cfg(feature = "alloc")]
 pub struct HidDevice<H: Dma> {
     device: Arc<UsbDevice<H>>,
     hid_type: HidType,
     interface: u8,
-    ep_in: u8,
-    ep_max_packet: u16,
+    alt_setting: u8,
+    ep_in: EndpointHandle<H>,
+    ep_desc: EndpointDesc,
+    /// Service interval replacing bInterval, in the xHCI encoding
+    interval: Option<u8>,
+    boot: bool,
+    report_protocol: AtomicBool,
+    /// Keyboard layout used in report protocol
+    nkro: Option<NkroKeyboard>,
+    /// Report descriptor splitting burst transfers in report protocol
+    reports: Option<ReportDescriptor>,
+    /// Keep boot keyboards in report protocol
+    nkro_requested: bool,
     report_buf: PhysMem<H>,
+    /// LED bitmap last sent by `set_leds`
+    leds: AtomicU8,
+    suspended: bool,
+    /// Remote wakeup was enabled by `suspend`
+    wakeup_armed: bool,
+    /// Keyboard reports read during `resume`, not yet polled
+    buffered: Lock<VecDeque<KeyboardState>>,
+    /// Further reports of a burst transfer, not yet polled
+    pending: Lock<VecDeque<Vec<u8>>>,
+    /// Error of the last failed report read, not yet taken by `last_error`
+    last_error: Lock<Option<UsbError>>,
+    #[cfg(feature = "input-traits")]
+    keys: KeyTracker,
 }
 
+#[cfg(feature = "alloc")]
 impl<H: Dma> HidDevice<H> {
     /// Try to create a HID device from an interface descriptor
+    ///
+    /// A boot device that rejects SET_PROTOCOL(Boot) is still returned,
+    /// left in report protocol; see `negotiated_protocol`. Keyboards in
+    /// report protocol are read through their report descriptor.
     pub fn from_interface(
         device: Arc<UsbDevice<H>>,
         iface: &InterfaceDesc,
         ep_in: &EndpointDesc,
+    ) -> Result<Self> {
+        Self::from_interface_with_interval(device, iface, ep_in, None)
+    }
+
+    /// Like `from_interface`, polling at `interval` instead of bInterval
+    ///
+    /// `interval` is in the xHCI encoding: the endpoint is polled every
+    /// 2^interval * 125 microseconds (see `UsbDevice::set_endpoint_interval`).
+    pub fn from_interface_with_interval(
+        device: Arc<UsbDevice<H>>,
+        iface: &InterfaceDesc,
+        ep_in: &EndpointDesc,
+        interval: Option<u8>,
     ) -> Result<Self> {
         if iface.interface_class != class::HID {
             return Err(UsbError::NotSupported);
@@ -557,40 +968,326 @@ impl<H: Dma> HidDevice<H> {
             HidType::Other
         };
 
+        // Select the alternate setting that carries the endpoint
+        if iface.alternate_setting != 0 {
+            device.set_interface(iface.interface_number, iface.alternate_setting)?;
+        }
+
         // Configure the interrupt endpoint
-        device.configure_endpoint(ep_in)?;
+        let ep = device.configure_endpoint_with_interval(ep_in, interval)?;
 
-        // Allocate report buffer (64-byte alignment for DMA)
-        let host = device.ctrl().host();
-        let report_buf = PhysMem::alloc(host, ep_in.max_packet_size as usize, 64)?;
+        // Allocate report buffer for a whole burst (64-byte alignment for DMA)
+        let report_len = device.burst_size(ep_in);
+        let report_buf = device.ctrl().alloc_mem(report_len, 64)?;
 
-        let hid = Self {
+        let mut hid = Self {
             device,
             hid_type,
             interface: iface.interface_number,
-            ep_in: ep_in.number(),
-            ep_max_packet: ep_in.max_packet_size,
+            alt_setting: iface.alternate_setting,
+            ep_in: ep,
+            ep_desc: *ep_in,
+            interval,
+            boot: iface.interface_subclass == hid_subclass::BOOT,
+            report_protocol: AtomicBool::new(true),
+            nkro: None,
+            reports: None,
+            nkro_requested: false,
             report_buf,
+            leds: AtomicU8::new(0),
+            suspended: false,
+            wakeup_armed: false,
+            buffered: Lock::new(VecDeque::new()),
+            pending: Lock::new(VecDeque::new()),
+            last_error: Lock::new(None),
+            #[cfg(feature = "input-traits")]
+            keys: KeyTracker::default(),
         };
+        hid.device.bind_driver(DriverKind::Hid);
+
+        // Non-boot devices are classified by their report descriptor;
+        // one that cannot be read leaves the device as Other
+        if hid_type == HidType::Other
+            && let Ok(desc) = hid.report_descriptor()
+        {
+            hid.hid_type = HidType::from_applications(desc.applications());
+            hid.reports = Some(desc);
+        }
+        hid.setup()?;
+
+        Ok(hid)
+    }
+
+    /// Select the boot protocol and idle rate after configuration
+    ///
+    /// A device rejecting either request keeps working: it stays in
+    /// report protocol, or reports at its own idle rate.
+    fn setup(&mut self) -> Result<()> {
+        // Devices come out of reset in report protocol
+        self.report_protocol.store(true, Ordering::Relaxed);
+        self.pending.lock().clear();
+
+        // Set boot protocol for boot devices, unless NKRO was requested
+        if self.boot {
+            match self.set_protocol(if self.nkro_requested { 1 } else { 0 }) {
+                Ok(()) | Err(UsbError::Stall | UsbError::XferFail(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
 
-        // Set boot protocol for boot devices
-        if iface.interface_subclass == hid_subclass::BOOT {
-            hid.set_protocol(0)?; // Boot protocol
+        // Keyboards left in report protocol are read by their report layout
+        self.nkro = None;
+        if self.negotiated_protocol() == HidProtocol::Report && self.hid_type == HidType::Keyboard {
+            match self.load_nkro() {
+                Ok(()) => {}
+                Err(e) if self.nkro_requested => return Err(e),
+                Err(_) => {}
+            }
         }
 
         // Set idle rate to 0 (only report on change)
-        let _ = hid.set_idle(0, 0);
+        let _ = self.set_idle(0, 0);
+        Ok(())
+    }
 
-        Ok(hid)
+    /// Reconfigure the device after `UsbDevice::reset_and_restore`
+    ///
+    /// Re-creates the interrupt endpoint, repeats the protocol and idle
+    /// setup, and re-queues the report buffer.
+    pub fn reinit(&mut self) -> Result<()> {
+        if self.alt_setting != 0 {
+            self.device.set_interface(self.interface, self.alt_setting)?;
+        }
+        self.ep_in = self
+            .device
+            .configure_endpoint_with_interval(&self.ep_desc, self.interval)?;
+        #[cfg(feature = "input-traits")]
+        self.keys.clear();
+        // A reset also resumes the port
+        self.suspended = false;
+        self.wakeup_armed = false;
+        self.buffered.lock().clear();
+        self.setup()?;
+        self.queue_read()
+    }
+
+    /// Suspend the device
+    ///
+    /// Cancels the pending report read, enables remote wakeup if
+    /// `remote_wakeup` is set, then suspends the root port. Input is only
+    /// read again after `resume`.
+    pub fn suspend(&mut self, remote_wakeup: bool) -> Result<()> {
+        if self.suspended {
+            return Ok(());
+        }
+
+        // Stop the endpoint and drop the event of the cancelled read
+        self.ep_in.restart()?;
+        while self.ep_in.poll().is_some() {}
+
+        if remote_wakeup {
+            self.device.set_remote_wakeup(true)?;
+            self.wakeup_armed = true;
+        }
+        self.device.suspend()?;
+        self.suspended = true;
+        Ok(())
+    }
+
+    /// Resume the device after `suspend` or a remote wakeup
+    ///
+    /// Resumes the port, re-queues the report read and restores the
+    /// keyboard LEDs. Keyboard reports sent right after the wake-up are
+    /// kept for `poll_keys`, so the key that woke the system is not lost.
+    pub fn resume(&mut self) -> Result<()> {
+        if !self.suspended {
+            return Ok(());
+        }
+
+        self.device.resume()?;
+        self.suspended = false;
+        if self.wakeup_armed {
+            self.wakeup_armed = false;
+            match self.device.set_remote_wakeup(false) {
+                Ok(()) | Err(UsbError::Stall) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.buffered.lock().clear();
+        self.pending.lock().clear();
+        self.queue_read()?;
+
+        if self.hid_type == HidType::Keyboard {
+            match self.set_leds(self.leds.load(Ordering::Relaxed)) {
+                Ok(()) | Err(UsbError::Stall) => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        if self.report_layout().is_none() && self.check_boot_reports(HidType::Keyboard).is_err() {
+            return Ok(());
+        }
+        for _ in 0..WAKE_REPORTS {
+            let evt = match self.ep_in.wait_event(WAKE_REPORT_TIMEOUT_US) {
+                Ok(evt) => evt,
+                Err(UsbError::Timeout) => break,
+                Err(e) => return Err(e),
+            };
+            let states: Vec<_> = match self.report_data(&evt) {
+                Ok(data) => self
+                    .split_reports(data)
+                    .filter_map(|report| self.decode_keys(report))
+                    .collect(),
+                Err(_) => Vec::new(),
+            };
+            self.queue_read()?;
+            self.buffered.lock().extend(states);
+        }
+        Ok(())
+    }
+
+    /// Returns true while the device is suspended.
+    pub fn is_suspended(&self) -> bool {
+        self.suspended
+    }
+
+    /// Reset the device if its endpoint hit the `ErrorPolicy` limit
+    ///
+    /// Returns true if the device was reset and reinitialized. Fails with
+    /// `DeviceFailed` once the port was disabled.
+    pub fn recover_from_errors(&mut self) -> Result<bool> {
+        if !self.device.recover_from_errors()? {
+            return Ok(false);
+        }
+        self.reinit()?;
+        Ok(true)
     }
 
     /// Set HID protocol (0 = Boot, 1 = Report)
     pub fn set_protocol(&self, protocol: u8) -> Result<()> {
         let setup = SetupPacket::set_protocol(self.interface, protocol);
         self.device.control_transfer(&setup, None)?;
+        self.report_protocol.store(protocol != 0, Ordering::Relaxed);
+        self.pending.lock().clear();
+        Ok(())
+    }
+
+    /// Returns the protocol the device was left in.
+    ///
+    /// Boot report polling and reads only work in `HidProtocol::Boot`.
+    pub fn negotiated_protocol(&self) -> HidProtocol {
+        if self.report_protocol.load(Ordering::Relaxed) {
+            HidProtocol::Report
+        } else {
+            HidProtocol::Boot
+        }
+    }
+
+    /// Read keyboards in report protocol through the report descriptor
+    ///
+    /// Boot keyboards are switched to report protocol, so NKRO keyboards
+    /// report every key held. Fails with `NotSupported` if the report
+    /// descriptor has no keyboard report.
+    pub fn enable_nkro(&mut self) -> Result<()> {
+        self.nkro_requested = true;
+        if let Err(e) = self.setup() {
+            self.nkro_requested = false;
+            return Err(e);
+        }
         Ok(())
     }
 
+    /// Returns the keyboard layout used in report protocol, if any.
+    pub fn nkro(&self) -> Option<&NkroKeyboard> {
+        self.nkro.as_ref()
+    }
+
+    /// Reads and parses the report descriptor of the interface.
+    ///
+    /// Its length is taken from the HID descriptor of the interface.
+    pub fn report_descriptor(&self) -> Result<ReportDescriptor> {
+        let tree = self.device.config_tree()?;
+        let setting = tree
+            .interface(self.interface)
+            .and_then(|f| {
+                f.settings
+                    .iter()
+                    .find(|s| s.iface.alternate_setting == self.alt_setting)
+            })
+            .ok_or(UsbError::InvalidDescriptor)?;
+        let hid_desc = DescIter::new(&setting.extra)
+            .find(|&(dtype, _)| dtype == desc_type::HID)
+            .and_then(|(_, data)| HidDesc::from_bytes(data))
+            .ok_or(UsbError::InvalidDescriptor)?;
+
+        let mut data = alloc::vec![0u8; hid_desc.report_desc_length as usize];
+        let setup = SetupPacket::hid_get_report_descriptor(self.interface, data.len() as u16);
+        let len = self.device.control_transfer(&setup, Some(&mut data))?;
+        ReportDescriptor::parse(&data[..len]).ok_or(UsbError::InvalidDescriptor)
+    }
+
+    /// Take the keyboard layout from the report descriptor
+    fn load_nkro(&mut self) -> Result<()> {
+        let desc = self.report_descriptor()?;
+        let layout = NkroKeyboard::from_descriptor(&desc).ok_or(UsbError::NotSupported)?;
+        self.grow_report_buf(layout.report_len())?;
+        self.nkro = Some(layout);
+        self.reports = Some(desc);
+        Ok(())
+    }
+
+    /// Make the report buffer hold `len` bytes in whole packets
+    fn grow_report_buf(&mut self, len: usize) -> Result<()> {
+        let granularity = self.device.transfer_granularity(&self.ep_desc);
+        let size = len.div_ceil(granularity) * granularity;
+        if size <= self.report_buf.size() {
+            return Ok(());
+        }
+
+        let buf = self.device.ctrl().alloc_mem(size, 64)?;
+        let old = core::mem::replace(&mut self.report_buf, buf);
+        unsafe {
+            self.device
+                .ctrl()
+                .host()
+                .free(old.virt(), old.size(), old.align());
+        }
+        Ok(())
+    }
+
+    /// Keyboard layout in effect, if reports follow the report descriptor
+    fn report_layout(&self) -> Option<&NkroKeyboard> {
+        self.nkro
+            .as_ref()
+            .filter(|_| self.negotiated_protocol() == HidProtocol::Report)
+    }
+
+    /// Fail with `NotSupported` unless the device sends boot reports of `hid_type`
+    fn check_boot_reports(&self, hid_type: HidType) -> Result<()> {
+        if self.hid_type != hid_type || self.negotiated_protocol() != HidProtocol::Boot {
+            return Err(UsbError::NotSupported);
+        }
+        Ok(())
+    }
+
+    /// Change how often the interrupt endpoint is polled
+    ///
+    /// `interval` is in the xHCI encoding, as for
+    /// `from_interface_with_interval`, and is kept across `reinit`. A
+    /// report read still pending is discarded and queued again.
+    pub fn set_polling_interval(&mut self, interval: u8) -> Result<()> {
+        self.device
+            .set_endpoint_interval(self.ep_in.dci(), interval)?;
+        self.interval = Some(interval);
+        self.queue_read()
+    }
+
+    /// Returns the polling interval the endpoint is configured with, in microseconds.
+    pub fn polling_interval_us(&self) -> u32 {
+        125 << self.ep_in.interval().min(15)
+    }
+
     /// Set idle rate
     pub fn set_idle(&self, duration: u8, report_id: u8) -> Result<()> {
         let setup = SetupPacket::set_idle(self.interface, duration, report_id);
@@ -610,6 +1307,7 @@ impl<H: Dma> HidDevice<H> {
         let setup = SetupPacket::hid_set_report(self.interface, report_type::OUTPUT, 0, 1);
         let mut buf = [leds];
         self.device.control_transfer(&setup, Some(&mut buf))?;
+        self.leds.store(leds, Ordering::Relaxed);
         Ok(())
     }
 
@@ -629,90 +1327,235 @@ impl<H: Dma> HidDevice<H> {
         Ok(buf[0])
     }
 
+    /// Bytes received by a completed report transfer
+    ///
+    /// Leaves the endpoint ready for the next read on failure: other
+    /// halting errors are recovered by `EndpointHandle::check`, a STALL
+    /// is cleared on both sides and the ring is restarted after a Ring
+    /// Overrun.
+    fn report_data(&self, evt: &Trb) -> Result<&[u8]> {
+        if let Err(e) = self.ep_in.check(evt) {
+            match e {
+                UsbError::Stall => {
+                    self.ep_in.restart()?;
+                    let setup = SetupPacket::clear_endpoint_feature(
+                        feature::ENDPOINT_HALT,
+                        self.ep_desc.endpoint_address,
+                    );
+                    self.device.control_transfer(&setup, None)?;
+                }
+                UsbError::RingOverrun => self.ep_in.restart()?,
+                _ => {}
+            }
+            return Err(e);
+        }
+
+        let len = evt.transferred(self.report_len());
+        self.report_buf.check_in(len, self.report_len());
+        Ok(unsafe { core::slice::from_raw_parts(self.report_buf.as_ptr::<u8>(), len) })
+    }
+
+    /// Bytes read per report transfer
+    ///
+    /// One burst of the interrupt endpoint, a single packet unless it
+    /// bursts, or enough whole packets for a keyboard report laid out by
+    /// the report descriptor if that is more.
+    fn report_len(&self) -> usize {
+        let granularity = self.device.transfer_granularity(&self.ep_desc);
+        let burst = self.device.burst_size(&self.ep_desc);
+        let len = match self.report_layout() {
+            Some(layout) => (layout.report_len().div_ceil(granularity) * granularity).max(burst),
+            None => burst,
+        };
+        len.min(self.report_buf.size())
+    }
+
+    /// Reports in the data of one transfer
+    ///
+    /// Split by the report descriptor in report protocol, where a burst
+    /// may carry several reports back to back; otherwise the data is one
+    /// report.
+    fn split_reports<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let desc = self
+            .reports
+            .as_ref()
+            .filter(|_| self.negotiated_protocol() == HidProtocol::Report);
+        desc.into_iter()
+            .flat_map(move |desc| desc.split_inputs(data))
+            .chain(desc.is_none().then_some(data))
+    }
+
+    /// Take the report of a completed read and queue the next read
+    ///
+    /// Reports left from a burst transfer come first, one per call.
+    /// `None` if no read completed or it failed; failures of the read or
+    /// of queueing the next one are kept for `last_error`. A read whose
+    /// completion may have been lost to an event ring overflow is
+    /// stopped and queued again, and reported as `EventLost`.
+    fn poll_report<T>(&self, parse: impl FnOnce(&[u8]) -> Option<T>) -> Option<T> {
+        let pending = self.pending.lock().pop_front();
+        if let Some(report) = pending {
+            return parse(&report);
+        }
+
+        let Some(evt) = self.ep_in.poll() else {
+            if self.ep_in.events_lost_since_queue() {
+                let requeued = self.ep_in.abort().and_then(|()| self.queue_read());
+                *self.last_error.lock() = Some(requeued.err().unwrap_or(UsbError::EventLost));
+            }
+            return None;
+        };
+        let report = match self.report_data(&evt) {
+            Ok(data) => {
+                let mut reports = self.split_reports(data);
+                let first = reports.next();
+                self.pending.lock().extend(reports.map(<[u8]>::to_vec));
+                first.and_then(parse)
+            }
+            Err(e) => {
+                *self.last_error.lock() = Some(e);
+                None
+            }
+        };
+
+        // Re-queue for next report
+        if let Err(e) = self.queue_read() {
+            *self.last_error.lock() = Some(e);
+        }
+        report
+    }
+
+    /// Returns the error of the last failed report read, clearing it.
+    ///
+    /// The poll functions return `None` for a failed read as for no
+    /// report and keep reading; this tells the two apart, e.g. for
+    /// logging. A failure to queue the next read is reported here too.
+    pub fn last_error(&self) -> Option<UsbError> {
+        self.last_error.lock().take()
+    }
+
     /// Queue a read from the interrupt endpoint
     pub fn queue_read(&self) -> Result<()> {
-        self.device.queue_transfer(
-            self.ep_in,
-            true,
-            &self.report_buf,
-            self.ep_max_packet as usize,
-        )
+        // Clear stale bytes from the previous report
+        unsafe {
+            core::ptr::write_bytes(self.report_buf.as_ptr::<u8>(), 0, self.report_buf.size());
+        }
+        self.report_buf.poison(self.report_len());
+        self.ep_in.queue(&self.report_buf, self.report_len())
+    }
+
+    /// Poll for an input report as raw bytes (non-blocking)
+    ///
+    /// In report protocol, reports sharing a burst transfer are returned
+    /// one per call, each with its report ID byte if the device uses
+    /// report IDs.
+    pub fn poll_raw_report(&self) -> Option<Vec<u8>> {
+        self.poll_report(|data| Some(data.to_vec()))
     }
 
     /// Poll for keyboard report (non-blocking)
+    ///
+    /// Always `None` unless the device is a keyboard in boot protocol.
     pub fn poll_keyboard(&self) -> Option<KeyboardReport> {
-        if self.hid_type != HidType::Keyboard {
-            return None;
-        }
+        self.check_boot_reports(HidType::Keyboard).ok()?;
 
-        if let Some(evt) = self.device.ctrl().poll_event()
-            && evt.slot_id() == self.device.slot_id()
-        {
-            let code = evt.completion_code();
-            if code == 1 || code == 13 {
-                // SUCCESS or SHORT_PACKET
-                let report = unsafe { *(self.report_buf.as_ptr::<KeyboardReport>()) };
+        self.poll_report(KeyboardReport::parse)
+    }
+
+    /// Poll for the keys held on a keyboard (non-blocking)
+    ///
+    /// Decodes boot reports in boot protocol and, once a layout was taken
+    /// from the report descriptor, keyboard reports in report protocol.
+    /// Reports kept by `resume` come first. `None` if no keyboard report
+    /// arrived or it signalled rollover.
+    pub fn poll_keys(&self) -> Option<KeyboardState> {
+        if let Some(state) = self.buffered.lock().pop_front() {
+            return Some(state);
+        }
+        if self.report_layout().is_none() {
+            self.check_boot_reports(HidType::Keyboard).ok()?;
+        }
 
-                // Re-queue for next report
-                let _ = self.queue_read();
+        self.poll_report(|data| self.decode_keys(data))
+    }
 
-                return Some(report);
-            }
+    /// Decode a keyboard report
+    fn decode_keys(&self, data: &[u8]) -> Option<KeyboardState> {
+        match self.report_layout() {
+            Some(layout) => layout.decode(data),
+            None => KeyboardReport::parse(data)?.state(),
         }
-        None
     }
 
     /// Poll for mouse report (non-blocking)
+    ///
+    /// Always `None` unless the device is a mouse in boot protocol.
     pub fn poll_mouse(&self) -> Option<MouseReport> {
-        if self.hid_type != HidType::Mouse {
-            return None;
-        }
+        self.check_boot_reports(HidType::Mouse).ok()?;
 
-        if let Some(evt) = self.device.ctrl().poll_event()
-            && evt.slot_id() == self.device.slot_id()
-        {
-            let code = evt.completion_code();
-            if code == 1 || code == 13 {
-                let report = unsafe { *(self.report_buf.as_ptr::<MouseReport>()) };
+        self.poll_report(MouseReport::parse)
+    }
 
-                // Re-queue for next report
-                let _ = self.queue_read();
+    /// Poll for mouse report with wheel and pan (non-blocking)
+    ///
+    /// Always `None` unless the device is a mouse in boot protocol.
+    pub fn poll_mouse_ex(&self) -> Option<MouseReportEx> {
+        self.check_boot_reports(HidType::Mouse).ok()?;
 
-                return Some(report);
-            }
-        }
-        None
+        self.poll_report(MouseReportEx::parse)
     }
 
     /// Blocking read for keyboard
+    ///
+    /// Fails with `NotSupported` unless the device is a keyboard in boot
+    /// protocol.
     pub fn read_keyboard(&self) -> Result<KeyboardReport> {
-        if self.hid_type != HidType::Keyboard {
-            return Err(UsbError::NotSupported);
-        }
+        self.check_boot_reports(HidType::Keyboard)?;
 
         self.queue_read()?;
 
         loop {
-            if let Some(report) = self.poll_keyboard() {
+            let evt = self.ep_in.wait_event(0)?;
+            let report = match self.report_data(&evt) {
+                Ok(data) => KeyboardReport::parse(data),
+                Err(UsbError::RingOverrun) => None,
+                Err(e) => return Err(e),
+            };
+
+            // Re-queue for next report
+            let _ = self.queue_read();
+
+            // Skip overrun and malformed reports
+            if let Some(report) = report {
                 return Ok(report);
             }
-            spin_loop();
         }
     }
 
     /// Blocking read for mouse
+    ///
+    /// Fails with `NotSupported` unless the device is a mouse in boot
+    /// protocol.
     pub fn read_mouse(&self) -> Result<MouseReport> {
-        if self.hid_type != HidType::Mouse {
-            return Err(UsbError::NotSupported);
-        }
+        self.check_boot_reports(HidType::Mouse)?;
 
         self.queue_read()?;
 
         loop {
-            if let Some(report) = self.poll_mouse() {
+            let evt = self.ep_in.wait_event(0)?;
+            let report = match self.report_data(&evt) {
+                Ok(data) => MouseReport::parse(data),
+                Err(UsbError::RingOverrun) => None,
+                Err(e) => return Err(e),
+            };
+
+            // Re-queue for next report
+            let _ = self.queue_read();
+
+            // Skip overrun and malformed reports
+            if let Some(report) = report {
                 return Ok(report);
             }
-            spin_loop();
         }
     }
 
@@ -732,8 +1575,48 @@ impl<H: Dma> HidDevice<H> {
     }
 }
 
+#[cfg(all(feature = "alloc", feature = "input-traits"))]
+impl<H: Dma> KeyInput for HidDevice<H> {
+    /// Diffs successive keyboard reports into press and release events;
+    /// always `None` for devices sending neither boot keyboard reports
+    /// nor keyboard reports laid out by the report descriptor.
+    fn poll_key(&mut self) -> Option<KeyEvent> {
+        loop {
+            if let Some(event) = self.keys.next_event() {
+                return Some(event);
+            }
+            let state = self.poll_keys()?;
+            self.keys.update(&state);
+        }
+    }
+}
+
+#[cfg(all(feature = "alloc", feature = "input-traits"))]
+impl<H: Dma> PointerInput for HidDevice<H> {
+    /// One event per mouse report; always `None` for devices that are not
+    /// mice in boot protocol.
+    fn poll_motion(&mut self) -> Option<PointerEvent> {
+        let ex = self.poll_mouse_ex()?;
+        Some(PointerEvent {
+            dx: ex.report.x as i32,
+            dy: ex.report.y as i32,
+            wheel: ex.wheel.unwrap_or(0) as i32,
+            pan: ex.pan.unwrap_or(0) as i32,
+            buttons: ex.report.buttons,
+        })
+    }
+}
+
+#[cfg(feature = "alloc")]
 impl<H: Dma> Drop for HidDevice<H> {
     fn drop(&mut self) {
+        self.device.unbind_driver(DriverKind::Hid);
+
+        // A queued read still points into the report buffer
+        if self.ep_in.abort().is_err() {
+            return;
+        }
+
         let host = self.device.ctrl().host();
         // Note: report_buf will be freed when PhysMem is dropped
         // but we need to explicitly free it since PhysMem doesn't auto-free
@@ -764,43 +1647,541 @@ pub fn scancode_to_ascii(scancode: u8, shift: bool) -> Option<char> {
 }
 
 /// Parse configuration descriptor to find HID interfaces
+///
+/// For each HID interface the first alternate setting with an Interrupt IN
+/// endpoint is chosen; its `alternate_setting` is selected by
+/// `HidDevice::from_interface`.
+#[cfg(feature = "alloc")]
 pub fn find_hid_interfaces(config_data: &[u8]) -> alloc::vec::Vec<(InterfaceDesc, EndpointDesc)> {
-    let mut result = alloc::vec::Vec::new();
-    let mut offset = 0;
-    let mut current_iface: Option<InterfaceDesc> = None;
+    let mut found = alloc::vec::Vec::new();
+    for_each_hid_interface(config_data, |iface, ep| found.push((iface, ep)));
+    found
+}
 
-    while offset + 2 <= config_data.len() {
-        let len = config_data[offset] as usize;
-        let dtype = config_data[offset + 1];
+/// Call `f` for each HID interface with its Interrupt IN endpoint
+///
+/// Picks the same alternate settings as `find_hid_interfaces`, without
+/// allocating.
+pub fn for_each_hid_interface(config_data: &[u8], mut f: impl FnMut(InterfaceDesc, EndpointDesc)) {
+    let mut done = InterfaceSet::default();
+    for_each_interface(
+        config_data,
+        |iface| iface.interface_class == class::HID,
+        |setting| {
+            let num = setting.iface.interface_number;
+            if done.contains(num) {
+                return;
+            }
+            // Only interested in Interrupt IN endpoints
+            let mut eps = setting.endpoints();
+            if let Some(ep) = eps.find(|ep| ep.is_in() && ep.transfer_type() == ep_type::INTERRUPT)
+            {
+                done.insert(num);
+                f(setting.iface, ep);
+            }
+        },
+    );
+}
 
-        if len == 0 || offset + len > config_data.len() {
-            break;
+/// Scan all configurations of a device for one with a usable HID interface
+///
+/// Returns the configuration value to pass to `set_configuration` together
+/// with that configuration's descriptor data.
+#[cfg(feature = "alloc")]
+pub fn find_hid_configuration<H: Dma>(
+    device: &UsbDevice<H>,
+) -> Result<Option<(u8, alloc::vec::Vec<u8>)>> {
+    let num_configs = device.device_desc().map_or(1, |d| d.num_configurations);
+
+    for index in 0..num_configs {
+        let config_data = device.get_config_descriptor(index)?;
+        let config = ConfigDesc::from_bytes(&config_data).ok_or(UsbError::InvalidDescriptor)?;
+        if !find_hid_interfaces(&config_data).is_empty() {
+            return Ok(Some((config.config_value, config_data)));
         }
+    }
 
-        match dtype {
-            desc_type::INTERFACE if len >= 9 => {
-                let iface = unsafe { *(config_data.as_ptr().add(offset) as *const InterfaceDesc) };
-                if iface.interface_class == class::HID {
-                    current_iface = Some(iface);
-                } else {
-                    current_iface = None;
-                }
+    Ok(None)
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyboard_report_parse() {
+        let report = KeyboardReport::parse(&[0x22, 0, 0x04, 0x05, 0, 0, 0, 0]).unwrap();
+        assert!(report.shift() && !report.ctrl() && !report.alt() && !report.gui());
+        assert_eq!({ report.keys }, [0x04, 0x05, 0, 0, 0, 0]);
+
+        // Short reports are padded, long ones truncated
+        let report = KeyboardReport::parse(&[0x01, 0, 0x1e]).unwrap();
+        assert!(report.ctrl());
+        assert_eq!({ report.keys }, [0x1e, 0, 0, 0, 0, 0]);
+        let report = KeyboardReport::parse(&[0, 0, 1, 2, 3, 4, 5, 6, 7]).unwrap();
+        assert_eq!({ report.keys }, [1, 2, 3, 4, 5, 6]);
+        assert!(KeyboardReport::parse(&[0, 0]).is_none());
+    }
+
+    #[test]
+    fn keyboard_report_state() {
+        let report = KeyboardReport::parse(&[0x80, 0, 0x04, 0x2c, 0, 0, 0, 0]).unwrap();
+        let state = report.state().unwrap();
+        assert!(state.is_pressed(scancode::RIGHT_GUI));
+        assert_eq!(state.keys().collect::<Vec<_>>(), [0x04, 0x2c]);
+
+        let mut rollover = [0; 8];
+        rollover[2..].fill(scancode::ERR_ROLLOVER);
+        assert!(KeyboardReport::parse(&rollover).unwrap().state().is_none());
+    }
+
+    #[test]
+    fn mouse_report_parse() {
+        let report = MouseReport::parse(&[0x05, 0xff, 0x10]).unwrap();
+        assert!(report.left() && !report.right() && report.middle());
+        assert_eq!(({ report.x }, { report.y }), (-1, 16));
+        assert!(MouseReport::parse(&[0x01, 0x02]).is_none());
+
+        let report = MouseReportEx::parse(&[0x19, 0x80, 0x7f, 0xfe, 0x01, 0xaa]).unwrap();
+        assert_eq!(({ report.report.x }, { report.report.y }), (-128, 127));
+        assert_eq!((report.wheel, report.pan), (Some(-2), Some(1)));
+        assert_eq!(report.extra_buttons(), 0x03);
+        let report = MouseReportEx::parse(&[0, 0, 0]).unwrap();
+        assert_eq!((report.wheel, report.pan), (None, None));
+    }
+
+    /// Keyboard application collection: modifiers, then `items`
+    fn keyboard_descriptor(report_id: Option<u8>, items: &[&[u8]]) -> ReportDescriptor {
+        let mut data = alloc::vec![0x05, 0x01, 0x09, 0x06, 0xa1, 0x01];
+        data.extend(report_id.map(|id| [0x85, id]).iter().flatten());
+        // Modifiers as 8 one-bit variables
+        data.extend([0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01]);
+        data.extend([0x75, 0x01, 0x95, 0x08, 0x81, 0x02]);
+        items.iter().for_each(|item| data.extend_from_slice(item));
+        data.push(0xc0);
+        ReportDescriptor::parse(&data).unwrap()
+    }
+
+    /// Constant byte and a six-key array, as in the boot report
+    const KEY_ARRAY: &[u8] = &[
+        0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x19, 0x00, 0x29, 0xff, 0x26, 0xff, 0x00, 0x95, 0x06,
+        0x81, 0x00,
+    ];
+
+    #[test]
+    fn nkro_bitmap_decoding() {
+        // Report ID 1, then a bitmap of usages 0 to 127
+        let bitmap: &[u8] = &[0x19, 0x00, 0x29, 0x7f, 0x25, 0x01, 0x95, 0x80, 0x81, 0x02];
+        let desc = keyboard_descriptor(Some(1), &[bitmap]);
+        let nkro = NkroKeyboard::from_descriptor(&desc).unwrap();
+        assert_eq!((nkro.report_id(), nkro.report_len()), (Some(1), 18));
+        assert!(nkro.has_bitmap());
+
+        // Left Shift with A, Space and Keypad 1 held
+        let mut report = [0u8; 18];
+        report[..2].copy_from_slice(&[1, 0x02]);
+        for code in [0x04, 0x2c, 0x59] {
+            report[2 + code / 8] |= 1 << (code % 8);
+        }
+        let state = nkro.decode(&report).unwrap();
+        assert_eq!(state.modifiers, 0x02);
+        assert_eq!(state.keys().collect::<Vec<_>>(), [0x04, 0x2c, 0x59]);
+
+        report[0] = 2;
+        assert!(nkro.decode(&report).is_none());
+        assert!(nkro.decode(&report[..10]).is_none());
+    }
+
+    #[test]
+    fn hybrid_report_decoding() {
+        // Boot-compatible part, then a bitmap of usages 4 to 115
+        let bitmap: &[u8] = &[
+            0x19, 0x04, 0x29, 0x73, 0x15, 0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x70, 0x81, 0x02,
+        ];
+        let desc = keyboard_descriptor(None, &[KEY_ARRAY, bitmap]);
+        let nkro = NkroKeyboard::from_descriptor(&desc).unwrap();
+        assert_eq!((nkro.report_id(), nkro.report_len()), (None, 22));
+        assert!(nkro.has_bitmap());
+
+        // B in the array and Space in the bitmap
+        let mut report = [0u8; 22];
+        report[2] = 0x05;
+        report[8 + (0x2c - 4) / 8] |= 1 << ((0x2c - 4) % 8);
+        let keys: Vec<u8> = nkro.decode(&report).unwrap().keys().collect();
+        assert_eq!(keys, [0x05, 0x2c]);
+
+        // A rollover array falls back on the bitmap
+        report[2..8].fill(scancode::ERR_ROLLOVER);
+        let keys: Vec<u8> = nkro.decode(&report).unwrap().keys().collect();
+        assert_eq!(keys, [0x2c]);
+    }
+
+    #[test]
+    fn array_rollover_without_bitmap() {
+        let desc = keyboard_descriptor(None, &[KEY_ARRAY]);
+        let nkro = NkroKeyboard::from_descriptor(&desc).unwrap();
+        assert!(!nkro.has_bitmap());
+
+        let state = nkro.decode(&[0x10, 0, 0x04, 0x05, 0, 0, 0, 0]).unwrap();
+        assert!(state.is_pressed(scancode::RIGHT_CTRL));
+        assert_eq!(state.keys().collect::<Vec<_>>(), [0x04, 0x05]);
+        let rollover = [0, 0, 1, 1, 1, 1, 1, 1];
+        assert!(nkro.decode(&rollover).is_none());
+    }
+
+    /// A boot keyboard driver on root port 0
+    fn keyboard() -> (crate::mock::Mock, HidDevice<crate::mock::MockHost>) {
+        let (ctrl, mock) = crate::mock::controller();
+        mock.attach(0, crate::mock::keyboard());
+        let dev = UsbDevice::new(ctrl, 0).unwrap();
+        let tree = dev
+            .choose_configuration(crate::dev::default_config_policy)
+            .unwrap();
+        let (iface, ep) = find_hid_interfaces(tree.raw())[0];
+        let hid = HidDevice::from_interface(Arc::new(dev), &iface, &ep).unwrap();
+        (mock, hid)
+    }
+
+    #[test]
+    fn drop_stops_pending_read_before_freeing() {
+        let (mock, hid) = keyboard();
+        // No key pressed yet: the read stays queued on the report buffer
+        assert!(hid.poll_keys().is_none());
+        let device = hid.device().clone();
+        let dci = hid.ep_in.dci().raw();
+        let live = mock.live_allocations();
+
+        drop(hid);
+        assert_eq!(mock.live_allocations(), live - 1);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+        assert_eq!(
+            mock.endpoint_state(device.slot_id(), dci),
+            crate::mock::EP_STOPPED
+        );
+    }
+
+    #[test]
+    fn failed_stop_leaks_report_buffer() {
+        use crate::ring::{completion, trb_type};
+
+        let (mock, hid) = keyboard();
+        assert!(hid.poll_keys().is_none());
+        let _device = hid.device().clone();
+        let live = mock.live_allocations();
+
+        mock.fail_command(trb_type::STOP_ENDPOINT, completion::TRB_ERROR);
+        drop(hid);
+        assert_eq!(mock.live_allocations(), live);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn ring_overrun_restarts_and_requeues() {
+        use crate::{
+            mock::Reply,
+            ring::{completion, trb_type},
+        };
+
+        let (mock, hid) = keyboard();
+        mock.with_device(0, 0, |d| {
+            d.push_input(0x81, Reply::Fail(completion::RING_OVERRUN));
+            d.push_input(0x81, Reply::Data(alloc::vec![0, 0, 0x04, 0, 0, 0, 0, 0]));
+        });
+        hid.queue_read().unwrap();
+        let commands = mock.commands().len();
+
+        assert!(hid.poll_keys().is_none());
+        assert!(matches!(hid.last_error(), Some(UsbError::RingOverrun)));
+        assert_eq!(hid.ep_in.stats().ring_overrun, 1);
+        assert_eq!(
+            mock.commands()[commands..],
+            [trb_type::STOP_ENDPOINT, trb_type::SET_TR_DEQUEUE]
+        );
+
+        // The read queued again picks up the next report
+        let state = (0..100)
+            .find_map(|_| {
+                mock.advance_us(125);
+                hid.poll_keys()
+            })
+            .expect("report after the overrun");
+        assert!(state.is_pressed(0x04));
+        assert!(hid.last_error().is_none());
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn remote_wakeup_rearms_the_report_read() {
+        use crate::{mock::Reply, ring::trb_type, xhci::LinkState};
+
+        let (mock, mut hid) = keyboard();
+        hid.queue_read().unwrap();
+        let ctrl = hid.device().ctrl().clone();
+        let slot_id = hid.device().slot_id();
+        let dci = hid.ep_in.dci().raw();
+        // The attach was handled before
+        let is_port_event = |e: &crate::Trb| e.trb_type() == trb_type::PORT_STATUS_CHANGE as u8;
+        while ctrl.poll_event_where(is_port_event).is_some() {}
+        ctrl.ack_port_change(0, &ctrl.port_change(0).unwrap())
+            .unwrap();
+
+        hid.suspend(true).unwrap();
+        assert!(matches!(ctrl.port_link_state(0), Ok(LinkState::U3)));
+        assert_eq!(mock.endpoint_state(slot_id, dci), crate::mock::EP_STOPPED);
+        let reads = mock.tds(slot_id, dci).len();
+
+        // The wake key arrives with the wakeup
+        mock.with_device(0, 0, |d| {
+            d.push_input(0x81, Reply::Data(alloc::vec![0, 0, 0x04, 0, 0, 0, 0, 0]));
+        });
+        mock.wake_port(0);
+
+        // The event dispatcher sees the link state change and resumes
+        assert!(ctrl.poll_event_where(is_port_event).is_some());
+        let change = ctrl.port_change(0).unwrap();
+        assert!(change.link_state && !change.connect);
+        ctrl.ack_port_change(0, &change).unwrap();
+        assert!(matches!(ctrl.port_link_state(0), Ok(LinkState::U0)));
+        hid.resume().unwrap();
+        assert!(!hid.is_suspended());
+
+        let state = hid.poll_keys().expect("wake key kept");
+        assert!(state.is_pressed(0x04));
+        // The interrupt IN read is armed again and picks up later input
+        assert_eq!(mock.endpoint_state(slot_id, dci), crate::mock::EP_RUNNING);
+        mock.with_device(0, 0, |d| {
+            d.push_input(0x81, Reply::Data(alloc::vec![0, 0, 0x05, 0, 0, 0, 0, 0]));
+        });
+        let state = (0..100)
+            .find_map(|_| {
+                mock.advance_us(125);
+                hid.poll_keys()
+            })
+            .expect("report after resume");
+        assert!(state.is_pressed(0x05));
+        assert!(mock.tds(slot_id, dci).len() >= reads + 2);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    /// Report descriptor of the mouse interface of a Logitech Unifying
+    /// receiver (046d:c52b, interface 1), as captured from the device
+    ///
+    /// Report IDs 2 (mouse, 7 bytes), 3 (consumer control, 4 bytes),
+    /// 4 (system control, 1 byte) and 8 (vendor, 1 byte).
+    const UNIFYING_MOUSE_REPORT: [u8; 148] = [
+        0x05, 0x01, 0x09, 0x02, 0xa1, 0x01, 0x85, 0x02, 0x09, 0x01, 0xa1, 0x00, 0x05, 0x09, 0x19,
+        0x01, 0x29, 0x10, 0x15, 0x00, 0x25, 0x01, 0x95, 0x10, 0x75, 0x01, 0x81, 0x02, 0x05, 0x01,
+        0x16, 0x01, 0xf8, 0x26, 0xff, 0x07, 0x75, 0x0c, 0x95, 0x02, 0x09, 0x30, 0x09, 0x31, 0x81,
+        0x06, 0x15, 0x81, 0x25, 0x7f, 0x75, 0x08, 0x95, 0x01, 0x09, 0x38, 0x81, 0x06, 0x05, 0x0c,
+        0x0a, 0x38, 0x02, 0x95, 0x01, 0x81, 0x06, 0xc0, 0xc0, 0x05, 0x0c, 0x09, 0x01, 0xa1, 0x01,
+        0x85, 0x03, 0x75, 0x10, 0x95, 0x02, 0x15, 0x01, 0x26, 0xff, 0x02, 0x19, 0x01, 0x2a, 0xff,
+        0x02, 0x81, 0x00, 0xc0, 0x05, 0x01, 0x09, 0x80, 0xa1, 0x01, 0x85, 0x04, 0x75, 0x02, 0x95,
+        0x01, 0x15, 0x01, 0x25, 0x03, 0x09, 0x82, 0x09, 0x81, 0x09, 0x83, 0x81, 0x60, 0x75, 0x06,
+        0x81, 0x03, 0xc0, 0x06, 0xbc, 0xff, 0x09, 0x88, 0xa1, 0x01, 0x85, 0x08, 0x19, 0x01, 0x29,
+        0xff, 0x15, 0x01, 0x26, 0xff, 0x00, 0x75, 0x08, 0x95, 0x01, 0x81, 0x00, 0xc0,
+    ];
+
+    #[test]
+    fn burst_transfer_is_split_per_report_id() {
+        use crate::{
+            mock::{MockDevice, Reply},
+            reg,
+        };
+
+        let [lo, hi] = (UNIFYING_MOUSE_REPORT.len() as u16).to_le_bytes();
+        let hid = alloc::vec![9, 0x21, 0x11, 1, 0, 1, 0x22, lo, hi];
+        // SuperSpeed interrupt endpoint of 16 bytes with a burst of 2
+        let companion = alloc::vec![6, 0x30, 1, 0, 32, 0];
+        let config = crate::mock::config(
+            1,
+            &[
+                crate::mock::interface(0, 0, (0x03, 0x00, 0x00), 1),
+                hid,
+                crate::mock::endpoint(0x81, 0x03, 16, 1),
+                companion,
+            ],
+        );
+        let receiver = MockDevice::new(
+            reg::SPEED_SUPER,
+            crate::mock::device_desc(0, 0x046d, 0xc52b, 1),
+            &[config],
+        )
+        .with_descriptor(0x22, 0, UNIFYING_MOUSE_REPORT.to_vec());
+        let (ctrl, mock) = crate::mock::controller();
+        mock.attach(0, receiver);
+        let dev = UsbDevice::new(ctrl, 0).unwrap();
+        let tree = dev
+            .choose_configuration(crate::dev::default_config_policy)
+            .unwrap();
+        let (iface, ep) = find_hid_interfaces(tree.raw())[0];
+        let hid = HidDevice::from_interface(Arc::new(dev), &iface, &ep).unwrap();
+        assert_eq!(hid.report_len(), 32);
+
+        // One burst: a motion, a volume key, a sleep request, a release
+        let reports: [&[u8]; 4] = [
+            &[0x02, 0x01, 0x00, 0x05, 0xf0, 0xff, 0x01, 0x00],
+            &[0x03, 0xe9, 0x00, 0x00, 0x00],
+            &[0x04, 0x02],
+            &[0x02, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        ];
+        hid.queue_read().unwrap();
+        mock.with_device(0, 0, |d| d.push_input(0x81, Reply::Data(reports.concat())));
+
+        let mut polled = Vec::new();
+        for _ in 0..100 {
+            mock.advance_us(125);
+            polled.extend(hid.poll_raw_report());
+        }
+        assert_eq!(polled, reports);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    /// Report descriptor of the second interface of a gaming keyboard: an
+    /// NKRO keyboard (report ID 1) and media keys (report ID 2)
+    const NKRO_MEDIA_REPORT: [u8; 58] = [
+        0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x85, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15,
+        0x00, 0x25, 0x01, 0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x19, 0x00, 0x29, 0x77, 0x95, 0x78,
+        0x81, 0x02, 0xc0, 0x05, 0x0c, 0x09, 0x01, 0xa1, 0x01, 0x85, 0x02, 0x19, 0x00, 0x2a, 0x3c,
+        0x02, 0x15, 0x00, 0x26, 0x3c, 0x02, 0x75, 0x10, 0x95, 0x01, 0x81, 0x00, 0xc0,
+    ];
+
+    #[test]
+    fn composite_keyboard_with_vendor_interface() {
+        use crate::mock::{MockDevice, Reply, Request, config, endpoint, interface};
+
+        let hid_desc = |len: usize| {
+            let [lo, hi] = (len as u16).to_le_bytes();
+            alloc::vec![9, 0x21, 0x11, 1, 0, 1, 0x22, lo, hi]
+        };
+        // Boot keyboard, NKRO keyboard with media keys, and a vendor
+        // interface for the lighting
+        let config = config(
+            1,
+            &[
+                interface(0, 0, (0x03, 0x01, 0x01), 1),
+                hid_desc(crate::mock::KEYBOARD_REPORT.len()),
+                endpoint(0x81, 0x03, 8, 8),
+                interface(1, 0, (0x03, 0x00, 0x00), 1),
+                hid_desc(NKRO_MEDIA_REPORT.len()),
+                endpoint(0x82, 0x03, 32, 8),
+                interface(2, 0, (0xff, 0xff, 0xff), 2),
+                endpoint(0x83, 0x03, 64, 1),
+                endpoint(0x03, 0x03, 64, 1),
+            ],
+        );
+        // Report descriptors are read per interface
+        let keyboard = MockDevice::new(
+            crate::reg::SPEED_HIGH,
+            crate::mock::device_desc(0, 0x1b1c, 0x1b3d, 1),
+            &[config],
+        )
+        .with_handler(|r| match r {
+            Request::Control { setup, .. }
+                if setup.request_type == 0x81 && setup.value == 0x2200 =>
+            {
+                let report: &[u8] = match setup.index {
+                    0 => &crate::mock::KEYBOARD_REPORT,
+                    1 => &NKRO_MEDIA_REPORT,
+                    _ => return Some(Reply::Stall),
+                };
+                Some(Reply::Data(
+                    report[..report.len().min(setup.length as usize)].to_vec(),
+                ))
             }
-            desc_type::ENDPOINT if len >= 7 => {
-                if let Some(iface) = current_iface {
-                    let ep = unsafe { *(config_data.as_ptr().add(offset) as *const EndpointDesc) };
-                    // Only interested in Interrupt IN endpoints
-                    if ep.is_in() && ep.transfer_type() == ep_type::INTERRUPT {
-                        result.push((iface, ep));
-                        current_iface = None;
-                    }
+            _ => None,
+        });
+        let (ctrl, mock) = crate::mock::controller();
+        mock.attach(0, keyboard);
+        let dev = UsbDevice::new(ctrl, 0).unwrap();
+        let tree = dev
+            .choose_configuration(crate::dev::default_config_policy)
+            .unwrap();
+        let found = find_hid_interfaces(tree.raw());
+        let numbers: Vec<u8> = found
+            .iter()
+            .map(|(iface, _)| iface.interface_number)
+            .collect();
+        assert_eq!(numbers, [0, 1]);
+
+        let dev = Arc::new(dev);
+        let boot = HidDevice::from_interface(dev.clone(), &found[0].0, &found[0].1).unwrap();
+        assert_eq!(boot.hid_type(), HidType::Keyboard);
+        assert_eq!(boot.negotiated_protocol(), HidProtocol::Boot);
+        assert!(boot.nkro().is_none());
+
+        // Not a boot interface, but a keyboard by its first application
+        let nkro = HidDevice::from_interface(dev, &found[1].0, &found[1].1).unwrap();
+        assert_eq!(nkro.hid_type(), HidType::Keyboard);
+        assert_eq!(nkro.negotiated_protocol(), HidProtocol::Report);
+        let layout = nkro.nkro().unwrap();
+        assert_eq!((layout.report_id(), layout.report_len()), (Some(1), 17));
+        assert!(layout.has_bitmap());
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn boot_mouse_reports_past_three_bytes() {
+        use crate::mock::{MockDevice, Reply};
+
+        let hid = alloc::vec![9, 0x21, 0x11, 1, 0, 1, 0x22, 0, 0];
+        let config = crate::mock::config(
+            1,
+            &[
+                crate::mock::interface(0, 0, (0x03, 0x01, 0x02), 1),
+                hid,
+                crate::mock::endpoint(0x81, 0x03, 8, 4),
+            ],
+        );
+        let mouse = MockDevice::new(
+            crate::reg::SPEED_FULL,
+            crate::mock::device_desc(0, 0x046d, 0xc077, 1),
+            &[config],
+        );
+        let (ctrl, mock) = crate::mock::controller();
+        mock.attach(0, mouse);
+        let dev = UsbDevice::new(ctrl, 0).unwrap();
+        let tree = dev
+            .choose_configuration(crate::dev::default_config_policy)
+            .unwrap();
+        let (iface, ep) = find_hid_interfaces(tree.raw())[0];
+        let hid = HidDevice::from_interface(Arc::new(dev), &iface, &ep).unwrap();
+
+        // Plain 3-byte boot report, then a wheel mouse, a tilt wheel with
+        // side buttons, and 8 bytes of which the last three are padding
+        let reports: [&[u8]; 4] = [
+            &[0x01, 0x05, 0xfb],
+            &[0x00, 0x00, 0x00, 0xff],
+            &[0x18, 0x02, 0x00, 0x01, 0xff],
+            &[0x02, 0xf6, 0x0a, 0x00, 0x00, 0x42, 0x42, 0x42],
+        ];
+        hid.queue_read().unwrap();
+        let mut polled = Vec::new();
+        for report in reports {
+            mock.with_device(0, 0, |d| d.push_input(0x81, Reply::Data(report.to_vec())));
+            for _ in 0..100 {
+                mock.advance_us(1000);
+                if let Some(report) = hid.poll_mouse_ex() {
+                    polled.push(report);
+                    break;
                 }
             }
-            _ => {}
         }
+        assert_eq!(polled.len(), reports.len());
 
-        offset += len;
+        // Only bytes within the transfer length count: the unwritten tail of
+        // the buffer after a short report is not a wheel or pan byte
+        let [plain, wheel, tilt, padded] = polled[..] else {
+            unreachable!()
+        };
+        assert!(plain.report.left());
+        assert_eq!(({ plain.report.x }, { plain.report.y }), (5, -5));
+        assert_eq!((plain.wheel, plain.pan), (None, None));
+        assert_eq!((wheel.wheel, wheel.pan), (Some(-1), None));
+        assert_eq!((tilt.wheel, tilt.pan), (Some(1), Some(-1)));
+        assert_eq!(tilt.extra_buttons(), 0x03);
+        assert!(padded.report.right());
+        assert_eq!(({ padded.report.x }, { padded.report.y }), (-10, 10));
+        assert_eq!((padded.wheel, padded.pan), (Some(0), Some(0)));
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
     }
-
-    result
 }
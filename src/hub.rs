@@ -0,0 +1,993 @@
+//! USB Hub class support.
+//!
+//! Provides port status decoding and status-change handling for external
+//! USB 2.0 and SuperSpeed hubs.
+//!
+//! # Features
+//!
+//! - Hub descriptor parsing and port power-up
+//! - Status-change detection through the hub's interrupt IN endpoint
+//! - GET_PORT_STATUS polling fallback
+
+use crate::desc::{EndpointDesc, InterfaceDesc, InterfaceSet, class, ep_type, for_each_interface};
+
+#[cfg(feature = "alloc")]
+use crate::{
+    Dma, Result, UsbError,
+    desc::{
+        HubDesc, HubDescriptorFull, SetupPacket, SsDevCapDesc, SsHubDesc, capability, desc_type,
+        find_capability, hub_feature, request,
+    },
+    dev::{DevicePath, DriverKind, EndpointHandle, ExitLatency, HubAttach, LinkPath, UsbDevice},
+    ring::{PhysMem, completion},
+    sync::Lock,
+    xhci::{OvercurrentPolicy, PortIndicator, Speed},
+};
+
+#[cfg(feature = "alloc")]
+use alloc::{sync::Arc, vec, vec::Vec};
+#[cfg(feature = "alloc")]
+use core::mem::ManuallyDrop;
+
+/// Current a bus-powered hub supplies per port, in milliamps (USB 2.0)
+#[cfg(feature = "alloc")]
+const BUS_POWERED_PORT_MA: u16 = 100;
+/// Current a bus-powered hub supplies per port, in milliamps (SuperSpeed)
+#[cfg(feature = "alloc")]
+const BUS_POWERED_SS_PORT_MA: u16 = 150;
+/// Current a self-powered hub supplies per port, in milliamps (USB 2.0)
+#[cfg(feature = "alloc")]
+const SELF_POWERED_PORT_MA: u16 = 500;
+/// Current a self-powered hub supplies per port, in milliamps (SuperSpeed)
+#[cfg(feature = "alloc")]
+const SELF_POWERED_SS_PORT_MA: u16 = 900;
+/// Time a downstream port reset may take, in microseconds
+#[cfg(feature = "alloc")]
+const PORT_RESET_TIMEOUT_US: u32 = 500_000;
+/// Reset recovery time before the first request (TRSTRCY), in microseconds
+#[cfg(feature = "alloc")]
+const RESET_RECOVERY_US: u32 = 10_000;
+
+/// Hub port status bits (wPortStatus).
+pub mod port_status {
+    /// Device is present
+    pub const CONNECTION: u16 = 1 << 0;
+    /// Port is enabled
+    pub const ENABLE: u16 = 1 << 1;
+    /// Port is suspended (USB 2.0)
+    pub const SUSPEND: u16 = 1 << 2;
+    /// Over-current condition
+    pub const OVER_CURRENT: u16 = 1 << 3;
+    /// Reset signaling is active
+    pub const RESET: u16 = 1 << 4;
+    /// Port is powered (USB 2.0)
+    pub const POWER: u16 = 1 << 8;
+    /// Low-speed device attached (USB 2.0)
+    pub const LOW_SPEED: u16 = 1 << 9;
+    /// High-speed device attached (USB 2.0)
+    pub const HIGH_SPEED: u16 = 1 << 10;
+    /// Port is powered (SuperSpeed)
+    pub const SS_POWER: u16 = 1 << 9;
+}
+
+/// Hub port change bits (wPortChange).
+pub mod port_change {
+    /// Connect status changed
+    pub const C_CONNECTION: u16 = 1 << 0;
+    /// Port was disabled by an error (USB 2.0)
+    pub const C_ENABLE: u16 = 1 << 1;
+    /// Resume completed (USB 2.0)
+    pub const C_SUSPEND: u16 = 1 << 2;
+    /// Over-current indicator changed
+    pub const C_OVER_CURRENT: u16 = 1 << 3;
+    /// Reset completed
+    pub const C_RESET: u16 = 1 << 4;
+    /// Warm reset completed (SuperSpeed)
+    pub const C_BH_RESET: u16 = 1 << 5;
+    /// Link state changed (SuperSpeed)
+    pub const C_LINK_STATE: u16 = 1 << 6;
+    /// Link configuration failed (SuperSpeed)
+    pub const C_CONFIG_ERROR: u16 = 1 << 7;
+}
+
+/// Hub status and change bits (wHubStatus / wHubChange).
+pub mod hub_status {
+    /// Local power supply lost
+    pub const LOCAL_POWER: u16 = 1 << 0;
+    /// Hub-wide over-current condition
+    pub const OVER_CURRENT: u16 = 1 << 1;
+}
+
+/// Status of one downstream hub port (GET_PORT_STATUS).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PortStatus {
+    /// Port status bits (see `port_status`)
+    pub status: u16,
+    /// Port change bits (see `port_change`)
+    pub change: u16,
+}
+
+impl PortStatus {
+    /// Returns true if a device is attached.
+    pub fn connected(&self) -> bool {
+        (self.status & port_status::CONNECTION) != 0
+    }
+
+    /// Returns true if the port is enabled.
+    pub fn enabled(&self) -> bool {
+        (self.status & port_status::ENABLE) != 0
+    }
+
+    /// Returns true if the port reports over-current.
+    pub fn over_current(&self) -> bool {
+        (self.status & port_status::OVER_CURRENT) != 0
+    }
+}
+
+/// Status of the hub itself (GET_HUB_STATUS).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HubStatus {
+    /// Hub status bits (see `hub_status`)
+    pub status: u16,
+    /// Hub change bits (see `hub_status`)
+    pub change: u16,
+}
+
+/// Changes reported by one round of hub status processing.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, Default)]
+pub struct HubChanges {
+    /// Hub-level status, if the hub change bit was set
+    pub hub: Option<HubStatus>,
+    /// Downstream ports (1-based) with their status before acknowledgement
+    pub ports: Vec<(u8, PortStatus)>,
+    /// Downstream ports powered off for over-current (see `OvercurrentPolicy`)
+    pub powered_off: Vec<u8>,
+}
+
+/// Bus power drawn from the downstream ports of a hub.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Debug, Default)]
+pub struct PowerBudget {
+    /// Current one port may supply, in milliamps
+    pub per_port_ma: u16,
+    /// Current drawn by the configured device on each port (port 1 first)
+    pub ports: Vec<u16>,
+}
+
+#[cfg(feature = "alloc")]
+impl PowerBudget {
+    /// Returns the current drawn by all configured devices, in milliamps.
+    pub fn used_ma(&self) -> u32 {
+        self.ports.iter().map(|&ma| ma as u32).sum()
+    }
+
+    /// Returns the current all ports together may supply, in milliamps.
+    pub fn total_ma(&self) -> u32 {
+        self.per_port_ma as u32 * self.ports.len() as u32
+    }
+}
+
+/// USB Hub device.
+#[cfg(feature = "alloc")]
+pub struct HubDevice<H: Dma> {
+    device: Arc<UsbDevice<H>>,
+    interface: u8,
+    ep_in: EndpointHandle<H>,
+    num_ports: u8,
+    desc: HubDescriptorFull,
+    superspeed: bool,
+    /// One TT per port (high-speed hubs in their multi-TT setting)
+    multi_tt: bool,
+    /// Exit latencies of a SuperSpeed hub, for its devices' SET_SEL
+    link: Option<LinkPath>,
+    /// Freed in `drop` once the status read is aborted
+    change_buf: ManuallyDrop<PhysMem<H>>,
+    change_len: usize,
+    per_port_ma: u16,
+    enforce_power: bool,
+    port_draw: Lock<Vec<u16>>,
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Dma> HubDevice<H> {
+    /// Creates a hub device from its interface and status-change endpoint.
+    ///
+    /// Reads the hub descriptor, tells a SuperSpeed hub its depth and
+    /// powers on all downstream ports.
+    pub fn from_interface(
+        device: Arc<UsbDevice<H>>,
+        iface: &InterfaceDesc,
+        ep_in: &EndpointDesc,
+    ) -> Result<Self> {
+        if iface.interface_class != class::HUB {
+            return Err(UsbError::NotSupported);
+        }
+
+        let superspeed = device.speed().is_super_or_faster();
+        let desc = read_hub_descriptor(&device, superspeed)?;
+        if superspeed {
+            // Selects the route string nibble the hub routes packets by
+            let setup = SetupPacket::hub_set_depth(device.path().depth());
+            device.control_transfer(&setup, None)?;
+        }
+        let link = superspeed.then(|| hub_link_path(&device)).flatten();
+        let num_ports = desc.num_ports;
+        let think_time = desc.tt_think_time();
+        let pwr_on_2_pwr_good = desc.pwr_on_2_pwr_good;
+
+        // Sent to the controller with the Configure Endpoint below
+        let multi_tt = device.speed() == Speed::High && iface.interface_protocol == 2;
+        device.set_hub_fields(num_ports, multi_tt, think_time);
+        let ep = device.configure_endpoint(ep_in)?;
+
+        // One bit for the hub plus one per port
+        let change_len = (num_ports as usize / 8 + 1).min(ep_in.packet_size().max(1) as usize);
+        let change_buf = device.ctrl().alloc_mem(change_len, 64)?;
+
+        let self_powered = device.config_tree().is_ok_and(|tree| tree.config.self_powered());
+        let per_port_ma = match (self_powered, superspeed) {
+            (false, false) => BUS_POWERED_PORT_MA,
+            (false, true) => BUS_POWERED_SS_PORT_MA,
+            (true, false) => SELF_POWERED_PORT_MA,
+            (true, true) => SELF_POWERED_SS_PORT_MA,
+        };
+
+        let hub = Self {
+            device,
+            interface: iface.interface_number,
+            ep_in: ep,
+            num_ports,
+            desc,
+            superspeed,
+            multi_tt,
+            link,
+            change_buf: ManuallyDrop::new(change_buf),
+            change_len,
+            per_port_ma,
+            enforce_power: true,
+            port_draw: Lock::new(vec![0; num_ports as usize]),
+        };
+        hub.device.bind_driver(DriverKind::Hub);
+
+        for port in 1..=num_ports {
+            hub.set_port_feature(hub_feature::PORT_POWER, port)?;
+        }
+
+        // Wait for power to become good on all ports
+        let _ = hub.device.ctrl().wait_until(pwr_on_2_pwr_good as u32 * 2000, || false);
+
+        Ok(hub)
+    }
+
+    /// Returns the number of downstream ports.
+    pub fn num_ports(&self) -> u8 {
+        self.num_ports
+    }
+
+    /// Returns true for a SuperSpeed hub.
+    pub fn is_superspeed(&self) -> bool {
+        self.superspeed
+    }
+
+    /// Returns the hub descriptor read when the hub was set up.
+    pub fn descriptor(&self) -> &HubDescriptorFull {
+        &self.desc
+    }
+
+    /// Returns true if the device on `port` (1-based) is removable.
+    ///
+    /// Devices on non-removable ports are built into the hub's product.
+    pub fn is_port_removable(&self, port: u8) -> bool {
+        self.desc.is_port_removable(port)
+    }
+
+    /// Returns the power drawn from the downstream ports.
+    pub fn power_budget(&self) -> PowerBudget {
+        PowerBudget {
+            per_port_ma: self.per_port_ma,
+            ports: self.port_draw.lock().clone(),
+        }
+    }
+
+    /// Overrides the current each port may supply, in milliamps.
+    ///
+    /// Defaults to the USB limit for the hub's speed and power source.
+    pub fn set_port_power_limit(&mut self, ma: u16) {
+        self.per_port_ma = ma;
+    }
+
+    /// Selects whether `set_device_configuration` enforces the power budget.
+    pub fn set_power_enforcement(&mut self, enable: bool) {
+        self.enforce_power = enable;
+    }
+
+    /// Selects a configuration of the device attached to a downstream port.
+    ///
+    /// Fails with `InsufficientPower`, without configuring the device, if
+    /// the configuration draws more than the port supplies. The draw is
+    /// accounted to the port until the device disconnects or
+    /// `release_port_power` is called.
+    pub fn set_device_configuration(
+        &self,
+        port: u8,
+        device: &UsbDevice<H>,
+        config: u8,
+    ) -> Result<()> {
+        if port == 0 || port > self.num_ports {
+            return Err(UsbError::InvPort);
+        }
+
+        let draw = if config == 0 {
+            0
+        } else {
+            let bcd_usb = device.device_desc().map_or_else(
+                || if device.speed().is_super_or_faster() { 0x0300 } else { 0x0200 },
+                |desc| desc.bcd_usb,
+            );
+            device.config_tree_for(config)?.config.max_power_ma_for(bcd_usb)
+        };
+        if self.enforce_power && draw > self.per_port_ma {
+            return Err(UsbError::InsufficientPower);
+        }
+
+        device.set_configuration(config)?;
+        self.port_draw.lock()[port as usize - 1] = draw;
+        Ok(())
+    }
+
+    /// Stops accounting the power drawn on a downstream port (1-based).
+    pub fn release_port_power(&self, port: u8) {
+        if port == 0 {
+            return;
+        }
+        if let Some(draw) = self.port_draw.lock().get_mut(port as usize - 1) {
+            *draw = 0;
+        }
+    }
+
+    /// Reads the status of a downstream port (1-based).
+    pub fn get_port_status(&self, port: u8) -> Result<PortStatus> {
+        if port == 0 || port > self.num_ports {
+            return Err(UsbError::InvPort);
+        }
+
+        let mut buf = [0u8; 4];
+        let setup = SetupPacket::hub_get_port_status(port);
+        self.device.control_transfer(&setup, Some(&mut buf))?;
+        Ok(PortStatus {
+            status: u16::from_le_bytes([buf[0], buf[1]]),
+            change: u16::from_le_bytes([buf[2], buf[3]]),
+        })
+    }
+
+    /// Reads the status of the hub itself.
+    pub fn get_hub_status(&self) -> Result<HubStatus> {
+        let mut buf = [0u8; 4];
+        let setup = SetupPacket::hub_get_status();
+        self.device.control_transfer(&setup, Some(&mut buf))?;
+        Ok(HubStatus {
+            status: u16::from_le_bytes([buf[0], buf[1]]),
+            change: u16::from_le_bytes([buf[2], buf[3]]),
+        })
+    }
+
+    /// Sets a feature of a downstream port (1-based, see `hub_feature`).
+    pub fn set_port_feature(&self, feature: u16, port: u8) -> Result<()> {
+        if port == 0 || port > self.num_ports {
+            return Err(UsbError::InvPort);
+        }
+
+        let setup = SetupPacket::hub_set_port_feature(feature, port);
+        self.device.control_transfer(&setup, None)?;
+        Ok(())
+    }
+
+    /// Clears a feature of a downstream port (1-based, see `hub_feature`).
+    pub fn clear_port_feature(&self, feature: u16, port: u8) -> Result<()> {
+        if port == 0 || port > self.num_ports {
+            return Err(UsbError::InvPort);
+        }
+
+        let setup = SetupPacket::hub_clear_port_feature(feature, port);
+        self.device.control_transfer(&setup, None)?;
+        Ok(())
+    }
+
+    /// Sets the indicator LED of a downstream port (1-based).
+    ///
+    /// Requires a USB 2.0 hub with port indicators; SuperSpeed hubs have
+    /// none. `Auto` hands the indicator back to the hub.
+    pub fn set_port_indicator(&self, port: u8, mode: PortIndicator) -> Result<()> {
+        if port == 0 || port > self.num_ports {
+            return Err(UsbError::InvPort);
+        }
+        if self.superspeed || !self.desc.has_port_indicators() {
+            return Err(UsbError::NotSupported);
+        }
+
+        let setup = SetupPacket::hub_set_port_indicator(port, mode.hub_selector());
+        self.device.control_transfer(&setup, None)?;
+        Ok(())
+    }
+
+    /// Switches the power of a downstream port (1-based).
+    ///
+    /// Powering a port off also stops accounting its power draw. Use it
+    /// after `UsbDevice::deactivate` to power down a device below the hub.
+    pub fn set_port_power(&self, port: u8, on: bool) -> Result<()> {
+        if on {
+            return self.set_port_feature(hub_feature::PORT_POWER, port);
+        }
+        self.clear_port_feature(hub_feature::PORT_POWER, port)?;
+        self.release_port_power(port);
+        Ok(())
+    }
+
+    /// Acknowledges the change bits reported in `status`.
+    pub fn ack_port_change(&self, port: u8, status: &PortStatus) -> Result<()> {
+        const FEATURES: [(u16, u16); 8] = [
+            (port_change::C_CONNECTION, hub_feature::C_PORT_CONNECTION),
+            (port_change::C_ENABLE, hub_feature::C_PORT_ENABLE),
+            (port_change::C_SUSPEND, hub_feature::C_PORT_SUSPEND),
+            (port_change::C_OVER_CURRENT, hub_feature::C_PORT_OVER_CURRENT),
+            (port_change::C_RESET, hub_feature::C_PORT_RESET),
+            (port_change::C_BH_RESET, hub_feature::C_BH_PORT_RESET),
+            (port_change::C_LINK_STATE, hub_feature::C_PORT_LINK_STATE),
+            (port_change::C_CONFIG_ERROR, hub_feature::C_PORT_CONFIG_ERROR),
+        ];
+
+        for (bit, feature) in FEATURES {
+            if status.change & bit != 0 {
+                self.clear_port_feature(feature, port)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Acknowledges the hub-level change bits reported in `status`.
+    pub fn ack_hub_change(&self, status: &HubStatus) -> Result<()> {
+        if status.change & hub_status::LOCAL_POWER != 0 {
+            let setup = SetupPacket::hub_clear_feature(hub_feature::C_HUB_LOCAL_POWER);
+            self.device.control_transfer(&setup, None)?;
+        }
+        if status.change & hub_status::OVER_CURRENT != 0 {
+            let setup = SetupPacket::hub_clear_feature(hub_feature::C_HUB_OVER_CURRENT);
+            self.device.control_transfer(&setup, None)?;
+        }
+        Ok(())
+    }
+
+    /// Queues a read of the status-change bitmap.
+    pub fn queue_status_read(&self) -> Result<()> {
+        unsafe {
+            core::ptr::write_bytes(self.change_buf.as_ptr::<u8>(), 0, self.change_len);
+        }
+        self.change_buf.poison(self.change_len);
+        self.ep_in.queue(&self.change_buf, self.change_len)
+    }
+
+    /// Processes a completed status-change read (non-blocking).
+    ///
+    /// Returns `Ok(None)` while no report has arrived. On completion only
+    /// the flagged ports are queried, their changes are acknowledged,
+    /// ports with over-current are powered off unless the controller's
+    /// policy is `OvercurrentPolicy::NotifyOnly`, and the read is
+    /// re-queued. A failed read is returned as an error; use
+    /// `poll_all_ports` as a fallback for such hubs. A read whose
+    /// completion may have been lost to an event ring overflow is queued
+    /// again and `EventLost` returned, as changes may have been missed.
+    pub fn poll_changes(&self) -> Result<Option<HubChanges>> {
+        let Some(evt) = self.ep_in.poll() else {
+            if self.ep_in.events_lost_since_queue() {
+                self.ep_in.abort()?;
+                self.queue_status_read()?;
+                return Err(UsbError::EventLost);
+            }
+            return Ok(None);
+        };
+
+        let code = evt.completion_code();
+        if !completion::is_success(code) {
+            return Err(UsbError::XferFail(code));
+        }
+
+        let len = evt.transferred(self.change_len);
+        self.change_buf.check_in(len, self.change_len);
+        let mut bitmap = [0u8; 32];
+        unsafe {
+            core::ptr::copy_nonoverlapping(
+                self.change_buf.as_ptr::<u8>(),
+                bitmap.as_mut_ptr(),
+                len.min(bitmap.len()),
+            );
+        }
+
+        let mut changes = HubChanges::default();
+        if bitmap[0] & 1 != 0 {
+            let status = self.get_hub_status()?;
+            self.ack_hub_change(&status)?;
+            changes.hub = Some(status);
+        }
+        for port in 1..=self.num_ports {
+            if bitmap[port as usize / 8] & (1 << (port % 8)) != 0 {
+                let status = self.get_port_status(port)?;
+                self.ack_port_change(port, &status)?;
+                if !status.connected() {
+                    self.release_port_power(port);
+                }
+                changes.ports.push((port, status));
+            }
+        }
+        self.apply_overcurrent_policy(&mut changes)?;
+
+        self.queue_status_read()?;
+        Ok(Some(changes))
+    }
+
+    /// Queries every port and the hub for changes (polling fallback).
+    ///
+    /// Over-current is handled as in `poll_changes`.
+    pub fn poll_all_ports(&self) -> Result<HubChanges> {
+        let mut changes = HubChanges::default();
+
+        let status = self.get_hub_status()?;
+        if status.change != 0 {
+            self.ack_hub_change(&status)?;
+            changes.hub = Some(status);
+        }
+        for port in 1..=self.num_ports {
+            let status = self.get_port_status(port)?;
+            if status.change != 0 {
+                self.ack_port_change(port, &status)?;
+                if !status.connected() {
+                    self.release_port_power(port);
+                }
+                changes.ports.push((port, status));
+            }
+        }
+        self.apply_overcurrent_policy(&mut changes)?;
+
+        Ok(changes)
+    }
+
+    /// Power off the ports `changes` reports over-current on
+    ///
+    /// Follows the controller's `OvercurrentPolicy`. A hub-wide
+    /// over-current powers off every port.
+    fn apply_overcurrent_policy(&self, changes: &mut HubChanges) -> Result<()> {
+        if self.device.ctrl().overcurrent_policy() != OvercurrentPolicy::PowerOff {
+            return Ok(());
+        }
+
+        let hub_wide = changes
+            .hub
+            .is_some_and(|status| status.status & hub_status::OVER_CURRENT != 0);
+        for port in 1..=self.num_ports {
+            let over_current = changes
+                .ports
+                .iter()
+                .any(|(p, status)| *p == port && status.over_current());
+            if hub_wide || over_current {
+                self.set_port_power(port, false)?;
+                changes.powered_off.push(port);
+            }
+        }
+        Ok(())
+    }
+
+    /// Resets a downstream port (1-based) and addresses the device on it.
+    ///
+    /// The device is returned in the Addressed state, ready for
+    /// `UsbDevice::get_device_descriptor`. Low- and full-speed devices
+    /// below a high-speed hub are routed through its transaction
+    /// translator. Fails with `DeviceNotFound` if the port is not
+    /// connected and enabled once the reset completes.
+    pub fn enumerate_port(&self, port: u8) -> Result<UsbDevice<H>> {
+        let path = self.port_path(port).ok_or(UsbError::InvPort)?;
+        let ctrl = self.device.ctrl();
+
+        self.set_port_feature(hub_feature::PORT_RESET, port)?;
+        let mut watch = ctrl.stopwatch();
+        let status = loop {
+            let status = self.get_port_status(port)?;
+            if status.change & port_change::C_RESET != 0 {
+                break status;
+            }
+            if watch.elapsed_us(ctrl) >= PORT_RESET_TIMEOUT_US as u64 {
+                return Err(UsbError::Timeout);
+            }
+        };
+        self.ack_port_change(
+            port,
+            &PortStatus {
+                change: port_change::C_RESET,
+                ..status
+            },
+        )?;
+        if !status.connected() || !status.enabled() {
+            return Err(UsbError::DeviceNotFound);
+        }
+        let _ = ctrl.wait_until(RESET_RECOVERY_US, || false);
+
+        let speed = if self.superspeed {
+            Speed::Super
+        } else if status.status & port_status::LOW_SPEED != 0 {
+            Speed::Low
+        } else if status.status & port_status::HIGH_SPEED != 0 {
+            Speed::High
+        } else {
+            Speed::Full
+        };
+
+        // Split transactions go through the nearest high-speed hub
+        let (tt, multi_tt) = if self.device.speed() == Speed::High {
+            let tt =
+                matches!(speed, Speed::Low | Speed::Full).then_some((self.device.slot_id(), port));
+            (tt, self.multi_tt)
+        } else {
+            self.device
+                .transaction_translator()
+                .map_or((None, false), |(tt, multi_tt)| (Some(tt), multi_tt))
+        };
+
+        let attach = HubAttach {
+            route: path.route,
+            speed,
+            tt,
+            multi_tt,
+            hub_link: self.link,
+        };
+        UsbDevice::address_below_hub(ctrl.clone(), path.root_port, attach)
+    }
+
+    /// Returns the interface number.
+    pub fn interface(&self) -> u8 {
+        self.interface
+    }
+
+    /// Returns the path of a device attached to `port` (1-based) of this hub.
+    ///
+    /// `None` if the port does not exist or the hub sits at the deepest
+    /// tier a route string can address.
+    pub fn port_path(&self, port: u8) -> Option<DevicePath> {
+        if port == 0 || port > self.num_ports() {
+            return None;
+        }
+        self.device.path().child(port)
+    }
+
+    /// Returns a reference to the underlying USB device.
+    pub fn device(&self) -> &Arc<UsbDevice<H>> {
+        &self.device
+    }
+}
+
+#[cfg(feature = "alloc")]
+impl<H: Dma> Drop for HubDevice<H> {
+    fn drop(&mut self) {
+        self.device.unbind_driver(DriverKind::Hub);
+
+        // A queued status read still points into the change buffer
+        if self.ep_in.abort().is_err() {
+            return;
+        }
+
+        // The field is not touched again
+        let change_buf = unsafe { ManuallyDrop::take(&mut self.change_buf) };
+        change_buf.free(self.device.ctrl().host());
+    }
+}
+
+/// Exit latencies of a SuperSpeed hub from its BOS, if it reports them
+#[cfg(feature = "alloc")]
+fn hub_link_path<H: Dma>(device: &UsbDevice<H>) -> Option<LinkPath> {
+    let bos = device.get_bos_descriptor().ok()?;
+    let cap =
+        find_capability(&bos, capability::SUPERSPEED_USB).and_then(SsDevCapDesc::from_bytes)?;
+    device.link_path(ExitLatency::from_capability(&cap))
+}
+
+/// Read the hub descriptor, including the bitmaps for all its ports
+#[cfg(feature = "alloc")]
+fn read_hub_descriptor<H: Dma>(
+    device: &UsbDevice<H>,
+    superspeed: bool,
+) -> Result<HubDescriptorFull> {
+    let (ty, prefix_len) = if superspeed {
+        (desc_type::SS_HUB, size_of::<SsHubDesc>())
+    } else {
+        (desc_type::HUB, size_of::<HubDesc>())
+    };
+    let setup = |len: usize| {
+        SetupPacket::new(
+            0xA0,
+            request::GET_DESCRIPTOR,
+            (ty as u16) << 8,
+            0,
+            len as u16,
+        )
+    };
+
+    // Up to 255 ports: 7 bytes plus two 32-byte bitmaps
+    let mut buf = [0u8; 71];
+    let mut len = device.control_transfer(&setup(prefix_len), Some(&mut buf[..prefix_len]))?;
+    if len < size_of::<HubDesc>() {
+        return Err(UsbError::InvalidDescriptor);
+    }
+    let num_ports = buf[2];
+    let full_len = HubDescriptorFull::wire_len(superspeed, num_ports);
+    if full_len > len {
+        len = device.control_transfer(&setup(full_len), Some(&mut buf[..full_len]))?;
+    }
+    HubDescriptorFull::parse(&buf[..len], num_ports).ok_or(UsbError::InvalidDescriptor)
+}
+
+/// Parses configuration descriptor to find hub interfaces.
+#[cfg(feature = "alloc")]
+pub fn find_hub_interfaces(config_data: &[u8]) -> Vec<(InterfaceDesc, EndpointDesc)> {
+    let mut found = Vec::new();
+    for_each_hub_interface(config_data, |iface, ep| found.push((iface, ep)));
+    found
+}
+
+/// Calls `f` for each hub interface with its status-change endpoint.
+///
+/// Only the first alternate setting of each interface is considered, as
+/// in `find_hub_interfaces`; needs no allocation.
+pub fn for_each_hub_interface(config_data: &[u8], mut f: impl FnMut(InterfaceDesc, EndpointDesc)) {
+    let mut seen = InterfaceSet::default();
+    for_each_interface(
+        config_data,
+        |iface| iface.interface_class == class::HUB,
+        |setting| {
+            if !seen.insert(setting.iface.interface_number) {
+                return;
+            }
+            let mut eps = setting.endpoints();
+            if let Some(ep) = eps.find(|ep| ep.is_in() && ep.transfer_type() == ep_type::INTERRUPT)
+            {
+                f(setting.iface, ep);
+            }
+        },
+    );
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::{
+        dev::default_config_policy,
+        mock::{self, HubState, MockHost},
+    };
+
+    /// A configured hub with `ports` ports on root port 0
+    fn configured(
+        ports: u8,
+        characteristics: u16,
+        superspeed: bool,
+    ) -> (
+        mock::Mock,
+        Arc<std::sync::Mutex<HubState>>,
+        HubDevice<MockHost>,
+    ) {
+        let (ctrl, mock) = mock::controller();
+        let (device, state) = mock::hub(ports, characteristics, superspeed);
+        mock.attach(0, device);
+
+        let mut dev = UsbDevice::new(ctrl, 0).expect("address hub");
+        dev.get_device_descriptor().expect("device descriptor");
+        let tree = dev
+            .choose_configuration(default_config_policy)
+            .expect("configure");
+        let (iface, ep) = find_hub_interfaces(tree.raw())[0];
+        let hub = HubDevice::from_interface(Arc::new(dev), &iface, &ep).expect("hub");
+        (mock, state, hub)
+    }
+
+    #[test]
+    fn port_indicator_requires_hub_support() {
+        let (_mock, state, hub) = configured(4, 0x80, false);
+        hub.set_port_indicator(2, PortIndicator::Green).unwrap();
+        hub.set_port_indicator(4, PortIndicator::Auto).unwrap();
+        assert_eq!(state.lock().unwrap().indicators, [(2, 2), (4, 0)]);
+
+        assert!(matches!(
+            hub.set_port_indicator(0, PortIndicator::Off),
+            Err(UsbError::InvPort)
+        ));
+        assert!(matches!(
+            hub.set_port_indicator(5, PortIndicator::Off),
+            Err(UsbError::InvPort)
+        ));
+
+        let (_mock, state, hub) = configured(4, 0, false);
+        let result = hub.set_port_indicator(1, PortIndicator::Amber);
+        assert!(matches!(result, Err(UsbError::NotSupported)));
+        assert!(state.lock().unwrap().indicators.is_empty());
+    }
+
+    #[test]
+    fn superspeed_hubs_have_no_port_indicators() {
+        let (_mock, state, hub) = configured(4, 0x80, true);
+        let result = hub.set_port_indicator(1, PortIndicator::Green);
+        assert!(matches!(result, Err(UsbError::NotSupported)));
+        assert!(state.lock().unwrap().indicators.is_empty());
+    }
+
+    #[test]
+    fn overcurrent_powers_port_off() {
+        let (_mock, state, hub) = configured(4, 0x09, false);
+        let powered = |port: usize| state.lock().unwrap().ports[port - 1].0 & port_status::POWER;
+        let over_current = |port: usize| {
+            let mut state = state.lock().unwrap();
+            state.ports[port - 1].0 |= port_status::OVER_CURRENT;
+            state.ports[port - 1].1 |= port_change::C_OVER_CURRENT;
+        };
+        assert_ne!(powered(3), 0);
+
+        over_current(3);
+        let changes = hub.poll_all_ports().unwrap();
+        assert_eq!(changes.powered_off, [3]);
+        assert_eq!(powered(3), 0);
+        assert_eq!(state.lock().unwrap().ports[2].1, 0);
+        assert_ne!(powered(2), 0);
+
+        hub.device()
+            .ctrl()
+            .set_overcurrent_policy(OvercurrentPolicy::NotifyOnly);
+        over_current(2);
+        let changes = hub.poll_all_ports().unwrap();
+        assert_eq!(changes.ports.len(), 1);
+        assert!(changes.powered_off.is_empty());
+        assert_ne!(powered(2), 0);
+    }
+
+    #[test]
+    fn hub_overcurrent_powers_all_ports_off() {
+        let (_mock, state, hub) = configured(2, 0x09, false);
+        state.lock().unwrap().hub = (hub_status::OVER_CURRENT, hub_status::OVER_CURRENT);
+
+        let changes = hub.poll_all_ports().unwrap();
+        assert_eq!(changes.powered_off, [1, 2]);
+        let state = state.lock().unwrap();
+        assert_eq!(state.hub.1, 0);
+        assert!(state.ports.iter().all(|p| p.0 & port_status::POWER == 0));
+    }
+
+    #[test]
+    fn superspeed_hubs_get_their_depth() {
+        let (mock, state, hub) = configured(4, 0x09, true);
+        assert_eq!(state.lock().unwrap().depth, Some(0));
+
+        let (below, below_state) = mock::hub(4, 0x09, true);
+        mock.attach_routed(0, 0x2, below);
+        state.lock().unwrap().ports[1].0 |= port_status::CONNECTION;
+        let mut dev = hub.enumerate_port(2).expect("enumerate port");
+        dev.get_device_descriptor().expect("device descriptor");
+        let tree = dev
+            .choose_configuration(default_config_policy)
+            .expect("configure");
+        let (iface, ep) = find_hub_interfaces(tree.raw())[0];
+        HubDevice::from_interface(Arc::new(dev), &iface, &ep).expect("hub");
+        assert_eq!(below_state.lock().unwrap().depth, Some(1));
+
+        let (_mock, state, _hub) = configured(4, 0x09, false);
+        assert_eq!(state.lock().unwrap().depth, None);
+    }
+
+    #[test]
+    fn drop_frees_change_buffer() {
+        let (mock, _state, hub) = configured(4, 0x09, false);
+        hub.queue_status_read().unwrap();
+        let _device = hub.device().clone();
+        let live = mock.live_allocations();
+        drop(hub);
+        assert_eq!(mock.live_allocations(), live - 1);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn port_features_check_port_range() {
+        let (_mock, state, hub) = configured(4, 0, false);
+        for port in [0, 5, u8::MAX] {
+            let set = hub.set_port_feature(hub_feature::PORT_POWER, port);
+            assert!(matches!(set, Err(UsbError::InvPort)), "port {port}");
+            let clear = hub.clear_port_feature(hub_feature::PORT_POWER, port);
+            assert!(matches!(clear, Err(UsbError::InvPort)), "port {port}");
+        }
+
+        hub.set_port_feature(hub_feature::PORT_POWER, 4).unwrap();
+        assert_ne!(state.lock().unwrap().ports[3].0 & (1 << 8), 0);
+    }
+
+    /// BOS with a SuperSpeed capability of the given exit latencies
+    fn bos(u1_exit: u8, u2_exit: u16) -> Vec<u8> {
+        let [lo, hi] = u2_exit.to_le_bytes();
+        vec![
+            5, 0x0f, 15, 0, 1, // BOS header
+            10, 0x10, 3, 0, 0x0e, 0, 1, u1_exit, lo, hi,
+        ]
+    }
+
+    #[test]
+    fn set_sel_covers_the_hub_path() {
+        use crate::{desc::SelData, mock::Request, reg};
+
+        let (ctrl, mock) = mock::controller();
+        let (device, state) = mock::hub(4, 0x09, true);
+        mock.attach(0, device.with_descriptor(desc_type::BOS, 0, bos(1, 50)));
+        let dev = UsbDevice::new(ctrl, 0).unwrap();
+        let tree = dev.choose_configuration(default_config_policy).unwrap();
+        let (iface, ep) = find_hub_interfaces(tree.raw())[0];
+        let hub = HubDevice::from_interface(Arc::new(dev), &iface, &ep).unwrap();
+
+        let sel = Arc::new(std::sync::Mutex::new(None));
+        let log = sel.clone();
+        let config = mock::config(1, &[mock::interface(0, 0, (0xff, 0, 0), 0)]);
+        let below = mock::MockDevice::new(
+            reg::SPEED_SUPER,
+            mock::device_desc(0, 0x0bda, 0x0411, 1),
+            &[config],
+        )
+        .with_descriptor(desc_type::BOS, 0, bos(2, 60))
+        .with_handler(move |r: &Request<'_>| {
+            if let Request::Control { setup, data } = r
+                && setup.request == request::SET_SEL
+            {
+                *log.lock().unwrap() = SelData::from_bytes(data);
+            }
+            None
+        });
+        mock.attach_routed(0, 0x2, below);
+        state.lock().unwrap().ports[1].0 |= port_status::CONNECTION;
+        let dev = hub.enumerate_port(2).unwrap();
+        dev.choose_configuration(default_config_policy).unwrap();
+
+        assert_eq!(dev.enable_link_pm(true, true).unwrap(), (true, true));
+        // The hub's 50 us U2 path plus 50 us to forward the wake outweigh
+        // the device's own 60 us
+        let expected = SelData {
+            u1_sel: 5,
+            u1_pel: 2,
+            u2_sel: 103,
+            u2_pel: 100,
+        };
+        assert_eq!(*sel.lock().unwrap(), Some(expected));
+    }
+
+    #[test]
+    fn usb2_lpm_is_refused_below_a_hub() {
+        use crate::reg;
+
+        let (mock, state, hub) = configured(4, 0x09, false);
+        // The root ports support hardware LPM; only the hub is in the way
+        mock.set_protocols(&[(2, 0, 4, reg::SUPP_PROTO_HLC)]);
+
+        // A USB 2.0 Extension capability with LPM
+        let lpm_bos = vec![5, 0x0f, 12, 0, 1, 7, 0x10, 2, 0x02, 0, 0, 0];
+        let config = mock::config(1, &[mock::interface(0, 0, (0xff, 0, 0), 0)]);
+        let below = mock::MockDevice::new(
+            reg::SPEED_HIGH,
+            mock::device_desc(0, 0x0bda, 0x8153, 1),
+            &[config],
+        )
+        .with_descriptor(desc_type::BOS, 0, lpm_bos);
+        mock.attach_routed(0, 0x2, below);
+        state.lock().unwrap().ports[1].0 |= port_status::CONNECTION | port_status::HIGH_SPEED;
+        let dev = hub.enumerate_port(2).unwrap();
+        assert_eq!(dev.speed(), Speed::High);
+
+        let writes = mock.register_writes().len();
+        assert!(matches!(
+            dev.enable_usb2_lpm(4),
+            Err(UsbError::NotSupported)
+        ));
+        assert!(matches!(
+            dev.disable_usb2_lpm(),
+            Err(UsbError::NotSupported)
+        ));
+        // Root port 0's PORTPMSC and PORTHLPMC were left alone
+        assert_eq!(mock.register_writes().len(), writes);
+    }
+}
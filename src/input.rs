@@ -0,0 +1,660 @@
+//! Input source traits.
+//!
+//! Lets code that consumes key presses and pointer motion stay generic
+//! over the backend, so a USB HID keyboard can stand in for a PS/2 one.
+//! `HidDevice` implements both traits; other drivers can implement them
+//! for their own devices.
+
+use crate::hid::scancode;
+#[cfg(feature = "alloc")]
+use crate::hid::KeyboardState;
+
+/// Source of key press and release events.
+pub trait KeyInput {
+    /// Returns the next key event, or `None` if there is none pending.
+    fn poll_key(&mut self) -> Option<KeyEvent>;
+}
+
+/// Source of pointer motion and button events.
+pub trait PointerInput {
+    /// Returns the next pointer event, or `None` if there is none pending.
+    fn poll_motion(&mut self) -> Option<PointerEvent>;
+}
+
+/// A key changing state.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// Key that changed
+    pub code: KeyCode,
+    /// True on press, false on release
+    pub pressed: bool,
+    /// Modifier keys held after the event (see `modifier`)
+    pub modifiers: u8,
+}
+
+/// Relative pointer motion and button state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PointerEvent {
+    /// Horizontal motion, positive to the right
+    pub dx: i32,
+    /// Vertical motion, positive downwards
+    pub dy: i32,
+    /// Vertical wheel motion, positive away from the user
+    pub wheel: i32,
+    /// Horizontal wheel (pan) motion, positive to the right
+    pub pan: i32,
+    /// Buttons held (bit 0 left, bit 1 right, bit 2 middle, then extra
+    /// buttons)
+    pub buttons: u8,
+}
+
+/// Layout-independent key identity.
+///
+/// Names the physical key position by its US layout legend, like the
+/// USB HID usage it is numbered after; translating it to a character is
+/// up to the keyboard layout.
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum KeyCode {
+    /// A key
+    A = scancode::A,
+    /// B key
+    B = scancode::B,
+    /// C key
+    C = scancode::C,
+    /// D key
+    D = scancode::D,
+    /// E key
+    E = scancode::E,
+    /// F key
+    F = scancode::F,
+    /// G key
+    G = scancode::G,
+    /// H key
+    H = scancode::H,
+    /// I key
+    I = scancode::I,
+    /// J key
+    J = scancode::J,
+    /// K key
+    K = scancode::K,
+    /// L key
+    L = scancode::L,
+    /// M key
+    M = scancode::M,
+    /// N key
+    N = scancode::N,
+    /// O key
+    O = scancode::O,
+    /// P key
+    P = scancode::P,
+    /// Q key
+    Q = scancode::Q,
+    /// R key
+    R = scancode::R,
+    /// S key
+    S = scancode::S,
+    /// T key
+    T = scancode::T,
+    /// U key
+    U = scancode::U,
+    /// V key
+    V = scancode::V,
+    /// W key
+    W = scancode::W,
+    /// X key
+    X = scancode::X,
+    /// Y key
+    Y = scancode::Y,
+    /// Z key
+    Z = scancode::Z,
+    /// 1 key
+    N1 = scancode::N1,
+    /// 2 key
+    N2 = scancode::N2,
+    /// 3 key
+    N3 = scancode::N3,
+    /// 4 key
+    N4 = scancode::N4,
+    /// 5 key
+    N5 = scancode::N5,
+    /// 6 key
+    N6 = scancode::N6,
+    /// 7 key
+    N7 = scancode::N7,
+    /// 8 key
+    N8 = scancode::N8,
+    /// 9 key
+    N9 = scancode::N9,
+    /// 0 key
+    N0 = scancode::N0,
+    /// Enter/Return
+    Enter = scancode::ENTER,
+    /// Escape
+    Escape = scancode::ESCAPE,
+    /// Backspace
+    Backspace = scancode::BACKSPACE,
+    /// Tab
+    Tab = scancode::TAB,
+    /// Space
+    Space = scancode::SPACE,
+    /// Minus/Underscore
+    Minus = scancode::MINUS,
+    /// Equal/Plus
+    Equal = scancode::EQUAL,
+    /// Left Bracket
+    LeftBracket = scancode::LEFT_BRACKET,
+    /// Right Bracket
+    RightBracket = scancode::RIGHT_BRACKET,
+    /// Backslash
+    Backslash = scancode::BACKSLASH,
+    /// Non-US Hash
+    NonUsHash = scancode::NON_US_HASH,
+    /// Semicolon
+    Semicolon = scancode::SEMICOLON,
+    /// Apostrophe/Quote
+    Apostrophe = scancode::APOSTROPHE,
+    /// Grave/Tilde
+    Grave = scancode::GRAVE,
+    /// Comma
+    Comma = scancode::COMMA,
+    /// Period/Dot
+    Period = scancode::PERIOD,
+    /// Slash
+    Slash = scancode::SLASH,
+    /// Caps Lock
+    CapsLock = scancode::CAPS_LOCK,
+    /// F1
+    F1 = scancode::F1,
+    /// F2
+    F2 = scancode::F2,
+    /// F3
+    F3 = scancode::F3,
+    /// F4
+    F4 = scancode::F4,
+    /// F5
+    F5 = scancode::F5,
+    /// F6
+    F6 = scancode::F6,
+    /// F7
+    F7 = scancode::F7,
+    /// F8
+    F8 = scancode::F8,
+    /// F9
+    F9 = scancode::F9,
+    /// F10
+    F10 = scancode::F10,
+    /// F11
+    F11 = scancode::F11,
+    /// F12
+    F12 = scancode::F12,
+    /// Print Screen
+    PrintScreen = scancode::PRINT_SCREEN,
+    /// Scroll Lock
+    ScrollLock = scancode::SCROLL_LOCK,
+    /// Pause
+    Pause = scancode::PAUSE,
+    /// Insert
+    Insert = scancode::INSERT,
+    /// Home
+    Home = scancode::HOME,
+    /// Page Up
+    PageUp = scancode::PAGE_UP,
+    /// Delete
+    Delete = scancode::DELETE,
+    /// End
+    End = scancode::END,
+    /// Page Down
+    PageDown = scancode::PAGE_DOWN,
+    /// Right Arrow
+    RightArrow = scancode::RIGHT_ARROW,
+    /// Left Arrow
+    LeftArrow = scancode::LEFT_ARROW,
+    /// Down Arrow
+    DownArrow = scancode::DOWN_ARROW,
+    /// Up Arrow
+    UpArrow = scancode::UP_ARROW,
+    /// Num Lock
+    NumLock = scancode::NUM_LOCK,
+    /// Keypad /
+    KpDivide = scancode::KP_DIVIDE,
+    /// Keypad *
+    KpMultiply = scancode::KP_MULTIPLY,
+    /// Keypad -
+    KpMinus = scancode::KP_MINUS,
+    /// Keypad +
+    KpPlus = scancode::KP_PLUS,
+    /// Keypad Enter
+    KpEnter = scancode::KP_ENTER,
+    /// Keypad 1/End
+    Kp1 = scancode::KP_1,
+    /// Keypad 2/Down
+    Kp2 = scancode::KP_2,
+    /// Keypad 3/PgDn
+    Kp3 = scancode::KP_3,
+    /// Keypad 4/Left
+    Kp4 = scancode::KP_4,
+    /// Keypad 5
+    Kp5 = scancode::KP_5,
+    /// Keypad 6/Right
+    Kp6 = scancode::KP_6,
+    /// Keypad 7/Home
+    Kp7 = scancode::KP_7,
+    /// Keypad 8/Up
+    Kp8 = scancode::KP_8,
+    /// Keypad 9/PgUp
+    Kp9 = scancode::KP_9,
+    /// Keypad 0/Ins
+    Kp0 = scancode::KP_0,
+    /// Keypad ./Del
+    KpDecimal = scancode::KP_DECIMAL,
+    /// Non-US Backslash
+    NonUsBackslash = scancode::NON_US_BACKSLASH,
+    /// Application/Menu
+    Application = scancode::APPLICATION,
+    /// Power
+    Power = scancode::POWER,
+    /// Keypad =
+    KpEqual = scancode::KP_EQUAL,
+    /// F13
+    F13 = scancode::F13,
+    /// F14
+    F14 = scancode::F14,
+    /// F15
+    F15 = scancode::F15,
+    /// F16
+    F16 = scancode::F16,
+    /// F17
+    F17 = scancode::F17,
+    /// F18
+    F18 = scancode::F18,
+    /// F19
+    F19 = scancode::F19,
+    /// F20
+    F20 = scancode::F20,
+    /// F21
+    F21 = scancode::F21,
+    /// F22
+    F22 = scancode::F22,
+    /// F23
+    F23 = scancode::F23,
+    /// F24
+    F24 = scancode::F24,
+    /// International1: Ro (`\` `_` on JIS keyboards)
+    IntlRo = scancode::INTL_RO,
+    /// International2: Katakana/Hiragana
+    KatakanaHiragana = scancode::KATAKANA_HIRAGANA,
+    /// International3: Yen (`¥` `|` on JIS keyboards)
+    IntlYen = scancode::INTL_YEN,
+    /// International4: Henkan (convert)
+    Henkan = scancode::HENKAN,
+    /// International5: Muhenkan (no convert)
+    Muhenkan = scancode::MUHENKAN,
+    /// International6: Keypad , on PC-98 keyboards
+    KpJpComma = scancode::KP_JP_COMMA,
+    /// International7
+    Intl7 = scancode::INTL_7,
+    /// International8
+    Intl8 = scancode::INTL_8,
+    /// International9
+    Intl9 = scancode::INTL_9,
+    /// LANG1: Hangul/English toggle, Kana on Mac JIS keyboards
+    Lang1 = scancode::LANG1,
+    /// LANG2: Hanja, Eisu on Mac JIS keyboards
+    Lang2 = scancode::LANG2,
+    /// LANG3: Katakana
+    Lang3 = scancode::LANG3,
+    /// LANG4: Hiragana
+    Lang4 = scancode::LANG4,
+    /// LANG5: Zenkaku/Hankaku
+    Lang5 = scancode::LANG5,
+    /// LANG6
+    Lang6 = scancode::LANG6,
+    /// LANG7
+    Lang7 = scancode::LANG7,
+    /// LANG8
+    Lang8 = scancode::LANG8,
+    /// LANG9
+    Lang9 = scancode::LANG9,
+    /// Left Control
+    LeftCtrl = scancode::LEFT_CTRL,
+    /// Left Shift
+    LeftShift = scancode::LEFT_SHIFT,
+    /// Left Alt
+    LeftAlt = scancode::LEFT_ALT,
+    /// Left GUI
+    LeftGui = scancode::LEFT_GUI,
+    /// Right Control
+    RightCtrl = scancode::RIGHT_CTRL,
+    /// Right Shift
+    RightShift = scancode::RIGHT_SHIFT,
+    /// Right Alt
+    RightAlt = scancode::RIGHT_ALT,
+    /// Right GUI
+    RightGui = scancode::RIGHT_GUI,
+}
+
+impl KeyCode {
+    /// Maps a USB HID scancode (Usage Page 0x07) to a key.
+    ///
+    /// Returns `None` for the error codes and usages without a key.
+    pub fn from_scancode(code: u8) -> Option<Self> {
+        use KeyCode::*;
+        const KEYS: [KeyCode; (scancode::F24 - scancode::A + 1) as usize] = [
+            A,
+            B,
+            C,
+            D,
+            E,
+            F,
+            G,
+            H,
+            I,
+            J,
+            K,
+            L,
+            M,
+            N,
+            O,
+            P,
+            Q,
+            R,
+            S,
+            T,
+            U,
+            V,
+            W,
+            X,
+            Y,
+            Z,
+            N1,
+            N2,
+            N3,
+            N4,
+            N5,
+            N6,
+            N7,
+            N8,
+            N9,
+            N0,
+            Enter,
+            Escape,
+            Backspace,
+            Tab,
+            Space,
+            Minus,
+            Equal,
+            LeftBracket,
+            RightBracket,
+            Backslash,
+            NonUsHash,
+            Semicolon,
+            Apostrophe,
+            Grave,
+            Comma,
+            Period,
+            Slash,
+            CapsLock,
+            F1,
+            F2,
+            F3,
+            F4,
+            F5,
+            F6,
+            F7,
+            F8,
+            F9,
+            F10,
+            F11,
+            F12,
+            PrintScreen,
+            ScrollLock,
+            Pause,
+            Insert,
+            Home,
+            PageUp,
+            Delete,
+            End,
+            PageDown,
+            RightArrow,
+            LeftArrow,
+            DownArrow,
+            UpArrow,
+            NumLock,
+            KpDivide,
+            KpMultiply,
+            KpMinus,
+            KpPlus,
+            KpEnter,
+            Kp1,
+            Kp2,
+            Kp3,
+            Kp4,
+            Kp5,
+            Kp6,
+            Kp7,
+            Kp8,
+            Kp9,
+            Kp0,
+            KpDecimal,
+            NonUsBackslash,
+            Application,
+            Power,
+            KpEqual,
+            F13,
+            F14,
+            F15,
+            F16,
+            F17,
+            F18,
+            F19,
+            F20,
+            F21,
+            F22,
+            F23,
+            F24,
+        ];
+        const INTL: [KeyCode; (scancode::LANG9 - scancode::INTL_RO + 1) as usize] = [
+            IntlRo,
+            KatakanaHiragana,
+            IntlYen,
+            Henkan,
+            Muhenkan,
+            KpJpComma,
+            Intl7,
+            Intl8,
+            Intl9,
+            Lang1,
+            Lang2,
+            Lang3,
+            Lang4,
+            Lang5,
+            Lang6,
+            Lang7,
+            Lang8,
+            Lang9,
+        ];
+        const MODIFIERS: [KeyCode; 8] = [
+            LeftCtrl, LeftShift, LeftAlt, LeftGui, RightCtrl, RightShift, RightAlt, RightGui,
+        ];
+
+        match code {
+            scancode::A..=scancode::F24 => Some(KEYS[(code - scancode::A) as usize]),
+            scancode::INTL_RO..=scancode::LANG9 => Some(INTL[(code - scancode::INTL_RO) as usize]),
+            scancode::LEFT_CTRL..=scancode::RIGHT_GUI => {
+                Some(MODIFIERS[(code - scancode::LEFT_CTRL) as usize])
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the USB HID scancode of the key.
+    pub fn scancode(self) -> u8 {
+        self as u8
+    }
+
+    /// Returns true for the keys switching or driving an input method.
+    ///
+    /// These are the Japanese Katakana/Hiragana, Henkan and Muhenkan keys
+    /// and LANG1 to LANG5, which carry the Korean Hangul and Hanja keys.
+    pub fn is_ime_key(self) -> bool {
+        use KeyCode::*;
+        matches!(
+            self,
+            KatakanaHiragana | Henkan | Muhenkan | Lang1 | Lang2 | Lang3 | Lang4 | Lang5
+        )
+    }
+
+    /// Returns true for the Ctrl, Shift, Alt and GUI keys.
+    pub fn is_modifier(self) -> bool {
+        self.scancode() >= scancode::LEFT_CTRL
+    }
+}
+
+/// Turns successive keyboard states into press and release events.
+#[cfg(feature = "alloc")]
+#[derive(Default)]
+pub(crate) struct KeyTracker {
+    /// State reported by the keyboard
+    state: KeyboardState,
+    /// State already turned into events
+    seen: KeyboardState,
+}
+
+#[cfg(feature = "alloc")]
+impl KeyTracker {
+    /// Take in the keys held according to a new report
+    pub fn update(&mut self, state: &KeyboardState) {
+        self.state = *state;
+    }
+
+    /// Next difference between the reported and seen state, as an event
+    ///
+    /// Modifiers come first, then releases, then presses. Usages without
+    /// a `KeyCode` are tracked but produce no event.
+    pub fn next_event(&mut self) -> Option<KeyEvent> {
+        loop {
+            let (code, pressed) =
+                if let Some(bit) = lowest_bit(self.state.modifiers ^ self.seen.modifiers) {
+                    let code = scancode::LEFT_CTRL + bit.trailing_zeros() as u8;
+                    (code, self.state.modifiers & bit != 0)
+                } else if let Some(code) = self.seen.first_key_not_in(&self.state) {
+                    (code, false)
+                } else if let Some(code) = self.state.first_key_not_in(&self.seen) {
+                    (code, true)
+                } else {
+                    return None;
+                };
+
+            if pressed {
+                self.seen.press(code);
+            } else {
+                self.seen.release(code);
+            }
+            if let Some(code) = KeyCode::from_scancode(code) {
+                return Some(KeyEvent {
+                    code,
+                    pressed,
+                    modifiers: self.seen.modifiers,
+                });
+            }
+        }
+    }
+
+    /// Forget all state, as after a reset
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// The lowest set bit of `bits`
+#[cfg(feature = "alloc")]
+fn lowest_bit(bits: u8) -> Option<u8> {
+    (bits != 0).then(|| bits & bits.wrapping_neg())
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::hid::KeyboardReport;
+    use alloc::vec::Vec;
+
+    #[test]
+    fn international_scancodes_map_to_keys() {
+        for code in scancode::INTL_RO..=scancode::LANG9 {
+            let key = KeyCode::from_scancode(code).expect("international key");
+            assert_eq!(key.scancode(), code);
+        }
+        assert_eq!(KeyCode::from_scancode(0x87), Some(KeyCode::IntlRo));
+        assert_eq!(KeyCode::from_scancode(0x89), Some(KeyCode::IntlYen));
+        assert_eq!(KeyCode::from_scancode(0x90), Some(KeyCode::Lang1));
+
+        let ime: Vec<KeyCode> = (scancode::INTL_RO..=scancode::LANG9)
+            .filter_map(KeyCode::from_scancode)
+            .filter(|key| key.is_ime_key())
+            .collect();
+        use KeyCode::*;
+        let expected = [
+            KatakanaHiragana,
+            Henkan,
+            Muhenkan,
+            Lang1,
+            Lang2,
+            Lang3,
+            Lang4,
+            Lang5,
+        ];
+        assert_eq!(ime, expected);
+    }
+
+    /// Events from a sequence of boot reports
+    fn events(reports: &[[u8; 8]]) -> Vec<(KeyCode, bool)> {
+        let mut tracker = KeyTracker::default();
+        let mut events = Vec::new();
+        for report in reports {
+            let state = KeyboardReport::parse(report).unwrap().state().unwrap();
+            tracker.update(&state);
+            while let Some(ev) = tracker.next_event() {
+                events.push((ev.code, ev.pressed));
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn jis_keyboard_reports() {
+        use KeyCode::*;
+        // A 109-key JIS board: Ro, Shift+Ro, Yen, then Henkan
+        let reports = [
+            [0, 0, 0x87, 0, 0, 0, 0, 0],
+            [0x02, 0, 0x87, 0, 0, 0, 0, 0],
+            [0, 0, 0x89, 0, 0, 0, 0, 0],
+            [0, 0, 0x8a, 0, 0, 0, 0, 0],
+            [0; 8],
+        ];
+        let expected = [
+            (IntlRo, true),
+            (LeftShift, true),
+            (LeftShift, false),
+            (IntlRo, false),
+            (IntlYen, true),
+            (IntlYen, false),
+            (Henkan, true),
+            (Henkan, false),
+        ];
+        assert_eq!(events(&reports), expected);
+
+        // Ro and Yen are not the US backslash key
+        let backslash = events(&[[0, 0, 0x31, 0, 0, 0, 0, 0]]);
+        assert_eq!(backslash, [(Backslash, true)]);
+    }
+
+    #[test]
+    fn korean_keyboard_reports() {
+        use KeyCode::*;
+        // Hangul and Hanja on a 103-key Korean board
+        let reports = [[0, 0, 0x90, 0, 0, 0, 0, 0], [0, 0, 0x91, 0, 0, 0, 0, 0]];
+        let expected = [(Lang1, true), (Lang1, false), (Lang2, true)];
+        assert_eq!(events(&reports), expected);
+    }
+}
@@ -9,46 +9,169 @@
 //! - xHCI controller initialization and management
 //! - USB device enumeration and configuration
 //! - HID (Human Interface Device) support for keyboards and mice
-//! - Mass Storage Class (MSC) with SCSI commands
+//! - HID report descriptor parsing, for NKRO keyboards beyond the boot protocol
+//! - Mass Storage Class (MSC) with SCSI commands over Bulk-Only and CBI
+//! - External hubs with interrupt-driven port change detection
+//! - Driver registry binding HID, MSC and user class drivers on enumeration
+//! - Hotplug monitor reporting attached and detached devices, hubs included
 //! - Comprehensive USB descriptor and class definitions
 //!
+//! The default `alloc` feature enables the controller, device and class
+//! drivers. Without it the crate only provides descriptor types,
+//! `SetupPacket`, register and class constants and the report structures,
+//! for use in environments without a heap.
+//!
+//! For hosts that forbid allocation after initialization, `StaticDma`
+//! serves all DMA memory from one region sized with
+//! `MappedXhci::memory_requirements`.
+//!
+//! The optional `defmt` feature implements `defmt::Format` for
+//! `DeviceSummary`.
+//!
+//! The optional `selftest` feature adds `run_controller_tests`, a
+//! checklist for validating board bring-up against known-good devices.
+//!
+//! The optional `bench` feature adds `MscDevice::benchmark`, which times
+//! block I/O patterns and reports throughput, IOPS and latency.
+//!
+//! The optional `input-traits` feature adds the `KeyInput` and
+//! `PointerInput` traits, so input consumers can be generic over USB and
+//! other keyboard and mouse drivers.
+//!
+//! The optional `trace` feature keeps the last `TRACE_ENTRIES` commands,
+//! transfer TRBs, doorbells, events and register writes in a lock-free
+//! ring, read back with `XhciCtrl::trace_snapshot` for post-mortem
+//! debugging.
+//!
+//! The optional `ffi` feature adds a C ABI in the `ffi` module: opaque,
+//! refcounted handles for the controller, the first mass storage device
+//! and the first keyboard, with `Dma` implemented by C callbacks. The
+//! header is `include/usb_oxide.h`.
+//!
+//! The optional `single-threaded` feature replaces the driver's spin locks
+//! with unsynchronized cells, for hosts that only ever use the driver
+//! from one context. The controller, devices and class drivers are then
+//! `!Sync`, and re-entering the driver while it holds a lock, e.g. from an
+//! interrupt or panic handler, panics instead of deadlocking. Combined
+//! with `ffi`, the C host must make every call from one context.
+//!
+//! # Ownership
+//!
+//! References only point down the stack: class drivers such as
+//! `HidDevice` and `MscDevice` hold an `Arc<UsbDevice>`, devices and
+//! their `EndpointHandle`s hold an `Arc<XhciCtrl>`, and the controller
+//! keeps only `Weak` references to its devices. Dropping the last driver
+//! of a device frees the device and its slot; dropping the last device
+//! lets the controller go.
+//!
+//! Restore and configuration hooks are passed the `UsbDevice` they run
+//! on; capturing an `Arc` of that device in one creates a cycle. Device
+//! tables kept by the OS should hold `Weak` references for the same
+//! reason. At shutdown, `XhciCtrl::release_all_devices` makes every
+//! device fail with `Disconnected` without waiting for its `Arc`s to drop.
+//!
 //! # Example
 //!
 //! ```ignore
 //! // Initialize xHCI controller
-//! let ctrl = XhciCtrl::new(mmio_phys, host)?;
-//! let ctrl = Arc::new(ctrl);
+//! let ctrl = Arc::new(XhciCtrl::new(mmio_phys, host)?);
 //!
 //! // Enumerate connected devices
-//! for port in 0..ctrl.max_ports() {
-//!     if ctrl.port_connected(port) {
-//!         let device = UsbDevice::new(ctrl.clone(), port)?;
-//!         // ... configure and use device
-//!     }
+//! for port in ctrl.connected_ports() {
+//!     let mut device = UsbDevice::new(ctrl.clone(), port.port)?;
+//!     device.get_device_descriptor()?;
+//!
+//!     // Configure it before sharing it with class drivers
+//!     let tree = device.config_tree_at(0)?;
+//!     let Some((iface, ep_in, ep_out)) = find_msc_interfaces(tree.raw()).into_iter().next() else {
+//!         continue;
+//!     };
+//!     device.set_configuration(tree.config.config_value)?;
+//!
+//!     let mut msc = MscDevice::from_interface(Arc::new(device), &iface, &ep_in, &ep_out)?;
+//!     let mut mbr = [0u8; 512];
+//!     msc.read_blocks(0, 0, 1, &mut mbr)?;
 //! }
 //! ```
+//!
+//! The `examples` directory has complete programs for enumeration, disk
+//! reads and keyboard input. They build on any `std` target but need
+//! identity-mapped physical memory to run.
 #![no_std]
 #![deny(missing_docs)]
+// Arcs of !Sync types are expected with `single-threaded`
+#![cfg_attr(feature = "single-threaded", allow(clippy::arc_with_non_send_sync))]
 
+#[cfg(feature = "alloc")]
 extern crate alloc;
+#[cfg(any(test, feature = "mock"))]
+extern crate std;
 
+#[cfg(feature = "alloc")]
+mod arena;
+#[cfg(feature = "alloc")]
+pub mod bandwidth;
+#[cfg(feature = "bench")]
+mod bench;
+#[cfg(feature = "alloc")]
+mod bind;
+#[cfg(feature = "alloc")]
+mod cbi;
 mod desc;
+#[cfg(feature = "alloc")]
 mod dev;
 mod err;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 mod hid;
+mod hub;
+#[cfg(feature = "input-traits")]
+mod input;
+#[cfg(feature = "alloc")]
+mod mmio;
+#[cfg(all(any(test, feature = "mock"), feature = "alloc"))]
+#[doc(hidden)]
+pub mod mock;
+#[cfg(feature = "alloc")]
+mod monitor;
 mod ram;
 mod msc;
 mod reg;
+#[cfg(feature = "alloc")]
+mod report;
 mod ring;
+#[cfg(feature = "selftest")]
+mod selftest;
+#[cfg(feature = "alloc")]
+mod sync;
+#[cfg(feature = "alloc")]
+mod topology;
+#[cfg(feature = "trace")]
+mod trace;
+#[cfg(feature = "alloc")]
 mod xhci;
 
 // Re-export main types
 pub use crate::{
-    dev::UsbDevice,
     err::{Result, UsbError},
     ram::Dma,
     ring::{PhysMem, Trb},
-    xhci::XhciCtrl,
+};
+
+#[cfg(feature = "alloc")]
+pub use crate::{
+    arena::{MemoryPlan, StaticDma},
+    dev::{
+        ConfigHook, Dci, DevicePath, DeviceSummary, DriverKind, EndpointHandle, EndpointState, EndpointStats,
+        EnumEvent, EnumObserver, EnumStep, EnumeratedDevice, ErrorPolicy, RestoreHook, RetryPolicy,
+        UsbDevice, default_config_policy,
+    },
+    topology::{TopologyDevice, TopologyNode},
+    xhci::{
+        Capabilities, DbcInfo, InterrupterHandle, LinkState, MappedXhci, OvercurrentPolicy,
+        PortChange, PortIndicator, PortSummary, Speed, StartError, StartStep, XhciBuilder,
+        XhciConfig, XhciCtrl, XhciQuirks,
+    },
 };
 
 // Re-export descriptor types and constants
@@ -56,18 +179,25 @@ pub use crate::desc::{
     // Descriptor structures
     BosDesc,
     ConfigDesc,
+    DescIter,
     DeviceDesc,
     DeviceQualifierDesc,
     EndpointDesc,
     HidDesc,
     HubDesc,
+    HubDescriptorFull,
     InterfaceAssocDesc,
     InterfaceDesc,
+    SelData,
     SetupPacket,
     SsDevCapDesc,
     SsEpCompDesc,
     SsHubDesc,
+    InterfaceSettingRef,
     Usb20ExtCapDesc,
+    // Functions
+    find_capability,
+    for_each_interface,
     // Constant modules
     capability,
     cdc_subclass,
@@ -91,45 +221,118 @@ pub use crate::desc::{
     request,
 };
 
+#[cfg(feature = "alloc")]
+pub use crate::desc::{ConfigTree, FoundInterface, InterfaceSetting, find_interfaces};
+
 // Re-export HID types and constants
 pub use crate::hid::{
     // Structures
-    HidDevice,
+    HidProtocol,
     HidType,
     KeyboardReport,
+    KeyboardState,
     MouseReport,
-    // Functions
-    find_hid_interfaces,
+    MouseReportEx,
     // Constant modules
     led,
     modifier,
     report_type,
     scancode,
+    for_each_hid_interface,
     scancode_to_ascii,
     usage_desktop,
     usage_page,
 };
 
+#[cfg(feature = "alloc")]
+pub use crate::hid::{HidDevice, NkroKeyboard, find_hid_configuration, find_hid_interfaces};
+
+// Re-export HID report descriptor types
+#[cfg(feature = "alloc")]
+pub use crate::report::{ReportDescriptor, ReportField, field_flags};
+
+// Re-export input traits
+#[cfg(feature = "input-traits")]
+pub use crate::input::{KeyCode, KeyEvent, KeyInput, PointerEvent, PointerInput};
+
+// Re-export hub types and constants
+pub use crate::hub::{
+    // Structures
+    HubStatus,
+    PortStatus,
+    // Functions
+    for_each_hub_interface,
+    // Constant modules
+    hub_status,
+    port_change,
+    port_status,
+};
+
+#[cfg(feature = "alloc")]
+pub use crate::hub::{HubChanges, HubDevice, PowerBudget, find_hub_interfaces};
+
 // Re-export MSC types and constants
 pub use crate::msc::{
     // Structures
+    BotPhase,
     Cbw,
     Csw,
+    DataPhase,
+    FormatParams,
+    FormatProgress,
     InquiryData,
-    MscDevice,
+    BlockGeometry,
     ReadCapacity10Data,
+    ReadCapacity16Data,
+    ReadyPolicy,
     RequestSenseData,
-    // Functions
-    find_msc_interfaces,
+    ScsiResult,
+    SmartAttribute,
+    SmartData,
+    UnitReadiness,
     // Constant modules
     scsi_op,
     sense_key,
+    // Functions
+    for_each_msc_interface,
+};
+
+#[cfg(feature = "alloc")]
+pub use crate::msc::{FormatHandle, MscDevice, find_cbi_interfaces, find_msc_interfaces};
+
+#[cfg(feature = "alloc")]
+pub use crate::cbi::{CbiDevice, CbiStatus};
+
+// Re-export driver registry types
+#[cfg(feature = "alloc")]
+pub use crate::bind::{
+    Binding, BoundDevice, ClassDriver, Driver, DriverRegistry, HidClassDriver, MscClassDriver,
 };
 
+// Re-export hotplug monitor types
+#[cfg(feature = "alloc")]
+pub use crate::monitor::{UsbEvent, UsbMonitor};
+
+// Re-export benchmark types
+#[cfg(feature = "bench")]
+pub use crate::bench::{BenchOp, BenchPattern, BenchPlan, BenchReport};
+
+// Re-export self-test types
+#[cfg(feature = "selftest")]
+pub use crate::selftest::{
+    MSC_TEST_BYTES, ScratchArea, SelfTestConfig, SelfTestOutcome, SelfTestReport, SelfTestResult,
+    run_controller_tests,
+};
+
+// Re-export trace types
+#[cfg(feature = "trace")]
+pub use crate::trace::{TRACE_ENTRIES, TraceEntry, TraceKind};
+
 // Re-export ring types and constants
 pub use crate::ring::{completion, trb_flags, trb_type};
 
 // Re-export device context types
+#[cfg(feature = "alloc")]
 pub use crate::dev::{DeviceContext, EndpointContext, InputContext, SlotContext};
 
 // Re-export register definitions (useful for advanced users)
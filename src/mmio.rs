@@ -0,0 +1,310 @@
+//! Typed access to the xHCI register file.
+//!
+//! Wraps the raw offsets in `reg` so callers do not compute register
+//! addresses by hand. Registers with write-1-to-clear bits get wrappers
+//! whose read-modify-write helpers never write those bits back by accident.
+
+use crate::reg;
+
+/// A 32-bit MMIO register
+#[derive(Clone, Copy)]
+pub(crate) struct Reg32(usize);
+
+impl Reg32 {
+    pub fn read(self) -> u32 {
+        #[cfg(any(test, feature = "mock"))]
+        if let Some(val) = crate::mock::mmio_read(self.0, 4) {
+            return val as u32;
+        }
+        unsafe { (self.0 as *const u32).read_volatile() }
+    }
+
+    pub fn write(self, val: u32) {
+        #[cfg(any(test, feature = "mock"))]
+        if crate::mock::mmio_write(self.0, 4, val as u64) {
+            return;
+        }
+        unsafe { (self.0 as *mut u32).write_volatile(val) }
+    }
+
+    /// Read, transform and write back the register, returning the value written
+    pub fn modify(self, f: impl FnOnce(u32) -> u32) -> u32 {
+        let val = f(self.read());
+        self.write(val);
+        val
+    }
+
+    /// Virtual address of the register
+    pub fn addr(self) -> usize {
+        self.0
+    }
+}
+
+/// A 64-bit MMIO register
+#[derive(Clone, Copy)]
+pub(crate) struct Reg64(usize);
+
+impl Reg64 {
+    pub fn read(self) -> u64 {
+        #[cfg(any(test, feature = "mock"))]
+        if let Some(val) = crate::mock::mmio_read(self.0, 8) {
+            return val;
+        }
+        unsafe { (self.0 as *const u64).read_volatile() }
+    }
+
+    pub fn write(self, val: u64) {
+        #[cfg(any(test, feature = "mock"))]
+        if crate::mock::mmio_write(self.0, 8, val) {
+            return;
+        }
+        unsafe { (self.0 as *mut u64).write_volatile(val) }
+    }
+
+    /// Virtual address of the register
+    pub fn addr(self) -> usize {
+        self.0
+    }
+}
+
+/// USB Command Register
+#[derive(Clone, Copy)]
+pub(crate) struct Usbcmd(Reg32);
+
+impl Usbcmd {
+    pub fn read(self) -> u32 {
+        self.0.read()
+    }
+
+    pub fn write(self, val: u32) {
+        self.0.write(val);
+    }
+
+    pub fn modify(self, f: impl FnOnce(u32) -> u32) -> u32 {
+        self.0.modify(f)
+    }
+
+    pub fn addr(self) -> usize {
+        self.0.addr()
+    }
+}
+
+/// USB Status Register
+#[derive(Clone, Copy)]
+pub(crate) struct Usbsts(Reg32);
+
+impl Usbsts {
+    pub fn read(self) -> u32 {
+        self.0.read()
+    }
+
+    /// Check if every bit of `bits` is set
+    pub fn contains(self, bits: u32) -> bool {
+        self.read() & bits == bits
+    }
+
+    /// Check if the register reads back all ones, as it does once the
+    /// controller is unplugged
+    pub fn is_gone(self) -> bool {
+        self.read() == u32::MAX
+    }
+
+    /// Clear the RW1C status bits in `bits`
+    pub fn clear(self, bits: u32) {
+        let rw1c = reg::USBSTS_HSE | reg::USBSTS_EINT | reg::USBSTS_PCD | reg::USBSTS_SRE;
+        self.0.write(bits & rw1c);
+    }
+}
+
+/// Command Ring Control Register
+#[derive(Clone, Copy)]
+pub(crate) struct Crcr(Reg64);
+
+impl Crcr {
+    /// Point the controller at a command ring with the given cycle state
+    ///
+    /// Only valid while the command ring is stopped.
+    pub fn set_ring(self, phys: u64, cycle: bool) {
+        self.0.write(phys | cycle as u64);
+    }
+
+    /// Abort the command being executed and stop the command ring
+    ///
+    /// The controller completes the aborted command with Command Aborted,
+    /// clears CRR and posts a Command Ring Stopped completion.
+    pub fn abort(self) {
+        self.0.write(reg::CRCR_CA);
+    }
+
+    /// Returns true while the command ring is running (CRR)
+    pub fn running(self) -> bool {
+        self.0.read() & reg::CRCR_CRR != 0
+    }
+
+    pub fn addr(self) -> usize {
+        self.0.addr()
+    }
+}
+
+/// Port Status and Control Register
+#[derive(Clone, Copy)]
+pub(crate) struct Portsc(Reg32);
+
+impl Portsc {
+    /// Bits where writing back a read 1 has a side effect (RW1C)
+    const RW1C: u32 = reg::PORTSC_PED | reg::PORTSC_CHANGE_MASK;
+
+    pub fn read(self) -> u32 {
+        self.0.read()
+    }
+
+    /// Write a raw value, RW1C bits included
+    pub fn write(self, val: u32) {
+        self.0.write(val);
+    }
+
+    /// Read, transform and write back PORTSC
+    ///
+    /// `f` sees the full register value. PED and the change bits are
+    /// masked out of its result, so they are never cleared by accident;
+    /// use `clear_changes` for that. Returns the value written.
+    pub fn modify(self, f: impl FnOnce(u32) -> u32) -> u32 {
+        let val = f(self.read()) & !Self::RW1C;
+        self.write(val);
+        val
+    }
+
+    /// Clear the change bits in `changes`, preserving the RW bits
+    pub fn clear_changes(self, changes: u32) {
+        let portsc = self.read();
+        self.write((portsc & reg::PORTSC_PRESERVE) | (changes & reg::PORTSC_CHANGE_MASK));
+    }
+
+    /// Disable the port by writing 1 to PED, preserving the RW bits
+    ///
+    /// Returns the value written.
+    pub fn disable(self) -> u32 {
+        let val = (self.read() & reg::PORTSC_PRESERVE) | reg::PORTSC_PED;
+        self.write(val);
+        val
+    }
+
+    pub fn addr(self) -> usize {
+        self.0.addr()
+    }
+}
+
+/// Interrupter Register Set
+#[derive(Clone, Copy)]
+pub(crate) struct Interrupter(usize);
+
+impl Interrupter {
+    pub fn iman(self) -> Reg32 {
+        Reg32(self.0 + reg::IMAN)
+    }
+
+    pub fn imod(self) -> Reg32 {
+        Reg32(self.0 + reg::IMOD)
+    }
+
+    pub fn erstsz(self) -> Reg32 {
+        Reg32(self.0 + reg::ERSTSZ)
+    }
+
+    pub fn erstba(self) -> Reg64 {
+        Reg64(self.0 + reg::ERSTBA)
+    }
+
+    pub fn erdp(self) -> Reg64 {
+        Reg64(self.0 + reg::ERDP)
+    }
+
+    /// Advance the dequeue pointer and clear Event Handler Busy
+    pub fn set_dequeue(self, phys: u64) {
+        self.erdp().write(phys | reg::ERDP_EHB);
+    }
+}
+
+/// The register file of a mapped xHCI controller
+pub(crate) struct RegisterBlock {
+    base: usize,
+    cap_length: u8,
+    rts_offset: u32,
+    db_offset: u32,
+}
+
+impl RegisterBlock {
+    /// Describe the registers of a controller mapped at `base`
+    ///
+    /// # Safety
+    ///
+    /// `base` must map the whole register file of the controller for as
+    /// long as the block is used.
+    pub unsafe fn new(base: usize, cap_length: u8, rts_offset: u32, db_offset: u32) -> Self {
+        Self {
+            base,
+            cap_length,
+            rts_offset,
+            db_offset,
+        }
+    }
+
+    /// A capability space register (capability or extended capability)
+    pub fn cap(&self, offset: usize) -> Reg32 {
+        Reg32(self.base + offset)
+    }
+
+    fn op(&self, offset: usize) -> usize {
+        self.base + self.cap_length as usize + offset
+    }
+
+    pub fn usbcmd(&self) -> Usbcmd {
+        Usbcmd(Reg32(self.op(reg::USBCMD)))
+    }
+
+    pub fn usbsts(&self) -> Usbsts {
+        Usbsts(Reg32(self.op(reg::USBSTS)))
+    }
+
+    pub fn crcr(&self) -> Crcr {
+        Crcr(Reg64(self.op(reg::CRCR)))
+    }
+
+    pub fn dcbaap(&self) -> Reg64 {
+        Reg64(self.op(reg::DCBAAP))
+    }
+
+    pub fn config(&self) -> Reg32 {
+        Reg32(self.op(reg::CONFIG))
+    }
+
+    /// Port registers, `port` being 0-based
+    pub fn portsc(&self, port: u8) -> Portsc {
+        Portsc(Reg32(self.port(port) + reg::PORTSC))
+    }
+
+    pub fn portpmsc(&self, port: u8) -> Reg32 {
+        Reg32(self.port(port) + reg::PORTPMSC)
+    }
+
+    pub fn porthlpmc(&self, port: u8) -> Reg32 {
+        Reg32(self.port(port) + reg::PORTHLPMC)
+    }
+
+    fn port(&self, port: u8) -> usize {
+        self.base + reg::port_reg_base(self.cap_length, port)
+    }
+
+    pub fn mfindex(&self) -> Reg32 {
+        Reg32(self.base + self.rts_offset as usize + reg::MFINDEX)
+    }
+
+    pub fn interrupter(&self, n: u16) -> Interrupter {
+        Interrupter(self.base + reg::interrupter_base(self.rts_offset, n))
+    }
+
+    /// Doorbell register of a slot (0 is the command ring)
+    pub fn doorbell(&self, slot: u8) -> Reg32 {
+        Reg32(self.base + reg::doorbell(self.db_offset, slot))
+    }
+}
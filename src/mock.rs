@@ -0,0 +1,2272 @@
+#![allow(dead_code)]
+
+//! Software model of an xHCI controller and its devices, for unit tests.
+//!
+//! The `mock` feature exports it for the examples' tests; it needs `std`
+//! and is not part of the supported API.
+//!
+//! `controller` starts an `XhciCtrl` on a register file kept in ordinary
+//! memory. Accesses through `mmio::Reg32` and `Reg64` that fall into it
+//! are routed here, so write-1-to-clear bits, doorbells and commands
+//! behave as on hardware. The model reads TRBs and moves data through the
+//! DMA memory handed out by `MockHost`, which poisons new and freed
+//! memory, quarantines freed memory and records every access to memory
+//! that is no longer allocated as a violation.
+//!
+//! Time is virtual: MFINDEX advances one microframe per read, and
+//! `Dma::delay_us` advances it by the delay.
+
+use std::{
+    alloc::{Layout, alloc, dealloc},
+    boxed::Box,
+    collections::{BTreeMap, VecDeque},
+    format,
+    string::String,
+    sync::{Arc, Mutex, MutexGuard, Weak},
+    vec,
+    vec::Vec,
+};
+
+use crate::{
+    Dma, XhciCtrl, XhciQuirks,
+    desc::{ConfigDesc, DeviceDesc, EndpointDesc, InterfaceDesc, SetupPacket, desc_type},
+    msc::{Cbw, Csw, scsi_op},
+    reg,
+    ring::{Trb, completion, trb_flags, trb_type},
+};
+
+/// Physical address the mock controller is "mapped" at
+pub const MOCK_PHYS: usize = 0xfe00_0000;
+/// Device slots of the mock controller
+pub const MAX_SLOTS: u8 = 8;
+/// Root ports of the mock controller
+pub const MAX_PORTS: u8 = 4;
+
+pub const CAP_LENGTH: usize = 0x20;
+const RTS_OFFSET: usize = 0x1000;
+pub const DB_OFFSET: usize = 0x2000;
+const MMIO_SIZE: usize = 0x10000;
+const EXT_CAPS: usize = 0x3000;
+
+const USBCMD: usize = CAP_LENGTH + reg::USBCMD;
+const USBSTS: usize = CAP_LENGTH + reg::USBSTS;
+const CRCR: usize = CAP_LENGTH + reg::CRCR;
+const DCBAAP: usize = CAP_LENGTH + reg::DCBAAP;
+const PORTS: usize = CAP_LENGTH + 0x400;
+const MFINDEX: usize = RTS_OFFSET + reg::MFINDEX;
+pub const IR0: usize = RTS_OFFSET + 0x20;
+
+/// Fill byte of new DMA memory, which `Dma::alloc` leaves uninitialized
+pub const ALLOCATED: u8 = 0x5a;
+/// Fill byte of freed DMA memory
+pub const FREED: u8 = 0x6b;
+/// Freed regions kept allocated, so late accesses can be detected
+const QUARANTINE: usize = 4096;
+
+/// Endpoint Context EP State values
+const EP_DISABLED: u8 = 0;
+pub const EP_RUNNING: u8 = 1;
+const EP_HALTED: u8 = 2;
+pub const EP_STOPPED: u8 = 3;
+
+/// How a device answers a transfer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Reply {
+    /// IN data (truncated to the request), or the OUT data accepted
+    Data(Vec<u8>),
+    /// Accept an OUT transfer or a request without data stage
+    Ack,
+    /// Handshake with STALL
+    Stall,
+    /// Keep NAKing; the transfer stays pending
+    Nak,
+    /// Fail with a completion code, e.g. a USB Transaction Error
+    Fail(u8),
+}
+
+/// A transfer seen by a device's handler
+pub enum Request<'a> {
+    /// Control transfer on EP0, with the OUT data stage
+    Control { setup: SetupPacket, data: &'a [u8] },
+    /// Transfer on another endpoint; `len` is the buffer size
+    Transfer { ep: u8, data: &'a [u8], len: usize },
+}
+
+type Handler = Box<dyn FnMut(&Request<'_>) -> Option<Reply> + Send>;
+
+/// A device attached to the mock controller
+///
+/// Standard requests are answered from `descriptors`; `faults` override
+/// the next control transfers and `handler` sees every transfer before
+/// the default handling. IN transfers on other endpoints take their
+/// replies from `inputs`, and NAK while it is empty.
+pub struct MockDevice {
+    /// PORTSC speed of the device
+    pub speed: u8,
+    /// Descriptors by wValue (type << 8 | index)
+    pub descriptors: Vec<(u16, Vec<u8>)>,
+    /// Time each transfer takes, in microseconds
+    pub latency_us: u32,
+    /// Replies overriding the next control transfers
+    pub faults: VecDeque<Reply>,
+    pub handler: Option<Handler>,
+    /// Every setup packet received, in order
+    pub setups: Vec<SetupPacket>,
+    /// Queued replies of non-control endpoints, by endpoint address
+    pub inputs: BTreeMap<u8, VecDeque<Reply>>,
+    /// OUT data received on non-control endpoints
+    pub outputs: Vec<(u8, Vec<u8>)>,
+    /// Value of the last SET_CONFIGURATION
+    pub config: u8,
+}
+
+impl MockDevice {
+    /// A device of `speed` with a device descriptor and configurations
+    pub fn new(speed: u8, device: DeviceDesc, configs: &[Vec<u8>]) -> Self {
+        let mut descriptors = vec![((desc_type::DEVICE as u16) << 8, device.to_bytes().to_vec())];
+        for (index, config) in configs.iter().enumerate() {
+            let value = ((desc_type::CONFIGURATION as u16) << 8) | index as u16;
+            descriptors.push((value, config.clone()));
+        }
+        Self {
+            speed,
+            descriptors,
+            latency_us: 0,
+            faults: VecDeque::new(),
+            handler: None,
+            setups: Vec::new(),
+            inputs: BTreeMap::new(),
+            outputs: Vec::new(),
+            config: 0,
+        }
+    }
+
+    /// Add a descriptor read with GET_DESCRIPTOR(`dtype`, `index`)
+    pub fn with_descriptor(mut self, dtype: u8, index: u8, bytes: Vec<u8>) -> Self {
+        self.descriptors
+            .push((((dtype as u16) << 8) | index as u16, bytes));
+        self
+    }
+
+    /// Add a string descriptor
+    pub fn with_string(self, index: u8, s: &str) -> Self {
+        let mut bytes = vec![0, desc_type::STRING];
+        for unit in s.encode_utf16() {
+            bytes.extend_from_slice(&unit.to_le_bytes());
+        }
+        bytes[0] = bytes.len() as u8;
+        self.with_descriptor(desc_type::STRING, index, bytes)
+    }
+
+    /// Let every transfer take `us` microseconds
+    pub fn with_latency(mut self, us: u32) -> Self {
+        self.latency_us = us;
+        self
+    }
+
+    /// See every transfer before the default handling
+    pub fn with_handler(
+        mut self,
+        handler: impl FnMut(&Request<'_>) -> Option<Reply> + Send + 'static,
+    ) -> Self {
+        self.handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Queue a reply for the next transfer on endpoint `ep`
+    pub fn push_input(&mut self, ep: u8, reply: Reply) {
+        self.inputs.entry(ep).or_default().push_back(reply);
+    }
+
+    fn control(&mut self, setup: SetupPacket, data: &[u8]) -> Reply {
+        self.setups.push(setup);
+        if let Some(reply) = self.faults.pop_front() {
+            return reply;
+        }
+        if let Some(handler) = &mut self.handler
+            && let Some(reply) = handler(&Request::Control { setup, data })
+        {
+            return reply;
+        }
+
+        let value = setup.value;
+        match (setup.request_type, setup.request) {
+            (0x80 | 0x81, 6) => match self.descriptors.iter().find(|(v, _)| *v == value) {
+                Some((_, bytes)) => {
+                    let mut bytes = bytes.clone();
+                    bytes.truncate(setup.length as usize);
+                    Reply::Data(bytes)
+                }
+                None => Reply::Stall,
+            },
+            (0x80, 8) => Reply::Data(vec![self.config]),
+            (0x00, 9) => {
+                self.config = value as u8;
+                Reply::Ack
+            }
+            (0x80..=0x82, 0) => Reply::Data(vec![0, 0]),
+            (0x00..=0x02, 1 | 3) | (0x01, 11) | (0x00, 5 | 0x30 | 0x31) => Reply::Ack,
+            // Class requests without data to return are accepted
+            (request_type, _) if request_type & 0xe0 == 0x20 => Reply::Ack,
+            _ => Reply::Stall,
+        }
+    }
+
+    fn transfer(&mut self, ep: u8, data: &[u8], len: usize) -> Reply {
+        if let Some(handler) = &mut self.handler
+            && let Some(reply) = handler(&Request::Transfer { ep, data, len })
+        {
+            return reply;
+        }
+        let queued = self.inputs.get_mut(&ep).and_then(|q| q.pop_front());
+        if ep & 0x80 != 0 {
+            return queued.unwrap_or(Reply::Nak);
+        }
+        match queued {
+            Some(Reply::Nak) => Reply::Nak,
+            Some(reply @ (Reply::Stall | Reply::Fail(_))) => reply,
+            _ => {
+                self.outputs.push((ep, data.to_vec()));
+                Reply::Ack
+            }
+        }
+    }
+}
+
+/// A device on a root port or, with a non-zero route, below hubs
+struct Attached {
+    root_port: u8,
+    route: u32,
+    device: MockDevice,
+    present: bool,
+}
+
+/// A TD gathered from a transfer ring
+struct Td {
+    trbs: Vec<(u64, Trb)>,
+    next: (u64, bool),
+}
+
+/// Transfer events of a TD that completes later
+struct Completion {
+    due: u64,
+    start: u64,
+    events: Vec<Trb>,
+}
+
+#[derive(Default)]
+struct Endpoint {
+    state: u8,
+    deq: u64,
+    cycle: bool,
+    /// The TD at the dequeue pointer is NAKed and retried every tick
+    nak: bool,
+    /// Virtual time at which the endpoint is done with its queued TDs
+    busy_until: u64,
+    completions: VecDeque<Completion>,
+    /// Buffers of the NAKed or in-flight TDs
+    buffers: Vec<(u64, usize)>,
+}
+
+struct Slot {
+    device: Option<usize>,
+    ctx: u64,
+    eps: Vec<Endpoint>,
+}
+
+impl Slot {
+    fn new() -> Self {
+        let mut eps = Vec::new();
+        eps.resize_with(32, Endpoint::default);
+        Self {
+            device: None,
+            ctx: 0,
+            eps,
+        }
+    }
+}
+
+struct Region {
+    size: usize,
+    align: usize,
+    live: bool,
+}
+
+struct State {
+    base: usize,
+    /// Virtual time, in microframes
+    clock: u64,
+    ticks_per_read: u64,
+    /// Virtual time in us at which the controller vanishes
+    removed_at: Option<u64>,
+    regions: BTreeMap<usize, Region>,
+    /// `Dma::alloc` calls so far
+    allocs: usize,
+    /// Successful allocations left before one fails, see `fail_allocation`
+    alloc_fault: Option<usize>,
+    quarantine: VecDeque<usize>,
+    violations: Vec<String>,
+    warnings: Vec<String>,
+    /// Register writes as (offset, value)
+    writes: Vec<(usize, u64)>,
+    /// PCI configuration space dwords by offset
+    pci_config: BTreeMap<u16, u32>,
+    devices: Vec<Attached>,
+    slots: BTreeMap<u8, Slot>,
+    cmd_deq: u64,
+    cmd_cycle: bool,
+    cmd_running: bool,
+    /// Leave the next commands pending until the ring is aborted
+    hang_commands: bool,
+    /// HCRST never completes
+    hang_reset: bool,
+    /// USBCMD.R/S leaves the controller halted
+    hang_start: bool,
+    /// Command left pending by `hang_commands`
+    hung: Option<u64>,
+    /// Completion codes forced on the next command of a type
+    command_faults: VecDeque<(u32, u8)>,
+    /// Commands processed, by TRB type
+    commands: Vec<u32>,
+    /// Input contexts of Evaluate Context commands, see `evaluated`
+    evaluated: Vec<(u8, [u32; 24])>,
+    /// TDs executed as (slot, DCI, TRB types), NAKed attempts excluded
+    tds: Vec<(u8, u8, Vec<u32>)>,
+    /// Scripted connection toggles as (virtual time in us, root port)
+    flaps: Vec<(u64, u8)>,
+    event_enq: usize,
+    event_cycle: bool,
+    event_full: bool,
+    events_dropped: u64,
+}
+
+impl State {
+    fn reg(&self, offset: usize) -> u32 {
+        unsafe { ((self.base + offset) as *const u32).read_volatile() }
+    }
+
+    fn set_reg(&mut self, offset: usize, val: u32) {
+        unsafe { ((self.base + offset) as *mut u32).write_volatile(val) }
+    }
+
+    fn reg64(&self, offset: usize) -> u64 {
+        self.reg(offset) as u64 | (self.reg(offset + 4) as u64) << 32
+    }
+
+    fn set_reg64(&mut self, offset: usize, val: u64) {
+        self.set_reg(offset, val as u32);
+        self.set_reg(offset + 4, (val >> 32) as u32);
+    }
+
+    fn running(&self) -> bool {
+        self.reg(USBSTS) & reg::USBSTS_HCH == 0
+    }
+
+    fn violation(&mut self, what: String) {
+        self.violations.push(what);
+    }
+
+    // ------------------------------------------------------------------
+    // DMA memory
+    // ------------------------------------------------------------------
+
+    /// Check `len` bytes at `addr` are live DMA memory
+    fn dma_ok(&mut self, addr: u64, len: usize, what: &str) -> bool {
+        let addr = addr as usize;
+        let found = self.regions.range(..=addr).next_back();
+        match found {
+            Some((&start, region)) if addr + len.max(1) <= start + region.size => {
+                if region.live {
+                    return true;
+                }
+                self.violation(format!("{what} of freed memory at {addr:#x}"));
+            }
+            _ => self.violation(format!("{what} of unknown memory at {addr:#x}")),
+        }
+        false
+    }
+
+    fn dma_read(&mut self, addr: u64, len: usize, what: &str) -> Option<Vec<u8>> {
+        if !self.dma_ok(addr, len, what) {
+            return None;
+        }
+        let mut buf = vec![0u8; len];
+        unsafe {
+            core::ptr::copy_nonoverlapping(addr as usize as *const u8, buf.as_mut_ptr(), len);
+        }
+        Some(buf)
+    }
+
+    fn dma_write(&mut self, addr: u64, data: &[u8], what: &str) -> bool {
+        if !self.dma_ok(addr, data.len(), what) {
+            return false;
+        }
+        unsafe {
+            core::ptr::copy_nonoverlapping(data.as_ptr(), addr as usize as *mut u8, data.len());
+        }
+        true
+    }
+
+    fn read_u32(&mut self, addr: u64) -> u32 {
+        self.dma_read(addr, 4, "context read")
+            .map_or(0, |b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn write_u32(&mut self, addr: u64, val: u32) {
+        self.dma_write(addr, &val.to_le_bytes(), "context write");
+    }
+
+    fn read_u64(&mut self, addr: u64) -> u64 {
+        self.read_u32(addr) as u64 | (self.read_u32(addr + 4) as u64) << 32
+    }
+
+    fn read_trb(&mut self, addr: u64, what: &str) -> Option<Trb> {
+        let b = self.dma_read(addr, 16, what)?;
+        Some(Trb {
+            param: u64::from_le_bytes(b[0..8].try_into().unwrap()),
+            status: u32::from_le_bytes(b[8..12].try_into().unwrap()),
+            control: u32::from_le_bytes(b[12..16].try_into().unwrap()),
+        })
+    }
+
+    fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        self.allocs += 1;
+        match self.alloc_fault {
+            Some(0) => {
+                self.alloc_fault = None;
+                return None;
+            }
+            Some(left) => self.alloc_fault = Some(left - 1),
+            None => {}
+        }
+        let layout = Layout::from_size_align(size.max(1), align).ok()?;
+        let addr = unsafe { alloc(layout) } as usize;
+        if addr == 0 {
+            return None;
+        }
+        unsafe {
+            core::ptr::write_bytes(addr as *mut u8, ALLOCATED, size.max(1));
+        }
+        self.regions.insert(
+            addr,
+            Region {
+                size: size.max(1),
+                align,
+                live: true,
+            },
+        );
+        Some(addr)
+    }
+
+    fn free(&mut self, addr: usize, size: usize) {
+        match self.regions.get(&addr) {
+            Some(region) if region.live => {}
+            Some(_) => return self.violation(format!("double free at {addr:#x}")),
+            None => return self.violation(format!("free of unknown memory at {addr:#x}")),
+        }
+
+        // Memory still referenced by an endpoint that will run again
+        let end = addr + size.max(1);
+        let within = |a: u64| (addr..end).contains(&(a as usize));
+        let mut users = Vec::new();
+        for (&slot_id, slot) in &self.slots {
+            if within(slot.ctx) {
+                users.push(format!("device context of slot {slot_id}"));
+            }
+            for (dci, ep) in slot.eps.iter().enumerate() {
+                let busy = ep.nak || !ep.completions.is_empty();
+                if ep.state != EP_RUNNING || !busy {
+                    continue;
+                }
+                if within(ep.deq) || ep.buffers.iter().any(|&(a, _)| within(a)) {
+                    users.push(format!("pending TD on slot {slot_id} DCI {dci}"));
+                }
+            }
+        }
+        for user in users {
+            self.violation(format!("freed {addr:#x} still used by {user}"));
+        }
+
+        unsafe {
+            core::ptr::write_bytes(addr as *mut u8, FREED, size.max(1));
+        }
+        if let Some(region) = self.regions.get_mut(&addr) {
+            region.live = false;
+        }
+        self.quarantine.push_back(addr);
+        if self.quarantine.len() > QUARANTINE
+            && let Some(old) = self.quarantine.pop_front()
+            && let Some(region) = self.regions.remove(&old)
+        {
+            unsafe {
+                dealloc(
+                    old as *mut u8,
+                    Layout::from_size_align_unchecked(region.size, region.align),
+                );
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Time and events
+    // ------------------------------------------------------------------
+
+    fn tick(&mut self, uframes: u64) {
+        let before = self.clock;
+        self.clock += uframes;
+        if self.removed() {
+            return;
+        }
+        if self.running() && self.reg(USBCMD) & reg::USBCMD_EWE != 0 {
+            for _ in (before >> 14)..(self.clock >> 14) {
+                self.post_event(Trb {
+                    param: 0,
+                    status: (completion::SUCCESS as u32) << 24,
+                    control: trb_type::MFINDEX_WRAP << 10,
+                });
+            }
+        }
+        self.flap();
+        self.service();
+    }
+
+    /// Toggle the connections whose scripted time has come
+    fn flap(&mut self) {
+        let now = self.clock * 125;
+        let due: Vec<u8> = self
+            .flaps
+            .iter()
+            .filter(|f| f.0 <= now)
+            .map(|f| f.1)
+            .collect();
+        self.flaps.retain(|f| f.0 > now);
+        for port in due {
+            let portsc = self.portsc(port);
+            let toggled = if portsc & reg::PORTSC_CCS != 0 {
+                portsc & !(reg::PORTSC_CCS | reg::PORTSC_PED)
+            } else {
+                portsc | reg::PORTSC_CCS
+            };
+            self.set_portsc(port, toggled | reg::PORTSC_CSC);
+            self.port_event(port);
+        }
+    }
+
+    /// Post the completions that are due and retry NAKed transfers
+    fn service(&mut self) {
+        if self.removed() {
+            return;
+        }
+        let clock = self.clock;
+        let mut due = Vec::new();
+        let mut naks = Vec::new();
+        for (&slot_id, slot) in &mut self.slots {
+            for (dci, ep) in slot.eps.iter_mut().enumerate() {
+                while ep.completions.front().is_some_and(|c| c.due <= clock) {
+                    let done = ep.completions.pop_front().unwrap();
+                    due.push((done.due, done.events));
+                }
+                if ep.completions.is_empty() && !ep.nak {
+                    ep.buffers.clear();
+                }
+                if ep.nak {
+                    naks.push((slot_id, dci as u8));
+                }
+            }
+        }
+        due.sort_by_key(|(due, _)| *due);
+        for (_, events) in due {
+            for event in events {
+                self.post_event(event);
+            }
+        }
+        for (slot_id, dci) in naks {
+            self.run_endpoint(slot_id, dci);
+        }
+    }
+
+    fn post_event(&mut self, mut trb: Trb) {
+        if !self.running() {
+            return;
+        }
+        let erst = self.reg64(IR0 + reg::ERSTBA);
+        let seg = self.read_u64(erst);
+        let size = (self.read_u32(erst + 8) & 0xffff) as usize;
+        if size == 0 {
+            return;
+        }
+        let erdp = self.reg64(IR0 + reg::ERDP) & !0xf;
+        let deq = (erdp.wrapping_sub(seg) / 16) as usize % size;
+
+        // One slot always stays free; the last one takes the Event Ring
+        // Full error
+        let free = (deq + size - self.event_enq - 1) % size;
+        if self.event_full || free == 0 {
+            self.events_dropped += 1;
+            return;
+        }
+        if free == 1 {
+            self.event_full = true;
+            self.events_dropped += 1;
+            trb = Trb {
+                param: 0,
+                status: (completion::EVENT_RING_FULL as u32) << 24,
+                control: trb_type::HOST_CONTROLLER_EVENT << 10,
+            };
+        }
+
+        trb.set_cycle(self.event_cycle);
+        let addr = seg + (self.event_enq * 16) as u64;
+        let mut bytes = [0u8; 16];
+        bytes[0..8].copy_from_slice(&trb.param.to_le_bytes());
+        bytes[8..12].copy_from_slice(&trb.status.to_le_bytes());
+        bytes[12..16].copy_from_slice(&trb.control.to_le_bytes());
+        self.dma_write(addr, &bytes, "event write");
+        self.event_enq += 1;
+        if self.event_enq == size {
+            self.event_enq = 0;
+            self.event_cycle = !self.event_cycle;
+        }
+
+        let iman = self.reg(IR0 + reg::IMAN);
+        self.set_reg(IR0 + reg::IMAN, iman | reg::IMAN_IP);
+        let sts = self.reg(USBSTS);
+        self.set_reg(USBSTS, sts | reg::USBSTS_EINT);
+    }
+
+    fn transfer_event(slot: u8, dci: u8, trb: u64, code: u8, residual: usize) -> Trb {
+        Trb {
+            param: trb,
+            status: ((code as u32) << 24) | (residual as u32 & 0xff_ffff),
+            control: (trb_type::TRANSFER_EVENT << 10)
+                | ((dci as u32) << 16)
+                | ((slot as u32) << 24),
+        }
+    }
+
+    fn port_event(&mut self, port: u8) {
+        self.post_event(Trb {
+            param: ((port as u64) + 1) << 24,
+            status: (completion::SUCCESS as u32) << 24,
+            control: trb_type::PORT_STATUS_CHANGE << 10,
+        });
+        let sts = self.reg(USBSTS);
+        self.set_reg(USBSTS, sts | reg::USBSTS_PCD);
+    }
+
+    // ------------------------------------------------------------------
+    // Registers
+    // ------------------------------------------------------------------
+
+    /// True once the controller vanished with `Mock::remove_at`
+    fn removed(&self) -> bool {
+        self.removed_at.is_some_and(|at| self.clock * 125 >= at)
+    }
+
+    fn read(&mut self, offset: usize, size: usize) -> u64 {
+        if self.removed() {
+            return if size == 8 { u64::MAX } else { u32::MAX as u64 };
+        }
+        match offset {
+            MFINDEX => {
+                let ticks = self.ticks_per_read;
+                self.tick(ticks);
+                self.clock & reg::MFINDEX_MASK as u64
+            }
+            USBSTS => {
+                self.service();
+                self.reg(USBSTS) as u64
+            }
+            CRCR => {
+                if self.cmd_running {
+                    reg::CRCR_CRR
+                } else {
+                    0
+                }
+            }
+            _ if size == 8 => self.reg64(offset),
+            _ => self.reg(offset) as u64,
+        }
+    }
+
+    fn write(&mut self, offset: usize, size: usize, val: u64) {
+        self.writes.push((offset, val));
+        if self.removed() {
+            return;
+        }
+        match offset {
+            USBCMD => self.write_usbcmd(val as u32),
+            USBSTS => {
+                let rw1c = reg::USBSTS_HSE | reg::USBSTS_EINT | reg::USBSTS_PCD | reg::USBSTS_SRE;
+                let sts = self.reg(USBSTS) & !(val as u32 & rw1c);
+                self.set_reg(USBSTS, sts);
+            }
+            CRCR => self.write_crcr(val),
+            _ if (PORTS..PORTS + 0x10 * MAX_PORTS as usize).contains(&offset)
+                && (offset - PORTS).is_multiple_of(0x10) =>
+            {
+                self.write_portsc(((offset - PORTS) / 0x10) as u8, val as u32);
+            }
+            _ if offset == IR0 + reg::IMAN => {
+                // IP is RW1C, IE is RW
+                let iman = self.reg(offset);
+                let ip = iman & reg::IMAN_IP & !(val as u32);
+                self.set_reg(offset, ip | (val as u32 & reg::IMAN_IE));
+            }
+            _ if offset == IR0 + reg::ERDP => {
+                let ehb = self.reg64(offset) & reg::ERDP_EHB & !val;
+                self.set_reg64(offset, (val & !reg::ERDP_EHB) | ehb);
+                self.event_full = false;
+            }
+            _ if offset == IR0 + reg::ERSTBA => {
+                self.set_reg64(offset, val);
+                self.event_enq = 0;
+                self.event_cycle = true;
+                self.event_full = false;
+            }
+            _ if (DB_OFFSET..=DB_OFFSET + 4 * MAX_SLOTS as usize).contains(&offset) => {
+                let slot = ((offset - DB_OFFSET) / 4) as u8;
+                if slot == 0 {
+                    self.cmd_running = true;
+                    self.run_commands();
+                } else {
+                    self.ring_doorbell(slot, val as u8);
+                }
+            }
+            _ if size == 8 => self.set_reg64(offset, val),
+            _ => self.set_reg(offset, val as u32),
+        }
+    }
+
+    fn write_usbcmd(&mut self, val: u32) {
+        if val & reg::USBCMD_HCRST != 0 {
+            self.reset();
+            if self.hang_reset {
+                self.set_reg(USBCMD, reg::USBCMD_HCRST);
+                self.set_reg(USBSTS, reg::USBSTS_HCH | reg::USBSTS_CNR);
+            }
+            return;
+        }
+        self.set_reg(USBCMD, val);
+        let sts = self.reg(USBSTS);
+        if val & reg::USBCMD_RUN != 0 && !self.hang_start {
+            self.set_reg(USBSTS, sts & !reg::USBSTS_HCH);
+        } else {
+            self.set_reg(USBSTS, sts | reg::USBSTS_HCH);
+            self.cmd_running = false;
+        }
+    }
+
+    fn reset(&mut self) {
+        self.set_reg(USBCMD, 0);
+        self.set_reg(USBSTS, reg::USBSTS_HCH);
+        for offset in [CRCR, DCBAAP, IR0 + reg::ERSTBA, IR0 + reg::ERDP] {
+            self.set_reg64(offset, 0);
+        }
+        self.set_reg(IR0 + reg::IMAN, 0);
+        self.slots.clear();
+        self.cmd_running = false;
+        self.cmd_deq = 0;
+        self.event_enq = 0;
+        self.event_cycle = true;
+        self.event_full = false;
+        for port in 0..MAX_PORTS {
+            let portsc = self.portsc(port) & !reg::PORTSC_PED;
+            self.set_portsc(port, portsc);
+        }
+    }
+
+    fn write_crcr(&mut self, val: u64) {
+        if val & (reg::CRCR_CA | reg::CRCR_CS) != 0 {
+            if !self.cmd_running {
+                return;
+            }
+            if val & reg::CRCR_CA != 0
+                && let Some(addr) = self.hung.take()
+            {
+                self.command_done(addr, completion::COMMAND_ABORTED, 0);
+                self.cmd_deq = addr + 16;
+            }
+            self.cmd_running = false;
+            let param = self.cmd_deq;
+            self.command_done(param, completion::COMMAND_RING_STOPPED, 0);
+            return;
+        }
+        if !self.cmd_running {
+            self.cmd_deq = val & !0xf;
+            self.cmd_cycle = val & 1 != 0;
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Ports
+    // ------------------------------------------------------------------
+
+    fn portsc(&self, port: u8) -> u32 {
+        self.reg(PORTS + 0x10 * port as usize)
+    }
+
+    fn set_portsc(&mut self, port: u8, val: u32) {
+        self.set_reg(PORTS + 0x10 * port as usize, val);
+    }
+
+    fn root_device(&self, port: u8) -> Option<usize> {
+        self.devices
+            .iter()
+            .position(|d| d.present && d.root_port == port && d.route == 0)
+    }
+
+    fn write_portsc(&mut self, port: u8, val: u32) {
+        let old = self.portsc(port);
+        // Change bits and PED are RW1C
+        let mut new = old & !(val & reg::PORTSC_CHANGE_MASK);
+        if val & reg::PORTSC_PED != 0 {
+            new &= !reg::PORTSC_PED;
+        }
+        let rw = reg::PORTSC_PP
+            | reg::PORTSC_PIC_MASK
+            | reg::PORTSC_WCE
+            | reg::PORTSC_WDE
+            | reg::PORTSC_WOE;
+        new = (new & !rw) | (val & rw);
+
+        let mut changed = false;
+        let device = self.root_device(port);
+        if old & reg::PORTSC_PP != 0 && new & reg::PORTSC_PP == 0 {
+            // Powered off: the device disconnects
+            if new & reg::PORTSC_CCS != 0 {
+                new |= reg::PORTSC_CSC;
+                changed = true;
+            }
+            new &= !(reg::PORTSC_CCS | reg::PORTSC_PED | reg::PORTSC_SPEED_MASK);
+        } else if old & reg::PORTSC_PP == 0
+            && new & reg::PORTSC_PP != 0
+            && let Some(index) = device
+        {
+            new |= reg::PORTSC_CCS | reg::PORTSC_CSC;
+            new |= (self.devices[index].device.speed as u32) << 10;
+            changed = true;
+        }
+
+        if val & (reg::PORTSC_PR | reg::PORTSC_WPR) != 0 && new & reg::PORTSC_CCS != 0 {
+            // Resets complete at once
+            new |= reg::PORTSC_PED | reg::PORTSC_PRC;
+            if val & reg::PORTSC_WPR != 0 {
+                new |= reg::PORTSC_WRC;
+            }
+            new = (new & !reg::PORTSC_PLS_MASK) | reg::portsc_set_pls(reg::PLS_U0);
+            changed = true;
+        }
+        if val & reg::PORTSC_LWS != 0 {
+            let pls = (val & reg::PORTSC_PLS_MASK) >> 5;
+            let from = reg::portsc_pls(old) as u32;
+            // Resume goes through U0 right away
+            let pls = if pls == reg::PLS_RESUME {
+                reg::PLS_U0
+            } else {
+                pls
+            };
+            new = (new & !reg::PORTSC_PLS_MASK) | reg::portsc_set_pls(pls);
+            if pls == reg::PLS_U0 && from == reg::PLS_U3 {
+                new |= reg::PORTSC_PLC;
+                changed = true;
+            }
+        }
+        self.set_portsc(port, new);
+        if changed {
+            self.port_event(port);
+        }
+    }
+
+    fn attach(&mut self, root_port: u8, route: u32, device: MockDevice) {
+        let speed = device.speed;
+        self.devices.push(Attached {
+            root_port,
+            route,
+            device,
+            present: true,
+        });
+        if route != 0 {
+            return;
+        }
+        let portsc = self.portsc(root_port);
+        if portsc & reg::PORTSC_PP != 0 {
+            let portsc = portsc & !(reg::PORTSC_PLS_MASK | reg::PORTSC_SPEED_MASK);
+            self.set_portsc(
+                root_port,
+                portsc
+                    | reg::PORTSC_CCS
+                    | reg::PORTSC_CSC
+                    | ((speed as u32) << 10)
+                    | reg::portsc_set_pls(reg::PLS_POLLING),
+            );
+            self.port_event(root_port);
+        }
+    }
+
+    fn detach(&mut self, root_port: u8, route: u32) {
+        for d in &mut self.devices {
+            if d.root_port == root_port && d.route == route {
+                d.present = false;
+            }
+        }
+        if route == 0 {
+            let portsc = self.portsc(root_port);
+            if portsc & reg::PORTSC_CCS != 0 {
+                let cleared = portsc & !(reg::PORTSC_CCS | reg::PORTSC_PED);
+                self.set_portsc(root_port, cleared | reg::PORTSC_CSC);
+                self.port_event(root_port);
+            }
+        }
+    }
+
+    // ------------------------------------------------------------------
+    // Commands
+    // ------------------------------------------------------------------
+
+    fn command_done(&mut self, addr: u64, code: u8, slot: u8) {
+        self.post_event(Trb {
+            param: addr,
+            status: (code as u32) << 24,
+            control: (trb_type::COMMAND_COMPLETION << 10) | ((slot as u32) << 24),
+        });
+    }
+
+    fn run_commands(&mut self) {
+        while self.cmd_running && self.hung.is_none() {
+            let addr = self.cmd_deq;
+            let Some(trb) = self.read_trb(addr, "command read") else {
+                return;
+            };
+            if trb.cycle() != self.cmd_cycle {
+                return;
+            }
+            let ty = trb.trb_type() as u32;
+            if ty == trb_type::LINK {
+                if trb.control & trb_flags::TOGGLE_CYCLE != 0 {
+                    self.cmd_cycle = !self.cmd_cycle;
+                }
+                self.cmd_deq = trb.param & !0xf;
+                continue;
+            }
+            if self.hang_commands {
+                self.hung = Some(addr);
+                return;
+            }
+            self.commands.push(ty);
+            let fault = self.command_faults.iter().position(|&(t, _)| t == ty);
+            let (code, slot) = match fault.and_then(|i| self.command_faults.remove(i)) {
+                Some((_, code)) => (code, trb.slot_id()),
+                None => self.execute(ty, &trb),
+            };
+            self.cmd_deq = addr + 16;
+            self.command_done(addr, code, slot);
+        }
+    }
+
+    fn output_ctx(&mut self, slot_id: u8) -> u64 {
+        let dcbaa = self.reg64(DCBAAP);
+        self.read_u64(dcbaa + 8 * slot_id as u64)
+    }
+
+    fn set_ep_state(&mut self, slot_id: u8, dci: u8, state: u8) {
+        let Some(slot) = self.slots.get_mut(&slot_id) else {
+            return;
+        };
+        let ep = &mut slot.eps[dci as usize];
+        ep.state = state;
+        let (ctx, deq) = (slot.ctx, ep.deq | ep.cycle as u64);
+        let addr = ctx + 32 * dci as u64;
+        let dw0 = self.read_u32(addr);
+        self.write_u32(addr, (dw0 & !0x7) | state as u32);
+        self.write_u32(addr + 8, deq as u32);
+        self.write_u32(addr + 12, (deq >> 32) as u32);
+    }
+
+    /// Copy the Endpoint Context of `dci` from the Input Context and enable it
+    fn add_endpoint(&mut self, slot_id: u8, input: u64, dci: u8) {
+        let ctx = self.slots[&slot_id].ctx;
+        let src = input + 32 + 32 * dci as u64;
+        if let Some(bytes) = self.dma_read(src, 32, "input context read") {
+            self.dma_write(ctx + 32 * dci as u64, &bytes, "context write");
+        }
+        let deq = self.read_u64(src + 8);
+        let ep = &mut self.slots.get_mut(&slot_id).unwrap().eps[dci as usize];
+        *ep = Endpoint {
+            deq: deq & !0xf,
+            cycle: deq & 1 != 0,
+            ..Endpoint::default()
+        };
+        self.set_ep_state(slot_id, dci, EP_RUNNING);
+    }
+
+    fn drop_endpoint(&mut self, slot_id: u8, dci: u8) {
+        if let Some(slot) = self.slots.get_mut(&slot_id) {
+            slot.eps[dci as usize] = Endpoint::default();
+        }
+        self.set_ep_state(slot_id, dci, EP_DISABLED);
+    }
+
+    fn execute(&mut self, ty: u32, trb: &Trb) -> (u8, u8) {
+        let slot_id = trb.slot_id();
+        let dci = trb.endpoint_id();
+        if ty == trb_type::ENABLE_SLOT {
+            let free = (1..=MAX_SLOTS).find(|id| !self.slots.contains_key(id));
+            return match free {
+                Some(id) => {
+                    self.slots.insert(id, Slot::new());
+                    (completion::SUCCESS, id)
+                }
+                None => (completion::NO_SLOTS_AVAILABLE, 0),
+            };
+        }
+        let needs_slot = matches!(
+            ty,
+            trb_type::DISABLE_SLOT
+                | trb_type::ADDRESS_DEVICE
+                | trb_type::CONFIGURE_ENDPOINT
+                | trb_type::EVALUATE_CONTEXT
+                | trb_type::RESET_ENDPOINT
+                | trb_type::STOP_ENDPOINT
+                | trb_type::SET_TR_DEQUEUE
+                | trb_type::RESET_DEVICE
+        );
+        if needs_slot && !self.slots.contains_key(&slot_id) {
+            return (completion::SLOT_NOT_ENABLED, slot_id);
+        }
+
+        let code = match ty {
+            trb_type::DISABLE_SLOT => {
+                self.slots.remove(&slot_id);
+                completion::SUCCESS
+            }
+            trb_type::ADDRESS_DEVICE => self.address_device(slot_id, trb.param),
+            trb_type::CONFIGURE_ENDPOINT => {
+                let input = trb.param;
+                let ctx = self.slots[&slot_id].ctx;
+                if trb.control & trb_flags::DECONFIGURE != 0 {
+                    for dci in 2..32 {
+                        self.drop_endpoint(slot_id, dci);
+                    }
+                } else {
+                    let drop_flags = self.read_u32(input);
+                    let add_flags = self.read_u32(input + 4);
+                    for dci in 2..32u8 {
+                        if drop_flags & (1 << dci) != 0 {
+                            self.drop_endpoint(slot_id, dci);
+                        }
+                        if add_flags & (1 << dci) != 0 {
+                            self.add_endpoint(slot_id, input, dci);
+                        }
+                    }
+                    if add_flags & 1 != 0 {
+                        for dw in 0..3 {
+                            let val = self.read_u32(input + 32 + 4 * dw);
+                            self.write_u32(ctx + 4 * dw, val);
+                        }
+                    }
+                }
+                completion::SUCCESS
+            }
+            trb_type::EVALUATE_CONTEXT => {
+                let input = trb.param;
+                let ctx = self.slots[&slot_id].ctx;
+                let dwords = core::array::from_fn(|i| self.read_u32(input + 4 * i as u64));
+                self.evaluated.push((slot_id, dwords));
+                let add_flags = self.read_u32(input + 4);
+                if add_flags & 1 != 0 {
+                    for dw in 1..3 {
+                        let val = self.read_u32(input + 32 + 4 * dw);
+                        self.write_u32(ctx + 4 * dw, val);
+                    }
+                }
+                if add_flags & 2 != 0 {
+                    let val = self.read_u32(input + 64 + 4);
+                    self.write_u32(ctx + 32 + 4, val);
+                }
+                completion::SUCCESS
+            }
+            trb_type::RESET_ENDPOINT => {
+                if self.slots[&slot_id].eps[dci as usize].state != EP_HALTED {
+                    completion::CONTEXT_STATE_ERROR
+                } else {
+                    self.set_ep_state(slot_id, dci, EP_STOPPED);
+                    completion::SUCCESS
+                }
+            }
+            trb_type::STOP_ENDPOINT => self.stop_endpoint(slot_id, dci),
+            trb_type::SET_TR_DEQUEUE => {
+                let state = self.slots[&slot_id].eps[dci as usize].state;
+                if state != EP_STOPPED {
+                    completion::CONTEXT_STATE_ERROR
+                } else {
+                    let ep = &mut self.slots.get_mut(&slot_id).unwrap().eps[dci as usize];
+                    ep.deq = trb.param & !0xf;
+                    ep.cycle = trb.param & 1 != 0;
+                    ep.nak = false;
+                    ep.completions.clear();
+                    ep.buffers.clear();
+                    self.set_ep_state(slot_id, dci, EP_STOPPED);
+                    completion::SUCCESS
+                }
+            }
+            trb_type::RESET_DEVICE => {
+                for dci in 2..32 {
+                    self.drop_endpoint(slot_id, dci);
+                }
+                completion::SUCCESS
+            }
+            trb_type::NO_OP_CMD | trb_type::FORCE_HEADER => completion::SUCCESS,
+            _ => completion::TRB_ERROR,
+        };
+        (code, slot_id)
+    }
+
+    fn address_device(&mut self, slot_id: u8, input: u64) -> u8 {
+        let route = self.read_u32(input + 32) & 0xfffff;
+        let root_port = ((self.read_u32(input + 36) >> 16) & 0xff) as u8;
+        let Some(index) = self
+            .devices
+            .iter()
+            .position(|d| d.present && d.root_port + 1 == root_port && d.route == route)
+        else {
+            return completion::USB_TRANSACTION_ERROR;
+        };
+
+        let ctx = self.output_ctx(slot_id);
+        let slot = self.slots.get_mut(&slot_id).unwrap();
+        slot.device = Some(index);
+        slot.ctx = ctx;
+        if let Some(bytes) = self.dma_read(input + 32, 32, "input context read") {
+            self.dma_write(ctx, &bytes, "context write");
+        }
+        // Addressed
+        self.write_u32(ctx + 12, (2 << 27) | slot_id as u32);
+        self.add_endpoint(slot_id, input, 1);
+        completion::SUCCESS
+    }
+
+    fn stop_endpoint(&mut self, slot_id: u8, dci: u8) -> u8 {
+        let ep = &mut self.slots.get_mut(&slot_id).unwrap().eps[dci as usize];
+        if ep.state != EP_RUNNING {
+            return completion::CONTEXT_STATE_ERROR;
+        }
+        // The TD in progress, if any, stops where it is
+        let stopped = match ep.completions.front() {
+            Some(c) => Some(c.start),
+            None if ep.nak => Some(ep.deq),
+            None => None,
+        };
+        if let Some(start) = stopped {
+            if let Some(first) = ep.completions.front() {
+                ep.deq = first.start;
+            }
+            ep.completions.clear();
+            ep.nak = false;
+            ep.buffers.clear();
+            let len = self
+                .read_trb(start, "transfer read")
+                .map_or(0, |t| t.status & 0x1ffff);
+            let event =
+                Self::transfer_event(slot_id, dci, start, completion::STOPPED, len as usize);
+            self.post_event(event);
+        }
+        self.set_ep_state(slot_id, dci, EP_STOPPED);
+        completion::SUCCESS
+    }
+
+    // ------------------------------------------------------------------
+    // Transfers
+    // ------------------------------------------------------------------
+
+    fn ring_doorbell(&mut self, slot_id: u8, dci: u8) {
+        let Some(slot) = self.slots.get(&slot_id) else {
+            return;
+        };
+        let Some(ep) = slot.eps.get(dci as usize) else {
+            return;
+        };
+        if ep.state == EP_STOPPED {
+            self.set_ep_state(slot_id, dci, EP_RUNNING);
+        }
+        self.run_endpoint(slot_id, dci);
+    }
+
+    fn gather(&mut self, mut addr: u64, mut cycle: bool, ep0: bool) -> Option<Td> {
+        let mut trbs = Vec::new();
+        for _ in 0..4096 {
+            let trb = self.read_trb(addr, "transfer read")?;
+            if trb.cycle() != cycle {
+                return None;
+            }
+            if trb.trb_type() as u32 == trb_type::LINK {
+                if trb.control & trb_flags::TOGGLE_CYCLE != 0 {
+                    cycle = !cycle;
+                }
+                addr = trb.param & !0xf;
+                continue;
+            }
+            trbs.push((addr, trb));
+            addr += 16;
+            let last = if ep0 {
+                trb.trb_type() as u32 == trb_type::STATUS
+            } else {
+                trb.control & trb_flags::CHAIN == 0
+            };
+            if last {
+                return Some(Td {
+                    trbs,
+                    next: (addr, cycle),
+                });
+            }
+        }
+        None
+    }
+
+    fn run_endpoint(&mut self, slot_id: u8, dci: u8) {
+        loop {
+            let Some(slot) = self.slots.get(&slot_id) else {
+                return;
+            };
+            let device = slot.device;
+            let ep = &slot.eps[dci as usize];
+            if ep.state != EP_RUNNING {
+                return;
+            }
+            let (deq, cycle) = (ep.deq, ep.cycle);
+            let Some(td) = self.gather(deq, cycle, dci == 1) else {
+                return;
+            };
+
+            let (events, halt, buffers) = if dci == 1 {
+                self.run_control(slot_id, device, &td)
+            } else {
+                self.run_transfer(slot_id, dci, device, &td)
+            };
+            let Some(events) = events else {
+                // NAKed: retried on every tick
+                let ep = &mut self.slots.get_mut(&slot_id).unwrap().eps[dci as usize];
+                ep.nak = true;
+                ep.buffers = buffers;
+                return;
+            };
+            let types = td.trbs.iter().map(|(_, t)| t.trb_type() as u32).collect();
+            self.tds.push((slot_id, dci, types));
+
+            let latency = device
+                .map_or(0, |i| self.devices[i].device.latency_us)
+                .div_ceil(125) as u64;
+            let clock = self.clock;
+            let ep = &mut self.slots.get_mut(&slot_id).unwrap().eps[dci as usize];
+            ep.nak = false;
+            let start = ep.busy_until.max(clock);
+            ep.busy_until = start + latency;
+            let due = ep.busy_until;
+            ep.deq = td.next.0;
+            ep.cycle = td.next.1;
+            if let Some(at) = halt {
+                ep.deq = at;
+                ep.cycle = cycle;
+            }
+            if due > clock {
+                ep.buffers.extend(buffers);
+                ep.completions.push_back(Completion {
+                    due,
+                    start: deq,
+                    events,
+                });
+            } else {
+                for event in events {
+                    self.post_event(event);
+                }
+            }
+            if halt.is_some() {
+                self.set_ep_state(slot_id, dci, EP_HALTED);
+                return;
+            }
+        }
+    }
+
+    /// Execute a control TD; returns its events (`None` if NAKed), the
+    /// TRB that halted EP0 and the data buffer
+    #[allow(clippy::type_complexity)]
+    fn run_control(
+        &mut self,
+        slot_id: u8,
+        device: Option<usize>,
+        td: &Td,
+    ) -> (Option<Vec<Trb>>, Option<u64>, Vec<(u64, usize)>) {
+        let find = |ty: u32| td.trbs.iter().find(|(_, t)| t.trb_type() as u32 == ty);
+        let setup = find(trb_type::SETUP).map(|(_, t)| t.param.to_le_bytes());
+        let data = find(trb_type::DATA).copied();
+        let (status_addr, status) = *td.trbs.last().unwrap();
+        let buffers: Vec<(u64, usize)> = data
+            .iter()
+            .map(|(_, t)| (t.param, (t.status & 0x1ffff) as usize))
+            .collect();
+        let ioc = |t: &Trb| t.control & trb_flags::IOC != 0;
+
+        let Some(setup) = setup.and_then(|b| SetupPacket::from_bytes(&b)) else {
+            // Resumed in the middle of a TD
+            let events = td
+                .trbs
+                .iter()
+                .filter(|(_, t)| ioc(t))
+                .map(|&(a, _)| Self::transfer_event(slot_id, 1, a, completion::SUCCESS, 0))
+                .collect();
+            return (Some(events), None, buffers);
+        };
+
+        let data_in = setup.request_type & 0x80 != 0;
+        let out = match data {
+            Some((_, t)) if !data_in => {
+                let len = (t.status & 0x1ffff) as usize;
+                self.dma_read(t.param, len, "OUT data read")
+                    .unwrap_or_default()
+            }
+            _ => Vec::new(),
+        };
+        let reply = match device {
+            Some(i) if self.devices[i].present => self.devices[i].device.control(setup, &out),
+            _ => Reply::Fail(completion::USB_TRANSACTION_ERROR),
+        };
+
+        let mut events = Vec::new();
+        let (code, sent) = match reply {
+            Reply::Nak => return (None, None, buffers),
+            Reply::Data(bytes) if data_in => (completion::SUCCESS, bytes),
+            Reply::Data(_) | Reply::Ack => (completion::SUCCESS, out),
+            Reply::Stall => (completion::STALL_ERROR, Vec::new()),
+            Reply::Fail(code) => (code, Vec::new()),
+        };
+        if code != completion::SUCCESS {
+            let (at, trb) = data.unwrap_or((status_addr, status));
+            let len = (trb.status & 0x1ffff) as usize;
+            events.push(Self::transfer_event(slot_id, 1, at, code, len));
+            let halt = completion::is_endpoint_halting(code).then_some(at);
+            return (Some(events), halt, buffers);
+        }
+
+        if let Some((addr, trb)) = data {
+            let len = (trb.status & 0x1ffff) as usize;
+            let moved = sent.len().min(len);
+            if data_in {
+                self.dma_write(trb.param, &sent[..moved], "IN data write");
+            }
+            if ioc(&trb) {
+                let code = if moved < len {
+                    completion::SHORT_PACKET
+                } else {
+                    completion::SUCCESS
+                };
+                events.push(Self::transfer_event(slot_id, 1, addr, code, len - moved));
+            }
+        }
+        if ioc(&status) {
+            events.push(Self::transfer_event(
+                slot_id,
+                1,
+                status_addr,
+                completion::SUCCESS,
+                0,
+            ));
+        }
+        (Some(events), None, buffers)
+    }
+
+    /// Execute a TD on a non-control endpoint, like `run_control`
+    #[allow(clippy::type_complexity)]
+    fn run_transfer(
+        &mut self,
+        slot_id: u8,
+        dci: u8,
+        device: Option<usize>,
+        td: &Td,
+    ) -> (Option<Vec<Trb>>, Option<u64>, Vec<(u64, usize)>) {
+        let data_in = dci & 1 == 1;
+        let ep_addr = (dci / 2) | if data_in { 0x80 } else { 0 };
+        let data_trbs: Vec<(u64, Trb)> = td
+            .trbs
+            .iter()
+            .filter(|(_, t)| {
+                let ty = t.trb_type() as u32;
+                ty == trb_type::NORMAL || ty == trb_type::ISOCH
+            })
+            .copied()
+            .collect();
+        let immediate = |t: &Trb| t.control & trb_flags::IDT != 0;
+        let buffers: Vec<(u64, usize)> = data_trbs
+            .iter()
+            .filter(|(_, t)| !immediate(t) && t.status & 0x1ffff != 0)
+            .map(|(_, t)| (t.param, (t.status & 0x1ffff) as usize))
+            .collect();
+        let total: usize = data_trbs
+            .iter()
+            .map(|(_, t)| (t.status & 0x1ffff) as usize)
+            .sum();
+
+        let mut out = Vec::new();
+        if !data_in {
+            for (_, t) in &data_trbs {
+                let len = (t.status & 0x1ffff) as usize;
+                if immediate(t) {
+                    out.extend_from_slice(&t.param.to_le_bytes()[..len.min(8)]);
+                } else if len != 0 {
+                    out.extend(
+                        self.dma_read(t.param, len, "OUT data read")
+                            .unwrap_or_default(),
+                    );
+                }
+            }
+        }
+        let reply = match device {
+            Some(i) if self.devices[i].present => {
+                self.devices[i].device.transfer(ep_addr, &out, total)
+            }
+            _ => Reply::Fail(completion::USB_TRANSACTION_ERROR),
+        };
+
+        let (first_addr, first) = td.trbs[0];
+        let (code, sent) = match reply {
+            Reply::Nak => return (None, None, buffers),
+            Reply::Data(bytes) if data_in => (completion::SUCCESS, bytes),
+            Reply::Data(_) | Reply::Ack => (completion::SUCCESS, out),
+            Reply::Stall => (completion::STALL_ERROR, Vec::new()),
+            Reply::Fail(code) => (code, Vec::new()),
+        };
+        if code != completion::SUCCESS {
+            let len = (first.status & 0x1ffff) as usize;
+            let events = vec![Self::transfer_event(slot_id, dci, first_addr, code, len)];
+            let halt = completion::is_endpoint_halting(code).then_some(first_addr);
+            return (Some(events), halt, buffers);
+        }
+
+        // Scatter IN data over the TRBs; the event goes to the last TRB
+        // asking for one, with that TRB's residual
+        let mut events = Vec::new();
+        let mut offset = 0;
+        let moved_total = sent.len().min(total);
+        for &(addr, trb) in &data_trbs {
+            let len = (trb.status & 0x1ffff) as usize;
+            let moved = (moved_total - offset).min(len);
+            if data_in && moved != 0 {
+                self.dma_write(trb.param, &sent[offset..offset + moved], "IN data write");
+            }
+            offset += moved;
+            let short = moved < len;
+            let wants =
+                trb.control & trb_flags::IOC != 0 || (short && trb.control & trb_flags::ISP != 0);
+            if wants {
+                let code = if short {
+                    completion::SHORT_PACKET
+                } else {
+                    completion::SUCCESS
+                };
+                events.push(Self::transfer_event(slot_id, dci, addr, code, len - moved));
+            }
+            if short && data_in {
+                break;
+            }
+        }
+        (Some(events), None, buffers)
+    }
+}
+
+struct Inner {
+    base: usize,
+    regs: Box<[u32]>,
+    state: Mutex<State>,
+}
+
+/// Handle on a mock controller, shared by the tests and `MockHost`
+#[derive(Clone)]
+pub struct Mock(Arc<Inner>);
+
+/// Register files of the live mock controllers, by base address
+static REGISTRY: Mutex<Vec<(usize, Weak<Inner>)>> = Mutex::new(Vec::new());
+
+fn lookup(addr: usize) -> Option<Arc<Inner>> {
+    let registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+    registry
+        .iter()
+        .find(|(base, _)| (*base..*base + MMIO_SIZE).contains(&addr))
+        .and_then(|(_, inner)| inner.upgrade())
+}
+
+/// Read a register of a mock controller, if `addr` belongs to one
+pub fn mmio_read(addr: usize, size: usize) -> Option<u64> {
+    let inner = lookup(addr)?;
+    let mut state = inner.lock();
+    Some(state.read(addr - inner.base, size))
+}
+
+/// Write a register of a mock controller; false if `addr` belongs to none
+pub fn mmio_write(addr: usize, size: usize, val: u64) -> bool {
+    let Some(inner) = lookup(addr) else {
+        return false;
+    };
+    let mut state = inner.lock();
+    state.write(addr - inner.base, size, val);
+    true
+}
+
+impl Inner {
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Default for Mock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mock {
+    /// A halted controller with powered, empty root ports
+    pub fn new() -> Self {
+        let mut regs = vec![0u32; MMIO_SIZE / 4].into_boxed_slice();
+        let base = regs.as_mut_ptr() as usize;
+        regs[0] = CAP_LENGTH as u32 | (0x0110 << 16);
+        regs[reg::HCSPARAMS1 / 4] = MAX_SLOTS as u32 | (1 << 8) | ((MAX_PORTS as u32) << 24);
+        regs[reg::HCCPARAMS1 / 4] =
+            reg::HCCPARAMS1_AC64 | reg::HCCPARAMS1_PPC | reg::HCCPARAMS1_PIND;
+        regs[reg::DBOFF / 4] = DB_OFFSET as u32;
+        regs[reg::RTSOFF / 4] = RTS_OFFSET as u32;
+        regs[USBSTS / 4] = reg::USBSTS_HCH;
+        for port in 0..MAX_PORTS as usize {
+            regs[(PORTS + 0x10 * port) / 4] =
+                reg::PORTSC_PP | reg::portsc_set_pls(reg::PLS_RXDETECT);
+        }
+
+        let state = State {
+            base,
+            clock: 0,
+            ticks_per_read: 1,
+            removed_at: None,
+            regions: BTreeMap::new(),
+            allocs: 0,
+            alloc_fault: None,
+            quarantine: VecDeque::new(),
+            violations: Vec::new(),
+            warnings: Vec::new(),
+            writes: Vec::new(),
+            pci_config: BTreeMap::new(),
+            devices: Vec::new(),
+            slots: BTreeMap::new(),
+            cmd_deq: 0,
+            cmd_cycle: true,
+            cmd_running: false,
+            hang_commands: false,
+            hang_reset: false,
+            hang_start: false,
+            hung: None,
+            command_faults: VecDeque::new(),
+            commands: Vec::new(),
+            evaluated: Vec::new(),
+            tds: Vec::new(),
+            flaps: Vec::new(),
+            event_enq: 0,
+            event_cycle: true,
+            event_full: false,
+            events_dropped: 0,
+        };
+        let inner = Arc::new(Inner {
+            base,
+            regs,
+            state: Mutex::new(state),
+        });
+        let mut registry = REGISTRY.lock().unwrap_or_else(|e| e.into_inner());
+        registry.retain(|(_, inner)| inner.strong_count() != 0);
+        registry.push((base, Arc::downgrade(&inner)));
+        Self(inner)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.0.lock()
+    }
+
+    /// Virtual address of the register file
+    pub fn base(&self) -> usize {
+        debug_assert_eq!(self.0.base, self.0.regs.as_ptr() as usize);
+        self.0.base
+    }
+
+    /// Attach a device to a root port (0-based)
+    pub fn attach(&self, port: u8, device: MockDevice) {
+        self.lock().attach(port, 0, device);
+    }
+
+    /// Attach a device below a hub, addressed by root port and route string
+    pub fn attach_routed(&self, root_port: u8, route: u32, device: MockDevice) {
+        self.lock().attach(root_port, route, device);
+    }
+
+    /// Unplug the device on a root port
+    pub fn detach(&self, port: u8) {
+        self.lock().detach(port, 0);
+    }
+
+    /// Unplug the device below a hub, addressed by root port and route
+    ///
+    /// The hub's port status is left to its `HubState`.
+    pub fn detach_routed(&self, root_port: u8, route: u32) {
+        self.lock().detach(root_port, route);
+    }
+
+    /// Run `f` on the device at a root port and route
+    pub fn with_device<R>(&self, port: u8, route: u32, f: impl FnOnce(&mut MockDevice) -> R) -> R {
+        let mut state = self.lock();
+        let device = state
+            .devices
+            .iter_mut()
+            .rev()
+            .find(|d| d.root_port == port && d.route == route)
+            .expect("no mock device there");
+        let result = f(&mut device.device);
+        // Data queued for a NAKing endpoint is picked up right away
+        state.service();
+        result
+    }
+
+    /// Toggle Current Connect Status of a root port at each virtual time
+    /// in `at_us`, as a device in link training or a bouncing contact
+    /// does; attached devices are left in place
+    ///
+    /// Toggles already due are applied right away.
+    pub fn flap_port(&self, port: u8, at_us: &[u64]) {
+        let mut state = self.lock();
+        state.flaps.extend(at_us.iter().map(|&t| (t, port)));
+        state.flap();
+    }
+
+    /// Signal remote wakeup from a device on a suspended root port
+    ///
+    /// The link goes from U3 to U0 with Port Link State Change set, as on
+    /// a USB3 port.
+    pub fn wake_port(&self, port: u8) {
+        let mut state = self.lock();
+        let portsc = state.portsc(port);
+        assert_eq!(
+            reg::portsc_pls(portsc) as u32,
+            reg::PLS_U3,
+            "port not suspended"
+        );
+        let portsc = portsc & !reg::PORTSC_PLS_MASK;
+        state.set_portsc(
+            port,
+            portsc | reg::portsc_set_pls(reg::PLS_U0) | reg::PORTSC_PLC,
+        );
+        state.port_event(port);
+    }
+
+    /// Remove the controller at virtual time `at_us`, as a hot unplug does
+    ///
+    /// From then on registers read all ones, writes are dropped (but
+    /// still logged) and no more events are posted.
+    pub fn remove_at(&self, at_us: u64) {
+        self.lock().removed_at = Some(at_us);
+    }
+
+    /// Raise or drop over-current on a root port
+    pub fn set_overcurrent(&self, port: u8, active: bool) {
+        let mut state = self.lock();
+        let portsc = state.portsc(port);
+        let portsc = if active {
+            portsc | reg::PORTSC_OCA | reg::PORTSC_OCC
+        } else {
+            (portsc & !reg::PORTSC_OCA) | reg::PORTSC_OCC
+        };
+        state.set_portsc(port, portsc);
+        state.port_event(port);
+    }
+
+    /// Advance virtual time
+    pub fn advance_us(&self, us: u64) {
+        self.lock().tick(us.div_ceil(125));
+    }
+
+    /// Virtual time since the mock was created, in microseconds
+    pub fn now_us(&self) -> u64 {
+        self.lock().clock * 125
+    }
+
+    /// Set how many microframes pass per MFINDEX read
+    pub fn set_ticks_per_read(&self, ticks: u64) {
+        self.lock().ticks_per_read = ticks;
+    }
+
+    /// Leave commands pending until the command ring is aborted
+    pub fn hang_commands(&self, hang: bool) {
+        self.lock().hang_commands = hang;
+    }
+
+    /// Leave HCRST set and the controller not ready after a reset
+    pub fn hang_reset(&self, hang: bool) {
+        self.lock().hang_reset = hang;
+    }
+
+    /// Leave the controller halted when it is told to run
+    pub fn hang_start(&self, hang: bool) {
+        self.lock().hang_start = hang;
+    }
+
+    /// Fail the DMA allocation after the next `after` ones
+    pub fn fail_allocation(&self, after: usize) {
+        self.lock().alloc_fault = Some(after);
+    }
+
+    /// Ask for `count` scratchpad buffers (HCSPARAMS2)
+    pub fn set_max_scratchpad(&self, count: u16) {
+        let hcs2 = ((count as u32 >> 5) << 21) | ((count as u32 & 0x1f) << 27);
+        self.lock().set_reg(reg::HCSPARAMS2, hcs2);
+    }
+
+    /// Fail the next command of TRB type `ty` with completion `code`
+    pub fn fail_command(&self, ty: u32, code: u8) {
+        self.lock().command_faults.push_back((ty, code));
+    }
+
+    /// TRB types of the commands processed so far
+    pub fn commands(&self) -> Vec<u32> {
+        self.lock().commands.clone()
+    }
+
+    /// Evaluate Context commands so far, as the slot ID and the first
+    /// dwords of the input context: the Input Control, Slot and EP0
+    /// contexts, 8 dwords each
+    pub fn evaluated(&self) -> Vec<(u8, [u32; 24])> {
+        self.lock().evaluated.clone()
+    }
+
+    /// TDs executed on an endpoint so far, as the types of their TRBs
+    pub fn tds(&self, slot_id: u8, dci: u8) -> Vec<Vec<u32>> {
+        let state = self.lock();
+        let on_ep = state.tds.iter().filter(|td| td.0 == slot_id && td.1 == dci);
+        on_ep.map(|td| td.2.clone()).collect()
+    }
+
+    /// Put an event on the event ring, as the controller would
+    pub fn inject_event(&self, trb: Trb) {
+        self.lock().post_event(trb);
+    }
+
+    /// Events the controller could not write, Event Ring Full included
+    pub fn events_dropped(&self) -> u64 {
+        self.lock().events_dropped
+    }
+
+    /// Register writes so far, as (offset, value)
+    pub fn register_writes(&self) -> Vec<(usize, u64)> {
+        self.lock().writes.clone()
+    }
+
+    /// Report HCIVERSION `version` (BCD, 0x0110 by default) and the extra
+    /// HCCPARAMS1 and HCCPARAMS2 bits `hcc1` and `hcc2`
+    pub fn set_capabilities(&self, version: u16, hcc1: u32, hcc2: u32) {
+        let mut state = self.lock();
+        state.set_reg(0, CAP_LENGTH as u32 | (version as u32) << 16);
+        let hcc1 = state.reg(reg::HCCPARAMS1) | hcc1;
+        state.set_reg(reg::HCCPARAMS1, hcc1);
+        let hcc2 = state.reg(reg::HCCPARAMS2) | hcc2;
+        state.set_reg(reg::HCCPARAMS2, hcc2);
+    }
+
+    /// Describe the root ports with Supported Protocol capabilities
+    ///
+    /// Each entry is (major revision, first port, port count, protocol
+    /// defined flags); ports are 0-based. Replaces the previous list.
+    pub fn set_protocols(&self, protocols: &[(u8, u8, u8, u32)]) {
+        let mut state = self.lock();
+        let hcc = state.reg(reg::HCCPARAMS1) & 0xffff;
+        if protocols.is_empty() {
+            state.set_reg(reg::HCCPARAMS1, hcc);
+            return;
+        }
+        state.set_reg(reg::HCCPARAMS1, hcc | ((EXT_CAPS as u32 >> 2) << 16));
+        for (i, &(major, first, count, flags)) in protocols.iter().enumerate() {
+            let offset = EXT_CAPS + i * reg::SUPP_PROTO_SIZE;
+            let next = if i + 1 < protocols.len() {
+                (reg::SUPP_PROTO_SIZE as u32 >> 2) << 8
+            } else {
+                0
+            };
+            let header = reg::ECAP_SUPPORTED_PROTOCOL as u32 | next | (major as u32) << 24;
+            state.set_reg(offset, header);
+            state.set_reg(offset + 4, u32::from_le_bytes(*b"USB "));
+            let ports = (first as u32 + 1) | (count as u32) << 8 | flags;
+            state.set_reg(offset + reg::SUPP_PROTO_PORTS, ports);
+            state.set_reg(offset + 0xc, 0);
+        }
+    }
+
+    /// Set a dword of the PCI configuration space
+    pub fn set_pci_config(&self, offset: u16, val: u32) {
+        self.lock().pci_config.insert(offset, val);
+    }
+
+    /// A dword of the PCI configuration space, `None` if never written
+    pub fn pci_config(&self, offset: u16) -> Option<u32> {
+        self.lock().pci_config.get(&offset).copied()
+    }
+
+    /// Accesses to freed or unknown memory so far
+    pub fn violations(&self) -> Vec<String> {
+        self.lock().violations.clone()
+    }
+
+    /// Messages passed to `Dma::warn`
+    pub fn warnings(&self) -> Vec<String> {
+        self.lock().warnings.clone()
+    }
+
+    /// Number of DMA allocations not freed yet
+    pub fn live_allocations(&self) -> usize {
+        self.lock().regions.values().filter(|r| r.live).count()
+    }
+
+    /// Number of `Dma::alloc` calls so far
+    pub fn allocations(&self) -> usize {
+        self.lock().allocs
+    }
+
+    /// EP State of an endpoint as the controller sees it
+    pub fn endpoint_state(&self, slot_id: u8, dci: u8) -> u8 {
+        self.lock()
+            .slots
+            .get(&slot_id)
+            .map_or(EP_DISABLED, |slot| slot.eps[dci as usize].state)
+    }
+}
+
+/// `Dma` backed by the process heap, identity mapped
+pub struct MockHost {
+    mock: Mock,
+}
+
+impl MockHost {
+    pub fn new(mock: Mock) -> Self {
+        Self { mock }
+    }
+
+    pub fn mock(&self) -> &Mock {
+        &self.mock
+    }
+}
+
+impl Dma for MockHost {
+    unsafe fn alloc(&self, size: usize, align: usize) -> Option<usize> {
+        self.mock.lock().alloc(size, align)
+    }
+
+    unsafe fn free(&self, addr: usize, size: usize, _align: usize) {
+        self.mock.lock().free(addr, size);
+    }
+
+    unsafe fn map_mmio(&self, phys: usize, size: usize) -> Option<usize> {
+        (phys == MOCK_PHYS && size <= MMIO_SIZE).then(|| self.mock.base())
+    }
+
+    unsafe fn unmap_mmio(&self, _virt: usize, _size: usize) {}
+
+    fn virt_to_phys(&self, va: usize) -> usize {
+        va
+    }
+
+    fn delay_us(&self, us: u32) {
+        self.mock.advance_us(us as u64);
+    }
+
+    // Trace timestamps must not tick the clock through MFINDEX reads
+    fn now_us(&self) -> Option<u64> {
+        Some(self.mock.now_us())
+    }
+
+    fn warn(&self, args: core::fmt::Arguments<'_>) {
+        self.mock.lock().warnings.push(format!("{args}"));
+    }
+
+    fn pci_read_config(&self, offset: u16) -> Option<u32> {
+        self.mock.pci_config(offset)
+    }
+
+    fn pci_write_config(&self, offset: u16, val: u32) {
+        self.mock.set_pci_config(offset, val);
+    }
+}
+
+/// Start a controller on a fresh mock
+pub fn controller() -> (Arc<XhciCtrl<MockHost>>, Mock) {
+    controller_with_quirks(Mock::new(), XhciQuirks::empty())
+}
+
+/// Start a controller with `quirks` on `mock`
+pub fn controller_with_quirks(mock: Mock, quirks: XhciQuirks) -> (Arc<XhciCtrl<MockHost>>, Mock) {
+    let host = MockHost::new(mock.clone());
+    let ctrl = XhciCtrl::new_with_quirks(MOCK_PHYS, host, quirks).expect("mock controller");
+    (Arc::new(ctrl), mock)
+}
+
+// ----------------------------------------------------------------------
+// Descriptor fixtures
+// ----------------------------------------------------------------------
+
+/// Device descriptor of a USB 2.0 device with a 64-byte EP0
+pub fn device_desc(class: u8, vendor_id: u16, product_id: u16, configs: u8) -> DeviceDesc {
+    DeviceDesc {
+        length: DeviceDesc::SIZE as u8,
+        desc_type: desc_type::DEVICE,
+        bcd_usb: 0x0200,
+        device_class: class,
+        max_packet_size0: 64,
+        vendor_id,
+        product_id,
+        bcd_device: 0x0100,
+        num_configurations: configs,
+        ..DeviceDesc::default()
+    }
+}
+
+/// Interface descriptor bytes
+pub fn interface(number: u8, alt: u8, class: (u8, u8, u8), endpoints: u8) -> Vec<u8> {
+    InterfaceDesc {
+        length: InterfaceDesc::SIZE as u8,
+        desc_type: desc_type::INTERFACE,
+        interface_number: number,
+        alternate_setting: alt,
+        num_endpoints: endpoints,
+        interface_class: class.0,
+        interface_subclass: class.1,
+        interface_protocol: class.2,
+        interface: 0,
+    }
+    .to_bytes()
+    .to_vec()
+}
+
+/// Endpoint descriptor bytes
+pub fn endpoint(address: u8, attributes: u8, max_packet: u16, interval: u8) -> Vec<u8> {
+    EndpointDesc {
+        length: EndpointDesc::SIZE as u8,
+        desc_type: desc_type::ENDPOINT,
+        endpoint_address: address,
+        attributes,
+        max_packet_size: max_packet,
+        interval,
+    }
+    .to_bytes()
+    .to_vec()
+}
+
+/// Configuration descriptor `value` followed by `body`
+///
+/// wTotalLength and bNumInterfaces are computed from the body.
+pub fn config(value: u8, body: &[Vec<u8>]) -> Vec<u8> {
+    let body: Vec<u8> = body.concat();
+    let mut interfaces = 0;
+    let mut i = 0;
+    while i + 3 < body.len() && body[i] != 0 {
+        if body[i + 1] == desc_type::INTERFACE && body[i + 3] == 0 {
+            interfaces += 1;
+        }
+        i += body[i] as usize;
+    }
+    let header = ConfigDesc {
+        length: ConfigDesc::SIZE as u8,
+        desc_type: desc_type::CONFIGURATION,
+        total_length: (ConfigDesc::SIZE + body.len()) as u16,
+        num_interfaces: interfaces,
+        config_value: value,
+        configuration: 0,
+        attributes: 0x80,
+        max_power: 50,
+    };
+    [header.to_bytes().to_vec(), body].concat()
+}
+
+/// Boot keyboard report descriptor
+pub const KEYBOARD_REPORT: [u8; 63] = [
+    0x05, 0x01, 0x09, 0x06, 0xa1, 0x01, 0x05, 0x07, 0x19, 0xe0, 0x29, 0xe7, 0x15, 0x00, 0x25, 0x01,
+    0x75, 0x01, 0x95, 0x08, 0x81, 0x02, 0x95, 0x01, 0x75, 0x08, 0x81, 0x01, 0x95, 0x05, 0x75, 0x01,
+    0x05, 0x08, 0x19, 0x01, 0x29, 0x05, 0x91, 0x02, 0x95, 0x01, 0x75, 0x03, 0x91, 0x01, 0x95, 0x06,
+    0x75, 0x08, 0x15, 0x00, 0x25, 0x65, 0x05, 0x07, 0x19, 0x00, 0x29, 0x65, 0x81, 0x00, 0xc0,
+];
+
+/// A high-speed boot keyboard with its interrupt IN endpoint at 0x81
+pub fn keyboard() -> MockDevice {
+    let hid = vec![
+        0x09,
+        0x21,
+        0x11,
+        0x01,
+        0x00,
+        0x01,
+        0x22,
+        KEYBOARD_REPORT.len() as u8,
+        0,
+    ];
+    let config = config(
+        1,
+        &[
+            interface(0, 0, (0x03, 0x01, 0x01), 1),
+            hid,
+            endpoint(0x81, 0x03, 8, 4),
+        ],
+    );
+    MockDevice::new(
+        reg::SPEED_HIGH,
+        device_desc(0, 0x046d, 0xc31c, 1),
+        &[config],
+    )
+    .with_descriptor(0x22, 0, KEYBOARD_REPORT.to_vec())
+}
+
+/// A high-speed Bulk-Only mass storage device with endpoints 0x81 and 0x02
+pub fn mass_storage() -> MockDevice {
+    let config = config(
+        1,
+        &[
+            interface(0, 0, (0x08, 0x06, 0x50), 2),
+            endpoint(0x81, 0x02, 512, 0),
+            endpoint(0x02, 0x02, 512, 0),
+        ],
+    );
+    MockDevice::new(
+        reg::SPEED_HIGH,
+        device_desc(0, 0x0781, 0x5567, 1),
+        &[config],
+    )
+}
+
+/// A high-speed keyboard with a card reader
+///
+/// The boot keyboard is interface 0 with endpoint 0x81; the card reader
+/// is interface 1, a `bulk_only_disk` of 64 blocks on 0x82 and 0x02.
+pub fn keyboard_with_card_reader() -> MockDevice {
+    let hid = vec![9, 0x21, 0x11, 1, 0, 1, 0x22, KEYBOARD_REPORT.len() as u8, 0];
+    let config = config(
+        1,
+        &[
+            interface(0, 0, (0x03, 0x01, 0x01), 1),
+            hid,
+            endpoint(0x81, 0x03, 8, 4),
+            interface(1, 0, (0x08, 0x06, 0x50), 2),
+            endpoint(0x82, 0x02, 512, 0),
+            endpoint(0x02, 0x02, 512, 0),
+        ],
+    );
+    MockDevice::new(
+        reg::SPEED_HIGH,
+        device_desc(0, 0x04b4, 0x2050, 1),
+        &[config],
+    )
+    .with_descriptor(0x22, 0, KEYBOARD_REPORT.to_vec())
+    .with_handler(bulk_only_disk(0x82, 0x02, 64))
+}
+
+/// Handler answering Bulk-Only commands for a disk of `blocks` blocks
+///
+/// Blocks are 512 bytes and block `lba` reads back as `lba as u8`
+/// repeated. GET MAX LUN reports one LUN and Mass Storage Reset drops
+/// the command in progress. TEST UNIT READY, INQUIRY, REQUEST SENSE,
+/// READ CAPACITY(10) and READ(10) pass, other commands fail. Transfers
+/// on other endpoints are left to the default handling.
+pub fn bulk_only_disk(
+    ep_in: u8,
+    ep_out: u8,
+    blocks: u32,
+) -> impl FnMut(&Request<'_>) -> Option<Reply> + Send + 'static {
+    // Data the device sends, bytes the host still expects, then the CSW
+    let mut data = VecDeque::new();
+    let mut expected = 0;
+    let mut csw = None;
+    move |r: &Request<'_>| match *r {
+        Request::Control { setup, .. } if setup.request == 0xfe => Some(Reply::Data(vec![0])),
+        // Mass Storage Reset drops the command in progress
+        Request::Control { setup, .. } if setup.request == 0xff => {
+            data.clear();
+            expected = 0;
+            csw = None;
+            Some(Reply::Ack)
+        }
+        Request::Transfer { ep, data: out, .. } if ep == ep_out && out.len() == Cbw::SIZE => {
+            let cbw = Cbw::from_bytes(out)?;
+            let (reply, status) = disk_command(&cbw.cb, blocks);
+            let length = cbw.data_transfer_length as usize;
+            let sent = reply.len().min(length);
+            data = reply[..sent].iter().copied().collect();
+            expected = length;
+            csw = Some(Csw {
+                signature: Csw::SIGNATURE,
+                tag: cbw.tag,
+                data_residue: (length - sent) as u32,
+                status,
+            });
+            Some(Reply::Ack)
+        }
+        Request::Transfer { ep, len, .. } if ep == ep_in && expected > 0 => {
+            let chunk: Vec<u8> = data.drain(..len.min(data.len())).collect();
+            // A short packet ends the data phase
+            expected = if chunk.len() < len {
+                0
+            } else {
+                expected.saturating_sub(len)
+            };
+            Some(Reply::Data(chunk))
+        }
+        Request::Transfer { ep, .. } if ep == ep_in => csw
+            .take()
+            .map(|csw: Csw| Reply::Data(csw.to_bytes().to_vec())),
+        _ => None,
+    }
+}
+
+/// Data and status of a SCSI command sent to `bulk_only_disk`
+fn disk_command(cdb: &[u8], blocks: u32) -> (Vec<u8>, u8) {
+    match cdb[0] {
+        scsi_op::TEST_UNIT_READY => (Vec::new(), Csw::STATUS_PASSED),
+        scsi_op::INQUIRY => {
+            let mut inquiry = vec![0, 0x80, 0x06, 0x02, 31, 0, 0, 0];
+            inquiry.extend_from_slice(b"MOCK    DISK            1.00");
+            (inquiry, Csw::STATUS_PASSED)
+        }
+        scsi_op::REQUEST_SENSE => {
+            let mut sense = vec![0; 18];
+            sense[0] = 0x70;
+            sense[7] = 10;
+            (sense, Csw::STATUS_PASSED)
+        }
+        scsi_op::READ_CAPACITY_10 => {
+            let mut capacity = (blocks - 1).to_be_bytes().to_vec();
+            capacity.extend_from_slice(&512u32.to_be_bytes());
+            (capacity, Csw::STATUS_PASSED)
+        }
+        scsi_op::READ_10 => {
+            let lba = u32::from_be_bytes(cdb[2..6].try_into().unwrap());
+            let count = u16::from_be_bytes([cdb[7], cdb[8]]) as u32;
+            if lba.saturating_add(count) > blocks {
+                return (Vec::new(), Csw::STATUS_FAILED);
+            }
+            let data = (lba..lba + count)
+                .flat_map(|lba| [lba as u8; 512])
+                .collect();
+            (data, Csw::STATUS_PASSED)
+        }
+        _ => (Vec::new(), Csw::STATUS_FAILED),
+    }
+}
+
+/// Downstream port state of a mock hub, shared with its request handler
+#[derive(Default)]
+pub struct HubState {
+    /// wPortStatus and wPortChange of each port, port 1 first
+    pub ports: Vec<(u16, u16)>,
+    /// wHubStatus and wHubChange
+    pub hub: (u16, u16),
+    /// Last SET_HUB_DEPTH value
+    pub depth: Option<u16>,
+    /// SET_PORT_FEATURE(PORT_INDICATOR) requests as (port, selector)
+    pub indicators: Vec<(u8, u8)>,
+}
+
+/// A hub with `ports` downstream ports and its port state
+///
+/// SuperSpeed hubs get an SS hub descriptor. Port features follow the
+/// hub class requests; a reset enables a connected port at once.
+pub fn hub(
+    ports: u8,
+    characteristics: u16,
+    superspeed: bool,
+) -> (MockDevice, Arc<Mutex<HubState>>) {
+    let power = if superspeed { 1 << 9 } else { 1 << 8 };
+    let state = Arc::new(Mutex::new(HubState {
+        ports: vec![(0, 0); ports as usize],
+        ..HubState::default()
+    }));
+
+    let [lo, hi] = characteristics.to_le_bytes();
+    let (speed, dtype, descriptor) = if superspeed {
+        let d = vec![12, 0x2a, ports, lo, hi, 50, 0, 0, 0, 0, 0, 0];
+        (reg::SPEED_SUPER, 0x2a, d)
+    } else {
+        let bitmap = ports as usize / 8 + 1;
+        let mut d = vec![0, 0x29, ports, lo, hi, 50, 100];
+        d.resize(7 + 2 * bitmap, 0);
+        d[0] = d.len() as u8;
+        (reg::SPEED_HIGH, 0x29, d)
+    };
+
+    let shared = state.clone();
+    let handler = move |r: &Request<'_>| {
+        let Request::Control { setup, .. } = r else {
+            return None;
+        };
+        let mut s = shared.lock().unwrap_or_else(|e| e.into_inner());
+        let (value, index) = (setup.value, setup.index);
+        let port = (index & 0xff) as usize;
+        let status = |(status, change): (u16, u16)| {
+            let mut b = status.to_le_bytes().to_vec();
+            b.extend_from_slice(&change.to_le_bytes());
+            Reply::Data(b)
+        };
+        let reply = match (setup.request_type, setup.request) {
+            (0xa0, 6) if value >> 8 == dtype as u16 => {
+                let mut d = descriptor.clone();
+                d.truncate(setup.length as usize);
+                Reply::Data(d)
+            }
+            (0xa0, 0) => status(s.hub),
+            (0xa3, 0) => match s.ports.get(port.wrapping_sub(1)) {
+                Some(&p) => status(p),
+                None => Reply::Stall,
+            },
+            (0x20, 1) => {
+                s.hub.1 &= !(1 << value);
+                Reply::Ack
+            }
+            (0x20, 12) => {
+                s.depth = Some(value);
+                Reply::Ack
+            }
+            (0x23, 1 | 3) if port == 0 || port > s.ports.len() => Reply::Stall,
+            (0x23, 3) => {
+                let p = &mut s.ports[port - 1];
+                match value {
+                    4 if p.0 & 1 != 0 => {
+                        p.0 |= 1 << 1;
+                        p.1 |= 1 << 4;
+                    }
+                    8 => p.0 |= power,
+                    22 => s.indicators.push((port as u8, (index >> 8) as u8)),
+                    _ => {}
+                }
+                Reply::Ack
+            }
+            (0x23, 1) => {
+                let p = &mut s.ports[port - 1];
+                match value {
+                    1 => p.0 &= !(1 << 1),
+                    8 => p.0 &= !(power | 1 << 1),
+                    16..=20 => p.1 &= !(1 << (value - 16)),
+                    25 | 26 => p.1 &= !(1 << (value - 19)),
+                    29 => p.1 &= !(1 << 5),
+                    _ => {}
+                }
+                Reply::Ack
+            }
+            _ => return None,
+        };
+        Some(reply)
+    };
+
+    let config = config(
+        1,
+        &[
+            interface(0, 0, (0x09, 0, 0), 1),
+            endpoint(0x81, 0x03, 2, 12),
+        ],
+    );
+    let device = MockDevice::new(speed, device_desc(0x09, 0x05e3, 0x0610, 1), &[config])
+        .with_handler(handler);
+    (device, state)
+}
@@ -0,0 +1,565 @@
+//! Hotplug monitor.
+//!
+//! Ties port change detection, hub status processing, enumeration and
+//! the driver registry together: the OS calls `UsbMonitor::poll` from a
+//! timer or interrupt handler and reacts to the attach and detach events
+//! it returns.
+//!
+//! # Features
+//!
+//! - Root and hub port connect changes, each debounced per port
+//! - Over-current reported, with the port powered off by policy
+//! - Hubs are driven by the monitor; devices behind them are reported
+//!   like any other
+//! - A device failing enumeration or binding is reported and skipped
+//!   until its port changes again
+
+use crate::{
+    Dma, Result, UsbError,
+    bind::{BoundDevice, DriverRegistry},
+    dev::{DevicePath, UsbDevice, default_config_policy},
+    hub::{HubDevice, find_hub_interfaces, port_change},
+    ring::trb_type,
+    xhci::{Stopwatch, XhciCtrl},
+};
+
+use alloc::{sync::Arc, vec::Vec};
+
+/// Time a port must stay connected before it is enumerated, in microseconds
+const CONNECT_DEBOUNCE_US: u64 = 100_000;
+
+/// Change reported by `UsbMonitor::poll`.
+pub enum UsbEvent<H: Dma> {
+    /// A device was enumerated
+    ///
+    /// Reported once per bound interface, once for a hub, or as
+    /// `BoundDevice::Failed` if bring-up failed.
+    Attached(BoundDevice<H>),
+    /// The device at this path went away
+    ///
+    /// Drivers bound to it must be dropped; its slot is freed once the
+    /// last of them is. Transfers on it fail with `Disconnected` meanwhile.
+    Detached(DevicePath),
+    /// Over-current became active on the port at this path
+    ///
+    /// `powered_off` is true if the port was powered off following the
+    /// controller's `OvercurrentPolicy`; see `XhciCtrl::restore_port_power`
+    /// and `HubDevice::set_port_power` to bring it back.
+    OverCurrent {
+        /// Port the condition was reported on
+        path: DevicePath,
+        /// The port is no longer powered
+        powered_off: bool,
+    },
+}
+
+/// Device attached through the monitor
+struct Node<H: Dma> {
+    path: DevicePath,
+    device: Arc<UsbDevice<H>>,
+    hub: Option<HubNode<H>>,
+}
+
+/// Hub driven by the monitor
+struct HubNode<H: Dma> {
+    hub: HubDevice<H>,
+    /// The status-change read failed; ports are queried on every poll
+    polled: bool,
+}
+
+/// Port waiting out the attach debounce interval
+struct Debounce {
+    path: DevicePath,
+    watch: Stopwatch,
+}
+
+/// Hotplug monitor driving one controller.
+///
+/// Owns the bus topology: devices must not be enumerated by other means
+/// while a monitor runs on the controller.
+pub struct UsbMonitor<H: Dma> {
+    ctrl: Arc<XhciCtrl<H>>,
+    registry: DriverRegistry<H>,
+    nodes: Vec<Node<H>>,
+    debounce: Vec<Debounce>,
+    /// Ports whose bring-up failed, skipped until their next connect change
+    failed: Vec<DevicePath>,
+}
+
+impl<H: Dma> UsbMonitor<H> {
+    /// Creates a monitor binding the built-in class drivers
+    ///
+    /// Devices already connected are reported by the first polls after
+    /// their debounce interval.
+    pub fn new(ctrl: Arc<XhciCtrl<H>>) -> Self {
+        Self::with_registry(ctrl, DriverRegistry::with_builtin())
+    }
+
+    /// Creates a monitor binding drivers from `registry`
+    pub fn with_registry(ctrl: Arc<XhciCtrl<H>>, registry: DriverRegistry<H>) -> Self {
+        let mut monitor = Self {
+            ctrl,
+            registry,
+            nodes: Vec::new(),
+            debounce: Vec::new(),
+            failed: Vec::new(),
+        };
+        for port in 0..monitor.ctrl.max_ports() {
+            if monitor.ctrl.port_connected(port) {
+                monitor.start_debounce(monitor.root_path(port));
+            }
+        }
+        monitor
+    }
+
+    /// Returns the driver registry, e.g. to register user drivers.
+    pub fn registry_mut(&mut self) -> &mut DriverRegistry<H> {
+        &mut self.registry
+    }
+
+    /// Returns the paths of the attached devices, hubs included.
+    pub fn attached(&self) -> impl Iterator<Item = DevicePath> + '_ {
+        self.nodes.iter().map(|node| node.path)
+    }
+
+    /// Processes pending port changes and returns what changed
+    ///
+    /// Detaches are reported as soon as a connect change is seen, parent
+    /// hubs after the devices below them. Attaches follow once a port
+    /// has been connected for 100 ms; devices behind a hub attached in
+    /// this call are picked up by later calls. The debounce is timed
+    /// with the controller's frame counter, so calls should be less than
+    /// 2 seconds apart.
+    pub fn poll(&mut self) -> Vec<UsbEvent<H>> {
+        let mut events = Vec::new();
+
+        // The port registers are scanned below; the events only wake us up
+        let is_port_event = |e: &crate::Trb| e.trb_type() == trb_type::PORT_STATUS_CHANGE as u8;
+        while self.ctrl.poll_event_where(is_port_event).is_some() {}
+
+        for port in 0..self.ctrl.max_ports() {
+            let Ok(change) = self.ctrl.port_change(port) else {
+                continue;
+            };
+            if !change.any() {
+                continue;
+            }
+            let _ = self.ctrl.ack_port_change(port, &change);
+            if change.over_current && change.over_current_active {
+                let powered_off = self.ctrl.apply_overcurrent_policy(port, &change);
+                events.push(UsbEvent::OverCurrent {
+                    path: self.root_path(port),
+                    powered_off: powered_off.unwrap_or(false),
+                });
+            }
+            if change.connect {
+                self.connect_changed(self.root_path(port), &mut events);
+            }
+        }
+
+        for path in self.hub_changes(&mut events) {
+            self.connect_changed(path, &mut events);
+        }
+
+        self.attach_settled(&mut events);
+        events
+    }
+
+    /// Path of a device on a root port
+    fn root_path(&self, port: u8) -> DevicePath {
+        DevicePath {
+            bus: self.ctrl.bus_index(),
+            root_port: port,
+            route: 0,
+        }
+    }
+
+    /// Ports of attached hubs whose connection changed
+    ///
+    /// Over-current on a hub port is reported to `events` right away. A
+    /// hub failing to report is left alone: if it went away, its parent
+    /// port reports that.
+    fn hub_changes(&mut self, events: &mut Vec<UsbEvent<H>>) -> Vec<DevicePath> {
+        let mut changed = Vec::new();
+        for node in &mut self.nodes {
+            let Some(hub) = &mut node.hub else {
+                continue;
+            };
+            let changes = if hub.polled {
+                hub.hub.poll_all_ports()
+            } else {
+                match hub.hub.poll_changes() {
+                    Ok(Some(changes)) => Ok(changes),
+                    Ok(None) => continue,
+                    // The read was queued again; catch up on what was missed
+                    Err(UsbError::EventLost) => hub.hub.poll_all_ports(),
+                    Err(e) => {
+                        hub.polled = true;
+                        Err(e)
+                    }
+                }
+            };
+            let Ok(changes) = changes else {
+                continue;
+            };
+
+            for port in 1..=hub.hub.num_ports() {
+                let active = changes.ports.iter().any(|&(p, status)| {
+                    p == port
+                        && status.change & port_change::C_OVER_CURRENT != 0
+                        && status.over_current()
+                });
+                let powered_off = changes.powered_off.contains(&port);
+                if let Some(path) = hub.hub.port_path(port)
+                    && (active || powered_off)
+                {
+                    events.push(UsbEvent::OverCurrent { path, powered_off });
+                }
+            }
+
+            let connect = changes
+                .ports
+                .iter()
+                .filter(|(_, status)| status.change & port_change::C_CONNECTION != 0);
+            changed.extend(connect.filter_map(|&(port, _)| hub.hub.port_path(port)));
+        }
+        changed
+    }
+
+    /// Tear down whatever was at `path` and debounce it afresh
+    fn connect_changed(&mut self, path: DevicePath, events: &mut Vec<UsbEvent<H>>) {
+        self.detach(path, events);
+        self.failed.retain(|failed| !failed.is_within(&path));
+        self.start_debounce(path);
+    }
+
+    /// Start or restart the debounce interval of a port
+    fn start_debounce(&mut self, path: DevicePath) {
+        self.debounce.retain(|entry| entry.path != path);
+        self.debounce.push(Debounce {
+            path,
+            watch: self.ctrl.stopwatch(),
+        });
+    }
+
+    /// Remove the device at `path` and everything below it, deepest first
+    fn detach(&mut self, path: DevicePath, events: &mut Vec<UsbEvent<H>>) {
+        self.debounce
+            .retain(|entry| entry.path == path || !entry.path.is_within(&path));
+
+        let mut gone: Vec<Node<H>> = Vec::new();
+        let mut i = 0;
+        while i < self.nodes.len() {
+            if self.nodes[i].path.is_within(&path) {
+                gone.push(self.nodes.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        gone.sort_by_key(|node| core::cmp::Reverse(node.path.depth()));
+
+        for node in gone {
+            node.device.mark_detached();
+            events.push(UsbEvent::Detached(node.path));
+        }
+    }
+
+    /// Enumerate every port whose debounce interval ran out
+    fn attach_settled(&mut self, events: &mut Vec<UsbEvent<H>>) {
+        let mut settled = Vec::new();
+        let mut i = 0;
+        while i < self.debounce.len() {
+            if self.debounce[i].watch.elapsed_us(&self.ctrl) >= CONNECT_DEBOUNCE_US {
+                settled.push(self.debounce.swap_remove(i).path);
+            } else {
+                i += 1;
+            }
+        }
+
+        for path in settled {
+            match self.still_connected(path) {
+                Ok(true) => {}
+                // Changed again: the next poll restarts the interval
+                Ok(false) | Err(_) => continue,
+            }
+            if let Err(error) = self.attach(path, events) {
+                self.failed.push(path);
+                events.push(UsbEvent::Attached(BoundDevice::Failed { path, error }));
+            }
+        }
+    }
+
+    /// Returns true if a device is on the port with no connect change pending
+    fn still_connected(&self, path: DevicePath) -> Result<bool> {
+        if self.nodes.iter().any(|node| node.path == path) || self.failed.contains(&path) {
+            return Ok(false);
+        }
+
+        match self.parent_hub(path)? {
+            None => {
+                let change = self.ctrl.port_change(path.root_port)?;
+                Ok(self.ctrl.port_connected(path.root_port) && !change.connect)
+            }
+            Some((hub, port)) => {
+                let status = hub.get_port_status(port)?;
+                Ok(status.connected() && status.change & port_change::C_CONNECTION == 0)
+            }
+        }
+    }
+
+    /// Hub a device path hangs off, with the port on it; `None` for root ports
+    ///
+    /// Fails with `DeviceNotFound` if the hub is no longer attached.
+    fn parent_hub(&self, path: DevicePath) -> Result<Option<(&HubDevice<H>, u8)>> {
+        let Some((parent, port)) = path.parent() else {
+            return Ok(None);
+        };
+        self.nodes
+            .iter()
+            .find(|node| node.path == parent)
+            .and_then(|node| node.hub.as_ref())
+            .map(|hub| Some((&hub.hub, port)))
+            .ok_or(UsbError::DeviceNotFound)
+    }
+
+    /// Enumerate, configure and bind the device on a port
+    fn attach(&mut self, path: DevicePath, events: &mut Vec<UsbEvent<H>>) -> Result<()> {
+        let parent = self.parent_hub(path)?;
+        let mut device = match parent {
+            None => UsbDevice::new(self.ctrl.clone(), path.root_port)?,
+            Some((hub, port)) => hub.enumerate_port(port)?,
+        };
+
+        let desc = device.get_device_descriptor()?;
+        let configs = (0..desc.num_configurations.max(1))
+            .map(|index| device.config_tree_at(index))
+            .collect::<Result<Vec<_>>>()?;
+        let tree = configs
+            .get(default_config_policy(&desc, &configs))
+            .ok_or(UsbError::InvalidArgument)?;
+        let value = tree.config.config_value;
+        match parent {
+            None => device.set_configuration(value)?,
+            Some((hub, port)) => hub.set_device_configuration(port, &device, value)?,
+        }
+
+        let device = Arc::new(device);
+        let hub = match find_hub_interfaces(tree.raw()).first() {
+            Some((iface, ep_in)) => {
+                let hub = HubDevice::from_interface(device.clone(), iface, ep_in)?;
+                hub.queue_status_read()?;
+                Some(hub)
+            }
+            None => None,
+        };
+
+        match hub {
+            Some(hub) => {
+                // Devices already present raise no status change
+                for port in 1..=hub.num_ports() {
+                    let connected = hub.get_port_status(port).is_ok_and(|s| s.connected());
+                    if let (true, Some(child)) = (connected, hub.port_path(port)) {
+                        self.start_debounce(child);
+                    }
+                }
+                events.push(UsbEvent::Attached(BoundDevice::Hub {
+                    device: device.clone(),
+                }));
+                self.nodes.push(Node {
+                    path,
+                    device,
+                    hub: Some(HubNode { hub, polled: false }),
+                });
+            }
+            None => {
+                let bound = self.registry.bind_device(device.clone());
+                events.extend(bound.into_iter().map(UsbEvent::Attached));
+                self.nodes.push(Node {
+                    path,
+                    device,
+                    hub: None,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        bind::Driver,
+        desc::desc_type,
+        hub::port_status,
+        mock::{self, HubState, MockDevice, MockHost, Reply, Request},
+        xhci::OvercurrentPolicy,
+    };
+    use alloc::{vec, vec::Vec};
+    use std::sync::Mutex;
+
+    /// Events as (kind, root port, route), in order
+    fn summary(events: &[UsbEvent<MockHost>]) -> Vec<(&'static str, u8, u32)> {
+        let at = |path: DevicePath| (path.root_port, path.route);
+        events
+            .iter()
+            .map(|ev| {
+                let (kind, (port, route)) = match ev {
+                    UsbEvent::Attached(BoundDevice::Bound(Driver::Hid(hid))) => {
+                        ("hid", at(hid.device().path()))
+                    }
+                    UsbEvent::Attached(BoundDevice::Bound(_)) => unreachable!(),
+                    UsbEvent::Attached(BoundDevice::Unbound { device, .. }) => {
+                        ("unbound", at(device.path()))
+                    }
+                    UsbEvent::Attached(BoundDevice::Hub { device }) => ("hub", at(device.path())),
+                    UsbEvent::Attached(BoundDevice::Failed { path, .. }) => ("failed", at(*path)),
+                    UsbEvent::Detached(path) => ("detached", at(*path)),
+                    UsbEvent::OverCurrent { path, .. } => ("overcurrent", at(*path)),
+                };
+                (kind, port, route)
+            })
+            .collect()
+    }
+
+    /// Poll after `us` of virtual time; attaches are sorted by path
+    fn poll_after(
+        monitor: &mut UsbMonitor<MockHost>,
+        mock: &mock::Mock,
+        us: u64,
+    ) -> Vec<(&'static str, u8, u32)> {
+        mock.advance_us(us);
+        let mut events = summary(&monitor.poll());
+        events.sort_by_key(|&(kind, port, route)| (kind == "detached", port, route));
+        events
+    }
+
+    /// Plug `device` into, or unplug the device from, a port of the hub on
+    /// root port 0 and signal the change on the hub's status endpoint
+    fn hub_port(mock: &mock::Mock, hub: &Mutex<HubState>, port: u8, device: Option<MockDevice>) {
+        let present = port_status::CONNECTION | port_status::HIGH_SPEED;
+        let mut state = hub.lock().unwrap();
+        let status = &mut state.ports[port as usize - 1];
+        match device {
+            Some(device) => {
+                mock.attach_routed(0, port as u32, device);
+                status.0 |= present;
+            }
+            None => {
+                mock.detach_routed(0, port as u32);
+                status.0 &= !(present | port_status::ENABLE);
+            }
+        }
+        status.1 |= port_change::C_CONNECTION;
+        drop(state);
+        mock.with_device(0, 0, |d| d.push_input(0x81, Reply::Data(vec![1 << port])));
+    }
+
+    /// A keyboard whose device descriptor cannot be read
+    fn broken_keyboard() -> MockDevice {
+        mock::keyboard().with_handler(|r: &Request<'_>| match r {
+            Request::Control { setup, .. } if setup.value >> 8 == desc_type::DEVICE as u16 => {
+                Some(Reply::Stall)
+            }
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn hub_plug_storm() {
+        const MS: u64 = 1000;
+        let (ctrl, mock) = mock::controller();
+        let present = port_status::CONNECTION | port_status::HIGH_SPEED;
+
+        // A hub with a keyboard on port 1, a broken device on port 3 and a
+        // second hub with a keyboard on port 4; a keyboard on root port 1
+        let (hub, hub_state) = mock::hub(4, 0x09, false);
+        let (inner, inner_state) = mock::hub(4, 0x09, false);
+        for port in [0, 2, 3] {
+            hub_state.lock().unwrap().ports[port].0 |= present;
+        }
+        inner_state.lock().unwrap().ports[0].0 |= present;
+        mock.attach(0, hub);
+        mock.attach_routed(0, 0x1, mock::keyboard());
+        mock.attach_routed(0, 0x3, broken_keyboard());
+        mock.attach_routed(0, 0x4, inner);
+        mock.attach_routed(0, 0x14, mock::keyboard());
+        mock.attach(1, mock::keyboard());
+
+        let mut monitor = UsbMonitor::new(ctrl);
+        assert_eq!(poll_after(&mut monitor, &mock, 0), []);
+        assert_eq!(poll_after(&mut monitor, &mock, 50 * MS), []);
+        assert_eq!(
+            poll_after(&mut monitor, &mock, 60 * MS),
+            [("hub", 0, 0), ("hid", 1, 0)]
+        );
+
+        // The ports of the new hub are debounced in turn, and a keyboard
+        // plugged in, out and in again restarts its interval
+        assert_eq!(poll_after(&mut monitor, &mock, 0), []);
+        mock.advance_us(20 * MS);
+        hub_port(&mock, &hub_state, 2, Some(mock::keyboard()));
+        assert_eq!(poll_after(&mut monitor, &mock, 0), []);
+        mock.advance_us(60 * MS);
+        hub_port(&mock, &hub_state, 2, None);
+        assert_eq!(poll_after(&mut monitor, &mock, 0), []);
+        mock.advance_us(10 * MS);
+        hub_port(&mock, &hub_state, 2, Some(mock::keyboard()));
+        assert_eq!(poll_after(&mut monitor, &mock, 0), []);
+        assert_eq!(
+            poll_after(&mut monitor, &mock, 40 * MS),
+            [("hid", 0, 0x1), ("failed", 0, 0x3), ("hub", 0, 0x4)]
+        );
+        assert_eq!(poll_after(&mut monitor, &mock, 80 * MS), [("hid", 0, 0x2)]);
+        assert_eq!(poll_after(&mut monitor, &mock, 30 * MS), [("hid", 0, 0x14)]);
+        // The broken device is not tried again until its port changes
+        assert_eq!(poll_after(&mut monitor, &mock, 200 * MS), []);
+
+        // Unplugging the hub takes everything below it, deepest first
+        mock.detach(0);
+        let events = summary(&monitor.poll());
+        assert_eq!(events[0], ("detached", 0, 0x14));
+        assert_eq!(events[4], ("detached", 0, 0));
+        let mut hub_ports: Vec<_> = events[1..4].iter().map(|e| e.2).collect();
+        hub_ports.sort();
+        assert_eq!(hub_ports, [0x1, 0x2, 0x4]);
+        assert_eq!(events.len(), 5);
+        let root_1 = monitor.root_path(1);
+        assert!(monitor.attached().eq([root_1]));
+
+        // A hub bouncing in and out is only attached once it stays
+        mock.attach(0, mock::hub(4, 0x09, false).0);
+        assert_eq!(poll_after(&mut monitor, &mock, 0), []);
+        mock.detach(0);
+        assert_eq!(poll_after(&mut monitor, &mock, 50 * MS), []);
+        mock.attach(0, mock::hub(4, 0x09, false).0);
+        assert_eq!(poll_after(&mut monitor, &mock, 20 * MS), []);
+        assert_eq!(poll_after(&mut monitor, &mock, 60 * MS), []);
+        assert_eq!(poll_after(&mut monitor, &mock, 50 * MS), [("hub", 0, 0)]);
+        assert_eq!(monitor.attached().count(), 2);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn root_overcurrent_is_reported() {
+        let (ctrl, mock) = mock::controller();
+        let mut monitor = UsbMonitor::new(ctrl.clone());
+        let path = monitor.root_path(1);
+
+        mock.set_overcurrent(1, true);
+        let events = monitor.poll();
+        assert!(matches!(
+            events[..],
+            [UsbEvent::OverCurrent { path: p, powered_off: true }] if p == path
+        ));
+
+        ctrl.set_overcurrent_policy(OvercurrentPolicy::NotifyOnly);
+        mock.set_overcurrent(2, true);
+        let events = monitor.poll();
+        assert!(matches!(
+            events[..],
+            [UsbEvent::OverCurrent { path: p, powered_off: false }] if p.root_port == 2
+        ));
+        assert!(monitor.poll().is_empty());
+    }
+}
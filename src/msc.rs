@@ -3,15 +3,50 @@
 //! Provides structures and functions for interacting with USB mass storage
 //! devices using the Bulk-Only Transport (BOT) protocol.
 
+use crate::desc::{
+    EndpointDesc, InterfaceDesc, InterfaceSet, class, ep_type, for_each_interface, le32,
+    msc_protocol,
+};
+
+#[cfg(feature = "alloc")]
 use crate::{
     Dma, Result, UsbError,
-    desc::{EndpointDesc, InterfaceDesc, SetupPacket, class, ep_type, msc_protocol},
-    dev::UsbDevice,
+    desc::{SetupPacket, feature, msc_subclass},
+    dev::{DriverKind, EndpointHandle, RetryPolicy, UsbDevice},
     ring::PhysMem,
+    xhci::{Stopwatch, XhciCtrl},
 };
 
+#[cfg(feature = "alloc")]
 use alloc::sync::Arc;
-use core::hint::spin_loop;
+#[cfg(feature = "alloc")]
+use core::{fmt, hint::spin_loop};
+
+#[cfg(feature = "alloc")]
+const COMMAND_TIMEOUT_US: u32 = 10_000_000;
+#[cfg(feature = "alloc")]
+const FORMAT_TIMEOUT_US: u32 = 30_000_000;
+/// Default overall deadline of `MscDevice::format_unit`, in minutes
+const FORMAT_UNIT_MINUTES: u32 = 10;
+/// Length every UFI, SFF-8070i and MMC-5 command block is padded to
+#[cfg(feature = "alloc")]
+const PACKET_CDB_LEN: usize = 12;
+#[cfg(feature = "alloc")]
+const MAX_LUN: u8 = 15;
+/// Largest bounce buffer `read_unaligned` reads through
+#[cfg(feature = "alloc")]
+const UNALIGNED_CHUNK: usize = 64 * 1024;
+
+/// Phase of a Bulk-Only Transport command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BotPhase {
+    /// Sending the Command Block Wrapper
+    Command,
+    /// Moving the data
+    Data,
+    /// Receiving the Command Status Wrapper
+    Status,
+}
 
 /// Command Block Wrapper (CBW) - 31 bytes.
 ///
@@ -35,11 +70,19 @@ pub struct Cbw {
     pub cb: [u8; 16],
 }
 
+const _: () = {
+    assert!(size_of::<Cbw>() == 31);
+    assert!(align_of::<Cbw>() == 1);
+};
+
 impl Cbw {
     /// CBW signature constant.
     pub const SIGNATURE: u32 = 0x43425355;
 
     /// Creates a new CBW.
+    ///
+    /// Copies up to 16 CDB bytes and sets `cb_length` to the count
+    /// copied; padding for the command set is up to the caller.
     pub fn new(tag: u32, length: u32, direction_in: bool, lun: u8, cdb: &[u8]) -> Self {
         let mut cb = [0u8; 16];
         let len = cdb.len().min(16);
@@ -55,6 +98,40 @@ impl Cbw {
             cb,
         }
     }
+
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 31;
+
+    /// Decodes a CBW from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        let mut cb = [0u8; 16];
+        cb.copy_from_slice(&data[15..31]);
+        Some(Self {
+            signature: le32(data, 0),
+            tag: le32(data, 4),
+            data_transfer_length: le32(data, 8),
+            flags: data[12],
+            lun: data[13],
+            cb_length: data[14],
+            cb,
+        })
+    }
+
+    /// Encodes the CBW as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0..4].copy_from_slice(&{ self.signature }.to_le_bytes());
+        b[4..8].copy_from_slice(&{ self.tag }.to_le_bytes());
+        b[8..12].copy_from_slice(&{ self.data_transfer_length }.to_le_bytes());
+        b[12] = self.flags;
+        b[13] = self.lun;
+        b[14] = self.cb_length;
+        b[15..31].copy_from_slice(&self.cb);
+        b
+    }
 }
 
 impl Default for Cbw {
@@ -87,6 +164,11 @@ pub struct Csw {
     pub status: u8,
 }
 
+const _: () = {
+    assert!(size_of::<Csw>() == 13);
+    assert!(align_of::<Csw>() == 1);
+};
+
 impl Csw {
     /// CSW signature constant.
     pub const SIGNATURE: u32 = 0x53425355;
@@ -98,6 +180,32 @@ impl Csw {
     /// Phase error.
     pub const STATUS_PHASE_ERROR: u8 = 2;
 
+    /// Encoded size in bytes.
+    pub const SIZE: usize = 13;
+
+    /// Decodes a CSW from little-endian wire bytes.
+    pub fn from_bytes(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            signature: le32(data, 0),
+            tag: le32(data, 4),
+            data_residue: le32(data, 8),
+            status: data[12],
+        })
+    }
+
+    /// Encodes the CSW as little-endian wire bytes.
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut b = [0u8; Self::SIZE];
+        b[0..4].copy_from_slice(&{ self.signature }.to_le_bytes());
+        b[4..8].copy_from_slice(&{ self.tag }.to_le_bytes());
+        b[8..12].copy_from_slice(&{ self.data_residue }.to_le_bytes());
+        b[12] = self.status;
+        b
+    }
+
     /// Returns true if the command completed successfully.
     pub fn is_ok(&self) -> bool {
         self.signature == Self::SIGNATURE && self.status == Self::STATUS_PASSED
@@ -112,6 +220,8 @@ pub mod scsi_op {
     pub const REQUEST_SENSE: u8 = 0x03;
     /// Format Unit
     pub const FORMAT_UNIT: u8 = 0x04;
+    /// Write (6)
+    pub const WRITE_6: u8 = 0x0A;
     /// Inquiry
     pub const INQUIRY: u8 = 0x12;
     /// Mode Select (6)
@@ -120,6 +230,8 @@ pub mod scsi_op {
     pub const MODE_SENSE_6: u8 = 0x1A;
     /// Start/Stop Unit
     pub const START_STOP_UNIT: u8 = 0x1B;
+    /// Send Diagnostic
+    pub const SEND_DIAGNOSTIC: u8 = 0x1D;
     /// Prevent/Allow Medium Removal
     pub const PREVENT_ALLOW_MEDIUM_REMOVAL: u8 = 0x1E;
     /// Read Format Capacities
@@ -138,6 +250,12 @@ pub mod scsi_op {
     pub const VERIFY_10: u8 = 0x2F;
     /// Synchronize Cache (10)
     pub const SYNCHRONIZE_CACHE_10: u8 = 0x35;
+    /// Write Buffer
+    pub const WRITE_BUFFER: u8 = 0x3B;
+    /// Write Same (10)
+    pub const WRITE_SAME_10: u8 = 0x41;
+    /// Unmap
+    pub const UNMAP: u8 = 0x42;
     /// Read TOC/PMA/ATIP
     pub const READ_TOC: u8 = 0x43;
     /// Mode Select (10)
@@ -154,6 +272,296 @@ pub mod scsi_op {
     pub const READ_16: u8 = 0x88;
     /// Write (16)
     pub const WRITE_16: u8 = 0x8A;
+    /// Write and Verify (16)
+    pub const WRITE_AND_VERIFY_16: u8 = 0x8E;
+    /// Write Same (16)
+    pub const WRITE_SAME_16: u8 = 0x93;
+    /// Write and Verify (12)
+    pub const WRITE_AND_VERIFY_12: u8 = 0xAE;
+    /// ATA Pass-Through (16)
+    pub const ATA_PASS_THROUGH_16: u8 = 0x85;
+    /// ATA Pass-Through (12)
+    pub const ATA_PASS_THROUGH_12: u8 = 0xA1;
+}
+
+/// Data phase of a SCSI command.
+#[derive(Debug)]
+pub enum DataPhase<'a> {
+    /// No data phase
+    None,
+    /// Device to host, into the buffer
+    In(&'a mut [u8]),
+    /// Host to device, from the buffer
+    Out(&'a [u8]),
+}
+
+impl DataPhase<'_> {
+    /// Returns the length of the data phase in bytes.
+    pub fn len(&self) -> usize {
+        match self {
+            Self::None => 0,
+            Self::In(d) => d.len(),
+            Self::Out(d) => d.len(),
+        }
+    }
+
+    /// Returns true if there is no data to move.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Outcome of a command sent with `MscDevice::pass_through`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScsiResult {
+    /// Bytes moved in the data phase, net of the CSW data residue
+    ///
+    /// Bytes of an IN buffer past this count are zero-filled.
+    pub transferred: usize,
+    /// CSW status (see `Csw::STATUS_*`)
+    pub status: u8,
+    /// Sense data, fetched when the command failed
+    pub sense: Option<RequestSenseData>,
+    /// Sense data as returned by the device, zero-filled
+    ///
+    /// Holds descriptor-format sense (such as the ATA status return
+    /// descriptor) that does not fit `RequestSenseData`.
+    pub raw_sense: [u8; 32],
+}
+
+/// Parameters of `MscDevice::format_unit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FormatParams {
+    /// Number of blocks to format (UFI only)
+    pub num_blocks: u32,
+    /// Block size in bytes (UFI only)
+    pub block_size: u32,
+    /// Return before the format completes (SCSI only; UFI drives
+    /// always format before completing the command)
+    pub immediate: bool,
+    /// Overall deadline of the format, in minutes
+    pub timeout_min: u32,
+}
+
+impl FormatParams {
+    /// Parameters for an immediate format of `num_blocks` blocks of `block_size` bytes.
+    ///
+    /// A 1.44 MB floppy has 2880 blocks of 512 bytes.
+    pub fn new(num_blocks: u32, block_size: u32) -> Self {
+        Self {
+            num_blocks,
+            block_size,
+            immediate: true,
+            timeout_min: FORMAT_UNIT_MINUTES,
+        }
+    }
+}
+
+/// How long `MscDevice::wait_ready` keeps polling a unit that is not ready.
+///
+/// The default makes 20 attempts 100 ms apart, enough for most flash
+/// drives after SET_CONFIGURATION.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReadyPolicy {
+    /// TEST UNIT READY attempts, including the first (0 acts as 1)
+    pub attempts: u16,
+    /// Delay before each retry, in microseconds
+    pub delay_us: u32,
+}
+
+impl Default for ReadyPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 20,
+            delay_us: 100_000,
+        }
+    }
+}
+
+/// Outcome of `MscDevice::wait_ready`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnitReadiness {
+    /// The unit passed TEST UNIT READY
+    Ready,
+    /// The unit reported MEDIUM NOT PRESENT
+    NoMedium,
+}
+
+/// State of a format started with `MscDevice::format_unit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FormatProgress {
+    /// Still formatting, with the progress indication out of 65536 if
+    /// the device reports one
+    InProgress(Option<u16>),
+    /// The unit is ready again
+    Complete,
+}
+
+impl FormatProgress {
+    /// Returns the completed percentage, if known.
+    pub fn percent(&self) -> Option<u8> {
+        match self {
+            Self::InProgress(progress) => progress.map(|p| (p as u32 * 100 / 65536) as u8),
+            Self::Complete => Some(100),
+        }
+    }
+}
+
+/// Format started with `MscDevice::format_unit`.
+///
+/// The deadline runs from the completion of FORMAT UNIT and only
+/// advances while `progress` is polled, at least once a second.
+#[cfg(feature = "alloc")]
+pub struct FormatHandle {
+    lun: u8,
+    watch: Stopwatch,
+    timeout_us: u64,
+}
+
+#[cfg(feature = "alloc")]
+impl FormatHandle {
+    /// Returns the LUN being formatted.
+    pub fn lun(&self) -> u8 {
+        self.lun
+    }
+
+    /// Polls the progress of the format with TEST UNIT READY.
+    ///
+    /// The progress indication is taken from the sense-key specific
+    /// bytes of the NOT READY (format in progress) sense data. Fails
+    /// with `Timeout` once the overall deadline has passed, and with
+    /// `XferFail` if the unit reports any other error.
+    pub fn progress<H: Dma>(&mut self, msc: &mut MscDevice<H>) -> Result<FormatProgress> {
+        let cdb = [scsi_op::TEST_UNIT_READY, 0, 0, 0, 0, 0];
+        let timeout_us = MscDevice::<H>::default_timeout_us(&cdb);
+        let result = msc.pass_through(self.lun, &cdb, DataPhase::None, timeout_us)?;
+        if result.status == Csw::STATUS_PASSED {
+            msc.geometry[self.lun as usize] = None;
+            return Ok(FormatProgress::Complete);
+        }
+
+        let sense = result.sense.unwrap_or_default();
+        let formatting = sense.sense_key() == sense_key::NOT_READY
+            && sense.asc == asc::NOT_READY
+            && sense.ascq == asc::FORMAT_IN_PROGRESS;
+        if !formatting {
+            return Err(UsbError::XferFail(result.status));
+        }
+        if self.watch.elapsed_us(msc.device.ctrl()) >= self.timeout_us {
+            return Err(UsbError::Timeout);
+        }
+        Ok(FormatProgress::InProgress(sense.progress()))
+    }
+}
+
+/// What a SCSI command does to the medium.
+#[cfg(feature = "alloc")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CommandClass {
+    /// Reads the medium or its geometry
+    Read,
+    /// May modify the medium or device settings
+    Write,
+    /// Neither reads nor modifies the medium
+    Control,
+}
+
+#[cfg(feature = "alloc")]
+impl CommandClass {
+    /// Classifies a CDB; unknown opcodes count as writes.
+    pub(crate) fn of(cdb: &[u8]) -> Self {
+        use scsi_op::*;
+        match cdb[0] {
+            ATA_PASS_THROUGH_16 if cdb.len() >= 15 => Self::of_ata(cdb[14], cdb[4]),
+            ATA_PASS_THROUGH_12 if cdb.len() >= 10 => Self::of_ata(cdb[9], cdb[3]),
+            READ_10
+            | READ_12
+            | READ_16
+            | READ_CAPACITY_10
+            | READ_CAPACITY_16
+            | READ_FORMAT_CAPACITIES
+            | READ_TOC
+            | VERIFY_10 => Self::Read,
+            TEST_UNIT_READY
+            | REQUEST_SENSE
+            | INQUIRY
+            | MODE_SENSE_6
+            | MODE_SENSE_10
+            | START_STOP_UNIT
+            | PREVENT_ALLOW_MEDIUM_REMOVAL
+            | SEEK_10 => Self::Control,
+            _ => Self::Write,
+        }
+    }
+
+    /// Classifies an ATA command sent through ATA PASS-THROUGH.
+    fn of_ata(command: u8, features: u8) -> Self {
+        match (command, features) {
+            (ata::IDENTIFY_DEVICE, _) => Self::Read,
+            (
+                ata::SMART,
+                ata::SMART_READ_DATA
+                | ata::SMART_READ_THRESHOLDS
+                | ata::SMART_READ_LOG
+                | ata::SMART_RETURN_STATUS,
+            ) => Self::Read,
+            _ => Self::Write,
+        }
+    }
+}
+
+/// ATA commands and SAT fields used by the ATA PASS-THROUGH helpers.
+#[cfg(feature = "alloc")]
+mod ata {
+    /// IDENTIFY DEVICE
+    pub const IDENTIFY_DEVICE: u8 = 0xEC;
+    /// SMART, the operation is given by the features register
+    pub const SMART: u8 = 0xB0;
+    /// SMART READ DATA
+    pub const SMART_READ_DATA: u8 = 0xD0;
+    /// SMART READ ATTRIBUTE THRESHOLDS
+    pub const SMART_READ_THRESHOLDS: u8 = 0xD1;
+    /// SMART READ LOG
+    pub const SMART_READ_LOG: u8 = 0xD5;
+    /// SMART RETURN STATUS
+    pub const SMART_RETURN_STATUS: u8 = 0xDA;
+    /// LBA mid/high signature required by every SMART command
+    pub const SMART_LBA_MID: u8 = 0x4F;
+    pub const SMART_LBA_HIGH: u8 = 0xC2;
+    /// LBA mid/high returned by SMART RETURN STATUS on a threshold exceeded
+    pub const SMART_FAILING_LBA_MID: u8 = 0xF4;
+    pub const SMART_FAILING_LBA_HIGH: u8 = 0x2C;
+
+    /// SAT protocol field: non-data
+    pub const PROTOCOL_NON_DATA: u8 = 3;
+    /// SAT protocol field: PIO data-in
+    pub const PROTOCOL_PIO_IN: u8 = 4;
+    /// CK_COND: return the ATA registers in the sense data
+    pub const CK_COND: u8 = 0x20;
+    /// T_DIR: data flows from the device
+    pub const T_DIR_IN: u8 = 0x08;
+    /// BYT_BLOK: the transfer length counts blocks
+    pub const BYT_BLOK: u8 = 0x04;
+    /// T_LENGTH: the transfer length is in the count field
+    pub const T_LENGTH_COUNT: u8 = 0x02;
+    /// Sense descriptor code of the ATA Status Return descriptor
+    pub const STATUS_RETURN_DESCRIPTOR: u8 = 0x09;
+
+    /// Build an ATA PASS-THROUGH (16) CDB for a 28-bit command
+    pub fn cdb16(protocol: u8, flags: u8, features: u8, count: u8, command: u8) -> [u8; 16] {
+        let mut cdb = [0u8; 16];
+        cdb[0] = super::scsi_op::ATA_PASS_THROUGH_16;
+        cdb[1] = protocol << 1;
+        cdb[2] = flags;
+        cdb[4] = features;
+        cdb[6] = count;
+        if command == SMART {
+            cdb[10] = SMART_LBA_MID;
+            cdb[12] = SMART_LBA_HIGH;
+        }
+        cdb[14] = command;
+        cdb
+    }
 }
 
 /// SCSI Inquiry data (standard response, 36 bytes minimum).
@@ -180,6 +588,11 @@ pub struct InquiryData {
     pub revision: [u8; 4],
 }
 
+const _: () = {
+    assert!(size_of::<InquiryData>() == 36);
+    assert!(align_of::<InquiryData>() == 1);
+};
+
 impl InquiryData {
     /// Returns the peripheral device type (0x00 = direct access block device).
     pub fn device_type(&self) -> u8 {
@@ -202,6 +615,11 @@ pub struct ReadCapacity10Data {
     pub block_size: u32,
 }
 
+const _: () = {
+    assert!(size_of::<ReadCapacity10Data>() == 8);
+    assert!(align_of::<ReadCapacity10Data>() == 1);
+};
+
 impl ReadCapacity10Data {
     /// Returns the last LBA (converted from big-endian).
     pub fn last_lba(&self) -> u32 {
@@ -219,6 +637,86 @@ impl ReadCapacity10Data {
     }
 }
 
+/// Read Capacity (16) response data.
+#[repr(C, packed)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadCapacity16Data {
+    /// Last logical block address (big-endian)
+    pub last_lba: u64,
+    /// Block size in bytes (big-endian)
+    pub block_size: u32,
+    /// Protection type and enable
+    pub protection: u8,
+    /// P_I exponent in bits 7:4, logical blocks per physical block exponent in bits 3:0
+    pub lbppbe: u8,
+    /// LBPME, LBPRZ and the lowest aligned LBA in bits 13:0 (big-endian)
+    pub lowest_aligned: u16,
+    /// Reserved
+    pub reserved: [u8; 16],
+}
+
+const _: () = {
+    assert!(size_of::<ReadCapacity16Data>() == 32);
+    assert!(align_of::<ReadCapacity16Data>() == 1);
+};
+
+impl ReadCapacity16Data {
+    /// Returns the last LBA (converted from big-endian).
+    pub fn last_lba(&self) -> u64 {
+        u64::from_be(self.last_lba)
+    }
+
+    /// Returns the block size (converted from big-endian).
+    pub fn block_size(&self) -> u32 {
+        u32::from_be(self.block_size)
+    }
+
+    /// Returns the total capacity in bytes.
+    pub fn capacity_bytes(&self) -> u64 {
+        self.last_lba()
+            .saturating_add(1)
+            .saturating_mul(self.block_size() as u64)
+    }
+
+    /// Returns the exponent of logical blocks per physical block.
+    ///
+    /// A physical block holds 2^exponent logical blocks.
+    pub fn physical_block_exponent(&self) -> u8 {
+        self.lbppbe & 0x0F
+    }
+
+    /// Returns the first LBA that starts a physical block.
+    pub fn lowest_aligned_lba(&self) -> u16 {
+        u16::from_be(self.lowest_aligned) & 0x3FFF
+    }
+}
+
+/// Logical unit geometry cached by `MscDevice`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BlockGeometry {
+    /// Logical block size in bytes
+    pub block_size: u32,
+    /// Number of logical blocks
+    pub num_blocks: u64,
+    /// A physical block holds 2^exponent logical blocks; 0 unless
+    /// READ CAPACITY (16) reported it
+    pub physical_block_exponent: u8,
+    /// First LBA that starts a physical block
+    pub lowest_aligned_lba: u16,
+}
+
+impl BlockGeometry {
+    /// Returns the physical block size in bytes.
+    pub fn physical_block_size(&self) -> u64 {
+        (self.block_size as u64) << self.physical_block_exponent.min(15)
+    }
+
+    /// Returns the total capacity in bytes.
+    pub fn capacity_bytes(&self) -> u64 {
+        self.num_blocks.saturating_mul(self.block_size as u64)
+    }
+}
+
 /// Request Sense data (fixed format).
 #[repr(C, packed)]
 #[derive(Clone, Copy, Debug, Default)]
@@ -245,11 +743,67 @@ pub struct RequestSenseData {
     pub sense_key_specific: [u8; 3],
 }
 
+const _: () = {
+    assert!(size_of::<RequestSenseData>() == 18);
+    assert!(align_of::<RequestSenseData>() == 1);
+};
+
 impl RequestSenseData {
+    /// Parses fixed or descriptor format sense data.
+    ///
+    /// Descriptor format (0x72, 0x73) only fills the response code, sense
+    /// key, ASC, ASCQ and additional sense length.
+    pub fn from_bytes(data: &[u8]) -> Self {
+        let mut raw = [0u8; 18];
+        let len = data.len().min(raw.len());
+        raw[..len].copy_from_slice(&data[..len]);
+        match raw[0] & 0x7F {
+            0x72 | 0x73 => Self {
+                response_code: raw[0],
+                sense_key: raw[1],
+                asc: raw[2],
+                ascq: raw[3],
+                additional_sense_length: raw[7],
+                ..Self::default()
+            },
+            _ => unsafe { *(raw.as_ptr() as *const Self) },
+        }
+    }
+
     /// Returns the sense key.
     pub fn sense_key(&self) -> u8 {
         self.sense_key & 0x0F
     }
+
+    /// Returns the progress indication out of 65536, if the sense-key
+    /// specific bytes hold one.
+    ///
+    /// Reported with NOT READY and NO SENSE while a long operation such
+    /// as FORMAT UNIT runs.
+    pub fn progress(&self) -> Option<u16> {
+        let key = self.sense_key();
+        let sksv = self.sense_key_specific[0] & 0x80 != 0;
+        if !sksv || !matches!(key, sense_key::NOT_READY | sense_key::NO_SENSE) {
+            return None;
+        }
+        Some(u16::from_be_bytes([
+            self.sense_key_specific[1],
+            self.sense_key_specific[2],
+        ]))
+    }
+}
+
+/// Additional sense codes and qualifiers.
+#[cfg(feature = "alloc")]
+mod asc {
+    /// LOGICAL UNIT NOT READY
+    pub const NOT_READY: u8 = 0x04;
+    /// Qualifier of LOGICAL UNIT NOT READY: BECOMING READY
+    pub const BECOMING_READY: u8 = 0x01;
+    /// Qualifier of LOGICAL UNIT NOT READY: FORMAT IN PROGRESS
+    pub const FORMAT_IN_PROGRESS: u8 = 0x04;
+    /// MEDIUM NOT PRESENT
+    pub const MEDIUM_NOT_PRESENT: u8 = 0x3A;
 }
 
 /// SCSI sense keys.
@@ -284,18 +838,96 @@ pub mod sense_key {
     pub const MISCOMPARE: u8 = 0x0E;
 }
 
+/// One entry of the SMART attribute table.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmartAttribute {
+    /// Attribute ID
+    pub id: u8,
+    /// Status flags
+    pub flags: u16,
+    /// Current normalized value
+    pub current: u8,
+    /// Worst normalized value seen
+    pub worst: u8,
+    /// Raw value (48 bits, vendor specific meaning)
+    pub raw: u64,
+}
+
+/// SMART data read from an ATA drive through SAT.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SmartData {
+    /// SMART data structure revision
+    pub revision: u16,
+    attributes: [SmartAttribute; SmartData::MAX_ATTRIBUTES],
+    count: usize,
+    /// Overall health from SMART RETURN STATUS: `Some(true)` if no
+    /// threshold is exceeded, `None` if the bridge could not report it
+    pub passed: Option<bool>,
+}
+
+impl SmartData {
+    /// Size of the SMART READ DATA response
+    pub const SIZE: usize = 512;
+    /// Entries in the attribute table
+    pub const MAX_ATTRIBUTES: usize = 30;
+
+    /// Parses the response to SMART READ DATA; `passed` is left unset.
+    pub fn from_bytes(data: &[u8; Self::SIZE]) -> Self {
+        let mut smart = Self {
+            revision: u16::from_le_bytes([data[0], data[1]]),
+            ..Self::default()
+        };
+        // 12-byte entries from offset 2; ID 0 marks an unused entry
+        for entry in data[2..2 + 12 * Self::MAX_ATTRIBUTES].chunks_exact(12) {
+            if entry[0] == 0 {
+                continue;
+            }
+            let mut raw = [0u8; 8];
+            raw[..6].copy_from_slice(&entry[5..11]);
+            smart.attributes[smart.count] = SmartAttribute {
+                id: entry[0],
+                flags: u16::from_le_bytes([entry[1], entry[2]]),
+                current: entry[3],
+                worst: entry[4],
+                raw: u64::from_le_bytes(raw),
+            };
+            smart.count += 1;
+        }
+        smart
+    }
+
+    /// Returns the attributes present in the table.
+    pub fn attributes(&self) -> &[SmartAttribute] {
+        &self.attributes[..self.count]
+    }
+
+    /// Returns the attribute with the given ID.
+    pub fn attribute(&self, id: u8) -> Option<&SmartAttribute> {
+        self.attributes().iter().find(|a| a.id == id)
+    }
+}
+
 /// USB Mass Storage device.
+#[cfg(feature = "alloc")]
 pub struct MscDevice<H: Dma> {
     device: Arc<UsbDevice<H>>,
     interface: u8,
-    ep_in: u8,
-    ep_out: u8,
-    ep_in_max_packet: u16,
-    ep_out_max_packet: u16,
+    ep_in: EndpointHandle<H>,
+    ep_out: EndpointHandle<H>,
+    ep_descs: [EndpointDesc; 2],
     max_lun: u8,
     tag: u32,
+    /// Phase being waited for, kept only if the command timed out
+    timed_out: Option<BotPhase>,
+    /// bInterfaceSubClass, selecting the command set
+    subclass: u8,
+    read_only: bool,
+    max_transfer: usize,
+    /// Geometry by LUN, cached from READ CAPACITY
+    geometry: [Option<BlockGeometry>; MAX_LUN as usize + 1],
 }
 
+#[cfg(feature = "alloc")]
 impl<H: Dma> MscDevice<H> {
     /// Creates a new MSC device from interface and endpoint descriptors.
     pub fn from_interface(
@@ -309,19 +941,26 @@ impl<H: Dma> MscDevice<H> {
         }
 
         // Configure endpoints
-        device.configure_endpoint(ep_in)?;
-        device.configure_endpoint(ep_out)?;
+        let [ep_in_handle, ep_out_handle]: [EndpointHandle<H>; 2] = device
+            .configure_endpoints(&[*ep_in, *ep_out])?
+            .try_into()
+            .map_err(|_| UsbError::InvEndpoint)?;
 
         let mut msc = Self {
             device,
             interface: iface.interface_number,
-            ep_in: ep_in.number(),
-            ep_out: ep_out.number(),
-            ep_in_max_packet: ep_in.max_packet_size,
-            ep_out_max_packet: ep_out.max_packet_size,
+            ep_in: ep_in_handle,
+            ep_out: ep_out_handle,
+            ep_descs: [*ep_in, *ep_out],
             max_lun: 0,
             tag: 1,
+            timed_out: None,
+            subclass: iface.interface_subclass,
+            read_only: false,
+            max_transfer: u32::MAX as usize,
+            geometry: [None; MAX_LUN as usize + 1],
         };
+        msc.device.bind_driver(DriverKind::MassStorage);
 
         // Get max LUN
         msc.max_lun = msc.get_max_lun().unwrap_or(0);
@@ -329,17 +968,40 @@ impl<H: Dma> MscDevice<H> {
         Ok(msc)
     }
 
+    /// Reconfigure the device after `UsbDevice::reset_and_restore`
+    ///
+    /// Re-creates both bulk endpoints, re-reads the maximum LUN and
+    /// restarts the CBW tag sequence. The cached geometry is dropped.
+    pub fn reinit(&mut self) -> Result<()> {
+        let [ep_in, ep_out]: [EndpointHandle<H>; 2] = self
+            .device
+            .configure_endpoints(&self.ep_descs)?
+            .try_into()
+            .map_err(|_| UsbError::InvEndpoint)?;
+        self.ep_in = ep_in;
+        self.ep_out = ep_out;
+        self.max_lun = self.get_max_lun().unwrap_or(0);
+        self.tag = 1;
+        self.geometry = [None; MAX_LUN as usize + 1];
+        Ok(())
+    }
+
     /// Returns the maximum LUN number.
+    ///
+    /// Every `lun` argument must be between 0 and this value, otherwise
+    /// the command fails with `InvLun`.
     pub fn max_lun(&self) -> u8 {
         self.max_lun
     }
 
     /// Gets the maximum LUN from the device.
+    ///
+    /// Clamped to 15, the highest LUN a CBW can address.
     fn get_max_lun(&self) -> Result<u8> {
         let mut buf = [0u8; 1];
         let setup = SetupPacket::msc_get_max_lun(self.interface);
         match self.device.control_transfer(&setup, Some(&mut buf)) {
-            Ok(_) => Ok(buf[0]),
+            Ok(_) => Ok(buf[0].min(MAX_LUN)),
             Err(UsbError::Stall) => Ok(0), // Single LUN device
             Err(e) => Err(e),
         }
@@ -352,7 +1014,60 @@ impl<H: Dma> MscDevice<H> {
         Ok(())
     }
 
-    /// Executes a SCSI command.
+    /// Performs Bulk-Only reset recovery.
+    ///
+    /// Drops the transfers left on both bulk endpoints, then issues a
+    /// Mass Storage Reset and clears the halt on both endpoints.
+    pub fn reset_recovery(&self) -> Result<()> {
+        for ep in [&self.ep_in, &self.ep_out] {
+            ep.restart()?;
+            // Discard the Stopped event of the aborted transfer
+            while ep.poll().is_some() {}
+        }
+
+        self.reset()?;
+        for desc in &self.ep_descs {
+            let setup =
+                SetupPacket::clear_endpoint_feature(feature::ENDPOINT_HALT, desc.endpoint_address);
+            self.device.control_transfer(&setup, None)?;
+        }
+        Ok(())
+    }
+
+    /// Opens the device read-only, or lifts the restriction.
+    ///
+    /// While read-only, every command that may modify the medium or the
+    /// device settings fails with `ReadOnly` before anything is sent. This
+    /// includes SYNCHRONIZE CACHE and any opcode not known to be harmless.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Returns true if the device is opened read-only.
+    pub fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Returns the phase in which the last command timed out.
+    ///
+    /// Cleared when the next command starts.
+    pub fn timed_out_phase(&self) -> Option<BotPhase> {
+        self.timed_out
+    }
+
+    /// Returns the default deadline of a command, in microseconds.
+    ///
+    /// 30 s for FORMAT UNIT, 10 s for everything else.
+    pub fn default_timeout_us(cdb: &[u8]) -> u32 {
+        match cdb.first() {
+            Some(&scsi_op::FORMAT_UNIT) => FORMAT_TIMEOUT_US,
+            _ => COMMAND_TIMEOUT_US,
+        }
+    }
+
+    /// Executes a SCSI command with the default deadline.
+    ///
+    /// A CSW status other than passed is returned as `XferFail`.
     pub fn scsi_command(
         &mut self,
         lun: u8,
@@ -360,91 +1075,312 @@ impl<H: Dma> MscDevice<H> {
         data: Option<&mut [u8]>,
         direction_in: bool,
     ) -> Result<usize> {
-        let host = self.device.ctrl().host();
-        let data_len = data.as_ref().map(|d| d.len()).unwrap_or(0);
+        let timeout_us = Self::default_timeout_us(cdb);
+        self.scsi_command_timeout(lun, cdb, data, direction_in, timeout_us)
+    }
+
+    /// Executes a SCSI command that must complete within `timeout_us`.
+    ///
+    /// See `pass_through` for the deadline; a CSW status other than passed
+    /// is returned as `XferFail`.
+    pub fn scsi_command_timeout(
+        &mut self,
+        lun: u8,
+        cdb: &[u8],
+        data: Option<&mut [u8]>,
+        direction_in: bool,
+        timeout_us: u32,
+    ) -> Result<usize> {
+        let data = match data {
+            Some(d) if direction_in => DataPhase::In(d),
+            Some(d) => DataPhase::Out(d),
+            None => DataPhase::None,
+        };
+        let result = self.pass_through(lun, cdb, data, timeout_us)?;
+        if result.status != Csw::STATUS_PASSED {
+            return Err(UsbError::XferFail(result.status));
+        }
+        Ok(result.transferred)
+    }
+
+    /// Sets the largest data phase `pass_through` accepts, in bytes.
+    ///
+    /// Rounded down to a multiple of the bulk IN packet size, so a read
+    /// split at this size never ends in an unintended short packet.
+    pub fn set_max_transfer(&mut self, bytes: usize) {
+        let granularity = self.bulk_max_packet();
+        self.max_transfer = if bytes >= granularity {
+            bytes - bytes % granularity
+        } else {
+            bytes
+        };
+    }
+
+    /// Returns the largest data phase `pass_through` accepts, in bytes.
+    pub fn max_transfer(&self) -> usize {
+        self.max_transfer
+    }
+
+    /// Returns the max packet size of the bulk IN endpoint in bytes.
+    ///
+    /// A data phase that is not a multiple of this size, or that the
+    /// device cuts short, ends with a short packet.
+    pub fn bulk_max_packet(&self) -> usize {
+        self.device.transfer_granularity(&self.ep_descs[0])
+    }
+
+    /// Sends an arbitrary CDB and reports the outcome.
+    ///
+    /// `lun` must not exceed `max_lun`, otherwise `InvLun` is returned.
+    /// The CDB must be 1 to 16 bytes (12 for UFI, SFF-8070i and MMC-5,
+    /// whose command sets only take 12-byte blocks; shorter CDBs are
+    /// zero-padded to 12 bytes) and the data phase no longer than
+    /// `max_transfer`, otherwise `InvalidArgument` is returned. Commands
+    /// that may write fail with `ReadOnly` on a read-only device.
+    ///
+    /// The deadline of `timeout_us` covers the command, data and status
+    /// phases together; 0 waits forever. When it expires, reset recovery
+    /// is performed, `Timeout` is returned and `timed_out_phase` reports
+    /// the phase that was waiting. Completions lost to an event ring
+    /// overflow are handled the same way, returning `EventLost`.
+    ///
+    /// A failed command is reported in `ScsiResult::status` with the sense
+    /// data fetched by REQUEST SENSE. A phase error, an invalid CSW or a
+    /// data residue larger than the data phase triggers reset recovery
+    /// and is reported as a phase error.
+    ///
+    /// Only the bytes the device actually sent, as given by the data
+    /// residue and checked against the transfer, are returned; the rest
+    /// of an IN buffer is zero-filled.
+    ///
+    /// Repeated babble or transaction errors reset the device as set by
+    /// its `ErrorPolicy`; if that fails, `DeviceFailed` is returned.
+    pub fn pass_through(
+        &mut self,
+        lun: u8,
+        cdb: &[u8],
+        data: DataPhase<'_>,
+        timeout_us: u32,
+    ) -> Result<ScsiResult> {
+        if lun > self.max_lun {
+            return Err(UsbError::InvLun);
+        }
+        let max_cdb = if self.pads_cdb() { PACKET_CDB_LEN } else { 16 };
+        if !(1..=max_cdb).contains(&cdb.len()) || data.len() > self.max_transfer {
+            return Err(UsbError::InvalidArgument);
+        }
+        let opcode = cdb[0];
+        if self.read_only && CommandClass::of(cdb) == CommandClass::Write {
+            return Err(UsbError::ReadOnly);
+        }
+
+        let (transferred, status) = match self.execute(lun, cdb, data, timeout_us) {
+            Ok(done) => done,
+            Err(e) => {
+                self.escalate_errors()?;
+                return Err(e);
+            }
+        };
+        let raw_sense = match status {
+            Csw::STATUS_PASSED => None,
+            Csw::STATUS_FAILED if opcode != scsi_op::REQUEST_SENSE => self.fetch_sense(lun).ok(),
+            Csw::STATUS_FAILED => None,
+            _ => {
+                self.reset_recovery()?;
+                None
+            }
+        };
+
+        Ok(ScsiResult {
+            transferred,
+            status,
+            sense: raw_sense.map(|raw| RequestSenseData::from_bytes(&raw)),
+            raw_sense: raw_sense.unwrap_or_default(),
+        })
+    }
+
+    /// Reset the device if its endpoints hit the `ErrorPolicy` limit
+    fn escalate_errors(&mut self) -> Result<()> {
+        if self.device.recover_from_errors()? {
+            self.reinit()?;
+        }
+        Ok(())
+    }
+
+    /// Run a command; returns the bytes moved and the CSW status
+    ///
+    /// An invalid CSW is reported as a phase error.
+    fn execute(
+        &mut self,
+        lun: u8,
+        cdb: &[u8],
+        data: DataPhase<'_>,
+        timeout_us: u32,
+    ) -> Result<(usize, u8)> {
+        debug_assert!(lun <= self.max_lun);
+        let data_len = data.len();
+        self.timed_out = None;
 
         // Allocate buffers (64-byte alignment for DMA)
-        let cbw_buf = PhysMem::alloc(host, core::mem::size_of::<Cbw>(), 64)?;
-        let csw_buf = PhysMem::alloc(host, core::mem::size_of::<Csw>(), 64)?;
+        let ctrl = self.device.ctrl().clone();
+        let cbw_buf = ctrl.alloc_mem(Cbw::SIZE, 64)?;
+        let csw_buf = ctrl.alloc_mem(Csw::SIZE, 64)?;
         let data_buf = if data_len > 0 {
-            Some(PhysMem::alloc(host, data_len, 64)?)
+            Some(ctrl.alloc_mem(data_len, 64)?)
         } else {
             None
         };
 
-        // Build and send CBW
+        // Build CBW; UFI, SFF-8070i and ATAPI devices only accept
+        // 12-byte command blocks and fail shorter ones with a phase error
+        let mut packet_cdb = [0u8; PACKET_CDB_LEN];
+        let cdb = if self.pads_cdb() {
+            packet_cdb[..cdb.len()].copy_from_slice(cdb);
+            &packet_cdb[..]
+        } else {
+            cdb
+        };
+        let direction_in = matches!(data, DataPhase::In(_));
         let cbw = Cbw::new(self.tag, data_len as u32, direction_in, lun, cdb);
         self.tag = self.tag.wrapping_add(1);
 
+        let bufs = (&cbw_buf, data_buf.as_ref(), &csw_buf);
+        let result = self.run_command(&ctrl, &cbw, bufs, data, timeout_us);
+
+        // Transfers still on the rings point into the buffers; a stalled
+        // endpoint stays halted until recovered
+        let recovered = match result {
+            Err(UsbError::Timeout | UsbError::EventLost) => self.reset_recovery().is_ok(),
+            Err(UsbError::Stall) => {
+                self.timed_out = None;
+                self.reset_recovery().is_ok()
+            }
+            _ => {
+                self.timed_out = None;
+                true
+            }
+        };
+
+        // Free buffers
+        if recovered {
+            let host = ctrl.host();
+            cbw_buf.free(host);
+            csw_buf.free(host);
+            if let Some(buf) = data_buf {
+                buf.free(host);
+            }
+        }
+
+        result
+    }
+
+    /// Run the three BOT phases of a command
+    fn run_command(
+        &mut self,
+        ctrl: &XhciCtrl<H>,
+        cbw: &Cbw,
+        (cbw_buf, data_buf, csw_buf): (&PhysMem<H>, Option<&PhysMem<H>>, &PhysMem<H>),
+        data: DataPhase<'_>,
+        timeout_us: u32,
+    ) -> Result<(usize, u8)> {
+        let mut watch = ctrl.stopwatch();
+
+        // Send CBW
+        let cbw_bytes = cbw.to_bytes();
         unsafe {
-            core::ptr::copy_nonoverlapping(&cbw as *const Cbw as *const u8, cbw_buf.as_ptr(), 31);
+            core::ptr::copy_nonoverlapping(cbw_bytes.as_ptr(), cbw_buf.as_ptr(), Cbw::SIZE);
         }
 
-        self.device
-            .queue_transfer(self.ep_out, false, &cbw_buf, 31)?;
-        self.wait_transfer()?;
+        self.timed_out = Some(BotPhase::Command);
+        self.ep_out.queue(cbw_buf, Cbw::SIZE)?;
+        Self::wait_transfer(ctrl, &self.ep_out, Cbw::SIZE, &mut watch, timeout_us)?;
+        cbw_buf.check_out(&cbw_bytes);
 
-        // Data phase (if any)
-        let transferred = if let (Some(buf), Some(ref mut d)) = (&data_buf, data) {
-            if direction_in {
+        // Data phase (if any); IN data is only handed out once the CSW
+        // says how much of it is valid
+        self.timed_out = Some(BotPhase::Data);
+        let data_len = data.len();
+        let (transferred, data_in) = match (data_buf, data) {
+            (Some(buf), DataPhase::In(d)) => {
                 // IN: device to host
-                self.device
-                    .queue_transfer(self.ep_in, true, buf, data_len)?;
-                let len = self.wait_transfer()?;
-                unsafe {
-                    core::ptr::copy_nonoverlapping(
-                        buf.as_ptr::<u8>(),
-                        d.as_mut_ptr(),
-                        len.min(d.len()),
-                    );
-                }
-                len
-            } else {
+                buf.poison(d.len());
+                self.ep_in.queue(buf, d.len())?;
+                let len = Self::wait_transfer(ctrl, &self.ep_in, d.len(), &mut watch, timeout_us)?;
+                buf.check_in(len, d.len());
+                (len, Some((buf, d)))
+            }
+            (Some(buf), DataPhase::Out(d)) => {
                 // OUT: host to device
                 unsafe {
                     core::ptr::copy_nonoverlapping(d.as_ptr(), buf.as_ptr(), d.len());
                 }
-                self.device
-                    .queue_transfer(self.ep_out, false, buf, data_len)?;
-                self.wait_transfer()?
+                self.ep_out.queue(buf, d.len())?;
+                let len = Self::wait_transfer(ctrl, &self.ep_out, d.len(), &mut watch, timeout_us)?;
+                buf.check_out(d);
+                (len, None)
             }
-        } else {
-            0
+            _ => (0, None),
         };
 
         // Receive CSW
-        self.device.queue_transfer(self.ep_in, true, &csw_buf, 13)?;
-        self.wait_transfer()?;
-
-        let csw = unsafe { *(csw_buf.as_ptr::<Csw>()) };
+        self.timed_out = Some(BotPhase::Status);
+        csw_buf.poison(Csw::SIZE);
+        self.ep_in.queue(csw_buf, Csw::SIZE)?;
+        let len = Self::wait_transfer(ctrl, &self.ep_in, Csw::SIZE, &mut watch, timeout_us)?;
+        csw_buf.check_in(len, Csw::SIZE);
 
-        // Free buffers
-        cbw_buf.free(host);
-        csw_buf.free(host);
-        if let Some(buf) = data_buf {
-            buf.free(host);
+        let mut csw_bytes = [0u8; Csw::SIZE];
+        unsafe {
+            core::ptr::copy_nonoverlapping(csw_buf.as_ptr::<u8>(), csw_bytes.as_mut_ptr(), Csw::SIZE);
         }
+        let csw = Csw::from_bytes(&csw_bytes).unwrap_or_default();
 
-        // Check CSW
-        if !csw.is_ok() {
-            return Err(UsbError::XferFail(csw.status));
-        }
+        // A residue larger than the data phase is a phase error as well
+        let residue = csw.data_residue as usize;
+        let valid_csw = csw.signature == Csw::SIGNATURE && csw.tag == cbw.tag;
+        let status = if valid_csw && residue <= data_len {
+            csw.status
+        } else {
+            Csw::STATUS_PHASE_ERROR
+        };
 
-        Ok(transferred)
+        // Trust the smaller of the transfer event and the CSW residue
+        let transferred = match status {
+            Csw::STATUS_PHASE_ERROR => 0,
+            _ => transferred.min(data_len - residue),
+        };
+        if let Some((buf, d)) = data_in {
+            unsafe {
+                core::ptr::copy_nonoverlapping(buf.as_ptr::<u8>(), d.as_mut_ptr(), transferred);
+            }
+            d[transferred..].fill(0);
+        }
+        Ok((transferred, status))
     }
 
-    fn wait_transfer(&self) -> Result<usize> {
+    /// Wait for a bulk transfer of `requested` bytes; returns the bytes moved
+    ///
+    /// Fails with `Timeout` once `watch` passes `timeout_us` (0 never expires)
+    /// and with `EventLost` if the event ring overflows first.
+    pub(crate) fn wait_transfer(
+        ctrl: &XhciCtrl<H>,
+        ep: &EndpointHandle<H>,
+        requested: usize,
+        watch: &mut Stopwatch,
+        timeout_us: u32,
+    ) -> Result<usize> {
+        let lost = ctrl.events_lost();
         loop {
-            if let Some(evt) = self.device.ctrl().poll_event()
-                && evt.slot_id() == self.device.slot_id()
-            {
-                let code = evt.completion_code();
-                if code == 1 || code == 13 {
-                    // SUCCESS or SHORT_PACKET
-                    return Ok(evt.transfer_length() as usize);
-                } else {
-                    return Err(UsbError::XferFail(code));
-                }
+            if let Some(evt) = ep.poll() {
+                ep.check(&evt)?;
+                return Ok(evt.transferred(requested));
+            }
+            if ctrl.events_lost() != lost {
+                return Err(UsbError::EventLost);
+            }
+            ctrl.check_alive()?;
+            if timeout_us != 0 && watch.elapsed_us(ctrl) >= timeout_us as u64 {
+                return Err(UsbError::Timeout);
             }
             spin_loop();
         }
@@ -460,6 +1396,69 @@ impl<H: Dma> MscDevice<H> {
         }
     }
 
+    /// Waits until a unit answers TEST UNIT READY
+    ///
+    /// Cheap flash drives report NOT READY (becoming ready), a unit
+    /// attention or transaction errors for a while after
+    /// SET_CONFIGURATION; those are retried after `policy.delay_us`, with
+    /// each retry reported through `Dma::warn`. MEDIUM NOT PRESENT is
+    /// returned at once as `UnitReadiness::NoMedium`. Other failures are
+    /// returned as `XferFail`, and `Timeout` once the attempts run out.
+    pub fn wait_ready(&mut self, lun: u8, policy: ReadyPolicy) -> Result<UnitReadiness> {
+        let cdb = [scsi_op::TEST_UNIT_READY, 0, 0, 0, 0, 0];
+        let timeout_us = Self::default_timeout_us(&cdb);
+        let attempts = policy.attempts.max(1);
+        for attempt in 1..=attempts {
+            if attempt > 1 {
+                self.device.ctrl().host().delay_us(policy.delay_us);
+            }
+            match self.pass_through(lun, &cdb, DataPhase::None, timeout_us) {
+                Ok(result) if result.status == Csw::STATUS_PASSED => {
+                    return Ok(UnitReadiness::Ready);
+                }
+                Ok(result) => {
+                    let sense = result.sense.unwrap_or_default();
+                    let (key, code, qualifier) = (sense.sense_key(), sense.asc, sense.ascq);
+                    match (key, code, qualifier) {
+                        (sense_key::NOT_READY, asc::MEDIUM_NOT_PRESENT, _) => {
+                            return Ok(UnitReadiness::NoMedium);
+                        }
+                        (sense_key::NOT_READY, asc::NOT_READY, asc::BECOMING_READY)
+                        | (sense_key::UNIT_ATTENTION, _, _) => self.warn_not_ready(
+                            lun,
+                            (attempt, attempts),
+                            format_args!("sense {key:#x}/{code:#04x}/{qualifier:#04x}"),
+                        ),
+                        _ => return Err(UsbError::XferFail(result.status)),
+                    }
+                }
+                Err(e) if RetryPolicy::retryable(&e) || matches!(e, UsbError::Timeout) => {
+                    self.warn_not_ready(lun, (attempt, attempts), format_args!("{e}"));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(UsbError::Timeout)
+    }
+
+    /// Report a failed attempt of `wait_ready`
+    fn warn_not_ready(&self, lun: u8, (attempt, attempts): (u16, u16), reason: fmt::Arguments<'_>) {
+        self.device.ctrl().host().warn(format_args!(
+            "mass storage LUN {lun} not ready ({reason}), attempt {attempt} of {attempts}"
+        ));
+    }
+
+    /// Sends INQUIRY for only the first `buf.len()` bytes of the data.
+    ///
+    /// For devices that fail or stall a full 36-byte INQUIRY; 5 bytes
+    /// already hold the peripheral device type and removable bit. At
+    /// most 255 bytes are requested. Returns the bytes received.
+    pub fn inquiry_prefix(&mut self, lun: u8, buf: &mut [u8]) -> Result<usize> {
+        let len = buf.len().min(255);
+        let cdb = [scsi_op::INQUIRY, 0, 0, 0, len as u8, 0];
+        self.scsi_command(lun, &cdb, Some(&mut buf[..len]), true)
+    }
+
     /// Sends INQUIRY command.
     pub fn inquiry(&mut self, lun: u8) -> Result<InquiryData> {
         let cdb = [scsi_op::INQUIRY, 0, 0, 0, 36, 0];
@@ -469,23 +1468,116 @@ impl<H: Dma> MscDevice<H> {
     }
 
     /// Sends READ CAPACITY (10) command.
+    ///
+    /// Caches the block size and count for `geometry`, unless the unit
+    /// is too large for READ CAPACITY (10) to describe.
     pub fn read_capacity(&mut self, lun: u8) -> Result<ReadCapacity10Data> {
         let cdb = [scsi_op::READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
         let mut data = [0u8; 8];
         self.scsi_command(lun, &cdb, Some(&mut data), true)?;
-        Ok(unsafe { *(data.as_ptr() as *const ReadCapacity10Data) })
+        let cap = unsafe { *(data.as_ptr() as *const ReadCapacity10Data) };
+
+        if cap.last_lba() != u32::MAX {
+            // Keep the physical layout READ CAPACITY (16) reported
+            let prev = self.geometry[lun as usize].filter(|g| g.block_size == cap.block_size());
+            self.geometry[lun as usize] = Some(BlockGeometry {
+                block_size: cap.block_size(),
+                num_blocks: cap.last_lba() as u64 + 1,
+                ..prev.unwrap_or_default()
+            });
+        }
+        Ok(cap)
+    }
+
+    /// Sends READ CAPACITY (16) command.
+    ///
+    /// Needed for units of 2^32 blocks or more, and the only source of
+    /// the physical block layout. Caches the result for `geometry`.
+    pub fn read_capacity16(&mut self, lun: u8) -> Result<ReadCapacity16Data> {
+        let mut data = [0u8; 32];
+        let mut cdb = [0u8; 16];
+        cdb[0] = scsi_op::READ_CAPACITY_16;
+        cdb[1] = 0x10; // SERVICE ACTION IN (16) service action
+        cdb[10..14].copy_from_slice(&(data.len() as u32).to_be_bytes());
+        self.scsi_command(lun, &cdb, Some(&mut data), true)?;
+        let cap = unsafe { *(data.as_ptr() as *const ReadCapacity16Data) };
+
+        self.geometry[lun as usize] = Some(BlockGeometry {
+            block_size: cap.block_size(),
+            num_blocks: cap.last_lba().saturating_add(1),
+            physical_block_exponent: cap.physical_block_exponent(),
+            lowest_aligned_lba: cap.lowest_aligned_lba(),
+        });
+        Ok(cap)
+    }
+
+    /// Returns the geometry of a LUN, once READ CAPACITY was issued.
+    pub fn geometry(&self, lun: u8) -> Option<BlockGeometry> {
+        self.geometry.get(lun as usize).copied().flatten()
+    }
+
+    /// Returns the logical block size of a LUN in bytes, once READ CAPACITY was issued.
+    pub fn block_size(&self, lun: u8) -> Option<u32> {
+        self.geometry(lun).map(|g| g.block_size)
+    }
+
+    /// Returns the number of logical blocks of a LUN, once READ CAPACITY was issued.
+    pub fn num_blocks(&self, lun: u8) -> Option<u64> {
+        self.geometry(lun).map(|g| g.num_blocks)
+    }
+
+    /// Cached geometry of a LUN, read with READ CAPACITY on first use
+    pub(crate) fn load_geometry(&mut self, lun: u8) -> Result<BlockGeometry> {
+        if let Some(geometry) = self.geometry(lun) {
+            return Ok(geometry);
+        }
+
+        // READ CAPACITY (10) reports the maximum when the unit is too large
+        if self.read_capacity(lun)?.last_lba() == u32::MAX {
+            self.read_capacity16(lun)?;
+        }
+        let geometry = self.geometry(lun).ok_or(UsbError::InvalidDescriptor)?;
+        if geometry.block_size == 0 {
+            return Err(UsbError::InvalidDescriptor);
+        }
+        Ok(geometry)
+    }
+
+    /// Check that `len` bytes are exactly `count` blocks within the unit
+    fn check_blocks(&mut self, lun: u8, lba: u32, count: u16, len: usize) -> Result<()> {
+        let geometry = self.load_geometry(lun)?;
+        if len as u64 != count as u64 * geometry.block_size as u64 {
+            return Err(UsbError::Misaligned);
+        }
+        if lba as u64 + count as u64 > geometry.num_blocks {
+            return Err(UsbError::InvalidArgument);
+        }
+        Ok(())
     }
 
     /// Sends REQUEST SENSE command.
+    ///
+    /// Descriptor-format sense is reduced to the sense key, ASC and ASCQ.
     pub fn request_sense(&mut self, lun: u8) -> Result<RequestSenseData> {
-        let cdb = [scsi_op::REQUEST_SENSE, 0, 0, 0, 18, 0];
-        let mut data = [0u8; 18];
+        let raw = self.fetch_sense(lun)?;
+        Ok(RequestSenseData::from_bytes(&raw))
+    }
+
+    /// Send REQUEST SENSE and return the sense data as is
+    fn fetch_sense(&mut self, lun: u8) -> Result<[u8; 32]> {
+        let mut data = [0u8; 32];
+        let cdb = [scsi_op::REQUEST_SENSE, 0, 0, 0, data.len() as u8, 0];
         self.scsi_command(lun, &cdb, Some(&mut data), true)?;
-        Ok(unsafe { *(data.as_ptr() as *const RequestSenseData) })
+        Ok(data)
     }
 
     /// Reads blocks from the device (READ 10).
+    ///
+    /// `buf` must hold exactly `count` logical blocks, otherwise
+    /// `Misaligned` is returned; blocks past the end of the unit fail
+    /// with `InvalidArgument`. The geometry is read on first use.
     pub fn read_blocks(&mut self, lun: u8, lba: u32, count: u16, buf: &mut [u8]) -> Result<usize> {
+        self.check_blocks(lun, lba, count, buf.len())?;
         let cdb = [
             scsi_op::READ_10,
             0,
@@ -502,7 +1594,10 @@ impl<H: Dma> MscDevice<H> {
     }
 
     /// Writes blocks to the device (WRITE 10).
+    ///
+    /// Checked like `read_blocks`.
     pub fn write_blocks(&mut self, lun: u8, lba: u32, count: u16, buf: &mut [u8]) -> Result<usize> {
+        self.check_blocks(lun, lba, count, buf.len())?;
         let cdb = [
             scsi_op::WRITE_10,
             0,
@@ -518,6 +1613,105 @@ impl<H: Dma> MscDevice<H> {
         self.scsi_command(lun, &cdb, Some(buf), false)
     }
 
+    /// Reads `buf.len()` bytes starting at byte `offset` of a LUN.
+    ///
+    /// For callers that need byte addressing: the covering blocks are read
+    /// into a bounce buffer and the requested bytes copied out. Fails with
+    /// `InvalidArgument` past the end of the unit and `NotSupported` past
+    /// the blocks READ (10) can address.
+    pub fn read_unaligned(&mut self, lun: u8, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let geometry = self.load_geometry(lun)?;
+        let block_size = geometry.block_size as u64;
+        let end = offset
+            .checked_add(buf.len() as u64)
+            .ok_or(UsbError::InvalidArgument)?;
+        if end > geometry.capacity_bytes() {
+            return Err(UsbError::InvalidArgument);
+        }
+        if end.div_ceil(block_size) > u32::MAX as u64 + 1 {
+            return Err(UsbError::NotSupported);
+        }
+
+        let chunk_blocks =
+            (UNALIGNED_CHUNK.min(self.max_transfer) as u64 / block_size).clamp(1, u16::MAX as u64);
+        let mut bounce = alloc::vec![0u8; (chunk_blocks * block_size) as usize];
+
+        let mut pos = offset;
+        let mut done = 0;
+        while pos < end {
+            let lba = pos / block_size;
+            let count = (end.div_ceil(block_size) - lba).min(chunk_blocks);
+            let len = (count * block_size) as usize;
+            self.read_blocks(lun, lba as u32, count as u16, &mut bounce[..len])?;
+
+            let skip = (pos - lba * block_size) as usize;
+            let take = (len - skip).min(buf.len() - done);
+            buf[done..done + take].copy_from_slice(&bounce[skip..skip + take]);
+            done += take;
+            pos += take as u64;
+        }
+        Ok(done)
+    }
+
+    /// Starts formatting a unit with FORMAT UNIT.
+    ///
+    /// UFI drives (floppies) are sent the format descriptor from
+    /// `params` and format the whole medium before the command completes.
+    /// Other devices keep their current format; with `params.immediate`
+    /// the command returns at once. Poll the returned handle until it
+    /// reports `FormatProgress::Complete`.
+    pub fn format_unit(&mut self, lun: u8, params: &FormatParams) -> Result<FormatHandle> {
+        let timeout_us = params.timeout_min as u64 * 60_000_000;
+        let cmd_timeout = timeout_us.min(u32::MAX as u64) as u32;
+
+        if self.is_ufi() {
+            // Defect list header (FOV, DCRT; whole medium) and format descriptor
+            let mut list = [0u8; 12];
+            list[1] = 0xA0;
+            list[3] = 8;
+            list[4..8].copy_from_slice(&params.num_blocks.to_be_bytes());
+            list[9..12].copy_from_slice(&params.block_size.to_be_bytes()[1..]);
+
+            let mut cdb = [0u8; PACKET_CDB_LEN];
+            cdb[0] = scsi_op::FORMAT_UNIT;
+            cdb[1] = 0x17; // FmtData, defect list format 7
+            cdb[7..9].copy_from_slice(&(list.len() as u16).to_be_bytes());
+            self.scsi_command_timeout(lun, &cdb, Some(&mut list), false, cmd_timeout)?;
+        } else if params.immediate {
+            // Short parameter list header: FOV, IMMED
+            let mut list = [0u8, 0x82, 0, 0];
+            let cdb = [scsi_op::FORMAT_UNIT, 0x10, 0, 0, 0, 0];
+            self.scsi_command_timeout(lun, &cdb, Some(&mut list), false, cmd_timeout)?;
+        } else {
+            let cdb = [scsi_op::FORMAT_UNIT, 0, 0, 0, 0, 0];
+            self.scsi_command_timeout(lun, &cdb, None, false, cmd_timeout)?;
+        }
+
+        self.geometry[lun as usize] = None;
+        Ok(FormatHandle {
+            lun,
+            watch: self.device.ctrl().stopwatch(),
+            timeout_us,
+        })
+    }
+
+    /// True if the device speaks the UFI command set
+    fn is_ufi(&self) -> bool {
+        self.subclass == msc_subclass::UFI
+    }
+
+    /// True if command blocks are padded to 12 bytes (UFI, SFF-8070i, MMC-5)
+    fn pads_cdb(&self) -> bool {
+        matches!(
+            self.subclass,
+            msc_subclass::UFI | msc_subclass::SFF8070I | msc_subclass::MMC5
+        )
+    }
+
     /// Synchronizes the cache (SYNCHRONIZE CACHE 10).
     pub fn sync_cache(&mut self, lun: u8) -> Result<()> {
         let cdb = [scsi_op::SYNCHRONIZE_CACHE_10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
@@ -525,6 +1719,117 @@ impl<H: Dma> MscDevice<H> {
         Ok(())
     }
 
+    /// Reads the IDENTIFY DEVICE data of an ATA drive behind a SAT bridge.
+    ///
+    /// Returns `NotSupported` if the bridge does not implement ATA
+    /// PASS-THROUGH.
+    pub fn ata_identify(&mut self, lun: u8) -> Result<[u8; 512]> {
+        let mut data = [0u8; 512];
+        self.ata_pio_in(lun, ata::IDENTIFY_DEVICE, 0, &mut data)?;
+        Ok(data)
+    }
+
+    /// Reads the SMART attributes and health of an ATA drive behind a SAT
+    /// bridge.
+    ///
+    /// Returns `NotSupported` if the bridge does not implement ATA
+    /// PASS-THROUGH. `SmartData::passed` is `None` if the bridge does not
+    /// return the ATA registers of SMART RETURN STATUS.
+    pub fn smart_read_data(&mut self, lun: u8) -> Result<SmartData> {
+        let mut data = [0u8; SmartData::SIZE];
+        self.ata_pio_in(lun, ata::SMART, ata::SMART_READ_DATA, &mut data)?;
+        let mut smart = SmartData::from_bytes(&data);
+        smart.passed = self.smart_return_status(lun)?;
+        Ok(smart)
+    }
+
+    /// Reads the overall SMART health; `None` if the bridge hides it
+    fn smart_return_status(&mut self, lun: u8) -> Result<Option<bool>> {
+        let cdb = ata::cdb16(
+            ata::PROTOCOL_NON_DATA,
+            ata::CK_COND,
+            ata::SMART_RETURN_STATUS,
+            0,
+            ata::SMART,
+        );
+        let timeout_us = Self::default_timeout_us(&cdb);
+        let result = Self::ata_result(self.pass_through(lun, &cdb, DataPhase::None, timeout_us))?;
+        if result.sense.is_none() {
+            return Ok(None);
+        }
+
+        let sense = &result.raw_sense;
+        let (lba_mid, lba_high) = match sense[0] & 0x7F {
+            // Descriptor format: look for the ATA Status Return descriptor
+            0x72 | 0x73 => {
+                let end = (8 + sense[7] as usize).min(sense.len());
+                let mut offset = 8;
+                let mut regs = None;
+                while offset + 1 < end {
+                    let desc = &sense[offset..end];
+                    if desc[0] == ata::STATUS_RETURN_DESCRIPTOR && desc.len() >= 14 {
+                        regs = Some((desc[9], desc[11]));
+                        break;
+                    }
+                    offset += 2 + desc[1] as usize;
+                }
+                match regs {
+                    Some(regs) => regs,
+                    None => return Ok(None),
+                }
+            }
+            // Fixed format: LBA (7:0) to (23:16) in bytes 9 to 11
+            _ => (sense[10], sense[11]),
+        };
+
+        Ok(match (lba_mid, lba_high) {
+            (ata::SMART_LBA_MID, ata::SMART_LBA_HIGH) => Some(true),
+            (ata::SMART_FAILING_LBA_MID, ata::SMART_FAILING_LBA_HIGH) => Some(false),
+            _ => None,
+        })
+    }
+
+    /// Run a 512-byte PIO data-in ATA command through ATA PASS-THROUGH (16)
+    fn ata_pio_in(
+        &mut self,
+        lun: u8,
+        command: u8,
+        features: u8,
+        data: &mut [u8; 512],
+    ) -> Result<()> {
+        let cdb = ata::cdb16(
+            ata::PROTOCOL_PIO_IN,
+            ata::T_DIR_IN | ata::BYT_BLOK | ata::T_LENGTH_COUNT,
+            features,
+            1,
+            command,
+        );
+        let timeout_us = Self::default_timeout_us(&cdb);
+        let result =
+            Self::ata_result(self.pass_through(lun, &cdb, DataPhase::In(data), timeout_us))?;
+        if result.status != Csw::STATUS_PASSED {
+            return Err(UsbError::XferFail(result.status));
+        }
+        Ok(())
+    }
+
+    /// Map a bridge without SAT support to `NotSupported`
+    ///
+    /// Such bridges either stall the unknown opcode or fail it with
+    /// ILLEGAL REQUEST.
+    fn ata_result(result: Result<ScsiResult>) -> Result<ScsiResult> {
+        match result {
+            Err(UsbError::Stall) => Err(UsbError::NotSupported),
+            Ok(r)
+                if r.sense
+                    .is_some_and(|s| s.sense_key() == sense_key::ILLEGAL_REQUEST) =>
+            {
+                Err(UsbError::NotSupported)
+            }
+            other => other,
+        }
+    }
+
     /// Returns a reference to the underlying USB device.
     pub fn device(&self) -> &Arc<UsbDevice<H>> {
         &self.device
@@ -536,66 +1841,767 @@ impl<H: Dma> MscDevice<H> {
     }
 }
 
+#[cfg(feature = "alloc")]
+impl<H: Dma> Drop for MscDevice<H> {
+    /// Stops both bulk endpoints, so transfers left behind by a command
+    /// whose recovery failed do not outlive the driver
+    fn drop(&mut self) {
+        self.device.unbind_driver(DriverKind::MassStorage);
+        let _ = self.ep_in.abort();
+        let _ = self.ep_out.abort();
+    }
+}
+
 /// Parses configuration descriptor to find MSC interfaces.
+///
+/// Only Bulk-Only Transport interfaces are returned; see
+/// `find_cbi_interfaces` for CBI.
+#[cfg(feature = "alloc")]
 pub fn find_msc_interfaces(
     config_data: &[u8],
 ) -> alloc::vec::Vec<(InterfaceDesc, EndpointDesc, EndpointDesc)> {
-    use crate::desc::desc_type;
+    let mut found = alloc::vec::Vec::new();
+    for_each_msc_interface(config_data, |iface, ep_in, ep_out| {
+        found.push((iface, ep_in, ep_out));
+    });
+    found
+}
+
+/// Calls `f` for each Bulk-Only mass storage interface with its bulk
+/// IN and OUT endpoints.
+///
+/// Picks the same alternate settings as `find_msc_interfaces`, without
+/// allocating.
+pub fn for_each_msc_interface(
+    config_data: &[u8],
+    mut f: impl FnMut(InterfaceDesc, EndpointDesc, EndpointDesc),
+) {
+    for_each_storage_interface(
+        config_data,
+        &[msc_protocol::BBB],
+        |(iface, ep_in, ep_out, _)| f(iface, ep_in, ep_out),
+    );
+}
 
-    let mut result = alloc::vec::Vec::new();
-    let mut offset = 0;
-    let mut current_iface: Option<InterfaceDesc> = None;
-    let mut ep_in: Option<EndpointDesc> = None;
-    let mut ep_out: Option<EndpointDesc> = None;
+/// CBI interface with its bulk IN, bulk OUT and interrupt IN endpoints
+type CbiInterface = (
+    InterfaceDesc,
+    EndpointDesc,
+    EndpointDesc,
+    Option<EndpointDesc>,
+);
 
-    while offset + 2 <= config_data.len() {
-        let len = config_data[offset] as usize;
-        let dtype = config_data[offset + 1];
+/// Parses configuration descriptor to find CBI mass storage interfaces.
+///
+/// Returns the bulk IN and OUT endpoints and, if the interface has one,
+/// the interrupt IN endpoint, ready for `CbiDevice::from_interface`.
+#[cfg(feature = "alloc")]
+pub fn find_cbi_interfaces(config_data: &[u8]) -> alloc::vec::Vec<CbiInterface> {
+    let protocols = [msc_protocol::CBI_INTERRUPT, msc_protocol::CBI_NO_INTERRUPT];
+    find_storage_interfaces(config_data, &protocols)
+}
+
+/// Mass storage interfaces speaking one of `protocols`, with their
+/// bulk endpoints and interrupt IN endpoint
+#[cfg(feature = "alloc")]
+fn find_storage_interfaces(config_data: &[u8], protocols: &[u8]) -> alloc::vec::Vec<CbiInterface> {
+    let mut found = alloc::vec::Vec::new();
+    for_each_storage_interface(config_data, protocols, |iface| found.push(iface));
+    found
+}
 
-        if len == 0 || offset + len > config_data.len() {
-            break;
+/// Call `f` for each mass storage interface speaking one of `protocols`
+fn for_each_storage_interface(
+    config_data: &[u8],
+    protocols: &[u8],
+    mut f: impl FnMut(CbiInterface),
+) {
+    let matches = |iface: &InterfaceDesc| {
+        iface.interface_class == class::MASS_STORAGE
+            && protocols.contains(&iface.interface_protocol)
+    };
+
+    let mut done = InterfaceSet::default();
+    for_each_interface(config_data, matches, |setting| {
+        // First alternate setting with both bulk endpoints
+        let num = setting.iface.interface_number;
+        if done.contains(num) {
+            return;
         }
+        let find = |kind: u8, is_in: bool| {
+            setting
+                .endpoints()
+                .find(|ep| ep.transfer_type() == kind && ep.is_in() == is_in)
+        };
+        let (Some(bulk_in), Some(bulk_out)) =
+            (find(ep_type::BULK, true), find(ep_type::BULK, false))
+        else {
+            return;
+        };
+        done.insert(num);
+        let int_in = find(ep_type::INTERRUPT, true);
+        f((setting.iface, bulk_in, bulk_out, int_in));
+    });
+}
 
-        match dtype {
-            desc_type::INTERFACE if len >= 9 => {
-                // Save previous interface if complete
-                if let (Some(iface), Some(ein), Some(eout)) = (current_iface, ep_in, ep_out) {
-                    result.push((iface, ein, eout));
-                }
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::{
+        desc::msc_subclass,
+        dev::default_config_policy,
+        mock::{self, MockHost, Reply, Request},
+        reg,
+    };
+    use alloc::vec::Vec;
+    use std::sync::Mutex;
 
-                let iface = unsafe { *(config_data.as_ptr().add(offset) as *const InterfaceDesc) };
-                if iface.interface_class == class::MASS_STORAGE
-                    && iface.interface_protocol == msc_protocol::BBB
-                {
-                    current_iface = Some(iface);
-                    ep_in = None;
-                    ep_out = None;
-                } else {
-                    current_iface = None;
+    /// A Bulk-Only device of `subclass` with LUNs up to `max_lun`
+    ///
+    /// Every command passes with no data; the CBWs it received are logged.
+    fn bulk_only(
+        subclass: u8,
+        max_lun: u8,
+    ) -> (mock::Mock, MscDevice<MockHost>, Arc<Mutex<Vec<Cbw>>>) {
+        let config = mock::config(
+            1,
+            &[
+                mock::interface(0, 0, (class::MASS_STORAGE, subclass, 0x50), 2),
+                mock::endpoint(0x81, 0x02, 512, 0),
+                mock::endpoint(0x02, 0x02, 512, 0),
+            ],
+        );
+        let cbws = Arc::new(Mutex::new(Vec::new()));
+        let log = cbws.clone();
+        let handler = move |r: &Request<'_>| match r {
+            Request::Control { setup, .. } if setup.request == 0xfe => {
+                Some(Reply::Data(alloc::vec![max_lun]))
+            }
+            Request::Transfer { ep: 0x02, data, .. } if data.len() == Cbw::SIZE => {
+                log.lock().unwrap().push(Cbw::from_bytes(data)?);
+                Some(Reply::Ack)
+            }
+            Request::Transfer {
+                ep: 0x81,
+                len: Csw::SIZE,
+                ..
+            } => {
+                let tag = log.lock().unwrap().last()?.tag;
+                let csw = Csw {
+                    signature: Csw::SIGNATURE,
+                    tag,
+                    data_residue: 0,
+                    status: Csw::STATUS_PASSED,
+                };
+                Some(Reply::Data(csw.to_bytes().to_vec()))
+            }
+            _ => None,
+        };
+        let desc = mock::device_desc(0, 0x0781, 0x5567, 1);
+        let device = mock::MockDevice::new(reg::SPEED_HIGH, desc, &[config]);
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, device.with_handler(handler));
+
+        let dev = Arc::new(UsbDevice::new(ctrl, 0).unwrap());
+        let tree = dev.choose_configuration(default_config_policy).unwrap();
+        let (iface, ep_in, ep_out) = find_msc_interfaces(tree.raw())[0];
+        let msc = MscDevice::from_interface(dev, &iface, &ep_in, &ep_out).unwrap();
+        (mock, msc, cbws)
+    }
+
+    #[test]
+    fn packet_command_sets_pad_cdbs_to_12_bytes() {
+        let test_unit_ready = [scsi_op::TEST_UNIT_READY, 0, 0, 0, 0, 0];
+        for subclass in [
+            msc_subclass::UFI,
+            msc_subclass::SFF8070I,
+            msc_subclass::MMC5,
+        ] {
+            let (_mock, mut msc, cbws) = bulk_only(subclass, 0);
+            let result = msc
+                .pass_through(0, &test_unit_ready, DataPhase::None, 0)
+                .unwrap();
+            assert_eq!(result.status, Csw::STATUS_PASSED);
+            let cbw = cbws.lock().unwrap()[0];
+            assert_eq!(cbw.cb_length, 12, "subclass {subclass:#x}");
+            assert_eq!(cbw.cb[..12], [0; 12]);
+
+            // Longer CDBs do not fit a 12-byte command block
+            let result = msc.pass_through(0, &[0; 13], DataPhase::None, 0);
+            assert!(matches!(result, Err(UsbError::InvalidArgument)));
+        }
+    }
+
+    #[test]
+    fn transparent_scsi_keeps_cdb_lengths() {
+        let (_mock, mut msc, cbws) = bulk_only(msc_subclass::SCSI_TRANSPARENT, 0);
+        let test_unit_ready = [scsi_op::TEST_UNIT_READY, 0, 0, 0, 0, 0];
+        msc.pass_through(0, &test_unit_ready, DataPhase::None, 0)
+            .unwrap();
+        msc.pass_through(0, &[0; 16], DataPhase::None, 0).unwrap();
+        let lengths: Vec<u8> = cbws.lock().unwrap().iter().map(|c| c.cb_length).collect();
+        assert_eq!(lengths, [6, 16]);
+
+        for cdb in [&[][..], &[0; 17][..]] {
+            let result = msc.pass_through(0, cdb, DataPhase::None, 0);
+            assert!(matches!(result, Err(UsbError::InvalidArgument)));
+        }
+    }
+
+    #[test]
+    fn lun_above_max_lun_is_refused() {
+        let test_unit_ready = [scsi_op::TEST_UNIT_READY, 0, 0, 0, 0, 0];
+        let (_mock, mut msc, cbws) = bulk_only(msc_subclass::SCSI_TRANSPARENT, 1);
+        assert_eq!(msc.max_lun(), 1);
+        msc.pass_through(1, &test_unit_ready, DataPhase::None, 0)
+            .unwrap();
+        assert_eq!(cbws.lock().unwrap()[0].lun, 1);
+
+        let result = msc.pass_through(2, &test_unit_ready, DataPhase::None, 0);
+        assert!(matches!(result, Err(UsbError::InvLun)));
+        assert_eq!(cbws.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn max_lun_is_clamped_to_cbw_range() {
+        let (_mock, mut msc, cbws) = bulk_only(msc_subclass::SCSI_TRANSPARENT, 0xff);
+        assert_eq!(msc.max_lun(), MAX_LUN);
+        let result = msc.pass_through(MAX_LUN + 1, &[0; 6], DataPhase::None, 0);
+        assert!(matches!(result, Err(UsbError::InvLun)));
+        assert!(cbws.lock().unwrap().is_empty());
+    }
+
+    /// A configured `mock::mass_storage` device answering with `handler`
+    fn disk(
+        handler: impl FnMut(&Request<'_>) -> Option<Reply> + Send + 'static,
+    ) -> (mock::Mock, MscDevice<MockHost>) {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, mock::mass_storage().with_handler(handler));
+        let dev = Arc::new(UsbDevice::new(ctrl, 0).unwrap());
+        let tree = dev.choose_configuration(default_config_policy).unwrap();
+        let (iface, ep_in, ep_out) = find_msc_interfaces(tree.raw())[0];
+        let msc = MscDevice::from_interface(dev, &iface, &ep_in, &ep_out).unwrap();
+        (mock, msc)
+    }
+
+    #[test]
+    fn data_phase_timeout_recovers_the_device() {
+        use crate::{desc::request, ring::trb_type};
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        // The first READ(10) never gets its data
+        let hang = Arc::new(AtomicBool::new(true));
+        let mut bulk_only_disk = mock::bulk_only_disk(0x81, 0x02, 64);
+        let hanging = hang.clone();
+        let (mock, mut msc) = disk(move |r: &Request<'_>| match r {
+            Request::Transfer {
+                ep: 0x81, len: 512, ..
+            } if hanging.load(Ordering::Relaxed) => Some(Reply::Nak),
+            _ => bulk_only_disk(r),
+        });
+        let commands = mock.commands().len();
+        let setups = mock.with_device(0, 0, |d| d.setups.len());
+
+        let read = [scsi_op::READ_10, 0, 0, 0, 0, 5, 0, 0, 1, 0];
+        let mut block = [0; 512];
+        let result = msc.pass_through(0, &read, DataPhase::In(&mut block), 10_000);
+        assert!(matches!(result, Err(UsbError::Timeout)));
+        assert_eq!(msc.timed_out_phase(), Some(BotPhase::Data));
+
+        // Both bulk endpoints are stopped and skipped past their TRBs...
+        let stop = [trb_type::STOP_ENDPOINT, trb_type::SET_TR_DEQUEUE];
+        assert_eq!(mock.commands()[commands..], [stop, stop].concat());
+        // ...then the device gets a Mass Storage Reset and both halts cleared
+        let sent: Vec<_> = mock.with_device(0, 0, |d| {
+            d.setups[setups..]
+                .iter()
+                .map(|s| (s.request_type, s.request, s.index))
+                .collect()
+        });
+        assert_eq!(
+            sent,
+            [
+                (0x21, 0xff, 0),
+                (0x02, request::CLEAR_FEATURE, 0x81),
+                (0x02, request::CLEAR_FEATURE, 0x02),
+            ]
+        );
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+
+        // The next command goes through
+        hang.store(false, Ordering::Relaxed);
+        assert_eq!(msc.read_blocks(0, 5, 1, &mut block).unwrap(), 512);
+        assert!(block.iter().all(|&b| b == 5));
+        assert_eq!(msc.timed_out_phase(), None);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn drop_stops_bulk_endpoints() {
+        let (mock, msc, _cbws) = bulk_only(msc_subclass::SCSI_TRANSPARENT, 0);
+        let device = msc.device().clone();
+        let host = device.ctrl().host();
+
+        // A read stranded on the IN endpoint, as after a failed recovery
+        let buf = device.ctrl().alloc_mem(512, 64).unwrap();
+        msc.ep_in.queue(&buf, 512).unwrap();
+        let dcis = [msc.ep_in.dci().raw(), msc.ep_out.dci().raw()];
+
+        drop(msc);
+        for dci in dcis {
+            assert_eq!(mock.endpoint_state(device.slot_id(), dci), mock::EP_STOPPED);
+        }
+        // The controller no longer writes into the caller's buffer
+        buf.free(host);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    /// Bulk-Only disk whose INQUIRY data is `inquiry_len` bytes long
+    ///
+    /// The data residue of each INQUIRY CSW is logged to `residues`.
+    fn inquiry_disk(
+        inquiry_len: usize,
+        residues: Arc<Mutex<Vec<u32>>>,
+    ) -> impl FnMut(&Request<'_>) -> Option<Reply> + Send + 'static {
+        let mut disk = mock::bulk_only_disk(0x81, 0x02, 64);
+        let is_inquiry = |cbw: &Cbw| cbw.cb[0] == scsi_op::INQUIRY;
+        let mut pending: Option<(Vec<u8>, Csw)> = None;
+        move |r| match *r {
+            Request::Transfer { ep: 0x02, data, .. }
+                if Cbw::from_bytes(data).is_some_and(|cbw| is_inquiry(&cbw)) =>
+            {
+                let cbw = Cbw::from_bytes(data)?;
+                let length = cbw.data_transfer_length as usize;
+                let mut inquiry = alloc::vec![0x5a; inquiry_len];
+                inquiry[..5].copy_from_slice(&[0, 0x80, 0x06, 0x02, (inquiry_len - 5) as u8]);
+                inquiry.truncate(length);
+                let csw = Csw {
+                    signature: Csw::SIGNATURE,
+                    tag: cbw.tag,
+                    data_residue: (length - inquiry.len()) as u32,
+                    status: Csw::STATUS_PASSED,
+                };
+                pending = Some((inquiry, csw));
+                Some(Reply::Ack)
+            }
+            Request::Transfer { ep: 0x81, .. } if pending.is_some() => {
+                let (data, _) = pending.as_mut().unwrap();
+                if !data.is_empty() {
+                    return Some(Reply::Data(core::mem::take(data)));
                 }
+                let csw = pending.take().unwrap().1;
+                residues.lock().unwrap().push(csw.data_residue);
+                Some(Reply::Data(csw.to_bytes().to_vec()))
             }
-            desc_type::ENDPOINT if len >= 7 => {
-                if current_iface.is_some() {
-                    let ep = unsafe { *(config_data.as_ptr().add(offset) as *const EndpointDesc) };
-                    if ep.transfer_type() == ep_type::BULK {
-                        if ep.is_in() {
-                            ep_in = Some(ep);
+            _ => disk(r),
+        }
+    }
+
+    #[test]
+    fn inquiry_longer_than_requested_is_cut() {
+        let residues = Arc::new(Mutex::new(Vec::new()));
+        let (mock, mut msc) = disk(inquiry_disk(96, residues.clone()));
+
+        let inquiry = msc.inquiry(0).unwrap();
+        assert_eq!(inquiry.additional_length, 91);
+        let mut buf = [0xaa; 36];
+        let cdb = [scsi_op::INQUIRY, 0, 0, 0, 36, 0];
+        let result = msc
+            .pass_through(0, &cdb, DataPhase::In(&mut buf), 0)
+            .unwrap();
+        assert_eq!(result.transferred, 36);
+        assert_eq!(result.status, Csw::STATUS_PASSED);
+        assert_eq!(buf[4], 91);
+        assert!(buf[5..].iter().all(|&b| b == 0x5a));
+        assert_eq!(*residues.lock().unwrap(), [0, 0]);
+        assert_eq!(msc.timed_out_phase(), None);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn inquiry_shorter_than_requested_is_zero_filled() {
+        let residues = Arc::new(Mutex::new(Vec::new()));
+        let (mock, mut msc) = disk(inquiry_disk(36, residues.clone()));
+
+        let mut buf = [0xaa; 96];
+        assert_eq!(msc.inquiry_prefix(0, &mut buf).unwrap(), 36);
+        assert_eq!(buf[4], 31);
+        assert!(buf[5..36].iter().all(|&b| b == 0x5a));
+        assert!(buf[36..].iter().all(|&b| b == 0));
+
+        let mut buf = [0xaa; 96];
+        let cdb = [scsi_op::INQUIRY, 0, 0, 0, 96, 0];
+        let result = msc
+            .pass_through(0, &cdb, DataPhase::In(&mut buf), 0)
+            .unwrap();
+        assert_eq!(result.transferred, 36);
+        assert_eq!(result.status, Csw::STATUS_PASSED);
+        assert!(buf[36..].iter().all(|&b| b == 0));
+        assert_eq!(*residues.lock().unwrap(), [60, 60]);
+        assert_eq!(msc.timed_out_phase(), None);
+        // No reset recovery: the residue was no phase error
+        let resets = mock.with_device(0, 0, |d| {
+            d.setups.iter().filter(|s| s.request == 0xff).count()
+        });
+        assert_eq!(resets, 0);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    #[test]
+    fn uas_stick_binds_its_bulk_only_setting() {
+        use crate::{desc::request, hid::find_hid_interfaces};
+
+        // A SuperSpeed stick offering Bulk-Only in alternate setting 0 and
+        // UAS in alternate setting 1, each endpoint with its companion
+        let bulk = |address: u8, usage: Option<u8>| {
+            let mut desc = mock::endpoint(address, 0x02, 1024, 0);
+            match usage {
+                // 32 streams, and the UAS pipe usage
+                Some(pipe) => desc.extend([6, 0x30, 15, 5, 0, 0, 4, 0x24, pipe, 0]),
+                None => desc.extend([6, 0x30, 15, 0, 0, 0]),
+            }
+            desc
+        };
+        let config = mock::config(
+            1,
+            &[
+                mock::interface(
+                    0,
+                    0,
+                    (
+                        class::MASS_STORAGE,
+                        msc_subclass::SCSI_TRANSPARENT,
+                        msc_protocol::BBB,
+                    ),
+                    2,
+                ),
+                bulk(0x81, None),
+                bulk(0x02, None),
+                mock::interface(
+                    0,
+                    1,
+                    (
+                        class::MASS_STORAGE,
+                        msc_subclass::SCSI_TRANSPARENT,
+                        msc_protocol::UAS,
+                    ),
+                    4,
+                ),
+                bulk(0x81, Some(3)),
+                bulk(0x02, Some(4)),
+                bulk(0x83, Some(2)),
+                bulk(0x04, Some(1)),
+            ],
+        );
+        let stick = mock::MockDevice::new(
+            reg::SPEED_SUPER,
+            mock::device_desc(0, 0x174c, 0x55aa, 1),
+            &[config],
+        )
+        .with_handler(mock::bulk_only_disk(0x81, 0x02, 64));
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, stick);
+        let dev = Arc::new(UsbDevice::new(ctrl, 0).unwrap());
+        let tree = dev.choose_configuration(default_config_policy).unwrap();
+
+        // Only the Bulk-Only setting is a mass storage interface here
+        let found = find_msc_interfaces(tree.raw());
+        assert_eq!(found.len(), 1);
+        let (iface, ep_in, ep_out) = found[0];
+        assert_eq!(
+            (iface.alternate_setting, iface.interface_protocol),
+            (0, msc_protocol::BBB)
+        );
+        assert_eq!(
+            (ep_in.endpoint_address, ep_out.endpoint_address),
+            (0x81, 0x02)
+        );
+        assert!(find_cbi_interfaces(tree.raw()).is_empty());
+        assert!(find_hid_interfaces(tree.raw()).is_empty());
+
+        let setups = mock.with_device(0, 0, |d| d.setups.len());
+        let mut msc = MscDevice::from_interface(dev, &iface, &ep_in, &ep_out).unwrap();
+        let mut block = [0; 512];
+        assert_eq!(msc.read_blocks(0, 7, 1, &mut block).unwrap(), 512);
+        assert!(block.iter().all(|&b| b == 7));
+        // Alternate setting 0 is current after SET_CONFIGURATION
+        let set_interface = mock.with_device(0, 0, |d| {
+            d.setups[setups..]
+                .iter()
+                .any(|s| s.request == request::SET_INTERFACE)
+        });
+        assert!(!set_interface);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
+
+    /// Fixed format sense data with sense-key specific bytes `specific`
+    fn sense(key: u8, code: u8, qualifier: u8, specific: [u8; 3]) -> [u8; 18] {
+        let mut data = [0; 18];
+        data[0] = 0x70;
+        data[2] = key;
+        data[7] = 10;
+        data[12..14].copy_from_slice(&[code, qualifier]);
+        data[15..18].copy_from_slice(&specific);
+        data
+    }
+
+    /// A Bulk-Only unit of `subclass` that is not ready while `senses`
+    /// has entries left
+    ///
+    /// TEST UNIT READY fails until the senses run out, and REQUEST SENSE
+    /// then returns the next one. Other commands pass without data. Each
+    /// CBW is logged with the data the host sent after it.
+    #[allow(clippy::type_complexity)]
+    fn unit(
+        subclass: u8,
+        senses: &[[u8; 18]],
+    ) -> (
+        mock::Mock,
+        MscDevice<MockHost>,
+        Arc<Mutex<Vec<(Cbw, Vec<u8>)>>>,
+    ) {
+        use alloc::collections::VecDeque;
+
+        let config = mock::config(
+            1,
+            &[
+                mock::interface(0, 0, (class::MASS_STORAGE, subclass, 0x50), 2),
+                mock::endpoint(0x81, 0x02, 64, 0),
+                mock::endpoint(0x02, 0x02, 64, 0),
+            ],
+        );
+        let log = Arc::new(Mutex::new(Vec::<(Cbw, Vec<u8>)>::new()));
+        let cbws = log.clone();
+        let mut steps: VecDeque<[u8; 18]> = senses.iter().copied().collect();
+        let mut pending = None;
+        let mut replies = VecDeque::new();
+        let handler = move |r: &Request<'_>| match *r {
+            Request::Control { setup, .. } if setup.request == 0xfe => {
+                Some(Reply::Data(alloc::vec![0]))
+            }
+            Request::Transfer { ep: 0x02, data, .. } => {
+                let Some(cbw) = Cbw::from_bytes(data).filter(|_| data.len() == Cbw::SIZE) else {
+                    // Parameter list of the last command
+                    cbws.lock().unwrap().last_mut()?.1.extend_from_slice(data);
+                    return Some(Reply::Ack);
+                };
+                let status = match cbw.cb[0] {
+                    scsi_op::TEST_UNIT_READY => {
+                        pending = steps.pop_front();
+                        if pending.is_some() {
+                            Csw::STATUS_FAILED
                         } else {
-                            ep_out = Some(ep);
+                            Csw::STATUS_PASSED
                         }
                     }
-                }
+                    scsi_op::REQUEST_SENSE => {
+                        let data = pending.take().unwrap_or(sense(0, 0, 0, [0; 3]));
+                        replies.push_back(data.to_vec());
+                        Csw::STATUS_PASSED
+                    }
+                    _ => Csw::STATUS_PASSED,
+                };
+                let csw = Csw {
+                    signature: Csw::SIGNATURE,
+                    tag: cbw.tag,
+                    data_residue: 0,
+                    status,
+                };
+                replies.push_back(csw.to_bytes().to_vec());
+                cbws.lock().unwrap().push((cbw, Vec::new()));
+                Some(Reply::Ack)
+            }
+            Request::Transfer { ep: 0x81, .. } => replies.pop_front().map(Reply::Data),
+            _ => None,
+        };
+        let desc = mock::device_desc(0, 0x057b, 0x0000, 1);
+        let device = mock::MockDevice::new(reg::SPEED_FULL, desc, &[config]);
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, device.with_handler(handler));
+
+        let dev = Arc::new(UsbDevice::new(ctrl, 0).unwrap());
+        let tree = dev.choose_configuration(default_config_policy).unwrap();
+        let (iface, ep_in, ep_out) = find_msc_interfaces(tree.raw())[0];
+        let msc = MscDevice::from_interface(dev, &iface, &ep_in, &ep_out).unwrap();
+        (mock, msc, log)
+    }
+
+    /// A UFI floppy drive, formatting while `progress` has steps left
+    #[allow(clippy::type_complexity)]
+    fn floppy(
+        progress: &[u16],
+    ) -> (
+        mock::Mock,
+        MscDevice<MockHost>,
+        Arc<Mutex<Vec<(Cbw, Vec<u8>)>>>,
+    ) {
+        let senses: Vec<_> = progress
+            .iter()
+            .map(|p| {
+                let [hi, lo] = p.to_be_bytes();
+                let code = (asc::NOT_READY, asc::FORMAT_IN_PROGRESS);
+                sense(sense_key::NOT_READY, code.0, code.1, [0x80, hi, lo])
+            })
+            .collect();
+        unit(msc_subclass::UFI, &senses)
+    }
+
+    #[test]
+    fn ufi_format_reports_progress_until_ready() {
+        let (mock, mut msc, log) = floppy(&[0x4000, 0xc000]);
+        let mut format = msc.format_unit(0, &FormatParams::new(2880, 512)).unwrap();
+
+        // 12-byte UFI FORMAT UNIT with a format descriptor for 2880 x 512
+        let (cbw, list) = log.lock().unwrap()[0].clone();
+        assert_eq!(cbw.cb_length, 12);
+        assert_eq!(cbw.cb[..12], [0x04, 0x17, 0, 0, 0, 0, 0, 0, 12, 0, 0, 0]);
+        assert_eq!(({ cbw.data_transfer_length }, cbw.flags), (12, 0));
+        assert_eq!(list, [0, 0xa0, 0, 8, 0, 0, 0x0b, 0x40, 0, 0, 2, 0]);
+
+        let mut polled = Vec::new();
+        loop {
+            let progress = format.progress(&mut msc).unwrap();
+            polled.push(progress);
+            if progress == FormatProgress::Complete {
+                break;
             }
-            _ => {}
         }
+        assert_eq!(
+            polled,
+            [
+                FormatProgress::InProgress(Some(0x4000)),
+                FormatProgress::InProgress(Some(0xc000)),
+                FormatProgress::Complete,
+            ]
+        );
+        let percent: Vec<_> = polled.iter().map(FormatProgress::percent).collect();
+        assert_eq!(percent, [Some(25), Some(75), Some(100)]);
 
-        offset += len;
+        // Every command went out padded to 12 bytes
+        let opcodes: Vec<_> = log
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(c, _)| (c.cb[0], c.cb_length))
+            .collect();
+        let (tur, sense) = ((scsi_op::TEST_UNIT_READY, 12), (scsi_op::REQUEST_SENSE, 12));
+        assert_eq!(
+            opcodes,
+            [(scsi_op::FORMAT_UNIT, 12), tur, sense, tur, sense, tur]
+        );
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
     }
 
-    // Save last interface if complete
-    if let (Some(iface), Some(ein), Some(eout)) = (current_iface, ep_in, ep_out) {
-        result.push((iface, ein, eout));
+    #[test]
+    fn format_progress_times_out() {
+        let (mock, mut msc, _log) = floppy(&[0x1000; 100]);
+        let params = FormatParams {
+            timeout_min: 1,
+            ..FormatParams::new(2880, 512)
+        };
+        let mut format = msc.format_unit(0, &params).unwrap();
+
+        // Polled once a second, the format is still running after a minute
+        for _ in 0..60 {
+            let progress = format.progress(&mut msc).unwrap();
+            assert_eq!(progress, FormatProgress::InProgress(Some(0x1000)));
+            mock.advance_us(1_000_000);
+        }
+        assert!(matches!(format.progress(&mut msc), Err(UsbError::Timeout)));
     }
 
-    result
+    /// NOT READY, becoming ready
+    const BECOMING_READY: [u8; 18] = [
+        0x70,
+        0,
+        sense_key::NOT_READY,
+        0,
+        0,
+        0,
+        0,
+        10,
+        0,
+        0,
+        0,
+        0,
+        0x04,
+        0x01,
+        0,
+        0,
+        0,
+        0,
+    ];
+
+    /// TEST UNIT READY commands `log` has seen
+    fn test_unit_readies(log: &Mutex<Vec<(Cbw, Vec<u8>)>>) -> usize {
+        let log = log.lock().unwrap();
+        log.iter()
+            .filter(|(cbw, _)| cbw.cb[0] == scsi_op::TEST_UNIT_READY)
+            .count()
+    }
+
+    #[test]
+    fn wait_ready_retries_until_the_unit_is_ready() {
+        let attention = sense(sense_key::UNIT_ATTENTION, 0x28, 0, [0; 3]);
+        let senses = [BECOMING_READY, BECOMING_READY, attention];
+        let (mock, mut msc, log) = unit(msc_subclass::SCSI_TRANSPARENT, &senses);
+        let warnings = mock.warnings().len();
+        let start = mock.now_us();
+
+        let policy = ReadyPolicy {
+            attempts: 5,
+            delay_us: 250_000,
+        };
+        assert_eq!(msc.wait_ready(0, policy).unwrap(), UnitReadiness::Ready);
+        assert_eq!(test_unit_readies(&log), 4);
+        assert!(mock.now_us() - start >= 750_000);
+
+        // Every failed attempt is reported
+        let warnings = &mock.warnings()[warnings..];
+        assert_eq!(warnings.len(), 3, "{warnings:?}");
+        assert!(
+            warnings[0].contains("sense 0x2/0x04/0x01"),
+            "{}",
+            warnings[0]
+        );
+        assert!(
+            warnings[2].contains("sense 0x6/0x28/0x00"),
+            "{}",
+            warnings[2]
+        );
+        assert!(warnings[2].ends_with("attempt 3 of 5"), "{}", warnings[2]);
+    }
+
+    #[test]
+    fn wait_ready_returns_missing_medium_at_once() {
+        let no_medium = sense(sense_key::NOT_READY, asc::MEDIUM_NOT_PRESENT, 0, [0; 3]);
+        let (mock, mut msc, log) = unit(msc_subclass::SCSI_TRANSPARENT, &[no_medium]);
+        let start = mock.now_us();
+        let readiness = msc.wait_ready(0, ReadyPolicy::default()).unwrap();
+        assert_eq!(readiness, UnitReadiness::NoMedium);
+        assert_eq!(test_unit_readies(&log), 1);
+        assert!(mock.now_us() - start < ReadyPolicy::default().delay_us as u64);
+    }
+
+    #[test]
+    fn wait_ready_gives_up() {
+        // Out of attempts
+        let (_mock, mut msc, log) = unit(msc_subclass::SCSI_TRANSPARENT, &[BECOMING_READY; 10]);
+        let policy = ReadyPolicy {
+            attempts: 3,
+            delay_us: 1000,
+        };
+        assert!(matches!(msc.wait_ready(0, policy), Err(UsbError::Timeout)));
+        assert_eq!(test_unit_readies(&log), 3);
+
+        // Errors that waiting does not fix are returned at once
+        let medium_error = sense(sense_key::MEDIUM_ERROR, 0x11, 0, [0; 3]);
+        let (_mock, mut msc, log) = unit(msc_subclass::SCSI_TRANSPARENT, &[medium_error]);
+        let result = msc.wait_ready(0, policy);
+        assert!(matches!(
+            result,
+            Err(UsbError::XferFail(Csw::STATUS_FAILED))
+        ));
+        assert_eq!(test_unit_readies(&log), 1);
+    }
 }
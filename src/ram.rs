@@ -4,6 +4,23 @@
 ///
 /// Used for DMA operations requiring contiguous physical memory
 /// and for mapping xHCI controller registers.
+///
+/// # Memory ordering
+///
+/// The driver builds rings, contexts and buffers with ordinary stores to
+/// memory returned by `alloc`, then hands them to the controller with an
+/// MMIO write: a doorbell, or CRCR, DCBAAP, ERSTBA or ERDP. It calls
+/// `wmb` between the two, so the controller never sees the MMIO write
+/// before the stores it publishes.
+///
+/// The implementor must guarantee that:
+///
+/// - DMA memory is coherent with the controller, i.e. mapped uncached or
+///   kept coherent by hardware snooping; the driver never flushes or
+///   invalidates caches
+/// - MMIO mappings are device memory (uncached, no write combining), so
+///   register accesses reach the controller in program order
+/// - `wmb` orders all earlier memory accesses before any later MMIO write
 pub trait Dma: Send + Sync {
     /// Allocates a `size` byte region of physically contiguous memory
     /// with the specified alignment.
@@ -32,6 +49,27 @@ pub trait Dma: Send + Sync {
     /// - `size` and `align` must match the original allocation
     unsafe fn free(&self, addr: usize, size: usize, align: usize);
 
+    /// Allocates like `alloc`, but entirely below 4 GiB physical.
+    ///
+    /// Used for controllers that cannot do 64-bit DMA. The default falls
+    /// back to `alloc` and fails if the region ends above 4 GiB; hosts
+    /// with a low memory zone should override it. The region is released
+    /// with `free`.
+    ///
+    /// # Safety
+    ///
+    /// Same as `alloc`.
+    unsafe fn alloc_dma32(&self, size: usize, align: usize) -> Option<usize> {
+        let addr = unsafe { self.alloc(size, align) }?;
+        if self.virt_to_phys(addr) as u64 + size as u64 > 1 << 32 {
+            unsafe {
+                self.free(addr, size, align);
+            }
+            return None;
+        }
+        Some(addr)
+    }
+
     /// Maps an MMIO region into virtual address space.
     ///
     /// Returns the virtual address, or `None` on failure.
@@ -56,4 +94,63 @@ pub trait Dma: Send + Sync {
     fn page_size(&self) -> usize {
         4096
     }
+
+    /// Busy-waits for at least `us` microseconds.
+    ///
+    /// Times the BIOS handoff, the post-reset quirk delay, connect and
+    /// reconnect debouncing, resume signalling, control transfer retry
+    /// backoff and `MscDevice::wait_ready` polling.
+    ///
+    /// Must be timed by a clock, e.g. the TSC or a platform timer: the
+    /// driver relies on it for delays the specification sets, such as
+    /// the 1 ms after a controller reset. There is no default because a
+    /// spin loop cannot tell how long it has run.
+    fn delay_us(&self, us: u32);
+
+    /// Returns a monotonic timestamp in microseconds, if the host has a clock.
+    ///
+    /// Only used to time benchmarks. The default has no clock; the
+    /// controller's frame counter is used instead.
+    fn now_us(&self) -> Option<u64> {
+        None
+    }
+
+    /// Reads a dword from the controller's PCI configuration space.
+    ///
+    /// Only used by controller quirks. The default reports the space as
+    /// inaccessible.
+    fn pci_read_config(&self, _offset: u16) -> Option<u32> {
+        None
+    }
+
+    /// Writes a dword to the controller's PCI configuration space.
+    ///
+    /// Only used by controller quirks, e.g. to route Intel shared ports
+    /// to xHCI. The default ignores the write.
+    fn pci_write_config(&self, _offset: u16, _val: u32) {}
+
+    /// Called when a DMA allocation of `size` bytes failed.
+    ///
+    /// The host may shed caches or other memory and return true to have
+    /// the allocation retried once. The default returns false, failing
+    /// the operation with `OoRam`.
+    fn low_memory(&self, _size: usize, _align: usize) -> bool {
+        false
+    }
+
+    /// Write barrier between DMA memory stores and the MMIO write that
+    /// publishes them.
+    ///
+    /// The default is `fence(Release)`. On Arm that is a `dmb ish`, which
+    /// only orders accesses within the inner shareable domain; hosts whose
+    /// controller sits outside it should override this with a `dsb st` or
+    /// a `dmb oshst`.
+    fn wmb(&self) {
+        core::sync::atomic::fence(core::sync::atomic::Ordering::Release);
+    }
+
+    /// Reports a condition worth a warning, e.g. an outdated controller.
+    ///
+    /// The default ignores it.
+    fn warn(&self, _args: core::fmt::Arguments<'_>) {}
 }
@@ -24,6 +24,63 @@ pub const RTSOFF: usize = 0x18;
 /// Capability Parameters 2
 pub const HCCPARAMS2: usize = 0x1C;
 
+// ============================================================================
+// HCCPARAMS1 Register Bits
+// ============================================================================
+
+/// 64-bit Addressing Capability
+pub const HCCPARAMS1_AC64: u32 = 1 << 0;
+/// Context Size (64-byte contexts)
+pub const HCCPARAMS1_CSZ: u32 = 1 << 2;
+/// Port Power Control
+pub const HCCPARAMS1_PPC: u32 = 1 << 3;
+/// Port Indicators
+pub const HCCPARAMS1_PIND: u32 = 1 << 4;
+/// Light HC Reset Capability
+pub const HCCPARAMS1_LHRC: u32 = 1 << 5;
+/// Latency Tolerance Messaging Capability
+pub const HCCPARAMS1_LTC: u32 = 1 << 6;
+/// Stopped EDTLA Capability (xHCI 1.0 and later)
+pub const HCCPARAMS1_SEC: u32 = 1 << 9;
+
+// ============================================================================
+// HCCPARAMS2 Register Bits
+// ============================================================================
+
+/// U3 Entry Capability
+pub const HCCPARAMS2_U3C: u32 = 1 << 0;
+/// Configure Endpoint Command Max Exit Latency Too Large Capability
+pub const HCCPARAMS2_CMC: u32 = 1 << 1;
+/// Force Save Context Capability
+pub const HCCPARAMS2_FSC: u32 = 1 << 2;
+/// Compliance Transition Capability
+pub const HCCPARAMS2_CTC: u32 = 1 << 3;
+/// Large ESIT Payload Capability
+pub const HCCPARAMS2_LEC: u32 = 1 << 4;
+/// Configuration Information Capability
+pub const HCCPARAMS2_CIC: u32 = 1 << 5;
+/// Extended TBC Capability (xHCI 1.1 and later)
+pub const HCCPARAMS2_ETC: u32 = 1 << 6;
+/// Extended TBC TRB Status Capability (xHCI 1.1 and later)
+pub const HCCPARAMS2_ETC_TSC: u32 = 1 << 7;
+
+// ============================================================================
+// PCI Identifiers and Intel Port Routing (PCI configuration space)
+// ============================================================================
+
+/// Intel PCI vendor ID
+pub const PCI_VENDOR_INTEL: u16 = 0x8086;
+/// ASMedia PCI vendor ID
+pub const PCI_VENDOR_ASMEDIA: u16 = 0x1B21;
+/// xHCI USB2 Port Routing
+pub const PCI_XUSB2PR: u16 = 0xD0;
+/// xHCI USB2 Port Routing Mask
+pub const PCI_USB2PRM: u16 = 0xD4;
+/// USB3 Port SuperSpeed Enable
+pub const PCI_USB3_PSSEN: u16 = 0xD8;
+/// USB3 Port Routing Mask
+pub const PCI_USB3PRM: u16 = 0xDC;
+
 // ============================================================================
 // Operational Registers (offset from operational base)
 // ============================================================================
@@ -97,6 +154,19 @@ pub const USBSTS_CNR: u32 = 1 << 11;
 /// Host Controller Error
 pub const USBSTS_HCE: u32 = 1 << 12;
 
+// ============================================================================
+// CRCR Register Bits
+// ============================================================================
+
+/// Ring Cycle State
+pub const CRCR_RCS: u64 = 1 << 0;
+/// Command Stop
+pub const CRCR_CS: u64 = 1 << 1;
+/// Command Abort
+pub const CRCR_CA: u64 = 1 << 2;
+/// Command Ring Running
+pub const CRCR_CRR: u64 = 1 << 3;
+
 // ============================================================================
 // Port Register Set (offset from port register set base)
 // ============================================================================
@@ -159,6 +229,51 @@ pub const PORTSC_DR: u32 = 1 << 30;
 /// Warm Port Reset
 pub const PORTSC_WPR: u32 = 1 << 31;
 
+/// PORTSC bits that must be written back unchanged (RW, non-strobe)
+pub const PORTSC_PRESERVE: u32 =
+    PORTSC_PP | PORTSC_PIC_MASK | PORTSC_WCE | PORTSC_WDE | PORTSC_WOE;
+/// PORTSC change bits (RW1C)
+pub const PORTSC_CHANGE_MASK: u32 = PORTSC_CSC
+    | PORTSC_PEC
+    | PORTSC_WRC
+    | PORTSC_OCC
+    | PORTSC_PRC
+    | PORTSC_PLC
+    | PORTSC_CEC;
+
+// ============================================================================
+// PORTPMSC Register Bits (USB3 protocol ports)
+// ============================================================================
+
+/// U1 Timeout (bits 7:0, in microseconds)
+pub const PORTPMSC_U1_TIMEOUT_MASK: u32 = 0xFF;
+/// U2 Timeout (bits 15:8, in 256 microsecond units)
+pub const PORTPMSC_U2_TIMEOUT_MASK: u32 = 0xFF << 8;
+/// Force Link PM Accept
+pub const PORTPMSC_FLA: u32 = 1 << 16;
+
+// ============================================================================
+// PORTPMSC / PORTHLPMC Register Bits (USB2 protocol ports)
+// ============================================================================
+
+/// L1 Status (bits 2:0)
+pub const PORTPMSC_L1S_MASK: u32 = 0x7;
+/// Remote Wake Enable
+pub const PORTPMSC_RWE: u32 = 1 << 3;
+/// Best Effort Service Latency (bits 7:4)
+pub const PORTPMSC_BESL_MASK: u32 = 0xF << 4;
+/// L1 Device Slot (bits 15:8)
+pub const PORTPMSC_L1DS_MASK: u32 = 0xFF << 8;
+/// Hardware LPM Enable
+pub const PORTPMSC_HLE: u32 = 1 << 16;
+
+/// Host Initiated Resume Duration Mode (bits 1:0)
+pub const PORTHLPMC_HIRDM_MASK: u32 = 0x3;
+/// L1 Timeout (bits 9:2, in 256 microsecond units)
+pub const PORTHLPMC_L1_TIMEOUT_MASK: u32 = 0xFF << 2;
+/// Best Effort Service Latency Deep (bits 13:10)
+pub const PORTHLPMC_BESLD_MASK: u32 = 0xF << 10;
+
 // ============================================================================
 // Port Link States
 // ============================================================================
@@ -205,6 +320,15 @@ pub const SPEED_SUPER: u8 = 4;
 /// SuperSpeed Plus (10 Gbps)
 pub const SPEED_SUPER_PLUS: u8 = 5;
 
+// ============================================================================
+// Runtime Registers (offset from runtime register base)
+// ============================================================================
+
+/// Microframe Index Register
+pub const MFINDEX: usize = 0x00;
+/// Microframe Index mask (bits 13:0)
+pub const MFINDEX_MASK: u32 = 0x3FFF;
+
 // ============================================================================
 // Interrupter Registers (offset from runtime register base + 0x20 * n)
 // ============================================================================
@@ -229,6 +353,15 @@ pub const IMAN_IP: u32 = 1 << 0;
 /// Interrupt Enable
 pub const IMAN_IE: u32 = 1 << 1;
 
+// ============================================================================
+// ERDP Register Bits
+// ============================================================================
+
+/// Event Handler Busy (RW1C)
+pub const ERDP_EHB: u64 = 1 << 3;
+/// Dequeue ERST Segment Index (DESI)
+pub const ERDP_DESI_MASK: u64 = 0x7;
+
 // ============================================================================
 // Extended Capability IDs
 // ============================================================================
@@ -250,10 +383,81 @@ pub const ECAP_USB_DEBUG: u8 = 10;
 /// Extended Message Interrupt
 pub const ECAP_EXT_MSG_INT: u8 = 17;
 
+// ============================================================================
+// USB Legacy Support Capability (offset from capability base)
+// ============================================================================
+
+/// USB Legacy Support Control/Status (USBLEGCTLSTS)
+pub const USBLEG_CTLSTS: usize = 0x04;
+/// HC BIOS Owned Semaphore (in USBLEGSUP)
+pub const USBLEGSUP_BIOS_OWNED: u32 = 1 << 16;
+/// HC OS Owned Semaphore (in USBLEGSUP)
+pub const USBLEGSUP_OS_OWNED: u32 = 1 << 24;
+/// SMI enable bits of USBLEGCTLSTS (USB, Host System Error, OS Ownership,
+/// PCI Command, BAR)
+pub const USBLEGCTLSTS_SMI_ENABLES: u32 = (1 << 0) | (1 << 4) | (1 << 13) | (1 << 14) | (1 << 15);
+/// SMI event bits of USBLEGCTLSTS (RW1C)
+pub const USBLEGCTLSTS_SMI_EVENTS: u32 = (1 << 29) | (1 << 30) | (1 << 31);
+
+// ============================================================================
+// Supported Protocol Capability (offset from capability base)
+// ============================================================================
+
+/// Name string / Port offset, count and protocol defined field dword
+pub const SUPP_PROTO_PORTS: usize = 0x08;
+/// Hardware LPM Capability (USB2 protocol, in the ports dword)
+pub const SUPP_PROTO_HLC: u32 = 1 << 19;
+/// BESL LPM Capability (USB2 protocol, in the ports dword)
+pub const SUPP_PROTO_BLC: u32 = 1 << 20;
+/// Supported Protocol capability size without its PSI dwords
+pub const SUPP_PROTO_SIZE: usize = 0x10;
+
+// ============================================================================
+// Debug Capability (offset from capability base)
+// ============================================================================
+
+/// Doorbell (DCDB)
+pub const DBC_DCDB: usize = 0x04;
+/// Event Ring Segment Table Size (DCERSTSZ)
+pub const DBC_DCERSTSZ: usize = 0x08;
+/// Event Ring Segment Table Base Address (DCERSTBA, 64-bit)
+pub const DBC_DCERSTBA: usize = 0x10;
+/// Event Ring Dequeue Pointer (DCERDP, 64-bit)
+pub const DBC_DCERDP: usize = 0x18;
+/// Control (DCCTRL)
+pub const DBC_DCCTRL: usize = 0x20;
+/// Status (DCST)
+pub const DBC_DCST: usize = 0x24;
+/// Port Status and Control (DCPORTSC)
+pub const DBC_DCPORTSC: usize = 0x28;
+/// Context Pointer (DCCP, 64-bit)
+pub const DBC_DCCP: usize = 0x30;
+/// Device Descriptor Info 1 (DCDDI1)
+pub const DBC_DCDDI1: usize = 0x38;
+/// Device Descriptor Info 2 (DCDDI2)
+pub const DBC_DCDDI2: usize = 0x3C;
+/// Debug Capability register block size
+pub const DBC_SIZE: usize = 0x40;
+/// DbC Run (in DCCTRL)
+pub const DCCTRL_DCR: u32 = 1 << 0;
+/// DbC Enable (in DCCTRL)
+pub const DCCTRL_DCE: u32 = 1 << 31;
+
 // ============================================================================
 // Helper Functions
 // ============================================================================
 
+/// Decodes HCIVERSION from the dword at CAPLENGTH as (major, minor).
+///
+/// The BCD minor version is returned in hundredths, so versions compare
+/// in order: 0.96 is (0, 96), 1.0 is (1, 0) and 1.1 is (1, 10).
+pub const fn hci_version(caplength: u32) -> (u8, u8) {
+    const fn bcd(byte: u32) -> u8 {
+        ((byte >> 4) * 10 + (byte & 0xF)) as u8
+    }
+    (bcd((caplength >> 24) & 0xFF), bcd((caplength >> 16) & 0xFF))
+}
+
 /// Returns the base offset for a port's register set.
 pub const fn port_reg_base(cap_length: u8, port: u8) -> usize {
     cap_length as usize + 0x400 + (port as usize * 0x10)
@@ -264,8 +468,13 @@ pub const fn doorbell(db_offset: u32, slot: u8) -> usize {
     db_offset as usize + (slot as usize * 4)
 }
 
+/// Creates a doorbell register value (DB Target and DB Stream ID).
+pub const fn doorbell_value(target: u8, stream_id: u16) -> u32 {
+    (target as u32) | ((stream_id as u32) << 16)
+}
+
 /// Returns the base offset for an interrupter's register set.
-pub const fn interrupter_base(rts_offset: u32, interrupter: u8) -> usize {
+pub const fn interrupter_base(rts_offset: u32, interrupter: u16) -> usize {
     rts_offset as usize + 0x20 + (interrupter as usize * 0x20)
 }
 
@@ -279,6 +488,26 @@ pub const fn portsc_pls(portsc: u32) -> u8 {
     ((portsc >> 5) & 0xF) as u8
 }
 
+/// Creates a USB3 PORTPMSC value with the specified U1/U2 timeouts.
+pub const fn portpmsc_u1u2(u1_timeout: u8, u2_timeout: u8) -> u32 {
+    (u1_timeout as u32) | ((u2_timeout as u32) << 8)
+}
+
+/// Creates a USB2 PORTPMSC value enabling hardware LPM for a device slot.
+pub const fn portpmsc_hle(slot: u8, besl: u8) -> u32 {
+    PORTPMSC_HLE | PORTPMSC_RWE | ((slot as u32) << 8) | (((besl & 0xF) as u32) << 4)
+}
+
+/// Returns the offset of the first extended capability from HCCPARAMS1.
+pub const fn hccparams1_xecp(hccparams1: u32) -> usize {
+    ((hccparams1 >> 16) as usize) << 2
+}
+
+/// Creates a PORTSC value with the specified Port Indicator Control.
+pub const fn portsc_pic(pic: u32) -> u32 {
+    (pic & 0x3) << 14
+}
+
 /// Creates a PORTSC value with the specified Port Link State.
 pub const fn portsc_set_pls(pls: u32) -> u32 {
     (pls & 0xF) << 5
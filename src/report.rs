@@ -0,0 +1,376 @@
+//! HID report descriptor parsing.
+//!
+//! Lays out the data fields of each input report from the items of a
+//! report descriptor, so reports that do not follow the Boot Protocol
+//! can still be decoded.
+
+use alloc::vec::Vec;
+
+/// Main item flag bits of a report field.
+pub mod field_flags {
+    /// Constant (padding) rather than data
+    pub const CONSTANT: u32 = 1 << 0;
+    /// One value per usage rather than an array of usage indices
+    pub const VARIABLE: u32 = 1 << 1;
+    /// Relative rather than absolute values
+    pub const RELATIVE: u32 = 1 << 2;
+}
+
+/// Item types (bType)
+mod item_type {
+    pub const MAIN: u8 = 0;
+    pub const GLOBAL: u8 = 1;
+    pub const LOCAL: u8 = 2;
+}
+
+/// Item tags (bTag) by item type
+mod item_tag {
+    // Main items
+    pub const INPUT: u8 = 0x8;
+    pub const COLLECTION: u8 = 0xA;
+    pub const END_COLLECTION: u8 = 0xC;
+
+    // Global items
+    pub const USAGE_PAGE: u8 = 0x0;
+    pub const LOGICAL_MIN: u8 = 0x1;
+    pub const LOGICAL_MAX: u8 = 0x2;
+    pub const REPORT_SIZE: u8 = 0x7;
+    pub const REPORT_ID: u8 = 0x8;
+    pub const REPORT_COUNT: u8 = 0x9;
+    pub const PUSH: u8 = 0xA;
+    pub const POP: u8 = 0xB;
+
+    // Local items
+    pub const USAGE: u8 = 0x0;
+    pub const USAGE_MIN: u8 = 0x1;
+    pub const USAGE_MAX: u8 = 0x2;
+}
+
+/// Prefix of a long item
+const LONG_ITEM: u8 = 0xFE;
+
+/// Collection item data of an Application collection
+const APPLICATION_COLLECTION: u32 = 0x01;
+
+/// Global item state, saved and restored by Push and Pop
+#[derive(Clone, Copy, Default)]
+struct Globals {
+    usage_page: u16,
+    logical_min: i32,
+    /// Raw Logical Maximum and its size in bits; its sign depends on
+    /// Logical Minimum, which may come later
+    logical_max: (u32, u32),
+    report_size: u32,
+    report_count: u32,
+    report_id: u8,
+}
+
+/// Data field of an input report.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ReportField {
+    /// Report ID the field belongs to; 0 if the device uses no report IDs
+    pub report_id: u8,
+    /// Offset of the first element in bits, after the report ID byte
+    pub bit_offset: u32,
+    /// Size of one element in bits (1 to 32)
+    pub bit_size: u32,
+    /// Number of elements
+    pub count: u32,
+    /// Main item flags (see `field_flags`)
+    pub flags: u32,
+    /// Logical Minimum
+    pub logical_min: i32,
+    /// Logical Maximum
+    pub logical_max: i32,
+    /// Usages as inclusive ranges of extended usages (page << 16 | ID)
+    usages: Vec<(u32, u32)>,
+}
+
+impl ReportField {
+    /// Returns true if the field holds one value per usage.
+    pub fn is_variable(&self) -> bool {
+        self.flags & field_flags::VARIABLE != 0
+    }
+
+    /// Returns true if values are relative to the previous report.
+    pub fn is_relative(&self) -> bool {
+        self.flags & field_flags::RELATIVE != 0
+    }
+
+    /// Returns the `index`th usage of the field as an extended usage.
+    ///
+    /// For a variable field this is the usage of element `index`; for an
+    /// array, of the element value `logical_min + index`.
+    pub fn usage(&self, index: u32) -> Option<u32> {
+        let mut index = index;
+        for &(min, max) in &self.usages {
+            if index <= max - min {
+                return Some(min + index);
+            }
+            index -= max - min + 1;
+        }
+        None
+    }
+
+    /// Returns the usage page of the first usage of the field.
+    pub fn usage_page(&self) -> Option<u16> {
+        self.usage(0).map(|usage| (usage >> 16) as u16)
+    }
+
+    /// Reads element `index` from the report data after the report ID.
+    ///
+    /// Sign-extended when Logical Minimum is negative. `None` if the
+    /// element lies past the end of `data`.
+    pub fn value(&self, data: &[u8], index: u32) -> Option<i32> {
+        if index >= self.count {
+            return None;
+        }
+
+        let start = self.bit_offset as u64 + index as u64 * self.bit_size as u64;
+        let raw = read_bits(data, start, self.bit_size)?;
+        Some(if self.logical_min < 0 {
+            sign_extend(raw, self.bit_size)
+        } else {
+            raw as i32
+        })
+    }
+}
+
+/// Parsed HID report descriptor.
+#[derive(Clone, Debug, Default)]
+pub struct ReportDescriptor {
+    /// Data fields of all input reports in descriptor order; constant
+    /// padding is left out
+    pub inputs: Vec<ReportField>,
+    report_ids: bool,
+    /// Input report sizes in bits by report ID
+    input_bits: Vec<(u8, u32)>,
+    /// Extended usages of the top-level Application collections
+    applications: Vec<u32>,
+}
+
+impl ReportDescriptor {
+    /// Parses a report descriptor.
+    ///
+    /// Output and feature reports are skipped, as are input fields with
+    /// elements wider than 32 bits. `None` if an item runs past the end,
+    /// a Pop has no matching Push or a report exceeds `u32::MAX` bits.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let mut desc = Self::default();
+        let mut globals = Globals::default();
+        let mut stack: Vec<Globals> = Vec::new();
+        let mut usages: Vec<(u32, u32)> = Vec::new();
+        let mut usage_min: Option<u32> = None;
+        let mut depth = 0u32;
+
+        let mut pos = 0;
+        while pos < data.len() {
+            let prefix = data[pos];
+            if prefix == LONG_ITEM {
+                let len = *data.get(pos + 1)? as usize;
+                pos += 3 + len;
+                if pos > data.len() {
+                    return None;
+                }
+                continue;
+            }
+
+            let size = match prefix & 0x3 {
+                3 => 4,
+                n => n as usize,
+            };
+            let bytes = data.get(pos + 1..pos + 1 + size)?;
+            pos += 1 + size;
+
+            let value = bytes.iter().rev().fold(0u32, |v, &b| (v << 8) | b as u32);
+            let bits = size as u32 * 8;
+            let tag = prefix >> 4;
+
+            match (prefix >> 2) & 0x3 {
+                item_type::MAIN => {
+                    match tag {
+                        item_tag::INPUT => {
+                            desc.add_input(&globals, core::mem::take(&mut usages), value)?;
+                        }
+                        item_tag::COLLECTION => {
+                            if depth == 0
+                                && value == APPLICATION_COLLECTION
+                                && let Some(&(usage, _)) = usages.first()
+                            {
+                                desc.applications.push(usage);
+                            }
+                            depth += 1;
+                        }
+                        item_tag::END_COLLECTION => depth = depth.saturating_sub(1),
+                        _ => {}
+                    }
+                    // Local items only apply to the next main item
+                    usages.clear();
+                    usage_min = None;
+                }
+                item_type::GLOBAL => match tag {
+                    item_tag::USAGE_PAGE => globals.usage_page = value as u16,
+                    item_tag::LOGICAL_MIN => globals.logical_min = sign_extend(value, bits),
+                    item_tag::LOGICAL_MAX => globals.logical_max = (value, bits),
+                    item_tag::REPORT_SIZE => globals.report_size = value,
+                    item_tag::REPORT_ID => {
+                        globals.report_id = value as u8;
+                        desc.report_ids = true;
+                    }
+                    item_tag::REPORT_COUNT => globals.report_count = value,
+                    item_tag::PUSH => stack.push(globals),
+                    item_tag::POP => globals = stack.pop()?,
+                    _ => {}
+                },
+                item_type::LOCAL => {
+                    let usage = extended_usage(value, size, globals.usage_page);
+                    match tag {
+                        item_tag::USAGE => usages.push((usage, usage)),
+                        item_tag::USAGE_MIN => usage_min = Some(usage),
+                        item_tag::USAGE_MAX => {
+                            if let Some(min) = usage_min.take()
+                                && min <= usage
+                            {
+                                usages.push((min, usage));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Some(desc)
+    }
+
+    /// Returns the usages of the top-level Application collections.
+    ///
+    /// Extended usages (page << 16 | ID), in descriptor order; e.g.
+    /// 0x0001_0006 for a Generic Desktop Keyboard.
+    pub fn applications(&self) -> &[u32] {
+        &self.applications
+    }
+
+    /// Returns true if every report starts with a report ID byte.
+    pub fn uses_report_ids(&self) -> bool {
+        self.report_ids
+    }
+
+    /// Returns the length in bytes of an input report, without the report ID byte.
+    pub fn input_len(&self, report_id: u8) -> usize {
+        self.input_bits
+            .iter()
+            .find(|(id, _)| *id == report_id)
+            .map_or(0, |(_, bits)| bits.div_ceil(8) as usize)
+    }
+
+    /// Returns the length in bytes of the longest input report, with its report ID byte.
+    pub fn max_input_len(&self) -> usize {
+        let id_len = self.report_ids as usize;
+        self.input_bits
+            .iter()
+            .map(|&(id, _)| self.input_len(id) + id_len)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Splits the data of one transfer into the input reports it holds.
+    ///
+    /// Burst transfers may carry several reports back to back; each is
+    /// cut at the length of its report ID. A report with an unknown ID is
+    /// returned with the rest of the data, as is a trailing partial one.
+    pub fn split_inputs<'a>(&'a self, data: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+        let mut rest = data;
+        core::iter::from_fn(move || {
+            let &first = rest.first()?;
+            let id = if self.report_ids { first } else { 0 };
+            let len = match self.input_len(id) {
+                0 => rest.len(),
+                len => (len + self.report_ids as usize).min(rest.len()),
+            };
+            let (report, tail) = rest.split_at(len);
+            rest = tail;
+            Some(report)
+        })
+    }
+
+    /// Lay out the field of an Input item after the previous ones of its report
+    fn add_input(&mut self, globals: &Globals, usages: Vec<(u32, u32)>, flags: u32) -> Option<()> {
+        let bits = globals.report_size.checked_mul(globals.report_count)?;
+        let idx = match self
+            .input_bits
+            .iter()
+            .position(|(id, _)| *id == globals.report_id)
+        {
+            Some(idx) => idx,
+            None => {
+                self.input_bits.push((globals.report_id, 0));
+                self.input_bits.len() - 1
+            }
+        };
+        let bit_offset = self.input_bits[idx].1;
+        self.input_bits[idx].1 = bit_offset.checked_add(bits)?;
+
+        if flags & field_flags::CONSTANT != 0 || bits == 0 || globals.report_size > 32 {
+            return Some(());
+        }
+
+        let logical_min = globals.logical_min;
+        let (max, max_bits) = globals.logical_max;
+        let logical_max = if logical_min < 0 {
+            sign_extend(max, max_bits)
+        } else {
+            max as i32
+        };
+
+        self.inputs.push(ReportField {
+            report_id: globals.report_id,
+            bit_offset,
+            bit_size: globals.report_size,
+            count: globals.report_count,
+            flags,
+            logical_min,
+            logical_max,
+            usages,
+        });
+        Some(())
+    }
+}
+
+/// Combine a Usage item with the current usage page unless it is 32 bits
+fn extended_usage(value: u32, size: usize, usage_page: u16) -> u32 {
+    if size == 4 {
+        value
+    } else {
+        ((usage_page as u32) << 16) | (value & 0xFFFF)
+    }
+}
+
+/// Sign-extend the low `bits` bits of `value`
+fn sign_extend(value: u32, bits: u32) -> i32 {
+    match bits {
+        0 => 0,
+        32.. => value as i32,
+        _ => {
+            let shift = 32 - bits;
+            ((value << shift) as i32) >> shift
+        }
+    }
+}
+
+/// Read `size` bits starting at bit `start`, least significant bit first
+fn read_bits(data: &[u8], start: u64, size: u32) -> Option<u32> {
+    if start + size as u64 > data.len() as u64 * 8 {
+        return None;
+    }
+
+    let mut value = 0u32;
+    for i in 0..size {
+        let bit = start + i as u64;
+        if (data[(bit / 8) as usize] >> (bit % 8)) & 1 != 0 {
+            value |= 1 << i;
+        }
+    }
+    Some(value)
+}
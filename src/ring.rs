@@ -2,7 +2,7 @@
 
 use crate::{Dma, Result, UsbError};
 
-use core::marker::PhantomData;
+use core::{marker::PhantomData, mem::offset_of};
 
 /// Transfer Request Block (TRB) - 16 bytes aligned.
 ///
@@ -19,6 +19,14 @@ pub struct Trb {
     pub control: u32,
 }
 
+const _: () = {
+    assert!(size_of::<Trb>() == 16);
+    assert!(align_of::<Trb>() == 16);
+    assert!(offset_of!(Trb, param) == 0);
+    assert!(offset_of!(Trb, status) == 8);
+    assert!(offset_of!(Trb, control) == 12);
+};
+
 impl Trb {
     /// Creates a new zeroed TRB.
     pub const fn new() -> Self {
@@ -63,10 +71,31 @@ impl Trb {
         ((self.control >> 16) & 0x1f) as u8
     }
 
-    /// Returns the transfer length.
+    /// Returns the port ID of a Port Status Change event (1-based).
+    pub fn port_id(&self) -> u8 {
+        ((self.param >> 24) & 0xff) as u8
+    }
+
+    /// Returns the Transfer Length field.
+    ///
+    /// In a Transfer Event this is the *residual*: the number of bytes of
+    /// the TRB that were not transferred. Use `transferred` to get the
+    /// number of bytes moved.
     pub fn transfer_length(&self) -> u32 {
         self.status & 0x1ffff
     }
+
+    /// Returns the bytes moved by a transfer of `requested` bytes.
+    ///
+    /// Only meaningful for Transfer Events; computed from the residual in
+    /// `transfer_length`. A Stopped - Length Invalid event carries no
+    /// valid length and counts as 0 bytes.
+    pub fn transferred(&self, requested: usize) -> usize {
+        if self.completion_code() == completion::STOPPED_LENGTH_INVALID {
+            return 0;
+        }
+        requested.saturating_sub(self.transfer_length() as usize)
+    }
 }
 
 /// TRB type codes as defined in the xHCI specification.
@@ -228,6 +257,7 @@ pub mod completion {
     /// Returns a human-readable name for the completion code.
     pub const fn name(code: u8) -> &'static str {
         match code {
+            INVALID => "Invalid",
             SUCCESS => "Success",
             DATA_BUFFER_ERROR => "Data Buffer Error",
             BABBLE_DETECTED => "Babble Detected",
@@ -266,6 +296,42 @@ pub mod completion {
             _ => "Unknown",
         }
     }
+
+    /// Returns true if the transfer completed, possibly short.
+    pub const fn is_success(code: u8) -> bool {
+        matches!(code, SUCCESS | SHORT_PACKET)
+    }
+
+    /// Returns true if the code reports a transfer stopped by Stop Endpoint.
+    pub const fn is_stopped(code: u8) -> bool {
+        matches!(
+            code,
+            STOPPED | STOPPED_LENGTH_INVALID | STOPPED_SHORT_PACKET
+        )
+    }
+
+    /// Returns true if a transfer failing with the code may be retried.
+    ///
+    /// Only transmission errors on the bus qualify; the controller has
+    /// already exhausted its own retries (CErr) when it reports them.
+    pub const fn is_retryable(code: u8) -> bool {
+        matches!(code, USB_TRANSACTION_ERROR | SPLIT_TRANSACTION_ERROR)
+    }
+
+    /// Returns true if the code leaves the endpoint Halted.
+    ///
+    /// The endpoint must be reset with Reset Endpoint before it can be
+    /// used again; a STALL also needs CLEAR_FEATURE(ENDPOINT_HALT).
+    pub const fn is_endpoint_halting(code: u8) -> bool {
+        matches!(
+            code,
+            BABBLE_DETECTED
+                | USB_TRANSACTION_ERROR
+                | TRB_ERROR
+                | STALL_ERROR
+                | SPLIT_TRANSACTION_ERROR
+        )
+    }
 }
 
 /// TRB control field flags.
@@ -288,8 +354,14 @@ pub mod trb_flags {
     pub const TOGGLE_CYCLE: u32 = 1 << 1;
     /// Block Event Interrupt (BEI)
     pub const BEI: u32 = 1 << 9;
+    /// Deconfigure (DC), for the Configure Endpoint command
+    pub const DECONFIGURE: u32 = 1 << 9;
 }
 
+/// Fill byte of IN transfer buffers in debug builds, exposing stale data
+#[cfg(feature = "alloc")]
+const POISON: u8 = 0xA5;
+
 /// Represents a DMA-capable physical memory region.
 pub struct PhysMem<H: Dma> {
     addr: usize,
@@ -301,9 +373,29 @@ pub struct PhysMem<H: Dma> {
 impl<H: Dma> PhysMem<H> {
     /// Allocates a new physical memory region with the specified alignment.
     pub fn alloc(host: &H, size: usize, align: usize) -> Result<Self> {
-        let addr = unsafe {
-            host.alloc(size, align)
-        }.ok_or(UsbError::OoRam)?;
+        Self::alloc_in(host, size, align, false)
+    }
+
+    /// Allocates a new physical memory region below 4 GiB.
+    pub fn alloc_dma32(host: &H, size: usize, align: usize) -> Result<Self> {
+        Self::alloc_in(host, size, align, true)
+    }
+
+    /// Allocates through `alloc_dma32` when `dma32` is set.
+    ///
+    /// A failed allocation is retried once if the host's `low_memory`
+    /// hook freed memory.
+    pub(crate) fn alloc_in(host: &H, size: usize, align: usize, dma32: bool) -> Result<Self> {
+        let alloc = || unsafe {
+            if dma32 {
+                host.alloc_dma32(size, align)
+            } else {
+                host.alloc(size, align)
+            }
+        };
+        let addr = alloc()
+            .or_else(|| host.low_memory(size, align).then(alloc).flatten())
+            .ok_or(UsbError::OoRam)?;
 
         unsafe {
             core::ptr::write_bytes(addr as *mut u8, 0, size);
@@ -341,49 +433,145 @@ impl<H: Dma> PhysMem<H> {
         self.align
     }
 
+    /// Fill the first `len` bytes with a poison pattern before an IN
+    /// transfer (debug builds only)
+    ///
+    /// Bytes the device does not send keep the pattern, so code reading
+    /// past the transferred length sees garbage instead of zeros.
+    #[cfg(feature = "alloc")]
+    pub(crate) fn poison(&self, len: usize) {
+        if cfg!(debug_assertions) {
+            unsafe {
+                core::ptr::write_bytes(self.as_ptr::<u8>(), POISON, len.min(self.size));
+            }
+        }
+    }
+
+    /// Check that an IN transfer of `len` poisoned bytes wrote nothing
+    /// past the `transferred` bytes it reported (debug builds only)
+    #[cfg(feature = "alloc")]
+    pub(crate) fn check_in(&self, transferred: usize, len: usize) {
+        if cfg!(debug_assertions) {
+            let len = len.min(self.size);
+            let bytes = unsafe { core::slice::from_raw_parts(self.as_ptr::<u8>(), len) };
+            let tail = bytes.get(transferred..).unwrap_or_default();
+            debug_assert!(
+                tail.iter().all(|&b| b == POISON),
+                "IN transfer wrote past the {transferred} bytes it reported"
+            );
+        }
+    }
+
+    /// Check that an OUT transfer left the data it sent untouched (debug
+    /// builds only)
+    #[cfg(feature = "alloc")]
+    pub(crate) fn check_out(&self, sent: &[u8]) {
+        if cfg!(debug_assertions) {
+            let len = sent.len().min(self.size);
+            let bytes = unsafe { core::slice::from_raw_parts(self.as_ptr::<u8>(), len) };
+            debug_assert!(
+                bytes == &sent[..len],
+                "OUT transfer buffer changed while in flight"
+            );
+        }
+    }
+
     /// Frees the memory region.
     pub fn free(self, host: &H) {
         unsafe {
             host.free(self.addr, self.size, self.align);
         }
-        core::mem::forget(self);
     }
 }
 
+#[cfg(feature = "alloc")]
 pub(crate) struct Ring<H: Dma> {
     mem: PhysMem<H>,
     enqueue: usize,
     cycle: bool,
     size: usize,
+    /// TRBs handed to the controller and not yet released
+    in_flight: usize,
+    /// Bumped by `release_all`, so older reservations release nothing
+    epoch: u32,
 }
 
+/// TRBs reserved on a `Ring` for one transfer
+#[cfg(feature = "alloc")]
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Reservation {
+    count: usize,
+    epoch: u32,
+}
+
+#[cfg(feature = "alloc")]
 impl<H: Dma> Ring<H> {
-    pub fn new(host: &H, trb_count: usize) -> Result<Self> {
-        let mem = PhysMem::alloc(
+    pub fn new(host: &H, trb_count: usize, dma32: bool) -> Result<Self> {
+        let mem = PhysMem::alloc_in(
             host,
             trb_count * core::mem::size_of::<Trb>(),
             core::mem::align_of::<Trb>(),
+            dma32,
         )?;
         Ok(Self {
             mem,
             enqueue: 0,
             cycle: true,
             size: trb_count,
+            in_flight: 0,
+            epoch: 0,
+        })
+    }
+
+    /// Reserve `count` TRBs for a transfer
+    ///
+    /// Fails with `RingFull` if enqueuing them could overwrite TRBs the
+    /// controller has not completed yet.
+    pub fn reserve(&mut self, count: usize) -> Result<Reservation> {
+        // One slot holds the Link TRB
+        if self.in_flight + count > self.size.saturating_sub(1) {
+            return Err(UsbError::RingFull);
+        }
+        self.in_flight += count;
+        Ok(Reservation {
+            count,
+            epoch: self.epoch,
         })
     }
 
+    /// Release TRBs of a completed transfer
+    ///
+    /// Does nothing if `release_all` ran since they were reserved.
+    pub fn release(&mut self, reservation: Reservation) {
+        if reservation.epoch == self.epoch {
+            self.in_flight = self.in_flight.saturating_sub(reservation.count);
+        }
+    }
+
+    /// Release every TRB, after the dequeue pointer was moved past them
+    pub fn release_all(&mut self) {
+        self.in_flight = 0;
+        self.epoch = self.epoch.wrapping_add(1);
+    }
+
     pub fn phys(&self, host: &H) -> u64 {
         self.mem.phys(host)
     }
 
-    fn trbs(&self) -> &mut [Trb] {
+    /// Enqueue pointer with the producer cycle state in bit 0
+    pub fn enqueue_ptr(&self, host: &H) -> u64 {
+        (self.mem.phys(host) + (self.enqueue * 16) as u64) | self.cycle as u64
+    }
+
+    fn trbs(&mut self) -> &mut [Trb] {
         unsafe { core::slice::from_raw_parts_mut(self.mem.as_ptr(), self.size) }
     }
 
     pub fn enqueue(&mut self, host: &H, mut trb: Trb) -> u64 {
         trb.set_cycle(self.cycle);
         let addr = self.mem.phys(host) + (self.enqueue * 16) as u64;
-        self.trbs()[self.enqueue] = trb;
+        let idx = self.enqueue;
+        self.trbs()[idx] = trb;
         self.enqueue += 1;
 
         if self.enqueue >= self.size - 1 {
@@ -391,7 +579,8 @@ impl<H: Dma> Ring<H> {
             link.param = self.mem.phys(host);
             link.control = (trb_type::LINK << 10) | 2;
             link.set_cycle(self.cycle);
-            self.trbs()[self.enqueue] = link;
+            let idx = self.enqueue;
+            self.trbs()[idx] = link;
             self.enqueue = 0;
             self.cycle = !self.cycle;
         }
@@ -404,7 +593,12 @@ impl<H: Dma> Ring<H> {
     }
 }
 
-#[repr(C, align(64))]
+/// Event Ring Segment Table entry
+///
+/// Entries are packed 16 bytes apart; the table itself must be 64-byte
+/// aligned (`ERST_ALIGN`).
+#[cfg(feature = "alloc")]
+#[repr(C)]
 #[derive(Clone, Copy, Default)]
 pub(crate) struct ErstEntry {
     pub base: u64,
@@ -412,6 +606,19 @@ pub(crate) struct ErstEntry {
     _0: [u8; 6],
 }
 
+#[cfg(feature = "alloc")]
+const _: () = {
+    assert!(size_of::<ErstEntry>() == 16);
+    assert!(align_of::<ErstEntry>() == 8);
+    assert!(offset_of!(ErstEntry, base) == 0);
+    assert!(offset_of!(ErstEntry, size) == 8);
+};
+
+/// Alignment of the Event Ring Segment Table
+#[cfg(feature = "alloc")]
+const ERST_ALIGN: usize = 64;
+
+#[cfg(feature = "alloc")]
 pub(crate) struct EventRing<H: Dma> {
     ring: PhysMem<H>,
     erst: PhysMem<H>,
@@ -420,14 +627,22 @@ pub(crate) struct EventRing<H: Dma> {
     cycle: bool,
 }
 
+#[cfg(feature = "alloc")]
 impl<H: Dma> EventRing<H> {
-    pub fn new(host: &H, trb_count: usize) -> Result<Self> {
-        let ring = PhysMem::alloc(
+    pub fn new(host: &H, trb_count: usize, dma32: bool) -> Result<Self> {
+        let ring = PhysMem::alloc_in(
             host,
             trb_count * core::mem::size_of::<Trb>(),
             core::mem::align_of::<Trb>(),
+            dma32,
         )?;
-        let erst = PhysMem::alloc(host, host.page_size(), core::mem::align_of::<ErstEntry>())?;
+        let erst = match PhysMem::alloc_in(host, host.page_size(), ERST_ALIGN, dma32) {
+            Ok(erst) => erst,
+            Err(e) => {
+                ring.free(host);
+                return Err(e);
+            }
+        };
 
         let entry = erst.as_ptr::<ErstEntry>();
         unsafe {
@@ -474,4 +689,60 @@ impl<H: Dma> EventRing<H> {
     pub fn dequeue_ptr(&self, host: &H) -> u64 {
         self.ring.phys(host) + (self.dequeue * 16) as u64
     }
+
+    /// Virtual address of the first TRB
+    pub fn virt(&self) -> usize {
+        self.ring.virt()
+    }
+
+    /// Dequeue index and consumer cycle state, packed as `index << 1 | cycle`
+    pub fn cursor(&self) -> u32 {
+        ((self.dequeue as u32) << 1) | self.cycle as u32
+    }
+
+    pub fn free(self, host: &H) {
+        self.ring.free(host);
+        self.erst.free(host);
+    }
+}
+
+#[cfg(all(test, feature = "alloc"))]
+mod tests {
+    use super::*;
+    use crate::mock::{Mock, MockHost};
+
+    #[test]
+    fn stale_release_keeps_newer_reservations() {
+        let host = MockHost::new(Mock::new());
+        let mut ring = Ring::new(&host, 8, false).unwrap();
+        let stale = ring.reserve(3).unwrap();
+        ring.release_all();
+        let live = ring.reserve(3).unwrap();
+
+        // Releasing the first transfer again must not free the second's TRBs
+        ring.release(stale);
+        assert!(matches!(ring.reserve(5), Err(UsbError::RingFull)));
+        ring.release(live);
+        assert!(ring.reserve(7).is_ok());
+    }
+
+    #[test]
+    fn transferred_from_residual() {
+        let event = |code: u8, residual: u32| Trb {
+            param: 0,
+            status: (code as u32) << 24 | residual,
+            control: trb_type::TRANSFER_EVENT << 10,
+        };
+        // Full, short and zero-length completions
+        assert_eq!(event(completion::SUCCESS, 0).transferred(512), 512);
+        assert_eq!(event(completion::SHORT_PACKET, 500).transferred(512), 12);
+        assert_eq!(event(completion::SHORT_PACKET, 512).transferred(512), 0);
+        assert_eq!(event(completion::SUCCESS, 0).transferred(0), 0);
+        // A residual beyond the request saturates
+        assert_eq!(event(completion::SHORT_PACKET, 600).transferred(512), 0);
+        // Stopped transfers count their residual, unless it is invalid
+        assert_eq!(event(completion::STOPPED, 256).transferred(512), 256);
+        let invalid = event(completion::STOPPED_LENGTH_INVALID, 0);
+        assert_eq!(invalid.transferred(512), 0);
+    }
 }
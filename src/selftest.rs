@@ -0,0 +1,533 @@
+//! Hardware-in-the-loop self-test.
+//!
+//! Runs a fixed checklist against a controller and the devices plugged
+//! into it, typically a known-good USB stick and keyboard during board
+//! bring-up, and collects a report that can be printed over a serial
+//! console.
+
+use crate::{
+    Result, UsbError,
+    dev::{UsbDevice, default_config_policy},
+    hid::{HidDevice, HidProtocol, HidType, find_hid_interfaces},
+    msc::{MscDevice, find_msc_interfaces},
+    ram::Dma,
+    ring::{Trb, completion, trb_type},
+    xhci::XhciCtrl,
+};
+
+use alloc::{format, string::String, sync::Arc, vec, vec::Vec};
+use core::{fmt, hint::spin_loop};
+
+/// Bytes written and verified by the mass storage test
+pub const MSC_TEST_BYTES: usize = 1 << 20;
+/// Bytes moved per READ (10) or WRITE (10) command
+const MSC_CHUNK_BYTES: usize = 64 * 1024;
+
+/// Blocks of a mass storage device the self-test may overwrite.
+///
+/// The original contents are read first and written back afterwards.
+#[derive(Clone, Copy, Debug)]
+pub struct ScratchArea {
+    /// Logical unit
+    pub lun: u8,
+    /// First block; LBA 0 is refused since it holds the partition table
+    pub start_lba: u32,
+    /// Number of blocks, covering at least `MSC_TEST_BYTES`
+    pub blocks: u32,
+}
+
+/// Parameters of `run_controller_tests`.
+#[derive(Clone, Copy, Debug)]
+pub struct SelfTestConfig {
+    /// No-Op commands to time; 0 skips the test
+    pub noop_iterations: u32,
+    /// Blocks the mass storage test may overwrite on every mass storage
+    /// device found; the test is skipped if `None`
+    pub scratch: Option<ScratchArea>,
+    /// Time to wait for a HID report, in microseconds; 0 skips the test
+    pub hid_timeout_us: u32,
+}
+
+impl Default for SelfTestConfig {
+    fn default() -> Self {
+        Self {
+            noop_iterations: 100,
+            scratch: None,
+            hid_timeout_us: 10_000_000,
+        }
+    }
+}
+
+/// Outcome of a single self-test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTestOutcome {
+    /// The test passed
+    Passed,
+    /// The test failed
+    Failed,
+    /// The test could not run
+    Skipped,
+}
+
+impl fmt::Display for SelfTestOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Passed => "PASS",
+            Self::Failed => "FAIL",
+            Self::Skipped => "SKIP",
+        })
+    }
+}
+
+/// Result of a single self-test.
+#[derive(Clone, Debug)]
+pub struct SelfTestResult {
+    /// Test name
+    pub name: &'static str,
+    /// Root port (0-based) of the device tested, `None` for controller tests
+    pub port: Option<u8>,
+    /// Outcome
+    pub outcome: SelfTestOutcome,
+    /// Time taken, in microseconds (125 us resolution)
+    pub duration_us: u64,
+    /// Error that made the test fail
+    pub error: Option<UsbError>,
+    /// Measurements, or the reason for a failure or skip
+    pub detail: String,
+}
+
+impl SelfTestResult {
+    fn passed(name: &'static str, port: Option<u8>, duration_us: u64, detail: String) -> Self {
+        Self {
+            name,
+            port,
+            outcome: SelfTestOutcome::Passed,
+            duration_us,
+            error: None,
+            detail,
+        }
+    }
+
+    fn failed(
+        name: &'static str,
+        port: Option<u8>,
+        duration_us: u64,
+        error: Option<UsbError>,
+        detail: String,
+    ) -> Self {
+        Self {
+            name,
+            port,
+            outcome: SelfTestOutcome::Failed,
+            duration_us,
+            error,
+            detail,
+        }
+    }
+
+    fn skipped(name: &'static str, port: Option<u8>, detail: &str) -> Self {
+        Self {
+            name,
+            port,
+            outcome: SelfTestOutcome::Skipped,
+            duration_us: 0,
+            error: None,
+            detail: String::from(detail),
+        }
+    }
+
+    /// Passed with `detail`, or failed with the error
+    fn from_result(
+        name: &'static str,
+        port: Option<u8>,
+        duration_us: u64,
+        result: Result<String>,
+    ) -> Self {
+        match result {
+            Ok(detail) => Self::passed(name, port, duration_us, detail),
+            Err(e) => Self::failed(name, port, duration_us, Some(e), String::new()),
+        }
+    }
+}
+
+impl fmt::Display for SelfTestResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {:<10}", self.outcome, self.name)?;
+        match self.port {
+            Some(port) => write!(f, " port {:<3}", port + 1)?,
+            None => write!(f, "          ")?,
+        }
+        write!(f, " {:>9} us", self.duration_us)?;
+        if let Some(e) = self.error {
+            write!(f, "  {:?}", e)?;
+        }
+        if !self.detail.is_empty() {
+            write!(f, "  {}", self.detail)?;
+        }
+        Ok(())
+    }
+}
+
+/// Results of `run_controller_tests`, in the order the tests ran.
+#[derive(Clone, Debug, Default)]
+pub struct SelfTestReport {
+    /// One entry per test
+    pub results: Vec<SelfTestResult>,
+}
+
+impl SelfTestReport {
+    /// Returns true if no test failed.
+    pub fn passed(&self) -> bool {
+        self.count(SelfTestOutcome::Failed) == 0
+    }
+
+    /// Returns the number of tests with the given outcome.
+    pub fn count(&self, outcome: SelfTestOutcome) -> usize {
+        self.results.iter().filter(|r| r.outcome == outcome).count()
+    }
+}
+
+impl fmt::Display for SelfTestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for result in &self.results {
+            writeln!(f, "{}", result)?;
+        }
+        write!(
+            f,
+            "{} passed, {} failed, {} skipped",
+            self.count(SelfTestOutcome::Passed),
+            self.count(SelfTestOutcome::Failed),
+            self.count(SelfTestOutcome::Skipped),
+        )
+    }
+}
+
+/// Runs the self-test checklist on a controller.
+///
+/// In order: No-Op command latency, then for every connected root port a
+/// port reset, enumeration with `default_config_policy`, and the class
+/// tests of the selected configuration. Mass storage devices get a
+/// write/verify cycle of `MSC_TEST_BYTES` over the scratch area; boot
+/// keyboards and mice must send a report within the timeout, so press a
+/// key or move the mouse while the test waits.
+///
+/// Ports are reset and devices re-enumerated, so run this on a freshly
+/// initialized controller with no devices in use. Devices are released
+/// again before returning.
+pub fn run_controller_tests<H: Dma>(
+    ctrl: &Arc<XhciCtrl<H>>,
+    config: &SelfTestConfig,
+) -> SelfTestReport {
+    let mut report = SelfTestReport::default();
+    report.results.push(noop_test(ctrl, config.noop_iterations));
+
+    for port in 0..ctrl.max_ports() {
+        if !ctrl.port_connected(port) {
+            continue;
+        }
+
+        let mut watch = ctrl.stopwatch();
+        let reset = ctrl.reset_port(port).map(|()| String::new());
+        let duration_us = watch.elapsed_us(ctrl);
+        let reset_ok = reset.is_ok();
+        report.results.push(SelfTestResult::from_result(
+            "port_reset",
+            Some(port),
+            duration_us,
+            reset,
+        ));
+        if !reset_ok {
+            continue;
+        }
+
+        let mut watch = ctrl.stopwatch();
+        let device = enumerate(ctrl, port);
+        let duration_us = watch.elapsed_us(ctrl);
+        let device = match device {
+            Ok(device) => {
+                let detail = format!("{}", device.summary());
+                report.results.push(SelfTestResult::passed(
+                    "enumerate",
+                    Some(port),
+                    duration_us,
+                    detail,
+                ));
+                Arc::new(device)
+            }
+            Err(e) => {
+                report.results.push(SelfTestResult::failed(
+                    "enumerate",
+                    Some(port),
+                    duration_us,
+                    Some(e),
+                    String::new(),
+                ));
+                continue;
+            }
+        };
+
+        class_tests(ctrl, &device, config, &mut report);
+    }
+
+    report
+}
+
+/// Time `iterations` No-Op commands
+fn noop_test<H: Dma>(ctrl: &XhciCtrl<H>, iterations: u32) -> SelfTestResult {
+    if iterations == 0 {
+        return SelfTestResult::skipped("noop", None, "no iterations");
+    }
+
+    let trb = Trb {
+        param: 0,
+        status: 0,
+        control: trb_type::NO_OP_CMD << 10,
+    };
+    let mut watch = ctrl.stopwatch();
+    let mut last = 0;
+    let mut max = 0;
+    for done in 0..iterations {
+        if let Err(e) = ctrl.submit_command(trb) {
+            let detail = format!("after {} commands", done);
+            return SelfTestResult::failed("noop", None, last, Some(e), detail);
+        }
+        let now = watch.elapsed_us(ctrl);
+        max = max.max(now - last);
+        last = now;
+    }
+
+    let detail = format!(
+        "{} commands, avg {} us, max {} us",
+        iterations,
+        last / iterations as u64,
+        max
+    );
+    SelfTestResult::passed("noop", None, last, detail)
+}
+
+/// Address a freshly reset device and select its configuration
+fn enumerate<H: Dma>(ctrl: &Arc<XhciCtrl<H>>, port: u8) -> Result<UsbDevice<H>> {
+    let mut device = UsbDevice::enable_and_address(ctrl.clone(), port, ctrl.ep0_ring_size())?;
+    device.get_device_descriptor()?;
+    device.choose_configuration(default_config_policy)?;
+    Ok(device)
+}
+
+/// Run the tests of the class drivers the active configuration binds to
+fn class_tests<H: Dma>(
+    ctrl: &XhciCtrl<H>,
+    device: &Arc<UsbDevice<H>>,
+    config: &SelfTestConfig,
+    report: &mut SelfTestReport,
+) {
+    let port = Some(device.port());
+    let tree = match device.config_tree() {
+        Ok(tree) => tree,
+        Err(e) => {
+            let result = SelfTestResult::failed("config", port, 0, Some(e), String::new());
+            report.results.push(result);
+            return;
+        }
+    };
+
+    if let Some((iface, ep_in, ep_out)) = find_msc_interfaces(tree.raw()).first() {
+        let result = match config.scratch {
+            None => SelfTestResult::skipped("msc_rw", port, "no scratch area"),
+            Some(area) => match MscDevice::from_interface(device.clone(), iface, ep_in, ep_out) {
+                Ok(mut msc) => msc_test(ctrl, &mut msc, &area),
+                Err(e) => SelfTestResult::failed("msc_rw", port, 0, Some(e), String::new()),
+            },
+        };
+        report.results.push(result);
+    }
+
+    if let Some((iface, ep_in)) = find_hid_interfaces(tree.raw()).first() {
+        let result = if config.hid_timeout_us == 0 {
+            SelfTestResult::skipped("hid_report", port, "no timeout")
+        } else {
+            match HidDevice::from_interface(device.clone(), iface, ep_in) {
+                Ok(hid) => hid_test(ctrl, &hid, config.hid_timeout_us),
+                Err(e) => SelfTestResult::failed("hid_report", port, 0, Some(e), String::new()),
+            }
+        };
+        report.results.push(result);
+    }
+}
+
+/// Write, read back and verify the scratch area, then restore it
+fn msc_test<H: Dma>(
+    ctrl: &XhciCtrl<H>,
+    msc: &mut MscDevice<H>,
+    area: &ScratchArea,
+) -> SelfTestResult {
+    const NAME: &str = "msc_rw";
+    let port = Some(msc.device().port());
+
+    // The first commands after enumeration often report a unit attention
+    for _ in 0..3 {
+        if let Ok(true) = msc.test_unit_ready(area.lun) {
+            break;
+        }
+    }
+    let capacity = match msc.read_capacity(area.lun) {
+        Ok(capacity) => capacity,
+        Err(e) => return SelfTestResult::failed(NAME, port, 0, Some(e), String::new()),
+    };
+
+    let block_size = capacity.block_size() as usize;
+    if block_size == 0 || !MSC_CHUNK_BYTES.is_multiple_of(block_size) {
+        let detail = format!("unsupported block size {}", block_size);
+        return SelfTestResult::skipped(NAME, port, &detail);
+    }
+    let blocks = (MSC_TEST_BYTES / block_size) as u32;
+    let end = area.start_lba as u64 + blocks as u64;
+    let unsafe_area = if area.start_lba == 0 {
+        Some("scratch area covers LBA 0")
+    } else if area.blocks < blocks {
+        Some("scratch area smaller than the test")
+    } else if end > capacity.last_lba() as u64 + 1 {
+        Some("scratch area past the end of the medium")
+    } else {
+        None
+    };
+    if let Some(reason) = unsafe_area {
+        let error = Some(UsbError::InvalidArgument);
+        return SelfTestResult::failed(NAME, port, 0, error, String::from(reason));
+    }
+
+    let mut watch = ctrl.stopwatch();
+    let run = msc_cycle(ctrl, msc, area, block_size);
+    let duration_us = watch.elapsed_us(ctrl);
+    match run {
+        Ok(run) if run.mismatch.is_none() => {
+            let detail = format!(
+                "{} KiB at LBA {}: write {} us ({} KiB/s), read {} us ({} KiB/s)",
+                MSC_TEST_BYTES / 1024,
+                area.start_lba,
+                run.write_us,
+                kib_per_s(run.write_us),
+                run.read_us,
+                kib_per_s(run.read_us),
+            );
+            SelfTestResult::passed(NAME, port, duration_us, detail)
+        }
+        Ok(run) => {
+            let offset = run.mismatch.unwrap_or_default();
+            let detail = format!(
+                "read back differs at LBA {} byte {}",
+                area.start_lba as usize + offset / block_size,
+                offset % block_size
+            );
+            SelfTestResult::failed(NAME, port, duration_us, None, detail)
+        }
+        Err(e) => SelfTestResult::failed(NAME, port, duration_us, Some(e), String::new()),
+    }
+}
+
+/// Timings of the mass storage write/verify cycle
+struct MscRun {
+    write_us: u64,
+    read_us: u64,
+    /// Offset of the first byte read back wrong
+    mismatch: Option<usize>,
+}
+
+/// Save the scratch area, write a pattern, read it back and restore the area
+fn msc_cycle<H: Dma>(
+    ctrl: &XhciCtrl<H>,
+    msc: &mut MscDevice<H>,
+    area: &ScratchArea,
+    block_size: usize,
+) -> Result<MscRun> {
+    let mut saved = vec![0u8; MSC_TEST_BYTES];
+    move_blocks(ctrl, msc, area, block_size, &mut saved, false)?;
+
+    let mut pattern: Vec<u8> = (0..MSC_TEST_BYTES as u32)
+        .map(|i| (i ^ (i >> 8) ^ (i >> 16)) as u8 ^ 0xA5)
+        .collect();
+    let expected = pattern.clone();
+    let cycle = move_blocks(ctrl, msc, area, block_size, &mut pattern, true).and_then(|write_us| {
+        pattern.fill(0);
+        let read_us = move_blocks(ctrl, msc, area, block_size, &mut pattern, false)?;
+        let mismatch = pattern.iter().zip(&expected).position(|(a, b)| a != b);
+        Ok(MscRun {
+            write_us,
+            read_us,
+            mismatch,
+        })
+    });
+
+    // Put the original contents back whatever happened
+    let restore = move_blocks(ctrl, msc, area, block_size, &mut saved, true)
+        .and_then(|_| msc.sync_cache(area.lun));
+    let run = cycle?;
+    restore?;
+    Ok(run)
+}
+
+/// Read or write `buf` at the start of the scratch area; returns the time taken
+fn move_blocks<H: Dma>(
+    ctrl: &XhciCtrl<H>,
+    msc: &mut MscDevice<H>,
+    area: &ScratchArea,
+    block_size: usize,
+    buf: &mut [u8],
+    write: bool,
+) -> Result<u64> {
+    let mut watch = ctrl.stopwatch();
+    let mut elapsed_us = 0;
+    for (i, chunk) in buf.chunks_mut(MSC_CHUNK_BYTES).enumerate() {
+        let lba = area.start_lba + (i * MSC_CHUNK_BYTES / block_size) as u32;
+        let count = (chunk.len() / block_size) as u16;
+        let len = if write {
+            msc.write_blocks(area.lun, lba, count, chunk)?
+        } else {
+            msc.read_blocks(area.lun, lba, count, chunk)?
+        };
+        if len < chunk.len() {
+            return Err(UsbError::XferFail(completion::SHORT_PACKET));
+        }
+        // Sampled per chunk so MFINDEX never wraps unseen
+        elapsed_us = watch.elapsed_us(ctrl);
+    }
+    Ok(elapsed_us)
+}
+
+/// Throughput of `MSC_TEST_BYTES` moved in `us` microseconds
+fn kib_per_s(us: u64) -> u64 {
+    (MSC_TEST_BYTES as u64 / 1024 * 1_000_000)
+        .checked_div(us)
+        .unwrap_or(0)
+}
+
+/// Wait for a report from a keyboard or mouse in boot protocol
+fn hid_test<H: Dma>(ctrl: &XhciCtrl<H>, hid: &HidDevice<H>, timeout_us: u32) -> SelfTestResult {
+    const NAME: &str = "hid_report";
+    let port = Some(hid.device().port());
+    if !matches!(hid.hid_type(), HidType::Keyboard | HidType::Mouse)
+        || hid.negotiated_protocol() != HidProtocol::Boot
+    {
+        return SelfTestResult::skipped(NAME, port, "not a boot keyboard or mouse");
+    }
+    if let Err(e) = hid.queue_read() {
+        return SelfTestResult::failed(NAME, port, 0, Some(e), String::new());
+    }
+
+    let mut watch = ctrl.stopwatch();
+    loop {
+        let received = match hid.hid_type() {
+            HidType::Keyboard => hid.poll_keyboard().is_some(),
+            _ => hid.poll_mouse().is_some(),
+        };
+        let elapsed_us = watch.elapsed_us(ctrl);
+        if received {
+            let detail = format!("{:?} report received", hid.hid_type());
+            return SelfTestResult::passed(NAME, port, elapsed_us, detail);
+        }
+        if elapsed_us >= timeout_us as u64 {
+            let detail = String::from("no report; press a key or move the mouse");
+            return SelfTestResult::failed(NAME, port, elapsed_us, Some(UsbError::Timeout), detail);
+        }
+        spin_loop();
+    }
+}
@@ -0,0 +1,85 @@
+//! Locks guarding the driver's shared state.
+//!
+//! `Lock` is a `spin::Mutex` by default. With the `single-threaded`
+//! feature it becomes an unsynchronized cell for hosts that never touch
+//! the driver from more than one context: locking costs a flag check,
+//! and every type holding a `Lock` is `!Sync`.
+//!
+//! # Re-entrancy
+//!
+//! `Dma` methods may run with a lock held and must not call back into the
+//! driver. Restore and configuration hooks run with their hook list
+//! locked, so they must not register further hooks. With the default
+//! spin locks, re-entering the driver while it holds a lock, e.g. from an
+//! interrupt or panic handler, deadlocks; under `single-threaded` the
+//! nested lock panics instead.
+
+#[cfg(not(feature = "single-threaded"))]
+pub(crate) type Lock<T> = spin::Mutex<T>;
+
+#[cfg(feature = "single-threaded")]
+pub(crate) use self::cell::Lock;
+
+#[cfg(feature = "single-threaded")]
+mod cell {
+    use core::{
+        cell::{Cell, UnsafeCell},
+        ops::{Deref, DerefMut},
+    };
+
+    /// Unsynchronized lock; `!Sync` through its cells
+    pub(crate) struct Lock<T> {
+        locked: Cell<bool>,
+        value: UnsafeCell<T>,
+    }
+
+    impl<T> Lock<T> {
+        pub const fn new(value: T) -> Self {
+            Self {
+                locked: Cell::new(false),
+                value: UnsafeCell::new(value),
+            }
+        }
+
+        /// Lock the cell; panics if it is already locked
+        pub fn lock(&self) -> LockGuard<'_, T> {
+            assert!(!self.locked.replace(true), "driver lock re-entered");
+            LockGuard { lock: self }
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.value.get_mut()
+        }
+    }
+
+    impl<T: Default> Default for Lock<T> {
+        fn default() -> Self {
+            Self::new(T::default())
+        }
+    }
+
+    pub(crate) struct LockGuard<'a, T> {
+        lock: &'a Lock<T>,
+    }
+
+    impl<T> Deref for LockGuard<'_, T> {
+        type Target = T;
+
+        fn deref(&self) -> &T {
+            // The guard is the only access while `locked` is set
+            unsafe { &*self.lock.value.get() }
+        }
+    }
+
+    impl<T> DerefMut for LockGuard<'_, T> {
+        fn deref_mut(&mut self) -> &mut T {
+            unsafe { &mut *self.lock.value.get() }
+        }
+    }
+
+    impl<T> Drop for LockGuard<'_, T> {
+        fn drop(&mut self) {
+            self.lock.locked.set(false);
+        }
+    }
+}
@@ -0,0 +1,174 @@
+//! Snapshot of the device tree of a controller.
+//!
+//! `XhciCtrl::topology` copies what the controller knows about its devices
+//! into a tree of `TopologyNode`s: root ports, the devices on them and,
+//! below hubs, the hub ports and their devices. The tree owns its data,
+//! so it stays consistent while hotplug handling adds and removes devices.
+
+use crate::dev::{DevicePath, DeviceSummary, DriverKind};
+use alloc::vec::Vec;
+use core::fmt;
+
+/// A device in a `TopologyNode`, as it was when the snapshot was taken.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TopologyDevice {
+    /// Topological path of the device
+    pub path: DevicePath,
+    /// xHCI slot ID, 0 while the device is deactivated
+    pub slot_id: u8,
+    /// Identification of the device
+    ///
+    /// The string fields stay empty until `UsbDevice::summary` was built,
+    /// and the IDs are 0 until the device descriptor was read.
+    pub summary: DeviceSummary,
+    /// Class drivers of this crate bound to the device, empty if none
+    pub drivers: Vec<DriverKind>,
+}
+
+/// One port of the device tree returned by `XhciCtrl::topology`.
+///
+/// The root node stands for the controller, with port 0 and no device;
+/// its children are the root ports. Only ports with a device, or with
+/// devices further down, are included. A hub that is no longer tracked
+/// while devices below it still are leaves its port without a device.
+///
+/// `Display` renders an indented tree like `lsusb -t`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TopologyNode {
+    /// Controller tag set with `XhciCtrl::set_bus_index`
+    pub bus: u8,
+    /// Port number (1-based) on the parent, 0 for the root
+    pub port: u8,
+    /// Device on the port
+    pub device: Option<TopologyDevice>,
+    /// Ports below this one, by ascending port number
+    pub children: Vec<TopologyNode>,
+}
+
+impl TopologyNode {
+    /// Build the tree of `bus` from its devices
+    pub(crate) fn build(bus: u8, devices: Vec<TopologyDevice>) -> Self {
+        let mut root = Self {
+            bus,
+            ..Default::default()
+        };
+        for device in devices {
+            let path = device.path;
+            let node = core::iter::once(path.root_port + 1)
+                .chain(path.hub_ports())
+                .fold(&mut root, |node, port| node.child_mut(port));
+            node.device = Some(device);
+        }
+        root
+    }
+
+    /// Returns the child on `port`, inserting an empty one in port order
+    fn child_mut(&mut self, port: u8) -> &mut Self {
+        let index = match self
+            .children
+            .binary_search_by_key(&port, |child| child.port)
+        {
+            Ok(index) => index,
+            Err(index) => {
+                let child = Self {
+                    bus: self.bus,
+                    port,
+                    ..Default::default()
+                };
+                self.children.insert(index, child);
+                index
+            }
+        };
+        &mut self.children[index]
+    }
+
+    /// Returns every device of the tree, parents before their children.
+    pub fn devices(&self) -> impl Iterator<Item = &TopologyDevice> {
+        let mut stack = Vec::from([self]);
+        core::iter::from_fn(move || {
+            while let Some(node) = stack.pop() {
+                stack.extend(node.children.iter().rev());
+                if node.device.is_some() {
+                    return node.device.as_ref();
+                }
+            }
+            None
+        })
+    }
+
+    /// Returns the device with slot ID `slot_id`.
+    pub fn find_slot(&self, slot_id: u8) -> Option<&TopologyDevice> {
+        self.devices()
+            .find(|device| slot_id != 0 && device.slot_id == slot_id)
+    }
+
+    fn fmt_level(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        write!(
+            f,
+            "{:width$}|__ Port {}",
+            "",
+            self.port,
+            width = (depth - 1) * 4
+        )?;
+        match &self.device {
+            Some(device) => write_device(f, device)?,
+            None => f.write_str(": no device")?,
+        }
+        writeln!(f)?;
+        for child in &self.children {
+            child.fmt_level(f, depth + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Name of a class driver, as `lsusb -t` shows it
+fn driver_name(kind: DriverKind) -> &'static str {
+    match kind {
+        DriverKind::Hid => "usbhid",
+        DriverKind::MassStorage => "usb-storage",
+        DriverKind::Hub => "hub",
+    }
+}
+
+fn write_device(f: &mut fmt::Formatter<'_>, device: &TopologyDevice) -> fmt::Result {
+    let summary = &device.summary;
+    write!(
+        f,
+        ": Slot {}, ID {:04x}:{:04x}, Class {:02x}/{:02x}/{:02x}",
+        device.slot_id,
+        summary.vendor_id,
+        summary.product_id,
+        summary.class.0,
+        summary.class.1,
+        summary.class.2,
+    )?;
+    if !summary.product.is_empty() {
+        write!(f, ", {}", summary.product)?;
+    }
+    f.write_str(", Driver=")?;
+    if device.drivers.is_empty() {
+        f.write_str("none")?;
+    }
+    for (i, &kind) in device.drivers.iter().enumerate() {
+        if i > 0 {
+            f.write_str(",")?;
+        }
+        f.write_str(driver_name(kind))?;
+    }
+    write!(f, ", {}", summary.speed_name())
+}
+
+impl fmt::Display for TopologyNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.port != 0 {
+            return self.fmt_level(f, 1);
+        }
+
+        writeln!(f, "Bus {:02}", self.bus)?;
+        for child in &self.children {
+            child.fmt_level(f, 1)?;
+        }
+        Ok(())
+    }
+}
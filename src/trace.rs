@@ -0,0 +1,137 @@
+//! Post-mortem trace of what the driver told the controller.
+//!
+//! A fixed ring of the last `TRACE_ENTRIES` commands, transfer TRBs,
+//! doorbells, events and register writes, read back with
+//! `XhciCtrl::trace_snapshot`. Recording takes no lock: writers claim a
+//! slot with a single atomic cursor and publish it with a sequence number,
+//! so readers skip slots that are being overwritten.
+
+use core::sync::atomic::{AtomicU64, Ordering, fence};
+
+/// Number of entries kept by the trace buffer
+pub const TRACE_ENTRIES: usize = 256;
+
+/// What a trace entry records.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TraceKind {
+    /// Command TRB placed on the command ring
+    #[default]
+    Command,
+    /// Transfer TRB placed on an endpoint ring
+    Transfer,
+    /// Doorbell write; `param` is the value written
+    Doorbell,
+    /// Event TRB taken from the event ring
+    Event,
+    /// Operational or runtime register write; `param` is the value
+    /// written and `status` the offset from the start of the register file
+    Register,
+}
+
+impl TraceKind {
+    fn from_raw(raw: u8) -> Self {
+        match raw {
+            1 => Self::Transfer,
+            2 => Self::Doorbell,
+            3 => Self::Event,
+            4 => Self::Register,
+            _ => Self::Command,
+        }
+    }
+}
+
+/// One recorded trace entry, as returned by `XhciCtrl::trace_snapshot`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TraceEntry {
+    /// Sequence number, counting every entry recorded since start-up
+    pub seq: u64,
+    /// Microseconds from `Dma::now_us`, or from the controller's frame
+    /// counter if the host has no clock
+    pub timestamp_us: u64,
+    /// What the entry records
+    pub kind: TraceKind,
+    /// Slot ID, 0 for the command ring and controller-wide entries
+    pub slot: u8,
+    /// Device Context Index of transfer TRBs, doorbells and transfer events
+    pub dci: u8,
+    /// TRB parameter, or the value written
+    pub param: u64,
+    /// TRB status, or the register offset
+    pub status: u32,
+    /// TRB control
+    pub control: u32,
+}
+
+/// Trace slot; `seq` is 0 while the slot is written
+#[derive(Default)]
+struct TraceSlot {
+    seq: AtomicU64,
+    words: [AtomicU64; 4],
+}
+
+pub(crate) struct TraceBuffer {
+    cursor: AtomicU64,
+    slots: [TraceSlot; TRACE_ENTRIES],
+}
+
+impl TraceBuffer {
+    pub fn new() -> Self {
+        Self {
+            cursor: AtomicU64::new(0),
+            slots: core::array::from_fn(|_| TraceSlot::default()),
+        }
+    }
+
+    /// Record an entry; `entry.seq` is assigned here
+    pub fn record(&self, entry: TraceEntry) {
+        let seq = self.cursor.fetch_add(1, Ordering::Relaxed) + 1;
+        let slot = &self.slots[(seq - 1) as usize % TRACE_ENTRIES];
+        let words = [
+            entry.timestamp_us,
+            entry.param,
+            entry.status as u64 | (entry.control as u64) << 32,
+            entry.kind as u64 | (entry.slot as u64) << 8 | (entry.dci as u64) << 16,
+        ];
+
+        slot.seq.store(0, Ordering::Relaxed);
+        fence(Ordering::Release);
+        for (word, value) in slot.words.iter().zip(words) {
+            word.store(value, Ordering::Relaxed);
+        }
+        slot.seq.store(seq, Ordering::Release);
+    }
+
+    /// Copy the most recent entries into `out`, oldest first
+    pub fn snapshot(&self, out: &mut [TraceEntry]) -> usize {
+        let end = self.cursor.load(Ordering::Acquire);
+        let count = out.len().min(TRACE_ENTRIES) as u64;
+        let mut n = 0;
+        for seq in end.saturating_sub(count) + 1..=end {
+            let slot = &self.slots[(seq - 1) as usize % TRACE_ENTRIES];
+            if slot.seq.load(Ordering::Acquire) != seq {
+                continue;
+            }
+            let words = slot
+                .words
+                .each_ref()
+                .map(|word| word.load(Ordering::Relaxed));
+            fence(Ordering::Acquire);
+            // Overwritten while it was read
+            if slot.seq.load(Ordering::Relaxed) != seq {
+                continue;
+            }
+            out[n] = TraceEntry {
+                seq,
+                timestamp_us: words[0],
+                kind: TraceKind::from_raw(words[3] as u8),
+                slot: (words[3] >> 8) as u8,
+                dci: (words[3] >> 16) as u8,
+                param: words[1],
+                status: words[2] as u32,
+                control: (words[2] >> 32) as u32,
+            };
+            n += 1;
+        }
+        n
+    }
+}
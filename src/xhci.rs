@@ -1,372 +1,3787 @@
 use crate::{
-    Dma, Result, UsbError, reg,
+    Dma, Result, UsbError,
+    arena::MemoryPlan,
+    bandwidth::LoadSummary,
+    desc::DeviceDesc,
+    dev::{DeviceContext, DeviceHealth, DevicePath, DeviceQuirk, InputContext},
+    mmio::{Interrupter, RegisterBlock},
+    reg,
     ring::{EventRing, PhysMem, Ring, Trb, completion, trb_type},
+    sync::Lock,
+    topology::{TopologyDevice, TopologyNode},
 };
 
-use alloc::{boxed::Box, sync::Arc};
-use core::hint::spin_loop;
-use spin::Mutex;
+#[cfg(feature = "trace")]
+use crate::trace::{TraceBuffer, TraceEntry, TraceKind};
+
+use alloc::{
+    boxed::Box,
+    collections::VecDeque,
+    sync::{Arc, Weak},
+    vec::Vec,
+};
+use core::{
+    fmt,
+    hint::spin_loop,
+    mem::ManuallyDrop,
+    ops::{BitOr, BitOrAssign},
+    sync::atomic::{AtomicBool, AtomicU8, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
 
 const MMIO_INIT_SIZE: usize = 0x1000;
 const CMD_RING_SIZE: usize = 256;
 const EVENT_RING_SIZE: usize = 256;
+const DEFAULT_EP0_RING_SIZE: usize = 256;
+const DEFAULT_MAX_DESCRIPTOR_LEN: usize = 4096;
+pub(crate) const PORT_RESET_TIMEOUT_US: u32 = 500_000;
+const MAX_PENDING_EVENTS: usize = 64;
+const COMMAND_TIMEOUT_US: u32 = 5_000_000;
+const BIOS_HANDOFF_TIMEOUT_US: u32 = 1_000_000;
+/// Longest wait for USBSTS.HCH to follow USBCMD.R/S (16 ms, xHCI 5.4.1)
+const HALT_TIMEOUT_US: u32 = 16_000;
+/// Longest wait for a controller reset to complete
+const RESET_TIMEOUT_US: u32 = 1_000_000;
+/// Polling interval of `XhciCtrl::handshake`
+const HANDSHAKE_POLL_US: u32 = 100;
+/// Connection must stay up this long before a port is enumerated (TATTDB)
+pub(crate) const CONNECT_DEBOUNCE_US: u32 = 100_000;
+/// Longest wait for a port link to enter U3 or return to U0
+const LINK_TRANSITION_US: u32 = 100_000;
+/// Resume signaling driven on a USB2 port before returning to L0 (TDRSMDN)
+const RESUME_SIGNAL_US: u32 = 20_000;
+/// Recovery time a device gets after resume before any traffic (TRSMRCY)
+const RESUME_RECOVERY_US: u32 = 10_000;
+/// Interrupt moderation interval of interrupter 0, in 250 ns units (1 ms)
+const IMOD_INTERVAL: u32 = 4000;
+
+/// Port link state (PORTSC.PLS).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkState {
+    /// U0 (USB3) / L0 On (USB2)
+    U0,
+    /// U1 (USB3)
+    U1,
+    /// U2 (USB3) / L1 Sleep (USB2)
+    U2,
+    /// U3 (USB3) / L2 Suspend (USB2)
+    U3,
+    /// Disabled
+    Disabled,
+    /// RxDetect (USB3)
+    RxDetect,
+    /// SS.Inactive (USB3)
+    Inactive,
+    /// Polling
+    Polling,
+    /// Recovery (USB3)
+    Recovery,
+    /// Hot Reset (USB3)
+    HotReset,
+    /// Compliance Mode (USB3)
+    Compliance,
+    /// Test Mode
+    Test,
+    /// Resume
+    Resume,
+    /// Reserved encoding
+    Reserved(u8),
+}
+
+impl LinkState {
+    /// Decodes a raw PLS value.
+    pub fn from_raw(pls: u8) -> Self {
+        match pls as u32 {
+            reg::PLS_U0 => Self::U0,
+            reg::PLS_U1 => Self::U1,
+            reg::PLS_U2 => Self::U2,
+            reg::PLS_U3 => Self::U3,
+            reg::PLS_DISABLED => Self::Disabled,
+            reg::PLS_RXDETECT => Self::RxDetect,
+            reg::PLS_INACTIVE => Self::Inactive,
+            reg::PLS_POLLING => Self::Polling,
+            reg::PLS_RECOVERY => Self::Recovery,
+            reg::PLS_HOT_RESET => Self::HotReset,
+            reg::PLS_COMPLIANCE => Self::Compliance,
+            reg::PLS_TEST => Self::Test,
+            reg::PLS_RESUME => Self::Resume,
+            _ => Self::Reserved(pls & 0xF),
+        }
+    }
+
+    /// Returns the raw PLS value.
+    pub fn raw(self) -> u8 {
+        (match self {
+            Self::U0 => reg::PLS_U0,
+            Self::U1 => reg::PLS_U1,
+            Self::U2 => reg::PLS_U2,
+            Self::U3 => reg::PLS_U3,
+            Self::Disabled => reg::PLS_DISABLED,
+            Self::RxDetect => reg::PLS_RXDETECT,
+            Self::Inactive => reg::PLS_INACTIVE,
+            Self::Polling => reg::PLS_POLLING,
+            Self::Recovery => reg::PLS_RECOVERY,
+            Self::HotReset => reg::PLS_HOT_RESET,
+            Self::Compliance => reg::PLS_COMPLIANCE,
+            Self::Test => reg::PLS_TEST,
+            Self::Resume => reg::PLS_RESUME,
+            Self::Reserved(pls) => pls as u32 & 0xF,
+        }) as u8
+    }
+}
+
+/// Port speed (PORTSC.Port Speed / Slot Context speed).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speed {
+    /// Low Speed (1.5 Mbps)
+    Low,
+    /// Full Speed (12 Mbps)
+    Full,
+    /// High Speed (480 Mbps)
+    High,
+    /// SuperSpeed (5 Gbps)
+    Super,
+    /// SuperSpeed Plus (10 Gbps)
+    SuperPlus,
+    /// Speed ID not decoded here (e.g. a PSI-defined speed)
+    Unknown(u8),
+}
+
+impl Default for Speed {
+    fn default() -> Self {
+        Self::Unknown(0)
+    }
+}
+
+impl From<u8> for Speed {
+    fn from(raw: u8) -> Self {
+        match raw {
+            reg::SPEED_LOW => Self::Low,
+            reg::SPEED_FULL => Self::Full,
+            reg::SPEED_HIGH => Self::High,
+            reg::SPEED_SUPER => Self::Super,
+            reg::SPEED_SUPER_PLUS => Self::SuperPlus,
+            _ => Self::Unknown(raw & 0xF),
+        }
+    }
+}
+
+impl Speed {
+    /// Returns the raw Speed ID value.
+    pub fn as_raw(self) -> u8 {
+        match self {
+            Self::Low => reg::SPEED_LOW,
+            Self::Full => reg::SPEED_FULL,
+            Self::High => reg::SPEED_HIGH,
+            Self::Super => reg::SPEED_SUPER,
+            Self::SuperPlus => reg::SPEED_SUPER_PLUS,
+            Self::Unknown(raw) => raw,
+        }
+    }
+
+    /// Returns the EP0 max packet size to use before the device descriptor is read.
+    pub fn default_ep0_max_packet(self) -> u16 {
+        match self {
+            Self::Low | Self::Full | Self::Unknown(_) => 8,
+            Self::High => 64,
+            Self::Super | Self::SuperPlus => 512,
+        }
+    }
+
+    /// Returns true for SuperSpeed and SuperSpeed Plus.
+    pub fn is_super_or_faster(self) -> bool {
+        matches!(self, Self::Super | Self::SuperPlus)
+    }
+
+    /// Returns the name of the speed.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Low => "Low Speed",
+            Self::Full => "Full Speed",
+            Self::High => "High Speed",
+            Self::Super => "SuperSpeed",
+            Self::SuperPlus => "SuperSpeedPlus",
+            Self::Unknown(_) => "Unknown Speed",
+        }
+    }
+}
+
+impl fmt::Display for Speed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Decoded port status change bits (PORTSC).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PortChange {
+    /// Raw PORTSC value the change was decoded from
+    pub portsc: u32,
+    /// Connect Status Change (CSC)
+    pub connect: bool,
+    /// Port Enabled/Disabled Change (PEC)
+    pub enable: bool,
+    /// Warm Port Reset Change (WRC)
+    pub warm_reset: bool,
+    /// Over-current Change (OCC)
+    pub over_current: bool,
+    /// Over-current Active (OCA)
+    pub over_current_active: bool,
+    /// Port Reset Change (PRC)
+    pub reset: bool,
+    /// Port Link State Change (PLC)
+    pub link_state: bool,
+    /// Port Config Error Change (CEC)
+    pub config_error: bool,
+}
+
+impl PortChange {
+    /// Decodes the change bits of a PORTSC value.
+    pub fn from_portsc(portsc: u32) -> Self {
+        Self {
+            portsc,
+            connect: (portsc & reg::PORTSC_CSC) != 0,
+            enable: (portsc & reg::PORTSC_PEC) != 0,
+            warm_reset: (portsc & reg::PORTSC_WRC) != 0,
+            over_current: (portsc & reg::PORTSC_OCC) != 0,
+            over_current_active: (portsc & reg::PORTSC_OCA) != 0,
+            reset: (portsc & reg::PORTSC_PRC) != 0,
+            link_state: (portsc & reg::PORTSC_PLC) != 0,
+            config_error: (portsc & reg::PORTSC_CEC) != 0,
+        }
+    }
+
+    /// Returns true if any change bit is set.
+    pub fn any(&self) -> bool {
+        (self.portsc & reg::PORTSC_CHANGE_MASK) != 0
+    }
+
+    /// Returns the link state at the time of the change.
+    pub fn current_link_state(&self) -> LinkState {
+        LinkState::from_raw(reg::portsc_pls(self.portsc))
+    }
+}
+
+/// Registers of one interrupter, from `XhciCtrl::interrupter`.
+///
+/// For OSes routing one MSI-X vector per interrupter. The crate takes its
+/// events from interrupter 0; the Event Ring of any other interrupter is
+/// set up and consumed by the caller, so the ERST and ERDP setters fail
+/// with `InvalidArgument` on interrupter 0.
+pub struct InterrupterHandle<'a, H: Dma> {
+    ctrl: &'a XhciCtrl<H>,
+    index: u16,
+}
+
+impl<H: Dma> InterrupterHandle<'_, H> {
+    /// Returns the interrupter number.
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    fn regs(&self) -> Interrupter {
+        self.ctrl.regs.interrupter(self.index)
+    }
+
+    /// Fail with `InvalidArgument` on the crate's own interrupter
+    fn check_owned(&self) -> Result<()> {
+        if self.index == 0 {
+            return Err(UsbError::InvalidArgument);
+        }
+        Ok(())
+    }
+
+    /// Enable interrupts of the interrupter (IMAN.IE)
+    pub fn enable(&self) {
+        let iman = self.regs().iman();
+        iman.write((iman.read() | reg::IMAN_IE) & !reg::IMAN_IP);
+    }
+
+    /// Disable interrupts of the interrupter (IMAN.IE)
+    pub fn disable(&self) {
+        let iman = self.regs().iman();
+        iman.write(iman.read() & !(reg::IMAN_IE | reg::IMAN_IP));
+    }
+
+    /// Returns true if the interrupter has an interrupt pending (IMAN.IP).
+    pub fn pending(&self) -> bool {
+        self.regs().iman().read() & reg::IMAN_IP != 0
+    }
+
+    /// Acknowledge a pending interrupt by clearing IMAN.IP
+    pub fn ack(&self) {
+        let iman = self.regs().iman();
+        iman.write(iman.read() | reg::IMAN_IP);
+    }
+
+    /// Returns the Event Ring Dequeue Pointer, without the flag bits.
+    pub fn erdp(&self) -> u64 {
+        self.regs().erdp().read() & !(reg::ERDP_EHB | reg::ERDP_DESI_MASK)
+    }
+
+    /// Set the Event Ring Dequeue Pointer, clearing Event Handler Busy
+    pub fn set_erdp(&self, phys: u64) -> Result<()> {
+        self.check_owned()?;
+        self.ctrl.host.wmb();
+        self.regs().set_dequeue(phys);
+        Ok(())
+    }
+
+    /// Returns the number of Event Ring Segment Table entries (ERSTSZ).
+    pub fn erstsz(&self) -> u16 {
+        self.regs().erstsz().read() as u16
+    }
+
+    /// Set the number of Event Ring Segment Table entries
+    pub fn set_erstsz(&self, entries: u16) -> Result<()> {
+        self.check_owned()?;
+        self.regs().erstsz().write(entries as u32);
+        Ok(())
+    }
+
+    /// Returns the Event Ring Segment Table base address (ERSTBA).
+    pub fn erstba(&self) -> u64 {
+        self.regs().erstba().read()
+    }
+
+    /// Set the Event Ring Segment Table base address
+    ///
+    /// Writing ERSTBA enables the Event Ring, so ERSTSZ and ERDP must be
+    /// set first.
+    pub fn set_erstba(&self, phys: u64) -> Result<()> {
+        self.check_owned()?;
+        self.ctrl.host.wmb();
+        self.regs().erstba().write(phys);
+        Ok(())
+    }
+}
+
+/// Connected root port, as returned by `XhciCtrl::connected_ports`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PortSummary {
+    /// Root port number, 0-based
+    pub port: u8,
+    /// Major USB revision of the port (2 or 3) from its Supported Protocol
+    /// capability, `None` if no capability covers it
+    pub protocol: Option<u8>,
+    /// Port speed
+    pub speed: Speed,
+    /// Port of the other protocol on the same connector, if known
+    pub companion: Option<u8>,
+    /// The companion port also shows a connection
+    pub companion_connected: bool,
+}
+
+/// Controller limits and features from the capability registers.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Interface version as (major, minor), see `reg::hci_version`
+    pub hci_version: (u8, u8),
+    /// Number of device slots (HCSPARAMS1.MaxSlots)
+    pub max_slots: u8,
+    /// Number of root hub ports (HCSPARAMS1.MaxPorts)
+    pub max_ports: u8,
+    /// Number of interrupters (HCSPARAMS1.MaxIntrs)
+    pub max_intrs: u16,
+    /// Scratchpad buffers the controller needs (HCSPARAMS2)
+    pub max_scratchpad: u16,
+    /// Isochronous Scheduling Threshold (HCSPARAMS2.IST)
+    ///
+    /// Bits 2:0 count microframes, or whole frames if bit 3 is set.
+    pub ist: u8,
+    /// Worst case U1 exit latency in microseconds (HCSPARAMS3)
+    pub u1_exit_latency: u8,
+    /// Worst case U2 exit latency in microseconds (HCSPARAMS3)
+    pub u2_exit_latency: u16,
+    /// 64-bit Addressing Capability (AC64)
+    pub ac64: bool,
+    /// 64-byte Context Size (CSZ)
+    pub csz: bool,
+    /// Port Power Control (PPC)
+    pub ppc: bool,
+    /// Port Indicators (PIND)
+    pub pind: bool,
+    /// Light HC Reset Capability (LHRC)
+    pub lhrc: bool,
+    /// Latency Tolerance Messaging Capability (LTC)
+    pub ltc: bool,
+    /// Stopped EDTLA Capability (SEC), only on xHCI 1.0 and later
+    pub sec: bool,
+    /// U3 Entry Capability (U3C)
+    pub u3c: bool,
+    /// Max Exit Latency Too Large from Configure Endpoint (CMC)
+    pub cmc: bool,
+    /// Force Save Context Capability (FSC)
+    pub fsc: bool,
+    /// Compliance Transition Capability (CTC)
+    pub ctc: bool,
+    /// Large ESIT Payload Capability (LEC)
+    pub lec: bool,
+    /// Configuration Information Capability (CIC)
+    pub cic: bool,
+    /// Extended TBC Capability (ETC), only on xHCI 1.1 and later
+    pub etc: bool,
+    /// Extended TBC TRB Status Capability (ETC_TSC), only on xHCI 1.1 and later
+    pub etc_tsc: bool,
+}
+
+impl Capabilities {
+    /// Decodes the CAPLENGTH dword, HCSPARAMS1-3 and HCCPARAMS1-2.
+    ///
+    /// Capability bits defined by a later revision than the controller
+    /// reports are ignored, as they are reserved there.
+    pub fn from_regs(cap: u32, hcs1: u32, hcs2: u32, hcs3: u32, hcc1: u32, hcc2: u32) -> Self {
+        let hci_version = reg::hci_version(cap);
+        let v1_0 = hci_version >= (1, 0);
+        let v1_1 = hci_version >= (1, 10);
+        Self {
+            hci_version,
+            max_slots: (hcs1 & 0xff) as u8,
+            max_ports: ((hcs1 >> 24) & 0xff) as u8,
+            max_intrs: ((hcs1 >> 8) & 0x7ff) as u16,
+            max_scratchpad: (((hcs2 >> 27) & 0x1f) | (((hcs2 >> 21) & 0x1f) << 5)) as u16,
+            ist: (hcs2 & 0xf) as u8,
+            u1_exit_latency: (hcs3 & 0xff) as u8,
+            u2_exit_latency: (hcs3 >> 16) as u16,
+            ac64: (hcc1 & reg::HCCPARAMS1_AC64) != 0,
+            csz: (hcc1 & reg::HCCPARAMS1_CSZ) != 0,
+            ppc: (hcc1 & reg::HCCPARAMS1_PPC) != 0,
+            pind: (hcc1 & reg::HCCPARAMS1_PIND) != 0,
+            lhrc: (hcc1 & reg::HCCPARAMS1_LHRC) != 0,
+            ltc: (hcc1 & reg::HCCPARAMS1_LTC) != 0,
+            sec: v1_0 && (hcc1 & reg::HCCPARAMS1_SEC) != 0,
+            u3c: (hcc2 & reg::HCCPARAMS2_U3C) != 0,
+            cmc: (hcc2 & reg::HCCPARAMS2_CMC) != 0,
+            fsc: (hcc2 & reg::HCCPARAMS2_FSC) != 0,
+            ctc: (hcc2 & reg::HCCPARAMS2_CTC) != 0,
+            lec: (hcc2 & reg::HCCPARAMS2_LEC) != 0,
+            cic: (hcc2 & reg::HCCPARAMS2_CIC) != 0,
+            etc: v1_1 && (hcc2 & reg::HCCPARAMS2_ETC) != 0,
+            etc_tsc: v1_1 && (hcc2 & reg::HCCPARAMS2_ETC_TSC) != 0,
+        }
+    }
+}
+
+/// The USB Debug Capability (DbC) of a controller, decoded read-only.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DbcInfo {
+    /// Offset of the DbC registers from the start of the MMIO region
+    pub offset: usize,
+    /// Size of the DbC register block in bytes
+    pub size: usize,
+    /// Event Ring Segment Table Max (DCID), as log2 of the entry count
+    pub erst_max: u8,
+    /// DbC Protocol reported in the interface descriptor (DCDDI1)
+    pub protocol: u8,
+    /// Vendor ID reported by the debug device (DCDDI1)
+    pub vendor_id: u16,
+    /// Product ID reported by the debug device (DCDDI2)
+    pub product_id: u16,
+    /// Device revision reported by the debug device (DCDDI2, BCD)
+    pub revision: u16,
+    /// Debug Max Burst Size (DCCTRL)
+    pub max_burst: u8,
+    /// DbC Enable (DCCTRL.DCE)
+    pub enabled: bool,
+    /// DbC Run: the debug device is configured (DCCTRL.DCR)
+    pub running: bool,
+    /// Root port of the debug device, 0-based, while it runs (DCST)
+    pub port: Option<u8>,
+}
+
+impl DbcInfo {
+    /// Decodes the DCID, DCCTRL, DCST, DCDDI1 and DCDDI2 registers of the
+    /// capability at `offset`.
+    pub fn from_regs(
+        offset: usize,
+        dcid: u32,
+        dcctrl: u32,
+        dcst: u32,
+        dcddi1: u32,
+        dcddi2: u32,
+    ) -> Self {
+        let running = dcctrl & reg::DCCTRL_DCR != 0;
+        // Debug Port Number is 1-based and only valid while running
+        let port = (dcst >> 24) as u8;
+        Self {
+            offset,
+            size: reg::DBC_SIZE,
+            erst_max: ((dcid >> 16) & 0x1f) as u8,
+            protocol: dcddi1 as u8,
+            vendor_id: (dcddi1 >> 16) as u16,
+            product_id: dcddi2 as u16,
+            revision: (dcddi2 >> 16) as u16,
+            max_burst: (dcctrl >> 16) as u8,
+            enabled: dcctrl & reg::DCCTRL_DCE != 0,
+            running,
+            port: (running && port != 0).then(|| port - 1),
+        }
+    }
+}
+
+/// Reaction to an over-current condition on a root or hub port.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OvercurrentPolicy {
+    /// Remove port power while over-current is active
+    #[default]
+    PowerOff,
+    /// Only report the condition
+    NotifyOnly,
+}
+
+/// Port indicator LED mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PortIndicator {
+    /// Indicator driven by the hub (hub ports only)
+    Auto,
+    /// Amber
+    Amber,
+    /// Green
+    Green,
+    /// Off
+    Off,
+}
+
+impl PortIndicator {
+    /// Returns the PORTSC PIC encoding, if the mode exists on root ports.
+    pub fn pic(self) -> Option<u32> {
+        match self {
+            Self::Auto => None,
+            Self::Off => Some(0),
+            Self::Amber => Some(1),
+            Self::Green => Some(2),
+        }
+    }
+
+    /// Returns the hub class PORT_INDICATOR selector.
+    pub fn hub_selector(self) -> u8 {
+        match self {
+            Self::Auto => 0,
+            Self::Amber => 1,
+            Self::Green => 2,
+            Self::Off => 3,
+        }
+    }
+}
+
+/// Controller quirks.
+///
+/// Workarounds for controllers that deviate from the xHCI specification.
+/// This crate does not access PCI, so the caller derives them from the
+/// PCI IDs with `from_pci_ids` or combines the flags by hand.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XhciQuirks(u32);
+
+impl XhciQuirks {
+    /// Wait 1 ms after setting HCRST before touching any register
+    pub const RESET_DELAY: Self = Self(1 << 0);
+    /// Route Intel shared ports from EHCI to xHCI through PCI
+    /// configuration writes (XUSB2PR, USB3_PSSEN) performed by the host
+    pub const INTEL_PORT_SWITCH: Self = Self(1 << 1);
+    /// Allocate all DMA memory below 4 GiB even if AC64 is advertised
+    pub const BROKEN_64BIT_DMA: Self = Self(1 << 2);
+
+    /// Returns the empty set.
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Returns the raw flag bits.
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Returns true if all flags in `other` are set.
+    pub const fn contains(self, other: Self) -> bool {
+        (self.0 & other.0) == other.0
+    }
+
+    /// Returns the known quirks of a controller from its PCI IDs.
+    pub fn from_pci_ids(vendor: u16, device: u16) -> Self {
+        let mut quirks = Self::empty();
+        match vendor {
+            reg::PCI_VENDOR_INTEL => {
+                quirks |= Self::RESET_DELAY;
+                // Panther Point, Lynx Point, Lynx Point-LP, Wildcat Point-LP
+                if matches!(device, 0x1E31 | 0x8C31 | 0x9C31 | 0x9CB1) {
+                    quirks |= Self::INTEL_PORT_SWITCH;
+                }
+            }
+            reg::PCI_VENDOR_ASMEDIA if device == 0x2142 => {
+                quirks |= Self::BROKEN_64BIT_DMA;
+            }
+            _ => {}
+        }
+        quirks
+    }
+}
+
+impl BitOr for XhciQuirks {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self {
+        Self(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for XhciQuirks {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.0 |= rhs.0;
+    }
+}
+
+/// Drop a pending event no waiter needs, oldest first
+///
+/// MFINDEX Wrap events are already counted when dequeued, and of two
+/// Port Status Change events for the same port the older one carries
+/// nothing the port registers do not. Returns false if every event may
+/// still be waited for.
+fn make_room(pending: &mut VecDeque<Trb>) -> bool {
+    let is_type = |e: &Trb, ty: u32| e.trb_type() == ty as u8;
+    let idx = pending
+        .iter()
+        .position(|e| is_type(e, trb_type::MFINDEX_WRAP))
+        .or_else(|| {
+            pending.iter().enumerate().position(|(i, e)| {
+                is_type(e, trb_type::PORT_STATUS_CHANGE)
+                    && pending.iter().skip(i + 1).any(|later| {
+                        is_type(later, trb_type::PORT_STATUS_CHANGE)
+                            && later.port_id() == e.port_id()
+                    })
+            })
+        });
+    idx.and_then(|idx| pending.remove(idx)).is_some()
+}
+
+/// Elapsed-time tracker driven by MFINDEX (125 us resolution).
+///
+/// Must be sampled at least once per MFINDEX wrap period (2.048 s).
+pub(crate) struct Stopwatch {
+    last: u16,
+    elapsed_us: u64,
+}
+
+impl Stopwatch {
+    /// Returns the microseconds elapsed since the stopwatch was started.
+    pub fn elapsed_us<H: Dma>(&mut self, ctrl: &XhciCtrl<H>) -> u64 {
+        let now = ctrl.microframe_index();
+        self.elapsed_us += (now.wrapping_sub(self.last) as u32 & reg::MFINDEX_MASK) as u64 * 125;
+        self.last = now;
+        self.elapsed_us
+    }
+}
+
+/// Set of device slot IDs
+#[derive(Clone, Copy, Default)]
+struct SlotSet([u64; 4]);
+
+impl SlotSet {
+    fn contains(&self, slot: u8) -> bool {
+        self.0[slot as usize / 64] & (1 << (slot % 64)) != 0
+    }
+
+    /// Add a slot; returns false if it was already in the set
+    fn insert(&mut self, slot: u8) -> bool {
+        let present = self.contains(slot);
+        self.0[slot as usize / 64] |= 1 << (slot % 64);
+        !present
+    }
+
+    fn remove(&mut self, slot: u8) {
+        self.0[slot as usize / 64] &= !(1 << (slot % 64));
+    }
+
+    fn iter(self) -> impl Iterator<Item = u8> {
+        (1..=u8::MAX).filter(move |&slot| self.contains(slot))
+    }
+}
+
+/// Slots enabled through the controller and slots kept from `enable_slot`
+#[derive(Default)]
+struct SlotMap {
+    enabled: SlotSet,
+    reserved: SlotSet,
+}
+
+/// xHCI Controller
+pub struct XhciCtrl<H: Dma> {
+    mmio: usize,
+    mmio_size: usize,
+    regs: RegisterBlock,
+    caps: Capabilities,
+    dcbaa: PhysMem<H>,
+    scratchpad: Option<Scratchpad<H>>,
+    cmd_ring: Lock<Box<Ring<H>>>,
+    event_ring: Lock<Box<EventRing<H>>>,
+    /// Event ring TRBs, for peeking without the event ring lock
+    event_trbs: usize,
+    /// Copy of the event ring cursor (see `EventRing::cursor`)
+    event_cursor: AtomicU32,
+    pending_events: Lock<VecDeque<Trb>>,
+    pending_count: AtomicUsize,
+    mfindex_wraps: AtomicU32,
+    /// Last value returned by `microframe_counter`
+    mfindex_last: AtomicU32,
+    /// Event Ring Full errors reported by the controller, plus events
+    /// dropped from a full pending queue
+    events_lost: AtomicU64,
+    oc_notify_only: AtomicBool,
+    /// Interrupts enabled with `enable_interrupts`
+    interrupts: AtomicBool,
+    /// Set once the registers read all ones (see `check_alive`)
+    dead: AtomicBool,
+    ep0_ring_size: AtomicUsize,
+    max_descriptor_len: AtomicUsize,
+    bus_index: AtomicU8,
+    slots: Lock<SlotMap>,
+    /// Devices created on the controller, held weakly so they can still drop
+    devices: Lock<Vec<Weak<DeviceHealth>>>,
+    device_quirks: Lock<Vec<DeviceQuirk>>,
+    quirks: XhciQuirks,
+    dma32: bool,
+    #[cfg(feature = "trace")]
+    trace: Box<TraceBuffer>,
+    host: Arc<H>,
+}
+
+/// Settings applied when the controller is started.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XhciConfig {
+    /// Command ring size, in TRBs (at least 2)
+    pub cmd_ring_size: usize,
+    /// Event ring segment size, in TRBs (16 to 4096)
+    pub event_ring_size: usize,
+    /// EP0 ring size, in TRBs, of newly enumerated devices
+    pub ep0_ring_size: usize,
+    /// Largest configuration or BOS descriptor set read from a device,
+    /// in bytes
+    pub max_descriptor_len: usize,
+}
+
+impl Default for XhciConfig {
+    fn default() -> Self {
+        Self {
+            cmd_ring_size: CMD_RING_SIZE,
+            event_ring_size: EVENT_RING_SIZE,
+            ep0_ring_size: DEFAULT_EP0_RING_SIZE,
+            max_descriptor_len: DEFAULT_MAX_DESCRIPTOR_LEN,
+        }
+    }
+}
+
+/// Entry point of a staged controller bring-up.
+///
+/// `XhciCtrl::new` runs every stage at once; the builder lets the caller
+/// stop in between:
+///
+/// ```ignore
+/// let mapped = XhciBuilder::map(mmio_phys, host)?;
+/// let quirks = pick_quirks(mapped.capabilities());
+/// let ctrl = mapped.with_quirks(quirks).take_ownership()?.reset_and_start()?;
+/// ```
+pub struct XhciBuilder;
+
+impl XhciBuilder {
+    /// Map the register file of a controller
+    ///
+    /// Only the capability registers are read; nothing is written.
+    pub fn map<H: Dma>(mmio_phys: usize, host: H) -> Result<MappedXhci<H>> {
+        Self::map_shared(mmio_phys, Arc::new(host))
+    }
+
+    /// Like `map`, with a host shared with other controllers
+    pub fn map_shared<H: Dma>(mmio_phys: usize, host: Arc<H>) -> Result<MappedXhci<H>> {
+        // Initial map to read capability registers
+        let init_mmio = unsafe {
+            host.map_mmio(mmio_phys, MMIO_INIT_SIZE)
+        }.ok_or(UsbError::MapFail)?;
+
+        let cap: u32 = unsafe { ((init_mmio + reg::CAPLENGTH) as *const u32).read_volatile() };
+        if cap == u32::MAX {
+            // Nothing answers behind the BAR
+            unsafe {
+                host.unmap_mmio(init_mmio, MMIO_INIT_SIZE);
+            }
+            return Err(UsbError::ControllerGone);
+        }
+        let cap_length = cap as u8;
+        let hcs1: u32 = unsafe { ((init_mmio + reg::HCSPARAMS1) as *const u32).read_volatile() };
+        let hcs2: u32 = unsafe { ((init_mmio + reg::HCSPARAMS2) as *const u32).read_volatile() };
+        let hcs3: u32 = unsafe { ((init_mmio + reg::HCSPARAMS3) as *const u32).read_volatile() };
+        let hcc1: u32 = unsafe { ((init_mmio + reg::HCCPARAMS1) as *const u32).read_volatile() };
+        let hcc2: u32 = unsafe { ((init_mmio + reg::HCCPARAMS2) as *const u32).read_volatile() };
+        let db_offset: u32 = unsafe { ((init_mmio + reg::DBOFF) as *const u32).read_volatile() };
+        let rts_offset: u32 = unsafe { ((init_mmio + reg::RTSOFF) as *const u32).read_volatile() };
+
+        let caps = Capabilities::from_regs(cap, hcs1, hcs2, hcs3, hcc1, hcc2);
+        if caps.hci_version < (1, 0) {
+            let (major, minor) = caps.hci_version;
+            host.warn(format_args!(
+                "xHCI {}.{:02} controller predates xHCI 1.0; expect missing features",
+                major, minor
+            ));
+        }
+
+        // Calculate total MMIO size needed
+        let mut mmio_size = (rts_offset as usize + 0x20 + 0x20)
+            .max(db_offset as usize + (caps.max_slots as usize + 1) * 4)
+            .max(0x10000);
+
+        unsafe {
+            host.unmap_mmio(init_mmio, MMIO_INIT_SIZE);
+        }
+
+        // Remap with full size
+        let mut mmio = unsafe {
+            host.map_mmio(mmio_phys, mmio_size)
+        }.ok_or(UsbError::MapFail)?;
+
+        // Extended capabilities, such as the Debug Capability, may lie
+        // past the registers sized above
+        let regs = loop {
+            let regs = unsafe { RegisterBlock::new(mmio, cap_length, rts_offset, db_offset) };
+            let caps_end = ext_caps_end(&regs, mmio_size);
+            if caps_end <= mmio_size {
+                break regs;
+            }
+            unsafe {
+                host.unmap_mmio(mmio, mmio_size);
+            }
+            mmio_size = caps_end.next_multiple_of(host.page_size());
+            mmio = unsafe { host.map_mmio(mmio_phys, mmio_size) }.ok_or(UsbError::MapFail)?;
+        };
+
+        Ok(MappedXhci {
+            mmio,
+            mmio_size,
+            regs,
+            caps,
+            config: XhciConfig::default(),
+            quirks: XhciQuirks::empty(),
+            host,
+        })
+    }
+}
+
+/// Step of `MappedXhci::reset_and_start`, as reported by `StartError`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StartStep {
+    /// Halting and resetting the controller
+    Reset,
+    /// Allocating the DCBAA and scratchpad buffers, and setting DCBAAP
+    Dcbaa,
+    /// Allocating the command ring and setting CRCR
+    CommandRing,
+    /// Allocating the event ring and setting up interrupter 0
+    EventRing,
+    /// Setting USBCMD.R/S and waiting for the controller to run
+    Run,
+}
+
+impl fmt::Display for StartStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Reset => "reset",
+            Self::Dcbaa => "DCBAA setup",
+            Self::CommandRing => "command ring setup",
+            Self::EventRing => "event ring setup",
+            Self::Run => "run",
+        })
+    }
+}
+
+/// Failure of `MappedXhci::reset_and_start`.
+///
+/// Converts into the `UsbError` it wraps.
+#[derive(Clone, Copy, Debug)]
+pub struct StartError {
+    /// Step that failed
+    pub step: StartStep,
+    /// Why it failed
+    pub error: UsbError,
+}
+
+impl fmt::Display for StartError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "controller {} failed: {}", self.step, self.error)
+    }
+}
+
+impl core::error::Error for StartError {}
+
+impl From<StartError> for UsbError {
+    fn from(e: StartError) -> Self {
+        e.error
+    }
+}
+
+/// Tag errors of `step` for `reset_and_start`
+fn failed_at(step: StartStep) -> impl FnOnce(UsbError) -> StartError {
+    move |error| StartError { step, error }
+}
+
+/// Scratchpad Buffer Array and the page-sized buffers it points to
+struct Scratchpad<H: Dma> {
+    array: PhysMem<H>,
+    bufs: PhysMem<H>,
+}
+
+impl<H: Dma> Scratchpad<H> {
+    /// Allocate `count` buffers and an array pointing to them
+    fn alloc(host: &H, count: usize, dma32: bool) -> Result<Self> {
+        // xHCI spec requires 64-byte alignment for scratchpad array
+        let array = PhysMem::alloc_in(host, count * 8, 64, dma32)?;
+        // Scratchpad buffers must be page-aligned
+        let page = host.page_size();
+        let bufs = match PhysMem::alloc_in(host, count * page, page, dma32) {
+            Ok(bufs) => bufs,
+            Err(e) => {
+                array.free(host);
+                return Err(e);
+            }
+        };
+
+        // Fill scratchpad array with buffer addresses
+        let array_ptr = array.as_ptr::<u64>();
+        for i in 0..count {
+            let buf_phys = bufs.phys(host) + (i * page) as u64;
+            unsafe {
+                array_ptr.add(i).write_volatile(buf_phys);
+            }
+        }
+        Ok(Self { array, bufs })
+    }
+
+    fn free(self, host: &H) {
+        self.array.free(host);
+        self.bufs.free(host);
+    }
+}
+
+/// Controller memory allocated by `reset_and_start` so far, freed unless
+/// taken by the started controller
+struct StartMemory<'a, H: Dma> {
+    host: &'a H,
+    dcbaa: Option<PhysMem<H>>,
+    scratchpad: Option<Scratchpad<H>>,
+    cmd_ring: Option<Box<Ring<H>>>,
+    event_ring: Option<Box<EventRing<H>>>,
+}
+
+impl<H: Dma> Drop for StartMemory<'_, H> {
+    fn drop(&mut self) {
+        if let Some(dcbaa) = self.dcbaa.take() {
+            dcbaa.free(self.host);
+        }
+        if let Some(scratchpad) = self.scratchpad.take() {
+            scratchpad.free(self.host);
+        }
+        if let Some(ring) = self.cmd_ring.take() {
+            ring.free(self.host);
+        }
+        if let Some(ring) = self.event_ring.take() {
+            ring.free(self.host);
+        }
+    }
+}
+
+/// A mapped controller that has not been reset yet.
+///
+/// Created by `XhciBuilder::map`. Dropping it unmaps the registers
+/// without touching the controller.
+pub struct MappedXhci<H: Dma> {
+    mmio: usize,
+    mmio_size: usize,
+    regs: RegisterBlock,
+    caps: Capabilities,
+    config: XhciConfig,
+    quirks: XhciQuirks,
+    host: Arc<H>,
+}
+
+impl<H: Dma> MappedXhci<H> {
+    /// Get the limits and features read from the capability registers
+    pub fn capabilities(&self) -> Capabilities {
+        self.caps
+    }
+
+    /// Check if the controller is halted (USBSTS.HCH)
+    pub fn is_halted(&self) -> bool {
+        self.regs.usbsts().contains(reg::USBSTS_HCH)
+    }
+
+    /// Get the Debug Capability, if the controller has one
+    pub fn dbc(&self) -> Option<DbcInfo> {
+        find_dbc(&self.regs, self.mmio_size)
+    }
+
+    /// Check if the firmware still owns the controller
+    ///
+    /// False if the controller has no USB Legacy Support capability.
+    pub fn bios_owned(&self) -> bool {
+        find_ext_cap(&self.regs, self.mmio_size, reg::ECAP_USB_LEGACY, None)
+            .is_some_and(|cap| self.regs.cap(cap).read() & reg::USBLEGSUP_BIOS_OWNED != 0)
+    }
+
+    /// Get the host the controller was mapped with
+    pub fn host(&self) -> &H {
+        &self.host
+    }
+
+    /// Compute the DMA memory needed to run `max_devices` devices
+    ///
+    /// Covers the controller's own structures for the configured ring
+    /// sizes plus, per device, its contexts and EP0 ring, `eps_per_device`
+    /// endpoint rings, a control transfer of up to `max_descriptor_len`
+    /// bytes, an interrupt buffer and a class transfer of up to
+    /// `max_transfer` bytes each, and the CBW and CSW of a mass storage
+    /// command. Rings are counted twice, as reconfiguring an endpoint
+    /// allocates its new ring before freeing the old one. Larger
+    /// transfers must be split by the caller. Used to size the region
+    /// handed to `StaticDma::provision`.
+    pub fn memory_requirements(
+        &self,
+        max_devices: usize,
+        eps_per_device: usize,
+        max_transfer: usize,
+    ) -> MemoryPlan {
+        let trb = core::mem::size_of::<Trb>();
+        let page = self.host.page_size();
+        let config = &self.config;
+        let max_scratchpad = self.caps.max_scratchpad as usize;
+        let mut plan = MemoryPlan {
+            align: page,
+            ..MemoryPlan::default()
+        };
+
+        plan.add(1, (self.caps.max_slots as usize + 1) * 8, 64);
+        if max_scratchpad > 0 {
+            plan.add(1, max_scratchpad * 8, 64);
+            plan.add(1, max_scratchpad * page, page);
+        }
+        plan.add(1, config.cmd_ring_size * trb, trb);
+        plan.add(1, config.event_ring_size * trb, trb);
+        plan.add(1, page, 64);
+
+        let devices = max_devices.min(self.caps.max_slots as usize);
+        plan.add(devices, core::mem::size_of::<DeviceContext>(), 64);
+        plan.add(devices, core::mem::size_of::<InputContext>(), 64);
+        plan.add(devices * 2, config.ep0_ring_size * trb, trb);
+        plan.add(devices * eps_per_device * 2, 256 * trb, trb);
+        plan.add(devices, config.max_descriptor_len, 64);
+        plan.add(devices * 2, max_transfer, 64);
+        plan.add(devices * 2, 64, 64);
+        plan
+    }
+
+    /// Set the ring sizes used once the controller is started
+    pub fn with_config(mut self, config: XhciConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Set the workarounds applied from the reset on
+    pub fn with_quirks(mut self, quirks: XhciQuirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    /// Take the controller over from the firmware (BIOS handoff)
+    ///
+    /// Sets the OS Owned semaphore of the USB Legacy Support capability
+    /// and waits up to 1 s for the firmware to release the BIOS Owned
+    /// one. A firmware that never lets go is overridden. Either way the
+    /// legacy SMIs are disabled. Does nothing if the controller has no
+    /// such capability.
+    pub fn take_ownership(self) -> Result<Self> {
+        let Some(cap) = find_ext_cap(&self.regs, self.mmio_size, reg::ECAP_USB_LEGACY, None) else {
+            return Ok(self);
+        };
+        let legsup = self.regs.cap(cap);
+
+        let bios_owned = || legsup.read() & reg::USBLEGSUP_BIOS_OWNED != 0;
+        if bios_owned() {
+            legsup.modify(|val| val | reg::USBLEGSUP_OS_OWNED);
+            let mut waited = 0;
+            while bios_owned() && waited < BIOS_HANDOFF_TIMEOUT_US {
+                self.host.delay_us(1000);
+                waited += 1000;
+            }
+            // The firmware did not respond; take the controller anyway
+            legsup.modify(|val| (val & !reg::USBLEGSUP_BIOS_OWNED) | reg::USBLEGSUP_OS_OWNED);
+        }
+
+        self.regs
+            .cap(cap + reg::USBLEG_CTLSTS)
+            .modify(|val| (val & !reg::USBLEGCTLSTS_SMI_ENABLES) | reg::USBLEGCTLSTS_SMI_EVENTS);
+        Ok(self)
+    }
+
+    /// Reset the controller, program its rings and start it
+    ///
+    /// Interrupts are left disabled; see `XhciCtrl::enable_interrupts`.
+    /// A failure reports the step it happened in, with the memory
+    /// allocated so far freed. The command ring step fails with
+    /// `InvalidArgument` if the command ring size is below 2, and the
+    /// event ring step if the event ring size is not within 16..=4096.
+    pub fn reset_and_start(self) -> core::result::Result<XhciCtrl<H>, StartError> {
+        let config = self.config;
+        let host = self.host.clone();
+        let max_slots = self.caps.max_slots;
+        let max_scratchpad = self.caps.max_scratchpad as usize;
+        let dma32 = self.quirks.contains(XhciQuirks::BROKEN_64BIT_DMA) || !self.caps.ac64;
+        let mut memory = StartMemory {
+            host: &*host,
+            dcbaa: None,
+            scratchpad: None,
+            cmd_ring: None,
+            event_ring: None,
+        };
+
+        // Allocate DCBAA (Device Context Base Address Array)
+        // xHCI spec requires 64-byte alignment for DCBAA
+        let dcbaa = PhysMem::alloc_in(&*host, (max_slots as usize + 1) * 8, 64, dma32)
+            .map_err(failed_at(StartStep::Dcbaa))?;
+        let dcbaa = memory.dcbaa.insert(dcbaa);
+
+        // Allocate scratchpad if needed
+        if max_scratchpad > 0 {
+            let scratchpad = Scratchpad::alloc(&*host, max_scratchpad, dma32)
+                .map_err(failed_at(StartStep::Dcbaa))?;
+            // Point DCBAA[0] to scratchpad array
+            unsafe {
+                dcbaa
+                    .as_ptr::<u64>()
+                    .write_volatile(scratchpad.array.phys(&*host));
+            }
+            memory.scratchpad = Some(scratchpad);
+        }
+
+        // Allocate rings on heap to reduce stack usage
+        if config.cmd_ring_size < 2 {
+            return Err(failed_at(StartStep::CommandRing)(UsbError::InvalidArgument));
+        }
+        let cmd_ring = Ring::new(&*host, config.cmd_ring_size, dma32)
+            .map_err(failed_at(StartStep::CommandRing))?;
+        memory.cmd_ring = Some(Box::new(cmd_ring));
+        if !(16..=4096).contains(&config.event_ring_size) {
+            return Err(failed_at(StartStep::EventRing)(UsbError::InvalidArgument));
+        }
+        let event_ring = EventRing::new(&*host, config.event_ring_size, dma32)
+            .map_err(failed_at(StartStep::EventRing))?;
+        memory.event_ring = Some(Box::new(event_ring));
+
+        let (Some(dcbaa), Some(cmd_ring), Some(event_ring)) = (
+            memory.dcbaa.take(),
+            memory.cmd_ring.take(),
+            memory.event_ring.take(),
+        ) else {
+            unreachable!("every step allocated its memory")
+        };
+        let scratchpad = memory.scratchpad.take();
+
+        // The controller unmaps the registers from here on
+        let this = ManuallyDrop::new(self);
+        let mut ctrl = XhciCtrl {
+            mmio: this.mmio,
+            mmio_size: this.mmio_size,
+            regs: unsafe { core::ptr::read(&this.regs) },
+            caps: this.caps,
+            dcbaa,
+            scratchpad,
+            cmd_ring: Lock::new(cmd_ring),
+            event_trbs: event_ring.virt(),
+            event_cursor: AtomicU32::new(event_ring.cursor()),
+            event_ring: Lock::new(event_ring),
+            pending_events: Lock::new(VecDeque::new()),
+            pending_count: AtomicUsize::new(0),
+            mfindex_wraps: AtomicU32::new(0),
+            mfindex_last: AtomicU32::new(0),
+            events_lost: AtomicU64::new(0),
+            oc_notify_only: AtomicBool::new(false),
+            interrupts: AtomicBool::new(false),
+            dead: AtomicBool::new(false),
+            ep0_ring_size: AtomicUsize::new(config.ep0_ring_size),
+            max_descriptor_len: AtomicUsize::new(config.max_descriptor_len),
+            bus_index: AtomicU8::new(0),
+            slots: Lock::new(SlotMap::default()),
+            devices: Lock::new(Vec::new()),
+            device_quirks: Lock::new(Vec::new()),
+            quirks: this.quirks,
+            dma32,
+            #[cfg(feature = "trace")]
+            trace: Box::new(TraceBuffer::new()),
+            host: unsafe { core::ptr::read(&this.host) },
+        };
+
+        // Dropping a controller that failed to start halts it and frees
+        // its memory
+        ctrl.init()?;
+        Ok(ctrl)
+    }
+}
+
+impl<H: Dma> Drop for MappedXhci<H> {
+    fn drop(&mut self) {
+        unsafe {
+            self.host.unmap_mmio(self.mmio, self.mmio_size);
+        }
+    }
+}
+
+impl<H: Dma> XhciCtrl<H> {
+    /// Create and initialize a new xHCI controller
+    ///
+    /// The controller runs in polling mode until `enable_interrupts`.
+    pub fn new(mmio_phys: usize, host: H) -> Result<Self> {
+        Self::new_with_quirks(mmio_phys, host, XhciQuirks::empty())
+    }
+
+    /// Create and initialize a new xHCI controller with workarounds applied
+    ///
+    /// Runs every stage of `XhciBuilder` with the default `XhciConfig`.
+    pub fn new_with_quirks(mmio_phys: usize, host: H, quirks: XhciQuirks) -> Result<Self> {
+        XhciBuilder::map(mmio_phys, host)?
+            .with_quirks(quirks)
+            .take_ownership()?
+            .reset_and_start()
+            .map_err(UsbError::from)
+    }
+
+    /// Create and initialize a controller sharing `host` with others
+    ///
+    /// Every controller allocates from and maps through the same host:
+    ///
+    /// ```no_run
+    /// # extern crate alloc;
+    /// # use alloc::sync::Arc;
+    /// # use core::sync::atomic::{AtomicUsize, Ordering};
+    /// # use usb_oxide::{Dma, XhciCtrl};
+    /// # /// Identity-mapped bump allocator over one DMA heap
+    /// # struct BumpDma {
+    /// #     next: AtomicUsize,
+    /// #     end: usize,
+    /// # }
+    /// # impl BumpDma {
+    /// #     fn new(base: usize, size: usize) -> Self {
+    /// #         Self { next: AtomicUsize::new(base), end: base + size }
+    /// #     }
+    /// # }
+    /// # impl Dma for BumpDma {
+    /// #     unsafe fn alloc(&self, size: usize, align: usize) -> Option<usize> {
+    /// #         let mut start = 0;
+    /// #         self.next
+    /// #             .fetch_update(Ordering::AcqRel, Ordering::Acquire, |next| {
+    /// #                 start = next.next_multiple_of(align);
+    /// #                 (start + size <= self.end).then_some(start + size)
+    /// #             })
+    /// #             .ok()?;
+    /// #         Some(start)
+    /// #     }
+    /// #     unsafe fn free(&self, _addr: usize, _size: usize, _align: usize) {}
+    /// #     unsafe fn map_mmio(&self, phys: usize, _size: usize) -> Option<usize> {
+    /// #         Some(phys)
+    /// #     }
+    /// #     unsafe fn unmap_mmio(&self, _virt: usize, _size: usize) {}
+    /// #     fn virt_to_phys(&self, va: usize) -> usize {
+    /// #         va
+    /// #     }
+    /// #     fn delay_us(&self, _us: u32) { /* platform timer */ }
+    /// # }
+    /// # fn main() -> usb_oxide::Result<()> {
+    /// # let (heap_base, heap_size) = (0x1000_0000, 0x10_0000);
+    /// # let (bar0_a, bar0_b) = (0xfe00_0000, 0xfd00_0000);
+    /// let host = Arc::new(BumpDma::new(heap_base, heap_size));
+    /// let ctrl0 = XhciCtrl::new_shared(bar0_a, host.clone())?;
+    /// let ctrl1 = XhciCtrl::new_shared(bar0_b, host)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn new_shared(mmio_phys: usize, host: Arc<H>) -> Result<Self> {
+        XhciBuilder::map_shared(mmio_phys, host)?
+            .take_ownership()?
+            .reset_and_start()
+            .map_err(UsbError::from)
+    }
+
+    fn init(&mut self) -> core::result::Result<(), StartError> {
+        let usbcmd = self.regs.usbcmd();
+        let usbsts = self.regs.usbsts();
+
+        // Stop controller if running
+        let cmd = usbcmd.read();
+        if (cmd & reg::USBCMD_RUN) != 0 {
+            usbcmd.write(cmd & !reg::USBCMD_RUN);
+            self.handshake(HALT_TIMEOUT_US, || usbsts.contains(reg::USBSTS_HCH))
+                .map_err(failed_at(StartStep::Reset))?;
+        }
+        if usbsts.is_gone() {
+            return Err(failed_at(StartStep::Reset)(self.mark_dead()));
+        }
+
+        // Reset controller
+        usbcmd.write(reg::USBCMD_HCRST);
+        self.trace_reg(usbcmd.addr(), reg::USBCMD_HCRST as u64);
+        if self.quirks.contains(XhciQuirks::RESET_DELAY) {
+            // Register access right after HCRST can hang some controllers
+            self.host.delay_us(1000);
+        }
+        self.handshake(RESET_TIMEOUT_US, || {
+            (usbcmd.read() & reg::USBCMD_HCRST) == 0 && !usbsts.contains(reg::USBSTS_CNR)
+        })
+        .map_err(failed_at(StartStep::Reset))?;
+
+        // Configure controller
+        self.regs.config().write(self.caps.max_slots as u32);
+        // The DCBAA, command ring and event ring are published below; no
+        // memory is written between here and the ERDP write
+        self.host.wmb();
+        let dcbaap = self.regs.dcbaap();
+        dcbaap.write(self.dcbaa.phys(&*self.host));
+        self.trace_reg(dcbaap.addr(), self.dcbaa.phys(&*self.host));
+
+        // Setup command ring
+        let cmd_ring = self.cmd_ring.lock();
+        let crcr = self.regs.crcr();
+        crcr.set_ring(cmd_ring.phys(&*self.host), true);
+        self.trace_reg(crcr.addr(), cmd_ring.phys(&*self.host) | 1);
+        drop(cmd_ring);
+
+        // Setup event ring
+        let event_ring = self.event_ring.lock();
+        let interrupter = self.regs.interrupter(0);
+
+        interrupter.erstsz().write(1);
+        interrupter.erstba().write(event_ring.erst_phys(&*self.host));
+        interrupter.erdp().write(event_ring.ring_phys(&*self.host));
+        self.trace_reg(interrupter.erstsz().addr(), 1);
+        self.trace_reg(
+            interrupter.erstba().addr(),
+            event_ring.erst_phys(&*self.host),
+        );
+        self.trace_reg(interrupter.erdp().addr(), event_ring.ring_phys(&*self.host));
+        drop(event_ring);
+
+        // Start controller; interrupts stay off until `enable_interrupts`
+        let mut cmd = reg::USBCMD_RUN;
+        if self.caps.etc {
+            // Wider TBC/TLBPC fields in Isoch TRBs
+            cmd |= reg::USBCMD_ETE;
+        }
+        usbcmd.write(cmd);
+        self.trace_reg(usbcmd.addr(), cmd as u64);
+
+        // Wait for controller to be ready
+        self.handshake(HALT_TIMEOUT_US, || !usbsts.contains(reg::USBSTS_HCH))
+            .map_err(failed_at(StartStep::Run))?;
+
+        if self.quirks.contains(XhciQuirks::INTEL_PORT_SWITCH) {
+            self.switch_intel_ports();
+        }
+
+        // Changes left over from the firmware or a previous OS are stale
+        for port in 0..self.caps.max_ports {
+            self.regs.portsc(port).clear_changes(reg::PORTSC_CHANGE_MASK);
+        }
+
+        Ok(())
+    }
+
+    /// Poll `cond` until it holds or `timeout_us` microseconds elapse
+    ///
+    /// For waits on the run state, which cannot use `wait_until`: MFINDEX
+    /// stops while the controller is halted.
+    fn handshake(&self, timeout_us: u32, cond: impl Fn() -> bool) -> Result<()> {
+        let mut waited = 0;
+        while !cond() {
+            if self.regs.usbsts().is_gone() {
+                return Err(self.mark_dead());
+            }
+            if waited >= timeout_us {
+                return Err(UsbError::Timeout);
+            }
+            self.host.delay_us(HANDSHAKE_POLL_US);
+            waited += HANDSHAKE_POLL_US;
+        }
+        Ok(())
+    }
+
+    /// Route the switchable ports of an Intel PCH from EHCI to xHCI
+    ///
+    /// Enables SuperSpeed on every port in USB3PRM, then hands every port
+    /// in USB2PRM to xHCI. Skipped if the host cannot read PCI
+    /// configuration space.
+    fn switch_intel_ports(&self) {
+        if let Some(mask) = self.host.pci_read_config(reg::PCI_USB3PRM) {
+            self.host.pci_write_config(reg::PCI_USB3_PSSEN, mask);
+        }
+        if let Some(mask) = self.host.pci_read_config(reg::PCI_USB2PRM) {
+            self.host.pci_write_config(reg::PCI_XUSB2PR, mask);
+        }
+    }
+
+    /// Ring the command doorbell
+    fn ring_cmd_doorbell(&self) {
+        // The command TRB must be visible before the doorbell
+        self.host.wmb();
+        self.regs.doorbell(0).write(0);
+        self.trace_doorbell(0, 0);
+    }
+
+    /// Ring device doorbell
+    ///
+    /// `target` is the endpoint's Device Context Index (1-31).
+    pub fn ring_doorbell(&self, slot: u8, target: u8) -> Result<()> {
+        self.ring_doorbell_stream(slot, target, 0)
+    }
+
+    /// Ring device doorbell for a specific stream of a stream-enabled endpoint
+    pub fn ring_doorbell_stream(&self, slot: u8, dci: u8, stream_id: u16) -> Result<()> {
+        // Doorbell 0 belongs to the command ring
+        if slot == 0 || slot > self.caps.max_slots {
+            return Err(UsbError::InvSlot);
+        }
+        if !(1..=31).contains(&dci) {
+            return Err(UsbError::InvEndpoint);
+        }
+        self.check_alive()?;
+
+        // The transfer TRBs and their buffers must be visible before the
+        // doorbell
+        self.host.wmb();
+        let value = reg::doorbell_value(dci, stream_id);
+        self.regs.doorbell(slot).write(value);
+        self.trace_doorbell(slot, value);
+        Ok(())
+    }
+
+    /// Update event ring dequeue pointer
+    fn update_erdp(&self) {
+        let event_ring = self.event_ring.lock();
+        // Reads of the consumed events must complete before the controller
+        // may overwrite them
+        self.host.wmb();
+        let interrupter = self.regs.interrupter(0);
+        interrupter.set_dequeue(event_ring.dequeue_ptr(&*self.host));
+        drop(event_ring);
+
+        // No interrupt handler acknowledges the status bits when polling
+        if !self.interrupts.load(Ordering::Acquire) {
+            self.regs.usbsts().clear(reg::USBSTS_EINT);
+            let iman = interrupter.iman();
+            let val = iman.read();
+            if val & reg::IMAN_IP != 0 {
+                // IP is write-1-to-clear
+                iman.write(val);
+            }
+        }
+    }
+
+    /// Copy the most recent trace entries into `out`, oldest first
+    ///
+    /// Returns the number of entries copied: at most `out.len()` and
+    /// `TRACE_ENTRIES`. Entries being overwritten while they are read are
+    /// skipped. Takes no lock, so it may be called from a panic handler.
+    #[cfg(feature = "trace")]
+    pub fn trace_snapshot(&self, out: &mut [TraceEntry]) -> usize {
+        self.trace.snapshot(out)
+    }
+
+    /// Record a trace entry, timestamped now
+    #[cfg(feature = "trace")]
+    fn trace_entry(&self, kind: TraceKind, slot: u8, dci: u8, trb: &Trb) {
+        let timestamp_us = self
+            .host
+            .now_us()
+            .unwrap_or_else(|| self.microframe_counter() as u64 * 125);
+        self.trace.record(TraceEntry {
+            seq: 0,
+            timestamp_us,
+            kind,
+            slot,
+            dci,
+            param: trb.param,
+            status: trb.status,
+            control: trb.control,
+        });
+    }
+
+    /// Trace a command TRB placed on the command ring
+    #[cfg_attr(not(feature = "trace"), allow(unused_variables))]
+    fn trace_command(&self, trb: &Trb) {
+        #[cfg(feature = "trace")]
+        self.trace_entry(TraceKind::Command, (trb.control >> 24) as u8, 0, trb);
+    }
+
+    /// Trace a transfer TRB placed on the ring of endpoint `dci`
+    #[cfg_attr(not(feature = "trace"), allow(unused_variables))]
+    pub(crate) fn trace_transfer(&self, slot: u8, dci: u8, trb: &Trb) {
+        #[cfg(feature = "trace")]
+        self.trace_entry(TraceKind::Transfer, slot, dci, trb);
+    }
+
+    /// Trace an event TRB taken from the event ring
+    #[cfg_attr(not(feature = "trace"), allow(unused_variables))]
+    fn trace_event(&self, trb: &Trb) {
+        #[cfg(feature = "trace")]
+        self.trace_entry(TraceKind::Event, trb.slot_id(), trb.endpoint_id(), trb);
+    }
+
+    /// Trace a doorbell write
+    #[cfg_attr(not(feature = "trace"), allow(unused_variables))]
+    fn trace_doorbell(&self, slot: u8, value: u32) {
+        #[cfg(feature = "trace")]
+        self.trace_entry(
+            TraceKind::Doorbell,
+            slot,
+            value as u8,
+            &Trb {
+                param: value as u64,
+                status: 0,
+                control: 0,
+            },
+        );
+    }
+
+    /// Trace a write of `value` to the register at virtual address `addr`
+    #[cfg_attr(not(feature = "trace"), allow(unused_variables))]
+    fn trace_reg(&self, addr: usize, value: u64) {
+        #[cfg(feature = "trace")]
+        self.trace_entry(
+            TraceKind::Register,
+            0,
+            0,
+            &Trb {
+                param: value,
+                status: (addr - self.mmio) as u32,
+                control: 0,
+            },
+        );
+    }
+
+    /// Get the registers of interrupter `n`
+    ///
+    /// Fails with `InvalidArgument` unless `n` is below MaxIntrs.
+    pub fn interrupter(&self, n: u16) -> Result<InterrupterHandle<'_, H>> {
+        if n >= self.caps.max_intrs {
+            return Err(UsbError::InvalidArgument);
+        }
+        Ok(InterrupterHandle {
+            ctrl: self,
+            index: n,
+        })
+    }
+
+    /// Enable interrupts: USBCMD.INTE and IMAN.IE of interrupter 0
+    ///
+    /// Interrupter 0 is moderated to at most one interrupt per
+    /// millisecond. The controller starts with interrupts disabled, for
+    /// hosts that only poll. Secondary interrupters are enabled through
+    /// their `InterrupterHandle`.
+    pub fn enable_interrupts(&self) {
+        self.interrupts.store(true, Ordering::Release);
+        let interrupter = self.regs.interrupter(0);
+        interrupter.imod().write(IMOD_INTERVAL);
+        self.trace_reg(interrupter.imod().addr(), IMOD_INTERVAL as u64);
+        let iman = interrupter.iman();
+        let val = (iman.read() | reg::IMAN_IE) & !reg::IMAN_IP;
+        iman.write(val);
+        self.trace_reg(iman.addr(), val as u64);
+        let usbcmd = self.regs.usbcmd();
+        let val = usbcmd.modify(|usbcmd| usbcmd | reg::USBCMD_INTE);
+        self.trace_reg(usbcmd.addr(), val as u64);
+    }
+
+    /// Disable interrupts: USBCMD.INTE and IMAN.IE of every interrupter
+    ///
+    /// Events are still delivered to the event rings and taken with
+    /// `poll_event`, which then acknowledges the pending bits itself.
+    pub fn disable_interrupts(&self) {
+        let usbcmd = self.regs.usbcmd();
+        let val = usbcmd.modify(|usbcmd| usbcmd & !reg::USBCMD_INTE);
+        self.trace_reg(usbcmd.addr(), val as u64);
+        for n in 0..self.caps.max_intrs {
+            let iman = self.regs.interrupter(n).iman();
+            iman.write(iman.read() & !(reg::IMAN_IE | reg::IMAN_IP));
+        }
+        self.regs.usbsts().clear(reg::USBSTS_EINT);
+        self.interrupts.store(false, Ordering::Release);
+    }
+
+    /// Get whether interrupts were enabled with `enable_interrupts`
+    pub fn interrupts_enabled(&self) -> bool {
+        self.interrupts.load(Ordering::Acquire)
+    }
+
+    /// Check if the controller was found removed
+    ///
+    /// Set once USBSTS reads back all ones, as it does after a hot
+    /// remove; every operation then fails with `ControllerGone`.
+    pub fn is_dead(&self) -> bool {
+        self.dead.load(Ordering::Acquire)
+    }
+
+    /// Fail with `ControllerGone` if the controller was removed
+    ///
+    /// Reads USBSTS, so waiters spinning on the controller notice the
+    /// removal on their next iteration.
+    pub(crate) fn check_alive(&self) -> Result<()> {
+        if self.is_dead() || self.regs.usbsts().is_gone() {
+            return Err(self.mark_dead());
+        }
+        Ok(())
+    }
+
+    /// Mark the controller removed and return the error to report
+    fn mark_dead(&self) -> UsbError {
+        if !self.dead.swap(true, Ordering::AcqRel) {
+            self.host.warn(format_args!("xHCI controller removed"));
+        }
+        UsbError::ControllerGone
+    }
+
+    /// Acknowledge an interrupt on interrupter 0, which carries the crate's events
+    ///
+    /// Returns true if the interrupter had an interrupt pending; its
+    /// events are then taken with `poll_event` and friends.
+    pub fn handle_interrupt(&self) -> bool {
+        self.handle_interrupt_on(0).unwrap_or(false)
+    }
+
+    /// Acknowledge an interrupt on interrupter `n`, e.g. in its MSI-X handler
+    ///
+    /// Clears USBSTS.EINT and the IMAN.IP bit of that interrupter only,
+    /// leaving the others pending. Returns true if `n` had an interrupt
+    /// pending. Fails with `InvalidArgument` unless `n` is below MaxIntrs.
+    pub fn handle_interrupt_on(&self, n: u16) -> Result<bool> {
+        let interrupter = self.interrupter(n)?;
+        self.regs.usbsts().clear(reg::USBSTS_EINT);
+        let pending = interrupter.pending();
+        if pending {
+            interrupter.ack();
+        }
+        Ok(pending)
+    }
+
+    /// Wait for command completion
+    pub fn wait_command(&self) -> Result<Trb> {
+        self.wait_command_where(|_| true)
+    }
+
+    /// Wait for the completion of a specific command
+    fn wait_command_where(&self, pred: impl Fn(&Trb) -> bool) -> Result<Trb> {
+        let trb = self.wait_event_where(
+            |e| e.trb_type() == trb_type::COMMAND_COMPLETION as u8 && pred(e),
+            COMMAND_TIMEOUT_US,
+        )?;
+
+        let code = trb.completion_code();
+        if code != completion::SUCCESS {
+            return Err(UsbError::CmdFail(code));
+        }
+        Ok(trb)
+    }
+
+    /// Check for an event without taking any lock
+    ///
+    /// Reads the cycle bit of the TRB at the last known dequeue position.
+    /// May report an event another caller is about to take, but never
+    /// misses one the controller has written.
+    fn event_ready(&self) -> bool {
+        let cursor = self.event_cursor.load(Ordering::Acquire);
+        let idx = (cursor >> 1) as usize;
+        let control = unsafe {
+            core::ptr::addr_of!((*(self.event_trbs as *const Trb).add(idx)).control)
+                .read_volatile()
+        };
+        (control & 1) == (cursor & 1)
+    }
+
+    /// Dequeue the next event and update ERDP
+    fn dequeue_event(&self) -> Option<Trb> {
+        if !self.event_ready() {
+            return None;
+        }
+
+        let mut event_ring = self.event_ring.lock();
+        let trb = event_ring.try_dequeue();
+        self.event_cursor.store(event_ring.cursor(), Ordering::Release);
+        drop(event_ring);
+
+        let trb = trb?;
+        self.update_erdp();
+        self.trace_event(&trb);
+
+        if trb.trb_type() == trb_type::MFINDEX_WRAP as u8 {
+            self.mfindex_wraps.fetch_add(1, Ordering::Relaxed);
+        }
+        if trb.trb_type() == trb_type::TRANSFER_EVENT as u8 {
+            self.count_completion(&trb);
+        }
+        if trb.trb_type() == trb_type::HOST_CONTROLLER_EVENT as u8
+            && trb.completion_code() == completion::EVENT_RING_FULL
+        {
+            let lost = self.events_lost.fetch_add(1, Ordering::AcqRel) + 1;
+            self.host.warn(format_args!(
+                "xHCI event ring full, events lost ({lost} overflows)"
+            ));
+        }
+        Some(trb)
+    }
+
+    /// Poll for transfer events (non-blocking)
+    pub fn poll_event(&self) -> Option<Trb> {
+        self.poll_event_where(|_| true)
+    }
+
+    /// Poll for the first event matching `pred` (non-blocking)
+    ///
+    /// Events dequeued while looking for a match are kept for later
+    /// callers, so several waiters (e.g. control transfers on different
+    /// slots) can share the event ring without losing each other's events.
+    /// When nothing is pending and the event ring is empty, no lock is
+    /// taken.
+    pub fn poll_event_where(&self, pred: impl Fn(&Trb) -> bool) -> Option<Trb> {
+        if self.pending_count.load(Ordering::Acquire) != 0 {
+            let mut pending = self.pending_events.lock();
+            if let Some(idx) = pending.iter().position(&pred) {
+                let trb = pending.remove(idx);
+                self.pending_count.store(pending.len(), Ordering::Release);
+                return trb;
+            }
+        }
+
+        while let Some(trb) = self.dequeue_event() {
+            if pred(&trb) {
+                return Some(trb);
+            }
+
+            let mut pending = self.pending_events.lock();
+            if pending.len() >= MAX_PENDING_EVENTS && !make_room(&mut pending) {
+                // Possibly another waiter's completion; make it give up
+                pending.pop_front();
+                let lost = self.events_lost.fetch_add(1, Ordering::AcqRel) + 1;
+                self.host.warn(format_args!(
+                    "xHCI pending event queue full, oldest event dropped ({lost} lost)"
+                ));
+            }
+            pending.push_back(trb);
+            self.pending_count.store(pending.len(), Ordering::Release);
+        }
+
+        None
+    }
+
+    /// Get the number of event losses since the controller started
+    ///
+    /// Counts the Event Ring Full errors reported by the controller, each
+    /// meaning it dropped events that were not dequeued in time, and the
+    /// events `poll_event_where` had to drop because no waiter took them.
+    /// Waiters fail with `EventLost` when it changes.
+    pub fn events_lost(&self) -> u64 {
+        self.events_lost.load(Ordering::Acquire)
+    }
+
+    /// Wait for the first event matching `pred`, up to `timeout_us` microseconds
+    ///
+    /// A `timeout_us` of 0 waits forever. Non-matching events are kept for
+    /// other waiters (see `poll_event_where`). Fails with `EventLost` if
+    /// the event ring overflows before a match arrives, as the match may
+    /// have been dropped.
+    pub fn wait_event_where(&self, pred: impl Fn(&Trb) -> bool, timeout_us: u32) -> Result<Trb> {
+        let mut watch = self.stopwatch();
+        let lost = self.events_lost();
+
+        loop {
+            if let Some(trb) = self.poll_event_where(&pred) {
+                return Ok(trb);
+            }
+            if self.events_lost() != lost {
+                return Err(UsbError::EventLost);
+            }
+            self.check_alive()?;
+            if timeout_us != 0 && watch.elapsed_us(self) >= timeout_us as u64 {
+                return Err(UsbError::Timeout);
+            }
+            spin_loop();
+        }
+    }
+
+    /// Wait for any event, up to `timeout_us` microseconds (0 waits forever)
+    pub fn poll_event_timeout(&self, timeout_us: u32) -> Result<Trb> {
+        self.wait_event_where(|_| true, timeout_us)
+    }
+
+    /// Submit a command TRB
+    ///
+    /// A command that does not complete in time is aborted, so the
+    /// commands queued after it can run.
+    pub fn submit_command(&self, trb: Trb) -> Result<Trb> {
+        self.check_alive()?;
+        let mut cmd_ring = self.cmd_ring.lock();
+        let addr = cmd_ring.enqueue(&*self.host, trb);
+        drop(cmd_ring);
+        self.trace_command(&trb);
+        self.ring_cmd_doorbell();
+        let result = self.wait_command_where(|e| e.param == addr);
+        if matches!(result, Err(UsbError::Timeout)) {
+            self.abort_command(addr);
+        }
+        result
+    }
+
+    /// Abort the command at `addr` with CRCR.CA and wait for the ring to stop
+    ///
+    /// Its Command Aborted completion and the Command Ring Stopped event
+    /// are taken off the event ring. The next doorbell restarts the ring
+    /// after the aborted command.
+    fn abort_command(&self, addr: u64) {
+        let crcr = self.regs.crcr();
+        crcr.abort();
+        self.trace_reg(crcr.addr(), reg::CRCR_CA);
+        if self
+            .wait_until(COMMAND_TIMEOUT_US, || !crcr.running())
+            .is_err()
+        {
+            self.host.warn(format_args!(
+                "xHCI command ring did not stop after an abort"
+            ));
+            return;
+        }
+
+        let completion = |e: &Trb| e.trb_type() == trb_type::COMMAND_COMPLETION as u8;
+        let _ = self.poll_event_where(|e| completion(e) && e.param == addr);
+        let _ = self.wait_event_where(
+            |e| completion(e) && e.completion_code() == completion::COMMAND_RING_STOPPED,
+            COMMAND_TIMEOUT_US,
+        );
+    }
+
+    /// Enable a device slot
+    ///
+    /// Never returns a reserved slot ID: a reserved slot picked by the
+    /// controller is kept enabled while Enable Slot is retried, then
+    /// disabled again.
+    pub fn enable_slot(&self) -> Result<u8> {
+        let mut held = SlotSet::default();
+        let result = loop {
+            let slot_id = match self.enable_slot_command() {
+                Ok(slot_id) => slot_id,
+                Err(e) => break Err(e),
+            };
+
+            let mut slots = self.slots.lock();
+            if !slots.reserved.contains(slot_id) {
+                slots.enabled.insert(slot_id);
+                break Ok(slot_id);
+            }
+            drop(slots);
+
+            // A controller handing out the same slot twice would never stop
+            if !held.insert(slot_id) {
+                break Err(UsbError::CmdFail(completion::NO_SLOTS_AVAILABLE));
+            }
+        };
+
+        for slot_id in held.iter() {
+            let _ = self.disable_slot_command(slot_id);
+        }
+        result
+    }
+
+    /// Disable a device slot
+    ///
+    /// Clears the slot's DCBAA entry once the slot is disabled. Valid
+    /// slot IDs are 1 to `max_slots`; others fail with `InvSlot`.
+    pub fn disable_slot(&self, slot_id: u8) -> Result<()> {
+        if slot_id == 0 || slot_id > self.caps.max_slots {
+            return Err(UsbError::InvSlot);
+        }
+
+        self.disable_slot_command(slot_id)?;
+        self.set_device_context(slot_id, 0)?;
+        self.slots.lock().enabled.remove(slot_id);
+        Ok(())
+    }
+
+    fn enable_slot_command(&self) -> Result<u8> {
+        let trb = Trb {
+            param: 0,
+            status: 0,
+            control: trb_type::ENABLE_SLOT << 10,
+        };
+        let evt = self.submit_command(trb)?;
+        Ok(evt.slot_id())
+    }
+
+    fn disable_slot_command(&self, slot_id: u8) -> Result<()> {
+        debug_assert!(slot_id != 0 && slot_id <= self.caps.max_slots);
+        let trb = Trb {
+            param: 0,
+            status: 0,
+            control: (trb_type::DISABLE_SLOT << 10) | ((slot_id as u32) << 24),
+        };
+        self.submit_command(trb)?;
+        Ok(())
+    }
+
+    /// Keep a slot ID from being returned by `enable_slot`
+    ///
+    /// Fails with `InvSlot` if the ID is out of range or the slot is
+    /// already enabled.
+    pub fn reserve_slot(&self, slot_id: u8) -> Result<()> {
+        if slot_id == 0 || slot_id > self.caps.max_slots {
+            return Err(UsbError::InvSlot);
+        }
+
+        let mut slots = self.slots.lock();
+        if slots.enabled.contains(slot_id) {
+            return Err(UsbError::InvSlot);
+        }
+        slots.reserved.insert(slot_id);
+        Ok(())
+    }
+
+    /// Make a reserved slot ID available to `enable_slot` again
+    pub fn release_slot(&self, slot_id: u8) {
+        self.slots.lock().reserved.remove(slot_id);
+    }
+
+    /// Count a Transfer Event against the pending transfers of its endpoint
+    fn count_completion(&self, evt: &Trb) {
+        let slot_id = evt.slot_id();
+        if slot_id == 0 {
+            return;
+        }
+        let health = self
+            .devices
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .find(|health| health.slot_id() == slot_id);
+        if let Some(health) = health {
+            health.transfer_completed(evt.endpoint_id());
+        }
+    }
+
+    /// Track a device for `release_all_devices` without keeping it alive
+    pub(crate) fn register_device(&self, health: &Arc<DeviceHealth>) {
+        let mut devices = self.devices.lock();
+        devices.retain(|device| device.strong_count() > 0);
+        devices.push(Arc::downgrade(health));
+    }
+
+    /// Invalidate every device of the controller, for an orderly shutdown
+    ///
+    /// Requests and transfers on the devices, their endpoint handles and
+    /// the class drivers on top fail with `Disconnected` from then on,
+    /// and their slots are disabled right away. A device's memory is
+    /// still freed only when its last `Arc` is dropped.
+    pub fn release_all_devices(&self) {
+        let devices = core::mem::take(&mut *self.devices.lock());
+        for device in devices.iter().filter_map(Weak::upgrade) {
+            device.release(self);
+        }
+    }
+
+    /// Take a snapshot of the devices of the controller as a tree
+    ///
+    /// Covers the `UsbDevice`s created on the controller that are still
+    /// alive and not released, by root port and hub port chain. The tree
+    /// is a copy: devices attached or detached afterwards do not change it.
+    pub fn topology(&self) -> TopologyNode {
+        let bus = self.bus_index();
+        let devices = self
+            .devices
+            .lock()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .filter(|health| !health.is_disconnected())
+            .map(|health| {
+                let (slot_id, summary, drivers) = health.snapshot();
+                TopologyDevice {
+                    path: DevicePath {
+                        bus,
+                        root_port: summary.root_port,
+                        route: summary.route,
+                    },
+                    slot_id,
+                    summary,
+                    drivers,
+                }
+            })
+            .collect();
+        TopologyNode::build(bus, devices)
+    }
+
+    /// Sum the periodic bandwidth of the configured endpoints
+    ///
+    /// Counts the interrupt and isochronous endpoints of the `UsbDevice`s
+    /// that are still alive and not released, by interval tier, with the
+    /// Max ESIT Payload the controller was given.
+    pub fn periodic_load(&self) -> LoadSummary {
+        let mut load = LoadSummary::default();
+        for health in self.devices.lock().iter().filter_map(Weak::upgrade) {
+            if !health.is_disconnected() {
+                health.add_periodic_load(&mut load);
+            }
+        }
+        load
+    }
+
+    /// Get the IDs of the slots enabled through `enable_slot`, in order
+    pub fn slots_in_use(&self) -> impl Iterator<Item = u8> + use<H> {
+        self.slots.lock().enabled.iter()
+    }
+
+    /// Get the physical address of a slot's Device Context
+    ///
+    /// Reads the slot's DCBAA entry; `None` if the ID is out of range or
+    /// no context is installed.
+    pub fn device_context_phys(&self, slot_id: u8) -> Option<u64> {
+        if slot_id == 0 || slot_id > self.caps.max_slots {
+            return None;
+        }
+
+        let phys = unsafe {
+            self.dcbaa
+                .as_ptr::<u64>()
+                .add(slot_id as usize)
+                .read_volatile()
+        };
+        (phys != 0).then_some(phys)
+    }
+
+    /// Reset a device slot back to the Default state after a port reset
+    ///
+    /// Valid slot IDs are 1 to `max_slots`; others fail with `InvSlot`.
+    pub fn reset_device(&self, slot_id: u8) -> Result<()> {
+        if slot_id == 0 || slot_id > self.caps.max_slots {
+            return Err(UsbError::InvSlot);
+        }
+
+        let trb = Trb {
+            param: 0,
+            status: 0,
+            control: (trb_type::RESET_DEVICE << 10) | ((slot_id as u32) << 24),
+        };
+        self.submit_command(trb)?;
+        Ok(())
+    }
+
+    /// Send a link management packet header on a USB3 root port (Force Header)
+    ///
+    /// `header` holds the three header dwords; the low 5 bits of the first
+    /// dword carry the packet type.
+    pub fn force_header(&self, port: u8, header: [u32; 3]) -> Result<()> {
+        self.check_port(port)?;
+
+        let trb = Trb {
+            param: header[0] as u64 | ((header[1] as u64) << 32),
+            status: header[2],
+            control: (trb_type::FORCE_HEADER << 10) | (((port + 1) as u32) << 24),
+        };
+        self.submit_command(trb)?;
+        Ok(())
+    }
+
+    /// Read port status
+    ///
+    /// Root ports are numbered from 0 to `max_ports - 1`; others fail
+    /// with `InvPort`.
+    pub fn port_status(&self, port: u8) -> Result<u32> {
+        self.check_port(port)?;
+
+        Ok(self.read_portsc(port))
+    }
+
+    /// Write port status (for clearing change bits, reset, etc.)
+    pub fn write_port_status(&self, port: u8, val: u32) -> Result<()> {
+        self.check_port(port)?;
+
+        let portsc = self.regs.portsc(port);
+        portsc.write(val);
+        self.trace_reg(portsc.addr(), val as u64);
+        Ok(())
+    }
+
+    /// Fail with `InvPort` unless `port` is a root port, and with
+    /// `ControllerGone` if the controller was removed
+    fn check_port(&self, port: u8) -> Result<()> {
+        if port >= self.caps.max_ports {
+            return Err(UsbError::InvPort);
+        }
+        self.check_alive()
+    }
+
+    /// Read PORTSC of a port already checked by the caller
+    ///
+    /// Reads as 0, i.e. nothing connected, once the controller is gone.
+    fn read_portsc(&self, port: u8) -> u32 {
+        debug_assert!(port < self.caps.max_ports);
+        if self.is_dead() {
+            return 0;
+        }
+        self.regs.portsc(port).read()
+    }
+
+    /// Set the indicator LED of a root port
+    ///
+    /// Requires Port Indicators support (HCCPARAMS1.PIND); `Auto` is only
+    /// meaningful on hub ports.
+    pub fn set_port_indicator(&self, port: u8, indicator: PortIndicator) -> Result<()> {
+        self.check_port(port)?;
+
+        if !self.caps.pind {
+            return Err(UsbError::NotSupported);
+        }
+        let pic = indicator.pic().ok_or(UsbError::NotSupported)?;
+
+        let portsc = self.regs.portsc(port);
+        let val = portsc.modify(|portsc| {
+            (portsc & reg::PORTSC_PRESERVE & !reg::PORTSC_PIC_MASK) | reg::portsc_pic(pic)
+        });
+        self.trace_reg(portsc.addr(), val as u64);
+        Ok(())
+    }
+
+    /// Disable a port
+    ///
+    /// The device stays attached but no longer sees any traffic until
+    /// the port is reset again.
+    pub fn disable_port(&self, port: u8) -> Result<()> {
+        self.check_port(port)?;
+
+        let portsc = self.regs.portsc(port);
+        let val = portsc.disable();
+        self.trace_reg(portsc.addr(), val as u64);
+        Ok(())
+    }
+
+    /// Reset a port
+    ///
+    /// USB3 ports whose link is stuck in SS.Inactive or Compliance Mode
+    /// only recover through a warm reset, which is used automatically.
+    /// Fails with `DeviceNotFound` if the device is gone after the reset.
+    pub fn reset_port(&self, port: u8) -> Result<()> {
+        self.check_port(port)?;
+
+        let change = self.start_port_reset(port);
+
+        // Wait for reset to complete
+        self.wait_until(PORT_RESET_TIMEOUT_US, || self.port_reset_done(port, change))?;
+
+        self.clear_port_reset_change(port);
+        if !self.port_connected(port) {
+            return Err(UsbError::DeviceNotFound);
+        }
+        Ok(())
+    }
+
+    /// Start a (warm, if needed) port reset and return the change bit to wait for
+    pub(crate) fn start_port_reset(&self, port: u8) -> u32 {
+        debug_assert!(port < self.caps.max_ports);
+        let mut change = reg::PORTSC_PRC;
+
+        let portsc = self.regs.portsc(port);
+        let val = portsc.modify(|portsc| {
+            let stuck = matches!(
+                LinkState::from_raw(reg::portsc_pls(portsc)),
+                LinkState::Inactive | LinkState::Compliance
+            );
+            let warm = stuck && matches!(self.port_protocol(port), Some((3, _)));
+            let reset = if warm {
+                change = reg::PORTSC_WRC;
+                reg::PORTSC_WPR
+            } else {
+                reg::PORTSC_PR
+            };
+
+            // Set port reset, preserve PP
+            (portsc & reg::PORTSC_PP) | reset
+        });
+        self.trace_reg(portsc.addr(), val as u64);
+
+        change
+    }
+
+    /// Returns true once a reset started by `start_port_reset` has completed
+    pub(crate) fn port_reset_done(&self, port: u8, change: u32) -> bool {
+        let portsc = self.read_portsc(port);
+        (portsc & reg::PORTSC_PR) == 0 && (portsc & change) != 0
+    }
+
+    /// Clear Connect Status Change
+    pub(crate) fn clear_connect_change(&self, port: u8) {
+        debug_assert!(port < self.caps.max_ports);
+        self.regs.portsc(port).clear_changes(reg::PORTSC_CSC);
+    }
+
+    /// Returns true if the connection of a port changed since the last clear
+    pub(crate) fn connect_changed(&self, port: u8) -> bool {
+        (self.read_portsc(port) & reg::PORTSC_CSC) != 0
+    }
+
+    /// Clear Port Reset Change (writing PED back as 1 would disable the port)
+    pub(crate) fn clear_port_reset_change(&self, port: u8) {
+        debug_assert!(port < self.caps.max_ports);
+        self.regs
+            .portsc(port)
+            .clear_changes(reg::PORTSC_PRC | reg::PORTSC_WRC);
+    }
+
+    /// Read the link state of a port
+    pub fn port_link_state(&self, port: u8) -> Result<LinkState> {
+        let portsc = self.port_status(port)?;
+        Ok(LinkState::from_raw(reg::portsc_pls(portsc)))
+    }
+
+    /// Request a link state transition (LWS-qualified PLS write)
+    pub fn set_port_link_state(&self, port: u8, state: LinkState) -> Result<()> {
+        self.check_port(port)?;
+
+        let portsc = self.regs.portsc(port);
+        let val = portsc.modify(|portsc| {
+            (portsc & reg::PORTSC_PRESERVE)
+                | reg::PORTSC_LWS
+                | reg::portsc_set_pls(state.raw() as u32)
+        });
+        self.trace_reg(portsc.addr(), val as u64);
+
+        Ok(())
+    }
+
+    /// Wait for a port to reach a link state, up to `timeout_us` microseconds
+    pub fn wait_link_state(&self, port: u8, state: LinkState, timeout_us: u32) -> Result<()> {
+        self.check_port(port)?;
+
+        self.wait_until(timeout_us, || {
+            LinkState::from_raw(reg::portsc_pls(self.read_portsc(port))) == state
+        })
+    }
+
+    /// Suspend a root port (link state U3 / L2)
+    ///
+    /// The device must have no transfers in flight.
+    pub fn suspend_port(&self, port: u8) -> Result<()> {
+        self.set_port_link_state(port, LinkState::U3)?;
+        self.wait_link_state(port, LinkState::U3, LINK_TRANSITION_US)
+    }
+
+    /// Resume a suspended root port
+    ///
+    /// Handles a port suspended by `suspend_port` as well as one the
+    /// device woke up with remote wakeup. Waits out the resume recovery
+    /// time before returning. A port already in U0 is left alone.
+    pub fn resume_port(&self, port: u8) -> Result<()> {
+        let state = self.port_link_state(port)?;
+        let usb2 = matches!(self.port_protocol(port), Some((2, _)));
+        match state {
+            LinkState::U0 => return Ok(()),
+            // USB2 ports drive resume signaling before returning to L0
+            LinkState::U3 if usb2 => {
+                self.set_port_link_state(port, LinkState::Resume)?;
+                self.host.delay_us(RESUME_SIGNAL_US);
+            }
+            // Remote wakeup already signaled resume on a USB2 port
+            LinkState::Resume if usb2 => self.host.delay_us(RESUME_SIGNAL_US),
+            _ => {}
+        }
+
+        self.set_port_link_state(port, LinkState::U0)?;
+        self.wait_link_state(port, LinkState::U0, LINK_TRANSITION_US)?;
+        self.regs.portsc(port).clear_changes(reg::PORTSC_PLC);
+        self.host.delay_us(RESUME_RECOVERY_US);
+        Ok(())
+    }
+
+    /// Read and decode the change bits of a port
+    ///
+    /// Only reads PORTSC; pass the result to `apply_overcurrent_policy`
+    /// to act on an over-current.
+    pub fn port_change(&self, port: u8) -> Result<PortChange> {
+        Ok(PortChange::from_portsc(self.port_status(port)?))
+    }
+
+    /// Apply the over-current policy to a change read by `port_change`
+    ///
+    /// Powers the port off if over-current is active and the policy is
+    /// `OvercurrentPolicy::PowerOff`. Returns true if the port was
+    /// powered off.
+    pub fn apply_overcurrent_policy(&self, port: u8, change: &PortChange) -> Result<bool> {
+        if !change.over_current_active || self.overcurrent_policy() != OvercurrentPolicy::PowerOff {
+            return Ok(false);
+        }
+        self.handle_overcurrent(port)?;
+        Ok(true)
+    }
+
+    /// Select how `apply_overcurrent_policy` reacts to over-current
+    ///
+    /// Hub drivers follow the same policy for their downstream ports.
+    pub fn set_overcurrent_policy(&self, policy: OvercurrentPolicy) {
+        self.oc_notify_only
+            .store(policy == OvercurrentPolicy::NotifyOnly, Ordering::Relaxed);
+    }
+
+    /// Returns the current over-current policy.
+    pub fn overcurrent_policy(&self) -> OvercurrentPolicy {
+        if self.oc_notify_only.load(Ordering::Relaxed) {
+            OvercurrentPolicy::NotifyOnly
+        } else {
+            OvercurrentPolicy::PowerOff
+        }
+    }
+
+    /// Remove power from a port after an over-current
+    ///
+    /// Requires Port Power Control (HCCPARAMS1.PPC). The Over-current
+    /// Change bit is left for `ack_port_change`.
+    pub fn handle_overcurrent(&self, port: u8) -> Result<()> {
+        self.check_port(port)?;
+
+        if !self.caps.ppc {
+            return Err(UsbError::NotSupported);
+        }
+
+        let portsc = self.regs.portsc(port);
+        let val = portsc.modify(|portsc| portsc & reg::PORTSC_PRESERVE & !reg::PORTSC_PP);
+        self.trace_reg(portsc.addr(), val as u64);
+        Ok(())
+    }
+
+    /// Switch the power of a root port (PORTSC.PP)
+    ///
+    /// Requires Port Power Control (HCCPARAMS1.PPC); fails with
+    /// `NotSupported` otherwise. A powered-off port shows no connection.
+    pub fn set_port_power(&self, port: u8, on: bool) -> Result<()> {
+        self.check_port(port)?;
+        if !self.caps.ppc {
+            return Err(UsbError::NotSupported);
+        }
+
+        let portsc = self.regs.portsc(port);
+        let val = portsc.modify(|portsc| {
+            let portsc = portsc & reg::PORTSC_PRESERVE & !reg::PORTSC_PP;
+            if on { portsc | reg::PORTSC_PP } else { portsc }
+        });
+        self.trace_reg(portsc.addr(), val as u64);
+        Ok(())
+    }
+
+    /// Power a port back on after an over-current shutdown
+    ///
+    /// Waits `cooldown_us` microseconds first, then re-applies power. If
+    /// over-current returns within 20 ms the port is powered off again
+    /// and `OverCurrent` is returned.
+    pub fn restore_port_power(&self, port: u8, cooldown_us: u32) -> Result<()> {
+        self.check_port(port)?;
+
+        let _ = self.wait_until(cooldown_us, || false);
+
+        self.regs
+            .portsc(port)
+            .modify(|portsc| (portsc & reg::PORTSC_PRESERVE) | reg::PORTSC_PP);
+
+        let oca = || (self.read_portsc(port) & reg::PORTSC_OCA) != 0;
+        if self.wait_until(20_000, oca).is_ok() {
+            self.handle_overcurrent(port)?;
+            return Err(UsbError::OverCurrent);
+        }
+        Ok(())
+    }
+
+    /// Acknowledge (clear) the change bits reported in `change`
+    pub fn ack_port_change(&self, port: u8, change: &PortChange) -> Result<()> {
+        self.check_port(port)?;
+
+        self.regs.portsc(port).clear_changes(change.portsc);
+        Ok(())
+    }
+
+    /// Program the U1/U2 inactivity timeouts of a USB3 port (PORTPMSC).
+    ///
+    /// `u1_timeout` is in microseconds and `u2_timeout` in 256 microsecond
+    /// units. A value of 0 disables initiating the state, 0xFF accepts the
+    /// state when the device requests it but never initiates it. Fails
+    /// with `NotSupported` on ports not covered by a USB3 Supported
+    /// Protocol capability.
+    pub fn set_port_u1u2_timeouts(&self, port: u8, u1_timeout: u8, u2_timeout: u8) -> Result<()> {
+        self.check_port(port)?;
+        if !matches!(self.port_protocol(port), Some((3, _))) {
+            return Err(UsbError::NotSupported);
+        }
+
+        let mask = reg::PORTPMSC_U1_TIMEOUT_MASK | reg::PORTPMSC_U2_TIMEOUT_MASK;
+        self.regs
+            .portpmsc(port)
+            .modify(|pmsc| (pmsc & !mask) | reg::portpmsc_u1u2(u1_timeout, u2_timeout));
+
+        Ok(())
+    }
+
+    /// Find the next extended capability with the given ID after `prev`
+    fn find_ext_cap(&self, id: u8, prev: Option<usize>) -> Option<usize> {
+        find_ext_cap(&self.regs, self.mmio_size, id, prev)
+    }
+
+    /// Find the Supported Protocol capability covering a port.
+    ///
+    /// Returns the protocol major revision and the ports dword.
+    fn port_protocol(&self, port: u8) -> Option<(u8, u32)> {
+        debug_assert!(port < self.caps.max_ports);
+        let mut cap = self.find_ext_cap(reg::ECAP_SUPPORTED_PROTOCOL, None);
+        while let Some(offset) = cap {
+            let header = self.regs.cap(offset).read();
+            let ports = self.regs.cap(offset + reg::SUPP_PROTO_PORTS).read();
+
+            // Compatible Port Offset is 1-based
+            let first = (ports & 0xff) as u16;
+            let count = ((ports >> 8) & 0xff) as u16;
+            let port_num = port as u16 + 1;
+            if port_num >= first && port_num < first + count {
+                return Some(((header >> 24) as u8, ports));
+            }
+
+            cap = self.find_ext_cap(reg::ECAP_SUPPORTED_PROTOCOL, Some(offset));
+        }
+        None
+    }
+
+    /// Root ports of a protocol major revision, in port order
+    fn protocol_ports(&self, major: u8) -> Vec<u8> {
+        let mut ports = Vec::new();
+        let mut cap = self.find_ext_cap(reg::ECAP_SUPPORTED_PROTOCOL, None);
+        while let Some(offset) = cap {
+            let header = self.regs.cap(offset).read();
+            let dw = self.regs.cap(offset + reg::SUPP_PROTO_PORTS).read();
+            if (header >> 24) as u8 == major {
+                // Compatible Port Offset is 1-based
+                let first = (dw & 0xff) as u16;
+                let count = ((dw >> 8) & 0xff) as u16;
+                let valid = 1..=self.caps.max_ports as u16;
+                let range = (first..first + count).filter(|port| valid.contains(port));
+                ports.extend(range.map(|port| (port - 1) as u8));
+            }
+            cap = self.find_ext_cap(reg::ECAP_SUPPORTED_PROTOCOL, Some(offset));
+        }
+        ports.sort_unstable();
+        ports
+    }
+
+    /// Get the port of the other protocol sharing a connector with `port`
+    ///
+    /// xHCI does not describe the pairing; the USB2 and USB3 ports are
+    /// matched by their position among the ports of their protocol, which
+    /// holds for the usual one-to-one layouts. `None` for ports without
+    /// a companion in that sense.
+    pub fn companion_port(&self, port: u8) -> Option<u8> {
+        if port >= self.caps.max_ports {
+            return None;
+        }
+        let (major, _) = self.port_protocol(port)?;
+        let other = match major {
+            2 => 3,
+            3 => 2,
+            _ => return None,
+        };
+        let index = self.protocol_ports(major).iter().position(|&p| p == port)?;
+        self.protocol_ports(other).get(index).copied()
+    }
+
+    /// Get the connected root ports with their protocol and speed
+    ///
+    /// Skips ports without Current Connect Status. `max_ports` counts the
+    /// USB2 and USB3 ports of a connector separately; the companion
+    /// fields let callers enumerate each connector once.
+    pub fn connected_ports(&self) -> impl Iterator<Item = PortSummary> + '_ {
+        (0..self.caps.max_ports)
+            .filter(|&port| self.port_connected(port))
+            .map(|port| {
+                let companion = self.companion_port(port);
+                PortSummary {
+                    port,
+                    protocol: self.port_protocol(port).map(|(major, _)| major),
+                    speed: Speed::from(reg::portsc_speed(self.read_portsc(port))),
+                    companion,
+                    companion_connected: companion.is_some_and(|c| self.port_connected(c)),
+                }
+            })
+    }
+
+    /// Enable USB2 hardware LPM (L1) on a root port.
+    ///
+    /// Requires the port's USB2 Supported Protocol capability to report
+    /// Hardware LPM Capability (HLC). `slot_id` is the slot of the device
+    /// attached to the port. `besl` is interpreted as BESL when the port
+    /// reports BLC, and as HIRD otherwise.
+    pub fn enable_usb2_lpm(&self, port: u8, slot_id: u8, besl: u8) -> Result<()> {
+        self.check_port(port)?;
+        if slot_id == 0 || slot_id > self.caps.max_slots {
+            return Err(UsbError::InvSlot);
+        }
+
+        match self.port_protocol(port) {
+            Some((2, ports)) if (ports & reg::SUPP_PROTO_HLC) != 0 => {}
+            _ => return Err(UsbError::NotSupported),
+        }
+
+        // L1 Timeout of 512us, no deep BESL
+        let mask = reg::PORTHLPMC_HIRDM_MASK
+            | reg::PORTHLPMC_L1_TIMEOUT_MASK
+            | reg::PORTHLPMC_BESLD_MASK;
+        self.regs
+            .porthlpmc(port)
+            .modify(|hlpmc| (hlpmc & !mask) | (2 << 2));
+
+        let mask = reg::PORTPMSC_RWE
+            | reg::PORTPMSC_BESL_MASK
+            | reg::PORTPMSC_L1DS_MASK
+            | reg::PORTPMSC_HLE;
+        self.regs
+            .portpmsc(port)
+            .modify(|pmsc| (pmsc & !mask) | reg::portpmsc_hle(slot_id, besl));
+
+        Ok(())
+    }
+
+    /// Disable USB2 hardware LPM (L1) on a root port.
+    pub fn disable_usb2_lpm(&self, port: u8) -> Result<()> {
+        self.check_port(port)?;
+
+        let mask = reg::PORTPMSC_RWE | reg::PORTPMSC_L1DS_MASK | reg::PORTPMSC_HLE;
+        self.regs.portpmsc(port).modify(|pmsc| pmsc & !mask);
+
+        Ok(())
+    }
+
+    /// Get port speed (after device is connected and port is enabled)
+    pub fn port_speed(&self, port: u8) -> Result<Speed> {
+        let portsc = self.port_status(port)?;
+        Ok(Speed::from(((portsc >> 10) & 0xf) as u8))
+    }
+
+    /// Read the current microframe index (MFINDEX, 14 bits)
+    pub fn microframe_index(&self) -> u16 {
+        let mfindex = self.regs.mfindex().read();
+        (mfindex & reg::MFINDEX_MASK) as u16
+    }
+
+    /// Read the current frame number (MFINDEX / 8, 11 bits)
+    pub fn frame_number(&self) -> u16 {
+        self.microframe_index() >> 3
+    }
+
+    /// Returns the first frame an isochronous TD can be scheduled in that
+    /// lines up with a device's synchronization frame.
+    ///
+    /// `synch` is the frame returned by `UsbDevice::synch_frame` and
+    /// `pattern` the length of the device's repeating packet size pattern
+    /// in frames (0 is taken as 1). The frame lies past the Isochronous
+    /// Scheduling Threshold, so it can be passed to
+    /// `EndpointHandle::queue_isoch` right away.
+    pub fn next_start_frame(&self, synch: u16, pattern: u16) -> u16 {
+        start_frame_after(self.frame_number(), self.caps.ist, synch, pattern)
+    }
+
+    /// Enable or disable MFINDEX Wrap Events (USBCMD.EWE)
+    ///
+    /// With wrap events enabled, every MFINDEX rollover observed through
+    /// `poll_event` extends the software microframe counter returned by
+    /// `microframe_counter`.
+    pub fn set_wrap_events(&self, enable: bool) {
+        let usbcmd = self.regs.usbcmd();
+        let val = usbcmd.modify(|usbcmd| {
+            if enable {
+                usbcmd | reg::USBCMD_EWE
+            } else {
+                usbcmd & !reg::USBCMD_EWE
+            }
+        });
+        self.trace_reg(usbcmd.addr(), val as u64);
+    }
+
+    /// Returns the 32-bit software-extended microframe counter.
+    ///
+    /// Never goes backwards while it is read, or the event ring polled
+    /// with wrap events enabled, at least once per wrap period (2.048 s).
+    /// A rollover seen before its MFINDEX Wrap event is dequeued is
+    /// counted right away; the event then adds nothing.
+    pub fn microframe_counter(&self) -> u32 {
+        let mut result = 0;
+        // MFINDEX is read after the previous reading was loaded, so a
+        // smaller value means it wrapped, even if the event is pending
+        let _ = self
+            .mfindex_last
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |last| {
+                let wraps = self.mfindex_wraps.load(Ordering::Relaxed);
+                let raw = self.microframe_index() as u32;
+                let counted = (wraps << 14) | raw;
+                let mut sampled = (last & !reg::MFINDEX_MASK) | raw;
+                if raw < last & reg::MFINDEX_MASK {
+                    sampled = sampled.wrapping_add(1 << 14);
+                }
+                result = if (counted.wrapping_sub(sampled) as i32) > 0 {
+                    counted
+                } else {
+                    sampled
+                };
+                Some(result)
+            });
+        result
+    }
+
+    /// Spin until `cond` holds or `timeout_us` microseconds elapse.
+    ///
+    /// Time is measured with MFINDEX, so the controller must be running.
+    pub(crate) fn wait_until(&self, timeout_us: u32, mut cond: impl FnMut() -> bool) -> Result<()> {
+        let mut watch = self.stopwatch();
+
+        loop {
+            if cond() {
+                return Ok(());
+            }
+            self.check_alive()?;
+            if watch.elapsed_us(self) >= timeout_us as u64 {
+                return Err(UsbError::Timeout);
+            }
+            spin_loop();
+        }
+    }
+
+    /// Start an MFINDEX-based stopwatch
+    pub(crate) fn stopwatch(&self) -> Stopwatch {
+        Stopwatch {
+            last: self.microframe_index(),
+            elapsed_us: 0,
+        }
+    }
+
+    /// Check if device is connected on port
+    ///
+    /// A port number of `max_ports` or above is never connected.
+    pub fn port_connected(&self, port: u8) -> bool {
+        port < self.caps.max_ports && (self.read_portsc(port) & reg::PORTSC_CCS) != 0
+    }
+
+    /// Get the root ports whose connection is stable
+    ///
+    /// Clears Connect Status Change on every connected port, waits for
+    /// the 100 ms attach debounce interval and keeps the ports still
+    /// connected with no new connect change. A transient connection seen
+    /// during link training is skipped; its change bit stays set.
+    pub fn debounced_ports(&self) -> Vec<u8> {
+        let connected: Vec<u8> = (0..self.caps.max_ports)
+            .filter(|&port| self.port_connected(port))
+            .collect();
+        if connected.is_empty() {
+            return connected;
+        }
+
+        for &port in &connected {
+            self.clear_connect_change(port);
+        }
+        self.host.delay_us(CONNECT_DEBOUNCE_US);
+
+        connected
+            .into_iter()
+            .filter(|&port| self.port_connected(port) && !self.connect_changed(port))
+            .collect()
+    }
+
+    /// Set device context in DCBAA
+    ///
+    /// Valid slot IDs are 1 to `max_slots`; others fail with `InvSlot`.
+    pub fn set_device_context(&self, slot: u8, phys: u64) -> Result<()> {
+        if slot == 0 || slot > self.caps.max_slots {
+            return Err(UsbError::InvSlot);
+        }
+
+        unsafe {
+            self.dcbaa
+                .as_ptr::<u64>()
+                .add(slot as usize)
+                .write_volatile(phys);
+        }
+        Ok(())
+    }
+
+    /// Get the quirks the controller was created with
+    pub fn quirks(&self) -> XhciQuirks {
+        self.quirks
+    }
+
+    /// Check if DMA memory must be allocated below 4 GiB
+    pub fn dma32(&self) -> bool {
+        self.dma32
+    }
+
+    /// Set the bus number reported in the `DevicePath` of every device
+    ///
+    /// Lets the caller tell controllers apart; 0 by default.
+    pub fn set_bus_index(&self, index: u8) {
+        self.bus_index.store(index, Ordering::Relaxed);
+    }
+
+    /// Get the bus number set with `set_bus_index`
+    pub fn bus_index(&self) -> u8 {
+        self.bus_index.load(Ordering::Relaxed)
+    }
+
+    /// Get the EP0 ring size, in TRBs, used for newly enumerated devices
+    pub fn ep0_ring_size(&self) -> usize {
+        self.ep0_ring_size.load(Ordering::Relaxed)
+    }
+
+    /// Set the EP0 ring size, in TRBs, used for newly enumerated devices
+    ///
+    /// Devices that are already addressed keep their ring.
+    pub fn set_ep0_ring_size(&self, trbs: usize) {
+        self.ep0_ring_size.store(trbs, Ordering::Relaxed);
+    }
+
+    /// Get the largest configuration or BOS descriptor set read from a device
+    pub fn max_descriptor_len(&self) -> usize {
+        self.max_descriptor_len.load(Ordering::Relaxed)
+    }
+
+    /// Set the largest configuration or BOS descriptor set read from a device
+    ///
+    /// Devices claiming a longer wTotalLength fail with `InvalidDescriptor`
+    /// instead of having the claimed size allocated.
+    pub fn set_max_descriptor_len(&self, bytes: usize) {
+        self.max_descriptor_len.store(bytes, Ordering::Relaxed);
+    }
 
-/// xHCI Controller
-pub struct XhciCtrl<H: Dma> {
-    mmio: usize,
-    mmio_size: usize,
-    cap_length: u8,
-    op_base: usize,
-    rt_base: usize,
-    db_offset: u32,
-    max_slots: u8,
-    max_ports: u8,
-    dcbaa: PhysMem<H>,
-    scratchpad: Option<PhysMem<H>>,
-    cmd_ring: Mutex<Box<Ring<H>>>,
-    event_ring: Mutex<Box<EventRing<H>>>,
-    host: Arc<H>,
-}
+    /// Register a workaround for devices with the quirk's VID/PID
+    ///
+    /// Applied to devices whose device descriptor is read afterwards. A
+    /// quirk for the same VID/PID replaces the earlier one.
+    pub fn add_device_quirk(&self, quirk: DeviceQuirk) {
+        let mut quirks = self.device_quirks.lock();
+        quirks.retain(|q| (q.vendor_id, q.product_id) != (quirk.vendor_id, quirk.product_id));
+        quirks.push(quirk);
+    }
 
-impl<H: Dma> XhciCtrl<H> {
-    /// Create and initialize a new xHCI controller
-    pub fn new(mmio_phys: usize, host: H) -> Result<Self> {
-        let host = Arc::new(host);
+    /// Get the registered quirk matching a device descriptor
+    pub fn device_quirk(&self, desc: &DeviceDesc) -> Option<DeviceQuirk> {
+        self.device_quirks
+            .lock()
+            .iter()
+            .find(|q| q.matches(desc))
+            .copied()
+    }
 
-        // Initial map to read capability registers
-        let init_mmio = unsafe {
-            host.map_mmio(mmio_phys, MMIO_INIT_SIZE)
-        }.ok_or(UsbError::MapFail)?;
+    /// Allocate DMA memory the controller can address
+    pub fn alloc_mem(&self, size: usize, align: usize) -> Result<PhysMem<H>> {
+        PhysMem::alloc_in(&*self.host, size, align, self.dma32)
+    }
 
-        let cap_length = unsafe { (init_mmio as *const u8).read_volatile() };
-        let hcs1: u32 = unsafe { ((init_mmio + reg::HCSPARAMS1) as *const u32).read_volatile() };
-        let hcs2: u32 = unsafe { ((init_mmio + reg::HCSPARAMS2) as *const u32).read_volatile() };
-        let db_offset: u32 = unsafe { ((init_mmio + reg::DBOFF) as *const u32).read_volatile() };
-        let rts_offset: u32 = unsafe { ((init_mmio + reg::RTSOFF) as *const u32).read_volatile() };
+    /// Get host reference
+    pub fn host(&self) -> &H {
+        &self.host
+    }
 
-        let max_slots = (hcs1 & 0xff) as u8;
-        let max_ports = ((hcs1 >> 24) & 0xff) as u8;
-        let max_scratchpad = ((hcs2 >> 27) & 0x1f) | (((hcs2 >> 21) & 0x1f) << 5);
+    /// Get the shared host, e.g. to start another controller on it
+    pub fn shared_host(&self) -> Arc<H> {
+        self.host.clone()
+    }
 
-        // Calculate total MMIO size needed
-        let mmio_size = (rts_offset as usize + 0x20 + 0x20)
-            .max(db_offset as usize + (max_slots as usize + 1) * 4)
-            .max(0x10000);
+    /// Get max slots
+    pub fn max_slots(&self) -> u8 {
+        self.caps.max_slots
+    }
 
-        unsafe {
-            host.unmap_mmio(init_mmio, MMIO_INIT_SIZE);
-        }
+    /// Get max ports
+    pub fn max_ports(&self) -> u8 {
+        self.caps.max_ports
+    }
 
-        // Remap with full size
-        let mmio = unsafe {
-            host.map_mmio(mmio_phys, mmio_size)
-        }.ok_or(UsbError::MapFail)?;
+    /// Get the limits and features read from the capability registers
+    pub fn capabilities(&self) -> Capabilities {
+        self.caps
+    }
 
-        let op_base = mmio + cap_length as usize;
-        let rt_base = mmio + rts_offset as usize;
+    /// Get the Debug Capability, if the controller has one
+    ///
+    /// Its registers lie within the controller's MMIO mapping, at
+    /// `dbc_regs`. The crate does not drive the DbC itself.
+    pub fn dbc(&self) -> Option<DbcInfo> {
+        find_dbc(&self.regs, self.mmio_size)
+    }
 
-        // Allocate DCBAA (Device Context Base Address Array)
-        // xHCI spec requires 64-byte alignment for DCBAA
-        let dcbaa = PhysMem::alloc(&*host, (max_slots as usize + 1) * 8, 64)?;
+    /// Get the virtual address of the Debug Capability registers
+    ///
+    /// The register offsets are `regs::DBC_*`. Accesses must be volatile.
+    pub fn dbc_regs(&self) -> Option<usize> {
+        self.dbc().map(|dbc| self.mmio + dbc.offset)
+    }
 
-        // Allocate scratchpad if needed
-        let scratchpad = if max_scratchpad > 0 {
-            // xHCI spec requires 64-byte alignment for scratchpad array
-            let sp_array = PhysMem::alloc(&*host, max_scratchpad as usize * 8, 64)?;
-            // Scratchpad buffers must be page-aligned
-            let sp_bufs = PhysMem::alloc(
-                &*host,
-                max_scratchpad as usize * host.page_size(),
-                host.page_size(),
-            )?;
-
-            // Fill scratchpad array with buffer addresses
-            let array_ptr = sp_array.as_ptr::<u64>();
-            for i in 0..max_scratchpad as usize {
-                let buf_phys = sp_bufs.phys(&*host) + (i * host.page_size()) as u64;
-                unsafe {
-                    array_ptr.add(i).write_volatile(buf_phys);
-                }
+    /// Get the interface version (HCIVERSION) as (major, minor)
+    ///
+    /// The minor version is in hundredths: 0.96 is (0, 96), 1.1 is (1, 10).
+    pub fn hci_version(&self) -> (u8, u8) {
+        self.caps.hci_version
+    }
+
+    /// Get the bytes moved before a transfer of `requested` bytes was stopped
+    ///
+    /// For the Stopped Transfer Event of an aborted transfer. `None` if
+    /// the event is not one, or the controller lacks Stopped EDTLA, as
+    /// pre-1.0 controllers leave the length undefined.
+    pub fn stopped_transferred(&self, evt: &Trb, requested: usize) -> Option<usize> {
+        match evt.completion_code() {
+            completion::STOPPED | completion::STOPPED_SHORT_PACKET if self.caps.sec => {
+                Some(evt.transferred(requested))
             }
+            _ => None,
+        }
+    }
+}
 
-            // Point DCBAA[0] to scratchpad array
-            unsafe {
-                dcbaa.as_ptr::<u64>().write_volatile(sp_array.phys(&*host));
+/// First frame past the Isochronous Scheduling Threshold `ist`, as of
+/// frame `now`, that lies a multiple of `pattern` frames after `synch`
+///
+/// Frame numbers wrap at 2048, so for patterns not dividing 2048 the
+/// phase is taken from the most recent `synch` frame.
+fn start_frame_after(now: u16, ist: u8, synch: u16, pattern: u16) -> u16 {
+    // IST in microframes, rounded up to frames, plus the current frame
+    let lead = if ist & 0x8 != 0 {
+        (ist & 0x7) as u16 + 1
+    } else {
+        ((ist & 0x7) as u16).div_ceil(8) + 1
+    };
+    let earliest = (now + lead) & 0x7ff;
+    let pattern = pattern.max(1);
+    let offset = earliest.wrapping_sub(synch) & 0x7ff;
+    let wait = (pattern - offset % pattern) % pattern;
+    earliest.wrapping_add(wait) & 0x7ff
+}
+
+/// Find the next extended capability with the given ID after `prev`
+fn find_ext_cap(
+    regs: &RegisterBlock,
+    mmio_size: usize,
+    id: u8,
+    prev: Option<usize>,
+) -> Option<usize> {
+    let mut offset = match prev {
+        Some(prev) => {
+            let next = (regs.cap(prev).read() >> 8) & 0xff;
+            if next == 0 {
+                return None;
             }
+            prev + ((next as usize) << 2)
+        }
+        None => reg::hccparams1_xecp(regs.cap(reg::HCCPARAMS1).read()),
+    };
 
-            // Keep sp_bufs alive, sp_array is referenced via DCBAA[0]
-            Some(sp_bufs)
-        } else {
-            None
-        };
+    while offset != 0 && offset + 4 <= mmio_size {
+        let cap = regs.cap(offset).read();
+        if (cap & 0xff) as u8 == id {
+            return Some(offset);
+        }
+        let next = (cap >> 8) & 0xff;
+        if next == 0 {
+            break;
+        }
+        offset += (next as usize) << 2;
+    }
 
-        // Allocate rings on heap to reduce stack usage
-        let cmd_ring = Box::new(Ring::new(&*host, CMD_RING_SIZE)?);
-        let event_ring = Box::new(EventRing::new(&*host, EVENT_RING_SIZE)?);
+    None
+}
 
-        let mut ctrl = Self {
-            mmio,
-            mmio_size,
-            cap_length,
-            op_base,
-            rt_base,
-            db_offset,
-            max_slots,
-            max_ports,
-            dcbaa,
-            scratchpad,
-            cmd_ring: Mutex::new(cmd_ring),
-            event_ring: Mutex::new(event_ring),
-            host,
+/// End of the last extended capability, as far as the chain can be
+/// followed within `mmio_size`
+///
+/// A chain continuing past `mmio_size` ends at least 4 bytes after it,
+/// so a larger mapping reveals the next header.
+fn ext_caps_end(regs: &RegisterBlock, mmio_size: usize) -> usize {
+    let mut end = 0;
+    let mut offset = reg::hccparams1_xecp(regs.cap(reg::HCCPARAMS1).read());
+    while offset != 0 {
+        if offset + 4 > mmio_size {
+            return end.max(offset + 4);
+        }
+        let cap = regs.cap(offset).read();
+        let size = match (cap & 0xff) as u8 {
+            reg::ECAP_USB_DEBUG => reg::DBC_SIZE,
+            reg::ECAP_USB_LEGACY => reg::USBLEG_CTLSTS + 4,
+            reg::ECAP_SUPPORTED_PROTOCOL if offset + reg::SUPP_PROTO_PORTS + 4 <= mmio_size => {
+                let psic = regs.cap(offset + reg::SUPP_PROTO_PORTS).read() >> 28;
+                reg::SUPP_PROTO_SIZE + psic as usize * 4
+            }
+            reg::ECAP_SUPPORTED_PROTOCOL => reg::SUPP_PROTO_SIZE,
+            _ => 4,
         };
+        end = end.max(offset + size);
 
-        ctrl.init()?;
-        Ok(ctrl)
+        let next = (cap >> 8) & 0xff;
+        if next == 0 {
+            break;
+        }
+        offset += (next as usize) << 2;
     }
+    end
+}
 
-    fn init(&mut self) -> Result<()> {
-        // Stop controller if running
-        let usbcmd = self.read_op::<u32>(reg::USBCMD);
-        if (usbcmd & reg::USBCMD_RUN) != 0 {
-            self.write_op(reg::USBCMD, usbcmd & !reg::USBCMD_RUN);
-            while (self.read_op::<u32>(reg::USBSTS) & reg::USBSTS_HCH) == 0 {
-                spin_loop();
-            }
+/// Decode the Debug Capability, if the controller has one
+fn find_dbc(regs: &RegisterBlock, mmio_size: usize) -> Option<DbcInfo> {
+    let offset = find_ext_cap(regs, mmio_size, reg::ECAP_USB_DEBUG, None)?;
+    if offset + reg::DBC_SIZE > mmio_size {
+        return None;
+    }
+    let read = |reg: usize| regs.cap(offset + reg).read();
+    Some(DbcInfo::from_regs(
+        offset,
+        read(0),
+        read(reg::DBC_DCCTRL),
+        read(reg::DBC_DCST),
+        read(reg::DBC_DCDDI1),
+        read(reg::DBC_DCDDI2),
+    ))
+}
+
+impl<H: Dma> Drop for XhciCtrl<H> {
+    fn drop(&mut self) {
+        // A removed controller has no registers left to stop
+        let mut halted = true;
+        if self.check_alive().is_ok() {
+            // Stop controller
+            self.regs
+                .usbcmd()
+                .modify(|usbcmd| usbcmd & !reg::USBCMD_RUN);
+
+            // Wait for halt; a removed controller reads as halted
+            let usbsts = self.regs.usbsts();
+            halted = self
+                .handshake(HALT_TIMEOUT_US, || usbsts.contains(reg::USBSTS_HCH))
+                .is_ok();
         }
 
-        // Reset controller
-        self.write_op(reg::USBCMD, reg::USBCMD_HCRST);
-        while (self.read_op::<u32>(reg::USBCMD) & reg::USBCMD_HCRST) != 0 {
-            spin_loop();
+        // A controller still running may write to its rings
+        if halted {
+            let host = &*self.host;
+            // Freed in place like `UsbDevice`'s EP0 ring; none of these
+            // has a Drop, so the stale copies left behind are harmless
+            unsafe {
+                core::ptr::read(&self.dcbaa).free(host);
+                if let Some(scratchpad) = core::ptr::read(&self.scratchpad) {
+                    scratchpad.free(host);
+                }
+                core::ptr::read(&**self.cmd_ring.get_mut()).free(host);
+                core::ptr::read(&**self.event_ring.get_mut()).free(host);
+            }
+        } else {
+            self.host.warn(format_args!(
+                "xHCI controller did not halt; leaking its memory"
+            ));
         }
-        while (self.read_op::<u32>(reg::USBSTS) & reg::USBSTS_CNR) != 0 {
-            spin_loop();
+
+        // Unmap MMIO
+        unsafe {
+            self.host.unmap_mmio(self.mmio, self.mmio_size);
         }
+    }
+}
 
-        // Configure controller
-        self.write_op(reg::CONFIG, self.max_slots as u32);
-        self.write_op(reg::DCBAAP, self.dcbaa.phys(&*self.host));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock;
 
-        // Setup command ring
-        let cmd_ring = self.cmd_ring.lock();
-        let crcr = cmd_ring.phys(&*self.host) | 1; // RCS = 1
-        self.write_op(reg::CRCR, crcr);
-        drop(cmd_ring);
+    fn event(ty: u32, param: u64) -> Trb {
+        Trb {
+            param,
+            status: (completion::SUCCESS as u32) << 24,
+            control: ty << 10,
+        }
+    }
 
-        // Setup event ring
-        let event_ring = self.event_ring.lock();
-        let int_base = reg::interrupter_base(self.rt_base as u32 - self.mmio as u32, 0);
+    /// Fill the pending queue with `count` events nobody waits for
+    fn flood(
+        ctrl: &XhciCtrl<mock::MockHost>,
+        mock: &mock::Mock,
+        count: usize,
+        ev: impl Fn(usize) -> Trb,
+    ) {
+        for i in 0..count {
+            mock.inject_event(ev(i));
+        }
+        while ctrl.poll_event_where(|_| false).is_some() {}
+    }
 
-        self.write_reg(int_base + reg::ERSTSZ, 1u32);
-        self.write_reg(int_base + reg::ERSTBA, event_ring.erst_phys(&*self.host));
-        self.write_reg(int_base + reg::ERDP, event_ring.ring_phys(&*self.host));
-        drop(event_ring);
+    #[test]
+    fn timed_out_command_is_aborted() {
+        let (ctrl, mock) = mock::controller();
+        let noop = event(trb_type::NO_OP_CMD, 0);
+        mock.hang_commands(true);
+        let result = ctrl.submit_command(noop);
+        assert!(matches!(result, Err(UsbError::Timeout)));
 
-        // Enable interrupts and start controller
-        self.write_op(reg::USBCMD, reg::USBCMD_RUN | reg::USBCMD_INTE);
+        // The ring runs again from the command after the aborted one
+        mock.hang_commands(false);
+        assert!(ctrl.submit_command(noop).is_ok());
+        assert_eq!(mock.commands(), [trb_type::NO_OP_CMD]);
+        assert!(ctrl.poll_event().is_none());
+    }
 
-        // Wait for controller to be ready
-        while (self.read_op::<u32>(reg::USBSTS) & reg::USBSTS_HCH) != 0 {
-            spin_loop();
+    #[test]
+    fn event_ring_full_fails_waiters() {
+        let (ctrl, mock) = mock::controller();
+        // Nobody dequeues until the controller runs out of room
+        for _ in 0..EVENT_RING_SIZE + 16 {
+            mock.inject_event(event(trb_type::MFINDEX_WRAP, 0));
         }
+        assert!(mock.events_dropped() > 16);
 
-        Ok(())
+        let result = ctrl.wait_event_where(|e| e.param == 0x1000, 1000);
+        assert!(matches!(result, Err(UsbError::EventLost)));
+        assert_eq!(ctrl.events_lost(), 1);
+        let warnings = mock.warnings();
+        assert!(warnings.iter().any(|w| w.contains("event ring full")));
+
+        // The ring accepts events again once it was drained
+        mock.inject_event(event(trb_type::TRANSFER_EVENT, 0x1000));
+        assert!(ctrl.wait_event_where(|e| e.param == 0x1000, 1000).is_ok());
     }
 
-    fn read_reg<T: Copy>(&self, offset: usize) -> T {
-        unsafe { ((self.mmio + offset) as *const T).read_volatile() }
+    #[test]
+    fn full_pending_queue_evicts_redundant_events() {
+        let (ctrl, mock) = mock::controller();
+        let waited = event(trb_type::TRANSFER_EVENT, 0x1000);
+        mock.inject_event(waited);
+        flood(&ctrl, &mock, MAX_PENDING_EVENTS, |i| {
+            if i % 2 == 0 {
+                event(trb_type::MFINDEX_WRAP, 0)
+            } else {
+                event(trb_type::PORT_STATUS_CHANGE, 1 << 24)
+            }
+        });
+
+        assert_eq!(ctrl.events_lost(), 0);
+        let found = ctrl.poll_event_where(|e| e.param == 0x1000);
+        assert!(found.is_some(), "waited-for event was evicted");
+        // The latest change of the port is still there
+        let port = ctrl.poll_event_where(|e| e.trb_type() == trb_type::PORT_STATUS_CHANGE as u8);
+        assert_eq!(port.map(|e| e.port_id()), Some(1));
     }
 
-    fn write_reg<T: Copy>(&self, offset: usize, val: T) {
-        unsafe {
-            ((self.mmio + offset) as *mut T).write_volatile(val);
+    #[test]
+    fn full_pending_queue_reports_dropped_events() {
+        let (ctrl, mock) = mock::controller();
+        // Another waiter's completion, already taken off the event ring
+        mock.inject_event(event(trb_type::TRANSFER_EVENT, 0x1000));
+        assert!(ctrl.poll_event_where(|_| false).is_none());
+
+        for i in 0..MAX_PENDING_EVENTS {
+            mock.inject_event(event(trb_type::TRANSFER_EVENT, 0x2000 + 16 * i as u64));
         }
+        let result = ctrl.wait_event_where(|e| e.param == 0x100, 1000);
+        assert!(matches!(result, Err(UsbError::EventLost)));
+        assert_eq!(ctrl.events_lost(), 1);
+        assert!(ctrl.poll_event_where(|e| e.param == 0x1000).is_none());
     }
 
-    fn read_op<T: Copy>(&self, offset: usize) -> T {
-        self.read_reg(self.op_base - self.mmio + offset)
+    #[test]
+    fn overcurrent_power_off_is_explicit() {
+        let (ctrl, mock) = mock::controller();
+        mock.set_overcurrent(1, true);
+
+        // Reading the change leaves the port powered
+        let change = ctrl.port_change(1).unwrap();
+        assert!(change.over_current && change.over_current_active);
+        assert_ne!(ctrl.read_portsc(1) & reg::PORTSC_PP, 0);
+
+        ctrl.set_overcurrent_policy(OvercurrentPolicy::NotifyOnly);
+        assert!(!ctrl.apply_overcurrent_policy(1, &change).unwrap());
+        assert_ne!(ctrl.read_portsc(1) & reg::PORTSC_PP, 0);
+
+        ctrl.set_overcurrent_policy(OvercurrentPolicy::PowerOff);
+        assert!(ctrl.apply_overcurrent_policy(1, &change).unwrap());
+        assert_eq!(ctrl.read_portsc(1) & reg::PORTSC_PP, 0);
     }
 
-    fn write_op<T: Copy>(&self, offset: usize, val: T) {
-        self.write_reg(self.op_base - self.mmio + offset, val)
+    #[test]
+    fn microframe_counter_counts_pending_wraps() {
+        let (ctrl, mock) = mock::controller();
+        ctrl.set_wrap_events(true);
+        while ctrl.poll_event().is_some() {}
+        let start = ctrl.microframe_counter();
+
+        // Read every second across five wraps, dequeuing the wrap events late
+        let mut last = start;
+        for i in 0..10 {
+            mock.advance_us(1_000_000);
+            let now = ctrl.microframe_counter();
+            assert!(now > last, "{now:#x} after {last:#x}");
+            if i % 3 == 2 {
+                while ctrl.poll_event().is_some() {}
+            }
+            last = now;
+        }
+        while ctrl.poll_event().is_some() {}
+        let elapsed = ctrl.microframe_counter() - start;
+        assert!((80_000..80_100).contains(&elapsed), "{elapsed}");
+
+        // Without wrap events the readings alone keep it going
+        ctrl.set_wrap_events(false);
+        let start = ctrl.microframe_counter();
+        for _ in 0..10 {
+            mock.advance_us(1_000_000);
+            ctrl.microframe_counter();
+        }
+        let elapsed = ctrl.microframe_counter() - start;
+        assert!((80_000..80_100).contains(&elapsed), "{elapsed}");
     }
 
-    /// Ring the command doorbell
-    fn ring_cmd_doorbell(&self) {
-        let db = reg::doorbell(self.db_offset, 0);
-        self.write_reg(db, 0u32);
+    #[test]
+    fn start_frame_follows_ist_and_pattern() {
+        // IST of 0 microframes: the next frame; 4 microframes round up
+        assert_eq!(start_frame_after(100, 0, 0, 1), 101);
+        assert_eq!(start_frame_after(100, 4, 0, 1), 102);
+        // IST of 2 frames, aligned to a pattern of 8 from frame 0
+        assert_eq!(start_frame_after(100, 0x8 | 2, 0, 8), 104);
+        // A pattern of 0 is taken as 1
+        assert_eq!(start_frame_after(100, 0, 7, 0), 101);
     }
 
-    /// Ring device doorbell
-    pub fn ring_doorbell(&self, slot: u8, target: u8) {
-        let db = reg::doorbell(self.db_offset, slot);
-        self.write_reg(db, target as u32);
+    #[test]
+    fn start_frame_wraps_at_2048() {
+        assert_eq!(start_frame_after(2046, 0x8 | 1, 2040, 4), 0);
+        assert_eq!(start_frame_after(2045, 0x8 | 1, 2040, 4), 0);
+        assert_eq!(start_frame_after(2047, 0, 0, 1), 0);
     }
 
-    /// Update event ring dequeue pointer
-    fn update_erdp(&self) {
-        let event_ring = self.event_ring.lock();
-        let int_base = reg::interrupter_base(self.rt_base as u32 - self.mmio as u32, 0);
-        self.write_reg(
-            int_base + reg::ERDP,
-            event_ring.dequeue_ptr(&*self.host) | 0x8,
-        );
+    #[test]
+    fn start_frame_with_patterns_not_dividing_2048() {
+        // 12 is 60 frames after 2000, across the wrap
+        assert_eq!(start_frame_after(10, 0, 2000, 3), 12);
+        assert_eq!(start_frame_after(2040, 0, 2041, 5), 2041);
+        // Synch 2042 is ahead of 2041, so it was 2048 frames earlier
+        assert_eq!(start_frame_after(2040, 0, 2042, 5), 2044);
+        // No overflow with patterns longer than the frame counter
+        assert_eq!(start_frame_after(0, 0, 0, u16::MAX), 2047);
     }
 
-    /// Wait for command completion
-    pub fn wait_command(&self) -> Result<Trb> {
-        loop {
-            let trb = {
-                let mut event_ring = self.event_ring.lock();
-                event_ring.try_dequeue()
-            };
+    #[test]
+    fn root_port_numbers_are_checked() {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(mock::MAX_PORTS - 1, mock::keyboard());
+        let last = mock::MAX_PORTS - 1;
+        assert!(ctrl.port_status(last).is_ok());
+        assert!(ctrl.port_connected(last));
 
-            if let Some(trb) = trb {
-                self.update_erdp();
+        for port in [mock::MAX_PORTS, u8::MAX] {
+            let inv = |r: Result<()>| matches!(r, Err(UsbError::InvPort));
+            assert!(inv(ctrl.port_status(port).map(drop)));
+            assert!(inv(ctrl.write_port_status(port, 0)));
+            assert!(inv(ctrl.port_link_state(port).map(drop)));
+            assert!(inv(ctrl.port_speed(port).map(drop)));
+            assert!(inv(ctrl.port_change(port).map(drop)));
+            assert!(inv(ctrl.ack_port_change(port, &PortChange::default())));
+            assert!(inv(ctrl.reset_port(port)));
+            assert!(inv(ctrl.enable_usb2_lpm(port, 1, 0)));
+            assert!(!ctrl.port_connected(port));
+        }
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+    }
 
-                if trb.trb_type() == trb_type::COMMAND_COMPLETION as u8 {
-                    let code = trb.completion_code();
-                    if code != completion::SUCCESS {
-                        return Err(UsbError::CmdFail(code));
-                    }
-                    return Ok(trb);
-                }
-            }
+    #[test]
+    fn u1u2_timeouts_need_a_usb3_port() {
+        // Ports 0-1 are USB2, ports 2-3 USB3
+        let mock = mock::Mock::new();
+        mock.set_protocols(&[(2, 0, 2, 0), (3, 2, 2, 0)]);
+        let (ctrl, mock) = mock::controller_with_quirks(mock, XhciQuirks::empty());
 
-            spin_loop();
+        for port in [0, 1] {
+            let r = ctrl.set_port_u1u2_timeouts(port, 0x7f, 0x3f);
+            assert!(matches!(r, Err(UsbError::NotSupported)));
         }
+        let pmsc = mock::CAP_LENGTH + 0x404;
+        assert!(!mock.register_writes().iter().any(|&(off, _)| off == pmsc));
+
+        ctrl.set_port_u1u2_timeouts(2, 0x7f, 0x3f).unwrap();
+        let pmsc = pmsc + 0x10 * 2;
+        let write = mock
+            .register_writes()
+            .into_iter()
+            .rfind(|&(off, _)| off == pmsc);
+        assert_eq!(write, Some((pmsc, reg::portpmsc_u1u2(0x7f, 0x3f) as u64)));
+
+        // Without any Supported Protocol capability the port is unknown
+        let (ctrl, _mock) = mock::controller();
+        let r = ctrl.set_port_u1u2_timeouts(2, 0x7f, 0x3f);
+        assert!(matches!(r, Err(UsbError::NotSupported)));
     }
 
-    /// Poll for transfer events (non-blocking)
-    pub fn poll_event(&self) -> Option<Trb> {
-        let mut event_ring = self.event_ring.lock();
-        let trb = event_ring.try_dequeue();
-        drop(event_ring);
-        if trb.is_some() {
-            self.update_erdp();
+    #[test]
+    fn slot_ids_are_checked() {
+        let (ctrl, mock) = mock::controller();
+        for slot in [0, mock::MAX_SLOTS + 1, u8::MAX] {
+            let inv = |r: Result<()>| matches!(r, Err(UsbError::InvSlot));
+            assert!(inv(ctrl.disable_slot(slot)), "slot {slot}");
+            assert!(inv(ctrl.reset_device(slot)), "slot {slot}");
+            assert!(inv(ctrl.set_device_context(slot, 0)), "slot {slot}");
+            assert!(inv(ctrl.enable_usb2_lpm(0, slot, 0)), "slot {slot}");
         }
-        trb
+        // Refused before any command reaches the controller
+        assert!(mock.commands().is_empty());
     }
 
-    /// Submit a command TRB
-    pub fn submit_command(&self, trb: Trb) -> Result<Trb> {
-        let mut cmd_ring = self.cmd_ring.lock();
-        cmd_ring.enqueue(&*self.host, trb);
-        drop(cmd_ring);
-        self.ring_cmd_doorbell();
-        self.wait_command()
+    // Locks are not Sync with `single-threaded`
+    #[cfg(not(feature = "single-threaded"))]
+    #[test]
+    fn idle_poll_takes_no_lock() {
+        let (ctrl, mock) = mock::controller();
+        let event_ring = ctrl.event_ring.lock();
+        let pending = ctrl.pending_events.lock();
+
+        // Would spin forever on either lock
+        let poller = std::thread::spawn({
+            let ctrl = ctrl.clone();
+            move || ctrl.poll_event().is_none()
+        });
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !poller.is_finished() {
+            assert!(std::time::Instant::now() < deadline, "idle poll blocked");
+            std::thread::yield_now();
+        }
+        assert!(poller.join().unwrap());
+        drop((event_ring, pending));
+
+        // A waiting event still goes through the ring
+        mock.inject_event(event(trb_type::NO_OP_CMD, 0));
+        assert!(ctrl.poll_event().is_some());
     }
 
-    /// Enable a device slot
-    pub fn enable_slot(&self) -> Result<u8> {
-        let trb = Trb {
-            param: 0,
-            status: 0,
-            control: trb_type::ENABLE_SLOT << 10,
+    #[test]
+    fn startup_register_writes() {
+        let (ctrl, mock) = mock::controller();
+        let (op, ir0) = (mock::CAP_LENGTH, mock::IR0);
+        let writes = mock.register_writes();
+        let offsets: Vec<usize> = writes.iter().map(|&(offset, _)| offset).collect();
+        let expected = [
+            op + reg::USBCMD,
+            op + reg::CONFIG,
+            op + reg::DCBAAP,
+            op + reg::CRCR,
+            ir0 + reg::ERSTSZ,
+            ir0 + reg::ERSTBA,
+            ir0 + reg::ERDP,
+            op + reg::USBCMD,
+        ];
+        assert_eq!(offsets[..expected.len()], expected);
+        assert_eq!(writes[0].1, reg::USBCMD_HCRST as u64);
+        assert_eq!(writes[1].1, mock::MAX_SLOTS as u64);
+        assert_eq!(writes[2].1, ctrl.dcbaa.phys(ctrl.host()));
+        assert_eq!(writes[3].1 & reg::CRCR_RCS, reg::CRCR_RCS);
+        assert_eq!(writes[4].1, 1);
+        assert_eq!(writes[7].1, reg::USBCMD_RUN as u64);
+
+        // Then the stale change bits of every root port are cleared
+        let ports: Vec<usize> = (0..mock::MAX_PORTS as usize)
+            .map(|port| op + 0x400 + 0x10 * port)
+            .collect();
+        assert_eq!(offsets[expected.len()..], ports);
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn startup_register_writes_are_traced() {
+        let (ctrl, mock) = mock::controller();
+        let mut entries = [TraceEntry::default(); 16];
+        let count = ctrl.trace_snapshot(&mut entries);
+        let traced: Vec<(usize, u64)> = entries[..count]
+            .iter()
+            .filter(|e| e.kind == TraceKind::Register)
+            .map(|e| (e.status as usize, e.param))
+            .collect();
+
+        // HCRST, DCBAAP, CRCR, ERSTSZ, ERSTBA, ERDP and RUN, as actually
+        // written
+        assert_eq!(traced.len(), 7);
+        let mut writes = mock.register_writes().into_iter();
+        for write in traced {
+            assert!(
+                writes.any(|w| w == write),
+                "{write:x?} not written in order"
+            );
+        }
+    }
+
+    #[test]
+    fn enabling_interrupts_writes_imod_iman_and_inte() {
+        let (ctrl, mock) = mock::controller();
+        let (op, ir0) = (mock::CAP_LENGTH, mock::IR0);
+        let start = mock.register_writes().len();
+        assert!(!ctrl.interrupts_enabled());
+
+        ctrl.enable_interrupts();
+        assert_eq!(
+            mock.register_writes()[start..],
+            [
+                (ir0 + reg::IMOD, IMOD_INTERVAL as u64),
+                (ir0 + reg::IMAN, reg::IMAN_IE as u64),
+                (
+                    op + reg::USBCMD,
+                    (reg::USBCMD_RUN | reg::USBCMD_INTE) as u64
+                ),
+            ]
+        );
+        assert!(ctrl.interrupts_enabled());
+    }
+
+    #[cfg(feature = "trace")]
+    #[test]
+    fn interrupt_startup_is_traced() {
+        let (ctrl, mock) = mock::controller();
+        ctrl.enable_interrupts();
+        let mut entries = [TraceEntry::default(); 16];
+        let count = ctrl.trace_snapshot(&mut entries);
+        let traced: Vec<(usize, u64)> = entries[..count]
+            .iter()
+            .filter(|e| e.kind == TraceKind::Register)
+            .map(|e| (e.status as usize, e.param))
+            .collect();
+
+        // The event ring is set up before the controller runs, and the
+        // interrupter before USBCMD.INTE
+        let (op, ir0) = (mock::CAP_LENGTH, mock::IR0);
+        let offsets: Vec<usize> = traced.iter().map(|&(offset, _)| offset).collect();
+        assert_eq!(
+            offsets[3..],
+            [
+                ir0 + reg::ERSTSZ,
+                ir0 + reg::ERSTBA,
+                ir0 + reg::ERDP,
+                op + reg::USBCMD,
+                ir0 + reg::IMOD,
+                ir0 + reg::IMAN,
+                op + reg::USBCMD,
+            ]
+        );
+        assert_eq!(traced[3].1, 1);
+        assert_eq!(traced[6].1, reg::USBCMD_RUN as u64);
+        assert_eq!(traced[7].1, IMOD_INTERVAL as u64);
+        assert_eq!(traced[8].1, reg::IMAN_IE as u64);
+        assert_eq!(traced[9].1, (reg::USBCMD_RUN | reg::USBCMD_INTE) as u64);
+
+        let mut writes = mock.register_writes().into_iter();
+        for write in traced {
+            assert!(
+                writes.any(|w| w == write),
+                "{write:x?} not written in order"
+            );
+        }
+    }
+
+    #[test]
+    fn reset_delay_quirk_waits_after_hcrst() {
+        let (_ctrl, plain) = mock::controller();
+        let quirk = XhciQuirks::RESET_DELAY;
+        let (_ctrl, delayed) = mock::controller_with_quirks(mock::Mock::new(), quirk);
+        assert!(delayed.now_us() >= plain.now_us() + 1000);
+
+        // Nothing else about the start-up sequence changes
+        let offsets = |mock: &mock::Mock| -> Vec<usize> {
+            mock.register_writes()
+                .iter()
+                .map(|&(offset, _)| offset)
+                .collect()
         };
-        let evt = self.submit_command(trb)?;
-        Ok(evt.slot_id())
+        assert_eq!(offsets(&delayed), offsets(&plain));
     }
 
-    /// Disable a device slot
-    pub fn disable_slot(&self, slot_id: u8) -> Result<()> {
-        let trb = Trb {
-            param: 0,
-            status: 0,
-            control: (trb_type::DISABLE_SLOT << 10) | ((slot_id as u32) << 24),
+    #[test]
+    fn intel_port_switch_routes_ports_to_xhci() {
+        let shared_ports = || {
+            let mock = mock::Mock::new();
+            mock.set_pci_config(reg::PCI_USB3PRM, 0x0f);
+            mock.set_pci_config(reg::PCI_USB2PRM, 0x3fff);
+            mock
         };
-        self.submit_command(trb)?;
-        Ok(())
+        let quirk = XhciQuirks::INTEL_PORT_SWITCH;
+        let (_ctrl, mock) = mock::controller_with_quirks(shared_ports(), quirk);
+        assert_eq!(mock.pci_config(reg::PCI_USB3_PSSEN), Some(0x0f));
+        assert_eq!(mock.pci_config(reg::PCI_XUSB2PR), Some(0x3fff));
+
+        let (_ctrl, mock) = mock::controller_with_quirks(shared_ports(), XhciQuirks::empty());
+        assert_eq!(mock.pci_config(reg::PCI_USB3_PSSEN), None);
+        assert_eq!(mock.pci_config(reg::PCI_XUSB2PR), None);
     }
 
-    /// Read port status
-    pub fn port_status(&self, port: u8) -> u32 {
-        let offset = reg::port_reg_base(self.cap_length, port);
-        self.read_reg(offset)
+    #[test]
+    fn quirks_from_pci_ids() {
+        let intel = XhciQuirks::from_pci_ids(reg::PCI_VENDOR_INTEL, 0x1e31);
+        assert_eq!(
+            intel,
+            XhciQuirks::RESET_DELAY | XhciQuirks::INTEL_PORT_SWITCH
+        );
+        let intel = XhciQuirks::from_pci_ids(reg::PCI_VENDOR_INTEL, 0xa36d);
+        assert_eq!(intel, XhciQuirks::RESET_DELAY);
+        let asmedia = XhciQuirks::from_pci_ids(reg::PCI_VENDOR_ASMEDIA, 0x2142);
+        assert_eq!(asmedia, XhciQuirks::BROKEN_64BIT_DMA);
+        assert_eq!(
+            XhciQuirks::from_pci_ids(0x1033, 0x0194),
+            XhciQuirks::empty()
+        );
     }
 
-    /// Write port status (for clearing change bits, reset, etc.)
-    pub fn write_port_status(&self, port: u8, val: u32) {
-        let offset = reg::port_reg_base(self.cap_length, port);
-        self.write_reg(offset, val);
+    #[test]
+    fn debounce_waits_for_a_stable_connection() {
+        let (ctrl, mock) = mock::controller();
+        for port in [0, 1, 3] {
+            mock.attach(port, mock::keyboard());
+        }
+        let start = mock.now_us();
+        // Port 1 drops out and comes back within the window, port 2 shows
+        // a connection during link training only, port 3 bounces just
+        // before the window ends
+        mock.flap_port(1, &[start + 30_000, start + 60_000]);
+        mock.flap_port(2, &[start, start + 5_000]);
+        mock.flap_port(3, &[start + 99_000, start + 99_500]);
+
+        assert_eq!(ctrl.debounced_ports(), [0]);
+        assert!(mock.now_us() - start >= CONNECT_DEBOUNCE_US as u64);
+
+        // Once stable for a full interval, the ports count again
+        let start = mock.now_us();
+        assert_eq!(ctrl.debounced_ports(), [0, 1, 3]);
+        assert!(mock.now_us() - start >= CONNECT_DEBOUNCE_US as u64);
     }
 
-    /// Reset a port
-    pub fn reset_port(&self, port: u8) -> Result<()> {
-        let offset = reg::port_reg_base(self.cap_length, port);
-        let portsc: u32 = self.read_reg(offset);
+    /// HCIVERSION as read, decoded version, then whether Stopped EDTLA and
+    /// Extended TBC are used when the controller reports them
+    const VERSIONS: [(u16, (u8, u8), bool, bool); 4] = [
+        (0x0096, (0, 96), false, false),
+        (0x0100, (1, 0), true, false),
+        (0x0110, (1, 10), true, true),
+        (0x0120, (1, 20), true, true),
+    ];
 
-        // Set port reset, preserve PP, clear change bits
-        let val = (portsc & reg::PORTSC_PP) | reg::PORTSC_PR;
-        self.write_reg(offset, val);
+    #[test]
+    fn hci_version_decoding() {
+        let hcc1 = reg::HCCPARAMS1_SEC;
+        let hcc2 = reg::HCCPARAMS2_ETC | reg::HCCPARAMS2_ETC_TSC;
+        for (raw, version, sec, etc) in VERSIONS {
+            // CAPLENGTH in the low byte, HCIVERSION in the upper half
+            let cap = 0x20 | (raw as u32) << 16;
+            assert_eq!(reg::hci_version(cap), version, "{raw:#06x}");
+            let caps = Capabilities::from_regs(cap, 0, 0, 0, hcc1, hcc2);
+            assert_eq!(caps.hci_version, version);
+            assert_eq!(
+                (caps.sec, caps.etc, caps.etc_tsc),
+                (sec, etc, etc),
+                "{raw:#06x}"
+            );
 
-        // Wait for reset to complete
-        loop {
-            let portsc: u32 = self.read_reg(offset);
-            if (portsc & reg::PORTSC_PR) == 0 {
-                break;
-            }
-            spin_loop();
+            // Without the capability bits nothing is used on any version
+            let caps = Capabilities::from_regs(cap, 0, 0, 0, 0, 0);
+            assert!(!caps.sec && !caps.etc && !caps.etc_tsc);
         }
+        assert!((0, 96) < (1, 0) && (1, 0) < (1, 10) && (1, 10) < (1, 20));
+    }
 
-        // Clear Port Reset Change
-        let portsc: u32 = self.read_reg(offset);
-        self.write_reg(offset, portsc | reg::PORTSC_PRC);
+    #[test]
+    fn hci_version_gates_features() {
+        let stopped = Trb {
+            param: 0,
+            status: (completion::STOPPED as u32) << 24 | 100,
+            control: trb_type::TRANSFER_EVENT << 10,
+        };
+        for (raw, version, sec, etc) in VERSIONS {
+            let mock = mock::Mock::new();
+            mock.set_capabilities(
+                raw,
+                reg::HCCPARAMS1_SEC,
+                reg::HCCPARAMS2_ETC | reg::HCCPARAMS2_ETC_TSC,
+            );
+            let (ctrl, mock) = mock::controller_with_quirks(mock, XhciQuirks::empty());
+            assert_eq!(ctrl.hci_version(), version);
 
-        Ok(())
+            // Extended TBC is enabled with the controller
+            let usbcmd = mock::CAP_LENGTH + reg::USBCMD;
+            let run = mock
+                .register_writes()
+                .into_iter()
+                .rev()
+                .find(|&(offset, val)| offset == usbcmd && val as u32 & reg::USBCMD_RUN != 0)
+                .unwrap()
+                .1 as u32;
+            assert_eq!(run & reg::USBCMD_ETE != 0, etc, "{raw:#06x}");
+
+            // Stopped lengths are only trusted with Stopped EDTLA
+            let expected = sec.then_some(412);
+            assert_eq!(ctrl.stopped_transferred(&stopped, 512), expected);
+
+            let warned = mock
+                .warnings()
+                .iter()
+                .any(|w| w.contains("predates xHCI 1.0"));
+            assert_eq!(warned, version < (1, 0), "{raw:#06x}");
+        }
     }
 
-    /// Get port speed (after device is connected and port is enabled)
-    pub fn port_speed(&self, port: u8) -> u8 {
-        let portsc = self.port_status(port);
-        ((portsc >> 10) & 0xf) as u8
+    /// Run every step of the bring-up on `mock` with `config`
+    fn start(
+        mock: &mock::Mock,
+        config: XhciConfig,
+    ) -> core::result::Result<XhciCtrl<mock::MockHost>, StartError> {
+        XhciBuilder::map(mock::MOCK_PHYS, mock::MockHost::new(mock.clone()))
+            .unwrap()
+            .with_config(config)
+            .take_ownership()
+            .unwrap()
+            .reset_and_start()
     }
 
-    /// Check if device is connected on port
-    pub fn port_connected(&self, port: u8) -> bool {
-        (self.port_status(port) & reg::PORTSC_CCS) != 0
+    #[test]
+    fn start_fails_at_reset() {
+        let mock = mock::Mock::new();
+        mock.hang_reset(true);
+        let begin = mock.now_us();
+        let err = start(&mock, XhciConfig::default()).err().unwrap();
+        assert_eq!(err.step, StartStep::Reset);
+        assert!(matches!(err.error, UsbError::Timeout));
+        assert!(mock.now_us() - begin >= RESET_TIMEOUT_US as u64);
+        assert_eq!(mock.live_allocations(), 0);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
     }
 
-    /// Set device context in DCBAA
-    pub fn set_device_context(&self, slot: u8, phys: u64) {
-        unsafe {
-            self.dcbaa
-                .as_ptr::<u64>()
-                .add(slot as usize)
-                .write_volatile(phys);
+    #[test]
+    fn start_fails_at_dcbaa() {
+        // DCBAA, then the scratchpad array and buffers
+        for after in 0..3 {
+            let mock = mock::Mock::new();
+            mock.set_max_scratchpad(4);
+            mock.fail_allocation(after);
+            let err = start(&mock, XhciConfig::default()).err().unwrap();
+            assert_eq!(err.step, StartStep::Dcbaa, "allocation {after}");
+            assert!(matches!(err.error, UsbError::OoRam));
+            assert_eq!(mock.allocations(), after + 1);
+            assert_eq!(mock.live_allocations(), 0, "allocation {after}");
+            // Nothing was written to the controller
+            assert!(mock.register_writes().is_empty());
         }
+
+        // Started, DCBAA[0] points to the scratchpad array
+        let mock = mock::Mock::new();
+        mock.set_max_scratchpad(4);
+        let ctrl = start(&mock, XhciConfig::default()).unwrap();
+        let scratchpad = ctrl.scratchpad.as_ref().unwrap();
+        let entry0 = unsafe { ctrl.dcbaa.as_ptr::<u64>().read_volatile() };
+        assert_eq!(entry0, scratchpad.array.phys(ctrl.host()));
     }
 
-    /// Get host reference
-    pub fn host(&self) -> &H {
-        &self.host
+    #[test]
+    fn start_fails_at_command_ring() {
+        let mock = mock::Mock::new();
+        mock.set_max_scratchpad(4);
+        mock.fail_allocation(3);
+        let err = start(&mock, XhciConfig::default()).err().unwrap();
+        assert_eq!(err.step, StartStep::CommandRing);
+        assert!(matches!(err.error, UsbError::OoRam));
+        assert_eq!(mock.live_allocations(), 0);
+
+        let mock = mock::Mock::new();
+        let config = XhciConfig {
+            cmd_ring_size: 1,
+            ..XhciConfig::default()
+        };
+        let err = start(&mock, config).err().unwrap();
+        assert_eq!(err.step, StartStep::CommandRing);
+        assert!(matches!(err.error, UsbError::InvalidArgument));
+        assert_eq!(mock.live_allocations(), 0);
     }
 
-    /// Get max slots
-    pub fn max_slots(&self) -> u8 {
-        self.max_slots
+    #[test]
+    fn start_fails_at_event_ring() {
+        // The event ring, then its segment table
+        for after in [4, 5] {
+            let mock = mock::Mock::new();
+            mock.set_max_scratchpad(4);
+            mock.fail_allocation(after);
+            let err = start(&mock, XhciConfig::default()).err().unwrap();
+            assert_eq!(err.step, StartStep::EventRing, "allocation {after}");
+            assert!(matches!(err.error, UsbError::OoRam));
+            assert_eq!(mock.live_allocations(), 0, "allocation {after}");
+        }
+
+        let mock = mock::Mock::new();
+        let config = XhciConfig {
+            event_ring_size: 8,
+            ..XhciConfig::default()
+        };
+        let err = start(&mock, config).err().unwrap();
+        assert_eq!(err.step, StartStep::EventRing);
+        assert!(matches!(err.error, UsbError::InvalidArgument));
+        assert_eq!(mock.live_allocations(), 0);
     }
 
-    /// Get max ports
-    pub fn max_ports(&self) -> u8 {
-        self.max_ports
+    #[test]
+    fn start_fails_at_run() {
+        let mock = mock::Mock::new();
+        mock.set_max_scratchpad(4);
+        mock.hang_start(true);
+        let err = start(&mock, XhciConfig::default()).err().unwrap();
+        assert_eq!(err.step, StartStep::Run);
+        assert!(matches!(err.error, UsbError::Timeout));
+        assert_eq!(
+            alloc::format!("{err}"),
+            "controller run failed: operation timed out"
+        );
+        // Left halted, with its memory freed
+        let usbcmd = mock::CAP_LENGTH + reg::USBCMD;
+        let last = mock
+            .register_writes()
+            .into_iter()
+            .rev()
+            .find(|w| w.0 == usbcmd);
+        assert_eq!(last, Some((usbcmd, 0)));
+        assert_eq!(mock.live_allocations(), 0);
+        assert!(mock.violations().is_empty(), "{:?}", mock.violations());
+
+        // A started controller frees its memory when dropped
+        let mock = mock::Mock::new();
+        mock.set_max_scratchpad(4);
+        let ctrl = start(&mock, XhciConfig::default()).unwrap();
+        assert_eq!(mock.live_allocations(), 6);
+        drop(ctrl);
+        assert_eq!(mock.live_allocations(), 0);
     }
-}
 
-impl<H: Dma> Drop for XhciCtrl<H> {
-    fn drop(&mut self) {
-        // Stop controller
-        let usbcmd = self.read_op::<u32>(reg::USBCMD);
-        self.write_op(reg::USBCMD, usbcmd & !reg::USBCMD_RUN);
+    /// Register writes from `from` on, by register name, with event ring
+    /// dequeue pointers made relative to the ring
+    fn named_writes(
+        ctrl: &XhciCtrl<mock::MockHost>,
+        mock: &mock::Mock,
+        from: usize,
+    ) -> Vec<(alloc::string::String, u64)> {
+        let (op, ir0) = (mock::CAP_LENGTH, mock::IR0);
+        let ring = ctrl.event_trbs as u64;
+        mock.register_writes()[from..]
+            .iter()
+            .map(|&(offset, val)| match offset {
+                o if o == op + reg::USBCMD => ("USBCMD".into(), val),
+                o if o == op + reg::USBSTS => ("USBSTS".into(), val),
+                o if o == ir0 + reg::IMAN => ("IMAN".into(), val),
+                o if o == ir0 + reg::ERDP => ("ERDP".into(), val - ring),
+                o if (op + 0x400..op + 0x800).contains(&o) => {
+                    let name = ["PORTSC", "PORTPMSC", "PORTLI", "PORTHLPMC"][(o % 0x10) / 4];
+                    (alloc::format!("{name}{}", (o - op - 0x400) / 0x10), val)
+                }
+                o if o >= mock::DB_OFFSET => {
+                    (alloc::format!("DB{}", (o - mock::DB_OFFSET) / 4), val)
+                }
+                o => (alloc::format!("{o:#x}"), val),
+            })
+            .collect()
+    }
 
-        // Wait for halt
-        while (self.read_op::<u32>(reg::USBSTS) & reg::USBSTS_HCH) == 0 {
-            spin_loop();
-        }
+    /// Port reset: clear the change bits, then set PR keeping only the
+    /// power bit
+    const PORT_RESET_TRACE: &[(&str, u64)] = &[("PORTSC0", 0x210), ("PORTSC0", 0x280200)];
 
-        // Unmap MMIO
-        unsafe {
-            self.host.unmap_mmio(self.mmio, self.mmio_size);
-        }
+    /// Enumeration of a boot keyboard: port reset, two commands on
+    /// doorbell 0, then the control transfers on EP0
+    const ENUMERATION_TRACE: &[(&str, u64)] = &[
+        ("PORTSC0", 0x210),
+        ("PORTSC0", 0x280200),
+        ("DB0", 0x0),
+        ("ERDP", 0x18),
+        ("USBSTS", 0x8),
+        ("IMAN", 0x1),
+        ("ERDP", 0x28),
+        ("USBSTS", 0x8),
+        ("ERDP", 0x38),
+        ("USBSTS", 0x8),
+        ("DB0", 0x0),
+        ("ERDP", 0x48),
+        ("USBSTS", 0x8),
+        ("IMAN", 0x1),
+        ("DB1", 0x1),
+        ("ERDP", 0x58),
+        ("USBSTS", 0x8),
+        ("IMAN", 0x1),
+        ("ERDP", 0x68),
+        ("USBSTS", 0x8),
+        ("DB1", 0x1),
+        ("ERDP", 0x78),
+        ("USBSTS", 0x8),
+        ("IMAN", 0x1),
+        ("ERDP", 0x88),
+        ("USBSTS", 0x8),
+        ("DB1", 0x1),
+        ("ERDP", 0x98),
+        ("USBSTS", 0x8),
+        ("IMAN", 0x1),
+        ("ERDP", 0xa8),
+        ("USBSTS", 0x8),
+        ("DB1", 0x1),
+        ("ERDP", 0xb8),
+        ("USBSTS", 0x8),
+        ("IMAN", 0x1),
+    ];
+
+    /// One BOT READ(10): CBW out, data in and CSW in, one event each
+    const BULK_READ_TRACE: &[(&str, u64)] = &[
+        ("DB1", 0x4),
+        ("ERDP", 0xf8),
+        ("USBSTS", 0x8),
+        ("IMAN", 0x1),
+        ("DB1", 0x3),
+        ("ERDP", 0x108),
+        ("USBSTS", 0x8),
+        ("IMAN", 0x1),
+        ("DB1", 0x3),
+        ("ERDP", 0x118),
+        ("USBSTS", 0x8),
+        ("IMAN", 0x1),
+    ];
+
+    fn assert_trace(writes: Vec<(alloc::string::String, u64)>, golden: &[(&str, u64)]) {
+        let writes: Vec<(&str, u64)> = writes.iter().map(|(n, v)| (n.as_str(), *v)).collect();
+        assert_eq!(writes, golden);
+    }
+
+    #[test]
+    fn port_reset_register_trace() {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, mock::keyboard());
+        let from = mock.register_writes().len();
+        ctrl.reset_port(0).unwrap();
+        assert_trace(named_writes(&ctrl, &mock, from), PORT_RESET_TRACE);
+    }
+
+    #[test]
+    fn enumeration_register_trace() {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(0, mock::keyboard());
+        let from = mock.register_writes().len();
+        let dev = crate::dev::UsbDevice::new(ctrl.clone(), 0).unwrap();
+        dev.choose_configuration(crate::dev::default_config_policy)
+            .unwrap();
+        assert_trace(named_writes(&ctrl, &mock, from), ENUMERATION_TRACE);
+    }
+
+    #[test]
+    fn bulk_transfer_register_trace() {
+        let (ctrl, mock) = mock::controller();
+        mock.attach(
+            0,
+            mock::mass_storage().with_handler(mock::bulk_only_disk(0x81, 0x02, 64)),
+        );
+        let dev = crate::dev::UsbDevice::new(ctrl.clone(), 0).unwrap();
+        let tree = dev
+            .choose_configuration(crate::dev::default_config_policy)
+            .unwrap();
+        let (iface, i, o) = crate::msc::find_msc_interfaces(tree.raw())[0];
+        let mut msc = crate::msc::MscDevice::from_interface(Arc::new(dev), &iface, &i, &o).unwrap();
+        let from = mock.register_writes().len();
+        let mut block = [0u8; 512];
+        let read = [crate::msc::scsi_op::READ_10, 0, 0, 0, 0, 5, 0, 0, 1, 0];
+        msc.pass_through(0, &read, crate::msc::DataPhase::In(&mut block), 0)
+            .unwrap();
+        assert_trace(named_writes(&ctrl, &mock, from), BULK_READ_TRACE);
     }
 }